@@ -0,0 +1,144 @@
+// Run with `cargo bench`. Criterion isn't available to this crate, so each
+// benchmark is timed with a small runner: warmed up, then repeated for a
+// fixed time, reporting the mean time per iteration and the throughput.
+// Pass a name filter as the first argument to run only matching ones.
+
+use rust_blockchain::block::Block;
+use rust_blockchain::consensus::PowAlgorithm;
+use rust_blockchain::params::ChainParams;
+use rust_blockchain::signature::{PrivateKey, SIGNATURE_CACHE, SignatureScheme};
+use rust_blockchain::transaction::{Transaction, TxInput, TxOutput};
+use rust_blockchain::utils;
+use rust_blockchain::utxo::UTXOSet;
+use rust_blockchain::wallet::Wallet;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const WARMUP: Duration = Duration::from_millis(200);
+const MEASURE: Duration = Duration::from_secs(1);
+
+struct Bench {
+    filter: Option<String>,
+}
+
+impl Bench {
+    // `units` is how many items one iteration handles, for the throughput
+    fn run<T>(&self, name: &str, units: u64, unit: &str, mut f: impl FnMut() -> T) {
+        if self
+            .filter
+            .as_ref()
+            .is_some_and(|filter| !name.contains(filter.as_str()))
+        {
+            return;
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < WARMUP {
+            black_box(f());
+        }
+
+        let mut iterations = 0u64;
+        let start = Instant::now();
+        while start.elapsed() < MEASURE {
+            black_box(f());
+            iterations += 1;
+        }
+        let elapsed = start.elapsed();
+        let per_iteration = elapsed / iterations as u32;
+        let throughput = (iterations * units) as f64 / elapsed.as_secs_f64();
+        println!("{name:<40} {per_iteration:>12.2?}/iter {throughput:>14.0} {unit}/s");
+    }
+}
+
+// A block with `count` signed transactions, each spending one made-up
+// output
+fn block_with_transactions(wallet: &Wallet, count: usize) -> Block {
+    let params = ChainParams::regtest();
+    let genesis = params.genesis();
+    let mut block = Block::new(1, genesis.digest, params.bits, Vec::new());
+    for i in 0..count {
+        let mut txid = [0xab; 32];
+        txid[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(txid, 1)],
+            vec![TxOutput::new(10, [1; 32]), TxOutput::new(20, wallet.pkhash)],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        block.transactions.push(tx);
+    }
+    utils::mine(&mut block, wallet.pkhash, params.initial_reward);
+    block
+}
+
+fn main() {
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with('-'));
+    let bench = Bench { filter };
+    let wallet = Wallet::new();
+
+    let header = Block::genesis().header;
+    bench.run("block header hashing", 1, "hashes", || header.hash());
+    for pow in [
+        PowAlgorithm::Sha256,
+        PowAlgorithm::DoubleSha256,
+        PowAlgorithm::Blake3,
+    ] {
+        bench.run(
+            &format!("proof of work hashing, {}", pow.name()),
+            1,
+            "hashes",
+            || header.pow_hash(&pow),
+        );
+    }
+
+    // A different block each time, or the same nonce would be found again
+    let params = ChainParams::mainnet();
+    let mut count = 0u64;
+    bench.run("mining at the mainnet target", 1, "blocks", || {
+        count += 1;
+        let mut prev_hash = [0; 32];
+        prev_hash[..8].copy_from_slice(&count.to_be_bytes());
+        let mut block = Block::new(1, prev_hash, params.bits, Vec::new());
+        utils::mine(&mut block, [1; 32], params.initial_reward);
+        block
+    });
+
+    for scheme in [
+        SignatureScheme::Ecdsa,
+        SignatureScheme::Ed25519,
+        SignatureScheme::Schnorr,
+    ] {
+        let key = PrivateKey::random(scheme);
+        let pubkey = key.public_key();
+        let signature = key.sign(b"message");
+        assert_eq!(scheme.verify(&pubkey, b"message", &signature), Ok(()));
+        bench.run(
+            &format!("{} signature verification", scheme.name()),
+            1,
+            "signatures",
+            || scheme.verify(&pubkey, b"message", &signature),
+        );
+    }
+
+    let chain_id = wallet.chain_id();
+
+    for count in [10, 100, 1000] {
+        let block = block_with_transactions(&wallet, count);
+        assert_eq!(block.validate(&chain_id, &PowAlgorithm::Sha256), Ok(()));
+        bench.run(
+            &format!("block validation, {count} transactions"),
+            count as u64,
+            "transactions",
+            || {
+                // Otherwise only the first run would check the signatures
+                SIGNATURE_CACHE.clear();
+                block.validate(&chain_id, &PowAlgorithm::Sha256)
+            },
+        );
+        bench.run(
+            &format!("UTXO set update, {count} transactions"),
+            count as u64,
+            "transactions",
+            || UTXOSet::new().update_with_block(&block),
+        );
+    }
+}
@@ -0,0 +1,59 @@
+/* C interface to the rust_blockchain library, matching ffi/src/lib.rs.
+ *
+ * Link against the cdylib or staticlib (librust_blockchain_ffi) built by
+ * `cargo build --release -p rust_blockchain_ffi`.
+ * Objects are opaque and freed with their _free function; strings and byte
+ * buffers returned by the library are freed with rb_string_free and
+ * rb_bytes_free. Functions returning int give RB_OK on success; on failure,
+ * and whenever a function returns NULL, rb_last_error describes what went
+ * wrong on the calling thread. Hashes are 32 bytes. */
+
+#ifndef RUST_BLOCKCHAIN_H
+#define RUST_BLOCKCHAIN_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define RB_OK 0
+#define RB_NULL_POINTER 1
+#define RB_INVALID_ARGUMENT 2
+#define RB_INVALID_DATA 3
+#define RB_VERIFICATION_FAILED 4
+#define RB_SIGNING_FAILED 5
+
+typedef struct Wallet Wallet;
+typedef struct Transaction Transaction;
+
+const char *rb_last_error(void);
+void rb_string_free(char *s);
+void rb_bytes_free(uint8_t *data, size_t len);
+
+/* chain_id is the chain's genesis digest, or NULL for mainnet */
+Wallet *rb_wallet_new(const uint8_t *chain_id);
+Wallet *rb_wallet_from_mnemonic(const char *phrase, const uint8_t *chain_id);
+void rb_wallet_free(Wallet *wallet);
+char *rb_wallet_address(const Wallet *wallet);
+int rb_wallet_pkhash(const Wallet *wallet, uint8_t *out);
+int rb_wallet_sign_transaction(const Wallet *wallet, Transaction *tx);
+
+Transaction *rb_transaction_new(void);
+Transaction *rb_transaction_decode(const uint8_t *data, size_t len);
+void rb_transaction_free(Transaction *tx);
+int rb_transaction_add_input(Transaction *tx, const uint8_t *txid, uint16_t output,
+                             uint64_t value);
+int rb_transaction_add_output(Transaction *tx, uint64_t value, const char *recipient);
+int rb_transaction_id(const Transaction *tx, uint8_t *out);
+uint8_t *rb_transaction_encode(const Transaction *tx, size_t *len);
+int rb_transaction_verify(const Transaction *tx, const uint8_t *chain_id);
+
+int rb_block_verify(const uint8_t *data, size_t len, const uint8_t *chain_id);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,382 @@
+// A C interface to wallets, transactions and block verification, for apps
+// that embed the library, declared in include/rust_blockchain.h. It is a
+// crate of its own so the main one stays an ordinary library, which builds
+// without std.
+//
+// Objects are opaque pointers owned by the caller, who frees each with its
+// `_free` function; strings and byte buffers returned to the caller are
+// freed with rb_string_free and rb_bytes_free. Functions returning a status
+// give RB_OK on success, and on failure leave a description for
+// rb_last_error on the calling thread. Pointers passed in must be null or
+// valid for the length given, which is what makes these functions unsafe.
+#![allow(clippy::missing_safety_doc)]
+
+use rust_blockchain::address::Address;
+use rust_blockchain::block::Block;
+use rust_blockchain::consensus::PowAlgorithm;
+use rust_blockchain::params::ChainParams;
+use rust_blockchain::transaction::{Transaction, TxInput, TxOutput};
+use rust_blockchain::wallet::Wallet;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_int};
+use std::{ptr, slice};
+
+pub const RB_OK: c_int = 0;
+pub const RB_NULL_POINTER: c_int = 1;
+pub const RB_INVALID_ARGUMENT: c_int = 2;
+pub const RB_INVALID_DATA: c_int = 3;
+pub const RB_VERIFICATION_FAILED: c_int = 4;
+pub const RB_SIGNING_FAILED: c_int = 5;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(status: c_int, message: impl ToString) -> c_int {
+    let message = CString::new(message.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+fn fail_null<T>(status: c_int, message: impl ToString) -> *mut T {
+    fail(status, message);
+    ptr::null_mut()
+}
+
+unsafe fn read_hash(hash: *const u8) -> Option<[u8; 32]> {
+    (!hash.is_null()).then(|| unsafe { *(hash as *const [u8; 32]) })
+}
+
+unsafe fn read_str<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(fail(RB_NULL_POINTER, "null string"));
+    }
+    unsafe { CStr::from_ptr(s) }
+        .to_str()
+        .map_err(|_| fail(RB_INVALID_ARGUMENT, "string is not UTF-8"))
+}
+
+unsafe fn read_bytes<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    (!data.is_null()).then(|| unsafe { slice::from_raw_parts(data, len) })
+}
+
+fn into_bytes(data: Vec<u8>, len: *mut usize) -> *mut u8 {
+    let data = data.into_boxed_slice();
+    if !len.is_null() {
+        unsafe { *len = data.len() };
+    }
+    Box::into_raw(data) as *mut u8
+}
+
+// The message for the last failure on this thread, valid until the next
+// call that fails
+#[unsafe(no_mangle)]
+pub extern "C" fn rb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_bytes_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(unsafe { Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)) });
+    }
+}
+
+// A new random wallet for the chain whose genesis digest is `chain_id`, or
+// for mainnet if it is null
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_new(chain_id: *const u8) -> *mut Wallet {
+    let wallet = match unsafe { read_hash(chain_id) } {
+        Some(chain_id) => Wallet::for_chain(chain_id),
+        None => Wallet::new(),
+    };
+    Box::into_raw(Box::new(wallet))
+}
+
+// Null if the phrase is invalid
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_from_mnemonic(
+    phrase: *const c_char,
+    chain_id: *const u8,
+) -> *mut Wallet {
+    let phrase = match unsafe { read_str(phrase) } {
+        Ok(phrase) => phrase,
+        Err(_) => return ptr::null_mut(),
+    };
+    let chain_id = unsafe { read_hash(chain_id) }.unwrap_or(Block::genesis().digest.0);
+    match Wallet::from_mnemonic_for_chain(phrase, chain_id) {
+        Ok(wallet) => Box::into_raw(Box::new(wallet)),
+        Err(e) => fail_null(RB_INVALID_ARGUMENT, format!("invalid mnemonic: {e:?}")),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_free(wallet: *mut Wallet) {
+    if !wallet.is_null() {
+        drop(unsafe { Box::from_raw(wallet) });
+    }
+}
+
+// The wallet's address as a string, to free with rb_string_free
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_address(wallet: *const Wallet) -> *mut c_char {
+    match unsafe { wallet.as_ref() } {
+        Some(wallet) => CString::new(wallet.address.encode()).unwrap().into_raw(),
+        None => fail_null(RB_NULL_POINTER, "null wallet"),
+    }
+}
+
+// Writes the 32 byte hash of the wallet's public key to `out`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_pkhash(wallet: *const Wallet, out: *mut u8) -> c_int {
+    match unsafe { wallet.as_ref() } {
+        Some(wallet) if !out.is_null() => {
+            unsafe { ptr::copy_nonoverlapping(wallet.pkhash.as_ptr(), out, 32) };
+            RB_OK
+        }
+        _ => fail(RB_NULL_POINTER, "null wallet or output"),
+    }
+}
+
+// Signs every input the wallet can sign on its own
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_wallet_sign_transaction(
+    wallet: *const Wallet,
+    tx: *mut Transaction,
+) -> c_int {
+    match unsafe { (wallet.as_ref(), tx.as_mut()) } {
+        (Some(wallet), Some(tx)) => match wallet.sign_transaction(tx) {
+            Ok(()) => RB_OK,
+            Err(e) => fail(RB_SIGNING_FAILED, e),
+        },
+        _ => fail(RB_NULL_POINTER, "null wallet or transaction"),
+    }
+}
+
+// An empty transaction, to fill in with rb_transaction_add_input and
+// rb_transaction_add_output
+#[unsafe(no_mangle)]
+pub extern "C" fn rb_transaction_new() -> *mut Transaction {
+    let mut tx = Transaction::new(Vec::new(), Vec::new());
+    tx.update_id();
+    Box::into_raw(Box::new(tx))
+}
+
+// Null if the data isn't a transaction
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_decode(data: *const u8, len: usize) -> *mut Transaction {
+    let Some(data) = (unsafe { read_bytes(data, len) }) else {
+        return fail_null(RB_NULL_POINTER, "null data");
+    };
+    match Transaction::from_bincode(data) {
+        Ok(tx) => Box::into_raw(Box::new(tx)),
+        Err(e) => fail_null(RB_INVALID_DATA, e),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_free(tx: *mut Transaction) {
+    if !tx.is_null() {
+        drop(unsafe { Box::from_raw(tx) });
+    }
+}
+
+// Spends output `output`, worth `value`, of the transaction `txid`, a 32
+// byte hash
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_add_input(
+    tx: *mut Transaction,
+    txid: *const u8,
+    output: u16,
+    value: u64,
+) -> c_int {
+    match unsafe { (tx.as_mut(), read_hash(txid)) } {
+        (Some(tx), Some(txid)) => {
+            tx.inputs
+                .push(TxInput::new_unsigned(txid, output).with_value(value));
+            tx.update_id();
+            RB_OK
+        }
+        _ => fail(RB_NULL_POINTER, "null transaction or txid"),
+    }
+}
+
+// Pays `value` to the address `recipient`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_add_output(
+    tx: *mut Transaction,
+    value: u64,
+    recipient: *const c_char,
+) -> c_int {
+    let Some(tx) = (unsafe { tx.as_mut() }) else {
+        return fail(RB_NULL_POINTER, "null transaction");
+    };
+    let recipient = match unsafe { read_str(recipient) } {
+        Ok(recipient) => recipient,
+        Err(status) => return status,
+    };
+    match Address::decode(recipient) {
+        Ok(address) => {
+            tx.outputs.push(TxOutput::new(value, address));
+            tx.update_id();
+            RB_OK
+        }
+        Err(e) => fail(RB_INVALID_ARGUMENT, format!("invalid address: {e:?}")),
+    }
+}
+
+// Writes the 32 byte transaction id to `out`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_id(tx: *const Transaction, out: *mut u8) -> c_int {
+    match unsafe { tx.as_ref() } {
+        Some(tx) if !out.is_null() => {
+            unsafe { ptr::copy_nonoverlapping(tx.id.0.as_ptr(), out, 32) };
+            RB_OK
+        }
+        _ => fail(RB_NULL_POINTER, "null transaction or output"),
+    }
+}
+
+// The transaction as it is sent to nodes, to free with rb_bytes_free. Its
+// length is written to `len`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_encode(tx: *const Transaction, len: *mut usize) -> *mut u8 {
+    match unsafe { tx.as_ref() } {
+        Some(tx) => into_bytes(tx.as_bincode(), len),
+        None => fail_null(RB_NULL_POINTER, "null transaction"),
+    }
+}
+
+// Checks the transaction on its own, including its signatures for the chain
+// whose genesis digest is `chain_id`
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_transaction_verify(
+    tx: *const Transaction,
+    chain_id: *const u8,
+) -> c_int {
+    match unsafe { (tx.as_ref(), read_hash(chain_id)) } {
+        (Some(tx), Some(chain_id)) => match tx.verify(&chain_id) {
+            Ok(()) => RB_OK,
+            Err(e) => fail(RB_VERIFICATION_FAILED, e),
+        },
+        _ => fail(RB_NULL_POINTER, "null transaction or chain id"),
+    }
+}
+
+// Checks an encoded block on its own: proof of work, merkle root and its
+// transactions with their signatures. Whether it extends the chain is up to
+// a node. The proof of work is that of the built-in network with the chain
+// id, or SHA-256 for other chains.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn rb_block_verify(
+    data: *const u8,
+    len: usize,
+    chain_id: *const u8,
+) -> c_int {
+    let (Some(data), Some(chain_id)) = (unsafe { (read_bytes(data, len), read_hash(chain_id)) })
+    else {
+        return fail(RB_NULL_POINTER, "null data or chain id");
+    };
+    let block = match Block::from_bincode(data) {
+        Ok(block) => block,
+        Err(e) => return fail(RB_INVALID_DATA, e),
+    };
+    let pow =
+        ChainParams::for_chain_id(&chain_id).map_or(PowAlgorithm::Sha256, |params| params.pow);
+    match block.validate(&chain_id, &pow) {
+        Ok(()) => RB_OK,
+        Err(e) => fail(RB_VERIFICATION_FAILED, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_blockchain::utils;
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(rb_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_build_and_sign_transaction() {
+        unsafe {
+            let wallet = rb_wallet_new(ptr::null());
+            let chain_id = (*wallet).chain_id();
+            let address = rb_wallet_address(wallet);
+            let other = rb_wallet_new(chain_id.as_ptr());
+            let change = rb_wallet_address(other);
+            let mut pkhash = [0; 32];
+            assert_eq!(rb_wallet_pkhash(wallet, pkhash.as_mut_ptr()), RB_OK);
+            assert_eq!(pkhash, (*wallet).pkhash);
+
+            let tx = rb_transaction_new();
+            assert_eq!(rb_transaction_add_input(tx, [1; 32].as_ptr(), 1, 40), RB_OK);
+            assert_eq!(rb_transaction_add_output(tx, 10, address), RB_OK);
+            assert_eq!(rb_transaction_add_output(tx, 20, change), RB_OK);
+            assert_eq!(
+                rb_transaction_add_output(tx, 5, c"not an address".as_ptr()),
+                RB_INVALID_ARGUMENT
+            );
+            assert!(last_error().starts_with("invalid address"));
+            assert_eq!(rb_wallet_sign_transaction(wallet, tx), RB_OK);
+            assert_eq!(rb_transaction_verify(tx, chain_id.as_ptr()), RB_OK);
+            // signed for another chain
+            assert_eq!(
+                rb_transaction_verify(tx, [9; 32].as_ptr()),
+                RB_VERIFICATION_FAILED
+            );
+
+            let mut len = 0;
+            let data = rb_transaction_encode(tx, &mut len);
+            let decoded = rb_transaction_decode(data, len);
+            let (mut id, mut decoded_id) = ([0; 32], [0; 32]);
+            assert_eq!(rb_transaction_id(tx, id.as_mut_ptr()), RB_OK);
+            assert_eq!(rb_transaction_id(decoded, decoded_id.as_mut_ptr()), RB_OK);
+            assert_eq!(id, decoded_id);
+            assert!(rb_transaction_decode(data, len - 1).is_null());
+
+            rb_bytes_free(data, len);
+            rb_transaction_free(decoded);
+            rb_transaction_free(tx);
+            rb_string_free(address);
+            rb_string_free(change);
+            rb_wallet_free(other);
+            rb_wallet_free(wallet);
+        }
+    }
+
+    #[test]
+    fn test_verify_block() {
+        let wallet = Wallet::new();
+        let genesis = Block::genesis();
+        let mut block = Block::new(1, genesis.digest, genesis.header.bits, Vec::new());
+        utils::mine(&mut block, wallet.pkhash, 50);
+        let chain_id = wallet.chain_id();
+        let data = block.as_bincode();
+        unsafe {
+            assert_eq!(
+                rb_block_verify(data.as_ptr(), data.len(), chain_id.as_ptr()),
+                RB_OK
+            );
+            assert_eq!(
+                rb_block_verify(data.as_ptr(), data.len() - 1, chain_id.as_ptr()),
+                RB_INVALID_DATA
+            );
+            assert_eq!(
+                rb_block_verify(ptr::null(), 0, chain_id.as_ptr()),
+                RB_NULL_POINTER
+            );
+            assert!(rb_wallet_from_mnemonic(c"not a phrase".as_ptr(), ptr::null()).is_null());
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use crate::utils;
+use bincode::{Decode, Encode};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::Path;
+
+// Addresses remembered at most; the least recently seen are forgotten first
+pub const MAX_KNOWN_ADDRS: usize = 10_000;
+// Addresses sent or accepted in one gossip message
+pub const MAX_ADDRS_PER_MESSAGE: usize = 1000;
+// Failed connection attempts, without a success since, before an address is
+// forgotten
+const MAX_FAILED_ATTEMPTS: u32 = 10;
+
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq)]
+struct KnownAddr {
+    // Unix time the address was last heard of or connected to
+    last_seen: u64,
+    // Failed connection attempts since the last success
+    attempts: u32,
+}
+
+// Peer addresses learned from seeds and gossip, for picking outbound
+// connections
+#[derive(Default)]
+pub struct AddrManager {
+    known: HashMap<SocketAddr, KnownAddr>,
+}
+
+impl AddrManager {
+    pub fn new() -> AddrManager {
+        AddrManager::default()
+    }
+
+    // Addresses that can't be connected to are ignored
+    pub fn add(&mut self, addr: SocketAddr, last_seen: u64) {
+        if addr.port() == 0 || addr.ip().is_unspecified() {
+            return;
+        }
+        if !self.known.contains_key(&addr) && self.known.len() >= MAX_KNOWN_ADDRS {
+            self.evict_oldest();
+        }
+        let known = self.known.entry(addr).or_insert(KnownAddr {
+            last_seen,
+            attempts: 0,
+        });
+        known.last_seen = known.last_seen.max(last_seen);
+    }
+
+    // Resolves a `host:port` seed and adds every address it resolves to,
+    // returning how many there were
+    pub fn add_seed(&mut self, seed: &str) -> io::Result<usize> {
+        let now = utils::unix_timestamp();
+        let mut count = 0;
+        for addr in seed.to_socket_addrs()? {
+            self.add(addr, now);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn mark_good(&mut self, addr: SocketAddr) {
+        self.add(addr, utils::unix_timestamp());
+        if let Some(known) = self.known.get_mut(&addr) {
+            known.attempts = 0;
+        }
+    }
+
+    pub fn mark_failed(&mut self, addr: &SocketAddr) {
+        if let Some(known) = self.known.get_mut(addr) {
+            known.attempts += 1;
+            if known.attempts >= MAX_FAILED_ATTEMPTS {
+                self.known.remove(addr);
+            }
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .known
+            .iter()
+            .min_by_key(|(_, known)| (known.last_seen, Reverse(known.attempts)))
+            .map(|(addr, _)| *addr);
+        if let Some(addr) = oldest {
+            self.known.remove(&addr);
+        }
+    }
+
+    // Up to `count` addresses to connect to, best first: those that failed
+    // least, then the most recently seen. Addresses from networks not picked
+    // yet come before a second one from the same network, so a single
+    // operator can't easily take every outbound slot.
+    pub fn select(&self, count: usize, exclude: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut candidates: Vec<(&SocketAddr, &KnownAddr)> = self
+            .known
+            .iter()
+            .filter(|(addr, _)| !exclude.contains(*addr))
+            .collect();
+        candidates.sort_by_key(|(addr, known)| (known.attempts, Reverse(known.last_seen), **addr));
+
+        let mut groups = HashSet::new();
+        let (mut selected, mut rest): (Vec<SocketAddr>, Vec<SocketAddr>) = (Vec::new(), Vec::new());
+        for (addr, _) in candidates {
+            if groups.insert(network_group(addr.ip())) {
+                selected.push(*addr);
+            } else {
+                rest.push(*addr);
+            }
+        }
+        selected.extend(rest);
+        selected.truncate(count);
+        selected
+    }
+
+    // The most recently seen addresses, for answering a request for them
+    pub fn addresses(&self, limit: usize) -> Vec<SocketAddr> {
+        let mut known: Vec<(&SocketAddr, &KnownAddr)> = self.known.iter().collect();
+        known.sort_by_key(|(addr, known)| (Reverse(known.last_seen), **addr));
+        known
+            .into_iter()
+            .take(limit)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.known.is_empty()
+    }
+
+    pub fn save_to_disk(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut known: Vec<(SocketAddr, KnownAddr)> = self
+            .known
+            .iter()
+            .map(|(addr, known)| (*addr, *known))
+            .collect();
+        known.sort_by_key(|(addr, _)| *addr);
+        let data = bincode::encode_to_vec(known, bincode::config::standard())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+
+    pub fn load_from_disk(path: impl AsRef<Path>) -> io::Result<AddrManager> {
+        let data = fs::read(path)?;
+        let (known, _): (Vec<(SocketAddr, KnownAddr)>, usize) =
+            bincode::decode_from_slice(&data, bincode::config::standard())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(AddrManager {
+            known: known.into_iter().collect(),
+        })
+    }
+}
+
+// The /16 of an IPv4 address or the /32 of an IPv6 one, roughly the block
+// a single provider hands out
+fn network_group(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[..2].to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..4].to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(a: u8, b: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([a, b, 0, 1], port))
+    }
+
+    #[test]
+    fn test_select_prefers_diverse_and_reliable() {
+        let mut addrs = AddrManager::new();
+        addrs.add(addr(10, 1, 1), 100);
+        addrs.add(addr(10, 1, 2), 300);
+        addrs.add(addr(10, 2, 1), 200);
+        addrs.add(addr(10, 3, 1), 50);
+        addrs.add(SocketAddr::from(([0, 0, 0, 0], 1)), 500);
+        assert_eq!(addrs.len(), 4);
+
+        // one per network first, most recently seen first
+        assert_eq!(
+            addrs.select(3, &HashSet::new()),
+            vec![addr(10, 1, 2), addr(10, 2, 1), addr(10, 3, 1)]
+        );
+        assert_eq!(addrs.select(10, &HashSet::new())[3], addr(10, 1, 1));
+
+        addrs.mark_failed(&addr(10, 1, 2));
+        let exclude = HashSet::from([addr(10, 2, 1)]);
+        assert_eq!(
+            addrs.select(2, &exclude),
+            vec![addr(10, 1, 1), addr(10, 3, 1)]
+        );
+
+        for _ in 1..MAX_FAILED_ATTEMPTS {
+            addrs.mark_failed(&addr(10, 1, 2));
+        }
+        assert_eq!(addrs.len(), 3);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut addrs = AddrManager::new();
+        addrs.add(addr(10, 1, 1), 100);
+        addrs.add_seed("127.0.0.1:9333").unwrap();
+        addrs.mark_failed(&addr(10, 1, 1));
+
+        let path = std::env::temp_dir().join(format!("peers_{}.dat", std::process::id()));
+        addrs.save_to_disk(&path).unwrap();
+        let loaded = AddrManager::load_from_disk(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.known, addrs.known);
+        assert_eq!(
+            loaded.addresses(1),
+            vec![SocketAddr::from(([127, 0, 0, 1], 9333))]
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Human-readable addresses. A pkhash is encoded as bech32m (BIP350) with the
+// "rbc" prefix, so a mistyped or truncated address fails the checksum instead
+// of silently sending funds to a different pkhash.
+
+use crate::errors::AddressError;
+use std::fmt;
+use std::str::FromStr;
+
+pub const ADDRESS_PREFIX: &str = "rbc";
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32M_CONST: u32 = 0x2bc8_30a3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub pkhash: [u8; 32],
+}
+
+impl Address {
+    pub fn encode(&self) -> String {
+        let data = convert_bits(&self.pkhash, 8, 5, true).unwrap();
+        bech32m_encode(ADDRESS_PREFIX, &data)
+    }
+
+    pub fn decode(address: &str) -> Result<Address, AddressError> {
+        let (hrp, data) = bech32m_decode(address)?;
+        if hrp != ADDRESS_PREFIX {
+            return Err(AddressError::WrongPrefix);
+        }
+
+        let pkhash = convert_bits(&data, 5, 8, false).ok_or(AddressError::InvalidLength)?;
+        Ok(Address {
+            pkhash: pkhash.try_into().map_err(|_| AddressError::InvalidLength)?,
+        })
+    }
+}
+
+impl From<[u8; 32]> for Address {
+    fn from(pkhash: [u8; 32]) -> Self {
+        Address { pkhash }
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Address::decode(s)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [
+        0x3b6a_57b2,
+        0x2650_8e6d,
+        0x1ea1_19fa,
+        0x3d42_33dd,
+        0x2a14_62b3,
+    ];
+    values.iter().fold(1u32, |chk, &value| {
+        let top = chk >> 25;
+        let chk = ((chk & 0x01ff_ffff) << 5) ^ value as u32;
+        (0..5)
+            .filter(|i| (top >> i) & 1 == 1)
+            .fold(chk, |chk, i| chk ^ GENERATOR[i])
+    })
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let bytes = hrp.as_bytes();
+    bytes
+        .iter()
+        .map(|b| b >> 5)
+        .chain([0])
+        .chain(bytes.iter().map(|b| b & 31))
+        .collect()
+}
+
+fn bech32m_encode(hrp: &str, data: &[u8]) -> String {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; 6]);
+    let checksum = polymod(&values) ^ BECH32M_CONST;
+
+    let mut encoded = format!("{hrp}1");
+    for &value in data {
+        encoded.push(CHARSET[value as usize] as char);
+    }
+    for i in 0..6 {
+        encoded.push(CHARSET[((checksum >> (5 * (5 - i))) & 31) as usize] as char);
+    }
+    encoded
+}
+
+fn bech32m_decode(encoded: &str) -> Result<(String, Vec<u8>), AddressError> {
+    if encoded.len() > 90 {
+        return Err(AddressError::InvalidLength);
+    }
+    if encoded.bytes().any(|b| !(33..=126).contains(&b)) {
+        return Err(AddressError::InvalidCharacter);
+    }
+    let lower = encoded.to_lowercase();
+    if lower != encoded && encoded.to_uppercase() != encoded {
+        return Err(AddressError::MixedCase);
+    }
+
+    let separator = lower.rfind('1').ok_or(AddressError::InvalidCharacter)?;
+    if separator == 0 || separator + 7 > lower.len() {
+        return Err(AddressError::InvalidLength);
+    }
+    let (hrp, rest) = (&lower[..separator], &lower[separator + 1..]);
+
+    let data = rest
+        .bytes()
+        .map(|b| CHARSET.iter().position(|&c| c == b).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(AddressError::InvalidCharacter)?;
+
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    if polymod(&values) != BECH32M_CONST {
+        return Err(AddressError::InvalidChecksum);
+    }
+
+    Ok((hrp.to_string(), data[..data.len() - 6].to_vec()))
+}
+
+// Regroups a bit stream, e.g. bytes into 5-bit bech32 values
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut out = Vec::new();
+    let max = (1 << to) - 1;
+
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            out.push(((acc >> bits) & max) as u8);
+        }
+    }
+
+    if pad {
+        if bits > 0 {
+            out.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Valid bech32m strings from BIP350
+    #[test]
+    fn test_bech32m_vectors() {
+        for encoded in [
+            "A1LQFN3A",
+            "a1lqfn3a",
+            "abcdef1l7aum6echk45nj3s0wdvt2fg8x9yrzpqzd3ryx",
+            "?1v759aa",
+        ] {
+            assert!(bech32m_decode(encoded).is_ok(), "{encoded}");
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let address = Address::from([7; 32]);
+        let encoded = address.encode();
+        assert!(encoded.starts_with("rbc1"));
+        assert_eq!(Address::decode(&encoded), Ok(address));
+        assert_eq!(encoded.to_uppercase().parse(), Ok(address));
+    }
+
+    #[test]
+    fn test_typos_rejected() {
+        let encoded = Address::from([7; 32]).encode();
+
+        let mut typo = encoded.clone().into_bytes();
+        typo[10] = if typo[10] == b'q' { b'p' } else { b'q' };
+        assert_eq!(
+            Address::decode(std::str::from_utf8(&typo).unwrap()),
+            Err(AddressError::InvalidChecksum)
+        );
+
+        let mut swapped = encoded.clone().into_bytes();
+        swapped.swap(10, 11);
+        if swapped != encoded.as_bytes() {
+            assert_eq!(
+                Address::decode(std::str::from_utf8(&swapped).unwrap()),
+                Err(AddressError::InvalidChecksum)
+            );
+        }
+
+        assert_eq!(
+            Address::decode(&encoded[..encoded.len() - 1]),
+            Err(AddressError::InvalidChecksum)
+        );
+        assert_eq!(
+            Address::decode(&encoded.replacen("rbc", "btc", 1)),
+            Err(AddressError::InvalidChecksum)
+        );
+        assert_eq!(
+            Address::decode(&encoded.replacen('q', "Q", 1)),
+            Err(AddressError::MixedCase)
+        );
+        assert_eq!(
+            Address::decode(&encoded.replacen('q', "b", 1)),
+            Err(AddressError::InvalidCharacter)
+        );
+    }
+
+    #[test]
+    fn test_wrong_prefix() {
+        let encoded = bech32m_encode("tbc", &convert_bits(&[7; 32], 8, 5, true).unwrap());
+        assert_eq!(Address::decode(&encoded), Err(AddressError::WrongPrefix));
+    }
+}
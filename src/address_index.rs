@@ -0,0 +1,136 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::hash::{OutPoint, TxId};
+use std::collections::{HashMap, HashSet};
+
+// An output paid to an address
+#[derive(Clone, Debug, PartialEq)]
+pub struct Received {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    pub height: u64,
+}
+
+// An output of an address spent by a later transaction
+#[derive(Clone, Debug, PartialEq)]
+pub struct Spent {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    pub txid: TxId,
+    pub height: u64,
+}
+
+// Every output ever paid to each pkhash and every spend of one, so an
+// address's history is found without scanning the chain. Blocks are added in
+// order, starting from genesis.
+#[derive(Default)]
+pub struct AddressIndex {
+    received: HashMap<[u8; 32], Vec<Received>>,
+    spent: HashMap<[u8; 32], Vec<Spent>>,
+    // Every output created, to find who a spend is from
+    owners: HashMap<OutPoint, ([u8; 32], Amount)>,
+}
+
+impl AddressIndex {
+    pub fn new() -> AddressIndex {
+        AddressIndex::default()
+    }
+
+    pub fn add_block(&mut self, block: &Block) {
+        let height = block.header.index;
+        for (position, tx) in block.transactions.iter().enumerate() {
+            if position > 0 {
+                for input in &tx.inputs {
+                    let outpoint = input.outpoint();
+                    if let Some(&(pkhash, value)) = self.owners.get(&outpoint) {
+                        self.spent.entry(pkhash).or_default().push(Spent {
+                            outpoint,
+                            value,
+                            txid: tx.id,
+                            height,
+                        });
+                    }
+                }
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(tx.id, index as u16);
+                self.owners.insert(outpoint, (output.pkhash, output.value));
+                self.received
+                    .entry(output.pkhash)
+                    .or_default()
+                    .push(Received {
+                        outpoint,
+                        value: output.value,
+                        height,
+                    });
+            }
+        }
+    }
+
+    // Oldest first
+    pub fn received_by(&self, pkhash: &[u8; 32]) -> &[Received] {
+        self.received.get(pkhash).map_or(&[], Vec::as_slice)
+    }
+
+    // Oldest first
+    pub fn sent_by(&self, pkhash: &[u8; 32]) -> &[Spent] {
+        self.spent.get(pkhash).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn unspent_by(&self, pkhash: &[u8; 32]) -> Vec<&Received> {
+        let spent: HashSet<OutPoint> = self
+            .sent_by(pkhash)
+            .iter()
+            .map(|spent| spent.outpoint)
+            .collect();
+        self.received_by(pkhash)
+            .iter()
+            .filter(|received| !spent.contains(&received.outpoint))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::Hash256;
+    use crate::transaction::{Transaction, TxInput, TxOutput};
+
+    #[test]
+    fn test_index() {
+        let mut index = AddressIndex::new();
+        let mut block = Block::new(1, Hash256([9; 32]), 0x2100_ffff, Vec::new());
+        block.add_coinbase_tx([1; 32], 100);
+        let coinbase_id = block.transactions[0].id;
+        index.add_block(&block);
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(coinbase_id, 0)],
+            vec![TxOutput::new(60, [2; 32]), TxOutput::new(40, [1; 32])],
+        );
+        tx.update_id();
+        let mut block = Block::new(2, block.digest, 0x2100_ffff, Vec::new());
+        block.add_tx(tx.clone());
+        block.add_coinbase_tx([2; 32], 100);
+        index.add_block(&block);
+
+        assert_eq!(index.received_by(&[1; 32]).len(), 2);
+        assert_eq!(
+            index.sent_by(&[1; 32]),
+            [Spent {
+                outpoint: OutPoint::new(coinbase_id, 0),
+                value: Amount(100),
+                txid: tx.id,
+                height: 2,
+            }]
+        );
+        let unspent = index.unspent_by(&[1; 32]);
+        assert_eq!(unspent.len(), 1);
+        assert_eq!(unspent[0].outpoint, OutPoint::new(tx.id, 1));
+        assert_eq!(unspent[0].value, 40);
+
+        assert_eq!(index.unspent_by(&[2; 32]).len(), 2);
+        assert!(index.sent_by(&[2; 32]).is_empty());
+        assert!(index.received_by(&[3; 32]).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+// Base units in one coin
+pub const COIN: u64 = 100_000_000;
+// No output, and no sum of outputs, can be worth more than this
+pub const MAX_MONEY: Amount = Amount(21_000_000 * COIN);
+
+// A number of base units. Arithmetic is checked, so overflows surface as
+// None rather than wrapping.
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default, Debug)]
+pub struct Amount(pub u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub fn from_coins(coins: u64) -> Option<Amount> {
+        coins.checked_mul(COIN).map(Amount)
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    // None if the sum overflows
+    pub fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Option<Amount> {
+        amounts
+            .into_iter()
+            .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+    }
+
+    pub fn is_valid(&self) -> bool {
+        *self <= MAX_MONEY
+    }
+}
+
+impl From<u64> for Amount {
+    fn from(units: u64) -> Self {
+        Amount(units)
+    }
+}
+
+impl PartialEq<u64> for Amount {
+    fn eq(&self, other: &u64) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialOrd<u64> for Amount {
+    fn partial_cmp(&self, other: &u64) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(other)
+    }
+}
+
+// In coins, with all 8 decimal places
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:08}", self.0 / COIN, self.0 % COIN)
+    }
+}
+
+// Takes coins with up to 8 decimal places, e.g. "1.5"
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid amount {s}");
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if fraction.len() > 8 || !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let whole: u64 = whole.parse().map_err(|_| invalid())?;
+        let fraction: u64 = format!("{fraction:0<8}").parse().map_err(|_| invalid())?;
+        Amount::from_coins(whole)
+            .and_then(|amount| amount.checked_add(Amount(fraction)))
+            .ok_or_else(invalid)
+    }
+}
+
+// Written as the number of base units
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u64::deserialize(deserializer).map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert_eq!(Amount(5).checked_add(Amount(7)), Some(Amount(12)));
+        assert_eq!(Amount(u64::MAX).checked_add(Amount(1)), None);
+        assert_eq!(Amount(5).checked_sub(Amount(6)), None);
+        assert_eq!(Amount::checked_sum([Amount(1), Amount(2)]), Some(Amount(3)));
+        assert_eq!(Amount::checked_sum([Amount(u64::MAX), Amount(1)]), None);
+        assert!(MAX_MONEY.is_valid());
+        assert!(!Amount(MAX_MONEY.0 + 1).is_valid());
+    }
+
+    #[test]
+    fn test_format_and_parse() {
+        assert_eq!(Amount(150_000_000).to_string(), "1.50000000");
+        assert_eq!(Amount(1).to_string(), "0.00000001");
+        assert_eq!("1.5".parse(), Ok(Amount(150_000_000)));
+        assert_eq!("0.00000001".parse(), Ok(Amount(1)));
+        assert_eq!("21".parse(), Ok(Amount(21 * COIN)));
+        assert!("0.000000001".parse::<Amount>().is_err());
+        assert!("1.-5".parse::<Amount>().is_err());
+        assert!("abc".parse::<Amount>().is_err());
+    }
+}
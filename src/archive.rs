@@ -0,0 +1,200 @@
+// A portable archive of a chain's blocks, for copying a chain between
+// machines or keeping one as a test fixture. Unlike the chain file in the
+// data directory, it says what it is and which network it is for, and every
+// block carries a checksum.
+//
+// All integers are big-endian:
+//
+//   magic           4 bytes, "RBCA"
+//   version         u32
+//   network magic   4 bytes
+//   block count     u64
+//   per block       u32 length, the first 4 bytes of the SHA-256 of the
+//                   data, then the data, the block's bincode encoding
+//
+// Blocks are in height order from genesis.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::ArchiveError;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"RBCA";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+// Writes every block of the chain, returning how many
+pub fn export<W: Write>(blockchain: &Blockchain, mut writer: W) -> io::Result<u64> {
+    let count = blockchain.height() + 1;
+    writer.write_all(&ARCHIVE_MAGIC)?;
+    writer.write_all(&ARCHIVE_VERSION.to_be_bytes())?;
+    writer.write_all(&blockchain.params().network.magic())?;
+    writer.write_all(&count.to_be_bytes())?;
+
+    for block in blockchain.iter() {
+        let data = block.as_bincode();
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&Sha256::digest(&data)[..4])?;
+        writer.write_all(&data)?;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// Adds the archive's blocks to the chain, validating each as if it had been
+// received from a peer. Blocks the chain already has are skipped, but they
+// have to be the same. Returns how many blocks were added.
+pub fn import<R: Read>(blockchain: &mut Blockchain, mut reader: R) -> Result<u64, ArchiveError> {
+    let mut header = [0u8; 20];
+    read_exact(&mut reader, &mut header)?;
+    if header[..4] != ARCHIVE_MAGIC {
+        return Err(ArchiveError::NotAnArchive);
+    }
+    let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    if version != ARCHIVE_VERSION {
+        return Err(ArchiveError::UnsupportedVersion(version));
+    }
+    if header[8..12] != blockchain.params().network.magic() {
+        return Err(ArchiveError::WrongNetwork);
+    }
+    let count = u64::from_be_bytes(header[12..20].try_into().unwrap());
+
+    let mut added = 0;
+    for height in 0..count {
+        let block = read_block(&mut reader, height, blockchain.params().max_block_size)?;
+        if block.header.index != height {
+            return Err(ArchiveError::Corrupt(height));
+        }
+        if let Some(existing) = blockchain.get_block_by_height(height) {
+            if existing.digest != block.digest {
+                return Err(ArchiveError::Diverges(height));
+            }
+            continue;
+        }
+        blockchain
+            .add_block(block)
+            .map_err(|e| ArchiveError::InvalidBlock(height, e))?;
+        added += 1;
+    }
+    Ok(added)
+}
+
+fn read_block<R: Read>(
+    reader: &mut R,
+    height: u64,
+    max_size: usize,
+) -> Result<Block, ArchiveError> {
+    let mut prefix = [0u8; 8];
+    read_exact(reader, &mut prefix)?;
+    let len = u32::from_be_bytes(prefix[..4].try_into().unwrap()) as usize;
+    if len > max_size {
+        return Err(ArchiveError::Corrupt(height));
+    }
+    let mut data = vec![0u8; len];
+    read_exact(reader, &mut data)?;
+    if Sha256::digest(&data)[..4] != prefix[4..] {
+        return Err(ArchiveError::Corrupt(height));
+    }
+    Block::from_bincode(&data).map_err(|_| ArchiveError::Corrupt(height))
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), ArchiveError> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => ArchiveError::Truncated,
+        _ => ArchiveError::Io(e),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::utils::mine;
+
+    fn chain(blocks: u64) -> Blockchain {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..blocks {
+            let mut block = blockchain.next_block();
+            mine(&mut block, [1; 32], blockchain.get_block_reward());
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let blockchain = chain(3);
+        let mut archive = Vec::new();
+        assert_eq!(export(&blockchain, &mut archive).unwrap(), 4);
+        assert_eq!(&archive[..4], b"RBCA");
+
+        let mut imported = Blockchain::new(ChainParams::regtest());
+        assert_eq!(import(&mut imported, archive.as_slice()).unwrap(), 3);
+        assert_eq!(imported.prev_hash(), blockchain.prev_hash());
+        assert_eq!(
+            imported.utxos.balance([1; 32]),
+            blockchain.utxos.balance([1; 32])
+        );
+
+        // Importing again, or into a chain that is ahead, adds nothing
+        assert_eq!(import(&mut imported, archive.as_slice()).unwrap(), 0);
+        let mut block = imported.next_block();
+        mine(&mut block, [1; 32], imported.get_block_reward());
+        imported.add_block(block).unwrap();
+        assert_eq!(import(&mut imported, archive.as_slice()).unwrap(), 0);
+        assert_eq!(imported.height(), 4);
+    }
+
+    #[test]
+    fn test_import_rejects_bad_archives() {
+        let mut archive = Vec::new();
+        export(&chain(2), &mut archive).unwrap();
+        let import_into_new = |archive: &[u8]| {
+            let mut blockchain = Blockchain::new(ChainParams::regtest());
+            import(&mut blockchain, archive)
+        };
+
+        let mut corrupted = archive.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 0xff;
+        assert!(matches!(
+            import_into_new(&corrupted),
+            Err(ArchiveError::Corrupt(2))
+        ));
+        assert!(matches!(
+            import_into_new(&archive[..archive.len() - 1]),
+            Err(ArchiveError::Truncated)
+        ));
+        assert!(matches!(
+            import_into_new(b"not an archive"),
+            Err(ArchiveError::Truncated)
+        ));
+        assert!(matches!(
+            import_into_new(b"not an archive at all"),
+            Err(ArchiveError::NotAnArchive)
+        ));
+
+        let mut newer = archive.clone();
+        newer[7] = 2;
+        assert!(matches!(
+            import_into_new(&newer),
+            Err(ArchiveError::UnsupportedVersion(2))
+        ));
+
+        let mut mainnet = Blockchain::new(ChainParams::mainnet());
+        assert!(matches!(
+            import(&mut mainnet, archive.as_slice()),
+            Err(ArchiveError::WrongNetwork)
+        ));
+
+        // A chain that went another way
+        let mut other = Blockchain::new(ChainParams::regtest());
+        let mut block = other.next_block();
+        mine(&mut block, [2; 32], other.get_block_reward());
+        other.add_block(block).unwrap();
+        assert!(matches!(
+            import(&mut other, archive.as_slice()),
+            Err(ArchiveError::Diverges(1))
+        ));
+    }
+}
@@ -0,0 +1,210 @@
+// BLAKE3 with the default 32 byte output, for proof of work. Only the
+// one-shot hash is needed: inputs are split into 1024 byte chunks, each
+// hashed on its own, and the chunks' chaining values are merged pairwise up
+// a binary tree whose left subtrees are always full.
+
+const IV: [u32; 8] = [
+    0x6a09_e667,
+    0xbb67_ae85,
+    0x3c6e_f372,
+    0xa54f_f53a,
+    0x510e_527f,
+    0x9b05_688c,
+    0x1f83_d9ab,
+    0x5be0_cd19,
+];
+const MSG_PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const BLOCK_LEN: usize = 64;
+const CHUNK_LEN: usize = 1024;
+
+const CHUNK_START: u32 = 1;
+const CHUNK_END: u32 = 2;
+const PARENT: u32 = 4;
+const ROOT: u32 = 8;
+
+pub fn hash(input: &[u8]) -> [u8; 32] {
+    let words = node(input, 0).compress(ROOT);
+    let mut out = [0u8; 32];
+    for (bytes, word) in out.chunks_exact_mut(4).zip(words) {
+        bytes.copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+// The last compression of a node, left undone so the root can add its flag
+struct Output {
+    chaining_value: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn compress(&self, flags: u32) -> [u32; 8] {
+        first_eight(compress(
+            &self.chaining_value,
+            &self.block,
+            self.counter,
+            self.block_len,
+            self.flags | flags,
+        ))
+    }
+}
+
+// `counter` is the index of the input's first chunk
+fn node(input: &[u8], counter: u64) -> Output {
+    if input.len() <= CHUNK_LEN {
+        return chunk(input, counter);
+    }
+    // The largest power of two number of chunks leaving some input over
+    let full_chunks = (input.len() - 1) / CHUNK_LEN;
+    let left_len = (1 << full_chunks.ilog2()) * CHUNK_LEN;
+    let left = node(&input[..left_len], counter).compress(0);
+    let right = node(&input[left_len..], counter + (left_len / CHUNK_LEN) as u64).compress(0);
+
+    let mut block = [0u32; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Output {
+        chaining_value: IV,
+        block,
+        counter: 0,
+        block_len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+fn chunk(input: &[u8], counter: u64) -> Output {
+    // An empty input is one empty block
+    let blocks = input.len().div_ceil(BLOCK_LEN).max(1);
+    let mut chaining_value = IV;
+    let mut flags = CHUNK_START;
+    for i in 0..blocks {
+        let bytes = &input[i * BLOCK_LEN..input.len().min((i + 1) * BLOCK_LEN)];
+        let mut padded = [0u8; BLOCK_LEN];
+        padded[..bytes.len()].copy_from_slice(bytes);
+        let mut block = [0u32; 16];
+        for (word, bytes) in block.iter_mut().zip(padded.chunks_exact(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+
+        if i == blocks - 1 {
+            return Output {
+                chaining_value,
+                block,
+                counter,
+                block_len: bytes.len() as u32,
+                flags: flags | CHUNK_END,
+            };
+        }
+        chaining_value = first_eight(compress(
+            &chaining_value,
+            &block,
+            counter,
+            BLOCK_LEN as u32,
+            flags,
+        ));
+        flags = 0;
+    }
+    unreachable!()
+}
+
+fn compress(
+    chaining_value: &[u32; 8],
+    block: &[u32; 16],
+    counter: u64,
+    block_len: u32,
+    flags: u32,
+) -> [u32; 16] {
+    let mut state = [
+        chaining_value[0],
+        chaining_value[1],
+        chaining_value[2],
+        chaining_value[3],
+        chaining_value[4],
+        chaining_value[5],
+        chaining_value[6],
+        chaining_value[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        block_len,
+        flags,
+    ];
+    let mut m = *block;
+    for round in 0..7 {
+        g(&mut state, 0, 4, 8, 12, m[0], m[1]);
+        g(&mut state, 1, 5, 9, 13, m[2], m[3]);
+        g(&mut state, 2, 6, 10, 14, m[4], m[5]);
+        g(&mut state, 3, 7, 11, 15, m[6], m[7]);
+        g(&mut state, 0, 5, 10, 15, m[8], m[9]);
+        g(&mut state, 1, 6, 11, 12, m[10], m[11]);
+        g(&mut state, 2, 7, 8, 13, m[12], m[13]);
+        g(&mut state, 3, 4, 9, 14, m[14], m[15]);
+        if round < 6 {
+            m = MSG_PERMUTATION.map(|i| m[i]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= chaining_value[i];
+    }
+    state
+}
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, mx: u32, my: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(mx);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(my);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn first_eight(words: [u32; 16]) -> [u32; 8] {
+    words[..8].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the BLAKE3 test vectors, whose inputs are the bytes 0, 1, ...,
+    // 250 repeated
+    #[test]
+    fn test_vectors() {
+        let input: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+        let vectors = [
+            (
+                0,
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            ),
+            (
+                1024,
+                "42214739f095a406f3fc83deb889744ac00df831c10daa55189b5d121c855af7",
+            ),
+            (
+                1025,
+                "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444",
+            ),
+            (
+                2048,
+                "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a",
+            ),
+        ];
+        for (len, expected) in vectors {
+            assert_eq!(hex::encode(hash(&input[..len])), expected);
+        }
+        assert_eq!(
+            hex::encode(hash(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+    }
+}
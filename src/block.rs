@@ -1,74 +1,169 @@
-use crate::errors::{BlockValidationError, TransactionError};
+use crate::amount::Amount;
+use crate::clock::{Clock, SystemClock};
+use crate::consensus::{self, ProofOfWork};
+use crate::deployments::VERSIONBITS_TOP_BITS;
+use crate::errors::{BlockValidationError, DecodeError, JsonError, TransactionError};
+use crate::hash::{Hash256, OutPoint};
+use crate::json::{self, impl_serde_struct};
+use crate::merkle;
+use crate::params::ChainParams;
+use crate::signature;
 use crate::transaction::Transaction;
 use crate::utils;
 use bincode::{Decode, Encode};
-use sha2::{Digest, Sha256};
+use num_bigint::BigUint;
 use std::fmt;
-use std::time::{SystemTime, UNIX_EPOCH};
 
-// TODO: use custom Digest type implementing From
+// The part of a block covered by its digest. Transactions are committed to
+// through the merkle root, so a header alone is enough to check inclusion
+// proofs.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    // Top bits VERSIONBITS_TOP_BITS, and the bits of the deployments the
+    // miner signals for
+    pub version: u32,
+    pub index: u64,
+    pub timestamp: u64,
+    pub prev_hash: Hash256,
+    pub merkle_root: [u8; 32],
+    // The target in compact form (see consensus::target_from_bits)
+    pub bits: u32,
+    pub nonce: u64,
+}
 
-#[derive(Encode)]
-struct BlockNoDigest<'a> {
+impl_serde_struct!(BlockHeader {
+    version: u32,
     index: u64,
     timestamp: u64,
-    prev_hash: &'a [u8; 32],
-    target: &'a [u8; 32],
-    transactions: &'a Vec<Transaction>,
+    prev_hash: Hash256,
+    merkle_root: [u8; 32] => hex,
+    bits: u32,
     nonce: u64,
+});
+
+impl BlockHeader {
+    pub fn hash(&self) -> Hash256 {
+        Hash256(consensus::Header::from(self).hash())
+    }
+
+    pub fn pow_hash(&self, pow: &impl ProofOfWork) -> [u8; 32] {
+        consensus::Header::from(self).pow_hash(pow)
+    }
+
+    // Invalid bits give a zero target, which no hash meets
+    pub fn target(&self) -> [u8; 32] {
+        consensus::target_from_bits(self.bits).unwrap_or([0; 32])
+    }
+
+    pub fn work(&self) -> BigUint {
+        target_work(&self.target())
+    }
+
+    // The checks that need neither the transactions nor the chain state,
+    // with `pow` as the chain's proof of work
+    pub fn validate(&self, pow: &impl ProofOfWork) -> Result<(), BlockValidationError> {
+        self.validate_at(pow, SystemClock.now())
+    }
+
+    // With `now` as the current time
+    pub fn validate_at(
+        &self,
+        pow: &impl ProofOfWork,
+        now: u64,
+    ) -> Result<(), BlockValidationError> {
+        if !consensus::meets_target(&self.pow_hash(pow), &self.target()) {
+            return Err(BlockValidationError::InvalidProofOfWork);
+        }
+        if self.timestamp > now {
+            return Err(BlockValidationError::TimestampInFuture);
+        }
+        Ok(())
+    }
+}
+
+// The expected number of hashes needed to find a block with `target`,
+// 2^256 / (target + 1)
+pub fn target_work(target: &[u8; 32]) -> BigUint {
+    (BigUint::from(1u8) << 256u32) / (BigUint::from_bytes_be(target) + 1u8)
 }
 
-#[derive(Encode, Decode)]
+impl From<&BlockHeader> for consensus::Header {
+    fn from(header: &BlockHeader) -> Self {
+        consensus::Header {
+            version: header.version,
+            index: header.index,
+            timestamp: header.timestamp,
+            prev_hash: header.prev_hash.0,
+            merkle_root: header.merkle_root,
+            bits: header.bits,
+            nonce: header.nonce,
+        }
+    }
+}
+
+#[derive(Encode, Decode, Clone)]
 pub struct Block {
-    pub digest: [u8; 32],
-    pub index: u64,
-    pub timestamp: u64,
-    pub prev_hash: [u8; 32],
-    pub target: [u8; 32],
+    pub digest: Hash256,
+    pub header: BlockHeader,
     pub transactions: Vec<Transaction>,
-    pub nonce: u64,
 }
 
+impl_serde_struct!(Block {
+    digest: Hash256,
+    header: BlockHeader,
+    transactions: Vec<Transaction>,
+});
+
 impl Block {
     pub fn new(
         index: u64,
-        prev_hash: [u8; 32],
-        target: [u8; 32],
+        prev_hash: impl Into<Hash256>,
+        bits: u32,
         transactions: Vec<Transaction>,
+    ) -> Block {
+        Block::new_with_clock(index, prev_hash, bits, transactions, &SystemClock)
+    }
+
+    // Timestamped by `clock`
+    pub fn new_with_clock(
+        index: u64,
+        prev_hash: impl Into<Hash256>,
+        bits: u32,
+        transactions: Vec<Transaction>,
+        clock: &dyn Clock,
     ) -> Block {
         let mut block = Block {
-            digest: [0; 32],
-            index,
-            timestamp: utils::unix_timestamp(),
-            prev_hash,
-            target,
+            digest: Hash256::default(),
+            header: BlockHeader {
+                version: VERSIONBITS_TOP_BITS,
+                index,
+                timestamp: clock.now(),
+                prev_hash: prev_hash.into(),
+                merkle_root: [0; 32],
+                bits,
+                nonce: 0,
+            },
             transactions,
-            nonce: 0,
         };
-        block.update_digest();
+        block.update_merkle_root();
         block
     }
 
-    pub fn from_bincode(data: &[u8]) -> Block {
-        bincode::decode_from_slice(data, bincode::config::standard())
-            .unwrap()
-            .0
+    pub fn from_bincode(data: &[u8]) -> Result<Block, DecodeError> {
+        utils::decode_with_limit::<_, { utils::MAX_DECODE_SIZE }>(data)
+    }
+
+    pub fn to_json(&self) -> String {
+        json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(data: &str) -> Result<Block, JsonError> {
+        json::from_str(data)
     }
 
+    // The mainnet genesis block
     pub fn genesis() -> Block {
-        Block {
-            digest: hex::decode("00094ec2294b08eff5da9c713f9d7cbdb5b84243b0e03f1842bdfe7cc9a66fcd")
-                .unwrap()
-                .as_slice().try_into().unwrap(),
-            index: 0,
-            timestamp: 1747162780,
-            prev_hash: [0; 32],
-            target: hex::decode(
-                "000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-            ).unwrap().as_slice().try_into().unwrap(),
-            transactions: Vec::new(),
-            nonce: 8376,
-        }
+        ChainParams::mainnet().genesis()
     }
 
     #[allow(dead_code)]
@@ -76,38 +171,31 @@ impl Block {
         bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
     }
 
-    fn as_bincode_no_digest(&self) -> Vec<u8> {
-        let no_digest = BlockNoDigest {
-            index: self.index,
-            timestamp: self.timestamp,
-            prev_hash: &self.prev_hash,
-            target: &self.target,
-            transactions: &self.transactions,
-            nonce: self.nonce,
-        };
-
-        bincode::encode_to_vec(no_digest, bincode::config::standard()).unwrap()
+    pub fn merkle_root(&self) -> [u8; 32] {
+        let txids: Vec<[u8; 32]> = self.transactions.iter().map(|tx| tx.id.0).collect();
+        merkle::merkle_root(&txids)
     }
 
-    pub fn hash(&self) -> [u8; 32] {
-        let block_data = self.as_bincode_no_digest();
-
-        let mut hasher = Sha256::new();
-        hasher.update(block_data);
-        hasher.finalize().as_slice().try_into().unwrap()
+    // Only the header is hashed; the transactions are covered by its merkle
+    // root
+    pub fn hash(&self) -> Hash256 {
+        self.header.hash()
     }
 
     pub fn update_digest(&mut self) {
         self.digest = self.hash();
     }
 
-    pub fn update_nonce_and_timestamp(&mut self) {
-        self.nonce += 1;
-        if self.nonce % 1000 == 0 {
-            self.timestamp = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+    // Must be called whenever the transactions change
+    pub fn update_merkle_root(&mut self) {
+        self.header.merkle_root = self.merkle_root();
+        self.update_digest();
+    }
+
+    pub fn update_nonce_and_timestamp(&mut self, clock: &dyn Clock) {
+        self.header.nonce += 1;
+        if self.header.nonce.is_multiple_of(1000) {
+            self.header.timestamp = clock.now();
         }
 
         self.update_digest();
@@ -116,57 +204,116 @@ impl Block {
     //TODO: validate transaction as added
     pub fn add_tx(&mut self, tx: Transaction) {
         self.transactions.push(tx);
-        self.update_digest();
+        self.update_merkle_root();
     }
 
-    pub fn add_coinbase_tx(&mut self, pkhash: [u8; 32], reward: u64) {
-        self.transactions
-            .insert(0, Transaction::new_coinbase(pkhash, reward, self.index));
-        self.update_digest();
+    pub fn add_coinbase_tx(&mut self, pkhash: [u8; 32], reward: impl Into<Amount>) {
+        self.add_coinbase_tx_with_data(pkhash, reward, Vec::new());
+    }
+
+    // `data` is at most MAX_COINBASE_DATA bytes
+    pub fn add_coinbase_tx_with_data(
+        &mut self,
+        pkhash: [u8; 32],
+        reward: impl Into<Amount>,
+        data: Vec<u8>,
+    ) {
+        self.transactions.insert(
+            0,
+            Transaction::new_coinbase_with_data(
+                pkhash,
+                reward,
+                self.header.index,
+                data,
+                self.header.timestamp,
+            ),
+        );
+        self.update_merkle_root();
+    }
+
+    pub fn validate(
+        &self,
+        chain_id: &[u8; 32],
+        pow: &impl ProofOfWork,
+    ) -> Result<(), BlockValidationError> {
+        self.validate_at(Some(chain_id), pow, SystemClock.now())
     }
 
-    pub fn validate(&self) -> Result<(), BlockValidationError> {
-        if self.hash() >= self.target {
+    // For blocks below a checkpoint, whose transactions are known to be valid
+    pub fn validate_without_signatures(
+        &self,
+        pow: &impl ProofOfWork,
+    ) -> Result<(), BlockValidationError> {
+        self.validate_at(None, pow, SystemClock.now())
+    }
+
+    // With `now` as the current time. Signatures are only checked given a
+    // chain id.
+    pub fn validate_at(
+        &self,
+        chain_id: Option<&[u8; 32]>,
+        pow: &impl ProofOfWork,
+        now: u64,
+    ) -> Result<(), BlockValidationError> {
+        if !consensus::meets_target(&self.header.pow_hash(pow), &self.header.target()) {
             return Err(BlockValidationError::InvalidProofOfWork);
         }
+        self.validate_unsealed_at(chain_id, now)
+    }
+
+    // Everything but the seal, which is the proof of work or whatever else
+    // the chain's consensus engine asks for (see engine.rs)
+    pub fn validate_unsealed_at(
+        &self,
+        chain_id: Option<&[u8; 32]>,
+        now: u64,
+    ) -> Result<(), BlockValidationError> {
+        if self.header.timestamp > now {
+            return Err(BlockValidationError::TimestampInFuture);
+        }
         if self.digest != self.hash() {
             return Err(BlockValidationError::HashDigestMismatch);
         }
-        if self.timestamp
-            > SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-        {
-            return Err(BlockValidationError::TimestampInFuture);
+        if self.header.merkle_root != self.merkle_root() {
+            return Err(BlockValidationError::InvalidMerkleRoot);
         }
 
         if !self.check_double_spend() {
             return Err(BlockValidationError::InvalidTransactions(TransactionError::DoubleSpend));
         }
 
-        self.validate_transactions()
+        self.validate_transactions(chain_id, now)
             .map_err(BlockValidationError::InvalidTransactions)?;
 
         Ok(())
     }
 
-    fn validate_transactions(&self) -> Result<(), TransactionError> {
+    fn validate_transactions(
+        &self,
+        chain_id: Option<&[u8; 32]>,
+        now: u64,
+    ) -> Result<(), TransactionError> {
         if self.transactions.is_empty() {
             return Err(TransactionError::InvalidCoinbase);
         }
-        self.transactions[0].verify_coinbase()?;
+        self.transactions[0].verify_coinbase(self.header.index)?;
+
+        let mut checks = Vec::new();
         for tx in &self.transactions[1..] {
-            tx.verify()?;
+            tx.verify_without_signatures_at(now)?;
+            if let Some(chain_id) = chain_id {
+                tx.signature_checks(chain_id, &mut checks)?;
+            }
         }
-        Ok(())
+        // Verified together so they can be spread over several threads
+        signature::verify_batch(&checks)
     }
 
-    fn get_spent_utxos(&self) -> Vec<([u8; 32], u16)> {
+    fn get_spent_utxos(&self) -> Vec<OutPoint> {
         let mut spent_utxos = Vec::new();
         for tx in &self.transactions[1..] {
             for input in &tx.inputs {
-                spent_utxos.push((input.txid, input.output));
+                spent_utxos.push(input.outpoint());
             }
         }
         spent_utxos
@@ -182,11 +329,11 @@ impl Block {
 
 impl fmt::Display for Block {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Block #{}", self.index)?;
-        writeln!(f, "  Timestamp: {}", self.timestamp)?;
-        writeln!(f, "  Previous Hash: {}", hex::encode(self.prev_hash))?;
-        writeln!(f, "  Nonce: {}", self.nonce)?;
-        writeln!(f, "  Hash: {}", hex::encode(self.digest))?;
+        writeln!(f, "Block #{}", self.header.index)?;
+        writeln!(f, "  Timestamp: {}", self.header.timestamp)?;
+        writeln!(f, "  Previous Hash: {}", self.header.prev_hash)?;
+        writeln!(f, "  Nonce: {}", self.header.nonce)?;
+        writeln!(f, "  Hash: {}", self.digest)?;
         writeln!(f, "  Transactions:")?;
         for tx in &self.transactions {
             let indented = tx
@@ -201,94 +348,103 @@ impl fmt::Display for Block {
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
+    use crate::consensus::PowAlgorithm;
     use crate::transaction::{Transaction, TxInput, TxOutput};
 
+    #[test]
+    fn test_genesis_digest() {
+        let genesis = Block::genesis();
+        assert_eq!(genesis.digest, genesis.hash());
+        assert!(consensus::meets_target(
+            genesis.digest.as_bytes(),
+            &genesis.header.target()
+        ));
+    }
+
+    #[test]
+    fn test_header_work() {
+        let mut header = Block::genesis().header;
+        header.bits = 0x2100_ffff;
+        assert_eq!(header.work(), BigUint::from(1u8));
+        header.bits = 0x207f_ffff;
+        assert_eq!(header.work(), BigUint::from(2u8));
+        header.bits = 0x1f01_0000;
+        assert_eq!(header.work(), BigUint::from(0xffffu32));
+    }
+
     #[test]
     fn test_invalid_pow() {
-        let block = Block::new(
-            0,
-            [0; 32],
-            hex::decode("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
-                .unwrap()
-                .as_slice()
-                .try_into()
-                .unwrap(),
-            Vec::new(),
-        );
+        let block = Block::new(0, [0; 32], 0x1f0f_ffff, Vec::new());
         assert_eq!(
-            block.validate(),
+            block.validate(&Block::genesis().digest.0, &PowAlgorithm::Sha256),
             Err(BlockValidationError::InvalidProofOfWork)
         );
     }
 
     #[test]
     fn test_invalid_digest() {
-        let mut block = Block::new(
-            0,
-            [0; 32],
-            hex::decode("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
-                .unwrap()
-                .as_slice()
-                .try_into()
-                .unwrap(),
-            Vec::new(),
-        );
+        let mut block = Block::new(0, [0; 32], 0x1f0f_ffff, Vec::new());
         utils::mine(&mut block, [0; 32], 0);
-        block.digest = [0; 32];
+        block.digest = Hash256([0; 32]);
         assert_eq!(
-            block.validate(),
+            block.validate(&Block::genesis().digest.0, &PowAlgorithm::Sha256),
             Err(BlockValidationError::HashDigestMismatch)
         );
     }
 
     #[test]
     fn test_invalid_timestamp() {
-        let mut block = Block::new(
-            0,
-            [0; 32],
-            hex::decode("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
-                .unwrap()
-                .as_slice()
-                .try_into()
-                .unwrap(),
-            Vec::new(),
-        );
-        block.timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            + 1000;
-
-        while !utils::hash_less_than_target(&block.digest, &block.target) {
-            block.nonce += 1;
+        let clock = MockClock::new(1_000_000);
+        let mut block = Block::new_with_clock(0, [0; 32], 0x1f0f_ffff, Vec::new(), &clock);
+        block.add_coinbase_tx([1; 32], 50);
+        assert_eq!(block.header.timestamp, 1_000_000);
+
+        while !consensus::meets_target(block.digest.as_bytes(), &block.header.target()) {
+            block.header.nonce += 1;
             block.update_digest();
         }
 
         assert_eq!(
-            block.validate(),
+            block.validate_at(None, &PowAlgorithm::Sha256, clock.now()),
+            Ok(())
+        );
+        assert_eq!(
+            block.validate_at(None, &PowAlgorithm::Sha256, clock.now() - 1),
             Err(BlockValidationError::TimestampInFuture)
         );
     }
 
     #[test]
-    fn test_invalid_transactions() {
-        let mut block = Block::new(
-            0,
-            [0; 32],
-            hex::decode("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
-                .unwrap()
-                .as_slice()
-                .try_into()
-                .unwrap(),
-            Vec::new(),
+    fn test_transactions_not_matching_header() {
+        let genesis = Block::genesis();
+        let mut block = Block::new(1, genesis.digest, genesis.header.bits, Vec::new());
+        utils::mine(&mut block, [0; 32], 0);
+        assert_eq!(
+            block.validate(&genesis.digest.0, &PowAlgorithm::Sha256),
+            Ok(())
+        );
+
+        // the header, and so the digest, still commit to the old transactions
+        block.transactions[0] = Transaction::new_coinbase([1; 32], 0, 1);
+        assert_eq!(block.digest, block.hash());
+        assert_eq!(
+            block.validate(&genesis.digest.0, &PowAlgorithm::Sha256),
+            Err(BlockValidationError::InvalidMerkleRoot)
         );
+    }
+
+    #[test]
+    fn test_invalid_transactions() {
+        let mut block = Block::new(0, [0; 32], 0x1f0f_ffff, Vec::new());
         let tx = Transaction::new(vec![], vec![]);
         block.add_tx(tx);
         utils::mine(&mut block, [0; 32], 0);
         assert_eq!(
-            block.validate(),
+            block.validate(&Block::genesis().digest.0, &PowAlgorithm::Sha256),
             Err(BlockValidationError::InvalidTransactions(
                 TransactionError::EmptyInputs
             ))
@@ -299,16 +455,10 @@ mod tests {
     fn test_deserialise_block() {
         let mut block = Block::new(
             10,
-            hex::decode("abcd000000000000000000000000000000000000000000000000000000000000")
-                .unwrap()
-                .as_slice()
-                .try_into()
-                .unwrap(),
-            hex::decode("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff")
-                .unwrap()
-                .as_slice()
-                .try_into()
+            "abcd000000000000000000000000000000000000000000000000000000000000"
+                .parse::<Hash256>()
                 .unwrap(),
+            0x1f0f_ffff,
             vec![Transaction::new(
                 vec![TxInput::new_unsigned([1; 32], 0)],
                 vec![TxOutput::new(50, [2; 32])],
@@ -317,14 +467,10 @@ mod tests {
         utils::mine(&mut block, [0; 32], 0);
 
         let serialised = block.as_bincode();
-        let deserialised = Block::from_bincode(&serialised);
+        let deserialised = Block::from_bincode(&serialised).unwrap();
 
-        assert_eq!(block.index, deserialised.index);
-        assert_eq!(block.prev_hash, deserialised.prev_hash);
-        assert_eq!(block.target, deserialised.target);
+        assert_eq!(block.header, deserialised.header);
         assert_eq!(block.transactions.len(), deserialised.transactions.len());
-        assert_eq!(block.timestamp, deserialised.timestamp);
-        assert_eq!(block.nonce, deserialised.nonce);
 
         assert_eq!(deserialised.transactions[0].id, block.transactions[0].id);
         assert_eq!(
@@ -344,4 +490,88 @@ mod tests {
             block.transactions[0].outputs[0].pkhash
         );
     }
+
+    #[test]
+    fn test_json_round_trip() {
+        let wallet = crate::wallet::Wallet::new();
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0)],
+            vec![TxOutput::new(50, [2; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+
+        let genesis = Block::genesis();
+        let mut block = Block::new(1, genesis.digest, genesis.header.bits, vec![tx]);
+        utils::mine(&mut block, [0; 32], 0);
+
+        let json = block.to_json();
+        assert!(json.contains(&format!("\"digest\":\"{}\"", hex::encode(block.digest))));
+
+        let decoded = Block::from_json(&json).unwrap();
+        assert_eq!(decoded.as_bincode(), block.as_bincode());
+        assert_eq!(
+            decoded.validate(&genesis.digest.0, &PowAlgorithm::Sha256),
+            Ok(())
+        );
+        assert_eq!(decoded.to_json(), json);
+    }
+
+    #[test]
+    fn test_invalid_json_block() {
+        let json = Block::genesis().to_json();
+        let digest = Block::genesis().digest.to_string();
+        assert!(Block::from_json(&json.replacen("\"digest\"", "\"hash\"", 1)).is_err());
+        assert!(Block::from_json(&json.replace(&digest, &digest[1..])).is_err());
+        assert!(Block::from_json(&json[1..]).is_err());
+    }
+
+    #[test]
+    fn test_decode_malformed_bytes() {
+        let mut block = Block::genesis();
+        block.add_tx(Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0)],
+            vec![TxOutput::new(50, [2; 32])],
+        ));
+        let encoded = block.as_bincode();
+
+        // xorshift, so failures can be reproduced
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..2000 {
+            let len = next() as usize % 512;
+            let random: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let _ = Block::from_bincode(&random);
+            let _ = Transaction::from_bincode(&random);
+
+            let mut mutated = encoded.clone();
+            for _ in 0..1 + next() % 4 {
+                let i = next() as usize % mutated.len();
+                mutated[i] = next() as u8;
+            }
+            mutated.truncate(next() as usize % (encoded.len() + 1));
+            let _ = Block::from_bincode(&mutated);
+        }
+
+        // a length prefix claiming far more transactions than could fit
+        let mut huge = block.header.hash().0.to_vec();
+        huge.extend(bincode::encode_to_vec(&block.header, bincode::config::standard()).unwrap());
+        huge.extend([0xfd, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(
+            Block::from_bincode(&huge).err(),
+            Some(DecodeError::TooLarge)
+        );
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(
+            Block::from_bincode(&trailing).err(),
+            Some(DecodeError::TrailingBytes)
+        );
+        assert_eq!(Block::from_bincode(&[]).err(), Some(DecodeError::Invalid));
+    }
 }
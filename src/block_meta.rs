@@ -0,0 +1,178 @@
+// What a node saw of each block as it came in, for looking into how blocks
+// propagate and how long they take to check. It differs from node to node
+// and is no part of consensus, so it is kept apart from the blocks.
+//
+// On disk, records are appended as the big-endian u32 length of the
+// payload, the payload (the block's digest, then the bincode encoding of
+// its BlockMeta) and the first 4 bytes of the payload's SHA-256. A record
+// cut short by a crash fails its checksum and is dropped when the file is
+// opened, along with anything after it. A later record for the same block
+// replaces an earlier one.
+
+use crate::hash::Hash256;
+use crate::json::impl_serde_struct;
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BlockMeta {
+    // Unix time in milliseconds
+    pub received: u64,
+    // The peer it came from, or None if it was submitted to the node
+    pub source: Option<String>,
+    // Microseconds spent checking and connecting it
+    pub validation_us: u64,
+    // Bytes, serialized
+    pub size: u64,
+}
+
+impl_serde_struct!(BlockMeta {
+    received: u64,
+    source: Option<String>,
+    validation_us: u64,
+    size: u64,
+});
+
+#[derive(Default)]
+pub struct BlockMetaStore {
+    entries: HashMap<Hash256, BlockMeta>,
+    // None keeps the entries in memory only
+    file: Option<File>,
+}
+
+impl BlockMetaStore {
+    pub fn new() -> BlockMetaStore {
+        BlockMetaStore::default()
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<BlockMetaStore> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let total_len = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut entries = HashMap::new();
+
+        let mut offset = 0;
+        while offset + 4 <= total_len {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as u64;
+            if offset + 4 + len + 4 > total_len {
+                break;
+            }
+            let mut payload = vec![0u8; len as usize + 4];
+            reader.read_exact(&mut payload)?;
+            let (payload, checksum) = payload.split_at(len as usize);
+            if Sha256::digest(payload)[..4] != *checksum {
+                break;
+            }
+            let Some((digest, meta)) = decode_record(payload) else {
+                break;
+            };
+            entries.insert(digest, meta);
+            offset += 4 + len + 4;
+        }
+        drop(reader);
+
+        // Drop a torn record so new ones are appended after the last good one
+        if offset != total_len {
+            file.set_len(offset)?;
+        }
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(BlockMetaStore {
+            entries,
+            file: Some(file),
+        })
+    }
+
+    // Kept in memory even if it can't be written
+    pub fn insert(&mut self, digest: Hash256, meta: BlockMeta) -> io::Result<()> {
+        let result = match &mut self.file {
+            Some(file) => file.write_all(&encode_record(&digest, &meta)),
+            None => Ok(()),
+        };
+        self.entries.insert(digest, meta);
+        result
+    }
+
+    pub fn get(&self, digest: &Hash256) -> Option<&BlockMeta> {
+        self.entries.get(digest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn encode_record(digest: &Hash256, meta: &BlockMeta) -> Vec<u8> {
+    let mut payload = digest.as_bytes().to_vec();
+    payload.extend(bincode::encode_to_vec(meta, bincode::config::standard()).unwrap());
+    let mut record = (payload.len() as u32).to_be_bytes().to_vec();
+    record.extend_from_slice(&payload);
+    record.extend_from_slice(&Sha256::digest(&payload)[..4]);
+    record
+}
+
+fn decode_record(payload: &[u8]) -> Option<(Hash256, BlockMeta)> {
+    let (digest, meta) = payload.split_first_chunk::<32>()?;
+    let (meta, len) = bincode::decode_from_slice(meta, bincode::config::standard()).ok()?;
+    (len == payload.len() - 32).then_some((Hash256(*digest), meta))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn meta(received: u64) -> BlockMeta {
+        BlockMeta {
+            received,
+            source: Some("127.0.0.1:8333".to_string()),
+            validation_us: 1_500,
+            size: 250,
+        }
+    }
+
+    #[test]
+    fn test_reopen_and_torn_record() {
+        let path = env::temp_dir().join(format!("block_meta_{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut store = BlockMetaStore::open(&path).unwrap();
+        store.insert(Hash256([1; 32]), meta(1)).unwrap();
+        store.insert(Hash256([2; 32]), meta(2)).unwrap();
+        store.insert(Hash256([1; 32]), meta(3)).unwrap();
+        drop(store);
+
+        let len = fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&encode_record(&Hash256([3; 32]), &meta(4))[..20])
+            .unwrap();
+        drop(file);
+
+        let mut store = BlockMetaStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(&Hash256([1; 32])), Some(&meta(3)));
+        assert_eq!(store.get(&Hash256([3; 32])), None);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+
+        store.insert(Hash256([3; 32]), meta(5)).unwrap();
+        drop(store);
+        let store = BlockMetaStore::open(&path).unwrap();
+        assert_eq!(store.get(&Hash256([3; 32])), Some(&meta(5)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,110 +1,1254 @@
-use crate::block::Block;
-use crate::errors::{BlockValidationError, TransactionError};
-use crate::utxo::UTXOSet;
-use sha2::{Digest, Sha256};
+use crate::address_index::AddressIndex;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::clock::{Clock, SystemClock};
+use crate::deployments::{Deployment, DeploymentState};
+use crate::engine::{ConsensusEngine, ProofOfWorkEngine};
+use crate::errors::{BlockValidationError, StorageError, TransactionError};
+use crate::events::{BlockStream, ChainEvent, Subscribers};
+use crate::fees::FeeEstimator;
+use crate::filter::BlockFilter;
+use crate::hash::{Hash256, OutPoint, TxId};
+use crate::invalid_blocks::InvalidBlocks;
+use crate::json::impl_serde_struct;
+use crate::log::{self, Level, Span};
+use crate::mempool::Mempool;
+use crate::merkle::MerkleProof;
+use crate::orphans::OrphanPool;
+use crate::params::ChainParams;
+use crate::transaction::{LOCKTIME_THRESHOLD, SUPPORTED_VERSIONS, Transaction, TxOutput};
+use crate::utils;
+use crate::utxo::{UTXOSet, UndoData};
+use num_bigint::BigUint;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+
+// A confirmed transaction paying to or spending from a pkhash, with the
+// amounts moved in each direction
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub txid: TxId,
+    pub height: u64,
+    pub received: u64,
+    pub sent: u64,
+}
+
+// Blocks looked back over for the interval and hash rate in ChainStats
+pub const STATS_WINDOW: u64 = 144;
+
+// Figures about the chain as a whole, for the explorer and RPC. Intervals
+// are in seconds and the hash rate in hashes per second, both over the last
+// STATS_WINDOW blocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainStats {
+    pub height: u64,
+    pub average_block_interval: u64,
+    pub median_block_interval: u64,
+    pub hash_rate: u64,
+    pub transactions: u64,
+    pub utxos: u64,
+    pub supply: Amount,
+}
+
+impl_serde_struct!(ChainStats {
+    height: u64,
+    average_block_interval: u64,
+    median_block_interval: u64,
+    hash_rate: u64,
+    transactions: u64,
+    utxos: u64,
+    supply: Amount,
+});
+
+// What became of a block the chain took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAcceptance {
+    // The block is the new tip
+    ConnectedToTip,
+    // The block builds on one other than the tip, and its branch has no
+    // more work than the chain. It is kept in case the branch overtakes.
+    AcceptedOnSideChain,
+    // The block's branch now has the most work, so the chain switched to
+    // it, first disconnecting `depth` blocks
+    CausedReorg { depth: u64 },
+    // The parent is unknown; the block was kept in the orphan pool
+    OrphanStored,
+}
 
 pub struct Blockchain {
     chain: Vec<Block>,
-    target: [u8; 32],
+    // digest -> height
+    heights: HashMap<Hash256, u64>,
+    // txid -> (height, position in block)
+    tx_index: HashMap<TxId, (u64, usize)>,
+    // One per block, for light clients
+    filters: Vec<BlockFilter>,
+    // Total work of the chain up to and including each block
+    chain_work: Vec<BigUint>,
+    // digest -> outputs the block spent, for rolling back the UTXO set.
+    // Blocks loaded onto a set that was already at the tip get theirs
+    // rebuilt from the blocks before them, see rebuild_undo.
+    undo: HashMap<Hash256, UndoData>,
+    // Off unless enabled, since it keeps every output ever created
+    address_index: Option<AddressIndex>,
+    // Blocks on branches off the chain, whose parents are in the chain or
+    // here too, by digest
+    side_blocks: HashMap<Hash256, Block>,
+    params: ChainParams,
     pub utxos: UTXOSet,
+    // Value of the unspent outputs: all the coinbases created, less fees
+    // they left unclaimed. Never above params.max_money.
+    supply: Amount,
+    pub mempool: Mempool,
+    pub orphans: OrphanPool,
+    invalid_blocks: InvalidBlocks,
+    // Blocks known to lead to the last checkpoint, which vouches for their
+    // signatures
+    checkpointed: HashSet<Hash256>,
+    pub fee_estimator: FeeEstimator,
+    subscribers: Subscribers,
+    clock: Arc<dyn Clock>,
+    engine: Arc<dyn ConsensusEngine>,
 }
 
-//TODO: add difficulty adjustment
-
 impl Blockchain {
-    pub fn new() -> Blockchain {
+    pub fn new(params: ChainParams) -> Blockchain {
+        Blockchain::with_utxos(params, UTXOSet::new())
+    }
+
+    // Starts from the genesis block with the given set, e.g. one kept on
+    // disk, which should be empty
+    pub fn with_utxos(params: ChainParams, utxos: UTXOSet) -> Blockchain {
+        let genesis = params.genesis();
         Blockchain {
-            chain: vec![Block::genesis()],
-            target: hex::decode(
-                "000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
-            ).unwrap().as_slice().try_into().unwrap(),
-            utxos: UTXOSet::new(),
+            heights: HashMap::from([(genesis.digest, 0)]),
+            tx_index: HashMap::new(),
+            filters: vec![BlockFilter::for_block(&genesis)],
+            chain_work: vec![genesis.header.work()],
+            chain: vec![genesis],
+            undo: HashMap::new(),
+            address_index: None,
+            side_blocks: HashMap::new(),
+            supply: utxos.total_value(),
+            utxos,
+            mempool: Mempool::new(),
+            orphans: OrphanPool::new(),
+            invalid_blocks: InvalidBlocks::new(),
+            checkpointed: HashSet::new(),
+            fee_estimator: FeeEstimator::new(),
+            subscribers: Subscribers::default(),
+            clock: Arc::new(SystemClock),
+            engine: Arc::new(ProofOfWorkEngine::new(params.pow)),
+            params,
         }
     }
 
+    // Timestamps new blocks and is what blocks and transactions from the
+    // future are judged against
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    // For what runs apart from the chain, like the miner
+    pub fn shared_clock(&self) -> Arc<dyn Clock> {
+        Arc::clone(&self.clock)
+    }
+
+    // Decides how blocks are sealed, what difficulty they have and what they
+    // may create: proof of work with the network's algorithm and schedules
+    // unless set. Set before adding any blocks.
+    pub fn set_engine(&mut self, engine: Arc<dyn ConsensusEngine>) {
+        self.engine = engine;
+    }
+
+    pub fn engine(&self) -> &Arc<dyn ConsensusEngine> {
+        &self.engine
+    }
+
+    // Events for every block added from now on, in order
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        self.subscribers.subscribe()
+    }
+
+    // The last `depth` blocks and those to come, with their confirmations,
+    // see BlockStream
+    pub fn subscribe_blocks(&mut self, depth: u64) -> BlockStream {
+        let start = (self.height() + 1).saturating_sub(depth);
+        let recent = self.blocks_in_range(start..self.height() + 1).to_vec();
+        BlockStream::new(
+            self.subscribers.subscribe_blocks(),
+            recent,
+            self.height(),
+            depth,
+        )
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    // Reward for the next block, cut short once the supply reaches the cap
     pub fn get_block_reward(&self) -> u64 {
-        50_000_000
+        let remaining = self.params.max_money.0.saturating_sub(self.supply.0);
+        self.engine
+            .block_reward(&self.params, self.chain.len() as u64)
+            .min(remaining)
+    }
+
+    pub fn supply(&self) -> Amount {
+        self.supply
+    }
+
+    // State of the named deployment for the next block, or None if there is
+    // no such deployment
+    pub fn deployment_state(&self, name: &str) -> Option<DeploymentState> {
+        let deployment = self.params.deployments.iter().find(|d| d.name == name)?;
+        Some(self.state_of(deployment))
+    }
+
+    fn state_of(&self, deployment: &Deployment) -> DeploymentState {
+        deployment.state_at(
+            self.chain.len() as u64,
+            self.params.signal_period,
+            self.params.signal_threshold,
+            |range| {
+                self.chain[range.start as usize..range.end as usize]
+                    .iter()
+                    .filter(|block| deployment.is_signaled_by(block.header.version))
+                    .count() as u64
+            },
+        )
     }
 
+    // The bits a block at `height` must have, as the engine decides
+    pub fn expected_bits(&self, height: u64) -> u32 {
+        self.engine.expected_bits(self, height)
+    }
+
+    // Signals for every deployment that can still lock in
     pub fn next_block(&self) -> Block {
-        Block::new(
+        let mut block = Block::new_with_clock(
             self.chain.len() as u64,
             self.prev_hash(),
-            self.target.clone(),
+            self.expected_bits(self.chain.len() as u64),
             Vec::new(),
-        )
+            self.clock.as_ref(),
+        );
+        for deployment in &self.params.deployments {
+            if self.state_of(deployment) == DeploymentState::Started {
+                block.header.version |= deployment.mask();
+            }
+        }
+        block.update_digest();
+        block
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<(), BlockValidationError> {
-        self.validate_block(&block)?;
-        self.utxos.update_with_block(&block);
-        self.chain.push(block);
-        Ok(())
+    // A block on top of the tip with the mempool transactions paying the
+    // best fee rates that fit in it, and a coinbase claiming the reward and
+    // their fees. Only the nonce is left to find, by the built-in miner or
+    // external hardware.
+    pub fn create_block_template(&self, miner_pkhash: [u8; 32]) -> Result<Block, TransactionError> {
+        let mut block = self.next_block();
+        block.add_coinbase_tx(miner_pkhash, self.get_block_reward());
+        // The transaction count may take a few more bytes once filled
+        let space = self
+            .params
+            .max_block_size
+            .saturating_sub(block.as_bincode().len() + 8);
+        block.transactions.clear();
+
+        for tx in self.mempool.select_for_size(space) {
+            block.add_tx(tx);
+        }
+        let fees = self.calculate_block_fees(&block)?;
+        let reward = Amount(self.get_block_reward())
+            .checked_add(fees)
+            .ok_or(TransactionError::AmountOutOfRange)?;
+        block.add_coinbase_tx(miner_pkhash, reward);
+        Ok(block)
     }
 
-    fn validate_transactions_stateful(&self, block: &Block) -> Result<(), TransactionError> {
-        if block.transactions[0].outputs[0].value != self.get_block_reward() {
-            return Err(TransactionError::InvalidCoinbase);
+    // A block building on one in the chain other than the tip, or on a side
+    // block, goes on a side chain, see add_side_block
+    pub fn add_block(&mut self, block: Block) -> Result<BlockAcceptance, BlockValidationError> {
+        self.check_known_invalid(&block)?;
+        if self.is_known(&block.digest) {
+            return Err(BlockValidationError::AlreadyKnown);
+        }
+        if block.header.prev_hash != self.prev_hash() && self.is_known(&block.header.prev_hash) {
+            return self.add_side_block(block);
         }
+        let mut span = Span::new(
+            Level::Debug,
+            "blockchain",
+            "add_block",
+            &[
+                ("height", &block.header.index),
+                ("digest", &block.digest),
+                ("txs", &block.transactions.len()),
+            ],
+        );
+        let supply = match self.check_block(&block) {
+            Ok(supply) => supply,
+            Err(e) => {
+                span.record("error", &e);
+                self.record_invalid(&block, &e);
+                return Err(e);
+            }
+        };
+        span.record("validation_us", &span.elapsed_us());
+        log::info(
+            "blockchain",
+            "connected block",
+            &[
+                ("height", &block.header.index),
+                ("txs", &block.transactions.len()),
+            ],
+        );
+        let undo = self.utxos.update_with_block(&block);
+        self.undo.insert(block.digest, undo);
+        self.supply = supply;
+        self.connect_block(block);
+        Ok(BlockAcceptance::ConnectedToTip)
+    }
 
-        for tx in &block.transactions[1..] {
-            let mut inputs_total = 0;
+    // Whether the block is in the chain or on a side chain
+    fn is_known(&self, digest: &Hash256) -> bool {
+        self.heights.contains_key(digest) || self.side_blocks.contains_key(digest)
+    }
 
-            for input in &tx.inputs {
-                if self.utxos.get_utxo(input.txid, input.output).is_none() {
-                    return Err(TransactionError::InvalidUTXO);
+    // The block is checked as far as it can be without the UTXO set at its
+    // parent; its transactions are only checked if its branch overtakes the
+    // chain. Ties go to the chain, see is_better_tip.
+    fn add_side_block(&mut self, block: Block) -> Result<BlockAcceptance, BlockValidationError> {
+        let parent = match self.get_block_by_hash(&block.header.prev_hash) {
+            Some(parent) => &parent.header,
+            None => &self.side_blocks[&block.header.prev_hash].header,
+        };
+        let result = if block.header.index != parent.index + 1 {
+            Err(BlockValidationError::InvalidIndex)
+        } else if block.header.timestamp < parent.timestamp {
+            Err(BlockValidationError::InvalidTimestamp)
+        } else {
+            self.check_standalone(&block)
+        };
+        if let Err(e) = result {
+            self.record_invalid(&block, &e);
+            return Err(e);
+        }
+        log::info(
+            "blockchain",
+            "side chain block",
+            &[("height", &block.header.index), ("digest", &block.digest)],
+        );
+
+        // The branch back to where it leaves the chain, oldest first
+        let mut branch = Vec::new();
+        let mut work = block.header.work();
+        let mut fork = block.header.prev_hash;
+        self.side_blocks.insert(block.digest, block.clone());
+        branch.push(block.digest);
+        while let Some(side) = self.side_blocks.get(&fork) {
+            work += side.header.work();
+            branch.push(side.digest);
+            fork = side.header.prev_hash;
+        }
+        branch.reverse();
+        let fork = self.heights[&fork];
+        work += &self.chain_work[fork as usize];
+
+        if !self.is_better_tip(&work) {
+            return Ok(BlockAcceptance::AcceptedOnSideChain);
+        }
+        self.reorganize(fork, &branch)
+    }
+
+    // Switches the chain to the side blocks in `branch`, which leaves it
+    // after height `fork`. The blocks disconnected become side blocks, and
+    // their transactions go back to the mempool. If a block on the branch
+    // turns out to be invalid, it and the side blocks building on it are
+    // dropped and the chain is put back as it was.
+    fn reorganize(
+        &mut self,
+        fork: u64,
+        branch: &[Hash256],
+    ) -> Result<BlockAcceptance, BlockValidationError> {
+        if self.chain[fork as usize + 1..]
+            .iter()
+            .any(|block| !self.undo.contains_key(&block.digest))
+        {
+            return Err(BlockValidationError::MissingUndoData);
+        }
+        // Rebuilt afterwards, as it can't take blocks back
+        let indexed = self.address_index.take().is_some();
+
+        let mut disconnected = Vec::new();
+        while self.height() > fork {
+            disconnected.push(self.disconnect_tip());
+        }
+        let mut failure = None;
+        for digest in branch {
+            let block = self.side_blocks.remove(digest).unwrap();
+            if let Err(e) = self.add_block(block) {
+                self.drop_side_descendants(digest);
+                failure = Some(e);
+                break;
+            }
+        }
+
+        let result = match failure {
+            Some(e) => {
+                let mut connected = Vec::new();
+                while self.height() > fork {
+                    connected.push(self.disconnect_tip());
+                }
+                for block in disconnected.into_iter().rev() {
+                    self.add_block(block)
+                        .expect("a disconnected block no longer connects");
                 }
+                self.set_aside(connected);
+                Err(e)
+            }
+            None => {
+                let depth = disconnected.len() as u64;
+                log::info(
+                    "blockchain",
+                    "reorganized",
+                    &[("depth", &depth), ("height", &self.height())],
+                );
+                self.set_aside(disconnected);
+                Ok(BlockAcceptance::CausedReorg { depth })
+            }
+        };
+        if indexed {
+            self.enable_address_index();
+        }
+        result
+    }
+
+    // Keeps blocks that left the chain as side blocks, returning their
+    // transactions the chain no longer has to the mempool
+    fn set_aside(&mut self, blocks: Vec<Block>) {
+        for block in blocks.into_iter().rev() {
+            for tx in &block.transactions[1..] {
+                if !self.tx_index.contains_key(&tx.id) {
+                    let _ = self.submit_transaction(tx.clone());
+                }
+            }
+            self.side_blocks.insert(block.digest, block);
+        }
+    }
+
+    // Removes the tip, undoing everything connecting it did
+    fn disconnect_tip(&mut self) -> Block {
+        let block = self.chain.pop().unwrap();
+        let undo = self.undo.remove(&block.digest).unwrap();
+        self.utxos
+            .disconnect_block(&block, &undo)
+            .expect("undo data does not match the tip");
+        // The block added its outputs and took away the ones it spent
+        let created: u64 = block
+            .transactions
+            .iter()
+            .flat_map(|tx| &tx.outputs)
+            .map(|output| output.value.0)
+            .sum();
+        let spent: u64 = block.transactions[1..]
+            .iter()
+            .flat_map(|tx| &tx.inputs)
+            .map(|input| input.value.0)
+            .sum();
+        self.supply = Amount(self.supply.0 + spent - created);
+        self.heights.remove(&block.digest);
+        for tx in &block.transactions {
+            self.tx_index.remove(&tx.id);
+        }
+        self.filters.pop();
+        self.chain_work.pop();
+        self.subscribers.block_disconnected(&block);
+        block
+    }
+
+    // The side blocks building on `digest`, however indirectly, can never
+    // connect either
+    fn drop_side_descendants(&mut self, digest: &Hash256) {
+        let mut parents = vec![*digest];
+        while let Some(parent) = parents.pop() {
+            let children: Vec<Hash256> = self
+                .side_blocks
+                .values()
+                .filter(|block| block.header.prev_hash == parent)
+                .map(|block| block.digest)
+                .collect();
+            for child in children {
+                self.side_blocks.remove(&child);
+                parents.push(child);
+            }
+        }
+    }
+
+    // The blocks on side chains, in no particular order
+    pub fn side_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.side_blocks.values()
+    }
+
+    // Adds a block whose changes are already in the UTXO set
+    fn connect_block(&mut self, block: Block) {
+        self.fee_estimator.process_block(&block, &self.mempool);
+        self.mempool.remove_confirmed(&block);
+        self.heights.insert(block.digest, block.header.index);
+        for (position, tx) in block.transactions.iter().enumerate() {
+            self.tx_index.insert(tx.id, (block.header.index, position));
+        }
+        self.filters.push(BlockFilter::for_block(&block));
+        if let Some(index) = &mut self.address_index {
+            index.add_block(&block);
+        }
+        self.chain_work
+            .push(self.total_work() + block.header.work());
+        self.subscribers.block_connected(&block);
+        self.chain.push(block);
+        self.expire_mempool();
+    }
 
-                let utxo = self.utxos.get_utxo(input.txid, input.output).unwrap();
+    // Like add_block, but a block whose parent is unknown is kept in the
+    // orphan pool instead of being dropped. Once a block is accepted, the
+    // orphans building on it are added too, recursively. Returns what became
+    // of the block, then the digests of the orphans added after it, each
+    // after its parent.
+    pub fn process_block(
+        &mut self,
+        block: Block,
+    ) -> Result<(BlockAcceptance, Vec<Hash256>), BlockValidationError> {
+        if !self.is_known(&block.header.prev_hash) {
+            self.check_known_invalid(&block)?;
+            if let Err(e) = self.check_standalone(&block) {
+                self.record_invalid(&block, &e);
+                return Err(e);
+            }
+            self.orphans.add(block);
+            return Ok((BlockAcceptance::OrphanStored, Vec::new()));
+        }
+
+        let mut parents = vec![block.digest];
+        let acceptance = self.add_block(block)?;
 
-                let input_pkhash: [u8; 32] =
-                    Sha256::digest(input.pubkey).as_slice().try_into().unwrap();
-                if input_pkhash != utxo.pkhash {
-                    return Err(TransactionError::UnauthorizedSpend);
+        let mut added = Vec::new();
+        while let Some(parent) = parents.pop() {
+            // Orphans that fail to connect are dropped
+            for orphan in self.orphans.take_children(&parent) {
+                let digest = orphan.digest;
+                if self.add_block(orphan).is_ok() {
+                    added.push(digest);
+                    parents.push(digest);
                 }
+            }
+        }
+        Ok((acceptance, added))
+    }
+
+    // A block that already failed validation is rejected with the same
+    // reason, and one building on it as an invalid descendant, without
+    // checking either again
+    fn check_known_invalid(&mut self, block: &Block) -> Result<(), BlockValidationError> {
+        let hash = block.hash();
+        if let Some(reason) = self.invalid_blocks.get(&hash) {
+            return Err(reason.clone());
+        }
+        if self.invalid_blocks.contains(&block.header.prev_hash) {
+            self.mark_invalid(hash, BlockValidationError::InvalidAncestor);
+            return Err(BlockValidationError::InvalidAncestor);
+        }
+        Ok(())
+    }
 
-                inputs_total += utxo.value as i64;
+    // Only failures nothing can fix later are remembered. A merkle root,
+    // size or digest mismatch may just mean a peer tampered with the
+    // transactions of a valid block, whose header must stay acceptable.
+    fn record_invalid(&mut self, block: &Block, error: &BlockValidationError) {
+        let tampered = matches!(
+            error,
+            BlockValidationError::InvalidMerkleRoot
+                | BlockValidationError::BlockTooLarge
+                | BlockValidationError::HashDigestMismatch
+        );
+        if error.is_invalid_anywhere() && !tampered {
+            self.mark_invalid(block.hash(), error.clone());
+        }
+    }
+
+    // Orphans building on the block can never connect, so they go too
+    fn mark_invalid(&mut self, hash: Hash256, reason: BlockValidationError) {
+        self.invalid_blocks.add(hash, reason);
+        let mut parents = vec![hash];
+        while let Some(parent) = parents.pop() {
+            for orphan in self.orphans.take_children(&parent) {
+                self.invalid_blocks
+                    .add(orphan.digest, BlockValidationError::InvalidAncestor);
+                parents.push(orphan.digest);
             }
+        }
+    }
+
+    // Why the block with this header hash was rejected, if it is known to be
+    // invalid
+    pub fn invalid_block_reason(&self, hash: &Hash256) -> Option<&BlockValidationError> {
+        self.invalid_blocks.get(hash)
+    }
 
-            let total_fees = inputs_total - tx.outputs.iter().map(|o| o.value as i64).sum::<i64>();
-            if total_fees < 0 {
-                return Err(TransactionError::Overspend);
+    pub fn submit_transaction(&mut self, tx: Transaction) -> Result<(), TransactionError> {
+        let chain_id = self.chain_id();
+        let height = self.chain.len() as u64;
+        self.check_amounts(&tx)?;
+        self.check_version(&tx, height)?;
+        self.check_locktimes(&tx, height)?;
+        self.check_inputs_unspent(&tx, |outpoint| self.mempool.output(outpoint))?;
+        let txid = tx.id;
+        let now = self.clock.now();
+        self.expire_mempool();
+        self.mempool
+            .add_transaction_at(tx, &self.utxos, &chain_id, height, now)?;
+        let evicted = self.mempool.trim_to_size();
+        for &evicted in &evicted {
+            if evicted != txid {
+                self.subscribers.emit(ChainEvent::TxEvicted(evicted));
             }
         }
+        if evicted.contains(&txid) {
+            return Err(TransactionError::MempoolFull);
+        }
+        self.subscribers.emit(ChainEvent::TxAccepted(txid));
+        Ok(())
+    }
+
+    fn expire_mempool(&mut self) {
+        for txid in self.mempool.expire(self.clock.now()) {
+            self.subscribers.emit(ChainEvent::TxExpired(txid));
+        }
+    }
+
+    // In base units per 1000 bytes, never below what the mempool accepts
+    pub fn estimate_fee_rate(&self, target_blocks: u64) -> u64 {
+        let min_relay_fee_rate = self.mempool.min_relay_fee_rate();
+        self.fee_estimator
+            .estimate(target_blocks)
+            .map_or(min_relay_fee_rate, |fee_rate| {
+                fee_rate.max(min_relay_fee_rate)
+            })
+    }
+
+    // Returns how many of the saved transactions are still valid and were
+    // re-added to the mempool
+    pub fn load_mempool(&mut self, path: impl AsRef<Path>) -> io::Result<usize> {
+        let mut accepted = 0;
+        for tx in Mempool::load_from_disk(path)? {
+            if self.submit_transaction(tx).is_ok() {
+                accepted += 1;
+            }
+        }
+        Ok(accepted)
+    }
 
+    // Sums the fees paid by the block's transactions. The block may or may
+    // not have its coinbase yet.
+    pub fn calculate_block_fees(&self, block: &Block) -> Result<Amount, TransactionError> {
+        let txs = match block.transactions.first() {
+            Some(tx) if tx.is_coinbase() => &block.transactions[1..],
+            _ => &block.transactions[..],
+        };
+
+        // Transactions may spend the outputs of earlier ones in the block
+        let mut created: HashMap<OutPoint, &TxOutput> = HashMap::new();
+        let mut total_fees = Amount::ZERO;
+        for tx in txs {
+            let unconfirmed = |outpoint: &OutPoint| created.get(outpoint).copied();
+            self.check_inputs_unspent(tx, unconfirmed)?;
+            let fee = self
+                .utxos
+                .validate_transaction_with(tx, block.header.index, unconfirmed)?;
+            total_fees = total_fees
+                .checked_add(fee)
+                .ok_or(TransactionError::AmountOutOfRange)?;
+            for (index, output) in tx.outputs.iter().enumerate() {
+                created.insert(OutPoint::new(tx.id, index as u16), output);
+            }
+        }
+        Ok(total_fees)
+    }
+
+    // The hash rate is the work done over the window divided by the time it
+    // took, so it is only as accurate as the miners' timestamps
+    pub fn stats(&self) -> ChainStats {
+        let start = self.height().saturating_sub(STATS_WINDOW);
+        let blocks = &self.chain[start as usize..];
+        // Timestamps may go backwards by up to the median time past
+        let mut intervals: Vec<u64> = blocks
+            .windows(2)
+            .map(|pair| {
+                pair[1]
+                    .header
+                    .timestamp
+                    .saturating_sub(pair[0].header.timestamp)
+            })
+            .collect();
+        intervals.sort_unstable();
+        let span = blocks[blocks.len() - 1]
+            .header
+            .timestamp
+            .saturating_sub(blocks[0].header.timestamp);
+        let work = self.total_work() - &self.chain_work[start as usize];
+
+        ChainStats {
+            height: self.height(),
+            average_block_interval: span / (intervals.len() as u64).max(1),
+            median_block_interval: intervals.get(intervals.len() / 2).copied().unwrap_or(0),
+            hash_rate: u64::try_from(work / span.max(1)).unwrap_or(u64::MAX),
+            transactions: self.tx_index.len() as u64,
+            utxos: self.utxos.len() as u64,
+            supply: self.supply,
+        }
+    }
+
+    // Median timestamp of the last 11 blocks, which unlike the tip's own
+    // timestamp cannot be moved far by a single miner
+    pub fn median_time_past(&self) -> u64 {
+        let start = self.chain.len().saturating_sub(11);
+        let mut timestamps: Vec<u64> = self.chain[start..]
+            .iter()
+            .map(|block| block.header.timestamp)
+            .collect();
+        timestamps.sort_unstable();
+        timestamps[timestamps.len() / 2]
+    }
+
+    // The coinbase has no inputs to sign, so the formats versions change
+    // don't apply to it
+    fn check_version(&self, tx: &Transaction, height: u64) -> Result<(), TransactionError> {
+        if !SUPPORTED_VERSIONS.contains(&tx.version) {
+            return Err(TransactionError::UnsupportedVersion);
+        }
+        if !self.params.is_version_active(tx.version, height) {
+            return Err(TransactionError::VersionNotActive);
+        }
         Ok(())
     }
 
+    // Checks the absolute and relative locktimes of a transaction to be
+    // included in the next block, at `height`
+    fn check_locktimes(&self, tx: &Transaction, height: u64) -> Result<(), TransactionError> {
+        let reached = if tx.locktime < LOCKTIME_THRESHOLD {
+            height >= tx.locktime
+        } else {
+            self.median_time_past() >= tx.locktime
+        };
+        if !reached {
+            return Err(TransactionError::LocktimeNotReached);
+        }
+
+        for input in tx.inputs.iter().filter(|input| input.relative_locktime > 0) {
+            let (_, confirmed_at) = self
+                .get_transaction(&input.txid)
+                .ok_or(TransactionError::InvalidUTXO)?;
+            if height < confirmed_at + input.relative_locktime as u64 {
+                return Err(TransactionError::RelativeLocktimeNotReached);
+            }
+        }
+        Ok(())
+    }
+
+    // Tells outputs spent by an earlier block apart from ones that never
+    // existed
+    fn check_inputs_unspent<'a>(
+        &self,
+        tx: &Transaction,
+        unconfirmed: impl Fn(&OutPoint) -> Option<&'a TxOutput>,
+    ) -> Result<(), TransactionError> {
+        for input in &tx.inputs {
+            let outpoint = input.outpoint();
+            if self.utxos.get_utxo(&outpoint).is_some() || unconfirmed(&outpoint).is_some() {
+                continue;
+            }
+            return match self.get_transaction(&input.txid) {
+                Some((spent, _)) if (input.output as usize) < spent.outputs.len() => {
+                    Err(TransactionError::AlreadySpent)
+                }
+                _ => Err(TransactionError::InvalidUTXO),
+            };
+        }
+        Ok(())
+    }
+
+    // The chain's cap may be below MAX_MONEY, which transactions are checked
+    // against on their own. Outputs have no negative values, so bounding the
+    // total bounds each of them too.
+    fn check_amounts(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        let total = Amount::checked_sum(tx.outputs.iter().map(|output| output.value));
+        if total.is_none_or(|total| total > self.params.max_money) {
+            return Err(TransactionError::AmountOutOfRange);
+        }
+        Ok(())
+    }
+
+    // Returns the supply once the block is connected
+    fn validate_transactions_stateful(&self, block: &Block) -> Result<Amount, TransactionError> {
+        for tx in &block.transactions {
+            self.check_amounts(tx)?;
+        }
+        for tx in &block.transactions[1..] {
+            self.check_version(tx, block.header.index)?;
+            self.check_locktimes(tx, block.header.index)?;
+        }
+        let total_fees = self.calculate_block_fees(block)?;
+        let max_coinbase = Amount(self.engine.block_reward(&self.params, block.header.index))
+            .checked_add(total_fees)
+            .ok_or(TransactionError::InvalidCoinbase)?;
+
+        let coinbase = block.transactions[0].outputs[0].value;
+        if coinbase > max_coinbase {
+            return Err(TransactionError::InvalidCoinbase);
+        }
+
+        // Fees only move coins from the spent outputs to the coinbase
+        self.supply
+            .checked_add(coinbase)
+            .and_then(|supply| supply.checked_sub(total_fees))
+            .ok_or(TransactionError::AmountOutOfRange)
+    }
+
     pub fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
-        block.validate()?;
-        self.validate_transactions_stateful(block)
+        self.check_block(block).map(|_| ())
+    }
+
+    // Returns the supply once the block is connected
+    fn check_block(&self, block: &Block) -> Result<Amount, BlockValidationError> {
+        self.check_standalone(block)?;
+        let supply = self
+            .validate_transactions_stateful(block)
             .map_err(BlockValidationError::InvalidTransactions)?;
+        if supply > self.params.max_money {
+            return Err(BlockValidationError::SupplyCapExceeded);
+        }
 
-        if block.prev_hash != self.prev_hash() {
+        if block.header.prev_hash != self.prev_hash() {
             return Err(BlockValidationError::InvalidPreviousHash);
         }
-        if block.index != self.chain.last().unwrap().index + 1 {
+        if block.header.index != self.chain.last().unwrap().header.index + 1 {
             return Err(BlockValidationError::InvalidIndex);
         }
-        if block.timestamp < self.chain.last().unwrap().timestamp {
+        if block.header.timestamp < self.chain.last().unwrap().header.timestamp {
             return Err(BlockValidationError::InvalidTimestamp);
         }
-        Ok(())
+        self.engine.check_producer(self, block)?;
+        Ok(supply)
+    }
+
+    // The checks that need neither the UTXO set nor the block's parent
+    fn check_standalone(&self, block: &Block) -> Result<(), BlockValidationError> {
+        if block.header.bits != self.expected_bits(block.header.index) {
+            return Err(BlockValidationError::InvalidTarget);
+        }
+        if block.as_bincode().len() > self.params.max_block_size {
+            return Err(BlockValidationError::BlockTooLarge);
+        }
+        if self
+            .params
+            .checkpoints
+            .iter()
+            .any(|&(height, digest)| height == block.header.index && digest != block.digest)
+        {
+            return Err(BlockValidationError::CheckpointMismatch);
+        }
+
+        let chain_id = self.chain_id();
+        let chain_id = (!self.checkpointed.contains(&block.digest)).then_some(&chain_id);
+        self.check_sealed(block, chain_id)
+    }
+
+    // Takes note of a chain of blocks by height and digest, each the parent
+    // of the next, such as headers synced from a peer. If it reaches the last
+    // checkpoint, the blocks up to it are the checkpoint's ancestors and
+    // their signatures aren't checked when they are added. Blocks off that
+    // chain are always checked in full, whatever their height.
+    pub fn note_checkpointed_chain(&mut self, chain: impl IntoIterator<Item = (u64, Hash256)>) {
+        let Some(checkpoint) = self
+            .params
+            .checkpoints
+            .iter()
+            .max_by_key(|(height, _)| *height)
+        else {
+            return;
+        };
+        let chain: Vec<(u64, Hash256)> = chain
+            .into_iter()
+            .take_while(|(height, _)| *height <= checkpoint.0)
+            .collect();
+        if chain.last() == Some(checkpoint) {
+            self.checkpointed
+                .extend(chain.into_iter().map(|(_, digest)| digest));
+        }
+    }
+
+    // The checks that need neither the UTXO set nor the chain. The seal may
+    // be anywhere in the block, so it is only looked at once the block is
+    // known to match its header.
+    fn check_sealed(
+        &self,
+        block: &Block,
+        chain_id: Option<&[u8; 32]>,
+    ) -> Result<(), BlockValidationError> {
+        self.engine.check_header(&block.header)?;
+        block.validate_unsealed_at(chain_id, self.clock.now())?;
+        self.engine.check_seal(block)
+    }
+
+    // Blocks are stored back to back, each prefixed with its big-endian u32
+    // length. The file is written to a temporary path first so a crash
+    // cannot leave a truncated chain behind.
+    pub fn save_to_disk(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        for block in &self.chain {
+            let data = block.as_bincode();
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(&data)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+
+        fs::rename(tmp_path, path)
+    }
+
+    // Every block is revalidated while loading, so the UTXO set is rebuilt
+    // from scratch and a tampered file is rejected
+    pub fn load_from_disk(
+        path: impl AsRef<Path>,
+        params: ChainParams,
+    ) -> Result<Blockchain, StorageError> {
+        Blockchain::load_from_disk_with_utxos(path, params, UTXOSet::new())
+    }
+
+    // Like load_from_disk, but a set kept on disk that is already at the tip
+    // of the file is used as it is. The blocks are then only checked on
+    // their own, since their transactions can't be checked against a set
+    // that has spent them. Any other set is cleared and rebuilt.
+    pub fn load_from_disk_with_utxos(
+        path: impl AsRef<Path>,
+        params: ChainParams,
+        mut utxos: UTXOSet,
+    ) -> Result<Blockchain, StorageError> {
+        let blocks = Blockchain::read_blocks(path)?;
+        let synced = blocks.len() > 1 && utxos.tip() == blocks.last().map(|block| block.digest);
+        if !synced {
+            utxos.clear()?;
+        }
+        let mut blockchain = Blockchain::with_utxos(params, utxos);
+        blockchain.note_checkpointed_chain(
+            blocks
+                .iter()
+                .map(|block| (block.header.index, block.digest)),
+        );
+
+        for (height, block) in blocks.into_iter().enumerate() {
+            let height = height as u64;
+            if height == 0 {
+                if block.digest != blockchain.chain[0].digest {
+                    return Err(StorageError::GenesisMismatch);
+                }
+            } else if synced {
+                let undo = blockchain
+                    .check_connects(&block)
+                    .and_then(|()| blockchain.rebuild_undo(&block))
+                    .map_err(|e| StorageError::InvalidBlock(height, e))?;
+                blockchain.undo.insert(block.digest, undo);
+                blockchain.connect_block(block);
+            } else {
+                blockchain
+                    .add_block(block)
+                    .map_err(|e| StorageError::InvalidBlock(height, e))?;
+            }
+        }
+
+        Ok(blockchain)
+    }
+
+    // The checks that don't need the UTXO set. The producer isn't checked,
+    // as the set is already past the block.
+    fn check_connects(&self, block: &Block) -> Result<(), BlockValidationError> {
+        if block.header.prev_hash != self.prev_hash() {
+            return Err(BlockValidationError::InvalidPreviousHash);
+        }
+        if block.header.index != self.height() + 1 {
+            return Err(BlockValidationError::InvalidIndex);
+        }
+        if block.header.bits != self.expected_bits(block.header.index) {
+            return Err(BlockValidationError::InvalidTarget);
+        }
+        self.check_sealed(block, None)
+    }
+
+    // The undo data update_with_block would have returned for a block
+    // connected onto the chain: the outputs it spends, in order, found in the
+    // blocks before it. Outputs it creates and spends itself are left out.
+    fn rebuild_undo(&self, block: &Block) -> Result<UndoData, BlockValidationError> {
+        let created: HashSet<TxId> = block.transactions.iter().map(|tx| tx.id).collect();
+        let mut undo = UndoData::default();
+        for tx in block.transactions.iter().skip(1) {
+            for input in &tx.inputs {
+                let outpoint = input.outpoint();
+                if created.contains(&outpoint.txid) {
+                    continue;
+                }
+                let output = self
+                    .get_transaction(&outpoint.txid)
+                    .filter(|(spent, _)| spent.id == outpoint.txid)
+                    .and_then(|(spent, _)| spent.outputs.get(outpoint.index as usize))
+                    .ok_or(BlockValidationError::InvalidTransactions(
+                        TransactionError::InvalidUTXO,
+                    ))?;
+                undo.spent.push((outpoint, output.clone()));
+            }
+        }
+        Ok(undo)
     }
 
-    pub fn prev_hash(&self) -> [u8; 32] {
+    fn read_blocks(path: impl AsRef<Path>) -> Result<Vec<Block>, StorageError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut blocks = Vec::new();
+
+        loop {
+            let mut len = [0u8; 4];
+            match reader.read_exact(&mut len) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+
+            // The length isn't trusted for the allocation
+            let len = u32::from_be_bytes(len) as usize;
+            if len > utils::MAX_DECODE_SIZE {
+                return Err(StorageError::Corrupt);
+            }
+            let mut data = vec![0u8; len];
+            reader
+                .read_exact(&mut data)
+                .map_err(|_| StorageError::Corrupt)?;
+            blocks.push(Block::from_bincode(&data).map_err(|_| StorageError::Corrupt)?);
+        }
+        Ok(blocks)
+    }
+
+    pub fn height(&self) -> u64 {
+        self.chain.last().unwrap().header.index
+    }
+
+    pub fn get_block_by_height(&self, height: u64) -> Option<&Block> {
+        self.chain.get(height as usize)
+    }
+
+    pub fn get_block_by_hash(&self, hash: &Hash256) -> Option<&Block> {
+        self.get_block_by_height(*self.heights.get(hash)?)
+    }
+
+    pub fn undo_data(&self, digest: &Hash256) -> Option<&UndoData> {
+        self.undo.get(digest)
+    }
+
+    // Builds the index from the blocks so far; later blocks are added as
+    // they connect
+    pub fn enable_address_index(&mut self) {
+        if self.address_index.is_none() {
+            let mut index = AddressIndex::new();
+            for block in &self.chain {
+                index.add_block(block);
+            }
+            self.address_index = Some(index);
+        }
+    }
+
+    pub fn address_index(&self) -> Option<&AddressIndex> {
+        self.address_index.as_ref()
+    }
+
+    pub fn get_block_filter(&self, height: u64) -> Option<&BlockFilter> {
+        self.filters.get(height as usize)
+    }
+
+    pub fn total_work(&self) -> &BigUint {
+        self.chain_work.last().unwrap()
+    }
+
+    pub fn chain_work_at(&self, height: u64) -> Option<&BigUint> {
+        self.chain_work.get(height as usize)
+    }
+
+    // Whether a competing tip with `work` in total should replace ours. On a
+    // tie the tip seen first is kept, so nodes don't flip between them.
+    pub fn is_better_tip(&self, work: &BigUint) -> bool {
+        work > self.total_work()
+    }
+
+    // Blocks from genesis to the tip
+    pub fn iter(&self) -> std::slice::Iter<'_, Block> {
+        self.chain.iter()
+    }
+
+    // The heights past the tip are left out
+    pub fn blocks_in_range(&self, range: Range<u64>) -> &[Block] {
+        let end = range.end.min(self.chain.len() as u64);
+        let start = range.start.min(end);
+        &self.chain[start as usize..end as usize]
+    }
+
+    // Hashes of blocks going back from the tip: the first ten one apart, then
+    // doubling the step each time, always ending with genesis. A peer with a
+    // diverging chain can find the last block we have in common in
+    // O(log height) hashes.
+    pub fn get_block_locator(&self) -> Vec<Hash256> {
+        let mut locator = Vec::new();
+        let mut height = self.height();
+        let mut step = 1;
+
+        loop {
+            locator.push(self.chain[height as usize].digest);
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    // Headers of up to `limit` blocks following the first locator hash found
+    // in our chain, or following genesis if none are
+    pub fn get_headers_after(&self, locator: &[Hash256], limit: usize) -> Vec<BlockHeader> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.get_block_by_hash(hash))
+            .map_or(0, |block| block.header.index);
+
+        self.chain
+            .iter()
+            .skip(start as usize + 1)
+            .take(limit)
+            .map(|block| block.header.clone())
+            .collect()
+    }
+
+    // A confirmed transaction and the height of its block
+    pub fn get_transaction(&self, txid: &TxId) -> Option<(&Transaction, u64)> {
+        let &(height, position) = self.tx_index.get(txid)?;
+        let tx = &self.get_block_by_height(height)?.transactions[position];
+        Some((tx, height))
+    }
+
+    // Blocks on the active chain confirming the transaction: 1 in the tip, 0
+    // while in the mempool, None if unknown. A transaction whose block left
+    // the chain in a reorg is back to 0 or None.
+    pub fn confirmations(&self, txid: &TxId) -> Option<u64> {
+        match self.get_transaction(txid) {
+            Some((tx, height)) if tx.id == *txid => Some(self.height() + 1 - height),
+            _ => self.mempool.contains(txid).then_some(0),
+        }
+    }
+
+    // Whether the transaction has at least `required` confirmations, so
+    // that undoing it would take a reorg that deep
+    pub fn is_final(&self, txid: &TxId, required: u64) -> bool {
+        self.confirmations(txid)
+            .is_some_and(|confirmations| confirmations >= required.max(1))
+    }
+
+    // Oldest first
+    pub fn address_history(&self, pkhash: [u8; 32]) -> Vec<HistoryEntry> {
+        let mut history = Vec::new();
+        for block in &self.chain {
+            for tx in &block.transactions {
+                let received = tx
+                    .outputs
+                    .iter()
+                    .filter(|output| output.pkhash == pkhash)
+                    .map(|output| output.value.0)
+                    .sum();
+                let sent = tx
+                    .inputs
+                    .iter()
+                    .filter_map(|input| {
+                        let (prev_tx, _) = self.get_transaction(&input.txid)?;
+                        prev_tx.outputs.get(input.output as usize)
+                    })
+                    .filter(|output| output.pkhash == pkhash)
+                    .map(|output| output.value.0)
+                    .sum();
+
+                if received > 0 || sent > 0 {
+                    history.push(HistoryEntry {
+                        txid: tx.id,
+                        height: block.header.index,
+                        received,
+                        sent,
+                    });
+                }
+            }
+        }
+        history
+    }
+
+    pub fn prove_inclusion(&self, txid: &TxId) -> Option<(BlockHeader, MerkleProof)> {
+        let &(height, position) = self.tx_index.get(txid)?;
+        let block = self.get_block_by_height(height)?;
+        let txids: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.id.0).collect();
+        Some((block.header.clone(), MerkleProof::build(&txids, position)?))
+    }
+
+    // Committed to by every input signature so transactions cannot be
+    // replayed on a chain with a different genesis block
+    pub fn chain_id(&self) -> [u8; 32] {
+        self.chain[0].digest.0
+    }
+
+    pub fn prev_hash(&self) -> Hash256 {
         match self.chain.last() {
-            Some(block) => block.digest.clone(),
-            None => [0; 32],
+            Some(block) => block.digest,
+            None => Hash256::default(),
         }
     }
 }
 
+impl<'a> IntoIterator for &'a Blockchain {
+    type Item = &'a Block;
+    type IntoIter = std::slice::Iter<'a, Block>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new(ChainParams::mainnet())
+    }
+}
+
 impl fmt::Display for Blockchain {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "\nBlockchain:")?;
-        writeln!(f, "Target: {}", hex::encode(self.target))?;
+        writeln!(f, "Network: {}", self.params.network)?;
+        writeln!(f, "Target: {}", hex::encode(self.params.target()))?;
         writeln!(f, "Number of blocks: {}", self.chain.len())?;
         for block in &self.chain {
             writeln!(f, "\n{}", block)?;
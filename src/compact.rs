@@ -0,0 +1,193 @@
+use crate::block::{Block, BlockHeader};
+use crate::hash::{Hash256, TxId};
+use crate::mempool::Mempool;
+use crate::transaction::Transaction;
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+pub type ShortId = [u8; 6];
+
+// A block announced by its header and short ids of its transactions, which
+// the receiver mostly has in its mempool already. The coinbase can't be in
+// a mempool, so it is sent in full.
+#[derive(Encode, Decode, Clone)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    pub coinbase: Transaction,
+    pub short_ids: Vec<ShortId>,
+}
+
+// Short ids are salted with the block hash, so a collision found for one
+// block can't be reused against others
+pub fn short_id(block_hash: &Hash256, txid: &TxId) -> ShortId {
+    let mut hasher = Sha256::new();
+    hasher.update(block_hash);
+    hasher.update(txid);
+    hasher.finalize()[..6].try_into().unwrap()
+}
+
+impl CompactBlock {
+    // Returns None for a block without a coinbase
+    pub fn from_block(block: &Block) -> Option<CompactBlock> {
+        let (coinbase, rest) = block.transactions.split_first()?;
+        let hash = block.hash();
+        Some(CompactBlock {
+            header: block.header.clone(),
+            coinbase: coinbase.clone(),
+            short_ids: rest.iter().map(|tx| short_id(&hash, &tx.id)).collect(),
+        })
+    }
+
+    pub fn hash(&self) -> Hash256 {
+        self.header.hash()
+    }
+
+    // Fills in the transactions found in the mempool. Short ids matching
+    // several mempool transactions are left missing.
+    pub fn reconstruct(&self, mempool: &Mempool) -> PartialBlock {
+        let hash = self.hash();
+        let mut candidates: HashMap<ShortId, Option<&Transaction>> = HashMap::new();
+        for tx in mempool.iter() {
+            candidates
+                .entry(short_id(&hash, &tx.id))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(tx));
+        }
+
+        let mut transactions = vec![Some(self.coinbase.clone())];
+        transactions.extend(
+            self.short_ids
+                .iter()
+                .map(|id| candidates.get(id).copied().flatten().cloned()),
+        );
+        PartialBlock {
+            header: self.header.clone(),
+            transactions,
+        }
+    }
+}
+
+pub struct PartialBlock {
+    pub header: BlockHeader,
+    pub transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    // Positions of the transactions to request from the sender
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    // Fills in transactions received for the positions from missing(), in
+    // the same order. Returns false if their number doesn't match.
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> bool {
+        let missing = self.missing();
+        if missing.len() != transactions.len() {
+            return false;
+        }
+        for (index, tx) in missing.into_iter().zip(transactions) {
+            self.transactions[index as usize] = Some(tx);
+        }
+        true
+    }
+
+    // Returns None while transactions are missing, or if the ones filled in
+    // don't match the header's merkle root, e.g. after a short id collision
+    pub fn into_block(self) -> Option<Block> {
+        let transactions: Vec<Transaction> =
+            self.transactions.into_iter().collect::<Option<_>>()?;
+        let mut block = Block {
+            digest: Hash256::default(),
+            header: self.header,
+            transactions,
+        };
+        if block.merkle_root() != block.header.merkle_root {
+            return None;
+        }
+        block.update_digest();
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::OutPoint;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::utxo::UTXOSet;
+
+    const CHAIN_ID: [u8; 32] = [0; 32];
+
+    fn spend(txid: u8) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([txid; 32], 0).with_value(2000)],
+            vec![TxOutput::new_anyone_can_spend(1000)],
+        );
+        tx.update_id();
+        tx
+    }
+
+    fn block_and_mempool() -> (Block, Mempool) {
+        let mut utxos = UTXOSet::new();
+        let mut mempool = Mempool::new();
+        let mut block = Block::new(1, [0; 32], 0x2100_ffff, vec![]);
+        block.add_coinbase_tx([1; 32], 50);
+        for txid in 1..=3 {
+            utxos.add_utxo(
+                OutPoint::new([txid; 32], 0),
+                TxOutput::new_anyone_can_spend(2000),
+            );
+            let tx = spend(txid);
+            mempool
+                .add_transaction(tx.clone(), &utxos, &CHAIN_ID, 1)
+                .unwrap();
+            block.add_tx(tx);
+        }
+        (block, mempool)
+    }
+
+    #[test]
+    fn test_reconstruct_from_mempool() {
+        let (block, mempool) = block_and_mempool();
+        let compact = CompactBlock::from_block(&block).unwrap();
+        assert_eq!(compact.short_ids.len(), 3);
+        let config = bincode::config::standard();
+        let compact_size = bincode::encode_to_vec(&compact, config).unwrap().len();
+        assert!(compact_size < block.as_bincode().len());
+
+        let partial = compact.reconstruct(&mempool);
+        assert!(partial.missing().is_empty());
+        let rebuilt = partial.into_block().unwrap();
+        assert_eq!(rebuilt.digest, block.digest);
+        assert_eq!(rebuilt.as_bincode(), block.as_bincode());
+    }
+
+    #[test]
+    fn test_fill_missing_transactions() {
+        let (block, mut mempool) = block_and_mempool();
+        let missing_tx = block.transactions[2].clone();
+        mempool.remove_transaction(&missing_tx.id);
+
+        let mut partial = CompactBlock::from_block(&block)
+            .unwrap()
+            .reconstruct(&mempool);
+        assert_eq!(partial.missing(), vec![2]);
+        assert!(!partial.fill(vec![]));
+
+        // a wrong transaction is caught by the merkle root
+        let mut wrong = CompactBlock::from_block(&block)
+            .unwrap()
+            .reconstruct(&mempool);
+        assert!(wrong.fill(vec![spend(9)]));
+        assert!(wrong.into_block().is_none());
+
+        assert!(partial.fill(vec![missing_tx]));
+        assert_eq!(partial.into_block().unwrap().digest, block.digest);
+    }
+}
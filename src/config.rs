@@ -0,0 +1,296 @@
+// Node and wallet settings, read from a simple `key = value` file. Blank lines
+// and lines starting with `#` are ignored; `peer` and `seed` may be given more
+// than once.
+
+use crate::errors::ConfigError;
+use crate::invoice::DEFAULT_INVOICE_CONFIRMATIONS;
+use crate::log::Level;
+use crate::mempool::{
+    DEFAULT_INCREMENTAL_RELAY_FEE_RATE, DEFAULT_MAX_MEMPOOL_SIZE, DEFAULT_MEMPOOL_EXPIRY,
+    DEFAULT_MIN_RELAY_FEE_RATE,
+};
+use crate::params::{ChainParams, Network};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_CONFIG_FILE: &str = "rust_blockchain.conf";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub network: Network,
+    pub data_dir: PathBuf,
+    pub listen: String,
+    pub rpc: String,
+    pub peers: Vec<String>,
+    // Resolved on startup for addresses to connect to when too few peers
+    // are known
+    pub seeds: Vec<String>,
+    // Fee rate a replace-by-fee transaction must pay on top of the fees it
+    // replaces, in base units per 1000 bytes
+    pub incremental_relay_fee_rate: u64,
+    // Lowest fee rate accepted into the mempool, in base units per 1000
+    // bytes
+    pub min_relay_fee_rate: u64,
+    // Most bytes of transactions the mempool holds
+    pub max_mempool_size: usize,
+    // Seconds after which unconfirmed transactions are dropped
+    pub mempool_expiry: u64,
+    // Keep the UTXO set in memory instead of in a file, rebuilding it from
+    // the chain on every start
+    pub in_memory: bool,
+    // Index every address's outputs and spends, for explorer queries
+    pub address_index: bool,
+    // Blocks on a payment before an invoice counts as paid
+    pub invoice_confirmations: u64,
+    // Wallet the CLI's calls to the node act on, when it has several loaded
+    pub rpc_wallet: Option<String>,
+    // Credentials for the RPC server. Without them the node writes new ones
+    // to the cookie file on each start, which the CLI reads.
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<String>,
+    // Log events at this level and above
+    pub log_level: Level,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            network: Network::Mainnet,
+            data_dir: PathBuf::from("data"),
+            listen: "0.0.0.0:9333".to_string(),
+            rpc: "127.0.0.1:9332".to_string(),
+            peers: Vec::new(),
+            seeds: Vec::new(),
+            incremental_relay_fee_rate: DEFAULT_INCREMENTAL_RELAY_FEE_RATE,
+            min_relay_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE,
+            max_mempool_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            mempool_expiry: DEFAULT_MEMPOOL_EXPIRY,
+            in_memory: false,
+            address_index: false,
+            invoice_confirmations: DEFAULT_INVOICE_CONFIRMATIONS,
+            rpc_wallet: None,
+            rpc_user: None,
+            rpc_password: None,
+            log_level: Level::Info,
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| ConfigError::InvalidLine(number + 1, line.to_string()))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "network" => {
+                    config.network = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "data_dir" => config.data_dir = PathBuf::from(value),
+                "listen" => config.listen = value,
+                "rpc" => config.rpc = value,
+                "peer" => config.peers.push(value),
+                "seed" => config.seeds.push(value),
+                "incremental_relay_fee_rate" => {
+                    config.incremental_relay_fee_rate = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "min_relay_fee_rate" => {
+                    config.min_relay_fee_rate = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "max_mempool_size" => {
+                    config.max_mempool_size = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "mempool_expiry" => {
+                    config.mempool_expiry = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "in_memory" => {
+                    config.in_memory = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "address_index" => {
+                    config.address_index = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "invoice_confirmations" => {
+                    config.invoice_confirmations = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                "rpc_wallet" => config.rpc_wallet = Some(value),
+                "rpc_user" => config.rpc_user = Some(value),
+                "rpc_password" => config.rpc_password = Some(value),
+                "log_level" => {
+                    config.log_level = value
+                        .parse()
+                        .map_err(|_| ConfigError::InvalidLine(number + 1, line.to_string()))?
+                }
+                key => return Err(ConfigError::UnknownKey(key.to_string())),
+            }
+        }
+
+        Ok(config)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn params(&self) -> ChainParams {
+        ChainParams::for_network(self.network)
+    }
+
+    // Test networks keep their files in a subdirectory so they can share a
+    // data directory with mainnet
+    pub fn network_dir(&self) -> PathBuf {
+        match self.network {
+            Network::Mainnet => self.data_dir.clone(),
+            network => self.data_dir.join(network.name()),
+        }
+    }
+
+    pub fn chain_path(&self) -> PathBuf {
+        self.network_dir().join("chain.dat")
+    }
+
+    pub fn utxo_path(&self) -> PathBuf {
+        self.network_dir().join("utxos.dat")
+    }
+
+    pub fn peers_path(&self) -> PathBuf {
+        self.network_dir().join("peers.dat")
+    }
+
+    pub fn mempool_path(&self) -> PathBuf {
+        self.network_dir().join("mempool.dat")
+    }
+
+    pub fn block_meta_path(&self) -> PathBuf {
+        self.network_dir().join("blockmeta.dat")
+    }
+
+    pub fn invoices_path(&self) -> PathBuf {
+        self.network_dir().join("invoices.dat")
+    }
+
+    pub fn wallet_path(&self) -> PathBuf {
+        self.network_dir().join("wallet.key")
+    }
+
+    // Where the node keeps the wallets it loads by name
+    pub fn wallets_dir(&self) -> PathBuf {
+        self.network_dir().join("wallets")
+    }
+
+    pub fn cookie_path(&self) -> PathBuf {
+        self.network_dir().join(".cookie")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "# local node\n\ndata_dir = /tmp/chain\nrpc=127.0.0.1:1234\npeer = a:1\npeer = b:2\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/chain"));
+        assert_eq!(config.rpc, "127.0.0.1:1234");
+        assert_eq!(config.listen, Config::default().listen);
+        assert_eq!(config.peers, vec!["a:1", "b:2"]);
+        assert!(config.seeds.is_empty());
+        assert_eq!(
+            Config::parse("seed = seed.example:9333").unwrap().seeds,
+            vec!["seed.example:9333"]
+        );
+        assert_eq!(config.wallet_path(), PathBuf::from("/tmp/chain/wallet.key"));
+
+        let config =
+            Config::parse("data_dir = /tmp/chain\nnetwork = regtest\nincremental_relay_fee_rate = 50\nmin_relay_fee_rate = 2000")
+                .unwrap();
+        assert_eq!(config.params(), ChainParams::regtest());
+        assert_eq!(config.incremental_relay_fee_rate, 50);
+        assert_eq!(config.min_relay_fee_rate, 2000);
+        assert_eq!(config.max_mempool_size, DEFAULT_MAX_MEMPOOL_SIZE);
+        assert_eq!(
+            Config::parse("mempool_expiry = 3600")
+                .unwrap()
+                .mempool_expiry,
+            3600
+        );
+        assert!(!config.in_memory);
+        assert!(Config::parse("in_memory = true").unwrap().in_memory);
+        assert!(Config::parse("address_index = true").unwrap().address_index);
+        assert_eq!(config.invoice_confirmations, DEFAULT_INVOICE_CONFIRMATIONS);
+        assert_eq!(
+            Config::parse("invoice_confirmations = 2")
+                .unwrap()
+                .invoice_confirmations,
+            2
+        );
+        assert_eq!(config.rpc_wallet, None);
+        assert_eq!(
+            Config::parse("rpc_wallet = savings").unwrap().rpc_wallet,
+            Some("savings".to_string())
+        );
+        let rpc = Config::parse("rpc_user = alice\nrpc_password = secret").unwrap();
+        assert_eq!(rpc.rpc_user, Some("alice".to_string()));
+        assert_eq!(rpc.rpc_password, Some("secret".to_string()));
+        assert_eq!(
+            config.cookie_path(),
+            PathBuf::from("/tmp/chain/regtest/.cookie")
+        );
+        assert_eq!(config.log_level, Level::Info);
+        assert_eq!(
+            Config::parse("log_level = debug").unwrap().log_level,
+            Level::Debug
+        );
+        assert_eq!(
+            config.chain_path(),
+            PathBuf::from("/tmp/chain/regtest/chain.dat")
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            Config::parse("data_dir = x\nlisten\n"),
+            Err(ConfigError::InvalidLine(2, _))
+        ));
+        assert!(matches!(
+            Config::parse("network = devnet"),
+            Err(ConfigError::InvalidLine(1, _))
+        ));
+        assert!(matches!(
+            Config::parse("log_level = loud"),
+            Err(ConfigError::InvalidLine(1, _))
+        ));
+        assert!(matches!(
+            Config::parse("port = 1"),
+            Err(ConfigError::UnknownKey(key)) if key == "port"
+        ));
+    }
+}
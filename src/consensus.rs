@@ -0,0 +1,580 @@
+// The rules that need no storage, clock or network: header hashing, proof of
+// work, merkle proofs and signatures. This module builds without std, so a
+// light client, in a browser say, can check headers and inclusion proofs
+// with the same code full nodes run.
+
+use crate::blake3;
+use crate::ed25519;
+pub use crate::ed25519::VerifyError;
+use alloc::vec::Vec;
+use core::fmt;
+use k256::ecdsa::signature::Verifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use k256::schnorr;
+use sha2::{Digest, Sha256};
+
+// A block header as plain bytes, hashed exactly like BlockHeader
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u32,
+    pub index: u64,
+    pub timestamp: u64,
+    pub prev_hash: [u8; 32],
+    pub merkle_root: [u8; 32],
+    // The target in compact form, see target_from_bits
+    pub bits: u32,
+    pub nonce: u64,
+}
+
+// Two 32-bit integers of up to 5 bytes, three of up to 9 bytes and two
+// hashes
+const MAX_HEADER_SIZE: usize = 2 * 5 + 3 * 9 + 2 * 32;
+
+impl Header {
+    // bincode's standard encoding: little-endian integers, varint-encoded,
+    // and hashes as they are
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MAX_HEADER_SIZE);
+        write_varint(&mut out, self.version.into());
+        write_varint(&mut out, self.index);
+        write_varint(&mut out, self.timestamp);
+        out.extend_from_slice(&self.prev_hash);
+        out.extend_from_slice(&self.merkle_root);
+        write_varint(&mut out, self.bits.into());
+        write_varint(&mut out, self.nonce);
+        out
+    }
+
+    // Fails on truncated or trailing data
+    pub fn decode(data: &[u8]) -> Option<Header> {
+        let mut data = data;
+        let header = Header {
+            version: read_varint(&mut data)?.try_into().ok()?,
+            index: read_varint(&mut data)?,
+            timestamp: read_varint(&mut data)?,
+            prev_hash: read_hash(&mut data)?,
+            merkle_root: read_hash(&mut data)?,
+            bits: read_varint(&mut data)?.try_into().ok()?,
+            nonce: read_varint(&mut data)?,
+        };
+        data.is_empty().then_some(header)
+    }
+
+    // The header's digest, which names the block whatever the proof of work
+    pub fn hash(&self) -> [u8; 32] {
+        Sha256::digest(self.encode()).into()
+    }
+
+    pub fn pow_hash(&self, pow: &impl ProofOfWork) -> [u8; 32] {
+        pow.hash(&self.encode())
+    }
+
+    pub fn has_valid_work(&self, pow: &impl ProofOfWork) -> bool {
+        target_from_bits(self.bits).is_some_and(|target| meets_target(&self.pow_hash(pow), &target))
+    }
+}
+
+// The hash a header's proof of work is measured with: the header must hash
+// below its target. Only the work depends on it; the block's digest is
+// always the SHA-256 of the header.
+pub trait ProofOfWork {
+    fn hash(&self, header: &[u8]) -> [u8; 32];
+}
+
+// The algorithms a chain can be configured with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowAlgorithm {
+    // The same hash as the digest
+    #[default]
+    Sha256,
+    // SHA-256 of the SHA-256, as Bitcoin mining hardware computes
+    DoubleSha256,
+    Blake3,
+}
+
+impl PowAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowAlgorithm::Sha256 => "sha256",
+            PowAlgorithm::DoubleSha256 => "sha256d",
+            PowAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PowAlgorithm> {
+        [
+            PowAlgorithm::Sha256,
+            PowAlgorithm::DoubleSha256,
+            PowAlgorithm::Blake3,
+        ]
+        .into_iter()
+        .find(|pow| pow.name() == name)
+    }
+}
+
+impl ProofOfWork for PowAlgorithm {
+    fn hash(&self, header: &[u8]) -> [u8; 32] {
+        match self {
+            PowAlgorithm::Sha256 => Sha256::digest(header).into(),
+            PowAlgorithm::DoubleSha256 => Sha256::digest(Sha256::digest(header)).into(),
+            PowAlgorithm::Blake3 => blake3::hash(header),
+        }
+    }
+}
+
+// Targets are written in a compact form, Bitcoin's "nBits": the top byte is
+// the target's length in bytes, the other three its most significant bytes.
+// The rest of the target is zeros, so only targets of that shape can be
+// written, and each has one encoding. The top bit of the three bytes is a
+// sign bit, which targets never have; None for those and for lengths that
+// overflow 32 bytes.
+pub fn target_from_bits(bits: u32) -> Option<[u8; 32]> {
+    let size = (bits >> 24) as usize;
+    let mantissa = bits & 0x00ff_ffff;
+    if mantissa & 0x0080_0000 != 0 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    for (i, &byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+        // Counted from the least significant byte. Bytes below it are cut
+        // off, as Bitcoin does for lengths under 3.
+        let Some(position) = size.checked_sub(i + 1) else {
+            continue;
+        };
+        if position >= 32 {
+            if byte != 0 {
+                return None;
+            }
+            continue;
+        }
+        target[31 - position] = byte;
+    }
+    Some(target)
+}
+
+// The compact form of the target, rounded down to its 3 most significant
+// bytes
+pub fn bits_from_target(target: &[u8; 32]) -> u32 {
+    let Some(first) = target.iter().position(|&byte| byte != 0) else {
+        return 0;
+    };
+    let byte = |i: usize| target.get(i).copied().unwrap_or(0) as u32;
+    let mut size = (32 - first) as u32;
+    let mut mantissa = byte(first) << 16 | byte(first + 1) << 8 | byte(first + 2);
+    if mantissa & 0x0080_0000 != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    size << 24 | mantissa
+}
+
+fn write_varint(out: &mut Vec<u8>, n: u64) {
+    if n < 251 {
+        out.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+        out.push(251);
+        out.extend_from_slice(&(n as u16).to_le_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(252);
+        out.extend_from_slice(&(n as u32).to_le_bytes());
+    } else {
+        out.push(253);
+        out.extend_from_slice(&n.to_le_bytes());
+    }
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if data.len() < len {
+        return None;
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Some(taken)
+}
+
+// Only the shortest encoding of each value is accepted, so a header has
+// just one
+fn read_varint(data: &mut &[u8]) -> Option<u64> {
+    let (n, len) = match take(data, 1)?[0] {
+        byte @ 0..=250 => return Some(byte as u64),
+        251 => (
+            u16::from_le_bytes(take(data, 2)?.try_into().unwrap()) as u64,
+            3,
+        ),
+        252 => (
+            u32::from_le_bytes(take(data, 4)?.try_into().unwrap()) as u64,
+            5,
+        ),
+        253 => (u64::from_le_bytes(take(data, 8)?.try_into().unwrap()), 9),
+        _ => return None,
+    };
+    (varint_len(n) == len).then_some(n)
+}
+
+fn varint_len(n: u64) -> usize {
+    match n {
+        0..251 => 1,
+        251..=0xffff => 3,
+        0x1_0000..=0xffff_ffff => 5,
+        _ => 9,
+    }
+}
+
+fn read_hash(data: &mut &[u8]) -> Option<[u8; 32]> {
+    take(data, 32).map(|hash| hash.try_into().unwrap())
+}
+
+// Both are big-endian numbers
+pub fn meets_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
+    hash < target
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    // The header doesn't follow the one before it
+    Disconnected { index: u64 },
+    // The target is easier than the chain allows
+    TargetTooEasy { index: u64 },
+    // The bits don't encode a target
+    InvalidBits { index: u64 },
+    InvalidProofOfWork { index: u64 },
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::Disconnected { index } => {
+                write!(f, "Header {index} does not extend the previous header")
+            }
+            HeaderError::TargetTooEasy { index } => {
+                write!(f, "Header {index} has a target above the maximum")
+            }
+            HeaderError::InvalidBits { index } => {
+                write!(f, "Header {index} has invalid target bits")
+            }
+            HeaderError::InvalidProofOfWork { index } => {
+                write!(f, "Header {index} has invalid proof of work")
+            }
+        }
+    }
+}
+
+impl core::error::Error for HeaderError {}
+
+// Checks that `headers` extend `tip` one after another, each with enough
+// work, hashed with `pow`, for a target no easier than `max_target`. Timestamps and difficulty
+// adjustments are left to full nodes, which know the time and the chain's
+// parameters.
+pub fn verify_header_chain(
+    tip: &Header,
+    headers: &[Header],
+    max_target: &[u8; 32],
+    pow: &impl ProofOfWork,
+) -> Result<(), HeaderError> {
+    let mut prev_hash = tip.hash();
+    let mut prev_index = tip.index;
+    for header in headers {
+        let index = header.index;
+        if header.prev_hash != prev_hash || index != prev_index + 1 {
+            return Err(HeaderError::Disconnected { index });
+        }
+        let target = target_from_bits(header.bits).ok_or(HeaderError::InvalidBits { index })?;
+        if target > *max_target {
+            return Err(HeaderError::TargetTooEasy { index });
+        }
+        if !meets_target(&header.pow_hash(pow), &target) {
+            return Err(HeaderError::InvalidProofOfWork { index });
+        }
+        prev_hash = header.hash();
+        prev_index = index;
+    }
+    Ok(())
+}
+
+pub fn merkle_parent(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// A node without a sibling is carried up to the next level unchanged rather
+// than being hashed with itself, so no two transaction lists share a root
+pub(crate) fn next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_parent(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn merkle_root(txids: &[[u8; 32]]) -> [u8; 32] {
+    if txids.is_empty() {
+        return [0; 32];
+    }
+
+    let mut level = txids.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+// The root reached from `leaf` through a branch of (sibling, whether the
+// sibling is on the left) pairs, from the bottom up
+pub fn merkle_branch_root(
+    leaf: [u8; 32],
+    branch: impl IntoIterator<Item = ([u8; 32], bool)>,
+) -> [u8; 32] {
+    branch.into_iter().fold(leaf, |acc, (sibling, is_left)| {
+        if is_left {
+            merkle_parent(&sibling, &acc)
+        } else {
+            merkle_parent(&acc, &sibling)
+        }
+    })
+}
+
+// `pubkey` is a SEC1-encoded secp256k1 point
+pub fn verify_ecdsa(
+    pubkey: &[u8],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), VerifyError> {
+    let verify_key =
+        VerifyingKey::from_sec1_bytes(pubkey).map_err(|_| VerifyError::InvalidPublicKey)?;
+    let signature =
+        Signature::from_bytes(signature.into()).map_err(|_| VerifyError::InvalidSignature)?;
+    // Anyone can turn (r, s) into the equally valid (r, n - s), so only the
+    // low s is accepted, keeping signatures, and the txids committing to
+    // them, from being changed in flight
+    if signature.normalize_s().is_some() {
+        return Err(VerifyError::NonCanonical);
+    }
+    verify_key
+        .verify(message, &signature)
+        .map_err(|_| VerifyError::Mismatch)
+}
+
+pub fn verify_ed25519(
+    pubkey: &[u8],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), VerifyError> {
+    let pubkey: &[u8; 32] = pubkey
+        .try_into()
+        .map_err(|_| VerifyError::InvalidPublicKey)?;
+    ed25519::verify(pubkey, message, signature)
+}
+
+// BIP340: `pubkey` is the 32 byte x coordinate of a secp256k1 point with an
+// even y, and `signature` is R's x coordinate followed by s
+pub fn verify_schnorr(
+    pubkey: &[u8],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), VerifyError> {
+    // from_bytes panics on other lengths
+    if pubkey.len() != 32 {
+        return Err(VerifyError::InvalidPublicKey);
+    }
+    let verify_key =
+        schnorr::VerifyingKey::from_bytes(pubkey).map_err(|_| VerifyError::InvalidPublicKey)?;
+    let signature =
+        schnorr::Signature::try_from(&signature[..]).map_err(|_| VerifyError::InvalidSignature)?;
+    verify_key
+        .verify_raw(message, &signature)
+        .map_err(|_| VerifyError::Mismatch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, BlockHeader};
+    use crate::blockchain::Blockchain;
+    use crate::hash::Hash256;
+    use crate::params::ChainParams;
+    use crate::signature::{PrivateKey, SignatureScheme};
+    use crate::utils;
+
+    #[test]
+    fn test_header_encoding() {
+        for n in [0, 250, 251, 0xffff, 0x1_0000, u32::MAX as u64 + 1, u64::MAX] {
+            let header = BlockHeader {
+                version: n as u32,
+                index: n,
+                timestamp: n / 2,
+                prev_hash: Hash256([1; 32]),
+                merkle_root: [2; 32],
+                bits: n as u32,
+                nonce: n,
+            };
+            let encoded = Header::from(&header).encode();
+            assert_eq!(
+                encoded,
+                bincode::encode_to_vec(&header, bincode::config::standard()).unwrap()
+            );
+            assert_eq!(Header::decode(&encoded), Some(Header::from(&header)));
+            assert_eq!(Header::decode(&encoded[1..]), None);
+        }
+
+        let mut encoded = Header::from(&Block::genesis().header).encode();
+        encoded.push(0);
+        assert_eq!(Header::decode(&encoded), None);
+        // 1 written in three bytes
+        let mut long = vec![251, 1, 0];
+        long.extend_from_slice(&encoded[1..encoded.len() - 1]);
+        assert_eq!(Header::decode(&long), None);
+    }
+
+    #[test]
+    fn test_verify_header_chain() {
+        let params = ChainParams::regtest();
+        let mut blockchain = Blockchain::new(params.clone());
+        for _ in 0..3 {
+            let mut block = blockchain.next_block();
+            utils::mine(&mut block, [1; 32], blockchain.get_block_reward());
+            blockchain.add_block(block).unwrap();
+        }
+        let headers: Vec<Header> = blockchain
+            .iter()
+            .map(|block| Header::from(&block.header))
+            .collect();
+        let (genesis, headers) = headers.split_first().unwrap();
+        assert_eq!(
+            verify_header_chain(genesis, headers, &params.target(), &params.pow),
+            Ok(())
+        );
+        assert!(
+            headers
+                .iter()
+                .all(|header| header.has_valid_work(&params.pow))
+        );
+
+        assert_eq!(
+            verify_header_chain(genesis, &headers[1..], &params.target(), &params.pow),
+            Err(HeaderError::Disconnected { index: 2 })
+        );
+        assert_eq!(
+            verify_header_chain(genesis, headers, &[0; 32], &params.pow),
+            Err(HeaderError::TargetTooEasy { index: 1 })
+        );
+        let mut forged = headers.to_vec();
+        forged[2].timestamp += 1;
+        while forged[2].has_valid_work(&params.pow) {
+            forged[2].nonce += 1;
+        }
+        assert_eq!(
+            verify_header_chain(genesis, &forged, &params.target(), &params.pow),
+            Err(HeaderError::InvalidProofOfWork { index: 3 })
+        );
+        let mut forged = headers.to_vec();
+        forged[0].bits = 0x2080_0000;
+        assert_eq!(
+            verify_header_chain(genesis, &forged, &params.target(), &params.pow),
+            Err(HeaderError::InvalidBits { index: 1 })
+        );
+    }
+
+    #[test]
+    fn test_pow_algorithms() {
+        let mut header = Header::from(&ChainParams::regtest().genesis().header);
+        let encoded = header.encode();
+        assert_eq!(header.pow_hash(&PowAlgorithm::Sha256), header.hash());
+        assert_eq!(
+            header.pow_hash(&PowAlgorithm::DoubleSha256),
+            <[u8; 32]>::from(Sha256::digest(header.hash()))
+        );
+        assert_eq!(
+            header.pow_hash(&PowAlgorithm::Blake3),
+            blake3::hash(&encoded)
+        );
+        for pow in [PowAlgorithm::DoubleSha256, PowAlgorithm::Blake3] {
+            assert_eq!(PowAlgorithm::from_name(pow.name()), Some(pow));
+        }
+        assert_eq!(PowAlgorithm::from_name("scrypt"), None);
+
+        // Work for one algorithm is no work for another
+        header.bits = 0x2000_ffff;
+        while !header.has_valid_work(&PowAlgorithm::Blake3) {
+            header.nonce += 1;
+        }
+        assert!(!header.has_valid_work(&PowAlgorithm::Sha256));
+    }
+
+    #[test]
+    fn test_compact_targets() {
+        let mut target = [0u8; 32];
+        target[..3].copy_from_slice(&[0x7f, 0xff, 0xff]);
+        assert_eq!(target_from_bits(0x207f_ffff), Some(target));
+        assert_eq!(bits_from_target(&target), 0x207f_ffff);
+
+        // A top bit set moves the mantissa down a byte
+        let mut target = [0u8; 32];
+        target[1..3].copy_from_slice(&[0x80, 0x01]);
+        assert_eq!(bits_from_target(&target), 0x2000_8001);
+        assert_eq!(target_from_bits(0x2000_8001), Some(target));
+
+        // Lower bytes are rounded off
+        assert_eq!(bits_from_target(&[0xff; 32]), 0x2100_ffff);
+        assert_eq!(bits_from_target(&[0; 32]), 0);
+        let mut small = [0u8; 32];
+        small[31] = 0x12;
+        assert_eq!(bits_from_target(&small), 0x0112_0000);
+        assert_eq!(target_from_bits(0x0112_3456), Some(small));
+
+        // Negative, or longer than 32 bytes
+        assert_eq!(target_from_bits(0x2080_0000), None);
+        assert_eq!(target_from_bits(0x2201_0000), None);
+        assert_eq!(
+            target_from_bits(0x2200_0001),
+            Some({
+                let mut target = [0u8; 32];
+                target[0] = 1;
+                target
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_signatures() {
+        for scheme in [
+            SignatureScheme::Ecdsa,
+            SignatureScheme::Ed25519,
+            SignatureScheme::Schnorr,
+        ] {
+            let key = PrivateKey::random(scheme);
+            let pubkey = key.public_key();
+            let signature = key.sign(b"message");
+            let verify = match scheme {
+                SignatureScheme::Ecdsa => verify_ecdsa,
+                SignatureScheme::Ed25519 => verify_ed25519,
+                SignatureScheme::Schnorr => verify_schnorr,
+            };
+            assert_eq!(verify(&pubkey, b"message", &signature), Ok(()));
+            assert_eq!(
+                verify(&pubkey, b"other message", &signature),
+                Err(VerifyError::Mismatch)
+            );
+            assert_eq!(
+                verify(&pubkey[1..], b"message", &signature),
+                Err(VerifyError::InvalidPublicKey)
+            );
+        }
+    }
+
+    #[test]
+    fn test_high_s_rejected() {
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let pubkey = key.public_key();
+        let signature = Signature::from_bytes(&key.sign(b"message").into()).unwrap();
+        let high_s = Signature::from_scalars(signature.r(), -*signature.s()).unwrap();
+        assert_eq!(
+            verify_ecdsa(&pubkey, b"message", &high_s.to_bytes().into()),
+            Err(VerifyError::NonCanonical)
+        );
+    }
+}
@@ -0,0 +1,141 @@
+// Soft forks activated by miners signaling in block versions, as in BIP9
+// but with heights instead of times. The chain is split into periods of
+// ChainParams::signal_period blocks, and a deployment's state only changes
+// at period boundaries, based on the period before:
+//
+//   DEFINED    until the period starting at or after start_height
+//   STARTED    miners signal; a period with signal_threshold signaling
+//              blocks locks it in
+//   LOCKED_IN  for one more period, giving nodes time to upgrade
+//   ACTIVE     its rules apply from then on
+//   FAILED     if timeout_height came first
+
+use std::fmt;
+
+// The top three bits of a block version are 001 for blocks taking part,
+// leaving the other 29 for signaling
+pub const VERSIONBITS_TOP_BITS: u32 = 0x2000_0000;
+pub const VERSIONBITS_TOP_MASK: u32 = 0xe000_0000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Deployment {
+    pub name: &'static str,
+    // Below 29
+    pub bit: u8,
+    pub start_height: u64,
+    pub timeout_height: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeploymentState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+    Failed,
+}
+
+impl fmt::Display for DeploymentState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            DeploymentState::Defined => "defined",
+            DeploymentState::Started => "started",
+            DeploymentState::LockedIn => "locked_in",
+            DeploymentState::Active => "active",
+            DeploymentState::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+impl Deployment {
+    pub fn mask(&self) -> u32 {
+        1 << self.bit
+    }
+
+    pub fn is_signaled_by(&self, version: u32) -> bool {
+        version & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS && version & self.mask() != 0
+    }
+
+    // The state for the block at `height`, given the versions of the blocks
+    // before it. `signaling(range)` counts the blocks in the range that
+    // signal for the deployment.
+    pub fn state_at(
+        &self,
+        height: u64,
+        period: u64,
+        threshold: u64,
+        signaling: impl Fn(std::ops::Range<u64>) -> u64,
+    ) -> DeploymentState {
+        let mut state = DeploymentState::Defined;
+        let mut period_start = 0;
+        while period_start + period <= height {
+            let next_start = period_start + period;
+            state = match state {
+                DeploymentState::Defined if next_start >= self.timeout_height => {
+                    DeploymentState::Failed
+                }
+                DeploymentState::Defined if next_start >= self.start_height => {
+                    DeploymentState::Started
+                }
+                DeploymentState::Started => {
+                    if signaling(period_start..next_start) >= threshold {
+                        DeploymentState::LockedIn
+                    } else if next_start >= self.timeout_height {
+                        DeploymentState::Failed
+                    } else {
+                        DeploymentState::Started
+                    }
+                }
+                DeploymentState::LockedIn => DeploymentState::Active,
+                state => state,
+            };
+            period_start = next_start;
+        }
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEPLOYMENT: Deployment = Deployment {
+        name: "test",
+        bit: 3,
+        start_height: 8,
+        timeout_height: 24,
+    };
+
+    // Blocks from `from` on signal
+    fn state(height: u64, from: u64) -> DeploymentState {
+        DEPLOYMENT.state_at(height, 4, 3, |range| {
+            range.filter(|&height| height >= from).count() as u64
+        })
+    }
+
+    #[test]
+    fn test_state_transitions() {
+        assert_eq!(state(7, 0), DeploymentState::Defined);
+        // Signals before the start don't count
+        assert_eq!(state(8, 0), DeploymentState::Started);
+        assert_eq!(state(12, 0), DeploymentState::LockedIn);
+        assert_eq!(state(15, 0), DeploymentState::LockedIn);
+        assert_eq!(state(16, 0), DeploymentState::Active);
+        assert_eq!(state(100, 0), DeploymentState::Active);
+
+        // Two signals out of four are not enough
+        assert_eq!(state(12, 10), DeploymentState::Started);
+        assert_eq!(state(16, 10), DeploymentState::LockedIn);
+        assert_eq!(state(24, u64::MAX), DeploymentState::Failed);
+        // Locking in during the last period still counts
+        assert_eq!(state(24, 20), DeploymentState::LockedIn);
+    }
+
+    #[test]
+    fn test_signaling() {
+        assert!(DEPLOYMENT.is_signaled_by(VERSIONBITS_TOP_BITS | 8));
+        assert!(!DEPLOYMENT.is_signaled_by(VERSIONBITS_TOP_BITS | 4));
+        assert!(!DEPLOYMENT.is_signaled_by(8));
+    }
+}
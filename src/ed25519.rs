@@ -0,0 +1,449 @@
+// Ed25519 signatures as specified in RFC 8032, section 5.1. Verification
+// only handles public data, so its field and scalar arithmetic use BigUint,
+// which is slow but small and easy to audit against the reference
+// implementation in the RFC.
+//
+// BigUint's running time depends on the values it works on, which would leak
+// the secret scalar and nonce. Deriving a public key and signing instead use
+// crypto-bigint's constant-time Montgomery arithmetic, with a ladder that
+// does the same work for every bit of the scalar and picks between results
+// with masks rather than branches.
+
+// Only verification is part of the consensus core; signing is for wallets
+#![cfg_attr(not(feature = "std"), allow(dead_code))]
+
+use crypto_bigint::modular::constant_mod::Residue;
+use crypto_bigint::subtle::{Choice, ConditionallySelectable};
+use crypto_bigint::{Encoding, U256, impl_modulus};
+use num_bigint::BigUint;
+use sha2::{Digest, Sha512};
+
+impl_modulus!(
+    FieldModulus,
+    U256,
+    "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffed"
+);
+impl_modulus!(
+    ScalarModulus,
+    U256,
+    "1000000000000000000000000000000014def9dea2f79cd65812631a5cf5d3ed"
+);
+
+type FieldElement = Residue<FieldModulus, { U256::LIMBS }>;
+type Scalar = Residue<ScalarModulus, { U256::LIMBS }>;
+
+// p - 2, for inverting by Fermat's little theorem with a fixed exponent
+const P_MINUS_2: U256 =
+    U256::from_be_hex("7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffeb");
+
+struct Curve {
+    p: BigUint,
+    q: BigUint,
+    d: BigUint,
+    sqrt_m1: BigUint,
+    base: Point,
+}
+
+#[derive(Clone)]
+struct Point {
+    x: BigUint,
+    y: BigUint,
+    z: BigUint,
+    t: BigUint,
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = (BigUint::from(1u32) << 255u32) - BigUint::from(19u32);
+        let q = (BigUint::from(1u32) << 252u32)
+            + "27742317777372353535851937790883648493"
+                .parse::<BigUint>()
+                .unwrap();
+        let d = (&p - BigUint::from(121665u32)) * inv(&BigUint::from(121666u32), &p) % &p;
+        let sqrt_m1 =
+            BigUint::from(2u32).modpow(&((&p - BigUint::from(1u32)) / BigUint::from(4u32)), &p);
+
+        let g_y = BigUint::from(4u32) * inv(&BigUint::from(5u32), &p) % &p;
+        let g_x = recover_x_with(&g_y, false, &p, &d, &sqrt_m1).unwrap();
+        let base = Point {
+            t: &g_x * &g_y % &p,
+            x: g_x,
+            y: g_y,
+            z: BigUint::from(1u32),
+        };
+
+        Curve {
+            p,
+            q,
+            d,
+            sqrt_m1,
+            base,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+static CURVE: std::sync::LazyLock<Curve> = std::sync::LazyLock::new(Curve::new);
+
+#[cfg(feature = "std")]
+fn curve() -> &'static Curve {
+    &CURVE
+}
+
+// Without std there is nowhere to keep the constants, so each signature
+// operation derives them again
+#[cfg(not(feature = "std"))]
+fn curve() -> Curve {
+    Curve::new()
+}
+
+fn inv(x: &BigUint, p: &BigUint) -> BigUint {
+    x.modpow(&(p - BigUint::from(2u32)), p)
+}
+
+fn sub_mod(a: &BigUint, b: &BigUint, p: &BigUint) -> BigUint {
+    (a + p - b % p) % p
+}
+
+fn recover_x_with(
+    y: &BigUint,
+    sign: bool,
+    p: &BigUint,
+    d: &BigUint,
+    sqrt_m1: &BigUint,
+) -> Option<BigUint> {
+    if y >= p {
+        return None;
+    }
+    let y2 = y * y % p;
+    let x2 =
+        sub_mod(&y2, &BigUint::from(1u32), p) * inv(&((d * &y2 + BigUint::from(1u32)) % p), p) % p;
+    if x2 == BigUint::ZERO {
+        return if sign { None } else { Some(x2) };
+    }
+
+    let mut x = x2.modpow(&((p + BigUint::from(3u32)) / BigUint::from(8u32)), p);
+    if (&x * &x % p) != x2 {
+        x = x * sqrt_m1 % p;
+    }
+    if (&x * &x % p) != x2 {
+        return None;
+    }
+    if x.bit(0) != sign {
+        x = p - x;
+    }
+    Some(x)
+}
+
+fn point_add(c: &Curve, a: &Point, b: &Point) -> Point {
+    let p = &c.p;
+    let aa = sub_mod(&a.y, &a.x, p) * sub_mod(&b.y, &b.x, p) % p;
+    let bb = (&a.y + &a.x) * (&b.y + &b.x) % p;
+    let cc = BigUint::from(2u32) * &a.t * &b.t % p * &c.d % p;
+    let dd = BigUint::from(2u32) * &a.z * &b.z % p;
+    let e = sub_mod(&bb, &aa, p);
+    let f = sub_mod(&dd, &cc, p);
+    let g = (&dd + &cc) % p;
+    let h = (&bb + &aa) % p;
+    Point {
+        x: &e * &f % p,
+        y: &g * &h % p,
+        z: &f * &g % p,
+        t: &e * &h % p,
+    }
+}
+
+fn point_mul(c: &Curve, scalar: &BigUint, point: &Point) -> Point {
+    let mut result = Point {
+        x: BigUint::ZERO,
+        y: BigUint::from(1u32),
+        z: BigUint::from(1u32),
+        t: BigUint::ZERO,
+    };
+    let mut addend = point.clone();
+    for i in 0..scalar.bits() {
+        if scalar.bit(i) {
+            result = point_add(c, &result, &addend);
+        }
+        addend = point_add(c, &addend, &addend);
+    }
+    result
+}
+
+// The point in extended coordinates, as used by the constant-time code
+#[derive(Clone, Copy)]
+struct CtPoint {
+    x: FieldElement,
+    y: FieldElement,
+    z: FieldElement,
+    t: FieldElement,
+}
+
+fn field_element(n: &BigUint) -> FieldElement {
+    FieldElement::new(&U256::from_le_slice(&to_le_32(n)))
+}
+
+impl CtPoint {
+    const IDENTITY: CtPoint = CtPoint {
+        x: FieldElement::ZERO,
+        y: FieldElement::ONE,
+        z: FieldElement::ONE,
+        t: FieldElement::ZERO,
+    };
+
+    fn from_point(point: &Point) -> CtPoint {
+        CtPoint {
+            x: field_element(&point.x),
+            y: field_element(&point.y),
+            z: field_element(&point.z),
+            t: field_element(&point.t),
+        }
+    }
+
+    // The same complete formula as point_add, which needs no special case for
+    // doubling or the identity
+    fn add(&self, other: &CtPoint, d2: &FieldElement) -> CtPoint {
+        let a = self.y.sub(&self.x).mul(&other.y.sub(&other.x));
+        let b = self.y.add(&self.x).mul(&other.y.add(&other.x));
+        let c = self.t.mul(d2).mul(&other.t);
+        let d = self.z.add(&self.z).mul(&other.z);
+        let e = b.sub(&a);
+        let f = d.sub(&c);
+        let g = d.add(&c);
+        let h = b.add(&a);
+        CtPoint {
+            x: e.mul(&f),
+            y: g.mul(&h),
+            z: f.mul(&g),
+            t: e.mul(&h),
+        }
+    }
+
+    fn select(a: &CtPoint, b: &CtPoint, choice: Choice) -> CtPoint {
+        let select = |a: &FieldElement, b: &FieldElement| {
+            FieldElement::from_montgomery(U256::conditional_select(
+                a.as_montgomery(),
+                b.as_montgomery(),
+                choice,
+            ))
+        };
+        CtPoint {
+            x: select(&a.x, &b.x),
+            y: select(&a.y, &b.y),
+            z: select(&a.z, &b.z),
+            t: select(&a.t, &b.t),
+        }
+    }
+
+    fn compress(&self) -> [u8; 32] {
+        let z_inv = self.z.pow(&P_MINUS_2);
+        let x = self.x.mul(&z_inv).retrieve().to_le_bytes();
+        let mut out = self.y.mul(&z_inv).retrieve().to_le_bytes();
+        out[31] |= (x[0] & 1) << 7;
+        out
+    }
+}
+
+// Multiplies the base point by a little-endian scalar, doubling and adding
+// for all 256 bits whatever their value
+fn mul_base_ct(c: &Curve, scalar: &[u8; 32]) -> [u8; 32] {
+    let d2 = field_element(&c.d).add(&field_element(&c.d));
+    let base = CtPoint::from_point(&c.base);
+    let mut result = CtPoint::IDENTITY;
+    for i in (0..256).rev() {
+        result = result.add(&result, &d2);
+        let sum = result.add(&base, &d2);
+        let bit = Choice::from((scalar[i / 8] >> (i % 8)) & 1);
+        result = CtPoint::select(&result, &sum, bit);
+    }
+    result.compress()
+}
+
+// A 64-byte hash reduced mod q as lo + hi * 2^256, in constant time
+fn sha512_scalar(parts: &[&[u8]]) -> Scalar {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let hash = hasher.finalize();
+    let two_256 = Scalar::new(&U256::MAX).add(&Scalar::ONE);
+    let lo = Scalar::new(&U256::from_le_slice(&hash[..32]));
+    let hi = Scalar::new(&U256::from_le_slice(&hash[32..]));
+    lo.add(&hi.mul(&two_256))
+}
+
+fn point_equal(c: &Curve, a: &Point, b: &Point) -> bool {
+    let p = &c.p;
+    (&a.x * &b.z % p) == (&b.x * &a.z % p) && (&a.y * &b.z % p) == (&b.y * &a.z % p)
+}
+
+fn to_le_32(n: &BigUint) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let bytes = n.to_bytes_le();
+    out[..bytes.len()].copy_from_slice(&bytes);
+    out
+}
+
+fn decompress(c: &Curve, bytes: &[u8; 32]) -> Option<Point> {
+    let mut y_bytes = *bytes;
+    let sign = y_bytes[31] & 0x80 != 0;
+    y_bytes[31] &= 0x7f;
+    let y = BigUint::from_bytes_le(&y_bytes);
+    let x = recover_x_with(&y, sign, &c.p, &c.d, &c.sqrt_m1)?;
+    Some(Point {
+        t: &x * &y % &c.p,
+        x,
+        y,
+        z: BigUint::from(1u32),
+    })
+}
+
+fn sha512_mod_q(c: &Curve, parts: &[&[u8]]) -> BigUint {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    BigUint::from_bytes_le(&hasher.finalize()) % &c.q
+}
+
+// The clamped secret scalar, little-endian, and the prefix that nonces are
+// derived from
+fn expand_secret(secret: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let h = Sha512::digest(secret);
+    let mut scalar: [u8; 32] = h[..32].try_into().unwrap();
+    scalar[0] &= 0xf8;
+    scalar[31] &= 0x7f;
+    scalar[31] |= 0x40;
+    (scalar, h[32..].try_into().unwrap())
+}
+
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    let c = &curve();
+    let (a, _) = expand_secret(secret);
+    mul_base_ct(c, &a)
+}
+
+pub fn sign(secret: &[u8; 32], message: &[u8]) -> [u8; 64] {
+    let c = &curve();
+    let (a, prefix) = expand_secret(secret);
+    let public = mul_base_ct(c, &a);
+
+    let r = sha512_scalar(&[&prefix, message]);
+    let r_bytes = mul_base_ct(c, &r.retrieve().to_le_bytes());
+    let h = sha512_scalar(&[&r_bytes, &public, message]);
+    let s = r.add(&h.mul(&Scalar::new(&U256::from_le_slice(&a))));
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&r_bytes);
+    signature[32..].copy_from_slice(&s.retrieve().to_le_bytes());
+    signature
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    InvalidPublicKey,
+    InvalidSignature,
+    // An ECDSA signature with a high s, which is as valid as its low-s twin
+    NonCanonical,
+    Mismatch,
+}
+
+pub fn verify(public: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> Result<(), VerifyError> {
+    let c = &curve();
+    let a = decompress(c, public).ok_or(VerifyError::InvalidPublicKey)?;
+
+    let r_bytes: [u8; 32] = signature[..32].try_into().unwrap();
+    let r = decompress(c, &r_bytes).ok_or(VerifyError::InvalidSignature)?;
+    let s = BigUint::from_bytes_le(&signature[32..]);
+    if s >= c.q {
+        return Err(VerifyError::InvalidSignature);
+    }
+
+    let h = sha512_mod_q(c, &[&r_bytes, public, message]);
+    let sb = point_mul(c, &s, &c.base);
+    let ha = point_mul(c, &h, &a);
+    if point_equal(c, &sb, &point_add(c, &r, &ha)) {
+        Ok(())
+    } else {
+        Err(VerifyError::Mismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode<const N: usize>(s: &str) -> [u8; N] {
+        hex::decode(s).unwrap().try_into().unwrap()
+    }
+
+    // RFC 8032 section 7.1, tests 1 and 2
+    #[test]
+    fn test_rfc8032_vectors() {
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+
+        for (secret, public, message, signature) in vectors {
+            let secret: [u8; 32] = decode(secret);
+            let message = hex::decode(message).unwrap();
+            assert_eq!(public_key(&secret), decode::<32>(public));
+            assert_eq!(sign(&secret, &message), decode::<64>(signature));
+            assert_eq!(
+                verify(&decode(public), &message, &decode(signature)),
+                Ok(())
+            );
+        }
+    }
+
+    #[test]
+    fn test_constant_time_mul_matches() {
+        let c = &curve();
+        let scalars = [
+            BigUint::ZERO,
+            BigUint::from(1u32),
+            BigUint::from(8u32),
+            &c.q - BigUint::from(1u32),
+            c.q.clone(),
+            (BigUint::from(1u32) << 256u32) - BigUint::from(1u32),
+            BigUint::from_bytes_le(&[0xa5; 32]),
+        ];
+        for scalar in scalars {
+            assert_eq!(
+                mul_base_ct(c, &to_le_32(&scalar)),
+                CtPoint::from_point(&point_mul(c, &scalar, &c.base)).compress(),
+                "{scalar}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_rejects_tampered_signature() {
+        let secret = [7u8; 32];
+        let public = public_key(&secret);
+        let mut signature = sign(&secret, b"message");
+
+        assert_eq!(
+            verify(&public, b"other message", &signature),
+            Err(VerifyError::Mismatch)
+        );
+
+        // s must be reduced mod q
+        signature[63] = 0xff;
+        assert_eq!(
+            verify(&public, b"message", &signature),
+            Err(VerifyError::InvalidSignature)
+        );
+    }
+}
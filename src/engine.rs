@@ -0,0 +1,162 @@
+// How blocks are sealed: the proof that whoever made a block was entitled
+// to, along with the difficulty and reward that go with it. Everything else
+// about a block, its transactions, digest and place in the chain, is checked
+// the same way whatever the engine, so blocks and transactions keep one
+// format. Proof of work is the engine unless the chain is given another,
+// such as the experimental proof of stake in pos.rs.
+
+use crate::block::{Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::consensus::{self, PowAlgorithm};
+use crate::errors::BlockValidationError;
+use crate::params::ChainParams;
+
+// Each check passes by default, and the schedules are the network's. A
+// block is checked in this order, with check_seal only once the block is
+// known to match its header.
+pub trait ConsensusEngine: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // The bits a block at `height` must have
+    fn expected_bits(&self, blockchain: &Blockchain, _height: u64) -> u32 {
+        blockchain.params().bits
+    }
+
+    // The most a block at `height` may create, before the supply cap
+    fn block_reward(&self, params: &ChainParams, height: u64) -> u64 {
+        params.block_reward(height)
+    }
+
+    // What the header alone shows, which is all headers-first sync has
+    fn check_header(&self, _header: &BlockHeader) -> Result<(), BlockValidationError> {
+        Ok(())
+    }
+
+    // What the rest of the block shows
+    fn check_seal(&self, _block: &Block) -> Result<(), BlockValidationError> {
+        Ok(())
+    }
+
+    // What needs the chain the block extends, which is at the block's parent
+    fn check_producer(
+        &self,
+        _blockchain: &Blockchain,
+        _block: &Block,
+    ) -> Result<(), BlockValidationError> {
+        Ok(())
+    }
+}
+
+// The header hashes below its target with the chain's algorithm. Anyone may
+// produce a block, so there is nothing to check against the chain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProofOfWorkEngine {
+    pub pow: PowAlgorithm,
+}
+
+impl ProofOfWorkEngine {
+    pub fn new(pow: PowAlgorithm) -> ProofOfWorkEngine {
+        ProofOfWorkEngine { pow }
+    }
+}
+
+//TODO: add difficulty adjustment
+impl ConsensusEngine for ProofOfWorkEngine {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    fn check_header(&self, header: &BlockHeader) -> Result<(), BlockValidationError> {
+        if !consensus::meets_target(&header.pow_hash(&self.pow), &header.target()) {
+            return Err(BlockValidationError::InvalidProofOfWork);
+        }
+        Ok(())
+    }
+}
+
+// Takes every block as sealed, so blocks need no mining: for regtest chains
+// that want instant blocks and for tests about anything but the seal
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NoopEngine;
+
+impl ConsensusEngine for NoopEngine {
+    fn name(&self) -> &'static str {
+        "none"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockAcceptance;
+    use crate::errors::TransactionError;
+    use crate::utils;
+    use std::sync::Arc;
+
+    struct FixedReward;
+
+    impl ConsensusEngine for FixedReward {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn expected_bits(&self, _blockchain: &Blockchain, _height: u64) -> u32 {
+            0x2000_ffff
+        }
+
+        fn block_reward(&self, _params: &ChainParams, _height: u64) -> u64 {
+            1_000
+        }
+    }
+
+    #[test]
+    fn test_noop_engine() {
+        let params = ChainParams::regtest();
+        let pow = ProofOfWorkEngine::new(params.pow);
+        let mut blockchain = Blockchain::new(params);
+        blockchain.set_engine(Arc::new(NoopEngine));
+
+        let mut block = blockchain.create_block_template([1; 32]).unwrap();
+        while pow.check_header(&block.header).is_ok() {
+            block.header.nonce += 1;
+            block.update_digest();
+        }
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+
+        // The rest of the block is still checked
+        let mut block = blockchain.create_block_template([1; 32]).unwrap();
+        block.digest.0[0] ^= 1;
+        assert_eq!(
+            blockchain.add_block(block),
+            Err(BlockValidationError::HashDigestMismatch)
+        );
+        assert_eq!(blockchain.height(), 1);
+    }
+
+    #[test]
+    fn test_engine_schedules() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        blockchain.set_engine(Arc::new(FixedReward));
+        assert_eq!(blockchain.get_block_reward(), 1_000);
+        assert_eq!(blockchain.expected_bits(1), 0x2000_ffff);
+
+        let mut block = blockchain.next_block();
+        utils::mine(&mut block, [1; 32], 1_001);
+        assert_eq!(
+            blockchain.add_block(block),
+            Err(BlockValidationError::InvalidTransactions(
+                TransactionError::InvalidCoinbase
+            ))
+        );
+        let mut block = blockchain.next_block();
+        assert_eq!(block.header.bits, 0x2000_ffff);
+        utils::mine(&mut block, [1; 32], 1_000);
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+    }
+}
@@ -1,15 +1,116 @@
-#[derive(Debug, PartialEq)]
+use crate::amount::Amount;
+use crate::hash::{OutPoint, TxId};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum BlockValidationError {
     InvalidPreviousHash,
     InvalidIndex,
     InvalidTimestamp,
     InvalidProofOfWork,
+    InvalidTarget,
+    InvalidMerkleRoot,
+    CheckpointMismatch,
+    BlockTooLarge,
     HashDigestMismatch,
     TimestampInFuture,
     InvalidTransactions(TransactionError),
+    // The block is already in the chain or on a side chain
+    AlreadyKnown,
+    // The block builds on one that failed validation
+    InvalidAncestor,
+    // The coinbase would take the supply above the chain's cap
+    SupplyCapExceeded,
+    // The block isn't sealed as the consensus engine requires
+    InvalidSeal,
+    // Sealed by someone the consensus engine didn't allow to produce it
+    WrongProducer,
+    // A reorg to the block would disconnect blocks whose undo data is gone
+    MissingUndoData,
 }
 
-#[derive(Debug, PartialEq)]
+impl BlockValidationError {
+    // Adds the height of the offending block
+    pub fn at_height(self, height: u64) -> ChainError {
+        ChainError::InvalidBlock {
+            height: Some(height),
+            error: self,
+        }
+    }
+
+    // Whether the block is invalid whatever chain it is checked against, as
+    // opposed to merely late, early or on a fork. Its transactions may spend
+    // outputs only found on another fork, so only their stateless checks
+    // count.
+    pub fn is_invalid_anywhere(&self) -> bool {
+        match self {
+            BlockValidationError::InvalidPreviousHash
+            | BlockValidationError::InvalidIndex
+            | BlockValidationError::InvalidTimestamp
+            | BlockValidationError::TimestampInFuture
+            | BlockValidationError::SupplyCapExceeded
+            | BlockValidationError::WrongProducer
+            | BlockValidationError::MissingUndoData
+            | BlockValidationError::AlreadyKnown => false,
+            BlockValidationError::InvalidTransactions(error) => matches!(
+                error,
+                TransactionError::InvalidPublicKey
+                    | TransactionError::InvalidSignature
+                    | TransactionError::SignatureVerificationFailed
+                    | TransactionError::NonCanonicalSignature
+                    | TransactionError::InvalidID
+                    | TransactionError::ZeroValueOutput
+                    | TransactionError::DuplicateInput
+                    | TransactionError::EmptyInputs
+                    | TransactionError::EmptyOutputs
+                    | TransactionError::InvalidCoinbase
+                    | TransactionError::CoinbaseHeightMismatch
+                    | TransactionError::InvalidSighashType
+                    | TransactionError::UnsupportedVersion
+            ),
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for BlockValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockValidationError::InvalidPreviousHash => write!(f, "does not extend the tip"),
+            BlockValidationError::InvalidIndex => write!(f, "wrong block index"),
+            BlockValidationError::InvalidTimestamp => write!(f, "timestamp before the tip's"),
+            BlockValidationError::InvalidProofOfWork => write!(f, "hash above the target"),
+            BlockValidationError::InvalidTarget => write!(f, "wrong target"),
+            BlockValidationError::InvalidMerkleRoot => write!(f, "merkle root mismatch"),
+            BlockValidationError::CheckpointMismatch => write!(f, "does not match the checkpoint"),
+            BlockValidationError::BlockTooLarge => write!(f, "block too large"),
+            BlockValidationError::HashDigestMismatch => write!(f, "digest is not the header hash"),
+            BlockValidationError::TimestampInFuture => write!(f, "timestamp in the future"),
+            BlockValidationError::InvalidTransactions(err) => {
+                write!(f, "invalid transaction: {err}")
+            }
+            BlockValidationError::AlreadyKnown => write!(f, "block already known"),
+            BlockValidationError::InvalidAncestor => write!(f, "descends from an invalid block"),
+            BlockValidationError::SupplyCapExceeded => write!(f, "coin supply above the cap"),
+            BlockValidationError::InvalidSeal => write!(f, "invalid block seal"),
+            BlockValidationError::WrongProducer => write!(f, "not the block's producer"),
+            BlockValidationError::MissingUndoData => {
+                write!(f, "chain can't be rolled back to the fork")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BlockValidationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlockValidationError::InvalidTransactions(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum TransactionError {
     InvalidPublicKey,
     InvalidSignature,
@@ -17,14 +118,750 @@ pub enum TransactionError {
     InvalidID,
     InvalidTimestamp,
     ZeroValueOutput,
+    // An output, or the sum of the outputs, is above MAX_MONEY
+    AmountOutOfRange,
     DuplicateInput,
-    DuplicateOutput,
     EmptyInputs,
     EmptyOutputs,
     InvalidCoinbase,
+    // The coinbase does not commit to the height of its block
+    CoinbaseHeightMismatch,
     // stateful validation errors
     DoubleSpend,
     InvalidUTXO,
+    // The output existed but was spent by an earlier block
+    AlreadySpent,
+    // The outpoint is already spent by the mempool transaction `spent_by`
+    MempoolConflict { outpoint: OutPoint, spent_by: TxId },
+    // A replacement must pay the fees of the transactions it evicts plus its
+    // own size at the mempool's incremental relay fee rate
+    ReplacementFeeTooLow,
+    // A replacement would evict more than MAX_REPLACED_TRANSACTIONS
+    TooManyReplacements,
+    // Pays less than the mempool's minimum relay fee rate
+    FeeTooLow,
+    // An output is worth less than it would cost to spend
+    Dust,
+    // Pays too little to stay in a mempool at its maximum size
+    MempoolFull,
     Overspend,
     UnauthorizedSpend,
+    Timelocked,
+    LocktimeNotReached,
+    RelativeLocktimeNotReached,
+    AlreadyInMempool,
+    // A raw transaction that could not be decoded
+    Malformed(DecodeError),
+    // A SINGLE input without an output at its index
+    InvalidSighashType,
+    // An input's value is not that of the output it spends
+    InputValueMismatch,
+    UnsupportedVersion,
+    // A version not yet active at the block's height
+    VersionNotActive,
+    // An ECDSA signature with a high s
+    NonCanonicalSignature,
+}
+
+impl TransactionError {
+    // Adds the id of the offending transaction
+    pub fn for_tx(self, txid: TxId) -> ChainError {
+        ChainError::InvalidTransaction {
+            txid: Some(txid),
+            error: self,
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransactionError::InvalidPublicKey => write!(f, "invalid public key"),
+            TransactionError::InvalidSignature => write!(f, "invalid signature"),
+            TransactionError::SignatureVerificationFailed => {
+                write!(f, "signature verification failed")
+            }
+            TransactionError::InvalidID => write!(f, "id is not the transaction hash"),
+            TransactionError::InvalidTimestamp => write!(f, "invalid timestamp"),
+            TransactionError::ZeroValueOutput => write!(f, "output of zero value"),
+            TransactionError::AmountOutOfRange => write!(f, "amount above the money supply"),
+            TransactionError::DuplicateInput => write!(f, "output spent twice"),
+            TransactionError::EmptyInputs => write!(f, "no inputs"),
+            TransactionError::EmptyOutputs => write!(f, "no outputs"),
+            TransactionError::InvalidCoinbase => write!(f, "invalid coinbase"),
+            TransactionError::CoinbaseHeightMismatch => {
+                write!(f, "coinbase does not commit to the block height")
+            }
+            TransactionError::DoubleSpend => write!(f, "output spent twice in the block"),
+            TransactionError::InvalidUTXO => write!(f, "spends an unknown output"),
+            TransactionError::AlreadySpent => write!(f, "spends an already spent output"),
+            TransactionError::MempoolConflict { outpoint, spent_by } => {
+                write!(
+                    f,
+                    "{outpoint} already spent by mempool transaction {spent_by}"
+                )
+            }
+            TransactionError::ReplacementFeeTooLow => write!(f, "replacement fee too low"),
+            TransactionError::TooManyReplacements => {
+                write!(f, "replacement would evict too many transactions")
+            }
+            TransactionError::FeeTooLow => write!(f, "fee rate below the minimum relay fee rate"),
+            TransactionError::Dust => write!(f, "output below the dust threshold"),
+            TransactionError::MempoolFull => write!(f, "mempool full"),
+            TransactionError::Overspend => write!(f, "outputs exceed inputs"),
+            TransactionError::UnauthorizedSpend => write!(f, "not authorized to spend"),
+            TransactionError::Timelocked => write!(f, "output still timelocked"),
+            TransactionError::LocktimeNotReached => write!(f, "locktime not reached"),
+            TransactionError::RelativeLocktimeNotReached => {
+                write!(f, "relative locktime not reached")
+            }
+            TransactionError::AlreadyInMempool => write!(f, "already in the mempool"),
+            TransactionError::Malformed(e) => write!(f, "malformed transaction: {e}"),
+            TransactionError::InvalidSighashType => {
+                write!(f, "sighash type SINGLE without a matching output")
+            }
+            TransactionError::InputValueMismatch => {
+                write!(f, "input value differs from the spent output's")
+            }
+            TransactionError::UnsupportedVersion => write!(f, "unsupported transaction version"),
+            TransactionError::VersionNotActive => {
+                write!(f, "transaction version not active at this height")
+            }
+            TransactionError::NonCanonicalSignature => {
+                write!(f, "signature not in canonical low-s form")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Corrupt,
+    GenesisMismatch,
+    InvalidBlock(u64, BlockValidationError),
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        StorageError::Io(err)
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(err) => write!(f, "{err}"),
+            StorageError::Corrupt => write!(f, "corrupt chain file"),
+            StorageError::GenesisMismatch => write!(f, "chain has a different genesis block"),
+            StorageError::InvalidBlock(height, err) => write!(f, "invalid block {height}: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StorageError::Io(err) => Some(err),
+            StorageError::InvalidBlock(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(std::io::Error),
+    // The file doesn't start with the archive magic
+    NotAnArchive,
+    UnsupportedVersion(u32),
+    WrongNetwork,
+    Truncated,
+    // The block at this height fails its checksum or doesn't decode
+    Corrupt(u64),
+    // The chain has a different block at this height
+    Diverges(u64),
+    InvalidBlock(u64, BlockValidationError),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Io(err) => write!(f, "{err}"),
+            ArchiveError::NotAnArchive => write!(f, "not a chain archive"),
+            ArchiveError::UnsupportedVersion(version) => {
+                write!(f, "unsupported archive version {version}")
+            }
+            ArchiveError::WrongNetwork => write!(f, "archive is for another network"),
+            ArchiveError::Truncated => write!(f, "archive is truncated"),
+            ArchiveError::Corrupt(height) => write!(f, "corrupt block {height} in archive"),
+            ArchiveError::Diverges(height) => {
+                write!(f, "archive has a different block at height {height}")
+            }
+            ArchiveError::InvalidBlock(height, err) => write!(f, "invalid block {height}: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ArchiveError::Io(err) => Some(err),
+            ArchiveError::InvalidBlock(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// A worker's solution, or the job for it, can't be used
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkError {
+    // Never handed out, or forgotten since
+    UnknownJob,
+    // The chain has moved on from the job's block
+    Stale,
+    InvalidProofOfWork,
+    Template(TransactionError),
+    Rejected(BlockValidationError),
+}
+
+impl fmt::Display for WorkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkError::UnknownJob => write!(f, "unknown job"),
+            WorkError::Stale => write!(f, "job is stale"),
+            WorkError::InvalidProofOfWork => write!(f, "nonce doesn't meet the target"),
+            WorkError::Template(err) => write!(f, "can't build a block: {err}"),
+            WorkError::Rejected(err) => write!(f, "block rejected: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for WorkError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    // Longer than the limit, or claiming more memory than it allows
+    TooLarge,
+    Invalid,
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge => write!(f, "encoded value too large"),
+            DecodeError::Invalid => write!(f, "invalid encoding"),
+            DecodeError::TrailingBytes => write!(f, "trailing bytes after encoded value"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+    UnsupportedVersion,
+    Corrupt,
+    CommitmentMismatch,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::UnsupportedVersion => write!(f, "unsupported snapshot version"),
+            SnapshotError::Corrupt => write!(f, "corrupt snapshot"),
+            SnapshotError::CommitmentMismatch => write!(f, "snapshot commitment mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectError {
+    Banned,
+    TooManyInbound,
+    TooManyOutbound,
+    AlreadyConnected,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Banned => write!(f, "peer is banned"),
+            ConnectError::TooManyInbound => write!(f, "too many inbound peers"),
+            ConnectError::TooManyOutbound => write!(f, "too many outbound peers"),
+            ConnectError::AlreadyConnected => write!(f, "already connected to peer"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+// A message breaking the limits checked before it is handled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageError {
+    TooLarge { size: usize, max: usize },
+    TooManyTransactions,
+    TooManyInputs,
+    TooManyOutputs,
+    // Too many headers, addresses, hashes or indexes
+    TooManyItems,
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MessageError::TooLarge { size, max } => {
+                write!(f, "message of {size} bytes, above the limit of {max}")
+            }
+            MessageError::TooManyTransactions => write!(f, "too many transactions"),
+            MessageError::TooManyInputs => write!(f, "transaction with too many inputs"),
+            MessageError::TooManyOutputs => write!(f, "transaction with too many outputs"),
+            MessageError::TooManyItems => write!(f, "too many items"),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+#[derive(Debug, PartialEq)]
+pub enum UndoError {
+    // The set was last updated with a different block
+    NotTip,
+    // The undo data doesn't hold the outputs the block spent
+    Mismatch,
+}
+
+impl fmt::Display for UndoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UndoError::NotTip => write!(f, "block is not the tip of the UTXO set"),
+            UndoError::Mismatch => write!(f, "undo data does not match the block"),
+        }
+    }
+}
+
+impl std::error::Error for UndoError {}
+
+// Any error from validating, storing or loading the chain, so callers
+// handling several of them can use `?` throughout. Errors converted with `?`
+// carry no height or txid; at_height() and for_tx() add them.
+#[derive(Debug)]
+pub enum ChainError {
+    InvalidBlock {
+        height: Option<u64>,
+        error: BlockValidationError,
+    },
+    InvalidTransaction {
+        txid: Option<TxId>,
+        error: TransactionError,
+    },
+    Storage(StorageError),
+    Snapshot(SnapshotError),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for ChainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChainError::InvalidBlock {
+                height: Some(height),
+                error,
+            } => write!(f, "invalid block {height}: {error}"),
+            ChainError::InvalidBlock {
+                height: None,
+                error,
+            } => write!(f, "invalid block: {error}"),
+            ChainError::InvalidTransaction {
+                txid: Some(txid),
+                error,
+            } => write!(f, "invalid transaction {txid}: {error}"),
+            ChainError::InvalidTransaction { txid: None, error } => {
+                write!(f, "invalid transaction: {error}")
+            }
+            ChainError::Storage(err) => write!(f, "{err}"),
+            ChainError::Snapshot(err) => write!(f, "{err}"),
+            ChainError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ChainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ChainError::InvalidBlock { error, .. } => Some(error),
+            ChainError::InvalidTransaction { error, .. } => Some(error),
+            ChainError::Storage(err) => Some(err),
+            ChainError::Snapshot(err) => Some(err),
+            ChainError::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<BlockValidationError> for ChainError {
+    fn from(error: BlockValidationError) -> Self {
+        ChainError::InvalidBlock {
+            height: None,
+            error,
+        }
+    }
+}
+
+impl From<TransactionError> for ChainError {
+    fn from(error: TransactionError) -> Self {
+        ChainError::InvalidTransaction { txid: None, error }
+    }
+}
+
+impl From<StorageError> for ChainError {
+    fn from(err: StorageError) -> Self {
+        ChainError::Storage(err)
+    }
+}
+
+impl From<SnapshotError> for ChainError {
+    fn from(err: SnapshotError) -> Self {
+        ChainError::Snapshot(err)
+    }
+}
+
+impl From<std::io::Error> for ChainError {
+    fn from(err: std::io::Error) -> Self {
+        ChainError::Io(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(std::io::Error),
+    Corrupt,
+    UnsupportedVersion,
+    // Key stretching too weak to protect the key, or so slow that opening
+    // the file would hang
+    InvalidIterations(u32),
+    WrongPassphrase,
+    // The wallet signs through a Signer and has no key to save
+    ExternalKey,
+}
+
+impl From<std::io::Error> for KeystoreError {
+    fn from(err: std::io::Error) -> Self {
+        KeystoreError::Io(err)
+    }
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "{e}"),
+            KeystoreError::Corrupt => write!(f, "wallet file is corrupt"),
+            KeystoreError::UnsupportedVersion => write!(f, "unsupported wallet file version"),
+            KeystoreError::InvalidIterations(iterations) => {
+                write!(f, "wallet file asks for {iterations} KDF iterations")
+            }
+            KeystoreError::WrongPassphrase => write!(f, "wrong passphrase"),
+            KeystoreError::ExternalKey => {
+                write!(f, "the wallet's key is held by an external signer")
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WalletManagerError {
+    // Names are letters, digits, '-' and '_'
+    InvalidName(String),
+    AlreadyLoaded(String),
+    NotLoaded(String),
+    // A wallet file with the name exists
+    AlreadyExists(String),
+    NotFound(String),
+    // No wallet was named and several are loaded
+    NotSpecified,
+    NoWallets,
+    // The manager keeps wallets in memory only
+    NoWalletDir,
+    Keystore(KeystoreError),
+}
+
+impl From<KeystoreError> for WalletManagerError {
+    fn from(err: KeystoreError) -> Self {
+        WalletManagerError::Keystore(err)
+    }
+}
+
+impl From<std::io::Error> for WalletManagerError {
+    fn from(err: std::io::Error) -> Self {
+        WalletManagerError::Keystore(KeystoreError::Io(err))
+    }
+}
+
+impl fmt::Display for WalletManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WalletManagerError::InvalidName(name) => write!(f, "invalid wallet name {name:?}"),
+            WalletManagerError::AlreadyLoaded(name) => write!(f, "wallet {name} is already loaded"),
+            WalletManagerError::NotLoaded(name) => write!(f, "wallet {name} is not loaded"),
+            WalletManagerError::AlreadyExists(name) => write!(f, "wallet {name} already exists"),
+            WalletManagerError::NotFound(name) => write!(f, "wallet {name} not found"),
+            WalletManagerError::NotSpecified => {
+                write!(f, "several wallets are loaded, name the one to use")
+            }
+            WalletManagerError::NoWallets => write!(f, "no wallet is loaded"),
+            WalletManagerError::NoWalletDir => write!(f, "the node keeps no wallet files"),
+            WalletManagerError::Keystore(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for WalletManagerError {}
+
+#[derive(Debug, PartialEq)]
+pub enum MnemonicError {
+    InvalidWordCount,
+    UnknownWord(String),
+    InvalidChecksum,
+    InvalidWordlist,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    InvalidLength,
+    InvalidCharacter,
+    MixedCase,
+    WrongPrefix,
+    InvalidChecksum,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonError {
+    Syntax(usize),
+    Custom(String),
+}
+
+impl std::fmt::Display for JsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JsonError::Syntax(pos) => write!(f, "invalid JSON at byte {pos}"),
+            JsonError::Custom(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for JsonError {}
+
+impl serde::ser::Error for JsonError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        JsonError::Custom(msg.to_string())
+    }
+}
+
+impl serde::de::Error for JsonError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        JsonError::Custom(msg.to_string())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PsbtError {
+    InputOutOfRange(usize),
+    // The output spent by the input is not known
+    MissingUtxo(usize),
+    // The key cannot sign for the input's output
+    KeyNotInvolved(usize),
+    // The input's value is not that of the output it spends
+    ValueMismatch(usize),
+    MissingSignatures(usize),
+    DifferentTransaction,
+    Signer(SignerError),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MusigError {
+    NoKeys,
+    InvalidPublicKey(usize),
+    InvalidNonce(usize),
+    WrongNonceCount,
+    WrongSignatureCount,
+    // The key is not one of the aggregated ones
+    UnknownSigner,
+    // The secret nonce is not the one the signer shared
+    NonceMismatch,
+    InvalidPartialSignature(usize),
+    // MuSig needs the secret key, which a Signer doesn't give out
+    ExternalKey,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SwapError {
+    // The step doesn't apply to a swap in this state
+    WrongState(crate::swap::SwapState),
+    // The counterparty's HTLC doesn't pay us with the swap's payment hash
+    WrongTerms,
+    // The counterparty's HTLC would time out before we could claim it
+    TimeoutTooSoon,
+    HtlcNotFound,
+    PreimageUnknown,
+    // Our HTLC can only be refunded in blocks from this height on
+    NotTimedOut(u64),
+    Build(BuildError),
+    Signer(SignerError),
+}
+
+impl fmt::Display for SwapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SwapError::WrongState(state) => write!(f, "not possible in state {state}"),
+            SwapError::WrongTerms => write!(f, "HTLC terms don't match the swap"),
+            SwapError::TimeoutTooSoon => write!(f, "HTLC times out too soon"),
+            SwapError::HtlcNotFound => write!(f, "HTLC not found"),
+            SwapError::PreimageUnknown => write!(f, "preimage not known yet"),
+            SwapError::NotTimedOut(height) => write!(f, "HTLC refundable from height {height}"),
+            SwapError::Build(error) => write!(f, "{error}"),
+            SwapError::Signer(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for SwapError {}
+
+// Why a Signer gave no signature
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignerError {
+    // Turned down by the key's holder, e.g. on a hardware wallet's screen
+    Rejected,
+    // The device or service holding the key couldn't be reached
+    Unavailable(String),
+}
+
+impl fmt::Display for SignerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignerError::Rejected => write!(f, "signing rejected"),
+            SignerError::Unavailable(reason) => write!(f, "signer unavailable: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for SignerError {}
+
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    NoPayments,
+    // A payment request left the amount to the payer
+    NoAmount,
+    // The wallet's outputs don't cover the payments and the fee
+    InsufficientFunds { available: Amount, needed: Amount },
+    AmountOutOfRange,
+    Signer(SignerError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NoPayments => write!(f, "no payments"),
+            BuildError::NoAmount => write!(f, "the payment request has no amount"),
+            BuildError::InsufficientFunds { available, needed } => {
+                write!(
+                    f,
+                    "insufficient funds: {available} available, {needed} needed"
+                )
+            }
+            BuildError::AmountOutOfRange => write!(f, "amount above the money supply"),
+            BuildError::Signer(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Debug, PartialEq)]
+pub enum PaymentRequestError {
+    WrongScheme,
+    Address(AddressError),
+    InvalidAmount,
+    // A bad percent escape, or one decoding to invalid UTF-8
+    InvalidEncoding,
+    DuplicateParameter(String),
+    // A "req-" parameter this wallet doesn't understand
+    UnknownRequired(String),
+}
+
+impl fmt::Display for PaymentRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentRequestError::WrongScheme => write!(f, "not a payment request"),
+            PaymentRequestError::Address(error) => write!(f, "invalid address: {error:?}"),
+            PaymentRequestError::InvalidAmount => write!(f, "invalid amount"),
+            PaymentRequestError::InvalidEncoding => write!(f, "invalid percent encoding"),
+            PaymentRequestError::DuplicateParameter(key) => write!(f, "{key} given twice"),
+            PaymentRequestError::UnknownRequired(key) => {
+                write!(f, "unsupported required parameter {key}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PaymentRequestError {}
+
+#[derive(Debug)]
+pub enum InvoiceError {
+    Io(std::io::Error),
+    Corrupt,
+    InvalidAmount,
+    // Another invoice waiting on a payment to the same pkhash
+    AddressInUse(u64),
+}
+
+impl From<std::io::Error> for InvoiceError {
+    fn from(err: std::io::Error) -> Self {
+        InvoiceError::Io(err)
+    }
+}
+
+impl fmt::Display for InvoiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvoiceError::Io(e) => write!(f, "{e}"),
+            InvoiceError::Corrupt => write!(f, "invoice file is corrupt"),
+            InvoiceError::InvalidAmount => write!(f, "invalid amount"),
+            InvoiceError::AddressInUse(id) => write!(f, "address in use by invoice {id}"),
+        }
+    }
+}
+
+impl std::error::Error for InvoiceError {}
+
+#[derive(Debug)]
+pub enum RpcError {
+    Io(std::io::Error),
+    InvalidResponse,
+    // The server turned down the credentials
+    Unauthorized,
+    // Error object returned by the server
+    Remote(i64, String),
+}
+
+impl From<std::io::Error> for RpcError {
+    fn from(err: std::io::Error) -> Self {
+        RpcError::Io(err)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    // Line number and the offending line
+    InvalidLine(usize, String),
+    UnknownKey(String),
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::Io(err)
+    }
 }
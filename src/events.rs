@@ -0,0 +1,302 @@
+use crate::block::Block;
+use crate::hash::{Hash256, OutPoint, TxId};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent {
+    BlockConnected { digest: Hash256, height: u64 },
+    // For each block a reorg takes off the tip, newest first
+    BlockDisconnected { digest: Hash256, height: u64 },
+    TxConfirmed(TxId),
+    // Added to the mempool
+    TxAccepted(TxId),
+    // Dropped from the mempool unconfirmed, having been there too long or
+    // paying too little once it filled up. Wallets may want to rebroadcast
+    // or replace them.
+    TxExpired(TxId),
+    TxEvicted(TxId),
+    UTXOSpent(OutPoint),
+}
+
+// Whole blocks as they join or leave the chain, for BlockStream
+#[derive(Clone)]
+pub enum BlockNotification {
+    Connected(Block),
+    Disconnected(Block),
+}
+
+// Channels of the subscribers to chain updates. A subscriber is dropped once
+// its receiver is.
+#[derive(Default)]
+pub struct Subscribers {
+    senders: Vec<Sender<ChainEvent>>,
+    block_senders: Vec<Sender<BlockNotification>>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self) -> Receiver<ChainEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.push(sender);
+        receiver
+    }
+
+    pub fn subscribe_blocks(&mut self) -> Receiver<BlockNotification> {
+        let (sender, receiver) = mpsc::channel();
+        self.block_senders.push(sender);
+        receiver
+    }
+
+    fn notify(&mut self, notification: BlockNotification) {
+        self.block_senders
+            .retain(|sender| sender.send(notification.clone()).is_ok());
+    }
+
+    pub fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.is_empty()
+    }
+
+    pub fn emit(&mut self, event: ChainEvent) {
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+
+    // The block first, then its transactions in order, each followed by the
+    // outpoints it spends
+    pub fn block_connected(&mut self, block: &Block) {
+        if !self.block_senders.is_empty() {
+            self.notify(BlockNotification::Connected(block.clone()));
+        }
+        if self.senders.is_empty() {
+            return;
+        }
+
+        self.emit(ChainEvent::BlockConnected {
+            digest: block.digest,
+            height: block.header.index,
+        });
+        for (position, tx) in block.transactions.iter().enumerate() {
+            self.emit(ChainEvent::TxConfirmed(tx.id));
+            if position > 0 {
+                for input in &tx.inputs {
+                    self.emit(ChainEvent::UTXOSpent(input.outpoint()));
+                }
+            }
+        }
+    }
+
+    // For a block removed from the tip
+    pub fn block_disconnected(&mut self, block: &Block) {
+        if !self.block_senders.is_empty() {
+            self.notify(BlockNotification::Disconnected(block.clone()));
+        }
+        self.emit(ChainEvent::BlockDisconnected {
+            digest: block.digest,
+            height: block.header.index,
+        });
+    }
+}
+
+// The blocks near the tip with their confirmations: 1 for the tip, 2 for its
+// parent and so on. Each time the tip changes, every block within `depth`
+// of it is yielded again with its new count, oldest first. A block
+// disconnected in a reorg is yielded with 0 confirmations, followed by the
+// lowered counts of the others, so waiting for confirmations never trusts a
+// block that left the chain.
+//
+// next() blocks until the chain sends another block, and the stream ends
+// once the chain is dropped.
+pub struct BlockStream {
+    receiver: Receiver<BlockNotification>,
+    recent: BTreeMap<u64, Block>,
+    depth: u64,
+    tip: u64,
+    pending: VecDeque<(Block, u64)>,
+}
+
+impl BlockStream {
+    // `recent` are the blocks already in the chain up to `tip`, as far back
+    // as wanted
+    pub fn new(
+        receiver: Receiver<BlockNotification>,
+        recent: impl IntoIterator<Item = Block>,
+        tip: u64,
+        depth: u64,
+    ) -> BlockStream {
+        let mut stream = BlockStream {
+            receiver,
+            recent: recent
+                .into_iter()
+                .map(|block| (block.header.index, block))
+                .collect(),
+            depth,
+            tip,
+            pending: VecDeque::new(),
+        };
+        stream.prune();
+        stream
+    }
+
+    pub fn tip(&self) -> u64 {
+        self.tip
+    }
+
+    // 0 for blocks not in the chain
+    pub fn confirmations(&self, digest: &Hash256) -> u64 {
+        self.recent
+            .values()
+            .find(|block| block.digest == *digest)
+            .map_or(0, |block| self.tip + 1 - block.header.index)
+    }
+
+    fn prune(&mut self) {
+        let tip = self.tip;
+        let depth = self.depth;
+        self.recent
+            .retain(|&height, _| height <= tip && tip - height < depth);
+    }
+
+    fn apply(&mut self, notification: BlockNotification) {
+        match notification {
+            BlockNotification::Connected(block) => {
+                self.tip = block.header.index;
+                self.recent.insert(self.tip, block);
+            }
+            BlockNotification::Disconnected(block) => {
+                let height = block.header.index;
+                self.recent.remove(&height);
+                self.tip = height.saturating_sub(1);
+                self.pending.push_back((block, 0));
+            }
+        }
+        self.prune();
+        for block in self.recent.values() {
+            let confirmations = self.tip + 1 - block.header.index;
+            self.pending.push_back((block.clone(), confirmations));
+        }
+    }
+
+    // Like next, but gives up after `timeout`
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<(Block, u64)> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.receiver.recv_timeout(remaining) {
+                Ok(notification) => self.apply(notification),
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    // Waits until the chain reaches `height`, returning the block there
+    pub fn wait_for_block(&mut self, height: u64) -> Option<Block> {
+        if let Some(block) = self.recent.get(&height) {
+            return Some(block.clone());
+        }
+        self.find(|(block, confirmations)| block.header.index == height && *confirmations > 0)
+            .map(|(block, _)| block)
+    }
+
+    // Waits until the block with `digest` has `confirmations`, which must be
+    // at most the stream's depth for it to be seen
+    pub fn wait_for_confirmations(
+        &mut self,
+        digest: &Hash256,
+        confirmations: u64,
+    ) -> Option<Block> {
+        if self.confirmations(digest) >= confirmations {
+            return self.recent.values().find(|b| b.digest == *digest).cloned();
+        }
+        self.find(|(block, count)| block.digest == *digest && *count >= confirmations)
+            .map(|(block, _)| block)
+    }
+}
+
+impl Iterator for BlockStream {
+    type Item = (Block, u64);
+
+    fn next(&mut self) -> Option<(Block, u64)> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Some(item);
+            }
+            let notification = self.receiver.recv().ok()?;
+            self.apply(notification);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dropped_subscriber_removed() {
+        let mut subscribers = Subscribers::default();
+        let kept = subscribers.subscribe();
+        drop(subscribers.subscribe());
+        assert_eq!(subscribers.len(), 2);
+
+        subscribers.emit(ChainEvent::TxConfirmed(TxId([1; 32])));
+        assert_eq!(subscribers.len(), 1);
+        assert_eq!(kept.try_recv(), Ok(ChainEvent::TxConfirmed(TxId([1; 32]))));
+    }
+
+    fn block(height: u64, fork: u8) -> Block {
+        Block::new(height, [fork; 32], 0x2100_ffff, Vec::new())
+    }
+
+    fn counts(stream: &mut BlockStream) -> Vec<(u64, u64)> {
+        let mut counts = Vec::new();
+        while let Some((block, confirmations)) = stream.next_timeout(Duration::ZERO) {
+            counts.push((block.header.index, confirmations));
+        }
+        counts
+    }
+
+    #[test]
+    fn test_block_stream_confirmations() {
+        let mut subscribers = Subscribers::default();
+        let mut stream = BlockStream::new(
+            subscribers.subscribe_blocks(),
+            (0..=5).map(|height| block(height, 0)),
+            5,
+            3,
+        );
+        assert_eq!(stream.confirmations(&block(4, 0).digest), 2);
+        assert_eq!(stream.confirmations(&block(1, 0).digest), 0);
+
+        subscribers.block_connected(&block(6, 0));
+        assert_eq!(counts(&mut stream), vec![(4, 3), (5, 2), (6, 1)]);
+
+        // a reorg replacing block 6
+        subscribers.block_disconnected(&block(6, 0));
+        subscribers.block_connected(&block(6, 1));
+        assert_eq!(
+            counts(&mut stream),
+            vec![(6, 0), (4, 2), (5, 1), (4, 3), (5, 2), (6, 1)]
+        );
+        assert_eq!(stream.confirmations(&block(6, 0).digest), 0);
+        assert_eq!(stream.confirmations(&block(6, 1).digest), 1);
+
+        assert_eq!(stream.wait_for_block(5).unwrap().digest, block(5, 0).digest);
+        subscribers.block_connected(&block(7, 0));
+        assert_eq!(stream.wait_for_block(7).unwrap().digest, block(7, 0).digest);
+        assert!(
+            stream
+                .wait_for_confirmations(&block(6, 1).digest, 2)
+                .is_some()
+        );
+
+        drop(subscribers);
+        assert_eq!(stream.wait_for_block(8).map(|block| block.digest), None);
+    }
+}
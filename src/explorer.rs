@@ -0,0 +1,275 @@
+// A block explorer served by the RPC server: a single page under /explorer
+// that reads the JSON below from the node.
+//
+//   /explorer/api/blocks                  the most recent blocks
+//   /explorer/api/block/<height or hash>  a block with its transactions
+//   /explorer/api/tx/<txid>               a confirmed or mempool transaction
+//   /explorer/api/address/<address>       balance, unspent outputs, history
+//   /explorer/api/mempool                 transactions by fee rate
+//   /explorer/api/stats                   block interval, hash rate, supply
+
+use crate::address::Address;
+use crate::block::Block;
+use crate::hash::{Hash256, TxId};
+use crate::json::{self, Json};
+use crate::network::Node;
+
+pub const EXPLORER_PATH: &str = "/explorer";
+// Blocks listed on the front page
+pub const RECENT_BLOCKS: u64 = 20;
+
+const PAGE: &str = include_str!("explorer/index.html");
+
+pub struct Response {
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn html(body: &str) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: body.to_string(),
+        }
+    }
+
+    fn json(value: Json) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body: value.to_string(),
+        }
+    }
+
+    fn not_found() -> Response {
+        Response {
+            status: "404 Not Found",
+            content_type: "application/json",
+            body: String::new(),
+        }
+    }
+}
+
+// None if the path isn't the explorer's
+pub fn handle(node: &Node, path: &str) -> Option<Response> {
+    let rest = path.strip_prefix(EXPLORER_PATH)?;
+    let response = match rest.trim_end_matches('/') {
+        "" => Response::html(PAGE),
+        "/api/blocks" => Response::json(recent_blocks(node)),
+        "/api/mempool" => Response::json(mempool(node)),
+        "/api/stats" => Response::json(stats(node)),
+        api => {
+            let (kind, id) = api
+                .strip_prefix("/api/")
+                .and_then(|api| api.split_once('/'))?;
+            let value = match kind {
+                "block" => block(node, id),
+                "tx" => transaction(node, id),
+                "address" => address(node, id),
+                _ => return None,
+            };
+            value.map_or_else(Response::not_found, Response::json)
+        }
+    };
+    Some(response)
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(
+        fields
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect(),
+    )
+}
+
+fn block_summary(block: &Block) -> Json {
+    object(vec![
+        ("height", Json::U64(block.header.index)),
+        ("hash", Json::String(block.digest.to_string())),
+        ("timestamp", Json::U64(block.header.timestamp)),
+        ("transactions", Json::U64(block.transactions.len() as u64)),
+    ])
+}
+
+// Newest first
+fn recent_blocks(node: &Node) -> Json {
+    let blockchain = node.blockchain();
+    let tip = blockchain.height();
+    let start = (tip + 1).saturating_sub(RECENT_BLOCKS);
+    let blocks = blockchain.blocks_in_range(start..tip + 1);
+    Json::Array(blocks.iter().rev().map(block_summary).collect())
+}
+
+fn block(node: &Node, id: &str) -> Option<Json> {
+    let blockchain = node.blockchain();
+    let block = match id.parse::<u64>() {
+        Ok(height) => blockchain.get_block_by_height(height),
+        Err(_) => blockchain.get_block_by_hash(&id.parse::<Hash256>().ok()?),
+    }?;
+    json::to_value(block).ok()
+}
+
+// A mempool transaction has no height and no confirmations
+fn transaction(node: &Node, id: &str) -> Option<Json> {
+    let txid: TxId = id.parse().ok()?;
+    let blockchain = node.blockchain();
+    let (tx, height) = match blockchain.get_transaction(&txid) {
+        Some((tx, height)) => (tx, Some(height)),
+        None => (&blockchain.mempool.get(&txid)?.tx, None),
+    };
+    Some(object(vec![
+        ("transaction", json::to_value(tx).ok()?),
+        ("height", height.map_or(Json::Null, Json::U64)),
+        (
+            "confirmations",
+            Json::U64(blockchain.confirmations(&txid).unwrap_or(0)),
+        ),
+    ]))
+}
+
+fn address(node: &Node, id: &str) -> Option<Json> {
+    let address = Address::decode(id).ok()?;
+    let blockchain = node.blockchain();
+
+    let mut outpoints = blockchain.utxos.utxos_from_pkhash(address.pkhash);
+    outpoints.sort();
+    let unspent = outpoints
+        .into_iter()
+        .filter_map(|outpoint| {
+            let value = blockchain.utxos.get_utxo(&outpoint)?.value;
+            Some(object(vec![
+                ("txid", Json::String(outpoint.txid.to_string())),
+                ("index", Json::U64(outpoint.index as u64)),
+                ("value", Json::U64(value.0)),
+            ]))
+        })
+        .collect();
+    // Newest first
+    let history = blockchain
+        .address_history(address.pkhash)
+        .into_iter()
+        .rev()
+        .map(|entry| {
+            object(vec![
+                ("txid", Json::String(entry.txid.to_string())),
+                ("height", Json::U64(entry.height)),
+                ("received", Json::U64(entry.received)),
+                ("sent", Json::U64(entry.sent)),
+            ])
+        })
+        .collect();
+
+    Some(object(vec![
+        ("address", Json::String(address.to_string())),
+        (
+            "balance",
+            Json::U64(blockchain.utxos.balance(address.pkhash)),
+        ),
+        ("unspent", Json::Array(unspent)),
+        ("history", Json::Array(history)),
+    ]))
+}
+
+fn stats(node: &Node) -> Json {
+    json::to_value(&node.blockchain().stats()).unwrap()
+}
+
+// Highest fee rate first
+fn mempool(node: &Node) -> Json {
+    let blockchain = node.blockchain();
+    let mut entries: Vec<_> = blockchain
+        .mempool
+        .iter()
+        .filter_map(|tx| blockchain.mempool.get(&tx.id))
+        .collect();
+    entries.sort_by_key(|entry| (std::cmp::Reverse(entry.fee_rate()), entry.tx.id));
+    Json::Array(
+        entries
+            .into_iter()
+            .map(|entry| {
+                object(vec![
+                    ("txid", Json::String(entry.tx.id.to_string())),
+                    ("fee", Json::U64(entry.fee.0)),
+                    ("size", Json::U64(entry.size as u64)),
+                    ("feerate", Json::U64(entry.fee_rate())),
+                ])
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use crate::transaction::{Transaction, TxInput, TxOutput};
+    use crate::utils;
+    use crate::wallet::Wallet;
+
+    fn get(node: &Node, path: &str) -> Json {
+        let response = handle(node, path).unwrap();
+        assert_eq!(response.status, "200 OK");
+        response.body.parse().unwrap()
+    }
+
+    #[test]
+    fn test_explorer_api() {
+        let wallet = Wallet::new();
+        let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+        let mut block = node.blockchain().next_block();
+        let reward = node.blockchain().get_block_reward();
+        utils::mine(&mut block, wallet.pkhash, reward);
+        let coinbase = block.transactions[0].id;
+        node.submit_block(block.clone()).unwrap();
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(coinbase, 0).with_value(reward)],
+            vec![TxOutput::new(reward - 1000, [1; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        node.submit_transaction(tx.clone()).unwrap();
+
+        let page = handle(&node, "/explorer/").unwrap();
+        assert!(page.content_type.starts_with("text/html"));
+        assert!(handle(&node, "/other").is_none());
+        assert!(handle(&node, "/explorer/api/unknown/1").is_none());
+        assert_eq!(
+            handle(&node, "/explorer/api/block/7").unwrap().status,
+            "404 Not Found"
+        );
+
+        let blocks = get(&node, "/explorer/api/blocks");
+        let blocks = blocks.as_array().unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(
+            blocks[0].get("hash"),
+            Some(&Json::String(block.digest.to_string()))
+        );
+
+        let by_hash = get(&node, &format!("/explorer/api/block/{}", block.digest));
+        assert_eq!(by_hash, get(&node, "/explorer/api/block/1"));
+
+        let confirmed = get(&node, &format!("/explorer/api/tx/{coinbase}"));
+        assert_eq!(confirmed.get("confirmations"), Some(&Json::U64(1)));
+        let pending = get(&node, &format!("/explorer/api/tx/{}", tx.id));
+        assert_eq!(pending.get("height"), Some(&Json::Null));
+
+        let address = get(&node, &format!("/explorer/api/address/{}", wallet.address));
+        assert_eq!(address.get("balance"), Some(&Json::U64(reward)));
+        assert_eq!(address.get("history").unwrap().as_array().unwrap().len(), 1);
+
+        let mempool = get(&node, "/explorer/api/mempool");
+        assert_eq!(
+            mempool.as_array().unwrap()[0].get("fee"),
+            Some(&Json::U64(1000))
+        );
+
+        let stats = get(&node, "/explorer/api/stats");
+        assert_eq!(stats.get("height"), Some(&Json::U64(1)));
+        assert_eq!(stats.get("supply"), Some(&Json::U64(reward)));
+    }
+}
@@ -0,0 +1,114 @@
+use crate::block::Block;
+use crate::mempool::Mempool;
+use std::cmp::Reverse;
+use std::collections::VecDeque;
+
+// Blocks whose confirmations are remembered
+pub const FEE_HISTORY_BLOCKS: usize = 100;
+// Blocks to confirm within when no target is given
+pub const DEFAULT_FEE_TARGET: u64 = 6;
+// Share of the transactions paying at least the estimate that must have
+// confirmed within the target, in percent
+const SUCCESS_PERCENT: usize = 90;
+
+// A transaction seen in the mempool and later confirmed
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Confirmation {
+    // In base units per 1000 bytes
+    fee_rate: u64,
+    // 1 if it confirmed in the first block mined after it was accepted
+    blocks: u64,
+}
+
+// Learns from how long mempool transactions took to confirm at the fee rates
+// they paid. Transactions first seen in a block say nothing about waiting,
+// so only those that went through the mempool are counted.
+#[derive(Default)]
+pub struct FeeEstimator {
+    // One entry per connected block, oldest first
+    history: VecDeque<Vec<Confirmation>>,
+}
+
+impl FeeEstimator {
+    pub fn new() -> FeeEstimator {
+        FeeEstimator::default()
+    }
+
+    // Must be called before the block's transactions leave the mempool
+    pub fn process_block(&mut self, block: &Block, mempool: &Mempool) {
+        let confirmations = block
+            .transactions
+            .iter()
+            .filter_map(|tx| mempool.get(&tx.id))
+            .map(|entry| Confirmation {
+                fee_rate: entry.fee_rate(),
+                blocks: block.header.index.saturating_sub(entry.height) + 1,
+            })
+            .collect();
+
+        if self.history.len() == FEE_HISTORY_BLOCKS {
+            self.history.pop_front();
+        }
+        self.history.push_back(confirmations);
+    }
+
+    // The lowest fee rate, in base units per 1000 bytes, at which most
+    // recent transactions paying as much confirmed within `target_blocks`.
+    // None until enough has been seen.
+    pub fn estimate(&self, target_blocks: u64) -> Option<u64> {
+        let mut confirmations: Vec<&Confirmation> = self.history.iter().flatten().collect();
+        confirmations.sort_by_key(|confirmation| Reverse(confirmation.fee_rate));
+
+        let mut estimate = None;
+        let (mut total, mut in_time) = (0, 0);
+        for confirmation in confirmations {
+            total += 1;
+            if confirmation.blocks <= target_blocks {
+                in_time += 1;
+            }
+            if in_time * 100 < total * SUCCESS_PERCENT {
+                break;
+            }
+            estimate = Some(confirmation.fee_rate);
+        }
+        estimate
+    }
+
+    pub fn tracked_blocks(&self) -> usize {
+        self.history.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimator(blocks: &[&[(u64, u64)]]) -> FeeEstimator {
+        let mut estimator = FeeEstimator::new();
+        for block in blocks {
+            estimator.history.push_back(
+                block
+                    .iter()
+                    .map(|&(fee_rate, blocks)| Confirmation { fee_rate, blocks })
+                    .collect(),
+            );
+        }
+        estimator
+    }
+
+    #[test]
+    fn test_estimate() {
+        assert_eq!(FeeEstimator::new().estimate(1), None);
+
+        // high rates confirm at once, low ones take a few blocks
+        let estimator = estimator(&[
+            &[(5000, 1), (4000, 1), (1000, 3)],
+            &[(4500, 1), (3000, 1), (1200, 4)],
+            &[(3500, 1), (2000, 2), (1000, 5)],
+        ]);
+        assert_eq!(estimator.estimate(1), Some(3000));
+        assert_eq!(estimator.estimate(2), Some(2000));
+        assert_eq!(estimator.estimate(5), Some(1000));
+        assert!(estimator.estimate(1) >= estimator.estimate(3));
+    }
+}
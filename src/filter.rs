@@ -0,0 +1,108 @@
+use crate::block::Block;
+use crate::hash::OutPoint;
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+
+// About 1% false positives
+const BITS_PER_ELEMENT: usize = 10;
+const HASH_COUNT: u64 = 7;
+
+// A bloom filter over the output pkhashes and spent outpoints of a block, so
+// a light client can tell whether a block may concern its addresses without
+// downloading the transactions. A match can be a false positive, but a
+// relevant block always matches.
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct BlockFilter {
+    bits: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn for_block(block: &Block) -> BlockFilter {
+        let mut elements: Vec<Vec<u8>> = Vec::new();
+        for (position, tx) in block.transactions.iter().enumerate() {
+            if position > 0 {
+                for input in &tx.inputs {
+                    elements.push(outpoint_element(&input.outpoint()));
+                }
+            }
+            for output in &tx.outputs {
+                elements.push(output.pkhash.to_vec());
+            }
+        }
+
+        let len = (elements.len() * BITS_PER_ELEMENT).div_ceil(8).max(1);
+        let mut filter = BlockFilter { bits: vec![0; len] };
+        let size = filter.size();
+        for element in &elements {
+            for bit in positions(element, size) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    pub fn contains_pkhash(&self, pkhash: &[u8; 32]) -> bool {
+        self.contains(pkhash)
+    }
+
+    pub fn contains_outpoint(&self, outpoint: &OutPoint) -> bool {
+        self.contains(&outpoint_element(outpoint))
+    }
+
+    // True if the block may pay to any of the pkhashes
+    pub fn matches_any(&self, pkhashes: &[[u8; 32]]) -> bool {
+        pkhashes.iter().any(|pkhash| self.contains_pkhash(pkhash))
+    }
+
+    fn contains(&self, element: &[u8]) -> bool {
+        positions(element, self.size()).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn size(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+}
+
+// Derives the bit positions from two halves of one hash
+fn positions(element: &[u8], size: u64) -> impl Iterator<Item = usize> {
+    let hash = Sha256::digest(element);
+    let h1 = u64::from_be_bytes(hash[..8].try_into().unwrap());
+    let h2 = u64::from_be_bytes(hash[8..16].try_into().unwrap());
+    (0..HASH_COUNT).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % size) as usize)
+}
+
+fn outpoint_element(outpoint: &OutPoint) -> Vec<u8> {
+    let mut element = outpoint.txid.0.to_vec();
+    element.extend_from_slice(&outpoint.index.to_be_bytes());
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Transaction, TxInput, TxOutput};
+
+    #[test]
+    fn test_filter_matches_block_contents() {
+        let mut block = Block::new(1, [0; 32], 0x2100_ffff, vec![]);
+        block.add_coinbase_tx([1; 32], 50);
+        block.add_tx(Transaction::new(
+            vec![TxInput::new_unsigned([7; 32], 3)],
+            vec![TxOutput::new(10, [2; 32]), TxOutput::new(20, [3; 32])],
+        ));
+
+        let filter = BlockFilter::for_block(&block);
+        for pkhash in [[1; 32], [2; 32], [3; 32]] {
+            assert!(filter.contains_pkhash(&pkhash));
+        }
+        assert!(filter.contains_outpoint(&OutPoint::new([7; 32], 3)));
+        assert!(filter.matches_any(&[[9; 32], [2; 32]]));
+
+        let unrelated: Vec<[u8; 32]> = (10..60).map(|i| [i; 32]).collect();
+        let false_positives = unrelated
+            .iter()
+            .filter(|pkhash| filter.contains_pkhash(pkhash))
+            .count();
+        assert!(false_positives < 5);
+    }
+}
@@ -0,0 +1,243 @@
+// Canned regtest chains for tests. A fixture is generated from a seed, the
+// same way every time: its wallets' keys come from the seed, blocks are
+// spaced a minute apart on a mock clock, and each block pays its coinbase to
+// one of the wallets and moves coins between them. Saved to a file, it is
+// loaded by replaying the blocks, which is much faster than mining them.
+//
+// The file is the magic "RBFX", then as big-endian integers the seed (u64)
+// and the number of wallets (u32), then the chain as an archive (see
+// archive.rs).
+
+use crate::amount::Amount;
+use crate::archive;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::clock::{Clock, MockClock};
+use crate::consensus::{self, PowAlgorithm};
+use crate::errors::ArchiveError;
+use crate::hash::OutPoint;
+use crate::params::ChainParams;
+use crate::signature::{PrivateKey, SignatureScheme};
+use crate::transaction::{Transaction, TxInput, TxOutput};
+use crate::wallet::Wallet;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const FIXTURE_MAGIC: [u8; 4] = *b"RBFX";
+const BLOCK_INTERVAL: u64 = 60;
+const FEE: u64 = 1000;
+
+pub struct Fixture {
+    pub seed: u64,
+    pub wallets: Vec<Wallet>,
+    pub blockchain: Blockchain,
+}
+
+impl Fixture {
+    pub fn generate(seed: u64, wallets: usize, blocks: u64) -> Fixture {
+        let params = ChainParams::regtest();
+        let clock = Arc::new(MockClock::new(params.genesis_timestamp));
+        let mut blockchain = Blockchain::new(params);
+        blockchain.set_clock(clock.clone());
+        let wallets: Vec<Wallet> = (0..wallets)
+            .map(|index| Fixture::wallet(seed, index, blockchain.chain_id()))
+            .collect();
+
+        for height in 1..=blocks {
+            clock.advance(BLOCK_INTERVAL);
+            let choice = random(seed, height);
+            let mut block = blockchain.next_block();
+            let mut reward = Amount(blockchain.get_block_reward());
+
+            let sender = &wallets[choice[0] as usize % wallets.len()];
+            let recipient = &wallets[choice[1] as usize % wallets.len()];
+            if let Some(tx) = transfer(&blockchain, sender, recipient.pkhash, &clock) {
+                block.add_tx(tx);
+                reward = reward.checked_add(Amount(FEE)).unwrap();
+            }
+
+            let miner = &wallets[choice[2] as usize % wallets.len()];
+            block.add_coinbase_tx(miner.pkhash, reward);
+            // The coinbase is stamped with the system time
+            block.transactions[0].timestamp = clock.now();
+            block.transactions[0].update_id();
+            block.update_merkle_root();
+            solve(&mut block, &blockchain.params().pow);
+            blockchain.add_block(block).unwrap();
+        }
+
+        Fixture {
+            seed,
+            wallets,
+            blockchain,
+        }
+    }
+
+    // Wallet `index` of the fixtures generated from `seed`
+    pub fn wallet(seed: u64, index: usize, chain_id: [u8; 32]) -> Wallet {
+        // Only a hash above the curve order isn't a key, so this stops
+        // almost always at the first try
+        (0u32..)
+            .find_map(|counter| {
+                let secret = Sha256::new()
+                    .chain_update(b"fixture wallet")
+                    .chain_update(seed.to_be_bytes())
+                    .chain_update((index as u64).to_be_bytes())
+                    .chain_update(counter.to_be_bytes())
+                    .finalize();
+                PrivateKey::from_bytes(SignatureScheme::Ecdsa, &secret)
+            })
+            .map(|key| Wallet::from_key(key, chain_id))
+            .unwrap()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.write_to(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Fixture, ArchiveError> {
+        Fixture::read_from(BufReader::new(File::open(path).map_err(ArchiveError::Io)?))
+    }
+
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(&FIXTURE_MAGIC)?;
+        writer.write_all(&self.seed.to_be_bytes())?;
+        writer.write_all(&(self.wallets.len() as u32).to_be_bytes())?;
+        archive::export(&self.blockchain, writer)?;
+        Ok(())
+    }
+
+    // The blocks are validated as they are replayed
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Fixture, ArchiveError> {
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => ArchiveError::Truncated,
+            _ => ArchiveError::Io(e),
+        })?;
+        if header[..4] != FIXTURE_MAGIC {
+            return Err(ArchiveError::NotAnArchive);
+        }
+        let seed = u64::from_be_bytes(header[4..12].try_into().unwrap());
+        let wallets = u32::from_be_bytes(header[12..16].try_into().unwrap()) as usize;
+
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        archive::import(&mut blockchain, reader)?;
+        let wallets = (0..wallets)
+            .map(|index| Fixture::wallet(seed, index, blockchain.chain_id()))
+            .collect();
+        Ok(Fixture {
+            seed,
+            wallets,
+            blockchain,
+        })
+    }
+}
+
+// Bytes to make the fixture's choices at `height` with
+fn random(seed: u64, height: u64) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(seed.to_be_bytes())
+        .chain_update(height.to_be_bytes())
+        .finalize()
+        .into()
+}
+
+// Sends half of the sender's largest output to the recipient, with the rest
+// less the fee back to the sender
+fn transfer(
+    blockchain: &Blockchain,
+    sender: &Wallet,
+    recipient: [u8; 32],
+    clock: &MockClock,
+) -> Option<Transaction> {
+    let (outpoint, value): (OutPoint, Amount) = blockchain
+        .utxos
+        .utxos_from_pkhash(sender.pkhash)
+        .into_iter()
+        .map(|outpoint| {
+            (
+                outpoint,
+                blockchain.utxos.get_utxo(&outpoint).unwrap().value,
+            )
+        })
+        // Ties are broken by outpoint, as the set is in no particular order
+        .max_by_key(|&(outpoint, value)| (value, outpoint))?;
+    let payment = value.0 / 2;
+    let change = value
+        .0
+        .checked_sub(payment + FEE)
+        .filter(|&change| change > 0)?;
+
+    let mut tx = Transaction::new_with_clock(
+        vec![TxInput::new_unsigned(outpoint.txid, outpoint.index).with_value(value)],
+        vec![
+            TxOutput::new(payment, recipient),
+            TxOutput::new(change, sender.pkhash),
+        ],
+        clock,
+    );
+    sender.sign_transaction(&mut tx).ok()?;
+    Some(tx)
+}
+
+// Like utils::solve_with, but leaves the timestamp alone
+fn solve(block: &mut Block, pow: &PowAlgorithm) {
+    let target = block.header.target();
+    while !consensus::meets_target(&block.header.pow_hash(pow), &target) {
+        block.header.nonce += 1;
+    }
+    block.update_digest();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture_is_deterministic() {
+        let fixture = Fixture::generate(7, 3, 12);
+        let again = Fixture::generate(7, 3, 12);
+        assert_eq!(fixture.blockchain.prev_hash(), again.blockchain.prev_hash());
+        assert_ne!(
+            Fixture::generate(8, 3, 12).blockchain.prev_hash(),
+            fixture.blockchain.prev_hash()
+        );
+
+        // Coins moved between the wallets, which hold all of them
+        let transactions: usize = fixture
+            .blockchain
+            .iter()
+            .skip(1)
+            .map(|block| block.transactions.len() - 1)
+            .sum();
+        assert!(transactions > 0);
+        let held: u64 = fixture
+            .wallets
+            .iter()
+            .map(|wallet| fixture.blockchain.utxos.balance(wallet.pkhash))
+            .sum();
+        assert_eq!(Amount(held), fixture.blockchain.supply());
+    }
+
+    #[test]
+    fn test_save_and_load_fixture() {
+        let fixture = Fixture::generate(1, 2, 5);
+        let mut data = Vec::new();
+        fixture.write_to(&mut data).unwrap();
+
+        let loaded = Fixture::read_from(data.as_slice()).unwrap();
+        assert_eq!(loaded.seed, 1);
+        assert_eq!(loaded.wallets[1].pkhash, fixture.wallets[1].pkhash);
+        assert_eq!(
+            loaded.blockchain.prev_hash(),
+            fixture.blockchain.prev_hash()
+        );
+        assert!(matches!(
+            Fixture::read_from(&data[4..]),
+            Err(ArchiveError::NotAnArchive)
+        ));
+    }
+}
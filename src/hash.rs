@@ -0,0 +1,164 @@
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use std::fmt;
+use std::str::FromStr;
+
+// 32 byte hashes that mean different things, kept apart by type. Both encode
+// exactly like a [u8; 32], and are written as hex.
+macro_rules! hash_newtype {
+    ($name:ident) => {
+        #[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+        pub struct $name(pub [u8; 32]);
+
+        impl $name {
+            pub fn as_bytes(&self) -> &[u8; 32] {
+                &self.0
+            }
+        }
+
+        impl From<[u8; 32]> for $name {
+            fn from(bytes: [u8; 32]) -> Self {
+                $name(bytes)
+            }
+        }
+
+        impl From<$name> for [u8; 32] {
+            fn from(hash: $name) -> Self {
+                hash.0
+            }
+        }
+
+        impl PartialEq<[u8; 32]> for $name {
+            fn eq(&self, other: &[u8; 32]) -> bool {
+                self.0 == *other
+            }
+        }
+
+        impl AsRef<[u8]> for $name {
+            fn as_ref(&self) -> &[u8] {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}", hex::encode(self.0))
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "{}({})", stringify!($name), self)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = String;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                hex::decode(s)
+                    .ok()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map($name)
+                    .ok_or_else(|| format!("invalid hash {s}"))
+            }
+        }
+
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?
+                    .parse()
+                    .map_err(de::Error::custom)
+            }
+        }
+    };
+}
+
+// The hash of a block header
+hash_newtype!(Hash256);
+// The id of a transaction
+hash_newtype!(TxId);
+
+// An output of a transaction, written as `txid:index`
+#[derive(Encode, Decode, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct OutPoint {
+    pub txid: TxId,
+    pub index: u16,
+}
+
+impl OutPoint {
+    pub fn new(txid: impl Into<TxId>, index: u16) -> OutPoint {
+        OutPoint {
+            txid: txid.into(),
+            index,
+        }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.index)
+    }
+}
+
+impl FromStr for OutPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid outpoint {s}");
+        let (txid, index) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(OutPoint {
+            txid: txid.parse().map_err(|_| invalid())?,
+            index: index.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Serialize for OutPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for OutPoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        let txid = TxId([0xab; 32]);
+        assert_eq!(txid.to_string(), "ab".repeat(32));
+        assert_eq!(txid.to_string().parse(), Ok(txid));
+        assert!("abcd".parse::<Hash256>().is_err());
+        assert!("zz".repeat(32).parse::<TxId>().is_err());
+
+        let outpoint = OutPoint::new([1; 32], 7);
+        assert_eq!(outpoint.to_string(), format!("{}:7", "01".repeat(32)));
+        assert_eq!(outpoint.to_string().parse(), Ok(outpoint));
+        assert!("01".repeat(32).parse::<OutPoint>().is_err());
+    }
+
+    #[test]
+    fn test_encodes_like_bytes() {
+        let config = bincode::config::standard();
+        assert_eq!(
+            bincode::encode_to_vec(Hash256([5; 32]), config).unwrap(),
+            bincode::encode_to_vec([5u8; 32], config).unwrap()
+        );
+        assert!(OutPoint::new([1; 32], 9) < OutPoint::new([2; 32], 0));
+    }
+}
@@ -0,0 +1,169 @@
+// Hierarchical deterministic keys (BIP32, generalised to Ed25519 by SLIP-10).
+// Only hardened derivation is supported, which both schemes allow and which
+// means a leaked child key never exposes its siblings or the parent.
+
+use crate::signature::{PrivateKey, SignatureScheme};
+use hmac::{Hmac, Mac};
+use k256::elliptic_curve::PrimeField;
+use k256::{FieldBytes, Scalar};
+use sha2::Sha512;
+
+type HmacSha512 = Hmac<Sha512>;
+
+pub const HARDENED: u32 = 1 << 31;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExtendedKey {
+    pub scheme: SignatureScheme,
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+fn hmac_sha512(key: &[u8], parts: &[&[u8]]) -> ([u8; 32], [u8; 32]) {
+    let mut mac = HmacSha512::new_from_slice(key).unwrap();
+    for part in parts {
+        mac.update(part);
+    }
+    let i = mac.finalize().into_bytes();
+    (i[..32].try_into().unwrap(), i[32..].try_into().unwrap())
+}
+
+fn secp256k1_scalar(bytes: [u8; 32]) -> Option<Scalar> {
+    Option::from(Scalar::from_repr(FieldBytes::from(bytes)))
+}
+
+impl ExtendedKey {
+    pub fn master(scheme: SignatureScheme, seed: &[u8]) -> Option<ExtendedKey> {
+        let curve_key: &[u8] = match scheme {
+            SignatureScheme::Ecdsa | SignatureScheme::Schnorr => b"Bitcoin seed",
+            SignatureScheme::Ed25519 => b"ed25519 seed",
+        };
+        let (key, chain_code) = hmac_sha512(curve_key, &[seed]);
+
+        if scheme != SignatureScheme::Ed25519 {
+            let k = secp256k1_scalar(key)?;
+            if bool::from(k.is_zero()) {
+                return None;
+            }
+        }
+
+        Some(ExtendedKey {
+            scheme,
+            key,
+            chain_code,
+        })
+    }
+
+    // Derives the hardened child at `index`. Returns None for the
+    // astronomically unlikely indices that give an invalid secp256k1 key; BIP32
+    // says to skip to the next index in that case.
+    pub fn derive_hardened(&self, index: u32) -> Option<ExtendedKey> {
+        let index = index | HARDENED;
+        let (tweak, chain_code) =
+            hmac_sha512(&self.chain_code, &[&[0], &self.key, &index.to_be_bytes()]);
+
+        let key = match self.scheme {
+            SignatureScheme::Ecdsa | SignatureScheme::Schnorr => {
+                let k = secp256k1_scalar(tweak)? + secp256k1_scalar(self.key)?;
+                if bool::from(k.is_zero()) {
+                    return None;
+                }
+                k.to_repr().into()
+            }
+            SignatureScheme::Ed25519 => tweak,
+        };
+
+        Some(ExtendedKey {
+            scheme: self.scheme,
+            key,
+            chain_code,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Option<ExtendedKey> {
+        path.iter()
+            .try_fold(*self, |key, index| key.derive_hardened(*index))
+    }
+
+    pub fn private_key(&self) -> PrivateKey {
+        PrivateKey::from_bytes(self.scheme, &self.key).unwrap()
+    }
+
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.key);
+        bytes[32..].copy_from_slice(&self.chain_code);
+        bytes
+    }
+
+    pub fn from_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Option<ExtendedKey> {
+        let bytes: &[u8; 64] = bytes.try_into().ok()?;
+        PrivateKey::from_bytes(scheme, &bytes[..32])?;
+        Some(ExtendedKey {
+            scheme,
+            key: bytes[..32].try_into().unwrap(),
+            chain_code: bytes[32..].try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vector 1 from BIP32 and SLIP-10: chain m and m/0'
+    #[test]
+    fn test_derivation_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let vectors = [
+            (
+                SignatureScheme::Ecdsa,
+                [
+                    (
+                        "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508",
+                        "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35",
+                    ),
+                    (
+                        "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141",
+                        "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea",
+                    ),
+                ],
+            ),
+            (
+                SignatureScheme::Ed25519,
+                [
+                    (
+                        "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb",
+                        "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7",
+                    ),
+                    (
+                        "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69",
+                        "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3",
+                    ),
+                ],
+            ),
+        ];
+
+        for (scheme, [master, child]) in vectors {
+            let key = ExtendedKey::master(scheme, &seed).unwrap();
+            assert_eq!(hex::encode(key.chain_code), master.0);
+            assert_eq!(hex::encode(key.key), master.1);
+
+            let key = key.derive_hardened(0).unwrap();
+            assert_eq!(hex::encode(key.chain_code), child.0);
+            assert_eq!(hex::encode(key.key), child.1);
+        }
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let key = ExtendedKey::master(SignatureScheme::Ecdsa, &[7; 64])
+            .unwrap()
+            .derive_path(&[0, 5])
+            .unwrap();
+        assert_eq!(
+            ExtendedKey::from_bytes(SignatureScheme::Ecdsa, &key.to_bytes()),
+            Some(key)
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use crate::errors::BlockValidationError;
+use crate::hash::Hash256;
+use std::collections::{HashMap, VecDeque};
+
+// Enough to remember a long run of bad blocks from a misbehaving peer while
+// keeping memory bounded
+pub const MAX_INVALID_BLOCKS: usize = 1000;
+
+// Header hashes of blocks that failed validation, with the reason, so that a
+// block sent again, or one building on it, is turned away without being
+// checked a second time
+pub struct InvalidBlocks {
+    reasons: HashMap<Hash256, BlockValidationError>,
+    // Insertion order, oldest first, used for eviction
+    order: VecDeque<Hash256>,
+}
+
+impl InvalidBlocks {
+    pub fn new() -> InvalidBlocks {
+        InvalidBlocks {
+            reasons: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, hash: Hash256, reason: BlockValidationError) {
+        if self.reasons.contains_key(&hash) {
+            return;
+        }
+        while self.reasons.len() >= MAX_INVALID_BLOCKS {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.reasons.remove(&oldest);
+        }
+        self.order.push_back(hash);
+        self.reasons.insert(hash, reason);
+    }
+
+    pub fn get(&self, hash: &Hash256) -> Option<&BlockValidationError> {
+        self.reasons.get(hash)
+    }
+
+    pub fn contains(&self, hash: &Hash256) -> bool {
+        self.reasons.contains_key(hash)
+    }
+}
+
+impl Default for InvalidBlocks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_oldest() {
+        let mut invalid = InvalidBlocks::new();
+        invalid.add(Hash256([0; 32]), BlockValidationError::InvalidTarget);
+        invalid.add(Hash256([0; 32]), BlockValidationError::InvalidMerkleRoot);
+        assert_eq!(
+            invalid.get(&Hash256([0; 32])),
+            Some(&BlockValidationError::InvalidTarget)
+        );
+
+        for i in 1..=MAX_INVALID_BLOCKS as u64 {
+            let mut hash = [0; 32];
+            hash[..8].copy_from_slice(&i.to_le_bytes());
+            invalid.add(Hash256(hash), BlockValidationError::InvalidAncestor);
+        }
+        assert_eq!(invalid.reasons.len(), MAX_INVALID_BLOCKS);
+        assert!(!invalid.contains(&Hash256([0; 32])));
+    }
+}
@@ -0,0 +1,458 @@
+// Invoices: requests for an amount to a pkhash by a deadline, watched for
+// the outputs paying them. An invoice counts as paid once outputs covering
+// its amount have `confirmations` blocks on them.
+//
+// The book scans the blocks added since its last sync and the mempool for
+// outputs paying an invoice's pkhash. Confirmations are read from the chain
+// each time a status is asked for, so a payment a reorg takes out of the
+// chain stops counting. Outputs paid late still count: the invoice only
+// shows as expired while it is short.
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::errors::InvoiceError;
+use crate::hash::{Hash256, OutPoint};
+use crate::json::impl_serde_struct;
+use crate::payment_request::PaymentRequest;
+use crate::transaction::Transaction;
+use bincode::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_INVOICE_CONFIRMATIONS: u64 = 6;
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct Invoice {
+    pub id: u64,
+    pub pkhash: [u8; 32],
+    pub amount: Amount,
+    // Unix time after which an invoice not yet paid shows as expired
+    pub expiry: u64,
+    pub memo: String,
+}
+
+impl_serde_struct!(Invoice {
+    id: u64,
+    pkhash: [u8; 32] => hex,
+    amount: Amount,
+    expiry: u64,
+    memo: String,
+});
+
+impl Invoice {
+    // For the payer's wallet to paste or scan
+    pub fn payment_request(&self) -> PaymentRequest {
+        let request = PaymentRequest::new(self.pkhash).with_amount(self.amount);
+        if self.memo.is_empty() {
+            request
+        } else {
+            request.with_message(&self.memo)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvoiceStatus {
+    Unpaid,
+    Expired,
+    // Paid in full, by outputs with fewer confirmations than required, the
+    // fewest of which is given. 0 while in the mempool.
+    Confirming { confirmations: u64 },
+    Paid,
+}
+
+impl fmt::Display for InvoiceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            InvoiceStatus::Unpaid => "unpaid",
+            InvoiceStatus::Expired => "expired",
+            InvoiceStatus::Confirming { .. } => "confirming",
+            InvoiceStatus::Paid => "paid",
+        };
+        write!(f, "{name}")
+    }
+}
+
+#[derive(Encode, Decode, Clone)]
+struct Entry {
+    invoice: Invoice,
+    // Outputs in earlier blocks were paid before the invoice existed
+    created_height: u64,
+    payments: Vec<(OutPoint, Amount)>,
+}
+
+#[derive(Encode, Decode)]
+struct Saved {
+    entries: Vec<Entry>,
+    next_id: u64,
+    next_height: u64,
+    synced_tip: Option<Hash256>,
+}
+
+pub struct InvoiceBook {
+    entries: BTreeMap<u64, Entry>,
+    next_id: u64,
+    confirmations: u64,
+    // Height of the next block to scan, and the digest of the block below
+    // it, to notice a reorg since the last sync
+    next_height: u64,
+    synced_tip: Option<Hash256>,
+    // None keeps the book in memory only
+    path: Option<PathBuf>,
+}
+
+impl Default for InvoiceBook {
+    fn default() -> Self {
+        InvoiceBook {
+            entries: BTreeMap::new(),
+            next_id: 1,
+            confirmations: DEFAULT_INVOICE_CONFIRMATIONS,
+            next_height: 0,
+            synced_tip: None,
+            path: None,
+        }
+    }
+}
+
+impl InvoiceBook {
+    pub fn new() -> InvoiceBook {
+        InvoiceBook::default()
+    }
+
+    // Loads the book saved at `path`, if any, and saves it there after each
+    // change
+    pub fn open(path: impl AsRef<Path>) -> Result<InvoiceBook, InvoiceError> {
+        let path = path.as_ref().to_path_buf();
+        let mut book = InvoiceBook::new();
+        if path.exists() {
+            let (saved, _): (Saved, usize) =
+                bincode::decode_from_slice(&fs::read(&path)?, bincode::config::standard())
+                    .map_err(|_| InvoiceError::Corrupt)?;
+            book.entries = saved
+                .entries
+                .into_iter()
+                .map(|entry| (entry.invoice.id, entry))
+                .collect();
+            book.next_id = saved.next_id;
+            book.next_height = saved.next_height;
+            book.synced_tip = saved.synced_tip;
+        }
+        book.path = Some(path);
+        Ok(book)
+    }
+
+    fn save(&self) -> Result<(), InvoiceError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = Saved {
+            entries: self.entries.values().cloned().collect(),
+            next_id: self.next_id,
+            next_height: self.next_height,
+            synced_tip: self.synced_tip,
+        };
+        fs::write(
+            path,
+            bincode::encode_to_vec(saved, bincode::config::standard()).unwrap(),
+        )?;
+        Ok(())
+    }
+
+    pub fn set_confirmations(&mut self, confirmations: u64) {
+        self.confirmations = confirmations.max(1);
+    }
+
+    pub fn confirmations(&self) -> u64 {
+        self.confirmations
+    }
+
+    // Each open invoice needs a pkhash of its own, or a payment couldn't be
+    // told apart from another's
+    pub fn create(
+        &mut self,
+        blockchain: &Blockchain,
+        pkhash: [u8; 32],
+        amount: impl Into<Amount>,
+        expiry: u64,
+        memo: &str,
+    ) -> Result<Invoice, InvoiceError> {
+        let amount = amount.into();
+        if amount == Amount::ZERO || !amount.is_valid() {
+            return Err(InvoiceError::InvalidAmount);
+        }
+        let now = blockchain.clock().now();
+        let open = self.entries.values().find(|entry| {
+            entry.invoice.pkhash == pkhash
+                && matches!(
+                    self.entry_status(entry, blockchain, now),
+                    InvoiceStatus::Unpaid | InvoiceStatus::Confirming { .. }
+                )
+        });
+        if let Some(entry) = open {
+            return Err(InvoiceError::AddressInUse(entry.invoice.id));
+        }
+
+        let invoice = Invoice {
+            id: self.next_id,
+            pkhash,
+            amount,
+            expiry,
+            memo: memo.to_string(),
+        };
+        self.next_id += 1;
+        self.entries.insert(
+            invoice.id,
+            Entry {
+                invoice: invoice.clone(),
+                created_height: blockchain.height() + 1,
+                payments: Vec::new(),
+            },
+        );
+        self.save()?;
+        Ok(invoice)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Invoice> {
+        self.entries.get(&id).map(|entry| &entry.invoice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Invoice> {
+        self.entries.values().map(|entry| &entry.invoice)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Scans the blocks added since the last sync and the mempool. After a
+    // reorg, the blocks since the oldest invoice was created are scanned
+    // again, since the new branch may pay invoices the old one didn't.
+    pub fn sync(&mut self, blockchain: &Blockchain) -> Result<(), InvoiceError> {
+        let reorged = self.next_height > 0
+            && blockchain
+                .get_block_by_height(self.next_height - 1)
+                .map(|block| block.digest)
+                != self.synced_tip;
+        if reorged {
+            let oldest = self.entries.values().map(|entry| entry.created_height);
+            self.next_height = oldest.min().unwrap_or(0).min(self.next_height);
+        }
+
+        let mut changed = reorged;
+        let tip = blockchain.height() + 1;
+        for block in blockchain.blocks_in_range(self.next_height..tip) {
+            for tx in &block.transactions {
+                changed |= self.record(tx, Some(block.header.index));
+            }
+        }
+        for tx in blockchain.mempool.iter() {
+            changed |= self.record(tx, None);
+        }
+        changed |= self.next_height != tip;
+        self.next_height = tip;
+        self.synced_tip = Some(blockchain.prev_hash());
+
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    // Returns whether it paid an invoice not already known to be paid by it
+    fn record(&mut self, tx: &Transaction, height: Option<u64>) -> bool {
+        let mut recorded = false;
+        for (index, output) in tx.outputs.iter().enumerate() {
+            let outpoint = OutPoint::new(tx.id, index as u16);
+            for entry in self.entries.values_mut() {
+                if entry.invoice.pkhash != output.pkhash
+                    || height.is_some_and(|height| height < entry.created_height)
+                    || entry.payments.iter().any(|(paid, _)| *paid == outpoint)
+                {
+                    continue;
+                }
+                entry.payments.push((outpoint, output.value));
+                recorded = true;
+            }
+        }
+        recorded
+    }
+
+    // With the total paid so far, counting the mempool. None for an unknown
+    // invoice. Call sync first to see the latest payments.
+    pub fn status(
+        &self,
+        id: u64,
+        blockchain: &Blockchain,
+        now: u64,
+    ) -> Option<(InvoiceStatus, Amount)> {
+        let entry = self.entries.get(&id)?;
+        let status = self.entry_status(entry, blockchain, now);
+        let received = Amount::checked_sum(
+            entry
+                .payments
+                .iter()
+                .filter(|(outpoint, _)| blockchain.confirmations(&outpoint.txid).is_some())
+                .map(|(_, value)| *value),
+        )
+        .unwrap_or(Amount(u64::MAX));
+        Some((status, received))
+    }
+
+    fn entry_status(&self, entry: &Entry, blockchain: &Blockchain, now: u64) -> InvoiceStatus {
+        // The best confirmed first, so the count is that of the fewest
+        // payments covering the amount
+        let mut payments: Vec<(u64, Amount)> = entry
+            .payments
+            .iter()
+            .filter_map(|(outpoint, value)| {
+                Some((blockchain.confirmations(&outpoint.txid)?, *value))
+            })
+            .collect();
+        payments.sort_by(|a, b| b.cmp(a));
+
+        let mut received = Amount::ZERO;
+        for (confirmations, value) in payments {
+            received = received.checked_add(value).unwrap_or(Amount(u64::MAX));
+            if received >= entry.invoice.amount {
+                if confirmations >= self.confirmations {
+                    return InvoiceStatus::Paid;
+                }
+                return InvoiceStatus::Confirming { confirmations };
+            }
+        }
+        if now >= entry.invoice.expiry {
+            InvoiceStatus::Expired
+        } else {
+            InvoiceStatus::Unpaid
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::utils::{mine, mine_with_fees};
+    use crate::wallet::Wallet;
+    use std::env;
+
+    fn mine_to(blockchain: &mut Blockchain, pkhash: [u8; 32]) {
+        let mut block = blockchain.next_block();
+        mine(&mut block, pkhash, blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_payment_confirms() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let wallet = Wallet::for_chain(blockchain.chain_id());
+        mine_to(&mut blockchain, wallet.pkhash);
+        let now = blockchain.clock().now();
+        let reward = blockchain.get_block_reward();
+
+        let mut book = InvoiceBook::new();
+        book.set_confirmations(2);
+        let invoice = book
+            .create(&blockchain, [5; 32], 1_000, now + 60, "tea")
+            .unwrap();
+        assert!(matches!(
+            book.create(&blockchain, [5; 32], 1_000, now + 60, ""),
+            Err(InvoiceError::AddressInUse(id)) if id == invoice.id
+        ));
+        assert!(matches!(
+            book.create(&blockchain, [6; 32], 0, now + 60, ""),
+            Err(InvoiceError::InvalidAmount)
+        ));
+        assert_eq!(
+            book.status(invoice.id, &blockchain, now),
+            Some((InvoiceStatus::Unpaid, Amount::ZERO))
+        );
+        assert_eq!(
+            book.status(invoice.id, &blockchain, now + 60),
+            Some((InvoiceStatus::Expired, Amount::ZERO))
+        );
+
+        let coinbase = &blockchain.get_block_by_height(1).unwrap().transactions[0];
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(coinbase.id, 0).with_value(reward)],
+            vec![
+                TxOutput::new(1_000, [5; 32]),
+                TxOutput::new(reward - 2_000, wallet.pkhash),
+            ],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        blockchain.submit_transaction(tx.clone()).unwrap();
+        book.sync(&blockchain).unwrap();
+        assert_eq!(
+            book.status(invoice.id, &blockchain, now + 60),
+            Some((
+                InvoiceStatus::Confirming { confirmations: 0 },
+                Amount(1_000)
+            ))
+        );
+
+        let mut block = blockchain.next_block();
+        block.add_tx(tx);
+        mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+        blockchain.add_block(block).unwrap();
+        book.sync(&blockchain).unwrap();
+        assert_eq!(
+            book.status(invoice.id, &blockchain, now).unwrap().0,
+            InvoiceStatus::Confirming { confirmations: 1 }
+        );
+        mine_to(&mut blockchain, [0; 32]);
+        book.sync(&blockchain).unwrap();
+        assert_eq!(
+            book.status(invoice.id, &blockchain, now).unwrap().0,
+            InvoiceStatus::Paid
+        );
+
+        // Once paid, the pkhash can be asked for again
+        let again = book
+            .create(&blockchain, [5; 32], 500, now + 60, "")
+            .unwrap();
+        book.sync(&blockchain).unwrap();
+        assert_eq!(
+            book.status(again.id, &blockchain, now),
+            Some((InvoiceStatus::Unpaid, Amount::ZERO))
+        );
+        assert_eq!(book.status(99, &blockchain, now), None);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = env::temp_dir().join(format!("invoices_{}.dat", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+
+        let mut book = InvoiceBook::open(&path).unwrap();
+        let invoice = book
+            .create(&blockchain, [5; 32], 1_000, u64::MAX, "rent")
+            .unwrap();
+        mine_to(&mut blockchain, [5; 32]);
+        book.sync(&blockchain).unwrap();
+        drop(book);
+
+        let mut book = InvoiceBook::open(&path).unwrap();
+        book.set_confirmations(1);
+        assert_eq!(book.get(invoice.id), Some(&invoice));
+        assert_eq!(
+            book.status(invoice.id, &blockchain, 0).unwrap().0,
+            InvoiceStatus::Paid
+        );
+        let next = book.create(&blockchain, [6; 32], 1, 0, "").unwrap();
+        assert_eq!(next.id, invoice.id + 1);
+        assert_eq!(
+            next.payment_request().encode(),
+            PaymentRequest::new([6; 32]).with_amount(1).encode()
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,880 @@
+// A small JSON implementation: a document model with a parser and writer, and
+// serde glue so any Serialize/Deserialize type can be converted to and from
+// JSON text. Integers are kept exact so u64 amounts and nonces round-trip.
+
+use crate::errors::JsonError;
+use serde::de::value::{MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::U64(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+pub fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Json, JsonError> {
+    value.serialize(ValueSerializer)
+}
+
+pub fn from_value<T: DeserializeOwned>(value: Json) -> Result<T, JsonError> {
+    T::deserialize(value)
+}
+
+pub fn to_string<T: Serialize + ?Sized>(value: &T) -> Result<String, JsonError> {
+    Ok(to_value(value)?.to_string())
+}
+
+pub fn from_str<T: DeserializeOwned>(s: &str) -> Result<T, JsonError> {
+    from_value(s.parse()?)
+}
+
+// Writing
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(b) => write!(f, "{b}"),
+            Json::U64(n) => write!(f, "{n}"),
+            Json::I64(n) => write!(f, "{n}"),
+            Json::F64(n) if n.is_finite() => write!(f, "{n:?}"),
+            Json::F64(_) => f.write_str("null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Json::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+// Parsing
+
+// Nesting deeper than this is rejected rather than risking a stack overflow
+// on hostile input
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> JsonError {
+        JsonError::Syntax(self.pos)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.bytes.get(self.pos), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        if self.peek() != Some(byte) {
+            return Err(self.error());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, text: &str, value: Json) -> Result<Json, JsonError> {
+        if !self.bytes[self.pos..].starts_with(text.as_bytes()) {
+            return Err(self.error());
+        }
+        self.pos += text.len();
+        Ok(value)
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error());
+        }
+
+        match self.peek().ok_or(self.error())? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => self.parse_string().map(Json::String),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.parse_value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(Json::Array(items));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error());
+                    }
+                    let key = self.parse_string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.parse_value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(Json::Object(fields));
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => self.parse_number(),
+            _ => Err(self.error()),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        while matches!(
+            self.bytes.get(self.pos),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        let text = std::str::from_utf8(&self.bytes[start..self.pos]).unwrap();
+
+        if let Ok(n) = text.parse::<u64>() {
+            Ok(Json::U64(n))
+        } else if let Ok(n) = text.parse::<i64>() {
+            Ok(Json::I64(n))
+        } else {
+            text.parse::<f64>()
+                .map(Json::F64)
+                .map_err(|_| JsonError::Syntax(start))
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.bytes.get(self.pos..self.pos + 4).ok_or(self.error())?;
+        let digits = std::str::from_utf8(digits).map_err(|_| self.error())?;
+        let value = u32::from_str_radix(digits, 16).map_err(|_| self.error())?;
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut out = Vec::new();
+
+        loop {
+            let byte = *self.bytes.get(self.pos).ok_or(self.error())?;
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.bytes.get(self.pos).ok_or(self.error())?;
+                    self.pos += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            // Characters outside the BMP are written as a
+                            // surrogate pair
+                            if (0xd800..0xdc00).contains(&code)
+                                && self.bytes[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error());
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            char::from_u32(code).ok_or(self.error())?
+                        }
+                        _ => return Err(self.error()),
+                    };
+                    out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                byte if byte < 0x20 => return Err(self.error()),
+                byte => out.push(byte),
+            }
+        }
+
+        String::from_utf8(out).map_err(|_| self.error())
+    }
+}
+
+impl std::str::FromStr for Json {
+    type Err = JsonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            bytes: s.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.parse_value(0)?;
+        if parser.peek().is_some() {
+            return Err(parser.error());
+        }
+        Ok(value)
+    }
+}
+
+// Deserializing from a document
+
+impl<'de> de::Deserializer<'de> for Json {
+    type Error = JsonError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JsonError> {
+        match self {
+            Json::Null => visitor.visit_unit(),
+            Json::Bool(b) => visitor.visit_bool(b),
+            Json::U64(n) => visitor.visit_u64(n),
+            Json::I64(n) => visitor.visit_i64(n),
+            Json::F64(n) => visitor.visit_f64(n),
+            Json::String(s) => visitor.visit_string(s),
+            Json::Array(items) => {
+                let mut seq = SeqDeserializer::new(items.into_iter());
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Json::Object(fields) => {
+                let mut map = MapDeserializer::new(fields.into_iter());
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JsonError> {
+        match self {
+            Json::Null => visitor.visit_none(),
+            value => visitor.visit_some(value),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl IntoDeserializer<'_, JsonError> for Json {
+    type Deserializer = Json;
+
+    fn into_deserializer(self) -> Json {
+        self
+    }
+}
+
+// Serializing into a document
+
+struct ValueSerializer;
+
+struct SeqBuilder {
+    variant: Option<&'static str>,
+    items: Vec<Json>,
+}
+
+struct MapBuilder {
+    variant: Option<&'static str>,
+    fields: Vec<(String, Json)>,
+    next_key: Option<String>,
+}
+
+fn wrap_variant(variant: Option<&'static str>, value: Json) -> Json {
+    match variant {
+        Some(variant) => Json::Object(vec![(variant.to_string(), value)]),
+        None => value,
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Json;
+    type Error = JsonError;
+    type SerializeSeq = SeqBuilder;
+    type SerializeTuple = SeqBuilder;
+    type SerializeTupleStruct = SeqBuilder;
+    type SerializeTupleVariant = SeqBuilder;
+    type SerializeMap = MapBuilder;
+    type SerializeStruct = MapBuilder;
+    type SerializeStructVariant = MapBuilder;
+
+    fn serialize_bool(self, v: bool) -> Result<Json, JsonError> {
+        Ok(Json::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Json, JsonError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Json, JsonError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Json, JsonError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Json, JsonError> {
+        Ok(if v < 0 {
+            Json::I64(v)
+        } else {
+            Json::U64(v as u64)
+        })
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Json, JsonError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Json, JsonError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Json, JsonError> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Json, JsonError> {
+        Ok(Json::U64(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Json, JsonError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Json, JsonError> {
+        Ok(Json::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Json, JsonError> {
+        Ok(Json::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Json, JsonError> {
+        Ok(Json::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Json, JsonError> {
+        Ok(Json::String(hex::encode(v)))
+    }
+
+    fn serialize_none(self) -> Result<Json, JsonError> {
+        Ok(Json::Null)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Json, JsonError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Json, JsonError> {
+        Ok(Json::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Json, JsonError> {
+        Ok(Json::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Json, JsonError> {
+        Ok(Json::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Json, JsonError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Json, JsonError> {
+        Ok(wrap_variant(Some(variant), value.serialize(self)?))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqBuilder, JsonError> {
+        Ok(SeqBuilder {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqBuilder, JsonError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SeqBuilder, JsonError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SeqBuilder, JsonError> {
+        Ok(SeqBuilder {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<MapBuilder, JsonError> {
+        Ok(MapBuilder {
+            variant: None,
+            fields: Vec::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<MapBuilder, JsonError> {
+        self.serialize_map(None)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<MapBuilder, JsonError> {
+        Ok(MapBuilder {
+            variant: Some(variant),
+            fields: Vec::new(),
+            next_key: None,
+        })
+    }
+}
+
+impl SeqBuilder {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.items.push(to_value(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Json, JsonError> {
+        Ok(wrap_variant(self.variant, Json::Array(self.items)))
+    }
+}
+
+impl ser::SerializeSeq for SeqBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SeqBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SeqBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl MapBuilder {
+    fn finish(self) -> Result<Json, JsonError> {
+        Ok(wrap_variant(self.variant, Json::Object(self.fields)))
+    }
+}
+
+impl ser::SerializeMap for MapBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    // Object keys must be strings, numbers are converted for convenience
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), JsonError> {
+        self.next_key = Some(match to_value(key)? {
+            Json::String(s) => s,
+            Json::U64(n) => n.to_string(),
+            Json::I64(n) => n.to_string(),
+            _ => return Err(JsonError::Custom("object key must be a string".into())),
+        });
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JsonError> {
+        let key = self.next_key.take().unwrap();
+        self.fields.push((key, to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for MapBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), JsonError> {
+        self.fields.push((key.to_string(), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for MapBuilder {
+    type Ok = Json;
+    type Error = JsonError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), JsonError> {
+        self.fields.push((key.to_string(), to_value(value)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Json, JsonError> {
+        self.finish()
+    }
+}
+
+// Documents can be embedded in other serializable values as-is
+impl Serialize for Json {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use ser::{SerializeMap, SerializeSeq};
+
+        match self {
+            Json::Null => serializer.serialize_unit(),
+            Json::Bool(b) => serializer.serialize_bool(*b),
+            Json::U64(n) => serializer.serialize_u64(*n),
+            Json::I64(n) => serializer.serialize_i64(*n),
+            Json::F64(n) => serializer.serialize_f64(*n),
+            Json::String(s) => serializer.serialize_str(s),
+            Json::Array(items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Json::Object(fields) => {
+                let mut map = serializer.serialize_map(Some(fields.len()))?;
+                for (key, value) in fields {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+// Byte strings are written as hex rather than arrays of numbers
+pub struct Hex<T>(pub T);
+
+impl<T: AsRef<[u8]>> Serialize for Hex<T> {
+    fn serialize<S: ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(self.0.as_ref()))
+    }
+}
+
+impl<'de, T: TryFrom<Vec<u8>>> de::Deserialize<'de> for Hex<T> {
+    fn deserialize<D: de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = hex::decode(&text).map_err(de::Error::custom)?;
+        let len = bytes.len();
+        T::try_from(bytes)
+            .map(Hex)
+            .map_err(|_| de::Error::invalid_length(len, &"a byte string of the right length"))
+    }
+}
+
+// Implements Serialize and Deserialize for a struct with named fields,
+// writing it as an object. Fields marked `=> hex` are byte strings.
+macro_rules! impl_serde_struct {
+    (@ser $value:expr) => { &$value };
+    (@ser $value:expr, hex) => { &$crate::json::Hex(&$value) };
+    (@de $map:ident, $ty:ty) => { $map.next_value::<$ty>()? };
+    (@de $map:ident, $ty:ty, hex) => { $map.next_value::<$crate::json::Hex<$ty>>()?.0 };
+
+    ($name:ident { $($field:ident: $ty:ty $(=> $hex:ident)?),* $(,)? }) => {
+        impl serde::Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                use serde::ser::SerializeStruct;
+                let fields = [$(stringify!($field)),*];
+                let mut state = serializer.serialize_struct(stringify!($name), fields.len())?;
+                $(
+                    state.serialize_field(
+                        stringify!($field),
+                        $crate::json::impl_serde_struct!(@ser self.$field $(, $hex)?),
+                    )?;
+                )*
+                state.end()
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct StructVisitor;
+
+                impl<'de> serde::de::Visitor<'de> for StructVisitor {
+                    type Value = $name;
+
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str(concat!("a ", stringify!($name)))
+                    }
+
+                    fn visit_map<A: serde::de::MapAccess<'de>>(
+                        self,
+                        mut map: A,
+                    ) -> Result<$name, A::Error> {
+                        $(let mut $field: Option<$ty> = None;)*
+                        while let Some(key) = map.next_key::<String>()? {
+                            match key.as_str() {
+                                $(stringify!($field) => {
+                                    $field = Some($crate::json::impl_serde_struct!(@de map, $ty $(, $hex)?));
+                                })*
+                                _ => {
+                                    map.next_value::<serde::de::IgnoredAny>()?;
+                                }
+                            }
+                        }
+                        Ok($name {
+                            $($field: $field
+                                .ok_or_else(|| serde::de::Error::missing_field(stringify!($field)))?,)*
+                        })
+                    }
+                }
+
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+                deserializer.deserialize_struct(stringify!($name), FIELDS, StructVisitor)
+            }
+        }
+    };
+}
+
+pub(crate) use impl_serde_struct;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_write() {
+        let text = r#"{"a":[1,-2,3.5,true,null],"b":"x\"y\\z\n","c":{}}"#;
+        let value: Json = text.parse().unwrap();
+        assert_eq!(value.get("a").unwrap().as_array().unwrap().len(), 5);
+        assert_eq!(value.get("b").unwrap().as_str(), Some("x\"y\\z\n"));
+        assert_eq!(value.to_string(), text);
+
+        let spaced: Json = " { \"a\" : [ 1 , -2 , 3.5 , true , null ] , \"b\" : \"x\\\"y\\\\z\\n\" , \"c\" : { } } "
+            .parse()
+            .unwrap();
+        assert_eq!(spaced, value);
+    }
+
+    #[test]
+    fn test_unicode_escapes() {
+        let value: Json = r#""é😀""#.parse().unwrap();
+        assert_eq!(value.as_str(), Some("é😀"));
+        let value: Json = r#""\u00e9\ud83d\ude00""#.parse().unwrap();
+        assert_eq!(value.as_str(), Some("é😀"));
+
+        // a high surrogate must be followed by a low one
+        for text in [
+            r#""\ud800\u0041""#,
+            r#""\ud800\ud800""#,
+            r#""\ud800""#,
+            r#""\udc00""#,
+            r#""\ud800x""#,
+        ] {
+            assert!(
+                matches!(text.parse::<Json>(), Err(JsonError::Syntax(_))),
+                "{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_large_integers_exact() {
+        let value: Json = u64::MAX.to_string().parse().unwrap();
+        assert_eq!(value.as_u64(), Some(u64::MAX));
+        assert_eq!(
+            from_str::<u64>(&to_string(&u64::MAX).unwrap()),
+            Ok(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_invalid_json_rejected() {
+        for text in ["", "{", "[1,]", "{\"a\" 1}", "tru", "\"abc", "1 2", "{1:2}"] {
+            assert!(text.parse::<Json>().is_err(), "{text}");
+        }
+        assert!("[".repeat(MAX_DEPTH + 2).parse::<Json>().is_err());
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let value: (Vec<u16>, Option<String>, bool) = (vec![1, 2], Some("hi".into()), false);
+        let text = to_string(&value).unwrap();
+        assert_eq!(text, r#"[[1,2],"hi",false]"#);
+        assert_eq!(
+            from_str::<(Vec<u16>, Option<String>, bool)>(&text),
+            Ok(value)
+        );
+
+        assert!(from_str::<u8>("256").is_err());
+    }
+}
@@ -1,7 +1,151 @@
+// The public API. Modules marked pub(crate) are implementation details that
+// may change between releases; `prelude` gathers the types most programs
+// using the chain, a wallet or a node need.
+//
+// Without the std feature only `consensus` is built, for no_std and wasm32
+// targets.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub(crate) mod blake3;
+pub mod consensus;
+pub(crate) mod ed25519;
+
+#[cfg(feature = "std")]
+pub mod addr_manager;
+#[cfg(feature = "std")]
+pub mod address;
+#[cfg(feature = "std")]
+pub mod address_index;
+#[cfg(feature = "std")]
+pub mod amount;
+#[cfg(feature = "std")]
+pub mod archive;
+#[cfg(feature = "std")]
 pub mod block;
+#[cfg(feature = "std")]
+pub mod block_meta;
+#[cfg(feature = "std")]
 pub mod blockchain;
+#[cfg(feature = "std")]
+pub mod clock;
+#[cfg(feature = "std")]
+pub mod compact;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod deployments;
+#[cfg(feature = "std")]
+pub mod engine;
+#[cfg(feature = "std")]
 pub mod errors;
+#[cfg(feature = "std")]
+pub mod events;
+#[cfg(feature = "std")]
+pub mod explorer;
+#[cfg(feature = "std")]
+pub mod fees;
+#[cfg(feature = "std")]
+pub mod filter;
+#[cfg(feature = "std")]
+pub mod fixtures;
+#[cfg(feature = "std")]
+pub mod hash;
+#[cfg(feature = "std")]
+pub mod hd;
+#[cfg(feature = "std")]
+pub(crate) mod invalid_blocks;
+#[cfg(feature = "std")]
+pub mod invoice;
+#[cfg(feature = "std")]
+pub mod json;
+#[cfg(feature = "std")]
+pub mod keystore;
+#[cfg(feature = "std")]
+pub mod log;
+#[cfg(feature = "std")]
+pub mod mempool;
+#[cfg(feature = "std")]
+pub mod merkle;
+#[cfg(feature = "std")]
+pub mod miner;
+#[cfg(feature = "std")]
+pub mod mnemonic;
+#[cfg(feature = "std")]
+pub mod musig;
+#[cfg(feature = "std")]
+pub mod network;
+#[cfg(feature = "std")]
+pub(crate) mod nfkd;
+#[cfg(feature = "std")]
+pub(crate) mod orphans;
+#[cfg(feature = "std")]
+pub mod params;
+#[cfg(feature = "std")]
+pub mod payment_request;
+#[cfg(feature = "std")]
+pub mod peer_manager;
+#[cfg(feature = "pos")]
+pub mod pos;
+#[cfg(feature = "std")]
+pub mod psbt;
+#[cfg(feature = "std")]
+pub(crate) mod rate_limit;
+#[cfg(feature = "std")]
+pub mod rpc;
+#[cfg(feature = "std")]
+pub mod runtime;
+#[cfg(feature = "std")]
+pub mod script;
+#[cfg(feature = "std")]
+pub mod sighash;
+#[cfg(feature = "std")]
+pub mod signature;
+#[cfg(feature = "std")]
+pub mod storage;
+#[cfg(feature = "std")]
+pub mod stratum;
+#[cfg(feature = "std")]
+pub mod swap;
+#[cfg(feature = "std")]
+pub(crate) mod sync;
+#[cfg(feature = "std")]
+pub mod testkit;
+#[cfg(feature = "std")]
 pub mod transaction;
+#[cfg(feature = "std")]
+pub mod tx_builder;
+#[cfg(feature = "std")]
 pub mod utils;
+#[cfg(feature = "std")]
 pub mod utxo;
+#[cfg(feature = "std")]
 pub mod wallet;
+#[cfg(feature = "std")]
+pub mod wallet_manager;
+#[cfg(feature = "std")]
+pub mod wallet_state;
+#[cfg(feature = "std")]
+pub mod websocket;
+
+#[cfg(feature = "std")]
+pub mod prelude {
+    pub use crate::amount::Amount;
+    pub use crate::block::{Block, BlockHeader};
+    pub use crate::blockchain::{BlockAcceptance, Blockchain};
+    pub use crate::clock::{Clock, SystemClock};
+    pub use crate::errors::{BlockValidationError, ChainError, TransactionError};
+    pub use crate::events::ChainEvent;
+    pub use crate::hash::{Hash256, OutPoint, TxId};
+    pub use crate::mempool::Mempool;
+    pub use crate::network::{Message, Node};
+    pub use crate::params::{ChainParams, Network};
+    pub use crate::sighash::SighashType;
+    pub use crate::signature::{PrivateKey, SignatureScheme};
+    pub use crate::transaction::{Transaction, TxInput, TxOutput};
+    pub use crate::tx_builder::TxBuilder;
+    pub use crate::utxo::UTXOSet;
+    pub use crate::wallet::Wallet;
+}
@@ -0,0 +1,275 @@
+// Structured logging: events carry a level, the subsystem they come from and
+// key=value fields, and go to whichever subscriber the program installed.
+// Nothing is logged until one is.
+
+use crate::utils;
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("unknown log level {s}")),
+        }
+    }
+}
+
+pub struct Record<'a> {
+    pub level: Level,
+    // The subsystem, e.g. "blockchain" or "network"
+    pub target: &'static str,
+    pub message: &'a str,
+    pub fields: &'a [(&'static str, String)],
+}
+
+pub trait Subscriber: Send + Sync {
+    // Checked before the fields are formatted
+    fn enabled(&self, level: Level, target: &str) -> bool;
+    fn record(&self, record: &Record);
+}
+
+static SUBSCRIBER: RwLock<Option<Arc<dyn Subscriber>>> = RwLock::new(None);
+
+// Replaces the subscriber every event goes to
+pub fn set_subscriber(subscriber: Arc<dyn Subscriber>) {
+    *SUBSCRIBER.write().unwrap() = Some(subscriber);
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    SUBSCRIBER
+        .read()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|subscriber| subscriber.enabled(level, target))
+}
+
+pub fn event(
+    level: Level,
+    target: &'static str,
+    message: &str,
+    fields: &[(&'static str, &dyn Display)],
+) {
+    if !enabled(level, target) {
+        return;
+    }
+    let fields: Vec<(&'static str, String)> = fields
+        .iter()
+        .map(|(key, value)| (*key, value.to_string()))
+        .collect();
+    emit(level, target, message, &fields);
+}
+
+pub fn error(target: &'static str, message: &str, fields: &[(&'static str, &dyn Display)]) {
+    event(Level::Error, target, message, fields);
+}
+
+pub fn warn(target: &'static str, message: &str, fields: &[(&'static str, &dyn Display)]) {
+    event(Level::Warn, target, message, fields);
+}
+
+pub fn info(target: &'static str, message: &str, fields: &[(&'static str, &dyn Display)]) {
+    event(Level::Info, target, message, fields);
+}
+
+pub fn debug(target: &'static str, message: &str, fields: &[(&'static str, &dyn Display)]) {
+    event(Level::Debug, target, message, fields);
+}
+
+fn emit(level: Level, target: &'static str, message: &str, fields: &[(&'static str, String)]) {
+    let subscriber = SUBSCRIBER.read().unwrap().clone();
+    if let Some(subscriber) = subscriber {
+        subscriber.record(&Record {
+            level,
+            target,
+            message,
+            fields,
+        });
+    }
+}
+
+// A timed unit of work, logged once it ends with the fields recorded along
+// the way and how long it took, as `elapsed_us`. Costs next to nothing when
+// its level is disabled.
+pub struct Span {
+    level: Level,
+    target: &'static str,
+    name: &'static str,
+    // None when disabled
+    fields: Option<Vec<(&'static str, String)>>,
+    start: Instant,
+}
+
+impl Span {
+    pub fn new(
+        level: Level,
+        target: &'static str,
+        name: &'static str,
+        fields: &[(&'static str, &dyn Display)],
+    ) -> Span {
+        let fields = enabled(level, target).then(|| {
+            fields
+                .iter()
+                .map(|(key, value)| (*key, value.to_string()))
+                .collect()
+        });
+        Span {
+            level,
+            target,
+            name,
+            fields,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, key: &'static str, value: &dyn Display) {
+        if let Some(fields) = &mut self.fields {
+            fields.push((key, value.to_string()));
+        }
+    }
+
+    // Time since the span started, in microseconds
+    pub fn elapsed_us(&self) -> u128 {
+        self.start.elapsed().as_micros()
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut fields) = self.fields.take() {
+            fields.push(("elapsed_us", self.elapsed_us().to_string()));
+            emit(self.level, self.target, self.name, &fields);
+        }
+    }
+}
+
+// Writes `<unix time> <LEVEL> <target>: <message> key=value ...` lines to
+// stderr, leaving out events below `max_level`
+pub struct StderrSubscriber {
+    max_level: Level,
+    // One line at a time when several threads log
+    lock: Mutex<()>,
+}
+
+impl StderrSubscriber {
+    pub fn new(max_level: Level) -> StderrSubscriber {
+        StderrSubscriber {
+            max_level,
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl Subscriber for StderrSubscriber {
+    fn enabled(&self, level: Level, _target: &str) -> bool {
+        level <= self.max_level
+    }
+
+    fn record(&self, record: &Record) {
+        let line = format_record(record);
+        let _guard = self.lock.lock().unwrap();
+        let _ = writeln!(io::stderr(), "{} {line}", utils::unix_timestamp());
+    }
+}
+
+// Values with spaces or quotes are quoted
+pub fn format_record(record: &Record) -> String {
+    let mut line = format!(
+        "{:>5} {}: {}",
+        record.level.name().to_uppercase(),
+        record.target,
+        record.message
+    );
+    for (key, value) in record.fields {
+        if value.is_empty() || value.contains([' ', '"', '=']) {
+            line.push_str(&format!(" {key}={value:?}"));
+        } else {
+            line.push_str(&format!(" {key}={value}"));
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collector {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, level: Level, target: &str) -> bool {
+            level <= Level::Debug && target == "log_test"
+        }
+
+        fn record(&self, record: &Record) {
+            self.lines.lock().unwrap().push(format_record(record));
+        }
+    }
+
+    #[test]
+    fn test_events_and_spans() {
+        let collector = Arc::new(Collector::default());
+        set_subscriber(collector.clone());
+
+        info("log_test", "peer connected", &[("addr", &"10.0.0.1:9333")]);
+        warn("log_test", "bad peer", &[("reason", &"invalid block")]);
+        event(Level::Trace, "log_test", "too detailed", &[]);
+        info("other", "filtered out", &[]);
+        {
+            let mut span = Span::new(Level::Debug, "log_test", "add_block", &[("height", &7)]);
+            span.record("txs", &3);
+        }
+
+        let lines = collector.lines.lock().unwrap();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            " INFO log_test: peer connected addr=10.0.0.1:9333"
+        );
+        assert_eq!(
+            lines[1],
+            " WARN log_test: bad peer reason=\"invalid block\""
+        );
+        assert!(lines[2].starts_with("DEBUG log_test: add_block height=7 txs=3 elapsed_us="));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+    }
+}
@@ -1,45 +1,731 @@
-use rust_blockchain::blockchain::Blockchain;
-use rust_blockchain::transaction::{Transaction, TxInput, TxOutput};
-use rust_blockchain::utils::*;
-use rust_blockchain::wallet::Wallet;
+use rust_blockchain::addr_manager::AddrManager;
+use rust_blockchain::address::Address;
+use rust_blockchain::archive;
+use rust_blockchain::block_meta::BlockMetaStore;
+use rust_blockchain::config::{self, Config};
+use rust_blockchain::fees::DEFAULT_FEE_TARGET;
+use rust_blockchain::invoice::InvoiceBook;
+use rust_blockchain::json::Json;
+use rust_blockchain::log::{self, StderrSubscriber};
+use rust_blockchain::miner::Miner;
+use rust_blockchain::mnemonic::{self, Wordlist};
+use rust_blockchain::payment_request::PaymentRequest;
+use rust_blockchain::peer_manager::DEFAULT_MAX_OUTBOUND;
+use rust_blockchain::prelude::*;
+use rust_blockchain::psbt::PartialTransaction;
+use rust_blockchain::rpc::{self, RpcAuth};
+use rust_blockchain::runtime::{NodeRuntime, RuntimeConfig};
+use rust_blockchain::wallet_manager::WalletManager;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fs, io, process};
+
+const USAGE: &str = "\
+usage: rust_blockchain [--config <file>] [--network <mainnet|testnet|regtest>]
+                      [--log-level <error|warn|info|debug|trace>] [--wallet <name>] <command>
+
+commands:
+  node start [--listen <addr>] [--rpc <addr>] [--connect <addr>]...
+             [--mine <address>] [--threads <n>] [--in-memory]
+             [--stratum <addr> --pay-to <address>]
+  wallet new [--mnemonic [--wordlist <file>] [--mnemonic-passphrase <text>]]
+  wallet restore <words>... [--wordlist <file>] [--mnemonic-passphrase <text>]
+  wallet address
+  wallet balance
+  wallet send <address> <amount> [--fee <amount> | --target <blocks>] [--replaceable]
+              [--unsigned <file> [--from <address>]]
+  wallet request [--amount <coins>] [--label <text>] [--message <text>] [--qr]
+  wallet pay <uri> [--amount <coins>] [--fee <amount> | --target <blocks>] [--replaceable]
+             [--unsigned <file> [--from <address>]]
+  wallet sign-offline <file> [--out <file>]
+  wallet broadcast <file>...
+  wallet create <name>
+  wallet load <name>
+  wallet unload [<name>]
+  wallet list
+  wallet info
+  mine --address <address> [--blocks <n>] [--threads <n>] [--in-memory]
+  chain show [--height <n>]
+  chain export <file>
+  chain import <file>
+
+Wallet commands read the passphrase from RUST_BLOCKCHAIN_PASSPHRASE or stdin.
+`wallet balance`, `send`, `pay` and `broadcast` talk to a running node over
+RPC; `mine` and the `chain` commands work on the chain in the data directory
+while the node is stopped.
+The UTXO set is kept in the data directory unless --in-memory is given.
+Recovery phrases are English unless --wordlist names another BIP39 wordlist,
+one word per line. --mnemonic-passphrase is the optional BIP39 passphrase,
+needed again to restore the wallet.
+For cold storage, `send --unsigned` on a machine with the node writes what to
+sign, `sign-offline` signs it on the machine with the key, and `broadcast`
+sends the signed result.
+`wallet create`, `load`, `unload`, `list` and `info` manage the wallets a
+running node keeps loaded by name. --wallet (or rpc_wallet in the config)
+picks the one `info`, `unload` and `balance` act on when several are loaded.
+RPC calls are authenticated with rpc_user and rpc_password from the config,
+or else with the credentials the node writes to .cookie in the data
+directory on each start.";
+
+// Flags that don't take a value
+const SWITCHES: [&str; 4] = ["mnemonic", "replaceable", "in-memory", "qr"];
+
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, Vec<String>>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        let mut positional = Vec::new();
+        let mut options: HashMap<String, Vec<String>> = HashMap::new();
+
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) if SWITCHES.contains(&name) => {
+                    options.entry(name.to_string()).or_default();
+                }
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for --{name}"))?;
+                    options.entry(name.to_string()).or_default().push(value);
+                }
+                None => positional.push(arg),
+            }
+        }
+
+        Ok(Args {
+            positional,
+            options,
+        })
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.options
+            .get(name)
+            .and_then(|values| values.last())
+            .map(String::as_str)
+    }
+
+    fn get_all(&self, name: &str) -> &[String] {
+        self.options.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+
+    fn parse_or<T: std::str::FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.get(name) {
+            Some(value) => value
+                .parse()
+                .map_err(|_| format!("invalid value for --{name}: {value}")),
+            None => Ok(default),
+        }
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    if let Err(e) = run() {
+        eprintln!("error: {e}");
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let args = Args::parse(env::args().skip(1))?;
+    let config = load_config(&args)?;
+    log::set_subscriber(Arc::new(StderrSubscriber::new(config.log_level)));
+    let command: Vec<&str> = args.positional.iter().map(String::as_str).collect();
+
+    match command.as_slice() {
+        ["node", "start"] => node_start(&args, &config),
+        ["wallet", "new"] => wallet_new(&args, &config),
+        ["wallet", "restore", words @ ..] => wallet_restore(&args, &words.join(" "), &config),
+        ["wallet", "address"] => {
+            println!("{}", load_wallet(&config)?.address);
+            Ok(())
+        }
+        ["wallet", "balance"] => wallet_balance(&config),
+        ["wallet", "send", address, amount] => wallet_send(&args, &config, address, amount),
+        ["wallet", "request"] => wallet_request(&args, &config),
+        ["wallet", "pay", uri] => wallet_pay(&args, &config, uri),
+        ["wallet", "sign-offline", path] => wallet_sign_offline(&args, &config, path),
+        ["wallet", "broadcast", paths @ ..] => wallet_broadcast(&config, paths),
+        ["wallet", "create", name] => node_wallet_create(&config, name),
+        ["wallet", "load", name] => node_wallet_load(&config, name),
+        ["wallet", "unload", names @ ..] if names.len() <= 1 => {
+            let params = names.iter().map(|name| Json::String(name.to_string()));
+            rpc_call(&config, "unloadwallet", params.collect()).map(|_| ())
+        }
+        ["wallet", "list"] => print_rpc(&config, "listwallets"),
+        ["wallet", "info"] => print_rpc(&config, "getwalletinfo"),
+        ["mine"] => mine(&args, &config),
+        ["chain", "show"] => chain_show(&args, &config),
+        ["chain", "export", path] => chain_export(&config, path),
+        ["chain", "import", path] => chain_import(&config, path),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn load_config(args: &Args) -> Result<Config, String> {
+    let path = args.get("config").unwrap_or(config::DEFAULT_CONFIG_FILE);
+    let mut config = if args.get("config").is_none() && !Path::new(path).exists() {
+        Config::default()
+    } else {
+        Config::load(path).map_err(|e| format!("could not read config {path}: {e:?}"))?
+    };
+
+    if let Some(network) = args.get("network") {
+        config.network = network.parse()?;
+    }
+    config.in_memory |= args.has("in-memory");
+    if let Some(wallet) = args.get("wallet") {
+        config.rpc_wallet = Some(wallet.to_string());
+    }
+    if let Some(level) = args.get("log-level") {
+        config.log_level = level.parse()?;
+    }
+    Ok(config)
+}
+
+fn load_chain(config: &Config) -> Result<Blockchain, String> {
+    let mut utxos = if config.in_memory {
+        UTXOSet::new()
+    } else {
+        fs::create_dir_all(config.network_dir()).map_err(|e| e.to_string())?;
+        UTXOSet::open(config.utxo_path())
+            .map_err(|e| format!("could not open the UTXO set: {e}"))?
+    };
+    let mut blockchain = if config.chain_path().exists() {
+        Blockchain::load_from_disk_with_utxos(config.chain_path(), config.params(), utxos)
+            .map_err(|e| format!("could not load chain: {e}"))?
+    } else {
+        // Whatever is left in the set belongs to a chain that is gone
+        utxos
+            .clear()
+            .map_err(|e| format!("could not clear the UTXO set: {e}"))?;
+        Blockchain::with_utxos(config.params(), utxos)
+    };
+    blockchain
+        .mempool
+        .set_incremental_relay_fee_rate(config.incremental_relay_fee_rate);
+    blockchain
+        .mempool
+        .set_min_relay_fee_rate(config.min_relay_fee_rate);
+    blockchain.mempool.set_max_size(config.max_mempool_size);
+    blockchain.mempool.set_max_age(config.mempool_expiry);
+    if config.address_index {
+        blockchain.enable_address_index();
+    }
+    Ok(blockchain)
+}
+
+fn save_chain(config: &Config, blockchain: &Blockchain) -> Result<(), String> {
+    fs::create_dir_all(config.network_dir()).map_err(|e| e.to_string())?;
+    blockchain
+        .save_to_disk(config.chain_path())
+        .and_then(|_| blockchain.mempool.save_to_disk(config.mempool_path()))
+        .map_err(|e| format!("could not save chain: {e}"))
+}
+
+fn passphrase() -> Result<String, String> {
+    if let Ok(passphrase) = env::var("RUST_BLOCKCHAIN_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    eprint!("passphrase: ");
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn load_wallet(config: &Config) -> Result<Wallet, String> {
+    Wallet::load(config.wallet_path(), &passphrase()?)
+        .map_err(|e| format!("could not open wallet: {e:?}"))
+}
+
+fn save_wallet(config: &Config, wallet: &Wallet) -> Result<(), String> {
+    let path = config.wallet_path();
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()));
+    }
+    fs::create_dir_all(config.network_dir()).map_err(|e| e.to_string())?;
+    wallet
+        .save(&path, &passphrase()?)
+        .map_err(|e| format!("could not save wallet: {e:?}"))?;
+    println!("{}", wallet.address);
+    Ok(())
+}
+
+fn parse_address(address: &str) -> Result<Address, String> {
+    address
+        .parse()
+        .map_err(|e| format!("invalid address {address}: {e:?}"))
+}
+
+fn node_start(args: &Args, config: &Config) -> Result<(), String> {
+    let mut blockchain = load_chain(config)?;
+    if config.mempool_path().exists() {
+        let restored = blockchain
+            .load_mempool(config.mempool_path())
+            .map_err(|e| e.to_string())?;
+        log::info("node", "restored mempool", &[("transactions", &restored)]);
+    }
+    log::info("node", "loaded chain", &[("height", &blockchain.height())]);
+
+    let miner_pkhash = args
+        .get("mine")
+        .map(parse_address)
+        .transpose()?
+        .map(|address| address.pkhash);
+    let stratum = match (args.get("stratum"), args.get("pay-to")) {
+        (Some(addr), Some(address)) => Some((addr.to_string(), parse_address(address)?.pkhash)),
+        (None, None) => None,
+        _ => return Err("--stratum and --pay-to go together".to_string()),
+    };
+    let rpc_auth = match configured_rpc_auth(config)? {
+        Some(auth) => auth,
+        None => {
+            let auth = RpcAuth::random();
+            fs::create_dir_all(config.network_dir()).map_err(|e| e.to_string())?;
+            auth.write_cookie(config.cookie_path())
+                .map_err(|e| format!("could not write the RPC cookie: {e}"))?;
+            auth
+        }
+    };
+    let node = Node::new(blockchain);
+    if config.peers_path().exists() {
+        *node.addr_manager() = AddrManager::load_from_disk(config.peers_path())
+            .map_err(|e| format!("could not load known peers: {e}"))?;
+    }
+    if !config.in_memory {
+        *node.block_meta() = BlockMetaStore::open(config.block_meta_path())
+            .map_err(|e| format!("could not open block metadata: {e}"))?;
+        *node.invoices() = InvoiceBook::open(config.invoices_path())
+            .map_err(|e| format!("could not open invoices: {e}"))?;
+        *node.wallets() = WalletManager::in_dir(config.wallets_dir());
+    }
+    node.invoices()
+        .set_confirmations(config.invoice_confirmations);
+    for seed in &config.seeds {
+        if let Err(e) = node.addr_manager().add_seed(seed) {
+            log::warn(
+                "node",
+                "could not resolve seed",
+                &[("seed", seed), ("error", &e)],
+            );
+        }
+    }
+
+    let runtime = NodeRuntime::start(
+        node,
+        RuntimeConfig {
+            listen: Some(args.get("listen").unwrap_or(&config.listen).to_string()),
+            rpc: Some((args.get("rpc").unwrap_or(&config.rpc).to_string(), rpc_auth)),
+            peers: Vec::new(),
+            mine_to: miner_pkhash,
+            miner_threads: args.parse_or("threads", 1)?,
+            stratum,
+        },
+    )
+    .map_err(|e| format!("could not start node: {e}"))?;
+    log::info(
+        "node",
+        "started",
+        &[
+            ("listen", &runtime.listen_addr().unwrap()),
+            ("rpc", &runtime.rpc_addr().unwrap()),
+        ],
+    );
+    if let Some(addr) = runtime.stratum_addr() {
+        log::info("node", "serving work to miners", &[("stratum", &addr)]);
+    }
+
+    for peer in config.peers.iter().chain(args.get_all("connect")) {
+        if let Err(e) = runtime.node().connect(peer) {
+            log::warn(
+                "node",
+                "could not connect",
+                &[("peer", peer), ("error", &e)],
+            );
+        }
+    }
+    let node = runtime.node();
+    let slots = DEFAULT_MAX_OUTBOUND.saturating_sub(node.peer_manager().outbound_count());
+    if slots > 0 {
+        let connected = node.connect_to_known_peers(slots);
+        log::info("node", "connected to known peers", &[("peers", &connected)]);
+    }
+
+    for event in runtime.subscribe() {
+        if let ChainEvent::BlockConnected { .. } = event {
+            save_chain(config, &node.blockchain())?;
+            node.addr_manager()
+                .save_to_disk(config.peers_path())
+                .map_err(|e| format!("could not save known peers: {e}"))?;
+        }
+    }
+    Ok(())
+}
 
-    let wallet = Wallet::new();
-    let mut blockchain = Blockchain::new();
+// The wordlist given by --wordlist, or English
+fn wordlist(args: &Args) -> Result<Cow<'static, Wordlist>, String> {
+    match args.get("wordlist") {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+            Wordlist::parse(&text)
+                .map(Cow::Owned)
+                .map_err(|e| format!("{path}: {e:?}"))
+        }
+        None => Ok(Cow::Borrowed(Wordlist::english())),
+    }
+}
+
+fn wallet_new(args: &Args, config: &Config) -> Result<(), String> {
+    let chain_id = config.params().genesis().digest.0;
+    if args.has("mnemonic") {
+        let wordlist = wordlist(args)?;
+        let phrase = mnemonic::generate_in(12, &wordlist).unwrap();
+        let passphrase = args.get("mnemonic-passphrase").unwrap_or_default();
+        let wallet = Wallet::from_mnemonic_in(&phrase, passphrase, &wordlist, chain_id).unwrap();
+        println!("write down your recovery phrase:\n{phrase}\n");
+        save_wallet(config, &wallet)
+    } else {
+        save_wallet(config, &Wallet::for_chain(chain_id))
+    }
+}
+
+fn wallet_restore(args: &Args, phrase: &str, config: &Config) -> Result<(), String> {
+    let wallet = Wallet::from_mnemonic_in(
+        phrase,
+        args.get("mnemonic-passphrase").unwrap_or_default(),
+        &*wordlist(args)?,
+        config.params().genesis().digest.0,
+    )
+    .map_err(|e| format!("invalid recovery phrase: {e:?}"))?;
+    save_wallet(config, &wallet)
+}
+
+// The credentials in the config, or None if the node keeps a cookie
+fn configured_rpc_auth(config: &Config) -> Result<Option<RpcAuth>, String> {
+    match (&config.rpc_user, &config.rpc_password) {
+        (Some(user), Some(password)) => Ok(Some(RpcAuth::new(user, password))),
+        (None, None) => Ok(None),
+        _ => Err("rpc_user and rpc_password go together".to_string()),
+    }
+}
+
+// Routed to the wallet named by --wallet, if any
+fn rpc_call(config: &Config, method: &str, params: Vec<Json>) -> Result<Json, String> {
+    let auth = match configured_rpc_auth(config)? {
+        Some(auth) => auth,
+        None => RpcAuth::read_cookie(config.cookie_path())
+            .map_err(|e| format!("could not read the RPC cookie, is the node running? {e}"))?,
+    };
+    match &config.rpc_wallet {
+        Some(wallet) => rpc::call_wallet(&config.rpc, &auth, wallet, method, params),
+        None => rpc::call(&config.rpc, &auth, method, params),
+    }
+    .map_err(|e| format!("{method} failed: {e:?}"))
+}
+
+fn print_rpc(config: &Config, method: &str) -> Result<(), String> {
+    println!("{}", rpc_call(config, method, Vec::new())?);
+    Ok(())
+}
+
+// Creates an HD wallet on the running node, saved with the passphrase
+fn node_wallet_create(config: &Config, name: &str) -> Result<(), String> {
+    let created = rpc_call(
+        config,
+        "createwallet",
+        vec![Json::String(name.to_string()), Json::String(passphrase()?)],
+    )?;
+    if let Some(phrase) = created.get("mnemonic").and_then(Json::as_str) {
+        println!("write down your recovery phrase:\n{phrase}\n");
+    }
+    println!(
+        "{}",
+        created
+            .get("address")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+fn node_wallet_load(config: &Config, name: &str) -> Result<(), String> {
+    let loaded = rpc_call(
+        config,
+        "loadwallet",
+        vec![Json::String(name.to_string()), Json::String(passphrase()?)],
+    )?;
+    println!(
+        "{}",
+        loaded
+            .get("address")
+            .and_then(Json::as_str)
+            .unwrap_or_default()
+    );
+    Ok(())
+}
+
+// Of the node wallet given by --wallet, or else of the wallet file's address
+fn wallet_balance(config: &Config) -> Result<(), String> {
+    if config.rpc_wallet.is_some() {
+        return print_rpc(config, "getbalance");
+    }
+    let wallet = load_wallet(config)?;
+    let balance = rpc_call(
+        config,
+        "getbalance",
+        vec![Json::String(wallet.address.to_string())],
+    )?;
+    println!("{balance}");
+    Ok(())
+}
+
+fn wallet_send(args: &Args, config: &Config, address: &str, amount: &str) -> Result<(), String> {
+    let recipient = parse_address(address)?;
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("invalid amount {amount}"))?;
+    send_payment(
+        args,
+        config,
+        &PaymentRequest::new(recipient).with_amount(amount),
+    )
+}
+
+// Prints a payment request URI for the wallet's address
+fn wallet_request(args: &Args, config: &Config) -> Result<(), String> {
+    let mut request = load_wallet(config)?.payment_request();
+    if args.has("amount") {
+        request = request.with_amount(args.parse_or("amount", Amount::ZERO)?);
+    }
+    if let Some(label) = args.get("label") {
+        request = request.with_label(label);
+    }
+    if let Some(message) = args.get("message") {
+        request = request.with_message(message);
+    }
+    if args.has("qr") {
+        println!("{}", request.encode_for_qr());
+    } else {
+        println!("{request}");
+    }
+    Ok(())
+}
+
+// --amount is only needed if the request leaves it to the payer
+fn wallet_pay(args: &Args, config: &Config, uri: &str) -> Result<(), String> {
+    let mut request =
+        PaymentRequest::parse(uri).map_err(|e| format!("invalid payment request: {e}"))?;
+    if request.amount.is_none() && args.has("amount") {
+        request.amount = Some(args.parse_or("amount", Amount::ZERO)?);
+    }
+    if let Some(label) = &request.label {
+        eprintln!("paying {label}");
+    }
+    send_payment(args, config, &request)
+}
+
+// With --unsigned, the transaction is written to the file with the outputs
+// it spends instead, for `wallet sign-offline` on the machine with the key.
+// --from spends the outputs of an address without opening the wallet.
+fn send_payment(args: &Args, config: &Config, request: &PaymentRequest) -> Result<(), String> {
+    if let Some(path) = args.get("unsigned") {
+        let from = match args.get("from") {
+            Some(address) => parse_address(address)?,
+            None => load_wallet(config)?.address,
+        };
+        let tx = payment_builder(args, config, from, request)?
+            .build_unsigned()
+            .map_err(|e| e.to_string())?;
+        let mut ptx = PartialTransaction::new(tx);
+        for (input, meta) in ptx.tx.inputs.iter().zip(&mut ptx.inputs) {
+            meta.utxo = Some(TxOutput::new(input.value, from));
+        }
+        return fs::write(path, ptx.to_hex()).map_err(|e| format!("could not write {path}: {e}"));
+    }
+
+    let wallet = load_wallet(config)?;
+    let tx = payment_builder(args, config, wallet.address, request)?
+        .build(&wallet)
+        .map_err(|e| e.to_string())?;
+    send_raw_transaction(config, &tx)
+}
+
+// Pays the request from the outputs of `from` the node knows about
+fn payment_builder(
+    args: &Args,
+    config: &Config,
+    from: Address,
+    request: &PaymentRequest,
+) -> Result<TxBuilder, String> {
+    let mut builder = TxBuilder::new(from);
+    builder
+        .pay_request(request)
+        .map_err(|e| e.to_string())?
+        .replaceable(args.has("replaceable"));
+    // Without a fee, pay the rate the node expects to confirm in time
+    if args.has("fee") {
+        builder.fee(args.parse_or::<u64>("fee", 0)?);
+    } else {
+        let target: u64 = args.parse_or("target", DEFAULT_FEE_TARGET)?;
+        let fee_rate = rpc_call(config, "estimatefee", vec![Json::U64(target)])?;
+        builder.fee_rate(fee_rate.as_u64().ok_or("invalid estimatefee response")?);
+    }
+
+    let unspent = rpc_call(config, "listunspent", vec![Json::String(from.to_string())])?;
+
+    for output in unspent.as_array().into_iter().flatten() {
+        let txid: Option<TxId> = output
+            .get("txid")
+            .and_then(Json::as_str)
+            .and_then(|txid| txid.parse().ok());
+        let index = output.get("index").and_then(Json::as_u64);
+        let value = output.get("value").and_then(Json::as_u64);
+        let (Some(txid), Some(index), Some(value)) = (txid, index, value) else {
+            return Err("invalid listunspent response".to_string());
+        };
+        builder.add_utxo(OutPoint::new(txid, index as u16), value);
+    }
+    Ok(builder)
+}
 
-    for _ in 0..5 {
-        let mut block = blockchain.next_block();
-        println!("Mining block {}...", block.index);
-        mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
-        blockchain.add_block(block).unwrap();
+fn read_partial(path: &str) -> Result<PartialTransaction, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("could not read {path}: {e}"))?;
+    PartialTransaction::from_hex(&data).ok_or(format!("{path} is not a partial transaction"))
+}
+
+// Signs the inputs the wallet can after showing what the transaction pays,
+// writing the signatures out for `wallet broadcast`. Needs no node.
+fn wallet_sign_offline(args: &Args, config: &Config, path: &str) -> Result<(), String> {
+    let mut ptx = read_partial(path)?;
+    for output in &ptx.tx.outputs {
+        eprintln!("pays {} to {}", output.value, output.address());
+    }
+    match ptx.fee() {
+        Some(fee) => eprintln!("fee {fee}"),
+        None => eprintln!("fee unknown, spent outputs missing"),
     }
 
-    println!("\nUTXO SET:\n{}\n", blockchain.utxos);
+    let wallet = load_wallet(config)?;
+    if wallet.sign_partial(&mut ptx).map_err(|e| e.to_string())? == 0 {
+        return Err("the wallet can't sign any of the inputs".to_string());
+    }
+    match args.get("out") {
+        Some(out) => {
+            fs::write(out, ptx.to_hex()).map_err(|e| format!("could not write {out}: {e}"))
+        }
+        None => {
+            println!("{}", ptx.to_hex());
+            Ok(())
+        }
+    }
+}
 
-    let (txid, index) = &blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+// Merges the signatures in each file and sends the finished transaction
+fn wallet_broadcast(config: &Config, paths: &[&str]) -> Result<(), String> {
+    let (first, rest) = paths.split_first().ok_or(USAGE)?;
+    let mut ptx = read_partial(first)?;
+    for path in rest {
+        ptx.merge(read_partial(path)?)
+            .map_err(|e| format!("could not merge {path}: {e:?}"))?;
+    }
+    let tx = ptx
+        .finalize()
+        .map_err(|e| format!("could not finalize: {e:?}"))?;
+    send_raw_transaction(config, &tx)
+}
 
-    let mut block = blockchain.next_block();
+fn send_raw_transaction(config: &Config, tx: &Transaction) -> Result<(), String> {
+    let txid = rpc_call(
+        config,
+        "sendrawtransaction",
+        vec![Json::String(hex::encode(tx.as_bincode()))],
+    )?;
+    println!("{}", txid.as_str().unwrap_or_default());
+    Ok(())
+}
 
-    let inputs = vec![
-        TxInput::new_unsigned(*txid, *index), // use coinbase UTXO
-    ];
+fn mine(args: &Args, config: &Config) -> Result<(), String> {
+    let address = parse_address(args.get("address").ok_or(USAGE)?)?;
+    let blocks: u64 = args.parse_or("blocks", 1)?;
+    let mut blockchain = load_chain(config)?;
+    if config.mempool_path().exists() {
+        blockchain
+            .load_mempool(config.mempool_path())
+            .map_err(|e| e.to_string())?;
+    }
 
-    let outputs = vec![
-        TxOutput::new(100, [0; 32]),        // unspendable
-        TxOutput::new(2000, wallet.pkhash), // send to self
-        TxOutput::new(42, [1; 32]),
-    ];
+    let mut miner = Miner::new(args.parse_or("threads", 1)?)
+        .with_pow(blockchain.params().pow)
+        .with_clock(blockchain.shared_clock());
+    for _ in 0..blocks {
+        let template = blockchain
+            .create_block_template(address.pkhash)
+            .map_err(|e| format!("could not create a block template: {e}"))?;
+        let attempts = miner.stats().attempts();
+        let block = miner
+            .start_template(template)
+            .recv()
+            .map_err(|_| "mining stopped".to_string())?;
+        let index = block.header.index;
+        blockchain
+            .add_block(block)
+            .map_err(|e| e.at_height(index).to_string())?;
+        println!(
+            "mined block {index} after {} hashes",
+            miner.stats().attempts() - attempts
+        );
+    }
+    let stats = miner.stats();
+    println!(
+        "{} hashes in {:.1}s, {:.0} hashes/s",
+        stats.attempts(),
+        stats.mining_time().as_secs_f64(),
+        stats.hash_rate()
+    );
+    if let Some(time) = stats.expected_time_to_block(&blockchain.params().target()) {
+        println!("expected time to a block: {:.1}s", time.as_secs_f64());
+    }
 
-    let mut tx = Transaction::new(inputs, outputs);
-    wallet.sign_transaction(&mut tx);
+    save_chain(config, &blockchain)
+}
 
-    block.add_tx(tx);
-    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
-    blockchain.add_block(block).unwrap();
+fn chain_show(args: &Args, config: &Config) -> Result<(), String> {
+    let blockchain = load_chain(config)?;
+    let height = args.parse_or("height", blockchain.height())?;
+    let block = blockchain
+        .get_block_by_height(height)
+        .ok_or_else(|| format!("no block at height {height}"))?;
+    println!("{block}");
+    Ok(())
+}
 
-    println!("{}", blockchain);
+fn chain_export(config: &Config, path: &str) -> Result<(), String> {
+    let blockchain = load_chain(config)?;
+    let file = fs::File::create(path).map_err(|e| format!("could not create {path}: {e}"))?;
+    let count = archive::export(&blockchain, io::BufWriter::new(file))
+        .map_err(|e| format!("could not export chain: {e}"))?;
+    println!("exported {count} blocks");
+    Ok(())
+}
 
-    println!("\nNEW UTXO SET:\n{}\n", blockchain.utxos);
+fn chain_import(config: &Config, path: &str) -> Result<(), String> {
+    let mut blockchain = load_chain(config)?;
+    let file = fs::File::open(path).map_err(|e| format!("could not open {path}: {e}"))?;
+    let added = archive::import(&mut blockchain, io::BufReader::new(file))
+        .map_err(|e| format!("could not import chain: {e}"));
+    // Blocks added before a bad one are kept
+    save_chain(config, &blockchain)?;
+    println!("imported {} blocks", added?);
+    Ok(())
 }
@@ -0,0 +1,1001 @@
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::clock::{Clock, SystemClock};
+use crate::errors::TransactionError;
+use crate::hash::{OutPoint, TxId};
+use crate::transaction::{self, Transaction, TxOutput};
+use crate::utxo::UTXOSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub struct MempoolEntry {
+    pub tx: Transaction,
+    pub fee: Amount,
+    // Encoded size in bytes, for fee rates
+    pub size: usize,
+    // Height of the next block when it was accepted
+    pub height: u64,
+    // When it was accepted
+    pub time: u64,
+    // Fee and size of the transaction with its mempool descendants
+    pub descendant_fee: Amount,
+    pub descendant_size: usize,
+}
+
+impl MempoolEntry {
+    // In base units per 1000 bytes
+    pub fn fee_rate(&self) -> u64 {
+        self.tx.fee_rate(self.fee)
+    }
+}
+
+// In base units per 1000 bytes of the replacement
+pub const DEFAULT_INCREMENTAL_RELAY_FEE_RATE: u64 = 1000;
+// Transactions one replacement may evict, counting descendants
+pub const MAX_REPLACED_TRANSACTIONS: usize = 100;
+// In base units per 1000 bytes
+pub const DEFAULT_MIN_RELAY_FEE_RATE: u64 = 1000;
+// Total encoded size of the transactions, in bytes
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 100_000_000;
+// Two weeks, in seconds
+pub const DEFAULT_MEMPOOL_EXPIRY: u64 = 14 * 24 * 60 * 60;
+
+pub struct Mempool {
+    entries: HashMap<TxId, MempoolEntry>,
+    // outpoint -> id of the mempool transaction spending it
+    spent: HashMap<OutPoint, TxId>,
+    // Fee rate a replacement must pay for its own relay, on top of the fees
+    // of the transactions it evicts
+    incremental_relay_fee_rate: u64,
+    // Transactions paying less, or with outputs worth less than spending
+    // them would cost at this rate, are not accepted
+    min_relay_fee_rate: u64,
+    // Sum of the entries' sizes
+    total_size: usize,
+    // Entries by the fee rate of their descendant package, lowest first,
+    // so trimming doesn't have to rescan the pool
+    by_descendant_fee_rate: BTreeSet<Descendants>,
+    // Past this, the lowest paying transactions are evicted
+    max_size: usize,
+    // Transactions accepted longer than this many seconds ago expire
+    max_age: u64,
+}
+
+impl Mempool {
+    pub fn new() -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            incremental_relay_fee_rate: DEFAULT_INCREMENTAL_RELAY_FEE_RATE,
+            min_relay_fee_rate: DEFAULT_MIN_RELAY_FEE_RATE,
+            total_size: 0,
+            by_descendant_fee_rate: BTreeSet::new(),
+            max_size: DEFAULT_MAX_MEMPOOL_SIZE,
+            max_age: DEFAULT_MEMPOOL_EXPIRY,
+        }
+    }
+
+    pub fn set_incremental_relay_fee_rate(&mut self, fee_rate: u64) {
+        self.incremental_relay_fee_rate = fee_rate;
+    }
+
+    pub fn set_min_relay_fee_rate(&mut self, fee_rate: u64) {
+        self.min_relay_fee_rate = fee_rate;
+    }
+
+    pub fn min_relay_fee_rate(&self) -> u64 {
+        self.min_relay_fee_rate
+    }
+
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn set_max_age(&mut self, max_age: u64) {
+        self.max_age = max_age;
+    }
+
+    pub fn add_transaction(
+        &mut self,
+        tx: Transaction,
+        utxos: &UTXOSet,
+        chain_id: &[u8; 32],
+        height: u64,
+    ) -> Result<(), TransactionError> {
+        self.add_transaction_at(tx, utxos, chain_id, height, SystemClock.now())
+    }
+
+    // With `now` as the current time
+    pub fn add_transaction_at(
+        &mut self,
+        tx: Transaction,
+        utxos: &UTXOSet,
+        chain_id: &[u8; 32],
+        height: u64,
+        now: u64,
+    ) -> Result<(), TransactionError> {
+        if self.entries.contains_key(&tx.id) {
+            return Err(TransactionError::AlreadyInMempool);
+        }
+
+        tx.verify_at(chain_id, now)?;
+        if tx
+            .outputs
+            .iter()
+            .any(|output| output.is_dust(self.min_relay_fee_rate))
+        {
+            return Err(TransactionError::Dust);
+        }
+
+        // Conflicting transactions can only be replaced if they all opted in
+        let mut conflicts = Vec::new();
+        for input in &tx.inputs {
+            let outpoint = input.outpoint();
+            if let Some(&spent_by) = self.spent.get(&outpoint) {
+                if !self.entries[&spent_by].tx.replaceable {
+                    return Err(TransactionError::MempoolConflict { outpoint, spent_by });
+                }
+                if !conflicts.contains(&spent_by) {
+                    conflicts.push(spent_by);
+                }
+            }
+        }
+
+        // Outputs of other mempool transactions can be spent, so children
+        // can be added before their parents confirm
+        let fee = utxos.validate_transaction_with(&tx, height, |outpoint| self.output(outpoint))?;
+        let size = tx.serialized_size();
+        if fee < transaction::fee_for_size(size, self.min_relay_fee_rate) {
+            return Err(TransactionError::FeeTooLow);
+        }
+
+        if !conflicts.is_empty() {
+            let mut replaced = Vec::new();
+            for txid in conflicts {
+                self.collect_descendants(txid, &mut replaced);
+            }
+            if replaced.len() > MAX_REPLACED_TRANSACTIONS {
+                return Err(TransactionError::TooManyReplacements);
+            }
+            // The outputs of the evicted transactions disappear with them
+            if tx.inputs.iter().any(|input| replaced.contains(&input.txid)) {
+                return Err(TransactionError::InvalidUTXO);
+            }
+            // It must also pay for its own bandwidth, which the evicted
+            // transactions' fees already went to relaying
+            let replaced_fees: u64 = replaced.iter().map(|txid| self.entries[txid].fee.0).sum();
+            let increment = transaction::fee_for_size(size, self.incremental_relay_fee_rate);
+            if fee.0 < replaced_fees.saturating_add(increment.0) {
+                return Err(TransactionError::ReplacementFeeTooLow);
+            }
+            self.remove_all(&replaced);
+        }
+
+        for input in &tx.inputs {
+            self.spent.insert(input.outpoint(), tx.id);
+        }
+        self.total_size += size;
+        let txid = tx.id;
+        self.entries.insert(
+            txid,
+            MempoolEntry {
+                tx,
+                fee,
+                size,
+                height,
+                time: now,
+                descendant_fee: fee,
+                descendant_size: size,
+            },
+        );
+        self.by_descendant_fee_rate
+            .insert(Descendants::of(&self.entries[&txid]));
+        for ancestor in self.ancestors(&txid) {
+            self.update_descendants(&ancestor, |entry| {
+                entry.descendant_fee.0 += fee.0;
+                entry.descendant_size += size;
+            });
+        }
+        Ok(())
+    }
+
+    // While the mempool is over its maximum size, evicts the transaction
+    // whose fee rate, counting its descendants, is lowest, along with those
+    // descendants, which can't stay without it. A parent is thus kept as
+    // long as a child pays enough for both. Returns the ids evicted.
+    pub fn trim_to_size(&mut self) -> Vec<TxId> {
+        let mut evicted = Vec::new();
+        while self.total_size > self.max_size {
+            let Some(&Descendants { txid, .. }) = self.by_descendant_fee_rate.first() else {
+                break;
+            };
+            evicted.extend(
+                self.remove_with_descendants(&txid)
+                    .into_iter()
+                    .map(|tx| tx.id),
+            );
+        }
+        evicted
+    }
+
+    // Removes the transactions accepted more than the maximum age before
+    // `now`, with their descendants, and returns their ids
+    pub fn expire(&mut self, now: u64) -> Vec<TxId> {
+        let mut expired: Vec<TxId> = self
+            .entries
+            .values()
+            .filter(|entry| now.saturating_sub(entry.time) > self.max_age)
+            .map(|entry| entry.tx.id)
+            .collect();
+        expired.sort();
+        let mut removed = Vec::new();
+        for txid in expired {
+            removed.extend(
+                self.remove_with_descendants(&txid)
+                    .into_iter()
+                    .map(|tx| tx.id),
+            );
+        }
+        removed
+    }
+
+    // An output of a mempool transaction
+    pub fn output(&self, outpoint: &OutPoint) -> Option<&TxOutput> {
+        self.entries
+            .get(&outpoint.txid)?
+            .tx
+            .outputs
+            .get(outpoint.index as usize)
+    }
+
+    // The mempool transactions whose outputs the transaction spends,
+    // directly or not. Parents come before their children.
+    pub fn ancestors(&self, txid: &TxId) -> Vec<TxId> {
+        let mut found = Vec::new();
+        if let Some(entry) = self.entries.get(txid) {
+            for input in &entry.tx.inputs {
+                self.collect_ancestors(input.txid, &mut found);
+            }
+        }
+        found
+    }
+
+    fn collect_ancestors(&self, txid: TxId, found: &mut Vec<TxId>) {
+        let Some(entry) = self.entries.get(&txid) else {
+            return;
+        };
+        if found.contains(&txid) {
+            return;
+        }
+        for input in &entry.tx.inputs {
+            self.collect_ancestors(input.txid, found);
+        }
+        found.push(txid);
+    }
+
+    // The mempool transactions spending the transaction's outputs, directly
+    // or not
+    pub fn descendants(&self, txid: &TxId) -> Vec<TxId> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+        let mut found = Vec::new();
+        self.collect_descendants(*txid, &mut found);
+        found.remove(0);
+        found
+    }
+
+    // Removes the transaction and its descendants, whose inputs no longer
+    // exist without it
+    pub fn remove_with_descendants(&mut self, txid: &TxId) -> Vec<Transaction> {
+        if !self.entries.contains_key(txid) {
+            return Vec::new();
+        }
+        let mut found = Vec::new();
+        self.collect_descendants(*txid, &mut found);
+        self.remove_all(&found)
+    }
+
+    pub fn remove_transaction(&mut self, txid: &TxId) -> Option<Transaction> {
+        self.remove_all(&[*txid]).pop()
+    }
+
+    // Removes the transactions, taking each out of the descendant packages
+    // of its ancestors that stay. Those are found before anything is
+    // removed, while every path to them is still there.
+    fn remove_all(&mut self, txids: &[TxId]) -> Vec<Transaction> {
+        let removing: HashSet<&TxId> = txids.iter().collect();
+        let mut staying_ancestors = Vec::new();
+        for txid in txids {
+            let Some(entry) = self.entries.get(txid) else {
+                continue;
+            };
+            let (fee, size) = (entry.fee.0, entry.size);
+            for ancestor in self.ancestors(txid) {
+                if !removing.contains(&ancestor) {
+                    staying_ancestors.push((ancestor, fee, size));
+                }
+            }
+        }
+        for (ancestor, fee, size) in staying_ancestors {
+            self.update_descendants(&ancestor, |entry| {
+                entry.descendant_fee.0 -= fee;
+                entry.descendant_size -= size;
+            });
+        }
+
+        let mut removed = Vec::new();
+        for txid in txids {
+            let Some(entry) = self.entries.remove(txid) else {
+                continue;
+            };
+            self.by_descendant_fee_rate.remove(&Descendants::of(&entry));
+            self.total_size -= entry.size;
+            for input in &entry.tx.inputs {
+                self.spent.remove(&input.outpoint());
+            }
+            removed.push(entry.tx);
+        }
+        removed
+    }
+
+    // Changes an entry's descendant package, keeping it in place in the
+    // eviction order
+    fn update_descendants(&mut self, txid: &TxId, update: impl FnOnce(&mut MempoolEntry)) {
+        let entry = self.entries.get_mut(txid).unwrap();
+        self.by_descendant_fee_rate.remove(&Descendants::of(entry));
+        update(entry);
+        self.by_descendant_fee_rate.insert(Descendants::of(entry));
+    }
+
+    // Adds the transaction and every mempool transaction spending its
+    // outputs, recursively
+    fn collect_descendants(&self, txid: TxId, found: &mut Vec<TxId>) {
+        if found.contains(&txid) {
+            return;
+        }
+        found.push(txid);
+        let outputs = self.entries[&txid].tx.outputs.len();
+        for output in 0..outputs as u16 {
+            if let Some(&child) = self.spent.get(&OutPoint::new(txid, output)) {
+                self.collect_descendants(child, found);
+            }
+        }
+    }
+
+    // Drops transactions included in the block, along with any that spend
+    // an outpoint the block has now consumed and their descendants
+    pub fn remove_confirmed(&mut self, block: &Block) {
+        for tx in &block.transactions {
+            self.remove_transaction(&tx.id);
+            for input in &tx.inputs {
+                if let Some(conflict) = self.spent.get(&input.outpoint()).copied() {
+                    self.remove_with_descendants(&conflict);
+                }
+            }
+        }
+    }
+
+    // Picks transactions by the fee rate of their package: the transaction
+    // with its ancestors not yet picked. A parent paying a low fee is thus
+    // picked along with a child paying a high one. Parents come before
+    // their children.
+    pub fn select_for_block(&self, max_txs: usize) -> Vec<Transaction> {
+        self.select(|count, _| count <= max_txs)
+    }
+
+    // Like select_for_block, but limited by the total encoded size
+    pub fn select_for_size(&self, max_size: usize) -> Vec<Transaction> {
+        self.select(|_, size| size <= max_size)
+    }
+
+    // `fits` is given the number and size of the transactions that would be
+    // selected
+    fn select(&self, fits: impl Fn(usize, usize) -> bool) -> Vec<Transaction> {
+        let mut selected: Vec<Transaction> = Vec::new();
+        let mut selected_size = 0;
+        let mut included: HashSet<TxId> = HashSet::new();
+        let mut skipped: HashSet<TxId> = HashSet::new();
+
+        // Each entry's package is worked out once. Picking a transaction
+        // then only takes it out of its descendants' packages, which go
+        // back on the heap; the heap entries they replace are skipped.
+        let mut packages: HashMap<TxId, Package> = HashMap::new();
+        for (txid, entry) in &self.entries {
+            let mut package = Package {
+                fee: entry.fee.0,
+                size: entry.size,
+                count: 1,
+                txid: *txid,
+            };
+            for ancestor in self.ancestors(txid) {
+                package.add(&self.entries[&ancestor]);
+            }
+            packages.insert(*txid, package);
+        }
+        let mut heap: BinaryHeap<Package> = packages.values().copied().collect();
+
+        while let Some(best) = heap.pop() {
+            let txid = best.txid;
+            if included.contains(&txid) || skipped.contains(&txid) || packages[&txid] != best {
+                continue;
+            }
+            if !fits(selected.len() + best.count, selected_size + best.size) {
+                skipped.insert(txid);
+                continue;
+            }
+            let mut package = self.ancestors(&txid);
+            package.retain(|ancestor| !included.contains(ancestor));
+            package.push(txid);
+            selected_size += best.size;
+
+            let mut changed = HashSet::new();
+            for picked in &package {
+                included.insert(*picked);
+            }
+            for picked in package {
+                let entry = &self.entries[&picked];
+                for descendant in self.descendants(&picked) {
+                    if !included.contains(&descendant) {
+                        packages.get_mut(&descendant).unwrap().remove(entry);
+                        changed.insert(descendant);
+                    }
+                }
+                selected.push(entry.tx.clone());
+            }
+            for descendant in changed {
+                if !skipped.contains(&descendant) {
+                    heap.push(packages[&descendant]);
+                }
+            }
+        }
+        selected
+    }
+
+    // The mempool transaction spending an outpoint
+    pub fn spender(&self, outpoint: &OutPoint) -> Option<TxId> {
+        self.spent.get(outpoint).copied()
+    }
+
+    pub fn get(&self, txid: &TxId) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.entries.values().map(|entry| &entry.tx)
+    }
+
+    pub fn contains(&self, txid: &TxId) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Encoded size of all the transactions, in bytes
+    pub fn total_size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn total_fees(&self) -> u64 {
+        self.entries.values().map(|entry| entry.fee.0).sum()
+    }
+
+    // Parents are written before their children, so they can be added back
+    // in order
+    pub fn save_to_disk(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut txs: Vec<&Transaction> = self.entries.values().map(|entry| &entry.tx).collect();
+        txs.sort_by_key(|tx| self.ancestors(&tx.id).len());
+        let data = bincode::encode_to_vec(txs, bincode::config::standard())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)
+    }
+
+    // Transactions are returned rather than inserted, since they have to be
+    // revalidated against the current UTXO set
+    pub fn load_from_disk(path: impl AsRef<Path>) -> io::Result<Vec<Transaction>> {
+        let data = fs::read(path)?;
+        let (txs, _) = bincode::decode_from_slice(&data, bincode::config::standard())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(txs)
+    }
+}
+
+// A transaction with its ancestors not yet picked, ordered so the best to
+// pick next is greatest: by fee rate, then fewer transactions, then id
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Package {
+    fee: u64,
+    size: usize,
+    count: usize,
+    txid: TxId,
+}
+
+impl Package {
+    fn add(&mut self, entry: &MempoolEntry) {
+        self.fee += entry.fee.0;
+        self.size += entry.size;
+        self.count += 1;
+    }
+
+    fn remove(&mut self, entry: &MempoolEntry) {
+        self.fee -= entry.fee.0;
+        self.size -= entry.size;
+        self.count -= 1;
+    }
+}
+
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rates((self.fee, self.size), (other.fee, other.size))
+            .then(other.count.cmp(&self.count))
+            .then(other.txid.cmp(&self.txid))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// An entry with its descendants, ordered so the first to evict is least:
+// by fee rate, then id
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Descendants {
+    fee: u64,
+    size: usize,
+    txid: TxId,
+}
+
+impl Descendants {
+    fn of(entry: &MempoolEntry) -> Descendants {
+        Descendants {
+            fee: entry.descendant_fee.0,
+            size: entry.descendant_size,
+            txid: entry.tx.id,
+        }
+    }
+}
+
+impl Ord for Descendants {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rates((self.fee, self.size), (other.fee, other.size))
+            .then(self.txid.cmp(&other.txid))
+    }
+}
+
+impl PartialOrd for Descendants {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Compares fee / size without dividing
+fn compare_fee_rates(a: (u64, usize), b: (u64, usize)) -> Ordering {
+    (a.0 as u128 * b.1 as u128).cmp(&(b.0 as u128 * a.1 as u128))
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::wallet::Wallet;
+
+    fn funded_utxos(wallet: &Wallet) -> UTXOSet {
+        let mut utxos = UTXOSet::new();
+        utxos.add_utxo(
+            OutPoint::new([1; 32], 0),
+            TxOutput::new(100_000, wallet.pkhash),
+        );
+        utxos.add_utxo(
+            OutPoint::new([2; 32], 0),
+            TxOutput::new(100_000, wallet.pkhash),
+        );
+        utxos
+    }
+
+    fn spend(wallet: &Wallet, txid: [u8; 32], value: u64) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(txid, 0).with_value(100_000)],
+            vec![TxOutput::new(value, [9; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_select_orders_by_fee() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let low_fee = spend(&wallet, [1; 32], 99_000);
+        let high_fee = spend(&wallet, [2; 32], 50_000);
+        let high_fee_id = high_fee.id;
+
+        mempool
+            .add_transaction(low_fee, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        mempool
+            .add_transaction(high_fee, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+
+        let selected = mempool.select_for_block(1);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].id, high_fee_id);
+        assert_eq!(mempool.get(&high_fee_id).unwrap().fee, 50_000);
+    }
+
+    #[test]
+    fn test_rejects_conflicting_spend() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let tx = spend(&wallet, [1; 32], 90_000);
+        let conflict = spend(&wallet, [1; 32], 80_000);
+        let txid = tx.id;
+
+        mempool
+            .add_transaction(tx, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert_eq!(
+            mempool.add_transaction(conflict, &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::MempoolConflict {
+                outpoint: OutPoint::new([1; 32], 0),
+                spent_by: txid,
+            })
+        );
+        assert_eq!(mempool.spender(&OutPoint::new([1; 32], 0)), Some(txid));
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_child_pays_for_parent() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let mut parent = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+            vec![TxOutput::new(99_700, wallet.pkhash)],
+        );
+        wallet.sign_transaction(&mut parent).unwrap();
+        let mut child = Transaction::new(
+            vec![TxInput::new_unsigned(parent.id, 0).with_value(99_700)],
+            vec![TxOutput::new(49_700, [9; 32])],
+        );
+        wallet.sign_transaction(&mut child).unwrap();
+        let other = spend(&wallet, [2; 32], 90_000);
+        let (parent_id, child_id, other_id) = (parent.id, child.id, other.id);
+
+        // the child can't come before its parent
+        assert_eq!(
+            mempool.add_transaction(child.clone(), &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::InvalidUTXO)
+        );
+        for tx in [parent, child, other] {
+            mempool
+                .add_transaction(tx, &utxos, &wallet.chain_id(), 1)
+                .unwrap();
+        }
+        assert_eq!(mempool.ancestors(&child_id), vec![parent_id]);
+        assert_eq!(mempool.descendants(&parent_id), vec![child_id]);
+
+        // the parent's fee of 300 is made up for by its child's
+        let ids = |txs: Vec<Transaction>| txs.iter().map(|tx| tx.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(mempool.select_for_block(3)),
+            vec![parent_id, child_id, other_id]
+        );
+        // a package that doesn't fit is skipped
+        assert_eq!(ids(mempool.select_for_block(1)), vec![other_id]);
+
+        assert_eq!(mempool.remove_with_descendants(&parent_id).len(), 2);
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_trim_to_size() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let mut parent = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+            vec![TxOutput::new(99_700, wallet.pkhash)],
+        );
+        wallet.sign_transaction(&mut parent).unwrap();
+        let mut child = Transaction::new(
+            vec![TxInput::new_unsigned(parent.id, 0).with_value(99_700)],
+            vec![TxOutput::new(49_700, [9; 32])],
+        );
+        wallet.sign_transaction(&mut child).unwrap();
+        let other = spend(&wallet, [2; 32], 90_000);
+        let (parent_id, child_id, other_id) = (parent.id, child.id, other.id);
+        for tx in [parent, child, other] {
+            mempool
+                .add_transaction(tx, &utxos, &wallet.chain_id(), 1)
+                .unwrap();
+        }
+        let size = mempool.total_size();
+        assert!(mempool.trim_to_size().is_empty());
+
+        // The parent pays the least, but its child makes up for it
+        mempool.set_max_size(size - 1);
+        assert_eq!(mempool.trim_to_size(), vec![other_id]);
+        let package_size =
+            mempool.get(&parent_id).unwrap().size + mempool.get(&child_id).unwrap().size;
+        assert_eq!(mempool.total_size(), package_size);
+        assert_eq!(
+            mempool.get(&parent_id).unwrap().descendant_size,
+            package_size
+        );
+
+        mempool.set_max_size(1);
+        assert_eq!(mempool.trim_to_size(), vec![parent_id, child_id]);
+        assert_eq!(mempool.total_size(), 0);
+    }
+
+    #[test]
+    fn test_trim_after_child_leaves() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let mut parent = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+            vec![TxOutput::new(99_700, wallet.pkhash)],
+        );
+        wallet.sign_transaction(&mut parent).unwrap();
+        let mut child = Transaction::new(
+            vec![TxInput::new_unsigned(parent.id, 0).with_value(99_700)],
+            vec![TxOutput::new(49_700, [9; 32])],
+        );
+        wallet.sign_transaction(&mut child).unwrap();
+        let other = spend(&wallet, [2; 32], 90_000);
+        let (parent_id, child_id, other_id) = (parent.id, child.id, other.id);
+        for tx in [parent, child, other] {
+            mempool
+                .add_transaction(tx, &utxos, &wallet.chain_id(), 1)
+                .unwrap();
+        }
+
+        // Without the child paying for it, the parent goes first
+        mempool.remove_transaction(&child_id);
+        let parent = mempool.get(&parent_id).unwrap();
+        assert_eq!(parent.descendant_fee, parent.fee);
+        assert_eq!(parent.descendant_size, parent.size);
+        mempool.set_max_size(mempool.total_size() - 1);
+        assert_eq!(mempool.trim_to_size(), vec![parent_id]);
+        assert!(mempool.contains(&other_id));
+    }
+
+    #[test]
+    fn test_expire() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+        mempool.set_max_age(100);
+
+        let old = spend(&wallet, [1; 32], 90_000);
+        let new = spend(&wallet, [2; 32], 90_000);
+        let (old_id, new_id) = (old.id, new.id);
+        let now = SystemClock.now();
+        mempool
+            .add_transaction_at(old, &utxos, &wallet.chain_id(), 1, now)
+            .unwrap();
+        mempool
+            .add_transaction_at(new, &utxos, &wallet.chain_id(), 1, now + 50)
+            .unwrap();
+
+        assert!(mempool.expire(now + 100).is_empty());
+        assert_eq!(mempool.expire(now + 101), vec![old_id]);
+        assert!(mempool.contains(&new_id));
+        assert_eq!(mempool.total_size(), mempool.get(&new_id).unwrap().size);
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+        mempool.set_incremental_relay_fee_rate(20_000);
+
+        let replaceable = |value| {
+            let mut tx = Transaction::new(
+                vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+                vec![TxOutput::new(value, [9; 32])],
+            );
+            tx.replaceable = true;
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+
+        // fee 10000, so a replacement must pay that plus 20 per byte of its
+        // own size, which is the same for all the values here
+        let increment = transaction::fee_for_size(replaceable(86_000).serialized_size(), 20_000).0;
+        let original = replaceable(90_000);
+        let original_id = original.id;
+        mempool
+            .add_transaction(original, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert_eq!(
+            mempool.add_transaction(
+                replaceable(90_000 - increment + 1),
+                &utxos,
+                &wallet.chain_id(),
+                1
+            ),
+            Err(TransactionError::ReplacementFeeTooLow)
+        );
+        assert!(mempool.contains(&original_id));
+
+        let replacement = replaceable(90_000 - increment);
+        let replacement_id = replacement.id;
+        mempool
+            .add_transaction(replacement, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert!(!mempool.contains(&original_id));
+        assert_eq!(
+            mempool.spender(&OutPoint::new([1; 32], 0)),
+            Some(replacement_id)
+        );
+        assert_eq!(mempool.total_fees(), 10_000 + increment);
+
+        // without the signal, even a much higher fee can't replace it
+        let final_tx = spend(&wallet, [2; 32], 90_000);
+        mempool
+            .add_transaction(final_tx, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert!(matches!(
+            mempool.add_transaction(
+                spend(&wallet, [2; 32], 1_000),
+                &utxos,
+                &wallet.chain_id(),
+                1
+            ),
+            Err(TransactionError::MempoolConflict { .. })
+        ));
+    }
+
+    #[test]
+    fn test_replacement_eviction_limit() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        // a replaceable parent with a child on each of its outputs
+        let mut parent = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+            vec![TxOutput::new(800, wallet.pkhash); MAX_REPLACED_TRANSACTIONS],
+        );
+        parent.replaceable = true;
+        wallet.sign_transaction(&mut parent).unwrap();
+        let parent_id = parent.id;
+        mempool
+            .add_transaction(parent.clone(), &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        let mut children = Vec::new();
+        for (index, output) in parent.outputs.iter().enumerate() {
+            let mut child = Transaction::new(
+                vec![TxInput::new_unsigned(parent_id, index as u16).with_value(output.value.0)],
+                vec![TxOutput::new(300, [9; 32])],
+            );
+            wallet.sign_transaction(&mut child).unwrap();
+            children.push(child.id);
+            mempool
+                .add_transaction(child, &utxos, &wallet.chain_id(), 1)
+                .unwrap();
+        }
+
+        let replacement = |value| {
+            let mut tx = Transaction::new(
+                vec![TxInput::new_unsigned([1; 32], 0).with_value(100_000)],
+                vec![TxOutput::new(value, [9; 32])],
+            );
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+        // it pays for everything, but would evict one transaction too many
+        assert_eq!(
+            mempool.add_transaction(replacement(1_000), &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::TooManyReplacements)
+        );
+        assert_eq!(mempool.len(), MAX_REPLACED_TRANSACTIONS + 1);
+
+        mempool.remove_with_descendants(&children[0]);
+        mempool
+            .add_transaction(replacement(1_001), &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_rejects_duplicate_and_invalid() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let tx = spend(&wallet, [1; 32], 90_000);
+        let duplicate = tx.clone();
+        mempool
+            .add_transaction(tx, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        assert_eq!(
+            mempool.add_transaction(duplicate, &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::AlreadyInMempool)
+        );
+
+        let overspend = spend(&wallet, [2; 32], 500_000);
+        assert_eq!(
+            mempool.add_transaction(overspend, &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::Overspend)
+        );
+
+        let missing = spend(&wallet, [3; 32], 10_000);
+        assert_eq!(
+            mempool.add_transaction(missing, &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::InvalidUTXO)
+        );
+    }
+
+    #[test]
+    fn test_relay_fee_rate_and_dust() {
+        let wallet = Wallet::new();
+        let utxos = funded_utxos(&wallet);
+        let mut mempool = Mempool::new();
+
+        let cheap = spend(&wallet, [1; 32], 99_990);
+        let min_fee =
+            transaction::fee_for_size(cheap.serialized_size(), DEFAULT_MIN_RELAY_FEE_RATE);
+        assert!(min_fee > 10);
+        assert_eq!(
+            mempool.add_transaction(cheap.clone(), &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::FeeTooLow)
+        );
+
+        let mut dust = Transaction::new(
+            vec![TxInput::new_unsigned([2; 32], 0).with_value(100_000)],
+            vec![TxOutput::new(50_000, [8; 32]), TxOutput::new(100, [9; 32])],
+        );
+        wallet.sign_transaction(&mut dust).unwrap();
+        assert_eq!(
+            mempool.add_transaction(dust, &utxos, &wallet.chain_id(), 1),
+            Err(TransactionError::Dust)
+        );
+
+        mempool.set_min_relay_fee_rate(0);
+        mempool
+            .add_transaction(cheap, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+
+        let paying = spend(&wallet, [2; 32], 50_000);
+        let paying_id = paying.id;
+        mempool
+            .add_transaction(paying, &utxos, &wallet.chain_id(), 1)
+            .unwrap();
+        let entry = mempool.get(&paying_id).unwrap();
+        assert_eq!(entry.size, entry.tx.serialized_size());
+        assert_eq!(entry.fee_rate(), 50_000 * 1000 / entry.size as u64);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::block::BlockHeader;
+use crate::consensus::{self, ProofOfWork, next_level};
+use crate::hash::TxId;
+use bincode::{Decode, Encode};
+
+pub use crate::consensus::merkle_root;
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct MerkleStep {
+    pub hash: [u8; 32],
+    pub is_left: bool,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct MerkleProof {
+    pub txid: [u8; 32],
+    pub steps: Vec<MerkleStep>,
+}
+
+impl MerkleProof {
+    pub fn build(txids: &[[u8; 32]], index: usize) -> Option<MerkleProof> {
+        let txid = *txids.get(index)?;
+
+        let mut steps = Vec::new();
+        let mut level = txids.to_vec();
+        let mut index = index;
+        while level.len() > 1 {
+            let sibling = index ^ 1;
+            if sibling < level.len() {
+                steps.push(MerkleStep {
+                    hash: level[sibling],
+                    is_left: sibling < index,
+                });
+            }
+            level = next_level(&level);
+            index /= 2;
+        }
+
+        Some(MerkleProof { txid, steps })
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        consensus::merkle_branch_root(
+            self.txid,
+            self.steps.iter().map(|step| (step.hash, step.is_left)),
+        )
+    }
+
+    pub fn as_bincode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    pub fn from_bincode(data: &[u8]) -> Option<MerkleProof> {
+        bincode::decode_from_slice(data, bincode::config::standard())
+            .ok()
+            .map(|(proof, _)| proof)
+    }
+}
+
+// Checks that the header carries valid proof of work, hashed with `pow`,
+// and that the proof links the transaction to its merkle root
+pub fn verify_proof(
+    header: &BlockHeader,
+    proof: &MerkleProof,
+    txid: &TxId,
+    pow: &impl ProofOfWork,
+) -> bool {
+    proof.txid == txid.0
+        && consensus::meets_target(&header.pow_hash(pow), &header.target())
+        && proof.root() == header.merkle_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txids(n: u8) -> Vec<[u8; 32]> {
+        (0..n).map(|i| [i; 32]).collect()
+    }
+
+    #[test]
+    fn test_proofs_match_root() {
+        for n in 1..=9 {
+            let txids = txids(n);
+            let root = merkle_root(&txids);
+            for index in 0..txids.len() {
+                let proof = MerkleProof::build(&txids, index).unwrap();
+                assert_eq!(proof.txid, txids[index]);
+                assert_eq!(proof.root(), root);
+            }
+        }
+    }
+
+    #[test]
+    fn test_odd_leaf_not_duplicated() {
+        let three = txids(3);
+        let mut four = three.clone();
+        four.push(three[2]);
+        assert_ne!(merkle_root(&three), merkle_root(&four));
+    }
+
+    #[test]
+    fn test_tampered_proof_fails() {
+        let txids = txids(5);
+        let root = merkle_root(&txids);
+        let mut proof = MerkleProof::build(&txids, 3).unwrap();
+        proof.steps[0].hash[0] ^= 1;
+        assert_ne!(proof.root(), root);
+        assert!(MerkleProof::build(&txids, 5).is_none());
+    }
+
+    #[test]
+    fn test_proof_roundtrip() {
+        let txids = txids(4);
+        let proof = MerkleProof::build(&txids, 2).unwrap();
+        assert_eq!(MerkleProof::from_bincode(&proof.as_bincode()), Some(proof));
+    }
+}
@@ -0,0 +1,306 @@
+use crate::block::{self, Block};
+use crate::clock::{Clock, SystemClock};
+use crate::consensus::{self, PowAlgorithm};
+use crate::log;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const TIMESTAMP_REFRESH_INTERVAL: u64 = 1 << 16;
+// Workers add their hashes to the stats in batches of this many
+const STATS_BATCH: u64 = 1 << 12;
+
+// What the miner has done since it first started: hashes tried, blocks
+// found, and the time spent mining, from which the hash rate follows.
+// Shared by the workers and whoever reports on them.
+#[derive(Default)]
+pub struct MiningStats {
+    attempts: AtomicU64,
+    blocks_found: AtomicU64,
+    // Time spent in earlier rounds, and when the current one started
+    elapsed: Mutex<(Duration, Option<Instant>)>,
+}
+
+impl MiningStats {
+    pub fn new() -> MiningStats {
+        MiningStats::default()
+    }
+
+    pub fn attempts(&self) -> u64 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    pub fn blocks_found(&self) -> u64 {
+        self.blocks_found.load(Ordering::Relaxed)
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.elapsed.lock().unwrap().1.is_some()
+    }
+
+    pub fn mining_time(&self) -> Duration {
+        let (elapsed, started) = *self.elapsed.lock().unwrap();
+        elapsed + started.map_or(Duration::ZERO, |started| started.elapsed())
+    }
+
+    // Hashes per second over all the time spent mining
+    pub fn hash_rate(&self) -> f64 {
+        let secs = self.mining_time().as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.attempts() as f64 / secs
+    }
+
+    // The expected time to find a block with `target` at the current hash
+    // rate, or None before any hash was counted
+    pub fn expected_time_to_block(&self, target: &[u8; 32]) -> Option<Duration> {
+        let rate = self.hash_rate();
+        if rate == 0.0 {
+            return None;
+        }
+        let work = block::target_work(target)
+            .iter_u64_digits()
+            .rev()
+            .fold(0.0, |work, digit| work * 2f64.powi(64) + digit as f64);
+        let secs = work / rate;
+        Some(Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX))
+    }
+
+    fn start(&self) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        elapsed.1.get_or_insert_with(Instant::now);
+    }
+
+    fn stop(&self) {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        if let Some(started) = elapsed.1.take() {
+            elapsed.0 += started.elapsed();
+        }
+    }
+
+    fn add_attempts(&self, attempts: u64) {
+        self.attempts.fetch_add(attempts, Ordering::Relaxed);
+    }
+}
+
+pub struct Miner {
+    threads: usize,
+    pow: PowAlgorithm,
+    stop: Arc<AtomicBool>,
+    stats: Arc<MiningStats>,
+    // Restamps the header as the workers go
+    clock: Arc<dyn Clock>,
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner::with_stats(threads, Arc::new(MiningStats::new()))
+    }
+
+    // Counting into `stats`, which may outlive the miner
+    pub fn with_stats(threads: usize, stats: Arc<MiningStats>) -> Miner {
+        Miner {
+            threads: threads.max(1),
+            pow: PowAlgorithm::Sha256,
+            stop: Arc::new(AtomicBool::new(false)),
+            stats,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    // Mining for a chain with another proof of work than SHA-256
+    pub fn with_pow(mut self, pow: PowAlgorithm) -> Miner {
+        self.pow = pow;
+        self
+    }
+
+    // Mining for a chain with its own clock
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Miner {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stats(&self) -> &Arc<MiningStats> {
+        &self.stats
+    }
+
+    // Adds the coinbase and starts mining the block
+    pub fn start(
+        &mut self,
+        mut block: Block,
+        miner_pkhash: [u8; 32],
+        block_reward: u64,
+    ) -> Receiver<Block> {
+        block.add_coinbase_tx(miner_pkhash, block_reward);
+        self.start_template(block)
+    }
+
+    // Starts searching for a valid nonce for a block that already has its
+    // coinbase, splitting the nonce space evenly between the worker threads.
+    // The solved block is sent on the returned channel; if mining is stopped
+    // first the channel is closed without a value.
+    pub fn start_template(&mut self, block: Block) -> Receiver<Block> {
+        self.stop();
+        self.stop = Arc::new(AtomicBool::new(false));
+        self.stats.start();
+
+        log::debug(
+            "miner",
+            "mining block",
+            &[
+                ("height", &block.header.index),
+                ("txs", &block.transactions.len()),
+                ("threads", &self.threads),
+            ],
+        );
+        let (sender, receiver) = mpsc::channel();
+        let range_size = u64::MAX / self.threads as u64;
+
+        for worker in 0..self.threads as u64 {
+            let mut block = block.clone();
+            let sender = sender.clone();
+            let stop = Arc::clone(&self.stop);
+            let stats = Arc::clone(&self.stats);
+            let pow = self.pow;
+            let clock = Arc::clone(&self.clock);
+            let start = worker * range_size;
+            let end = start + range_size;
+
+            thread::spawn(move || {
+                let header = &mut block.header;
+                header.nonce = start;
+                let target = header.target();
+
+                let mut counted = start;
+
+                while header.nonce < end && !stop.load(Ordering::Relaxed) {
+                    if consensus::meets_target(&header.pow_hash(&pow), &target) {
+                        stats.add_attempts(header.nonce + 1 - counted);
+                        if !stop.swap(true, Ordering::Relaxed) {
+                            stats.blocks_found.fetch_add(1, Ordering::Relaxed);
+                            stats.stop();
+                            log::info(
+                                "miner",
+                                "found block",
+                                &[
+                                    ("height", &header.index),
+                                    ("nonce", &header.nonce),
+                                    ("hashes", &(header.nonce - start + 1)),
+                                    ("worker", &worker),
+                                ],
+                            );
+                            block.digest = header.hash();
+                            let _ = sender.send(block);
+                        }
+                        return;
+                    }
+
+                    header.nonce += 1;
+                    if (header.nonce - start).is_multiple_of(STATS_BATCH) {
+                        stats.add_attempts(header.nonce - counted);
+                        counted = header.nonce;
+                    }
+                    if (header.nonce - start).is_multiple_of(TIMESTAMP_REFRESH_INTERVAL) {
+                        header.timestamp = clock.now();
+                    }
+                }
+                stats.add_attempts(header.nonce - counted);
+            });
+        }
+
+        receiver
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.stats.stop();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Miner {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{BlockAcceptance, Blockchain};
+    use crate::params::ChainParams;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mined_block_is_valid() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
+        let mut miner = Miner::new(4);
+
+        let block = blockchain.next_block();
+        let receiver = miner.start(block, [1; 32], blockchain.get_block_reward());
+        let block = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+
+        assert!(miner.is_stopped());
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+
+        let stats = miner.stats();
+        assert_eq!(stats.blocks_found(), 1);
+        assert!(stats.attempts() > 0);
+        assert!(!stats.is_mining());
+        assert!(stats.hash_rate() > 0.0);
+        assert!(
+            stats
+                .expected_time_to_block(&ChainParams::regtest().target())
+                .unwrap()
+                < stats
+                    .expected_time_to_block(&ChainParams::mainnet().target())
+                    .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_mining_with_other_pow() {
+        let params = ChainParams::mainnet().with_pow(PowAlgorithm::Blake3);
+        let mut blockchain = Blockchain::new(params);
+        let mut miner = Miner::new(2).with_pow(PowAlgorithm::Blake3);
+
+        let block = blockchain.next_block();
+        let receiver = miner.start(block, [1; 32], blockchain.get_block_reward());
+        let block = receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(block.digest, block.hash());
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+    }
+
+    #[test]
+    fn test_stats_before_mining() {
+        let stats = MiningStats::new();
+        assert_eq!(stats.hash_rate(), 0.0);
+        assert_eq!(stats.mining_time(), Duration::ZERO);
+        assert_eq!(stats.expected_time_to_block(&[0xff; 32]), None);
+    }
+
+    #[test]
+    fn test_stop_aborts_mining() {
+        let blockchain = Blockchain::new(ChainParams::mainnet());
+        let mut miner = Miner::new(2);
+
+        let mut block = blockchain.next_block();
+        block.header.bits = 0;
+        let receiver = miner.start(block, [1; 32], blockchain.get_block_reward());
+
+        miner.stop();
+        assert!(receiver.recv_timeout(Duration::from_secs(30)).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+// BIP39 mnemonic phrases. The phrase encodes the entropy plus a short SHA-256
+// checksum, 11 bits per word, and is stretched into a 64-byte seed with
+// PBKDF2-HMAC-SHA512. Phrase and passphrase are NFKD normalized first, so
+// accented words and passphrases give the same seed however they were typed.
+//
+// English is built in. The other BIP39 languages are used by parsing their
+// published wordlist with Wordlist::parse.
+
+use crate::errors::MnemonicError;
+use crate::nfkd::nfkd;
+use crate::utils;
+use hmac::Hmac;
+use rand_core::RngCore;
+use sha2::{Digest, Sha256, Sha512};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+const SEED_ITERATIONS: u32 = 2048;
+const WORDLIST_LEN: usize = 2048;
+
+static ENGLISH: LazyLock<Wordlist> =
+    LazyLock::new(|| Wordlist::parse(include_str!("wordlists/english.txt")).unwrap());
+
+#[derive(Clone)]
+pub struct Wordlist {
+    words: Vec<String>,
+    indices: HashMap<String, usize>,
+}
+
+impl Wordlist {
+    pub fn english() -> &'static Wordlist {
+        &ENGLISH
+    }
+
+    // One word per line, in the order of the BIP39 list. Lists that aren't
+    // 2048 distinct words once normalized are rejected.
+    pub fn parse(text: &str) -> Result<Wordlist, MnemonicError> {
+        let words: Vec<String> = text
+            .lines()
+            .map(|line| normalize_word(line.trim()))
+            .filter(|word| !word.is_empty())
+            .collect();
+        let indices: HashMap<String, usize> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.clone(), i))
+            .collect();
+        if words.len() != WORDLIST_LEN || indices.len() != WORDLIST_LEN {
+            return Err(MnemonicError::InvalidWordlist);
+        }
+        Ok(Wordlist { words, indices })
+    }
+
+    fn word(&self, index: usize) -> &str {
+        &self.words[index]
+    }
+
+    fn index(&self, word: &str) -> Option<usize> {
+        self.indices.get(word).copied()
+    }
+}
+
+fn normalize_word(word: &str) -> String {
+    nfkd(word).to_lowercase()
+}
+
+fn normalize_phrase(phrase: &str) -> Vec<String> {
+    nfkd(phrase)
+        .split_whitespace()
+        .map(normalize_word)
+        .collect()
+}
+
+// Word counts allowed by BIP39, 12 words for 128 bits up to 24 for 256 bits
+fn entropy_len(word_count: usize) -> Option<usize> {
+    match word_count {
+        12 | 15 | 18 | 21 | 24 => Some(word_count * 11 * 32 / 33 / 8),
+        _ => None,
+    }
+}
+
+pub fn generate(word_count: usize) -> Result<String, MnemonicError> {
+    generate_in(word_count, Wordlist::english())
+}
+
+pub fn generate_in(word_count: usize, wordlist: &Wordlist) -> Result<String, MnemonicError> {
+    let len = entropy_len(word_count).ok_or(MnemonicError::InvalidWordCount)?;
+    let mut entropy = vec![0u8; len];
+    rand_core::OsRng.fill_bytes(&mut entropy);
+    from_entropy_in(&entropy, wordlist)
+}
+
+pub fn from_entropy(entropy: &[u8]) -> Result<String, MnemonicError> {
+    from_entropy_in(entropy, Wordlist::english())
+}
+
+pub fn from_entropy_in(entropy: &[u8], wordlist: &Wordlist) -> Result<String, MnemonicError> {
+    if !entropy.len().is_multiple_of(4) || !(16..=32).contains(&entropy.len()) {
+        return Err(MnemonicError::InvalidWordCount);
+    }
+
+    let checksum = Sha256::digest(entropy);
+    let mut bits = entropy.to_vec();
+    bits.push(checksum[0]);
+
+    let word_count = entropy.len() * 8 / 32 * 3;
+    let words: Vec<&str> = (0..word_count)
+        .map(|i| {
+            let index = (0..11).fold(0usize, |acc, bit| {
+                let pos = i * 11 + bit;
+                (acc << 1) | ((bits[pos / 8] >> (7 - pos % 8)) & 1) as usize
+            });
+            wordlist.word(index)
+        })
+        .collect();
+
+    Ok(words.join(" "))
+}
+
+// Returns the entropy encoded by the phrase after checking every word and the
+// checksum. Words are matched case-insensitively.
+pub fn to_entropy(phrase: &str) -> Result<Vec<u8>, MnemonicError> {
+    to_entropy_in(phrase, Wordlist::english())
+}
+
+pub fn to_entropy_in(phrase: &str, wordlist: &Wordlist) -> Result<Vec<u8>, MnemonicError> {
+    let words = normalize_phrase(phrase);
+    let len = entropy_len(words.len()).ok_or(MnemonicError::InvalidWordCount)?;
+
+    let mut bits = vec![0u8; len + 1];
+    for (i, word) in words.iter().enumerate() {
+        let index = wordlist
+            .index(word)
+            .ok_or_else(|| MnemonicError::UnknownWord(word.clone()))?;
+        for bit in 0..11 {
+            if index >> (10 - bit) & 1 == 1 {
+                let pos = i * 11 + bit;
+                bits[pos / 8] |= 0x80 >> (pos % 8);
+            }
+        }
+    }
+
+    let checksum_bits = len / 4;
+    let checksum = bits.pop().unwrap();
+    let mask = 0xffu8 << (8 - checksum_bits);
+    if checksum & mask != Sha256::digest(&bits)[0] & mask {
+        return Err(MnemonicError::InvalidChecksum);
+    }
+    Ok(bits)
+}
+
+pub fn to_seed(phrase: &str, passphrase: &str) -> Result<[u8; 64], MnemonicError> {
+    to_seed_in(phrase, passphrase, Wordlist::english())
+}
+
+// The passphrase is normalized but, unlike the words, is case-sensitive
+pub fn to_seed_in(
+    phrase: &str,
+    passphrase: &str,
+    wordlist: &Wordlist,
+) -> Result<[u8; 64], MnemonicError> {
+    to_entropy_in(phrase, wordlist)?;
+
+    let normalized = normalize_phrase(phrase).join(" ");
+    let mut seed = [0u8; 64];
+    utils::pbkdf2::<Hmac<Sha512>>(
+        normalized.as_bytes(),
+        format!("mnemonic{}", nfkd(passphrase)).as_bytes(),
+        SEED_ITERATIONS,
+        &mut seed,
+    );
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the BIP39 reference test vectors
+    const VECTORS: [(&str, &str); 3] = [
+        (
+            "00000000000000000000000000000000",
+            "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about",
+        ),
+        (
+            "7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f7f",
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+        ),
+        (
+            "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo vote",
+        ),
+    ];
+
+    #[test]
+    fn test_wordlist() {
+        let english = Wordlist::english();
+        assert_eq!(english.words.len(), 2048);
+        assert!(english.words.is_sorted());
+    }
+
+    #[test]
+    fn test_custom_wordlist() {
+        // Accented words, as in the Spanish and French lists, are stored
+        // decomposed and match however they are typed
+        let text: String = (0..2048).map(|i| format!("caf\u{e9}{i}\n")).collect();
+        let wordlist = Wordlist::parse(&text).unwrap();
+        let entropy = [0x5a; 16];
+        let phrase = from_entropy_in(&entropy, &wordlist).unwrap();
+        assert_eq!(to_entropy_in(&phrase, &wordlist).unwrap(), entropy);
+        let composed = phrase.replace("cafe\u{301}", "CAF\u{c9}");
+        assert_eq!(to_entropy_in(&composed, &wordlist).unwrap(), entropy);
+        assert_eq!(
+            to_seed_in(&composed, "", &wordlist),
+            to_seed_in(&phrase, "", &wordlist)
+        );
+        assert!(matches!(
+            to_entropy(&phrase),
+            Err(MnemonicError::UnknownWord(_))
+        ));
+
+        assert!(matches!(
+            Wordlist::parse("abandon\nability\n"),
+            Err(MnemonicError::InvalidWordlist)
+        ));
+        let duplicated = text.replace("caf\u{e9}1\n", "cafe\u{301}0\n");
+        assert!(matches!(
+            Wordlist::parse(&duplicated),
+            Err(MnemonicError::InvalidWordlist)
+        ));
+    }
+
+    #[test]
+    fn test_passphrase_is_normalized() {
+        let phrase = VECTORS[1].1;
+        assert_eq!(
+            to_seed(phrase, "caf\u{e9}").unwrap(),
+            to_seed(phrase, "cafe\u{301}").unwrap()
+        );
+        assert_ne!(
+            to_seed(phrase, "caf\u{e9}").unwrap(),
+            to_seed(phrase, "CAF\u{c9}").unwrap()
+        );
+        assert_ne!(
+            to_seed(phrase, "").unwrap(),
+            to_seed(phrase, "caf\u{e9}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_entropy_vectors() {
+        for (entropy, phrase) in VECTORS {
+            let entropy = hex::decode(entropy).unwrap();
+            assert_eq!(from_entropy(&entropy).unwrap(), phrase);
+            assert_eq!(to_entropy(phrase).unwrap(), entropy);
+        }
+    }
+
+    #[test]
+    fn test_seed_vector() {
+        let seed = to_seed(VECTORS[0].1, "TREZOR").unwrap();
+        assert_eq!(
+            hex::encode(seed),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+    }
+
+    #[test]
+    fn test_generate() {
+        for word_count in [12, 24] {
+            let phrase = generate(word_count).unwrap();
+            assert_eq!(phrase.split(' ').count(), word_count);
+            assert!(to_entropy(&phrase).is_ok());
+        }
+        assert_eq!(generate(13), Err(MnemonicError::InvalidWordCount));
+    }
+
+    #[test]
+    fn test_invalid_phrases_rejected() {
+        let phrase = VECTORS[0].1;
+        assert_eq!(
+            to_entropy(&phrase.replace("about", "abandon")),
+            Err(MnemonicError::InvalidChecksum)
+        );
+        assert_eq!(
+            to_entropy(&phrase.replace("about", "blockchain")),
+            Err(MnemonicError::UnknownWord("blockchain".to_string()))
+        );
+        assert_eq!(
+            to_entropy("abandon about"),
+            Err(MnemonicError::InvalidWordCount)
+        );
+        assert!(to_entropy(&phrase.to_uppercase()).is_ok());
+    }
+}
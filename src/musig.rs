@@ -0,0 +1,382 @@
+// MuSig2 (BIP327, without tweaks) for Schnorr keys: n signers combine their
+// public keys into one aggregate key, and together produce a single BIP340
+// signature for it. On chain an n-of-n multisig then looks like any other
+// Schnorr input, one 32 byte key and one signature, and verifies with
+// SignatureScheme::Schnorr.
+//
+// Signing takes two rounds:
+//
+//   1. each signer calls generate_nonce and shares the PublicNonce
+//   2. with all the nonces, each signer creates a Session for the message
+//      and shares Session::sign's partial signature
+//
+// Session::aggregate then adds the partial signatures up. A SecretNonce is
+// consumed by signing, as signing two messages with one nonce gives away
+// the key.
+//
+// Keys are x-only, like BIP340's, where BIP327 uses compressed points.
+
+use crate::errors::MusigError;
+use crate::signature::PrivateKey;
+use k256::elliptic_curve::Field;
+use k256::elliptic_curve::ops::Reduce;
+use k256::elliptic_curve::point::AffineCoordinates;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::{AffinePoint, ProjectivePoint, PublicKey, Scalar, U256, schnorr};
+use sha2::{Digest, Sha256};
+
+pub type PartialSignature = [u8; 32];
+
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag);
+    hasher.update(tag);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn hash_to_scalar(tag: &str, parts: &[&[u8]]) -> Scalar {
+    <Scalar as Reduce<U256>>::reduce_bytes(&tagged_hash(tag, parts).into())
+}
+
+fn x_only(point: &AffinePoint) -> [u8; 32] {
+    point.x().into()
+}
+
+fn has_odd_y(point: &AffinePoint) -> bool {
+    point.y_is_odd().into()
+}
+
+// Negates `scalar` when `negate` is set
+fn conditional_negate(scalar: Scalar, negate: bool) -> Scalar {
+    if negate { -scalar } else { scalar }
+}
+
+pub struct KeyAggContext {
+    pubkeys: Vec<[u8; 32]>,
+    points: Vec<ProjectivePoint>,
+    coefficients: Vec<Scalar>,
+    // The summed point has an odd y, so every key counts negated
+    negated: bool,
+    aggregate_key: [u8; 32],
+}
+
+impl KeyAggContext {
+    // The order of `pubkeys` matters: every signer must use the same one
+    pub fn new(pubkeys: &[Vec<u8>]) -> Result<KeyAggContext, MusigError> {
+        if pubkeys.is_empty() {
+            return Err(MusigError::NoKeys);
+        }
+        let mut xs = Vec::with_capacity(pubkeys.len());
+        let mut points = Vec::with_capacity(pubkeys.len());
+        for (index, pubkey) in pubkeys.iter().enumerate() {
+            let invalid = MusigError::InvalidPublicKey(index);
+            if pubkey.len() != 32 {
+                return Err(invalid);
+            }
+            let key = schnorr::VerifyingKey::from_bytes(pubkey).map_err(|_| invalid)?;
+            xs.push(key.to_bytes().into());
+            points.push(ProjectivePoint::from(*key.as_affine()));
+        }
+
+        let list: Vec<u8> = xs.concat();
+        let list_hash = tagged_hash("KeyAgg list", &[&list]);
+        let coefficients: Vec<Scalar> = xs
+            .iter()
+            .map(|x: &[u8; 32]| hash_to_scalar("KeyAgg coefficient", &[&list_hash, x]))
+            .collect();
+
+        let sum: ProjectivePoint = points
+            .iter()
+            .zip(&coefficients)
+            .map(|(point, coefficient)| *point * coefficient)
+            .sum();
+        let sum = PublicKey::from_affine(sum.to_affine()).map_err(|_| MusigError::NoKeys)?;
+        let sum = sum.as_affine();
+
+        Ok(KeyAggContext {
+            pubkeys: xs,
+            points,
+            coefficients,
+            negated: has_odd_y(sum),
+            aggregate_key: x_only(sum),
+        })
+    }
+
+    // The key the signers' combined signatures verify against
+    pub fn aggregate_key(&self) -> [u8; 32] {
+        self.aggregate_key
+    }
+
+    pub fn pkhash(&self) -> [u8; 32] {
+        Sha256::digest(self.aggregate_key).into()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pubkeys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty()
+    }
+
+    fn signer_index(&self, pubkey: &[u8]) -> Option<usize> {
+        self.pubkeys.iter().position(|key| key[..] == *pubkey)
+    }
+}
+
+// Two secret scalars, of which the PublicNonce holds the points. Not Clone,
+// so it can only be used once.
+pub struct SecretNonce([Scalar; 2]);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicNonce(pub [u8; 66]);
+
+impl PublicNonce {
+    fn points(&self) -> Option<[ProjectivePoint; 2]> {
+        let first = PublicKey::from_sec1_bytes(&self.0[..33]).ok()?;
+        let second = PublicKey::from_sec1_bytes(&self.0[33..]).ok()?;
+        Some([first.to_projective(), second.to_projective()])
+    }
+}
+
+pub fn generate_nonce() -> (SecretNonce, PublicNonce) {
+    let secret = [
+        Scalar::random(&mut rand_core::OsRng),
+        Scalar::random(&mut rand_core::OsRng),
+    ];
+    let mut public = [0u8; 66];
+    for (i, k) in secret.iter().enumerate() {
+        let point = (ProjectivePoint::GENERATOR * k).to_affine();
+        public[i * 33..(i + 1) * 33].copy_from_slice(point.to_encoded_point(true).as_bytes());
+    }
+    (SecretNonce(secret), PublicNonce(public))
+}
+
+// Signing one message with the nonces of every signer, in the order of the
+// keys in the KeyAggContext
+pub struct Session {
+    nonces: Vec<[ProjectivePoint; 2]>,
+    // Weight of the second nonces
+    b: Scalar,
+    // The challenge
+    e: Scalar,
+    r: [u8; 32],
+    // The combined nonce has an odd y, so every nonce counts negated
+    nonce_negated: bool,
+}
+
+impl Session {
+    pub fn new(
+        context: &KeyAggContext,
+        nonces: &[PublicNonce],
+        message: &[u8; 32],
+    ) -> Result<Session, MusigError> {
+        if nonces.len() != context.len() {
+            return Err(MusigError::WrongNonceCount);
+        }
+        let nonces = nonces
+            .iter()
+            .enumerate()
+            .map(|(index, nonce)| nonce.points().ok_or(MusigError::InvalidNonce(index)))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let r1: ProjectivePoint = nonces.iter().map(|n| n[0]).sum();
+        let r2: ProjectivePoint = nonces.iter().map(|n| n[1]).sum();
+        let mut aggregate_nonce = Vec::with_capacity(66);
+        for point in [r1, r2] {
+            aggregate_nonce.extend_from_slice(point.to_affine().to_encoded_point(true).as_bytes());
+        }
+        let b = hash_to_scalar(
+            "MuSig/noncecoef",
+            &[&aggregate_nonce, &context.aggregate_key, message],
+        );
+        // BIP327 falls back to the generator in the negligible case the
+        // nonces cancel out
+        let mut r = (r1 + r2 * b).to_affine();
+        if r == AffinePoint::IDENTITY {
+            r = AffinePoint::GENERATOR;
+        }
+        let e = hash_to_scalar(
+            "BIP0340/challenge",
+            &[&x_only(&r), &context.aggregate_key, message],
+        );
+
+        Ok(Session {
+            nonces,
+            b,
+            e,
+            r: x_only(&r),
+            nonce_negated: has_odd_y(&r),
+        })
+    }
+
+    // This signer's share of the signature. `key` must be the Schnorr key
+    // of one of the context's public keys.
+    pub fn sign(
+        &self,
+        context: &KeyAggContext,
+        nonce: SecretNonce,
+        key: &PrivateKey,
+    ) -> Result<PartialSignature, MusigError> {
+        let PrivateKey::Schnorr(key) = key else {
+            return Err(MusigError::UnknownSigner);
+        };
+        let index = context
+            .signer_index(&key.verifying_key().to_bytes())
+            .ok_or(MusigError::UnknownSigner)?;
+        let public = (ProjectivePoint::GENERATOR * nonce.0[0]).to_affine();
+        if self.nonces[index][0].to_affine() != public {
+            return Err(MusigError::NonceMismatch);
+        }
+
+        // The signing key is already negated as needed for its x-only key
+        let d = conditional_negate(**key.as_nonzero_scalar(), context.negated);
+        let k1 = conditional_negate(nonce.0[0], self.nonce_negated);
+        let k2 = conditional_negate(nonce.0[1], self.nonce_negated);
+        let s = k1 + self.b * k2 + self.e * context.coefficients[index] * d;
+        Ok(s.to_bytes().into())
+    }
+
+    // Checks signer `index`'s partial signature, so a wrong one can be
+    // blamed on its signer rather than spoiling the aggregate
+    pub fn verify_partial(
+        &self,
+        context: &KeyAggContext,
+        index: usize,
+        partial: &PartialSignature,
+    ) -> Result<(), MusigError> {
+        let invalid = MusigError::InvalidPartialSignature(index);
+        let s = parse_scalar(partial).ok_or(invalid.clone())?;
+        let nonce = self.nonces.get(index).ok_or(invalid.clone())?;
+        let mut r = nonce[0] + nonce[1] * self.b;
+        if self.nonce_negated {
+            r = -r;
+        }
+        let mut key = context.points[index] * (self.e * context.coefficients[index]);
+        if context.negated {
+            key = -key;
+        }
+        if ProjectivePoint::GENERATOR * s == r + key {
+            Ok(())
+        } else {
+            Err(invalid)
+        }
+    }
+
+    // Adds up everyone's partial signatures into a BIP340 signature for the
+    // aggregate key
+    pub fn aggregate(&self, partials: &[PartialSignature]) -> Result<[u8; 64], MusigError> {
+        if partials.len() != self.nonces.len() {
+            return Err(MusigError::WrongSignatureCount);
+        }
+        let mut s = Scalar::ZERO;
+        for (index, partial) in partials.iter().enumerate() {
+            s += parse_scalar(partial).ok_or(MusigError::InvalidPartialSignature(index))?;
+        }
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&self.r);
+        signature[32..].copy_from_slice(&s.to_bytes());
+        Ok(signature)
+    }
+}
+
+// Scalars at or above the group order are rejected rather than reduced
+fn parse_scalar(bytes: &[u8; 32]) -> Option<Scalar> {
+    use k256::elliptic_curve::PrimeField;
+    Option::from(Scalar::from_repr((*bytes).into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::SignatureScheme;
+
+    fn sign_together(keys: &[PrivateKey], message: &[u8; 32]) -> (KeyAggContext, [u8; 64]) {
+        let pubkeys: Vec<Vec<u8>> = keys.iter().map(PrivateKey::public_key).collect();
+        let context = KeyAggContext::new(&pubkeys).unwrap();
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+            keys.iter().map(|_| generate_nonce()).unzip();
+        let session = Session::new(&context, &public_nonces, message).unwrap();
+        let partials: Vec<PartialSignature> = secret_nonces
+            .into_iter()
+            .zip(keys)
+            .map(|(nonce, key)| session.sign(&context, nonce, key).unwrap())
+            .collect();
+        for (index, partial) in partials.iter().enumerate() {
+            assert_eq!(session.verify_partial(&context, index, partial), Ok(()));
+        }
+        let signature = session.aggregate(&partials).unwrap();
+        (context, signature)
+    }
+
+    #[test]
+    fn test_aggregate_signature_verifies() {
+        for signers in [1, 2, 5] {
+            let keys: Vec<PrivateKey> = (0..signers)
+                .map(|_| PrivateKey::random(SignatureScheme::Schnorr))
+                .collect();
+            let (context, signature) = sign_together(&keys, &[7; 32]);
+            let key = context.aggregate_key();
+            assert_eq!(
+                SignatureScheme::Schnorr.verify(&key, &[7; 32], &signature),
+                Ok(())
+            );
+            assert!(
+                SignatureScheme::Schnorr
+                    .verify(&key, &[8; 32], &signature)
+                    .is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_key_order_matters() {
+        let keys: Vec<Vec<u8>> = (0..2)
+            .map(|_| PrivateKey::random(SignatureScheme::Schnorr).public_key())
+            .collect();
+        let reversed: Vec<Vec<u8>> = keys.iter().rev().cloned().collect();
+        assert_ne!(
+            KeyAggContext::new(&keys).unwrap().aggregate_key(),
+            KeyAggContext::new(&reversed).unwrap().aggregate_key()
+        );
+    }
+
+    #[test]
+    fn test_bad_partial_signature_blamed() {
+        let keys: Vec<PrivateKey> = (0..3)
+            .map(|_| PrivateKey::random(SignatureScheme::Schnorr))
+            .collect();
+        let pubkeys: Vec<Vec<u8>> = keys.iter().map(PrivateKey::public_key).collect();
+        let context = KeyAggContext::new(&pubkeys).unwrap();
+        let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+            keys.iter().map(|_| generate_nonce()).unzip();
+        let session = Session::new(&context, &public_nonces, &[1; 32]).unwrap();
+
+        let mut nonces = secret_nonces.into_iter();
+        let first = session
+            .sign(&context, nonces.next().unwrap(), &keys[0])
+            .unwrap();
+        // signing with another signer's nonce
+        assert_eq!(
+            session.sign(&context, nonces.next().unwrap(), &keys[2]),
+            Err(MusigError::NonceMismatch)
+        );
+        let outsider = PrivateKey::random(SignatureScheme::Schnorr);
+        assert_eq!(
+            session.sign(&context, nonces.next().unwrap(), &outsider),
+            Err(MusigError::UnknownSigner)
+        );
+        assert_eq!(
+            session.verify_partial(&context, 1, &first),
+            Err(MusigError::InvalidPartialSignature(1))
+        );
+        assert_eq!(
+            Session::new(&context, &public_nonces[..2], &[1; 32]).err(),
+            Some(MusigError::WrongNonceCount)
+        );
+    }
+}
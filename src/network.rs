@@ -0,0 +1,1174 @@
+use crate::addr_manager::{AddrManager, MAX_ADDRS_PER_MESSAGE};
+use crate::block::{Block, BlockHeader};
+use crate::block_meta::{BlockMeta, BlockMetaStore};
+use crate::blockchain::{BlockAcceptance, Blockchain};
+use crate::compact::{CompactBlock, PartialBlock};
+use crate::errors::{BlockValidationError, MessageError, TransactionError};
+use crate::hash::{Hash256, TxId};
+use crate::invoice::InvoiceBook;
+use crate::log;
+use crate::miner::MiningStats;
+use crate::params::Network;
+use crate::peer_manager::{Direction, Misbehavior, PeerManager};
+use crate::rate_limit::RateLimiter;
+pub use crate::sync::SyncState;
+use crate::sync::{HeadersOutcome, MAX_HEADERS, SyncManager};
+use crate::transaction::Transaction;
+use crate::utils;
+use crate::wallet_manager::WalletManager;
+use bincode::{Decode, Encode};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// Peers older than this are disconnected
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+// Service flags: serves the full chain
+pub const SERVICE_NETWORK: u64 = 1 << 0;
+// Understands compact block announcements
+pub const SERVICE_COMPACT_BLOCKS: u64 = 1 << 1;
+pub const DEFAULT_SERVICES: u64 = SERVICE_NETWORK | SERVICE_COMPACT_BLOCKS;
+// Per-message limits, checked as soon as a message is decoded
+pub const MAX_BLOCK_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+pub const MAX_TX_MESSAGE_SIZE: usize = 1024 * 1024;
+pub const MAX_SMALL_MESSAGE_SIZE: usize = 256 * 1024;
+// The largest of those, so a frame no message may fill is dropped before
+// it is read
+const MAX_MESSAGE_SIZE: usize = MAX_BLOCK_MESSAGE_SIZE;
+pub const MAX_BLOCK_TRANSACTIONS: usize = 20_000;
+pub const MAX_TX_INPUTS: usize = 5_000;
+pub const MAX_TX_OUTPUTS: usize = 5_000;
+pub const MAX_LOCATOR_SIZE: usize = 101;
+// Compact blocks waiting for their missing transactions
+const MAX_PENDING_BLOCKS: usize = 16;
+// A peer that hasn't answered a request for this long is stalling
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Encode, Decode, Clone)]
+pub enum Message {
+    Handshake {
+        height: u64,
+    },
+    Block(Block),
+    Transaction(Transaction),
+    GetBlockByIndex(u64),
+    GetBlockByHash(Hash256),
+    // New blocks are announced compactly, and the receiver asks for the
+    // transactions missing from its mempool by position in the block
+    CompactBlock(CompactBlock),
+    GetBlockTransactions {
+        block: Hash256,
+        indexes: Vec<u32>,
+    },
+    BlockTransactions {
+        block: Hash256,
+        transactions: Vec<Transaction>,
+    },
+    // Asks for addresses of other peers, answered with Addr
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    // Asks for the headers after the first hash of the locator on the
+    // peer's chain, answered with Headers
+    GetHeaders(Vec<Hash256>),
+    Headers(Vec<BlockHeader>),
+}
+
+impl Message {
+    // Largest encoding accepted for this kind of message
+    pub fn max_size(&self) -> usize {
+        match self {
+            Message::Block(_) | Message::BlockTransactions { .. } | Message::Headers(_) => {
+                MAX_BLOCK_MESSAGE_SIZE
+            }
+            Message::Transaction(_) => MAX_TX_MESSAGE_SIZE,
+            _ => MAX_SMALL_MESSAGE_SIZE,
+        }
+    }
+
+    // Checks sizes and counts, which are cheap, so that oversized messages
+    // are rejected before anything in them is validated. `size` is the
+    // length of the encoding.
+    pub fn check_limits(&self, size: usize) -> Result<(), MessageError> {
+        let max = self.max_size();
+        if size > max {
+            return Err(MessageError::TooLarge { size, max });
+        }
+        match self {
+            Message::Block(block) => check_transactions(&block.transactions),
+            Message::BlockTransactions { transactions, .. } => check_transactions(transactions),
+            Message::Transaction(tx) => check_transaction(tx),
+            Message::CompactBlock(compact) => {
+                if compact.short_ids.len() > MAX_BLOCK_TRANSACTIONS {
+                    return Err(MessageError::TooManyTransactions);
+                }
+                check_transaction(&compact.coinbase)
+            }
+            Message::GetBlockTransactions { indexes, .. } => {
+                check_count(indexes.len(), MAX_BLOCK_TRANSACTIONS)
+            }
+            Message::Addr(addrs) => check_count(addrs.len(), MAX_ADDRS_PER_MESSAGE),
+            Message::GetHeaders(locator) => check_count(locator.len(), MAX_LOCATOR_SIZE),
+            Message::Headers(headers) => check_count(headers.len(), MAX_HEADERS),
+            Message::Handshake { .. }
+            | Message::GetBlockByIndex(_)
+            | Message::GetBlockByHash(_)
+            | Message::GetAddr => Ok(()),
+        }
+    }
+}
+
+fn check_transactions(transactions: &[Transaction]) -> Result<(), MessageError> {
+    if transactions.len() > MAX_BLOCK_TRANSACTIONS {
+        return Err(MessageError::TooManyTransactions);
+    }
+    transactions.iter().try_for_each(check_transaction)
+}
+
+fn check_transaction(tx: &Transaction) -> Result<(), MessageError> {
+    if tx.inputs.len() > MAX_TX_INPUTS {
+        return Err(MessageError::TooManyInputs);
+    }
+    if tx.outputs.len() > MAX_TX_OUTPUTS {
+        return Err(MessageError::TooManyOutputs);
+    }
+    Ok(())
+}
+
+fn check_count(count: usize, max: usize) -> Result<(), MessageError> {
+    if count > max {
+        return Err(MessageError::TooManyItems);
+    }
+    Ok(())
+}
+
+// What the sender says about itself in front of every message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub magic: [u8; 4],
+    pub version: u32,
+    pub services: u64,
+}
+
+impl Envelope {
+    pub fn new(network: Network) -> Envelope {
+        Envelope {
+            magic: network.magic(),
+            version: PROTOCOL_VERSION,
+            services: DEFAULT_SERVICES,
+        }
+    }
+}
+
+const HEADER_SIZE: usize = 24;
+
+// Messages are framed as the network magic, then as big-endian integers the
+// protocol version (u32), the service flags (u64) and the payload length
+// (u32), then the first 4 bytes of the payload's SHA-256 and the payload,
+// the bincode encoding of the message. Returns the size of the frame.
+pub fn write_message<W: Write>(
+    writer: &mut W,
+    envelope: &Envelope,
+    message: &Message,
+) -> io::Result<usize> {
+    let data = bincode::encode_to_vec(message, bincode::config::standard())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut frame = Vec::with_capacity(HEADER_SIZE + data.len());
+    frame.extend_from_slice(&envelope.magic);
+    frame.extend_from_slice(&envelope.version.to_be_bytes());
+    frame.extend_from_slice(&envelope.services.to_be_bytes());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&Sha256::digest(&data)[..4]);
+    frame.extend_from_slice(&data);
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(frame.len())
+}
+
+// Messages for another network or from an incompatible version fail with
+// Unsupported, corrupted ones and those over their limits with InvalidData
+pub fn read_message<R: Read>(reader: &mut R, magic: [u8; 4]) -> io::Result<(Envelope, Message)> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    let envelope = Envelope {
+        magic: header[..4].try_into().unwrap(),
+        version: u32::from_be_bytes(header[4..8].try_into().unwrap()),
+        services: u64::from_be_bytes(header[8..16].try_into().unwrap()),
+    };
+    if envelope.magic != magic {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "message for another network",
+        ));
+    }
+    if envelope.version < MIN_PROTOCOL_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "incompatible protocol version",
+        ));
+    }
+    let len = u32::from_be_bytes(header[16..20].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message too large",
+        ));
+    }
+
+    // Grows as the data arrives, rather than at the length the peer claims
+    let mut data = Vec::new();
+    reader.take(len as u64).read_to_end(&mut data)?;
+    if data.len() < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if Sha256::digest(&data)[..4] != header[20..] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "message checksum mismatch",
+        ));
+    }
+    // Decoded messages take more memory than their encoding, but not this
+    // much more
+    let message: Message = utils::decode_with_limit::<_, { 4 * MAX_MESSAGE_SIZE }>(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    message
+        .check_limits(len)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((envelope, message))
+}
+
+// Counts the bytes read through it
+struct CountingReader<'a, R> {
+    inner: &'a mut R,
+    count: usize,
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read;
+        Ok(read)
+    }
+}
+
+struct Peer {
+    stream: TcpStream,
+    // The same socket, locked while a message is written to it so messages
+    // sent from different threads don't interleave
+    writer: Arc<Mutex<TcpStream>>,
+    height: u64,
+    // Service flags from its latest message
+    services: u64,
+    // When the oldest unanswered request for blocks was sent
+    requested_at: Option<Instant>,
+    traffic: RateLimiter,
+}
+
+#[derive(Clone)]
+pub struct Node {
+    blockchain: Arc<Mutex<Blockchain>>,
+    peers: Arc<Mutex<HashMap<SocketAddr, Peer>>>,
+    pending_blocks: Arc<Mutex<HashMap<Hash256, PartialBlock>>>,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    addr_manager: Arc<Mutex<AddrManager>>,
+    block_meta: Arc<Mutex<BlockMetaStore>>,
+    invoices: Arc<Mutex<InvoiceBook>>,
+    wallets: Arc<Mutex<WalletManager>>,
+    sync: Arc<Mutex<SyncManager>>,
+    mining_stats: Arc<MiningStats>,
+    envelope: Envelope,
+}
+
+impl Node {
+    pub fn new(blockchain: Blockchain) -> Node {
+        let envelope = Envelope::new(blockchain.params().network);
+        Node {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            pending_blocks: Arc::new(Mutex::new(HashMap::new())),
+            peer_manager: Arc::new(Mutex::new(PeerManager::new())),
+            addr_manager: Arc::new(Mutex::new(AddrManager::new())),
+            block_meta: Arc::new(Mutex::new(BlockMetaStore::new())),
+            invoices: Arc::new(Mutex::new(InvoiceBook::new())),
+            wallets: Arc::new(Mutex::new(WalletManager::new())),
+            sync: Arc::new(Mutex::new(SyncManager::new())),
+            mining_stats: Arc::new(MiningStats::new()),
+            envelope,
+        }
+    }
+
+    // What the node sends in front of its messages
+    pub fn envelope(&self) -> Envelope {
+        self.envelope
+    }
+
+    pub fn with_peer_manager(blockchain: Blockchain, peer_manager: PeerManager) -> Node {
+        let node = Node::new(blockchain);
+        *node.peer_manager() = peer_manager;
+        node
+    }
+
+    pub fn peer_manager(&self) -> MutexGuard<'_, PeerManager> {
+        self.peer_manager.lock().unwrap()
+    }
+
+    pub fn addr_manager(&self) -> MutexGuard<'_, AddrManager> {
+        self.addr_manager.lock().unwrap()
+    }
+
+    // When and from where the blocks added came in
+    pub fn block_meta(&self) -> MutexGuard<'_, BlockMetaStore> {
+        self.block_meta.lock().unwrap()
+    }
+
+    // Lock the blockchain first when holding both
+    pub fn invoices(&self) -> MutexGuard<'_, InvoiceBook> {
+        self.invoices.lock().unwrap()
+    }
+
+    // Lock the blockchain first when holding both
+    pub fn wallets(&self) -> MutexGuard<'_, WalletManager> {
+        self.wallets.lock().unwrap()
+    }
+
+    // Kept by the miner running for the node, if any
+    pub fn mining_stats(&self) -> &Arc<MiningStats> {
+        &self.mining_stats
+    }
+
+    fn sync(&self) -> MutexGuard<'_, SyncManager> {
+        self.sync.lock().unwrap()
+    }
+
+    // Where initial block download is at
+    pub fn sync_state(&self) -> SyncState {
+        self.sync().state()
+    }
+
+    pub fn blockchain(&self) -> MutexGuard<'_, Blockchain> {
+        self.blockchain.lock().unwrap()
+    }
+
+    // Waits up to `timeout` for the chain to reach `height`, returning the
+    // block there
+    pub fn wait_for_block(&self, height: u64, timeout: Duration) -> Option<Block> {
+        let mut stream = {
+            let mut blockchain = self.blockchain();
+            if let Some(block) = blockchain.get_block_by_height(height) {
+                return Some(block.clone());
+            }
+            blockchain.subscribe_blocks(1)
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (block, _) = stream.next_timeout(remaining)?;
+            if block.header.index == height {
+                return Some(block);
+            }
+        }
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn peer_services(&self, addr: &SocketAddr) -> Option<u64> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(addr)
+            .map(|peer| peer.services)
+    }
+
+    // Bytes received from and sent to the peer
+    pub fn peer_traffic(&self, addr: &SocketAddr) -> Option<(u64, u64)> {
+        let peers = self.peers.lock().unwrap();
+        let traffic = &peers.get(addr)?.traffic;
+        Some((traffic.bytes_received, traffic.bytes_sent))
+    }
+
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let node = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Banned peers and those over the limit are dropped at once
+                if node.register(&stream, Direction::Inbound).is_ok() {
+                    let node = node.clone();
+                    thread::spawn(move || node.handle_connection(stream));
+                }
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    pub fn connect(&self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let stream = TcpStream::connect(addr)?;
+        self.register(&stream, Direction::Outbound)?;
+        self.addr_manager().mark_good(stream.peer_addr()?);
+        let node = self.clone();
+        thread::spawn(move || node.handle_connection(stream));
+        Ok(())
+    }
+
+    // Connects to up to `count` of the known addresses not connected to
+    // yet, returning how many connections were made
+    pub fn connect_to_known_peers(&self, count: usize) -> usize {
+        let connected: HashSet<SocketAddr> = self.peers.lock().unwrap().keys().copied().collect();
+        let candidates = self.addr_manager().select(count, &connected);
+
+        let mut made = 0;
+        for addr in candidates {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    if self.register(&stream, Direction::Outbound).is_ok() {
+                        self.addr_manager().mark_good(addr);
+                        let node = self.clone();
+                        thread::spawn(move || node.handle_connection(stream));
+                        made += 1;
+                    }
+                }
+                Err(_) => self.addr_manager().mark_failed(&addr),
+            }
+        }
+        made
+    }
+
+    pub fn submit_block(&self, block: Block) -> Result<BlockAcceptance, BlockValidationError> {
+        let acceptance = self.add_recorded(block.clone(), None, Blockchain::add_block)?;
+        self.announce(&block, None);
+        Ok(acceptance)
+    }
+
+    // Adds a block with `add`, keeping its metadata if it's accepted. Only
+    // the time spent in `add` counts as validation, not waiting for the lock.
+    fn add_recorded<T>(
+        &self,
+        block: Block,
+        source: Option<SocketAddr>,
+        add: impl FnOnce(&mut Blockchain, Block) -> Result<T, BlockValidationError>,
+    ) -> Result<T, BlockValidationError> {
+        let received = utils::unix_millis();
+        let digest = block.digest;
+        let size = block.as_bincode().len() as u64;
+
+        let mut blockchain = self.blockchain();
+        let started = Instant::now();
+        let result = add(&mut blockchain, block);
+        let validation_us = started.elapsed().as_micros() as u64;
+        drop(blockchain);
+
+        if result.is_ok() {
+            let meta = BlockMeta {
+                received,
+                source: source.map(|addr| addr.to_string()),
+                validation_us,
+                size,
+            };
+            if let Err(e) = self.block_meta().insert(digest, meta) {
+                log::warn("node", "couldn't save block metadata", &[("error", &e)]);
+            }
+        }
+        result
+    }
+
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<(), TransactionError> {
+        let message = Message::Transaction(tx.clone());
+        self.blockchain().submit_transaction(tx)?;
+        self.broadcast(&message, None);
+        Ok(())
+    }
+
+    // Sends a transaction to the peers again, submitting it first if it
+    // has dropped out of the mempool, e.g. after expiring
+    pub fn rebroadcast_transaction(&self, tx: &Transaction) -> Result<(), TransactionError> {
+        if !self.blockchain().mempool.contains(&tx.id) {
+            return self.submit_transaction(tx.clone());
+        }
+        self.broadcast(&Message::Transaction(tx.clone()), None);
+        Ok(())
+    }
+
+    // Decodes a bincode transaction and submits it, returning its id
+    pub fn submit_raw_transaction(&self, raw: &[u8]) -> Result<TxId, TransactionError> {
+        let tx = Transaction::from_bincode(raw).map_err(TransactionError::Malformed)?;
+        let txid = tx.id;
+        self.submit_transaction(tx)?;
+        Ok(txid)
+    }
+
+    // Like submit_raw_transaction, for hex encoded bincode
+    pub fn submit_raw_transaction_hex(&self, raw: &str) -> Result<TxId, TransactionError> {
+        let tx = Transaction::from_hex(raw).map_err(TransactionError::Malformed)?;
+        let txid = tx.id;
+        self.submit_transaction(tx)?;
+        Ok(txid)
+    }
+
+    fn register(&self, stream: &TcpStream, direction: Direction) -> io::Result<()> {
+        let addr = stream.peer_addr()?;
+        self.peer_manager()
+            .add(addr, direction)
+            .map_err(io::Error::other)
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let Ok(addr) = stream.peer_addr() else {
+            return;
+        };
+        let (Ok(mut reader), Ok(writer)) = (stream.try_clone(), stream.try_clone()) else {
+            self.peer_manager().remove(&addr);
+            return;
+        };
+
+        self.peers.lock().unwrap().insert(
+            addr,
+            Peer {
+                stream,
+                writer: Arc::new(Mutex::new(writer)),
+                height: 0,
+                services: 0,
+                requested_at: None,
+                traffic: RateLimiter::new(),
+            },
+        );
+
+        log::info("network", "peer connected", &[("addr", &addr)]);
+        let height = self.blockchain().height();
+        self.send_to(addr, &Message::Handshake { height });
+
+        while self.wait_for_message(addr, &reader) {
+            let mut counted = CountingReader {
+                inner: &mut reader,
+                count: 0,
+            };
+            let message = read_message(&mut counted, self.envelope.magic);
+            let size = counted.count;
+            let keep = match message {
+                Ok((envelope, message)) => {
+                    let within_limits = match self.peers.lock().unwrap().get_mut(&addr) {
+                        Some(peer) => {
+                            peer.services = envelope.services;
+                            peer.traffic.received(size, Instant::now())
+                        }
+                        None => false,
+                    };
+                    if within_limits {
+                        self.handle_message(addr, message)
+                    } else {
+                        // Dropped unread
+                        !self.report(addr, Misbehavior::Flooding)
+                    }
+                }
+                // The stream can't be trusted to be at a message boundary
+                // any more
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    log::debug(
+                        "network",
+                        "malformed message",
+                        &[("addr", &addr), ("error", &e)],
+                    );
+                    self.report(addr, Misbehavior::MalformedMessage);
+                    false
+                }
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    log::debug(
+                        "network",
+                        "incompatible peer",
+                        &[("addr", &addr), ("error", &e)],
+                    );
+                    false
+                }
+                Err(_) => false,
+            };
+            if !keep {
+                break;
+            }
+        }
+
+        self.peers.lock().unwrap().remove(&addr);
+        self.peer_manager().remove(&addr);
+        self.sync().remove_peer(&addr);
+        log::info("network", "peer disconnected", &[("addr", &addr)]);
+        self.request_blocks();
+        let _ = reader.shutdown(Shutdown::Both);
+    }
+
+    // Blocks until the peer sends something, checking meanwhile that it
+    // answers our requests. Returns false once it should be disconnected.
+    fn wait_for_message(&self, addr: SocketAddr, reader: &TcpStream) -> bool {
+        let _ = reader.set_read_timeout(Some(STALL_CHECK_INTERVAL));
+        loop {
+            match reader.peek(&mut [0]) {
+                Ok(0) => return false,
+                Ok(_) => break,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    // Blocks that timed out are asked of other peers
+                    self.request_blocks();
+                    if self.take_stalled_request(addr) {
+                        if self.report(addr, Misbehavior::Stalling) {
+                            return false;
+                        }
+                        self.request_next_block(addr);
+                    }
+                }
+                Err(_) => return false,
+            }
+        }
+        // Once a message starts it is read whole
+        let _ = reader.set_read_timeout(None);
+        true
+    }
+
+    // Forgets a request that went unanswered for too long, so it is only
+    // reported once
+    fn take_stalled_request(&self, addr: SocketAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let Some(peer) = peers.get_mut(&addr) else {
+            return false;
+        };
+        if peer
+            .requested_at
+            .is_some_and(|requested_at| requested_at.elapsed() >= STALL_TIMEOUT)
+        {
+            peer.requested_at = None;
+            return true;
+        }
+        false
+    }
+
+    // Returns true if the peer is now banned, in which case it is
+    // disconnected
+    fn report(&self, addr: SocketAddr, misbehavior: Misbehavior) -> bool {
+        if !self.peer_manager().misbehaving(addr, misbehavior) {
+            return false;
+        }
+        log::warn(
+            "network",
+            "banned peer",
+            &[("addr", &addr), ("reason", &format!("{misbehavior:?}"))],
+        );
+        if let Some(peer) = self.peers.lock().unwrap().remove(&addr) {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+        true
+    }
+
+    // Returns false if the peer should be disconnected
+    fn handle_message(&self, addr: SocketAddr, message: Message) -> bool {
+        if matches!(
+            message,
+            Message::Block(_) | Message::BlockTransactions { .. } | Message::Headers(_)
+        ) && let Some(peer) = self.peers.lock().unwrap().get_mut(&addr)
+        {
+            peer.requested_at = None;
+        }
+
+        match message {
+            Message::Handshake { height } => {
+                self.update_peer_height(addr, height);
+                if !self.start_sync() {
+                    self.request_next_block(addr);
+                }
+                self.send_to(addr, &Message::GetAddr);
+            }
+            Message::GetAddr => {
+                let addrs = self.addr_manager().addresses(MAX_ADDRS_PER_MESSAGE);
+                self.send_to(addr, &Message::Addr(addrs));
+            }
+            Message::Addr(addrs) => {
+                let now = utils::unix_timestamp();
+                let mut addr_manager = self.addr_manager();
+                for gossiped in addrs {
+                    addr_manager.add(gossiped, now);
+                }
+            }
+            Message::Block(block) => {
+                let Some(block) = self.sync().block_received(block) else {
+                    return self.connect_synced_blocks(addr);
+                };
+                return self.handle_block(addr, block);
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self.blockchain().get_headers_after(&locator, MAX_HEADERS);
+                self.send_to(addr, &Message::Headers(headers));
+            }
+            Message::Headers(headers) => {
+                let engine = self.blockchain().engine().clone();
+                let outcome = self
+                    .sync()
+                    .headers_received(addr, &headers, engine.as_ref());
+                match outcome {
+                    Ok(HeadersOutcome::More) => {
+                        let locator = self.sync().header_locator();
+                        self.send_to(addr, &Message::GetHeaders(locator));
+                    }
+                    Ok(HeadersOutcome::Done) => {
+                        let headers: Vec<(u64, Hash256)> = self.sync().headers().collect();
+                        self.blockchain().note_checkpointed_chain(headers);
+                        self.request_blocks();
+                    }
+                    Ok(HeadersOutcome::Ignored) => {}
+                    Err(e) => {
+                        if e.is_invalid_anywhere() && self.report(addr, Misbehavior::InvalidBlock) {
+                            return false;
+                        }
+                        self.request_next_block(addr);
+                    }
+                }
+            }
+            Message::Transaction(tx) => {
+                let relay = Message::Transaction(tx.clone());
+                if self.blockchain().submit_transaction(tx).is_ok() {
+                    self.broadcast(&relay, Some(addr));
+                }
+            }
+            Message::GetBlockByIndex(index) => {
+                let block = self.blockchain().get_block_by_height(index).cloned();
+                if let Some(block) = block {
+                    self.send_to(addr, &Message::Block(block));
+                }
+            }
+            Message::GetBlockByHash(hash) => {
+                let block = self.blockchain().get_block_by_hash(&hash).cloned();
+                if let Some(block) = block {
+                    self.send_to(addr, &Message::Block(block));
+                }
+            }
+            Message::CompactBlock(compact) => {
+                self.update_peer_height(addr, compact.header.index);
+
+                let hash = compact.hash();
+                let partial = {
+                    let blockchain = self.blockchain();
+                    if blockchain.get_block_by_hash(&hash).is_some() {
+                        return true;
+                    }
+                    compact.reconstruct(&blockchain.mempool)
+                };
+
+                let missing = partial.missing();
+                if missing.is_empty() {
+                    return self.complete_block(addr, hash, partial);
+                } else {
+                    let mut pending = self.pending_blocks.lock().unwrap();
+                    if pending.len() >= MAX_PENDING_BLOCKS {
+                        drop(pending);
+                        self.send_to(addr, &Message::GetBlockByHash(hash));
+                    } else {
+                        pending.insert(hash, partial);
+                        drop(pending);
+                        let request = Message::GetBlockTransactions {
+                            block: hash,
+                            indexes: missing,
+                        };
+                        self.send_to(addr, &request);
+                    }
+                }
+            }
+            Message::GetBlockTransactions { block, indexes } => {
+                let transactions: Option<Vec<Transaction>> =
+                    self.blockchain().get_block_by_hash(&block).and_then(|b| {
+                        indexes
+                            .iter()
+                            .map(|&i| b.transactions.get(i as usize).cloned())
+                            .collect()
+                    });
+                if let Some(transactions) = transactions {
+                    self.send_to(
+                        addr,
+                        &Message::BlockTransactions {
+                            block,
+                            transactions,
+                        },
+                    );
+                }
+            }
+            Message::BlockTransactions {
+                block,
+                transactions,
+            } => {
+                let partial = self.pending_blocks.lock().unwrap().remove(&block);
+                if let Some(mut partial) = partial {
+                    if partial.fill(transactions) {
+                        return self.complete_block(addr, block, partial);
+                    } else {
+                        self.send_to(addr, &Message::GetBlockByHash(block));
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    // Returns false if the peer should be disconnected
+    fn handle_block(&self, addr: SocketAddr, block: Block) -> bool {
+        self.update_peer_height(addr, block.header.index);
+
+        let prev_hash = block.header.prev_hash;
+        let result = self.add_recorded(block.clone(), Some(addr), Blockchain::process_block);
+        match result {
+            Ok((BlockAcceptance::OrphanStored, _)) => {
+                self.send_to(addr, &Message::GetBlockByHash(prev_hash));
+            }
+            Ok(_) => self.announce(&block, Some(addr)),
+            Err(e) if e.is_invalid_anywhere() => {
+                if self.report(addr, Misbehavior::InvalidBlock) {
+                    return false;
+                }
+            }
+            Err(_) => {}
+        }
+        self.request_next_block(addr);
+        true
+    }
+
+    // Starts an initial block download if a peer is far ahead, returning
+    // whether one is running
+    fn start_sync(&self) -> bool {
+        let (height, tip, locator) = {
+            let blockchain = self.blockchain();
+            (
+                blockchain.height(),
+                blockchain.prev_hash(),
+                blockchain.get_block_locator(),
+            )
+        };
+        let peer = self.sync().start(height, tip);
+        match peer {
+            Some(peer) => {
+                log::info(
+                    "sync",
+                    "initial block download started",
+                    &[("height", &height), ("peer", &peer)],
+                );
+                self.send_to(peer, &Message::GetHeaders(locator));
+                true
+            }
+            None => self.sync().is_syncing(),
+        }
+    }
+
+    // Connects the downloaded blocks that are next in order. A block that
+    // fails ends the sync, since the rest build on it.
+    fn connect_synced_blocks(&self, addr: SocketAddr) -> bool {
+        let ready = self.sync().take_ready();
+        let mut done = !ready.is_empty() && !self.sync().is_syncing();
+        for (block, peer) in ready {
+            let result = self.add_recorded(block, Some(peer), Blockchain::add_block);
+            if let Err(e) = result {
+                log::warn("sync", "downloaded block rejected", &[("error", &e)]);
+                self.sync().reset();
+                done = false;
+                break;
+            }
+        }
+
+        if self.sync().is_syncing() {
+            self.request_blocks();
+        } else {
+            if done {
+                let height = self.blockchain().height();
+                log::info(
+                    "sync",
+                    "initial block download finished",
+                    &[("height", &height)],
+                );
+            }
+            self.request_next_block(addr);
+        }
+        true
+    }
+
+    fn request_blocks(&self) {
+        let requests = self.sync().next_requests(Instant::now());
+        for (peer, hash) in requests {
+            self.send_to(peer, &Message::GetBlockByHash(hash));
+        }
+    }
+
+    // Falls back to the full block if the transactions filled in don't
+    // match the header
+    fn complete_block(&self, addr: SocketAddr, hash: Hash256, partial: PartialBlock) -> bool {
+        match partial.into_block() {
+            Some(block) => self.handle_block(addr, block),
+            None => {
+                self.send_to(addr, &Message::GetBlockByHash(hash));
+                true
+            }
+        }
+    }
+
+    fn update_peer_height(&self, addr: SocketAddr, height: u64) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&addr) {
+            peer.height = peer.height.max(height);
+        }
+        self.sync().update_peer(addr, height);
+    }
+
+    // Outside of an initial block download, blocks are asked for one at a
+    // time
+    fn request_next_block(&self, addr: SocketAddr) {
+        if self.sync().is_syncing() {
+            return;
+        }
+        let our_height = self.blockchain().height();
+        let peer_height = match self.peers.lock().unwrap().get(&addr) {
+            Some(peer) => peer.height,
+            None => return,
+        };
+        if peer_height > our_height {
+            self.send_to(addr, &Message::GetBlockByIndex(our_height + 1));
+        }
+    }
+
+    // The peers to write to, with their service flags. Writes happen after
+    // the peers lock is released, so a peer slow to read only holds up the
+    // thread writing to it.
+    fn writers(&self, except: Option<SocketAddr>) -> Vec<(SocketAddr, u64, Arc<Mutex<TcpStream>>)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, _)| Some(**addr) != except)
+            .map(|(addr, peer)| (*addr, peer.services, peer.writer.clone()))
+            .collect()
+    }
+
+    // Returns false, and drops the peer, if it can't be written to any more
+    fn write_to(
+        &self,
+        addr: SocketAddr,
+        writer: &Arc<Mutex<TcpStream>>,
+        message: &Message,
+    ) -> bool {
+        let result = write_message(&mut *writer.lock().unwrap(), &self.envelope, message);
+        let mut peers = self.peers.lock().unwrap();
+        // It may have disconnected, and even reconnected, meanwhile
+        let Some(peer) = peers
+            .get_mut(&addr)
+            .filter(|peer| Arc::ptr_eq(&peer.writer, writer))
+        else {
+            return false;
+        };
+        match result {
+            Ok(size) => {
+                peer.traffic.sent(size);
+                if is_request(message) && peer.requested_at.is_none() {
+                    peer.requested_at = Some(Instant::now());
+                }
+                true
+            }
+            Err(_) => {
+                peers.remove(&addr);
+                false
+            }
+        }
+    }
+
+    fn send_to(&self, addr: SocketAddr, message: &Message) {
+        let writer = match self.peers.lock().unwrap().get(&addr) {
+            Some(peer) => peer.writer.clone(),
+            None => return,
+        };
+        self.write_to(addr, &writer, message);
+    }
+
+    fn broadcast(&self, message: &Message, except: Option<SocketAddr>) {
+        for (addr, _, writer) in self.writers(except) {
+            self.write_to(addr, &writer, message);
+        }
+    }
+
+    // Peers that understand compact blocks get one, the rest the full block
+    fn announce(&self, block: &Block, except: Option<SocketAddr>) {
+        let compact = CompactBlock::from_block(block).map(Message::CompactBlock);
+        let full = Message::Block(block.clone());
+        for (addr, services, writer) in self.writers(except) {
+            let message = match &compact {
+                Some(compact) if services & SERVICE_COMPACT_BLOCKS != 0 => compact,
+                _ => &full,
+            };
+            self.write_to(addr, &writer, message);
+        }
+    }
+}
+
+// Messages the peer is expected to answer
+fn is_request(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::GetBlockByIndex(_)
+            | Message::GetBlockByHash(_)
+            | Message::GetBlockTransactions { .. }
+            | Message::GetHeaders(_)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::DecodeError;
+    use crate::params::ChainParams;
+    use crate::transaction::{TxInput, TxOutput};
+    use crate::wallet::Wallet;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0)],
+            vec![TxOutput::new(50, [2; 32])],
+        );
+
+        let envelope = Envelope::new(Network::Regtest);
+        let mut buf = Vec::new();
+        write_message(&mut buf, &envelope, &Message::Transaction(tx.clone())).unwrap();
+        write_message(&mut buf, &envelope, &Message::GetBlockByIndex(7)).unwrap();
+
+        let mut reader = buf.as_slice();
+        match read_message(&mut reader, envelope.magic).unwrap() {
+            (decoded_envelope, Message::Transaction(decoded)) => {
+                assert_eq!(decoded_envelope, envelope);
+                assert_eq!(decoded.id, tx.id);
+            }
+            _ => panic!("expected transaction"),
+        }
+        assert!(matches!(
+            read_message(&mut reader, envelope.magic).unwrap(),
+            (_, Message::GetBlockByIndex(7))
+        ));
+    }
+
+    #[test]
+    fn test_submit_raw_transaction() {
+        let wallet = Wallet::new();
+        let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+        let mut block = node.blockchain().next_block();
+        let reward = node.blockchain().get_block_reward();
+        utils::mine(&mut block, wallet.pkhash, reward);
+        let coinbase = block.transactions[0].id;
+        node.submit_block(block).unwrap();
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(coinbase, 0).with_value(reward)],
+            vec![TxOutput::new(reward - 1000, [1; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        let raw = tx.as_bincode();
+
+        assert_eq!(
+            node.submit_raw_transaction(&raw[1..]),
+            Err(TransactionError::Malformed(DecodeError::Invalid))
+        );
+        assert_eq!(
+            node.submit_raw_transaction_hex("zz"),
+            Err(TransactionError::Malformed(DecodeError::Invalid))
+        );
+        assert_eq!(
+            node.submit_raw_transaction_hex(&hex::encode(&raw)),
+            Ok(tx.id)
+        );
+        assert!(node.blockchain().mempool.contains(&tx.id));
+        assert_eq!(
+            node.submit_raw_transaction(&raw),
+            Err(TransactionError::AlreadyInMempool)
+        );
+    }
+
+    #[test]
+    fn test_rejects_oversized_message() {
+        let magic = Network::Regtest.magic();
+        let header = |len: usize| {
+            let mut buf = magic.to_vec();
+            buf.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+            buf.extend_from_slice(&DEFAULT_SERVICES.to_be_bytes());
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+            buf.extend_from_slice(&[0; 4]);
+            buf
+        };
+        // too large for a block, so dropped from the header alone
+        let buf = header(MAX_BLOCK_MESSAGE_SIZE + 1);
+        let error = read_message(&mut buf.as_slice(), magic).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // a block's worth is read, and found missing
+        let mut buf = header(MAX_BLOCK_MESSAGE_SIZE);
+        buf.extend_from_slice(&[0; 16]);
+        let error = read_message(&mut buf.as_slice(), magic).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_message_limits() {
+        let tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0); MAX_TX_INPUTS + 1],
+            vec![TxOutput::new(50, [2; 32])],
+        );
+        let message = Message::Transaction(tx);
+        assert_eq!(message.check_limits(1000), Err(MessageError::TooManyInputs));
+        assert_eq!(
+            message.check_limits(MAX_TX_MESSAGE_SIZE + 1),
+            Err(MessageError::TooLarge {
+                size: MAX_TX_MESSAGE_SIZE + 1,
+                max: MAX_TX_MESSAGE_SIZE
+            })
+        );
+
+        let addrs = Message::Addr(vec![SocketAddr::from(([10, 0, 0, 1], 1)); 1001]);
+        assert_eq!(addrs.check_limits(100), Err(MessageError::TooManyItems));
+        assert_eq!(Message::GetAddr.check_limits(100), Ok(()));
+
+        // enforced when reading
+        let envelope = Envelope::new(Network::Regtest);
+        let mut buf = Vec::new();
+        write_message(&mut buf, &envelope, &message).unwrap();
+        let error = read_message(&mut buf.as_slice(), envelope.magic)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_rejects_bad_envelope() {
+        let envelope = Envelope::new(Network::Regtest);
+        let mut buf = Vec::new();
+        write_message(&mut buf, &envelope, &Message::GetBlockByIndex(7)).unwrap();
+
+        let error = read_message(&mut buf.as_slice(), Network::Mainnet.magic())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+
+        let mut corrupted = buf.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let error = read_message(&mut corrupted.as_slice(), envelope.magic)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let old = Envelope {
+            version: MIN_PROTOCOL_VERSION - 1,
+            ..envelope
+        };
+        let mut buf = Vec::new();
+        write_message(&mut buf, &old, &Message::GetBlockByIndex(7)).unwrap();
+        let error = read_message(&mut buf.as_slice(), envelope.magic)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,127 @@
+// Unicode compatibility decomposition (NFKD), which BIP39 applies to mnemonic
+// phrases and passphrases so the same text typed on different systems gives
+// the same seed. The tables in unicode/ are generated from the Unicode 14.0
+// character database: each character's full decomposition, and the
+// canonical combining class of the characters that have one, as ranges.
+// Hangul syllables are decomposed arithmetically instead.
+
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+static DECOMPOSITIONS: LazyLock<HashMap<char, Vec<char>>> = LazyLock::new(|| {
+    include_str!("unicode/decompositions.txt")
+        .lines()
+        .map(|line| {
+            let mut chars = line.split(' ').map(parse_char);
+            (chars.next().unwrap(), chars.collect())
+        })
+        .collect()
+});
+
+static COMBINING_CLASSES: LazyLock<Vec<(char, char, u8)>> = LazyLock::new(|| {
+    include_str!("unicode/combining_classes.txt")
+        .lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split(' ').collect();
+            (
+                parse_char(fields[0]),
+                parse_char(fields[1]),
+                fields[2].parse().unwrap(),
+            )
+        })
+        .collect()
+});
+
+const HANGUL_BASE: u32 = 0xAC00;
+const HANGUL_COUNT: u32 = 11172;
+const LEAD_BASE: u32 = 0x1100;
+const VOWEL_BASE: u32 = 0x1161;
+const TRAIL_BASE: u32 = 0x11A7;
+const VOWEL_COUNT: u32 = 21;
+const TRAIL_COUNT: u32 = 28;
+
+fn parse_char(hex: &str) -> char {
+    char::from_u32(u32::from_str_radix(hex, 16).unwrap()).unwrap()
+}
+
+fn combining_class(c: char) -> u8 {
+    let classes = &*COMBINING_CLASSES;
+    match classes.binary_search_by(|&(start, _, _)| start.cmp(&c)) {
+        Ok(i) => classes[i].2,
+        Err(0) => 0,
+        Err(i) if c <= classes[i - 1].1 => classes[i - 1].2,
+        Err(_) => 0,
+    }
+}
+
+fn decompose(c: char, out: &mut Vec<char>) {
+    let offset = (c as u32).wrapping_sub(HANGUL_BASE);
+    if offset < HANGUL_COUNT {
+        out.push(char::from_u32(LEAD_BASE + offset / (VOWEL_COUNT * TRAIL_COUNT)).unwrap());
+        out.push(
+            char::from_u32(VOWEL_BASE + offset % (VOWEL_COUNT * TRAIL_COUNT) / TRAIL_COUNT)
+                .unwrap(),
+        );
+        if !offset.is_multiple_of(TRAIL_COUNT) {
+            out.push(char::from_u32(TRAIL_BASE + offset % TRAIL_COUNT).unwrap());
+        }
+        return;
+    }
+    match DECOMPOSITIONS.get(&c) {
+        Some(decomposition) => out.extend(decomposition),
+        None => out.push(c),
+    }
+}
+
+pub fn nfkd(text: &str) -> String {
+    let mut chars = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        decompose(c, &mut chars);
+    }
+    // Canonical ordering: each run of combining marks is sorted by class,
+    // keeping the order of marks of the same class
+    let mut start = 0;
+    while start < chars.len() {
+        if combining_class(chars[start]) == 0 {
+            start += 1;
+            continue;
+        }
+        let end = (start..chars.len())
+            .find(|&i| combining_class(chars[i]) == 0)
+            .unwrap_or(chars.len());
+        chars[start..end].sort_by_key(|&c| combining_class(c));
+        start = end;
+    }
+    chars.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nfkd() {
+        let cases = [
+            ("abandon", "abandon"),
+            ("caf\u{e9}", "cafe\u{301}"),
+            ("cafe\u{301}", "cafe\u{301}"),
+            // compatibility characters
+            ("\u{fb01}x", "fix"),
+            ("\u{2460}", "1"),
+            ("\u{3000}", " "),
+            ("\u{ff21}", "A"),
+            // Hangul, with and without a trailing consonant
+            (
+                "\u{d55c}\u{ac00}",
+                "\u{1112}\u{1161}\u{11ab}\u{1100}\u{1161}",
+            ),
+            // dot below (220) goes before circumflex (230)
+            ("\u{1ead}", "a\u{323}\u{302}"),
+            ("a\u{302}\u{323}", "a\u{323}\u{302}"),
+            ("\u{1ea5}", "a\u{302}\u{301}"),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(nfkd(text), expected, "{text}");
+        }
+    }
+}
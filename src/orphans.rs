@@ -0,0 +1,138 @@
+use crate::block::Block;
+use crate::hash::Hash256;
+use std::collections::{HashMap, VecDeque};
+
+// Enough to cover a burst of out-of-order blocks without letting a peer fill
+// memory with blocks that never connect
+pub const MAX_ORPHANS: usize = 100;
+
+pub struct OrphanPool {
+    blocks: HashMap<Hash256, Block>,
+    // prev_hash -> digests of orphans building on it
+    children: HashMap<Hash256, Vec<Hash256>>,
+    // Insertion order, oldest first, used for eviction
+    order: VecDeque<Hash256>,
+}
+
+impl OrphanPool {
+    pub fn new() -> OrphanPool {
+        OrphanPool {
+            blocks: HashMap::new(),
+            children: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // Returns false if the block was already in the pool
+    pub fn add(&mut self, block: Block) -> bool {
+        if self.blocks.contains_key(&block.digest) {
+            return false;
+        }
+
+        while self.blocks.len() >= MAX_ORPHANS {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+
+        self.children
+            .entry(block.header.prev_hash)
+            .or_default()
+            .push(block.digest);
+        self.order.push_back(block.digest);
+        self.blocks.insert(block.digest, block);
+        true
+    }
+
+    // Removes and returns the orphans whose parent is `parent`
+    pub fn take_children(&mut self, parent: &Hash256) -> Vec<Block> {
+        let digests = self.children.remove(parent).unwrap_or_default();
+        self.order.retain(|digest| !digests.contains(digest));
+        digests
+            .iter()
+            .filter_map(|digest| self.blocks.remove(digest))
+            .collect()
+    }
+
+    fn remove(&mut self, digest: &Hash256) -> Option<Block> {
+        let block = self.blocks.remove(digest)?;
+        if let Some(siblings) = self.children.get_mut(&block.header.prev_hash) {
+            siblings.retain(|sibling| sibling != digest);
+            if siblings.is_empty() {
+                self.children.remove(&block.header.prev_hash);
+            }
+        }
+        self.order.retain(|d| d != digest);
+        Some(block)
+    }
+
+    pub fn contains(&self, digest: &Hash256) -> bool {
+        self.blocks.contains_key(digest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(prev_hash: Hash256, nonce: u64) -> Block {
+        let mut block = Block::new(1, prev_hash, 0x2100_ffff, Vec::new());
+        block.header.nonce = nonce;
+        block.digest = block.hash();
+        block
+    }
+
+    #[test]
+    fn test_take_children() {
+        let mut pool = OrphanPool::new();
+        let a = block(Hash256([1; 32]), 0);
+        let b = block(Hash256([1; 32]), 1);
+        let c = block(Hash256([2; 32]), 0);
+
+        assert!(pool.add(a.clone()));
+        assert!(!pool.add(a.clone()));
+        pool.add(b.clone());
+        pool.add(c.clone());
+        assert_eq!(pool.len(), 3);
+
+        let children: Vec<Hash256> = pool
+            .take_children(&Hash256([1; 32]))
+            .iter()
+            .map(|block| block.digest)
+            .collect();
+        assert_eq!(children, vec![a.digest, b.digest]);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&c.digest));
+        assert!(pool.take_children(&Hash256([1; 32])).is_empty());
+    }
+
+    #[test]
+    fn test_evicts_oldest() {
+        let mut pool = OrphanPool::new();
+        let first = block(Hash256([1; 32]), 0);
+        pool.add(first.clone());
+        for nonce in 1..=MAX_ORPHANS as u64 {
+            pool.add(block(Hash256([1; 32]), nonce));
+        }
+
+        assert_eq!(pool.len(), MAX_ORPHANS);
+        assert!(!pool.contains(&first.digest));
+        assert_eq!(pool.take_children(&Hash256([1; 32])).len(), MAX_ORPHANS);
+        assert!(pool.is_empty());
+    }
+}
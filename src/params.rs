@@ -0,0 +1,294 @@
+// Consensus parameters that differ between networks. Each network has its
+// own genesis block, and so its own chain id, which keeps wallets and
+// transactions from one network from being valid on another.
+
+use crate::amount::{Amount, MAX_MONEY};
+use crate::block::{Block, BlockHeader};
+use crate::consensus::{self, PowAlgorithm};
+use crate::deployments::Deployment;
+use crate::hash::Hash256;
+use crate::transaction::TRANSACTION_VERSION;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    // Local testing, with a target almost every hash meets
+    Regtest,
+}
+
+impl Network {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet => "testnet",
+            Network::Regtest => "regtest",
+        }
+    }
+
+    // Starts every message on the network, so peers from another network
+    // are told apart before anything is decoded
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Network::Mainnet => [0xd2, 0xb7, 0xc1, 0x5a],
+            Network::Testnet => [0xd2, 0xb7, 0xc1, 0x7e],
+            Network::Regtest => [0xd2, 0xb7, 0xc1, 0xfa],
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format!("unknown network {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainParams {
+    pub network: Network,
+    pub genesis_timestamp: u64,
+    pub genesis_nonce: u64,
+    // The target of every block, in compact form
+    pub bits: u32,
+    // The hash blocks must meet the target with
+    pub pow: PowAlgorithm,
+    pub initial_reward: u64,
+    // The block reward halves every this many blocks
+    pub halving_interval: u64,
+    // Known (height, digest) pairs. Blocks must match them, and signatures
+    // are not checked at or below the highest one.
+    pub checkpoints: Vec<(u64, Hash256)>,
+    // Largest encoded size of a block, in bytes
+    pub max_block_size: usize,
+    // Most coins there can ever be. Block rewards stop once the supply
+    // reaches it.
+    pub max_money: Amount,
+    // (version, height) pairs. Transactions of a version are valid in blocks
+    // from its activation height on, so a new format can be scheduled
+    // without invalidating old blocks. Versions missing are never valid.
+    pub transaction_versions: Vec<(u32, u64)>,
+    // Soft forks miners can signal for, see deployments.rs
+    pub deployments: Vec<Deployment>,
+    // Deployment states change every this many blocks
+    pub signal_period: u64,
+    // Signaling blocks in a period needed to lock a deployment in
+    pub signal_threshold: u64,
+}
+
+impl ChainParams {
+    pub fn mainnet() -> ChainParams {
+        ChainParams {
+            network: Network::Mainnet,
+            genesis_timestamp: 1747162780,
+            genesis_nonce: 524,
+            // 000fffff00...00
+            bits: 0x1f0f_ffff,
+            pow: PowAlgorithm::Sha256,
+            initial_reward: 50_000_000,
+            halving_interval: 210_000,
+            checkpoints: Vec::new(),
+            max_block_size: 1_000_000,
+            max_money: MAX_MONEY,
+            transaction_versions: vec![(TRANSACTION_VERSION, 0)],
+            deployments: Vec::new(),
+            signal_period: 2016,
+            signal_threshold: 1916,
+        }
+    }
+
+    pub fn testnet() -> ChainParams {
+        ChainParams {
+            network: Network::Testnet,
+            genesis_timestamp: 1747600000,
+            genesis_nonce: 2526,
+            signal_threshold: 1512,
+            ..ChainParams::mainnet()
+        }
+    }
+
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Regtest,
+            genesis_timestamp: 1747600000,
+            genesis_nonce: 1,
+            // 7fffff00...00
+            bits: 0x207f_ffff,
+            pow: PowAlgorithm::Sha256,
+            initial_reward: 50_000_000,
+            halving_interval: 150,
+            checkpoints: Vec::new(),
+            max_block_size: 1_000_000,
+            max_money: MAX_MONEY,
+            transaction_versions: vec![(TRANSACTION_VERSION, 0)],
+            deployments: Vec::new(),
+            signal_period: 144,
+            signal_threshold: 108,
+        }
+    }
+
+    pub fn for_network(network: Network) -> ChainParams {
+        match network {
+            Network::Mainnet => ChainParams::mainnet(),
+            Network::Testnet => ChainParams::testnet(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    // The built-in network whose genesis digest is `chain_id`
+    pub fn for_chain_id(chain_id: &[u8; 32]) -> Option<ChainParams> {
+        [Network::Mainnet, Network::Testnet, Network::Regtest]
+            .into_iter()
+            .map(ChainParams::for_network)
+            .find(|params| params.genesis().digest.0 == *chain_id)
+    }
+
+    pub fn target(&self) -> [u8; 32] {
+        consensus::target_from_bits(self.bits).unwrap()
+    }
+
+    // The same chain with `pow` as its proof of work, and so a genesis
+    // block mined again for it
+    pub fn with_pow(mut self, pow: PowAlgorithm) -> ChainParams {
+        self.pow = pow;
+        let mut header = consensus::Header::from(&self.genesis().header);
+        header.nonce = 0;
+        while !header.has_valid_work(&pow) {
+            header.nonce += 1;
+        }
+        self.genesis_nonce = header.nonce;
+        self
+    }
+
+    pub fn genesis(&self) -> Block {
+        let mut genesis = Block {
+            digest: Hash256::default(),
+            header: BlockHeader {
+                version: 1,
+                index: 0,
+                timestamp: self.genesis_timestamp,
+                prev_hash: Hash256::default(),
+                merkle_root: [0; 32],
+                bits: self.bits,
+                nonce: self.genesis_nonce,
+            },
+            transactions: Vec::new(),
+        };
+        genesis.update_merkle_root();
+        genesis
+    }
+
+    pub fn is_version_active(&self, version: u32, height: u64) -> bool {
+        self.transaction_versions
+            .iter()
+            .any(|&(v, activation)| v == version && height >= activation)
+    }
+
+    pub fn last_checkpoint_height(&self) -> Option<u64> {
+        self.checkpoints.iter().map(|&(height, _)| height).max()
+    }
+
+    pub fn block_reward(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            0
+        } else {
+            self.initial_reward >> halvings
+        }
+    }
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::BlockValidationError;
+
+    #[test]
+    fn test_genesis_blocks() {
+        let expected = [
+            (
+                ChainParams::mainnet(),
+                "0005c6bfbd1c4b2557f7c11252f790d6d760ce4568a0cce5584acee7d2a9098d",
+            ),
+            (
+                ChainParams::testnet(),
+                "000262287536791593bfc2aea53bb861eba241264d825665add4d7a04b3f299b",
+            ),
+            (
+                ChainParams::regtest(),
+                "39e4fd033f42c4fe24db3c57e4072af72aa365b50f1924f5751afba72556bdb5",
+            ),
+        ];
+
+        for (params, digest) in expected {
+            let genesis = params.genesis();
+            assert_eq!(genesis.digest.to_string(), digest);
+            assert_eq!(genesis.header.validate(&params.pow), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_genesis_with_other_pow() {
+        for pow in [PowAlgorithm::DoubleSha256, PowAlgorithm::Blake3] {
+            let params = ChainParams::mainnet().with_pow(pow);
+            let genesis = params.genesis();
+            assert_eq!(genesis.header.validate(&pow), Ok(()));
+            assert_ne!(genesis.digest, ChainParams::mainnet().genesis().digest);
+            assert_eq!(
+                genesis.header.validate(&PowAlgorithm::Sha256),
+                Err(BlockValidationError::InvalidProofOfWork)
+            );
+        }
+    }
+
+    #[test]
+    fn test_block_reward_halving() {
+        let params = ChainParams::regtest();
+        assert_eq!(params.block_reward(1), 50_000_000);
+        assert_eq!(params.block_reward(149), 50_000_000);
+        assert_eq!(params.block_reward(150), 25_000_000);
+        assert_eq!(params.block_reward(450), 6_250_000);
+        assert_eq!(params.block_reward(150 * 64), 0);
+        assert_eq!(ChainParams::mainnet().block_reward(210_000), 25_000_000);
+    }
+
+    #[test]
+    fn test_version_activation() {
+        let params = ChainParams {
+            transaction_versions: vec![(2, 0), (3, 100)],
+            ..ChainParams::regtest()
+        };
+        assert!(params.is_version_active(2, 0));
+        assert!(!params.is_version_active(3, 99));
+        assert!(params.is_version_active(3, 100));
+        assert!(!params.is_version_active(1, 100));
+    }
+
+    #[test]
+    fn test_parse_network() {
+        for network in [Network::Mainnet, Network::Testnet, Network::Regtest] {
+            assert_eq!(network.to_string().parse(), Ok(network));
+        }
+        assert!("devnet".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,256 @@
+// Payment request URIs, after BIP21: "rbc:<address>?amount=1.5&label=Shop",
+// for pasting into a wallet or showing as a QR code. The amount is in coins,
+// and the label and message are percent-encoded. Parameters a wallet doesn't
+// know are ignored, unless they start with "req-", which marks them as
+// required.
+
+use crate::address::Address;
+use crate::amount::Amount;
+use crate::errors::PaymentRequestError;
+use std::fmt;
+use std::str::FromStr;
+
+pub const URI_SCHEME: &str = "rbc";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PaymentRequest {
+    pub address: Address,
+    // Left to the payer if None
+    pub amount: Option<Amount>,
+    // Who is being paid, e.g. a shop
+    pub label: Option<String>,
+    // What the payment is for
+    pub message: Option<String>,
+}
+
+impl PaymentRequest {
+    pub fn new(address: impl Into<Address>) -> PaymentRequest {
+        PaymentRequest {
+            address: address.into(),
+            amount: None,
+            label: None,
+            message: None,
+        }
+    }
+
+    pub fn with_amount(mut self, amount: impl Into<Amount>) -> PaymentRequest {
+        self.amount = Some(amount.into());
+        self
+    }
+
+    pub fn with_label(mut self, label: &str) -> PaymentRequest {
+        self.label = Some(label.to_string());
+        self
+    }
+
+    pub fn with_message(mut self, message: &str) -> PaymentRequest {
+        self.message = Some(message.to_string());
+        self
+    }
+
+    pub fn encode(&self) -> String {
+        self.encode_with(&self.address.encode(), URI_SCHEME)
+    }
+
+    // The scheme and address in upper case, so a QR code can hold them in
+    // its denser alphanumeric mode. Both are case-insensitive.
+    pub fn encode_for_qr(&self) -> String {
+        self.encode_with(
+            &self.address.encode().to_uppercase(),
+            &URI_SCHEME.to_uppercase(),
+        )
+    }
+
+    fn encode_with(&self, address: &str, scheme: &str) -> String {
+        let mut params = Vec::new();
+        if let Some(amount) = self.amount {
+            params.push(format!("amount={}", format_coins(amount)));
+        }
+        if let Some(label) = &self.label {
+            params.push(format!("label={}", percent_encode(label)));
+        }
+        if let Some(message) = &self.message {
+            params.push(format!("message={}", percent_encode(message)));
+        }
+
+        let mut uri = format!("{scheme}:{address}");
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+
+    pub fn parse(uri: &str) -> Result<PaymentRequest, PaymentRequestError> {
+        let (scheme, rest) = uri
+            .split_once(':')
+            .ok_or(PaymentRequestError::WrongScheme)?;
+        if !scheme.eq_ignore_ascii_case(URI_SCHEME) {
+            return Err(PaymentRequestError::WrongScheme);
+        }
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut request =
+            PaymentRequest::new(Address::decode(address).map_err(PaymentRequestError::Address)?);
+
+        let mut seen = Vec::new();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            if seen.contains(&key) {
+                return Err(PaymentRequestError::DuplicateParameter(key.to_string()));
+            }
+            seen.push(key);
+            match key {
+                "amount" => {
+                    let amount: Amount = value
+                        .parse()
+                        .map_err(|_| PaymentRequestError::InvalidAmount)?;
+                    if !amount.is_valid() {
+                        return Err(PaymentRequestError::InvalidAmount);
+                    }
+                    request.amount = Some(amount);
+                }
+                "label" => request.label = Some(percent_decode(value)?),
+                "message" => request.message = Some(percent_decode(value)?),
+                _ if key.starts_with("req-") => {
+                    return Err(PaymentRequestError::UnknownRequired(key.to_string()));
+                }
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+}
+
+impl FromStr for PaymentRequest {
+    type Err = PaymentRequestError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PaymentRequest::parse(s)
+    }
+}
+
+impl fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.encode())
+    }
+}
+
+// Coins without trailing zeros, e.g. "1.5" or "2"
+fn format_coins(amount: Amount) -> String {
+    let coins = amount.to_string();
+    coins
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+// Everything but the RFC 3986 unreserved characters is escaped
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Result<String, PaymentRequestError> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2).ok_or(PaymentRequestError::InvalidEncoding)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| PaymentRequestError::InvalidEncoding)?;
+            bytes.push(
+                u8::from_str_radix(hex, 16).map_err(|_| PaymentRequestError::InvalidEncoding)?,
+            );
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| PaymentRequestError::InvalidEncoding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::COIN;
+    use crate::errors::AddressError;
+
+    #[test]
+    fn test_encode_and_parse() {
+        let address = Address::from([7; 32]);
+        let request = PaymentRequest::new(address)
+            .with_amount(COIN + COIN / 2)
+            .with_label("Joe's Café")
+            .with_message("order #12 & more");
+
+        let uri = request.encode();
+        assert_eq!(
+            uri,
+            format!(
+                "rbc:{address}?amount=1.5&label=Joe%27s%20Caf%C3%A9&message=order%20%2312%20%26%20more"
+            )
+        );
+        assert_eq!(PaymentRequest::parse(&uri), Ok(request.clone()));
+        assert_eq!(request.encode_for_qr().parse(), Ok(request));
+
+        let bare = PaymentRequest::new(address);
+        assert_eq!(bare.encode(), format!("rbc:{address}"));
+        assert_eq!(bare.encode().parse(), Ok(bare));
+        assert_eq!(
+            format!("rbc:{address}?amount=20&foo=bar").parse(),
+            Ok(PaymentRequest::new(address).with_amount(20 * COIN))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let address = Address::from([7; 32]);
+        let cases = [
+            (format!("{address}"), PaymentRequestError::WrongScheme),
+            (format!("btc:{address}"), PaymentRequestError::WrongScheme),
+            (
+                "rbc:rbc1qqqq".to_string(),
+                PaymentRequestError::Address(AddressError::InvalidLength),
+            ),
+            (
+                format!("rbc:{address}?amount=1.123456789"),
+                PaymentRequestError::InvalidAmount,
+            ),
+            (
+                format!("rbc:{address}?amount=-1"),
+                PaymentRequestError::InvalidAmount,
+            ),
+            (
+                format!("rbc:{address}?amount=30000000"),
+                PaymentRequestError::InvalidAmount,
+            ),
+            (
+                format!("rbc:{address}?amount=1&amount=2"),
+                PaymentRequestError::DuplicateParameter("amount".to_string()),
+            ),
+            (
+                format!("rbc:{address}?label=%E"),
+                PaymentRequestError::InvalidEncoding,
+            ),
+            (
+                format!("rbc:{address}?label=%FF"),
+                PaymentRequestError::InvalidEncoding,
+            ),
+            (
+                format!("rbc:{address}?req-expires=10"),
+                PaymentRequestError::UnknownRequired("req-expires".to_string()),
+            ),
+        ];
+
+        for (uri, error) in cases {
+            assert_eq!(PaymentRequest::parse(&uri), Err(error), "{uri}");
+        }
+    }
+}
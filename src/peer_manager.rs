@@ -0,0 +1,223 @@
+use crate::errors::ConnectError;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Peers reaching this score are disconnected and banned
+pub const BAN_THRESHOLD: u32 = 100;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+pub const DEFAULT_MAX_INBOUND: usize = 32;
+pub const DEFAULT_MAX_OUTBOUND: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // Connected to us
+    Inbound,
+    // We connected to it
+    Outbound,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Misbehavior {
+    // A block that can't be valid on any chain
+    InvalidBlock,
+    // A message that can't be decoded
+    MalformedMessage,
+    // Data we asked for didn't arrive in time
+    Stalling,
+    // Sending faster than the rate limits allow
+    Flooding,
+}
+
+impl Misbehavior {
+    pub fn score(self) -> u32 {
+        match self {
+            Misbehavior::InvalidBlock => 100,
+            Misbehavior::MalformedMessage => 50,
+            Misbehavior::Stalling => 20,
+            Misbehavior::Flooding => 10,
+        }
+    }
+}
+
+// Tracks connected peers and how they behaved. Scores are kept per
+// connection, bans per IP address, so a banned peer can't come back from
+// another port.
+pub struct PeerManager {
+    inbound: HashSet<SocketAddr>,
+    outbound: HashSet<SocketAddr>,
+    scores: HashMap<SocketAddr, u32>,
+    // IP -> end of the ban
+    banned: HashMap<IpAddr, Instant>,
+    max_inbound: usize,
+    max_outbound: usize,
+    ban_duration: Duration,
+}
+
+impl PeerManager {
+    pub fn new() -> PeerManager {
+        PeerManager::with_limits(DEFAULT_MAX_INBOUND, DEFAULT_MAX_OUTBOUND)
+    }
+
+    pub fn with_limits(max_inbound: usize, max_outbound: usize) -> PeerManager {
+        PeerManager {
+            inbound: HashSet::new(),
+            outbound: HashSet::new(),
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            max_inbound,
+            max_outbound,
+            ban_duration: DEFAULT_BAN_DURATION,
+        }
+    }
+
+    pub fn set_ban_duration(&mut self, duration: Duration) {
+        self.ban_duration = duration;
+    }
+
+    // Registers a connection, unless the peer is banned or there are
+    // already as many connections in its direction as allowed
+    pub fn add(&mut self, addr: SocketAddr, direction: Direction) -> Result<(), ConnectError> {
+        if self.is_banned(&addr.ip()) {
+            return Err(ConnectError::Banned);
+        }
+        if self.inbound.contains(&addr) || self.outbound.contains(&addr) {
+            return Err(ConnectError::AlreadyConnected);
+        }
+        let (peers, max, full) = match direction {
+            Direction::Inbound => (
+                &mut self.inbound,
+                self.max_inbound,
+                ConnectError::TooManyInbound,
+            ),
+            Direction::Outbound => (
+                &mut self.outbound,
+                self.max_outbound,
+                ConnectError::TooManyOutbound,
+            ),
+        };
+        if peers.len() >= max {
+            return Err(full);
+        }
+        peers.insert(addr);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.inbound.remove(addr);
+        self.outbound.remove(addr);
+        self.scores.remove(addr);
+    }
+
+    // Adds to the peer's score. Returns true if it reached the threshold and
+    // was banned, in which case it should be disconnected.
+    pub fn misbehaving(&mut self, addr: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let score = self.scores.entry(addr).or_default();
+        *score += misbehavior.score();
+        if *score >= BAN_THRESHOLD {
+            self.ban(addr.ip());
+            return true;
+        }
+        false
+    }
+
+    pub fn score(&self, addr: &SocketAddr) -> u32 {
+        self.scores.get(addr).copied().unwrap_or(0)
+    }
+
+    pub fn ban(&mut self, ip: IpAddr) {
+        self.banned.insert(ip, Instant::now() + self.ban_duration);
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) {
+        self.banned.remove(ip);
+    }
+
+    // Expired bans are forgotten
+    pub fn is_banned(&mut self, ip: &IpAddr) -> bool {
+        match self.banned.get(ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.banned.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn inbound_count(&self) -> usize {
+        self.inbound.len()
+    }
+
+    pub fn outbound_count(&self) -> usize {
+        self.outbound.len()
+    }
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(last: u8, port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, last], port))
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let mut peers = PeerManager::with_limits(1, 2);
+        assert_eq!(peers.add(addr(1, 1), Direction::Inbound), Ok(()));
+        assert_eq!(
+            peers.add(addr(2, 1), Direction::Inbound),
+            Err(ConnectError::TooManyInbound)
+        );
+        assert_eq!(
+            peers.add(addr(1, 1), Direction::Outbound),
+            Err(ConnectError::AlreadyConnected)
+        );
+        assert_eq!(peers.add(addr(3, 1), Direction::Outbound), Ok(()));
+        assert_eq!(peers.add(addr(4, 1), Direction::Outbound), Ok(()));
+        assert_eq!(
+            peers.add(addr(5, 1), Direction::Outbound),
+            Err(ConnectError::TooManyOutbound)
+        );
+
+        peers.remove(&addr(1, 1));
+        assert_eq!(peers.add(addr(2, 1), Direction::Inbound), Ok(()));
+        assert_eq!((peers.inbound_count(), peers.outbound_count()), (1, 2));
+    }
+
+    #[test]
+    fn test_ban_scores() {
+        let mut peers = PeerManager::new();
+        peers.add(addr(1, 1), Direction::Inbound).unwrap();
+
+        assert!(!peers.misbehaving(addr(1, 1), Misbehavior::Stalling));
+        assert!(!peers.misbehaving(addr(1, 1), Misbehavior::MalformedMessage));
+        assert_eq!(peers.score(&addr(1, 1)), 70);
+        assert!(peers.misbehaving(addr(1, 1), Misbehavior::MalformedMessage));
+        peers.remove(&addr(1, 1));
+
+        // the ban covers every port of the address
+        assert_eq!(
+            peers.add(addr(1, 2), Direction::Inbound),
+            Err(ConnectError::Banned)
+        );
+        peers.unban(&addr(1, 1).ip());
+        assert_eq!(peers.add(addr(1, 2), Direction::Inbound), Ok(()));
+        assert_eq!(peers.score(&addr(1, 2)), 0);
+    }
+
+    #[test]
+    fn test_ban_expires() {
+        let mut peers = PeerManager::new();
+        peers.set_ban_duration(Duration::ZERO);
+        assert!(peers.misbehaving(addr(1, 1), Misbehavior::InvalidBlock));
+        assert!(!peers.is_banned(&addr(1, 1).ip()));
+    }
+}
@@ -0,0 +1,343 @@
+// Experimental proof of stake. Instead of searching for a nonce, a block's
+// producer is drawn by lot, every coin in the UTXO set the block builds on
+// being one ticket, and signs the block. Time is split into slots of
+// `slot_duration` seconds, and each slot has its own draw, seeded by the
+// parent block and the slot. A block must be in a later slot than its
+// parent, so a producer who is offline only delays the chain by a slot.
+// Until anything is staked, which on a new chain is until the first block,
+// any key may produce.
+//
+// The seal is kept in the coinbase data, so blocks have the same format as
+// under proof of work: a byte for the signature scheme, a byte for the
+// length of the public key, the key, then the signature. The signature
+// covers the block as it would be with no coinbase data, see seal_hash.
+//
+// Nothing stops a producer from signing two blocks for one slot, and
+// branches are chosen by work as under proof of work, which here just means
+// the longest one wins, so this is only for experiments.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::engine::ConsensusEngine;
+use crate::errors::{BlockValidationError, TransactionError};
+use crate::hash::Hash256;
+use crate::merkle;
+use crate::script;
+use crate::signature::{PrivateKey, SignatureScheme};
+use crate::utxo::UTXOSet;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProofOfStake {
+    pub slot_duration: u64,
+}
+
+impl ProofOfStake {
+    pub fn new(slot_duration: u64) -> ProofOfStake {
+        ProofOfStake {
+            slot_duration: slot_duration.max(1),
+        }
+    }
+
+    pub fn slot(&self, timestamp: u64) -> u64 {
+        timestamp / self.slot_duration
+    }
+
+    // The pkhash drawn to produce the block in `slot` on top of
+    // `prev_hash`, or None if nothing is staked
+    pub fn producer(&self, utxos: &UTXOSet, prev_hash: &Hash256, slot: u64) -> Option<[u8; 32]> {
+        let balances = utxos.balances();
+        let total: u128 = balances.values().map(|&value| value as u128).sum();
+        if total == 0 {
+            return None;
+        }
+        let seed: [u8; 32] = Sha256::new()
+            .chain_update(b"stake draw")
+            .chain_update(prev_hash.as_bytes())
+            .chain_update(slot.to_be_bytes())
+            .finalize()
+            .into();
+        let mut ticket = u128::from_be_bytes(seed[..16].try_into().unwrap()) % total;
+        balances.into_iter().find_map(|(pkhash, value)| {
+            if ticket < value as u128 {
+                return Some(pkhash);
+            }
+            ticket -= value as u128;
+            None
+        })
+    }
+
+    // The first slot after the tip's in which `pkhash` may produce, looking
+    // at most `limit` slots ahead
+    pub fn next_slot(&self, blockchain: &Blockchain, pkhash: [u8; 32], limit: u64) -> Option<u64> {
+        let tip = blockchain.get_block_by_height(blockchain.height()).unwrap();
+        let after = self.slot(tip.header.timestamp);
+        (after + 1..=after + limit).find(|&slot| {
+            self.producer(&blockchain.utxos, &tip.digest, slot)
+                .is_none_or(|producer| producer == pkhash)
+        })
+    }
+
+    // A sealed block for the current slot paying to `key`, or None if it
+    // isn't the key's turn
+    pub fn produce(
+        &self,
+        blockchain: &Blockchain,
+        key: &PrivateKey,
+    ) -> Result<Option<Block>, TransactionError> {
+        let pkhash = script::pubkey_hash(&key.public_key());
+        let tip = blockchain.get_block_by_height(blockchain.height()).unwrap();
+        let slot = self.slot(blockchain.clock().now());
+        if slot <= self.slot(tip.header.timestamp) {
+            return Ok(None);
+        }
+        if self
+            .producer(&blockchain.utxos, &tip.digest, slot)
+            .is_some_and(|producer| producer != pkhash)
+        {
+            return Ok(None);
+        }
+        let mut block = blockchain.create_block_template(pkhash)?;
+        seal(&mut block, key);
+        Ok(Some(block))
+    }
+}
+
+impl ConsensusEngine for ProofOfStake {
+    fn name(&self) -> &'static str {
+        "pos"
+    }
+
+    fn check_seal(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let (scheme, pubkey, signature) =
+            read_seal(block).ok_or(BlockValidationError::InvalidSeal)?;
+        scheme
+            .verify(pubkey, &seal_hash(block), &signature)
+            .map_err(|_| BlockValidationError::InvalidSeal)
+    }
+
+    fn check_producer(
+        &self,
+        blockchain: &Blockchain,
+        block: &Block,
+    ) -> Result<(), BlockValidationError> {
+        let parent = blockchain
+            .get_block_by_hash(&block.header.prev_hash)
+            .ok_or(BlockValidationError::InvalidPreviousHash)?;
+        let slot = self.slot(block.header.timestamp);
+        if slot <= self.slot(parent.header.timestamp) {
+            return Err(BlockValidationError::WrongProducer);
+        }
+        let (_, pubkey, _) = read_seal(block).ok_or(BlockValidationError::InvalidSeal)?;
+        match self.producer(&blockchain.utxos, &parent.digest, slot) {
+            Some(producer) if producer != script::pubkey_hash(pubkey) => {
+                Err(BlockValidationError::WrongProducer)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// What the producer signs: the header with the merkle root the block would
+// have if its coinbase had no data
+pub fn seal_hash(block: &Block) -> [u8; 32] {
+    let mut txids: Vec<[u8; 32]> = block.transactions.iter().map(|tx| tx.id.0).collect();
+    if let Some(coinbase) = block.transactions.first() {
+        let mut coinbase = coinbase.clone();
+        coinbase.inputs[0].pubkey.clear();
+        coinbase.update_id();
+        txids[0] = coinbase.id.0;
+    }
+    let mut header = block.header.clone();
+    header.merkle_root = merkle::merkle_root(&txids);
+    Sha256::new()
+        .chain_update(b"stake seal")
+        .chain_update(header.hash().as_bytes())
+        .finalize()
+        .into()
+}
+
+// Signs a block with its coinbase in place, replacing any coinbase data
+pub fn seal(block: &mut Block, key: &PrivateKey) {
+    let pubkey = key.public_key();
+    let signature = key.sign(&seal_hash(block));
+    let mut data = vec![scheme_byte(key.scheme()), pubkey.len() as u8];
+    data.extend_from_slice(&pubkey);
+    data.extend_from_slice(&signature);
+
+    let coinbase = &mut block.transactions[0];
+    coinbase.inputs[0].pubkey = data;
+    coinbase.update_id();
+    block.update_merkle_root();
+}
+
+fn read_seal(block: &Block) -> Option<(SignatureScheme, &[u8], [u8; 64])> {
+    let data = block.transactions.first()?.coinbase_data()?;
+    let (&[scheme, len], rest) = data.split_first_chunk()?;
+    let scheme = match scheme {
+        0 => SignatureScheme::Ecdsa,
+        1 => SignatureScheme::Ed25519,
+        2 => SignatureScheme::Schnorr,
+        _ => return None,
+    };
+    let (pubkey, signature) = rest.split_at_checked(len as usize)?;
+    Some((scheme, pubkey, signature.try_into().ok()?))
+}
+
+fn scheme_byte(scheme: SignatureScheme) -> u8 {
+    match scheme {
+        SignatureScheme::Ecdsa => 0,
+        SignatureScheme::Ed25519 => 1,
+        SignatureScheme::Schnorr => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::BlockAcceptance;
+    use crate::clock::MockClock;
+    use crate::hash::OutPoint;
+    use crate::params::ChainParams;
+    use crate::tx_builder::TxBuilder;
+    use crate::wallet::Wallet;
+    use std::sync::Arc;
+
+    fn key(seed: u8) -> PrivateKey {
+        PrivateKey::from_bytes(SignatureScheme::Ecdsa, &[seed; 32]).unwrap()
+    }
+
+    fn pkhash(key: &PrivateKey) -> [u8; 32] {
+        script::pubkey_hash(&key.public_key())
+    }
+
+    fn setup() -> (Blockchain, Arc<MockClock>, ProofOfStake) {
+        let params = ChainParams::regtest();
+        let clock = Arc::new(MockClock::new(params.genesis_timestamp));
+        let pos = ProofOfStake::new(10);
+        let mut blockchain = Blockchain::new(params);
+        blockchain.set_clock(clock.clone());
+        blockchain.set_engine(Arc::new(pos));
+        (blockchain, clock, pos)
+    }
+
+    // A block by `key` whether or not it is the key's turn
+    fn forced(blockchain: &Blockchain, key: &PrivateKey) -> Block {
+        let mut block = blockchain.create_block_template(pkhash(key)).unwrap();
+        seal(&mut block, key);
+        block
+    }
+
+    #[test]
+    fn test_only_the_drawn_key_produces() {
+        let (mut blockchain, clock, pos) = setup();
+        let (a, b) = (key(1), key(2));
+
+        // Nothing is staked yet, so anyone may produce
+        assert!(pos.produce(&blockchain, &a).unwrap().is_none());
+        clock.advance(10);
+        let block = pos.produce(&blockchain, &a).unwrap().unwrap();
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+
+        // A now holds all the stake
+        clock.advance(10);
+        assert!(pos.produce(&blockchain, &b).unwrap().is_none());
+        assert_eq!(
+            blockchain.add_block(forced(&blockchain, &b)),
+            Err(BlockValidationError::WrongProducer)
+        );
+        let block = pos.produce(&blockchain, &a).unwrap().unwrap();
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+
+        // One block per slot
+        assert!(pos.produce(&blockchain, &a).unwrap().is_none());
+        assert_eq!(
+            blockchain.add_block(forced(&blockchain, &a)),
+            Err(BlockValidationError::WrongProducer)
+        );
+        assert_eq!(blockchain.height(), 2);
+    }
+
+    #[test]
+    fn test_invalid_seal() {
+        let (mut blockchain, clock, pos) = setup();
+        clock.advance(10);
+
+        let unsealed = blockchain.create_block_template([1; 32]).unwrap();
+        assert_eq!(
+            blockchain.add_block(unsealed),
+            Err(BlockValidationError::InvalidSeal)
+        );
+
+        let mut block = pos.produce(&blockchain, &key(1)).unwrap().unwrap();
+        let coinbase = &mut block.transactions[0];
+        *coinbase.inputs[0].pubkey.last_mut().unwrap() ^= 1;
+        coinbase.update_id();
+        block.update_merkle_root();
+        assert_eq!(
+            pos.check_seal(&block),
+            Err(BlockValidationError::InvalidSeal)
+        );
+        assert_eq!(
+            blockchain.add_block(block),
+            Err(BlockValidationError::InvalidSeal)
+        );
+        assert_eq!(blockchain.height(), 0);
+    }
+
+    #[test]
+    fn test_draw_is_weighted_by_stake() {
+        let (mut blockchain, clock, pos) = setup();
+        let (a, b) = (key(1), key(2));
+        clock.advance(10);
+        let block = pos.produce(&blockchain, &a).unwrap().unwrap();
+        let coinbase = block.transactions[0].clone();
+        blockchain.add_block(block).unwrap();
+
+        // A gives B half its stake
+        let value = coinbase.outputs[0].value.0;
+        let wallet = Wallet::from_key(key(1), blockchain.chain_id());
+        let mut builder = TxBuilder::new(wallet.pkhash);
+        builder
+            .add_utxo(OutPoint::new(coinbase.id, 0), value)
+            .pay(value / 2, pkhash(&b))
+            .fee(1_000);
+        let tx = builder.build(&wallet).unwrap();
+        // The transaction is stamped with the real time
+        clock.set(crate::utils::unix_timestamp());
+        blockchain.submit_transaction(tx).unwrap();
+        let block = pos.produce(&blockchain, &a).unwrap().unwrap();
+        assert_eq!(block.transactions.len(), 2);
+        blockchain.add_block(block).unwrap();
+
+        // B has a quarter of the stake. The transaction's real timestamp
+        // makes the draws differ between runs, so only their share is
+        // checked.
+        let tip = blockchain.get_block_by_height(blockchain.height()).unwrap();
+        let after = pos.slot(tip.header.timestamp);
+        let drawn: Vec<[u8; 32]> = (after + 1..=after + 100)
+            .map(|slot| pos.producer(&blockchain.utxos, &tip.digest, slot).unwrap())
+            .collect();
+        let by_b = drawn.iter().filter(|&&drawn| drawn == pkhash(&b)).count();
+        assert!(by_b > 5 && by_b < 50);
+        assert_eq!(
+            drawn.iter().filter(|&&drawn| drawn == pkhash(&a)).count(),
+            100 - by_b
+        );
+
+        let slot = pos.next_slot(&blockchain, pkhash(&b), 100).unwrap();
+        clock.set(slot * pos.slot_duration);
+        assert!(pos.produce(&blockchain, &a).unwrap().is_none());
+        let block = pos.produce(&blockchain, &b).unwrap().unwrap();
+        assert_eq!(
+            blockchain.add_block(block),
+            Ok(BlockAcceptance::ConnectedToTip)
+        );
+    }
+}
@@ -0,0 +1,292 @@
+use crate::amount::Amount;
+use crate::errors::PsbtError;
+use crate::script::{self, HtlcSpend, KeySignature, Script};
+use crate::signature::{SignatureScheme, Signer};
+use crate::transaction::{Transaction, TxInput, TxOutput};
+use crate::utxo::UTXOSet;
+use bincode::{Decode, Encode};
+
+// An unsigned transaction passed between signers, e.g. the members of a
+// multisig or an offline machine holding the key. Each signer adds its
+// signatures to the metadata of the inputs, and once enough are collected
+// finalize() builds the signed transaction.
+#[derive(Encode, Decode, Clone)]
+pub struct PartialTransaction {
+    pub tx: Transaction,
+    pub inputs: Vec<PartialInput>,
+}
+
+#[derive(Encode, Decode, Clone, Default)]
+pub struct PartialInput {
+    // The output being spent, needed to know which keys can sign
+    pub utxo: Option<TxOutput>,
+    // The key set of a multisig output, in order
+    pub pubkeys: Vec<Vec<u8>>,
+    pub signatures: Vec<PartialSignature>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct PartialSignature {
+    pub pubkey: Vec<u8>,
+    pub scheme: SignatureScheme,
+    pub signature: [u8; 64],
+}
+
+// The pkhash of the single key spending `utxo`. For an HTLC it depends on
+// whether the input claims or refunds it, which the creator of the
+// transaction sets in its witness.
+fn signer_pkhash(utxo: &TxOutput, input: &TxInput) -> Option<[u8; 32]> {
+    match utxo.script {
+        Script::Htlc => input.witness.htlc.as_ref().map(HtlcSpend::signer),
+        _ => Some(utxo.pkhash),
+    }
+}
+
+impl PartialTransaction {
+    pub fn new(tx: Transaction) -> PartialTransaction {
+        let inputs = vec![PartialInput::default(); tx.inputs.len()];
+        PartialTransaction { tx, inputs }
+    }
+
+    // Fills in the spent outputs found in the set
+    pub fn fill_utxos(&mut self, utxos: &UTXOSet) {
+        for (input, meta) in self.tx.inputs.iter().zip(&mut self.inputs) {
+            if let Some(output) = utxos.get_utxo(&input.outpoint()) {
+                meta.utxo = Some(output.clone());
+            }
+        }
+    }
+
+    // Signs one input with a key able to spend it
+    pub fn add_signature(
+        &mut self,
+        input: usize,
+        signer: &(impl Signer + ?Sized),
+        chain_id: &[u8; 32],
+    ) -> Result<(), PsbtError> {
+        let meta = self
+            .inputs
+            .get_mut(input)
+            .ok_or(PsbtError::InputOutOfRange(input))?;
+        let utxo = meta.utxo.as_ref().ok_or(PsbtError::MissingUtxo(input))?;
+        // The signature commits to the value, so a wrong one would only
+        // make it invalid, but it may also hide what is really being spent
+        if self.tx.inputs[input].value != utxo.value {
+            return Err(PsbtError::ValueMismatch(input));
+        }
+        let pubkey = signer.public_key();
+        let mut signed = self.tx.clone();
+
+        let signature = match utxo.script {
+            Script::PayToPubkeyHash | Script::Timelock { .. } | Script::Htlc => {
+                if Some(script::pubkey_hash(&pubkey)) != signer_pkhash(utxo, &self.tx.inputs[input])
+                {
+                    return Err(PsbtError::KeyNotInvolved(input));
+                }
+                signed
+                    .sign_input(input, signer, chain_id)
+                    .map_err(PsbtError::Signer)?;
+                signed.inputs[input].signature
+            }
+            Script::Multisig { .. } => {
+                signed.inputs[input].witness.signatures.clear();
+                if !signed
+                    .sign_multisig_input(input, signer, &meta.pubkeys, chain_id)
+                    .map_err(PsbtError::Signer)?
+                {
+                    return Err(PsbtError::KeyNotInvolved(input));
+                }
+                signed.inputs[input].witness.signatures[0].signature
+            }
+            Script::AnyoneCanSpend => return Err(PsbtError::KeyNotInvolved(input)),
+        };
+
+        meta.signatures.retain(|s| s.pubkey != pubkey);
+        meta.signatures.push(PartialSignature {
+            pubkey,
+            scheme: signer.scheme(),
+            signature,
+        });
+        Ok(())
+    }
+
+    // Combines the metadata and signatures collected by another signer of
+    // the same transaction
+    pub fn merge(&mut self, other: PartialTransaction) -> Result<(), PsbtError> {
+        if self.tx.as_bincode() != other.tx.as_bincode() {
+            return Err(PsbtError::DifferentTransaction);
+        }
+
+        for (meta, other) in self.inputs.iter_mut().zip(other.inputs) {
+            if meta.utxo.is_none() {
+                meta.utxo = other.utxo;
+            }
+            if meta.pubkeys.is_empty() {
+                meta.pubkeys = other.pubkeys;
+            }
+            for signature in other.signatures {
+                if !meta.signatures.iter().any(|s| s.pubkey == signature.pubkey) {
+                    meta.signatures.push(signature);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Builds the signed transaction, failing if an input still lacks the
+    // signatures its output requires
+    pub fn finalize(&self) -> Result<Transaction, PsbtError> {
+        let mut tx = self.tx.clone();
+
+        for (index, (input, meta)) in tx.inputs.iter_mut().zip(&self.inputs).enumerate() {
+            let utxo = meta.utxo.as_ref().ok_or(PsbtError::MissingUtxo(index))?;
+
+            match utxo.script {
+                Script::PayToPubkeyHash | Script::Timelock { .. } | Script::Htlc => {
+                    let signer = signer_pkhash(utxo, input);
+                    let signature = meta
+                        .signatures
+                        .iter()
+                        .find(|s| Some(script::pubkey_hash(&s.pubkey)) == signer)
+                        .ok_or(PsbtError::MissingSignatures(index))?;
+                    input.scheme = signature.scheme;
+                    input.pubkey = signature.pubkey.clone();
+                    input.signature = signature.signature;
+                }
+                Script::Multisig { threshold } => {
+                    let mut signatures: Vec<KeySignature> = meta
+                        .signatures
+                        .iter()
+                        .filter_map(|s| {
+                            let key = meta.pubkeys.iter().position(|k| *k == s.pubkey)?;
+                            Some(KeySignature {
+                                key: u8::try_from(key).ok()?,
+                                signature: s.signature,
+                            })
+                        })
+                        .collect();
+                    if signatures.is_empty() || signatures.len() < threshold as usize {
+                        return Err(PsbtError::MissingSignatures(index));
+                    }
+                    signatures.sort_by_key(|s| s.key);
+
+                    input.scheme = meta.signatures[0].scheme;
+                    input.pubkey.clear();
+                    input.signature = [0; 64];
+                    input.witness.pubkeys = meta.pubkeys.clone();
+                    input.witness.signatures = signatures;
+                }
+                Script::AnyoneCanSpend => {}
+            }
+        }
+
+        tx.update_id();
+        Ok(tx)
+    }
+
+    // What the inputs spend less what the outputs pay, so a signer can see
+    // what it gives up before signing. None while a spent output is unknown.
+    pub fn fee(&self) -> Option<Amount> {
+        let spent = self
+            .inputs
+            .iter()
+            .map(|meta| meta.utxo.as_ref().map(|utxo| utxo.value))
+            .collect::<Option<Vec<Amount>>>()?;
+        let paid = self.tx.outputs.iter().map(|output| output.value);
+        Amount::checked_sum(spent)?.checked_sub(Amount::checked_sum(paid)?)
+    }
+
+    pub fn as_bincode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    pub fn from_bincode(data: &[u8]) -> Option<PartialTransaction> {
+        bincode::decode_from_slice(data, bincode::config::standard())
+            .ok()
+            .map(|(ptx, _)| ptx)
+    }
+
+    // Hex encoded bincode, to carry to and from a signer as text
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.as_bincode())
+    }
+
+    pub fn from_hex(data: &str) -> Option<PartialTransaction> {
+        PartialTransaction::from_bincode(&hex::decode(data.trim()).ok()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::OutPoint;
+    use crate::transaction::TxInput;
+    use crate::wallet::Wallet;
+
+    const CHAIN_ID: [u8; 32] = [3; 32];
+
+    fn spend(outputs: Vec<TxOutput>) -> (PartialTransaction, UTXOSet) {
+        let mut utxos = UTXOSet::new();
+        let mut inputs = Vec::new();
+        for (index, output) in outputs.into_iter().enumerate() {
+            inputs.push(TxInput::new_unsigned([index as u8; 32], 0).with_value(output.value));
+            utxos.add_utxo(OutPoint::new([index as u8; 32], 0), output);
+        }
+
+        let tx = Transaction::new(inputs, vec![TxOutput::new(10, [9; 32])]);
+        let mut ptx = PartialTransaction::new(tx);
+        ptx.fill_utxos(&utxos);
+        (ptx, utxos)
+    }
+
+    #[test]
+    fn test_sign_separately_and_merge() {
+        let alice = Wallet::for_chain(CHAIN_ID);
+        let signers: Vec<Wallet> = (0..3).map(|_| Wallet::for_chain(CHAIN_ID)).collect();
+        let pubkeys: Vec<Vec<u8>> = signers.iter().map(Wallet::public_key).collect();
+
+        let (mut ptx, utxos) = spend(vec![
+            TxOutput::new(50, alice.pkhash),
+            TxOutput::new_multisig(50, 2, &pubkeys),
+        ]);
+        ptx.inputs[1].pubkeys = pubkeys.clone();
+
+        // each signer works on its own copy, as if it were sent to them
+        let mut copies: Vec<PartialTransaction> = Vec::new();
+        for wallet in [&alice, &signers[0], &signers[2]] {
+            let mut copy = PartialTransaction::from_bincode(&ptx.as_bincode()).unwrap();
+            assert_eq!(wallet.sign_partial(&mut copy).unwrap(), 1);
+            copies.push(copy);
+        }
+
+        assert_eq!(ptx.finalize().err(), Some(PsbtError::MissingSignatures(0)));
+        ptx.merge(copies.remove(0)).unwrap();
+        ptx.merge(copies.remove(0)).unwrap();
+        assert_eq!(ptx.finalize().err(), Some(PsbtError::MissingSignatures(1)));
+        ptx.merge(copies.remove(0)).unwrap();
+
+        let tx = ptx.finalize().unwrap();
+        assert_eq!(tx.verify(&CHAIN_ID), Ok(()));
+        assert_eq!(utxos.validate_transaction(&tx, 1), Ok(Amount(90)));
+    }
+
+    #[test]
+    fn test_signer_not_involved() {
+        let wallet = Wallet::for_chain(CHAIN_ID);
+        let (mut ptx, _) = spend(vec![TxOutput::new(50, [1; 32])]);
+        assert_eq!(wallet.sign_partial(&mut ptx).unwrap(), 0);
+        assert!(ptx.inputs[0].signatures.is_empty());
+
+        // can't tell who may sign without the spent output
+        let mut unknown = PartialTransaction::new(ptx.tx.clone());
+        assert_eq!(wallet.sign_partial(&mut unknown).unwrap(), 0);
+        assert_eq!(unknown.finalize().err(), Some(PsbtError::MissingUtxo(0)));
+    }
+
+    #[test]
+    fn test_merge_different_transaction() {
+        let (mut ptx, _) = spend(vec![TxOutput::new(50, [1; 32])]);
+        let (other, _) = spend(vec![TxOutput::new(50, [1; 32]), TxOutput::new(50, [1; 32])]);
+        assert_eq!(ptx.merge(other), Err(PsbtError::DifferentTransaction));
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Instant;
+
+// Bytes a peer may send per second on average, and in a burst. The burst
+// fits the largest message.
+pub const MAX_BYTES_PER_SEC: f64 = 4.0 * 1024.0 * 1024.0;
+pub const MAX_BYTES_BURST: f64 = 40.0 * 1024.0 * 1024.0;
+pub const MAX_MESSAGES_PER_SEC: f64 = 200.0;
+pub const MAX_MESSAGES_BURST: f64 = 1000.0;
+
+// Refills at `rate` per second up to `capacity`
+struct TokenBucket {
+    tokens: f64,
+    capacity: f64,
+    rate: f64,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64) -> TokenBucket {
+        TokenBucket {
+            tokens: capacity,
+            capacity,
+            rate,
+        }
+    }
+
+    fn refill(&mut self, elapsed: f64) {
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+    }
+}
+
+// What a peer sent us, and whether it is sending faster than allowed
+pub struct RateLimiter {
+    bytes: TokenBucket,
+    messages: TokenBucket,
+    last_update: Instant,
+    pub bytes_received: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter::with_rates(
+            MAX_BYTES_PER_SEC,
+            MAX_BYTES_BURST,
+            MAX_MESSAGES_PER_SEC,
+            MAX_MESSAGES_BURST,
+        )
+    }
+
+    pub fn with_rates(
+        bytes_per_sec: f64,
+        bytes_burst: f64,
+        messages_per_sec: f64,
+        messages_burst: f64,
+    ) -> RateLimiter {
+        RateLimiter {
+            bytes: TokenBucket::new(bytes_per_sec, bytes_burst),
+            messages: TokenBucket::new(messages_per_sec, messages_burst),
+            last_update: Instant::now(),
+            bytes_received: 0,
+            messages_received: 0,
+            bytes_sent: 0,
+        }
+    }
+
+    // Accounts for a message of `size` bytes received at `now`. Returns
+    // false if the peer is over either limit, in which case the message
+    // should be dropped.
+    pub fn received(&mut self, size: usize, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_update)
+            .as_secs_f64();
+        self.last_update = self.last_update.max(now);
+        self.bytes.refill(elapsed);
+        self.messages.refill(elapsed);
+
+        self.bytes_received += size as u64;
+        self.messages_received += 1;
+        self.bytes.tokens -= size as f64;
+        self.messages.tokens -= 1.0;
+        self.bytes.tokens >= 0.0 && self.messages.tokens >= 0.0
+    }
+
+    pub fn sent(&mut self, size: usize) {
+        self.bytes_sent += size as u64;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_rate_limits() {
+        let mut limiter = RateLimiter::with_rates(100.0, 200.0, 1.0, 2.0);
+        let start = Instant::now();
+        assert!(limiter.received(150, start));
+        // too many bytes
+        assert!(!limiter.received(100, start));
+
+        // the overdraft is paid back before more is allowed
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.received(10, later));
+        // too many messages
+        assert!(!limiter.received(10, later));
+        assert!(limiter.received(10, later + Duration::from_secs(2)));
+
+        assert_eq!(limiter.bytes_received, 280);
+        assert_eq!(limiter.messages_received, 5);
+    }
+}
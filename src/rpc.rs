@@ -0,0 +1,1115 @@
+// JSON-RPC 2.0 over HTTP for controlling a running node. Each connection
+// carries a single POST whose body is the request, and is closed once the
+// response has been written.
+//
+// A GET upgrading to a WebSocket on /ws instead streams new blocks and
+// mempool transactions as JSON. Adding `?address=...` (repeatable, an
+// address or hex pkhash) limits them to the ones touching those addresses.
+// Other GETs under /explorer serve the block explorer.
+//
+// Wallet methods act on one of the wallets the node has loaded, named by
+// posting to /wallet/<name>. A POST to / works with the only wallet loaded.
+//
+// POSTs must carry the server's credentials as HTTP Basic auth and a
+// Content-Type of application/json. A browser may add credentials it
+// remembers by itself, but a page from another origin can't set that
+// Content-Type without a preflight the server never answers, so it can't
+// call methods through the user's browser. The explorer and the WebSocket
+// only read, and are served to anyone.
+
+use crate::address::Address;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::errors::{InvoiceError, RpcError, TransactionError, WalletManagerError};
+use crate::events::ChainEvent;
+use crate::explorer;
+use crate::fees::DEFAULT_FEE_TARGET;
+use crate::hash::{Hash256, TxId};
+use crate::invoice::{Invoice, InvoiceBook, InvoiceStatus};
+use crate::json::{self, Json};
+use crate::network::Node;
+use crate::script;
+use crate::transaction::Transaction;
+use crate::tx_builder::TxBuilder;
+use crate::wallet::Wallet;
+use crate::wallet_manager::LoadedWallet;
+use crate::websocket::{self, OPCODE_CLOSE, OPCODE_PING, OPCODE_PONG, OPCODE_TEXT};
+use base64ct::{Base64, Encoding};
+use crypto_bigint::subtle::ConstantTimeEq;
+use rand_core::RngCore;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Standard JSON-RPC error codes
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// Application errors, numbered as in bitcoind
+pub const NOT_FOUND: i64 = -5;
+pub const DESERIALIZATION_ERROR: i64 = -22;
+pub const TRANSACTION_REJECTED: i64 = -26;
+pub const WALLET_ERROR: i64 = -4;
+pub const WALLET_NOT_FOUND: i64 = -18;
+pub const WALLET_NOT_SPECIFIED: i64 = -19;
+
+// Seconds an invoice stays open unless createinvoice is given another expiry
+pub const DEFAULT_INVOICE_EXPIRY: u64 = 60 * 60;
+
+const MAX_REQUEST_SIZE: usize = 4 * 1024 * 1024;
+const MAX_HEADER_LINES: usize = 64;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+pub const WEBSOCKET_PATH: &str = "/ws";
+pub const WALLET_PATH: &str = "/wallet/";
+// How often a WebSocket connection checks whether the client has left
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// User name of the credentials in a cookie file, as bitcoind writes it
+pub const COOKIE_USER: &str = "__cookie__";
+
+type MethodResult = Result<Json, (i64, String)>;
+
+fn invalid_params(message: &str) -> (i64, String) {
+    (INVALID_PARAMS, message.to_string())
+}
+
+fn hash_param(param: &Json) -> Option<Hash256> {
+    param.as_str()?.parse().ok()
+}
+
+// Takes an address or a hex pkhash
+fn parse_address(address: &str) -> Option<[u8; 32]> {
+    Address::decode(address)
+        .ok()
+        .map(|address| address.pkhash)
+        .or_else(|| hex::decode(address).ok()?.try_into().ok())
+}
+
+// Takes either a height or a hex block hash
+fn block_param<'a>(
+    blockchain: &'a Blockchain,
+    params: &[Json],
+) -> Result<&'a Block, (i64, String)> {
+    let block = match params.first() {
+        Some(Json::U64(height)) => blockchain.get_block_by_height(*height),
+        Some(param @ Json::String(_)) => {
+            let hash = hash_param(param).ok_or(invalid_params("invalid block hash"))?;
+            blockchain.get_block_by_hash(&hash)
+        }
+        _ => return Err(invalid_params("expected a block height or hash")),
+    };
+    block.ok_or((NOT_FOUND, "block not found".to_string()))
+}
+
+fn sync_invoices(invoices: &mut InvoiceBook, blockchain: &Blockchain) -> Result<(), (i64, String)> {
+    invoices
+        .sync(blockchain)
+        .map_err(|e| (WALLET_ERROR, e.to_string()))
+}
+
+// The invoice with where it's at, and a payment request URI for it
+fn invoice_json(invoice: &Invoice, invoices: &InvoiceBook, blockchain: &Blockchain) -> Json {
+    let (status, received) = invoices
+        .status(invoice.id, blockchain, blockchain.clock().now())
+        .unwrap();
+    let Json::Object(mut fields) = json::to_value(invoice).unwrap() else {
+        unreachable!()
+    };
+    fields.extend([
+        (
+            "address".to_string(),
+            Json::String(Address::from(invoice.pkhash).to_string()),
+        ),
+        (
+            "uri".to_string(),
+            Json::String(invoice.payment_request().encode()),
+        ),
+        ("status".to_string(), Json::String(status.to_string())),
+        ("received".to_string(), Json::U64(received.0)),
+    ]);
+    if let InvoiceStatus::Confirming { confirmations } = status {
+        fields.push(("confirmations".to_string(), Json::U64(confirmations)));
+    }
+    Json::Object(fields)
+}
+
+fn wallet_error(e: WalletManagerError) -> (i64, String) {
+    let code = match e {
+        WalletManagerError::NotLoaded(_) | WalletManagerError::NoWallets => WALLET_NOT_FOUND,
+        WalletManagerError::NotSpecified => WALLET_NOT_SPECIFIED,
+        WalletManagerError::InvalidName(_) => INVALID_PARAMS,
+        _ => WALLET_ERROR,
+    };
+    (code, e.to_string())
+}
+
+fn wallet_json(loaded: &LoadedWallet) -> Json {
+    Json::Object(vec![
+        ("name".to_string(), Json::String(loaded.name.clone())),
+        (
+            "address".to_string(),
+            Json::String(loaded.wallet.address.to_string()),
+        ),
+    ])
+}
+
+fn string_param<'a>(
+    params: &'a [Json],
+    index: usize,
+    what: &str,
+) -> Result<&'a str, (i64, String)> {
+    params
+        .get(index)
+        .and_then(Json::as_str)
+        .ok_or_else(|| invalid_params(&format!("expected {what}")))
+}
+
+fn address_param(params: &[Json]) -> Result<[u8; 32], (i64, String)> {
+    params
+        .first()
+        .ok_or(invalid_params("expected an address"))?
+        .as_str()
+        .and_then(parse_address)
+        .ok_or(invalid_params("invalid address"))
+}
+
+// A transaction touches a pkhash by paying to it or by spending with the key
+// hashing to it
+fn touches(tx: &Transaction, pkhashes: &[[u8; 32]]) -> bool {
+    tx.outputs
+        .iter()
+        .any(|output| pkhashes.contains(&output.pkhash))
+        || tx.inputs.iter().any(|input| {
+            !input.pubkey.is_empty() && pkhashes.contains(&script::pubkey_hash(&input.pubkey))
+        })
+}
+
+// The user name and password a client has to send. A node not given any
+// makes up a password on each start and writes both to a cookie file in its
+// data directory, so only local users able to read that file can call it.
+#[derive(Clone, PartialEq)]
+pub struct RpcAuth {
+    pub user: String,
+    pub password: String,
+}
+
+impl RpcAuth {
+    pub fn new(user: impl Into<String>, password: impl Into<String>) -> RpcAuth {
+        RpcAuth {
+            user: user.into(),
+            password: password.into(),
+        }
+    }
+
+    pub fn random() -> RpcAuth {
+        let mut password = [0u8; 32];
+        rand_core::OsRng.fill_bytes(&mut password);
+        RpcAuth::new(COOKIE_USER, hex::encode(password))
+    }
+
+    // Written as `user:password`, readable only by its owner on Unix
+    pub fn write_cookie(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        // A leftover file would keep its permissions
+        let _ = fs::remove_file(path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(path)?;
+        write!(file, "{}:{}", self.user, self.password)
+    }
+
+    pub fn read_cookie(path: impl AsRef<Path>) -> io::Result<RpcAuth> {
+        let text = fs::read_to_string(path)?;
+        let (user, password) = text
+            .trim_end()
+            .split_once(':')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid cookie file"))?;
+        Ok(RpcAuth::new(user, password))
+    }
+
+    fn header(&self) -> String {
+        let credentials = format!("{}:{}", self.user, self.password);
+        let mut buf = vec![0u8; Base64::encoded_len(credentials.as_bytes())];
+        format!(
+            "Basic {}",
+            Base64::encode(credentials.as_bytes(), &mut buf).unwrap()
+        )
+    }
+
+    // Compared in constant time, so how long it takes says nothing about the
+    // password
+    fn accepts(&self, header: Option<&str>) -> bool {
+        header.is_some_and(|header| bool::from(header.as_bytes().ct_eq(self.header().as_bytes())))
+    }
+}
+
+// Leaves the password out of logs
+impl fmt::Debug for RpcAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RpcAuth")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+// Only application/json, with or without parameters such as the charset
+fn is_json(content_type: Option<&str>) -> bool {
+    content_type.is_some_and(|content_type| {
+        let media_type = content_type.split(';').next().unwrap_or("");
+        media_type.trim().eq_ignore_ascii_case("application/json")
+    })
+}
+
+#[derive(Clone)]
+pub struct RpcServer {
+    node: Node,
+    auth: RpcAuth,
+}
+
+impl RpcServer {
+    pub fn new(node: Node, auth: RpcAuth) -> RpcServer {
+        RpcServer { node, auth }
+    }
+
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = server.clone();
+                thread::spawn(move || server.handle_connection(stream));
+            }
+        });
+
+        Ok(local_addr)
+    }
+
+    fn handle_connection(&self, mut stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+
+        let response = match read_http_request(&mut stream) {
+            Ok(request) if request.method == "GET" => {
+                let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+                if let Some(response) = explorer::handle(&self.node, path) {
+                    let _ = write_http_response(
+                        &mut stream,
+                        response.status,
+                        response.content_type,
+                        &response.body,
+                    );
+                    return;
+                }
+                let filter: Option<Vec<[u8; 32]>> = query
+                    .split('&')
+                    .filter(|pair| !pair.is_empty())
+                    .map(|pair| match pair.split_once('=') {
+                        Some(("address", address)) => parse_address(address),
+                        _ => None,
+                    })
+                    .collect();
+
+                match (request.header("sec-websocket-key"), filter) {
+                    (Some(key), Some(filter)) if path == WEBSOCKET_PATH => {
+                        let key = key.to_string();
+                        self.handle_websocket(stream, &key, filter);
+                        return;
+                    }
+                    (_, None) => ("400 Bad Request", String::new()),
+                    _ => ("404 Not Found", String::new()),
+                }
+            }
+            Ok(request) if !self.auth.accepts(request.header("authorization")) => {
+                ("401 Unauthorized", String::new())
+            }
+            Ok(request) if !is_json(request.header("content-type")) => {
+                ("415 Unsupported Media Type", String::new())
+            }
+            Ok(request) => {
+                let wallet = request.path.strip_prefix(WALLET_PATH);
+                let response = match std::str::from_utf8(&request.body)
+                    .ok()
+                    .and_then(|body| body.parse::<Json>().ok())
+                {
+                    Some(request) => self.handle_wallet_request(&request, wallet),
+                    None => error_response(Json::Null, PARSE_ERROR, "parse error"),
+                };
+                ("200 OK", response.to_string())
+            }
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                ("405 Method Not Allowed", String::new())
+            }
+            Err(_) => ("400 Bad Request", String::new()),
+        };
+
+        let _ = write_http_response(&mut stream, response.0, "application/json", &response.1);
+    }
+
+    // Pushes events until the client closes the connection. Client frames
+    // are read on a second thread, which answers pings and notices the
+    // close.
+    fn handle_websocket(&self, stream: TcpStream, key: &str, filter: Vec<[u8; 32]>) {
+        let events = self.node.blockchain().subscribe();
+        let (Ok(mut reader), Ok(writer)) = (stream.try_clone(), stream.try_clone()) else {
+            return;
+        };
+        let writer = Arc::new(Mutex::new(writer));
+        if websocket::write_handshake_response(&mut *writer.lock().unwrap(), key).is_err() {
+            return;
+        }
+
+        let closed = Arc::new(AtomicBool::new(false));
+        let _ = reader.set_read_timeout(None);
+        {
+            let writer = writer.clone();
+            let closed = closed.clone();
+            thread::spawn(move || {
+                loop {
+                    let reply = match websocket::read_frame(&mut reader) {
+                        Ok((OPCODE_PING, payload)) => Some((OPCODE_PONG, payload)),
+                        Ok((OPCODE_CLOSE, _)) | Err(_) => None,
+                        Ok(_) => continue,
+                    };
+                    let mut writer = writer.lock().unwrap();
+                    match reply {
+                        Some((opcode, payload)) => {
+                            let _ = websocket::write_frame(&mut *writer, opcode, &payload, None);
+                        }
+                        None => {
+                            let _ = websocket::write_frame(&mut *writer, OPCODE_CLOSE, &[], None);
+                            let _ = writer.shutdown(Shutdown::Both);
+                            closed.store(true, Ordering::Relaxed);
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+
+        while !closed.load(Ordering::Relaxed) {
+            let event = match events.recv_timeout(EVENT_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let Some(message) = self.event_message(&event, &filter) else {
+                continue;
+            };
+
+            let mut writer = writer.lock().unwrap();
+            let text = message.to_string();
+            if websocket::write_frame(&mut *writer, OPCODE_TEXT, text.as_bytes(), None).is_err() {
+                let _ = writer.shutdown(Shutdown::Both);
+                break;
+            }
+        }
+    }
+
+    // The JSON pushed for new blocks and mempool transactions, or None for
+    // other events and those not passing the filter
+    fn event_message(&self, event: &ChainEvent, filter: &[[u8; 32]]) -> Option<Json> {
+        let blockchain = self.node.blockchain();
+        let (kind, key, value) = match event {
+            ChainEvent::BlockConnected { digest, .. } => {
+                let block = blockchain.get_block_by_hash(digest)?;
+                if !filter.is_empty() && !block.transactions.iter().any(|tx| touches(tx, filter)) {
+                    return None;
+                }
+                ("block", "block", json::to_value(block).ok()?)
+            }
+            ChainEvent::TxAccepted(txid) => {
+                let tx = &blockchain.mempool.get(txid)?.tx;
+                if !filter.is_empty() && !touches(tx, filter) {
+                    return None;
+                }
+                ("transaction", "transaction", json::to_value(tx).ok()?)
+            }
+            _ => return None,
+        };
+
+        Some(Json::Object(vec![
+            ("type".to_string(), Json::String(kind.to_string())),
+            (key.to_string(), value),
+        ]))
+    }
+
+    pub fn handle_request(&self, request: &Json) -> Json {
+        self.handle_wallet_request(request, None)
+    }
+
+    // With wallet methods acting on the wallet named, as for a POST to
+    // /wallet/<name>
+    pub fn handle_wallet_request(&self, request: &Json, wallet: Option<&str>) -> Json {
+        let id = request.get("id").cloned().unwrap_or(Json::Null);
+
+        let Some(method) = request.get("method").and_then(Json::as_str) else {
+            return error_response(id, INVALID_REQUEST, "missing method");
+        };
+        let params = match request.get("params") {
+            None => &Vec::new(),
+            Some(params) => match params.as_array() {
+                Some(params) => params,
+                None => return error_response(id, INVALID_PARAMS, "params must be an array"),
+            },
+        };
+
+        match self.dispatch(method, params, wallet) {
+            Ok(result) => Json::Object(vec![
+                ("jsonrpc".to_string(), Json::String("2.0".to_string())),
+                ("result".to_string(), result),
+                ("id".to_string(), id),
+            ]),
+            Err((code, message)) => error_response(id, code, &message),
+        }
+    }
+
+    fn dispatch(&self, method: &str, params: &[Json], wallet: Option<&str>) -> MethodResult {
+        match method {
+            "getblockcount" => Ok(Json::U64(self.node.blockchain().height())),
+            "getblock" => self.get_block(params),
+            "getblockmeta" => self.get_block_meta(params),
+            "createinvoice" => self.create_invoice(params),
+            "getinvoice" => self.get_invoice(params),
+            "listinvoices" => self.list_invoices(),
+            "getbalance" => self.get_balance(params, wallet),
+            "listwallets" => Ok(Json::Array(
+                self.node
+                    .wallets()
+                    .names()
+                    .map(|name| Json::String(name.to_string()))
+                    .collect(),
+            )),
+            "createwallet" => self.create_wallet(params),
+            "loadwallet" => self.load_wallet(params),
+            "unloadwallet" => self.unload_wallet(params, wallet),
+            "getwalletinfo" => self.get_wallet_info(wallet),
+            "sendtoaddress" => self.send_to_address(params, wallet),
+            "listunspent" => self.list_unspent(params),
+            "sendrawtransaction" => self.send_raw_transaction(params),
+            "getconfirmations" => self.get_confirmations(params),
+            "estimatefee" => {
+                let target_blocks = match params.first() {
+                    Some(Json::U64(target_blocks)) if *target_blocks > 0 => *target_blocks,
+                    None => DEFAULT_FEE_TARGET,
+                    _ => return Err(invalid_params("expected a number of blocks")),
+                };
+                let fee_rate = self.node.blockchain().estimate_fee_rate(target_blocks);
+                Ok(Json::U64(fee_rate))
+            }
+            "getchaininfo" => {
+                let blockchain = self.node.blockchain();
+                let network = blockchain.params().network.to_string();
+                let chain_id = hex::encode(blockchain.chain_id());
+                let Json::Object(mut fields) = json::to_value(&blockchain.stats()).unwrap() else {
+                    unreachable!()
+                };
+                fields.insert(0, ("network".to_string(), Json::String(network)));
+                // What transactions for this chain are signed over
+                fields.insert(1, ("chain_id".to_string(), Json::String(chain_id)));
+                Ok(Json::Object(fields))
+            }
+            "getmininginfo" => {
+                let blockchain = self.node.blockchain();
+                let stats = self.node.mining_stats();
+                let target = blockchain.params().target();
+                let expected_time = stats
+                    .expected_time_to_block(&target)
+                    .map_or(Json::Null, |time| Json::F64(time.as_secs_f64()));
+                Ok(Json::Object(vec![
+                    ("blocks".to_string(), Json::U64(blockchain.height())),
+                    (
+                        "bits".to_string(),
+                        Json::U64(blockchain.expected_bits(blockchain.height() + 1).into()),
+                    ),
+                    ("target".to_string(), Json::String(hex::encode(target))),
+                    ("mining".to_string(), Json::Bool(stats.is_mining())),
+                    ("hashespersec".to_string(), Json::F64(stats.hash_rate())),
+                    ("attempts".to_string(), Json::U64(stats.attempts())),
+                    ("blocksfound".to_string(), Json::U64(stats.blocks_found())),
+                    ("expectedtime".to_string(), expected_time),
+                ]))
+            }
+            "getmempoolinfo" => {
+                let blockchain = self.node.blockchain();
+                Ok(Json::Object(vec![
+                    (
+                        "size".to_string(),
+                        Json::U64(blockchain.mempool.len() as u64),
+                    ),
+                    (
+                        "fees".to_string(),
+                        Json::U64(blockchain.mempool.total_fees()),
+                    ),
+                    (
+                        "bytes".to_string(),
+                        Json::U64(blockchain.mempool.total_size() as u64),
+                    ),
+                    (
+                        "maxsize".to_string(),
+                        Json::U64(blockchain.mempool.max_size() as u64),
+                    ),
+                    (
+                        "minrelayfeerate".to_string(),
+                        Json::U64(blockchain.mempool.min_relay_fee_rate()),
+                    ),
+                ]))
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {method}"))),
+        }
+    }
+
+    fn get_block(&self, params: &[Json]) -> MethodResult {
+        let blockchain = self.node.blockchain();
+        let block = block_param(&blockchain, params)?;
+        json::to_value(block).map_err(|e| invalid_params(&e.to_string()))
+    }
+
+    // What this node saw of a block as it came in, which other nodes may
+    // have seen differently
+    fn get_block_meta(&self, params: &[Json]) -> MethodResult {
+        let digest = block_param(&self.node.blockchain(), params)?.digest;
+        let block_meta = self.node.block_meta();
+        let meta = block_meta
+            .get(&digest)
+            .ok_or((NOT_FOUND, "no metadata for block".to_string()))?;
+        let Json::Object(mut fields) = json::to_value(meta).unwrap() else {
+            unreachable!()
+        };
+        fields.insert(0, ("hash".to_string(), Json::String(digest.to_string())));
+        Ok(Json::Object(fields))
+    }
+
+    // Of an address, or of the wallet without one
+    fn get_balance(&self, params: &[Json], wallet: Option<&str>) -> MethodResult {
+        if params.is_empty() {
+            let blockchain = self.node.blockchain();
+            let mut wallets = self.node.wallets();
+            wallets.sync(&blockchain);
+            let loaded = wallets.select(wallet).map_err(wallet_error)?;
+            return Ok(Json::U64(loaded.state.balance()));
+        }
+        let pkhash = address_param(params)?;
+        Ok(Json::U64(self.node.blockchain().utxos.balance(pkhash)))
+    }
+
+    // Takes a name and a passphrase for the wallet file, and returns the
+    // recovery phrase of the new HD wallet
+    fn create_wallet(&self, params: &[Json]) -> MethodResult {
+        let name = string_param(params, 0, "a wallet name")?;
+        let passphrase = match params.get(1) {
+            Some(Json::String(passphrase)) => passphrase.as_str(),
+            None => "",
+            _ => return Err(invalid_params("expected a passphrase")),
+        };
+
+        let (wallet, phrase) = Wallet::generate_mnemonic(self.node.blockchain().chain_id());
+        let mut wallets = self.node.wallets();
+        let loaded = wallets
+            .create(name, wallet, passphrase)
+            .map_err(wallet_error)?;
+        let Json::Object(mut fields) = wallet_json(loaded) else {
+            unreachable!()
+        };
+        fields.push(("mnemonic".to_string(), Json::String(phrase)));
+        Ok(Json::Object(fields))
+    }
+
+    fn load_wallet(&self, params: &[Json]) -> MethodResult {
+        let name = string_param(params, 0, "a wallet name")?;
+        let passphrase = string_param(params, 1, "a passphrase")?;
+        let mut wallets = self.node.wallets();
+        let loaded = wallets.load(name, passphrase).map_err(wallet_error)?;
+        Ok(wallet_json(loaded))
+    }
+
+    // Takes the name, unless the call is routed to the wallet
+    fn unload_wallet(&self, params: &[Json], wallet: Option<&str>) -> MethodResult {
+        let name = match params.first() {
+            Some(_) => Some(string_param(params, 0, "a wallet name")?),
+            None => wallet,
+        };
+        let mut wallets = self.node.wallets();
+        let name = wallets.select(name).map_err(wallet_error)?.name.clone();
+        wallets.unload(&name);
+        Ok(Json::Null)
+    }
+
+    fn get_wallet_info(&self, wallet: Option<&str>) -> MethodResult {
+        let blockchain = self.node.blockchain();
+        let mut wallets = self.node.wallets();
+        wallets.sync(&blockchain);
+        let loaded = wallets.select(wallet).map_err(wallet_error)?;
+        let Json::Object(mut fields) = wallet_json(loaded) else {
+            unreachable!()
+        };
+        fields.extend([
+            ("balance".to_string(), Json::U64(loaded.state.balance())),
+            (
+                "spendable".to_string(),
+                Json::U64(loaded.state.spendable().len() as u64),
+            ),
+            (
+                "pending".to_string(),
+                Json::U64(loaded.state.pending().count() as u64),
+            ),
+            (
+                "syncedheight".to_string(),
+                Json::U64(loaded.state.synced_height()),
+            ),
+        ]);
+        Ok(Json::Object(fields))
+    }
+
+    // Takes the address to pay, the amount in base units, and optionally the
+    // blocks to confirm within, which sets the fee rate. Returns the txid.
+    fn send_to_address(&self, params: &[Json], wallet: Option<&str>) -> MethodResult {
+        let recipient = address_param(params)?;
+        let amount = match params.get(1) {
+            Some(Json::U64(amount)) => *amount,
+            _ => return Err(invalid_params("expected an amount")),
+        };
+        let target_blocks = match params.get(2) {
+            Some(Json::U64(target_blocks)) if *target_blocks > 0 => *target_blocks,
+            None => DEFAULT_FEE_TARGET,
+            _ => return Err(invalid_params("expected a number of blocks")),
+        };
+
+        let (name, tx) = {
+            let blockchain = self.node.blockchain();
+            let mut wallets = self.node.wallets();
+            wallets.sync(&blockchain);
+            let loaded = wallets.select(wallet).map_err(wallet_error)?;
+            let tx = TxBuilder::new(loaded.wallet.pkhash)
+                .add_spendable(&loaded.state)
+                .pay(amount, recipient)
+                .fee_rate(blockchain.estimate_fee_rate(target_blocks))
+                .build_and_lock(&loaded.wallet, &mut loaded.state)
+                .map_err(|e| (WALLET_ERROR, e.to_string()))?;
+            (loaded.name.clone(), tx)
+        };
+
+        // The blockchain is locked again to submit, so the wallets can't be
+        // held meanwhile
+        let txid = tx.id;
+        let submitted = self.node.submit_transaction(tx.clone());
+        if let Some(loaded) = self.node.wallets().get_mut(&name) {
+            match submitted {
+                Ok(_) => loaded.state.add_pending(tx),
+                Err(_) => {
+                    for input in &tx.inputs {
+                        loaded.state.unlock_unspent(&input.outpoint());
+                    }
+                }
+            }
+        }
+        submitted.map_err(|e| (TRANSACTION_REJECTED, e.for_tx(txid).to_string()))?;
+        Ok(Json::String(txid.to_string()))
+    }
+
+    fn list_unspent(&self, params: &[Json]) -> MethodResult {
+        let pkhash = address_param(params)?;
+        let blockchain = self.node.blockchain();
+
+        let mut outpoints = blockchain.utxos.utxos_from_pkhash(pkhash);
+        outpoints.sort();
+        let unspent = outpoints
+            .into_iter()
+            .map(|outpoint| {
+                let value = blockchain.utxos.get_utxo(&outpoint).unwrap().value;
+                Json::Object(vec![
+                    ("txid".to_string(), Json::String(outpoint.txid.to_string())),
+                    ("index".to_string(), Json::U64(outpoint.index as u64)),
+                    ("value".to_string(), Json::U64(value.0)),
+                ])
+            })
+            .collect();
+        Ok(Json::Array(unspent))
+    }
+
+    // 0 for a transaction in the mempool
+    fn get_confirmations(&self, params: &[Json]) -> MethodResult {
+        let txid: TxId = params
+            .first()
+            .and_then(Json::as_str)
+            .and_then(|txid| txid.parse().ok())
+            .ok_or(invalid_params("expected a txid"))?;
+        let confirmations = self
+            .node
+            .blockchain()
+            .confirmations(&txid)
+            .ok_or((NOT_FOUND, "transaction not found".to_string()))?;
+        Ok(Json::U64(confirmations))
+    }
+
+    // Takes the address to be paid, the amount in base units, and optionally
+    // the seconds until it expires and a memo
+    fn create_invoice(&self, params: &[Json]) -> MethodResult {
+        let pkhash = address_param(params)?;
+        let amount = match params.get(1) {
+            Some(Json::U64(amount)) => *amount,
+            _ => return Err(invalid_params("expected an amount")),
+        };
+        let expiry = match params.get(2) {
+            Some(Json::U64(expiry)) => *expiry,
+            None => DEFAULT_INVOICE_EXPIRY,
+            _ => return Err(invalid_params("expected the seconds until it expires")),
+        };
+        let memo = match params.get(3) {
+            Some(Json::String(memo)) => memo.as_str(),
+            None => "",
+            _ => return Err(invalid_params("expected a memo")),
+        };
+
+        let blockchain = self.node.blockchain();
+        let mut invoices = self.node.invoices();
+        sync_invoices(&mut invoices, &blockchain)?;
+        let expiry = blockchain.clock().now().saturating_add(expiry);
+        let invoice = invoices
+            .create(&blockchain, pkhash, amount, expiry, memo)
+            .map_err(|e| match e {
+                InvoiceError::InvalidAmount => invalid_params(&e.to_string()),
+                e => (WALLET_ERROR, e.to_string()),
+            })?;
+        Ok(invoice_json(&invoice, &invoices, &blockchain))
+    }
+
+    fn get_invoice(&self, params: &[Json]) -> MethodResult {
+        let id = match params.first() {
+            Some(Json::U64(id)) => *id,
+            _ => return Err(invalid_params("expected an invoice id")),
+        };
+        let blockchain = self.node.blockchain();
+        let mut invoices = self.node.invoices();
+        sync_invoices(&mut invoices, &blockchain)?;
+        let invoice = invoices
+            .get(id)
+            .ok_or((NOT_FOUND, "invoice not found".to_string()))?;
+        Ok(invoice_json(invoice, &invoices, &blockchain))
+    }
+
+    fn list_invoices(&self) -> MethodResult {
+        let blockchain = self.node.blockchain();
+        let mut invoices = self.node.invoices();
+        sync_invoices(&mut invoices, &blockchain)?;
+        Ok(Json::Array(
+            invoices
+                .iter()
+                .map(|invoice| invoice_json(invoice, &invoices, &blockchain))
+                .collect(),
+        ))
+    }
+
+    // Takes a hex encoded bincode transaction and returns its txid
+    fn send_raw_transaction(&self, params: &[Json]) -> MethodResult {
+        let raw = params
+            .first()
+            .and_then(Json::as_str)
+            .ok_or(invalid_params("expected a hex encoded transaction"))?;
+        let tx = Transaction::from_hex(raw).map_err(|e| {
+            (
+                DESERIALIZATION_ERROR,
+                TransactionError::Malformed(e).to_string(),
+            )
+        })?;
+
+        let txid = tx.id;
+        self.node
+            .submit_transaction(tx)
+            .map_err(|e| (TRANSACTION_REJECTED, e.for_tx(txid).to_string()))?;
+        Ok(Json::String(txid.to_string()))
+    }
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    Json::Object(vec![
+        ("jsonrpc".to_string(), Json::String("2.0".to_string())),
+        (
+            "error".to_string(),
+            Json::Object(vec![
+                ("code".to_string(), Json::I64(code)),
+                ("message".to_string(), Json::String(message.to_string())),
+            ]),
+        ),
+        ("id".to_string(), id),
+    ])
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Reads a POST with its body, or a GET, which has none. Bytes past the
+// headers of a GET are left unread, since they belong to the WebSocket.
+fn read_http_request(stream: &mut TcpStream) -> io::Result<HttpRequest> {
+    // Bounds header lines as well as the body. Reading byte by byte until
+    // the method is known keeps a GET from buffering frames.
+    let mut reader =
+        BufReader::with_capacity(1, stream.take((MAX_REQUEST_SIZE + 64 * 1024) as u64));
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("invalid request line"));
+    };
+    if method != "POST" && method != "GET" {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "expected POST or GET",
+        ));
+    }
+    let mut request = HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers: Vec::new(),
+        body: Vec::new(),
+    };
+
+    for _ in 0..MAX_HEADER_LINES {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            if request.method == "GET" {
+                return Ok(request);
+            }
+
+            let len = request
+                .header("content-length")
+                .ok_or(invalid("missing content length"))?
+                .trim()
+                .parse::<usize>()
+                .map_err(|_| invalid("invalid content length"))?;
+            if len > MAX_REQUEST_SIZE {
+                return Err(invalid("request too large"));
+            }
+            request.body = vec![0u8; len];
+            reader.read_exact(&mut request.body)?;
+            return Ok(request);
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            request
+                .headers
+                .push((name.to_string(), value.trim().to_string()));
+        }
+    }
+
+    Err(invalid("too many headers"))
+}
+
+fn write_http_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+// Calls a method on a node's RPC server and returns the result
+pub fn call(
+    addr: impl ToSocketAddrs,
+    auth: &RpcAuth,
+    method: &str,
+    params: Vec<Json>,
+) -> Result<Json, RpcError> {
+    post(addr, auth, "/", method, params)
+}
+
+// Calls a method with wallet methods acting on the wallet named
+pub fn call_wallet(
+    addr: impl ToSocketAddrs,
+    auth: &RpcAuth,
+    wallet: &str,
+    method: &str,
+    params: Vec<Json>,
+) -> Result<Json, RpcError> {
+    post(
+        addr,
+        auth,
+        &format!("{WALLET_PATH}{wallet}"),
+        method,
+        params,
+    )
+}
+
+fn post(
+    addr: impl ToSocketAddrs,
+    auth: &RpcAuth,
+    path: &str,
+    method: &str,
+    params: Vec<Json>,
+) -> Result<Json, RpcError> {
+    let body = Json::Object(vec![
+        ("jsonrpc".to_string(), Json::String("2.0".to_string())),
+        ("method".to_string(), Json::String(method.to_string())),
+        ("params".to_string(), Json::Array(params)),
+        ("id".to_string(), Json::U64(1)),
+    ])
+    .to_string();
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {path} HTTP/1.1\r\nAuthorization: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        auth.header(),
+        body.len()
+    )?;
+    stream.flush()?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    if response.starts_with("HTTP/1.1 401") {
+        return Err(RpcError::Unauthorized);
+    }
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or(RpcError::InvalidResponse)?;
+    let response: Json = body.parse().map_err(|_| RpcError::InvalidResponse)?;
+
+    match response.get("error") {
+        Some(error) if *error != Json::Null => {
+            let code = match error.get("code") {
+                Some(Json::I64(code)) => *code,
+                Some(Json::U64(code)) => *code as i64,
+                _ => 0,
+            };
+            let message = error.get("message").and_then(Json::as_str).unwrap_or("");
+            Err(RpcError::Remote(code, message.to_string()))
+        }
+        _ => response
+            .get("result")
+            .cloned()
+            .ok_or(RpcError::InvalidResponse),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+
+    fn request(text: &str) -> Json {
+        let server = RpcServer::new(
+            Node::new(Blockchain::new(ChainParams::mainnet())),
+            RpcAuth::random(),
+        );
+        server.handle_request(&text.parse().unwrap())
+    }
+
+    fn error_code(response: &Json) -> Option<&Json> {
+        response.get("error")?.get("code")
+    }
+
+    #[test]
+    fn test_auth() {
+        let auth = RpcAuth::new("alice", "secret");
+        assert!(auth.accepts(Some("Basic YWxpY2U6c2VjcmV0")));
+        assert!(!auth.accepts(Some("Basic YWxpY2U6c2VjcmV1")));
+        assert!(!auth.accepts(Some("Basic YWxpY2U6c2VjcmV0AA==")));
+        assert!(!auth.accepts(None));
+        assert!(!format!("{auth:?}").contains("secret"));
+
+        let path = std::env::temp_dir().join(format!("cookie_{}", std::process::id()));
+        let cookie = RpcAuth::random();
+        assert_ne!(cookie, RpcAuth::random());
+        cookie.write_cookie(&path).unwrap();
+        cookie.write_cookie(&path).unwrap();
+        assert_eq!(RpcAuth::read_cookie(&path).unwrap(), cookie);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(path).unwrap();
+
+        assert!(is_json(Some("application/json")));
+        assert!(is_json(Some("Application/JSON; charset=utf-8")));
+        for content_type in [
+            "text/plain",
+            "application/x-www-form-urlencoded",
+            "multipart/form-data; boundary=x",
+        ] {
+            assert!(!is_json(Some(content_type)), "{content_type}");
+        }
+        assert!(!is_json(None));
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let response = request(r#"{"jsonrpc":"2.0","method":"getblockcount","id":7}"#);
+        assert_eq!(response.get("result"), Some(&Json::U64(0)));
+        assert_eq!(response.get("id"), Some(&Json::U64(7)));
+
+        let response = request(r#"{"jsonrpc":"2.0","method":"getblock","params":[0],"id":1}"#);
+        let digest = hex::encode(crate::block::Block::genesis().digest);
+        assert_eq!(
+            response.get("result").unwrap().get("digest"),
+            Some(&Json::String(digest))
+        );
+    }
+
+    #[test]
+    fn test_error_responses() {
+        let cases = [
+            (r#"{"id":1}"#, INVALID_REQUEST),
+            (
+                r#"{"method":"getblock","params":{},"id":1}"#,
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"method":"getblock","params":["zz"],"id":1}"#,
+                INVALID_PARAMS,
+            ),
+            (r#"{"method":"getblock","params":[5],"id":1}"#, NOT_FOUND),
+            (
+                r#"{"method":"getbalance","params":["rbc1xyz"],"id":1}"#,
+                INVALID_PARAMS,
+            ),
+            (
+                r#"{"method":"sendrawtransaction","params":["00"],"id":1}"#,
+                DESERIALIZATION_ERROR,
+            ),
+            (
+                r#"{"method":"sendrawtransaction","params":[0],"id":1}"#,
+                INVALID_PARAMS,
+            ),
+            (r#"{"method":"stop","id":1}"#, METHOD_NOT_FOUND),
+        ];
+
+        for (text, code) in cases {
+            assert_eq!(error_code(&request(text)), Some(&Json::I64(code)), "{text}");
+        }
+    }
+}
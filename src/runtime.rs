@@ -0,0 +1,260 @@
+use crate::block::Block;
+use crate::blockchain::BlockAcceptance;
+use crate::errors::ChainError;
+use crate::events::ChainEvent;
+use crate::log;
+use crate::miner::Miner;
+use crate::network::Node;
+use crate::rpc::{RpcAuth, RpcServer};
+use crate::stratum::{StratumServer, WorkProvider};
+use crate::transaction::Transaction;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often the miner checks whether the tip moved or it was stopped
+const MINER_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+// What to run besides block and transaction handling
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeConfig {
+    pub listen: Option<String>,
+    // Serve JSON-RPC here to clients with the credentials
+    pub rpc: Option<(String, RpcAuth)>,
+    pub peers: Vec<String>,
+    // Mine on top of the tip, paying to this pkhash
+    pub mine_to: Option<[u8; 32]>,
+    pub miner_threads: usize,
+    // Serve work to external miners here, for blocks paying to the pkhash
+    pub stratum: Option<(String, [u8; 32])>,
+}
+
+enum Command {
+    SubmitBlock(Block, Sender<Result<BlockAcceptance, ChainError>>),
+    SubmitTransaction(Transaction, Sender<Result<(), ChainError>>),
+    Shutdown,
+}
+
+// Sends blocks and transactions to a running node. Cheap to clone, so every
+// subsystem gets its own.
+#[derive(Clone)]
+pub struct RuntimeHandle {
+    commands: Sender<Command>,
+}
+
+impl RuntimeHandle {
+    pub fn submit_block(&self, block: Block) -> Result<BlockAcceptance, ChainError> {
+        self.request(|reply| Command::SubmitBlock(block, reply))
+    }
+
+    pub fn submit_transaction(&self, tx: Transaction) -> Result<(), ChainError> {
+        self.request(|reply| Command::SubmitTransaction(tx, reply))
+    }
+
+    fn request<T>(
+        &self,
+        command: impl FnOnce(Sender<Result<T, ChainError>>) -> Command,
+    ) -> Result<T, ChainError> {
+        let (reply, result) = mpsc::channel();
+        self.commands.send(command(reply)).map_err(|_| stopped())?;
+        result.recv().map_err(|_| stopped())?
+    }
+}
+
+fn stopped() -> ChainError {
+    ChainError::Io(io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "node runtime stopped",
+    ))
+}
+
+// Runs a node's subsystems on their own threads: peers, RPC clients and
+// external miners are served as they connect, the miner works on the tip,
+// and blocks and transactions from the miner and from handles are validated
+// one at a time by the chain thread. Listeners keep running until the
+// process exits; shutdown() stops mining and the chain thread.
+pub struct NodeRuntime {
+    node: Node,
+    handle: RuntimeHandle,
+    listen_addr: Option<SocketAddr>,
+    rpc_addr: Option<SocketAddr>,
+    stratum_addr: Option<SocketAddr>,
+    stop: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl NodeRuntime {
+    // Fails if a listener can't be bound. Peers that can't be reached are
+    // skipped.
+    pub fn start(node: Node, config: RuntimeConfig) -> io::Result<NodeRuntime> {
+        let listen_addr = config
+            .listen
+            .as_ref()
+            .map(|addr| node.listen(addr))
+            .transpose()?;
+        let rpc_addr = config
+            .rpc
+            .as_ref()
+            .map(|(addr, auth)| RpcServer::new(node.clone(), auth.clone()).listen(addr))
+            .transpose()?;
+        let stratum_addr = config
+            .stratum
+            .as_ref()
+            .map(|(addr, pkhash)| {
+                StratumServer::new(WorkProvider::new(node.clone(), *pkhash)).listen(addr)
+            })
+            .transpose()?;
+        for peer in &config.peers {
+            let _ = node.connect(peer);
+        }
+
+        let (commands, receiver) = mpsc::channel();
+        let handle = RuntimeHandle { commands };
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let chain_node = node.clone();
+        let mut threads = vec![thread::spawn(move || run_chain(chain_node, receiver))];
+        if let Some(pkhash) = config.mine_to {
+            let (pow, clock) = {
+                let blockchain = node.blockchain();
+                (blockchain.params().pow, blockchain.shared_clock())
+            };
+            let miner = MinerTask {
+                node: node.clone(),
+                handle: handle.clone(),
+                miner: Miner::with_stats(config.miner_threads, node.mining_stats().clone())
+                    .with_pow(pow)
+                    .with_clock(clock),
+                stop: Arc::clone(&stop),
+            };
+            threads.push(thread::spawn(move || miner.run(pkhash)));
+        }
+
+        Ok(NodeRuntime {
+            node,
+            handle,
+            listen_addr,
+            rpc_addr,
+            stratum_addr,
+            stop,
+            threads,
+        })
+    }
+
+    pub fn node(&self) -> &Node {
+        &self.node
+    }
+
+    pub fn handle(&self) -> RuntimeHandle {
+        self.handle.clone()
+    }
+
+    pub fn listen_addr(&self) -> Option<SocketAddr> {
+        self.listen_addr
+    }
+
+    pub fn rpc_addr(&self) -> Option<SocketAddr> {
+        self.rpc_addr
+    }
+
+    pub fn stratum_addr(&self) -> Option<SocketAddr> {
+        self.stratum_addr
+    }
+
+    // Events for every block and transaction accepted from now on
+    pub fn subscribe(&self) -> Receiver<ChainEvent> {
+        self.node.blockchain().subscribe()
+    }
+
+    pub fn shutdown(mut self) {
+        self.stop_threads();
+    }
+
+    fn stop_threads(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.handle.commands.send(Command::Shutdown);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for NodeRuntime {
+    fn drop(&mut self) {
+        self.stop_threads();
+    }
+}
+
+fn run_chain(node: Node, commands: Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::SubmitBlock(block, reply) => {
+                let _ = reply.send(node.submit_block(block).map_err(ChainError::from));
+            }
+            Command::SubmitTransaction(tx, reply) => {
+                let _ = reply.send(node.submit_transaction(tx).map_err(ChainError::from));
+            }
+            Command::Shutdown => break,
+        }
+    }
+}
+
+struct MinerTask {
+    node: Node,
+    handle: RuntimeHandle,
+    miner: Miner,
+    stop: Arc<AtomicBool>,
+}
+
+impl MinerTask {
+    fn run(mut self, pkhash: [u8; 32]) {
+        let events = self.node.blockchain().subscribe();
+        while !self.stop.load(Ordering::Relaxed) {
+            // Including the block just mined
+            events.try_iter().for_each(drop);
+            let template = self.node.blockchain().create_block_template(pkhash);
+            match template {
+                Ok(template) => self.mine(template, &events),
+                Err(_) => thread::sleep(MINER_POLL_INTERVAL),
+            }
+        }
+        self.miner.stop();
+    }
+
+    // Gives up on the template once another block connects
+    fn mine(&mut self, template: Block, events: &Receiver<ChainEvent>) {
+        let solved = self.miner.start_template(template);
+        loop {
+            match solved.recv_timeout(MINER_POLL_INTERVAL) {
+                Ok(block) => {
+                    let height = block.header.index;
+                    match self.handle.submit_block(block) {
+                        // Another block at the same height got there first
+                        Ok(BlockAcceptance::AcceptedOnSideChain) => {
+                            log::info("miner", "mined block is stale", &[("height", &height)]);
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            log::warn("miner", "mined block rejected", &[("error", &e)]);
+                        }
+                    }
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let tip_moved = events
+                        .try_iter()
+                        .any(|event| matches!(event, ChainEvent::BlockConnected { .. }));
+                    if tip_moved || self.stop.load(Ordering::Relaxed) {
+                        self.miner.stop();
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
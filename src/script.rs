@@ -0,0 +1,396 @@
+use crate::errors::TransactionError;
+use crate::json::{Hex, impl_serde_struct};
+use crate::transaction::TxInput;
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+// Spending condition of an output. All but AnyoneCanSpend are tied to the
+// output's pkhash.
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Script {
+    // A signature from the key hashing to the pkhash
+    #[default]
+    PayToPubkeyHash,
+    // `threshold` signatures from the key set hashing to the pkhash
+    Multisig {
+        threshold: u8,
+    },
+    // Like PayToPubkeyHash, but not spendable in a block below `height`
+    Timelock {
+        height: u64,
+    },
+    AnyoneCanSpend,
+    // Hash-time-locked contract, with the pkhash committing to its Htlc
+    // terms: the recipient's key with the payment hash's preimage, or the
+    // refund key from the timeout height on
+    Htlc,
+}
+
+impl Script {
+    // Checks that the input unlocks an output with this script and pkhash
+    // when included in a block at `height`. The signatures themselves are
+    // checked without the UTXO set by Transaction::verify_input_signatures.
+    pub fn evaluate(
+        &self,
+        pkhash: &[u8; 32],
+        input: &TxInput,
+        height: u64,
+    ) -> Result<(), TransactionError> {
+        match *self {
+            Script::PayToPubkeyHash => check_pubkey_hash(pkhash, input),
+            Script::Multisig { threshold } => {
+                if multisig_hash(&input.witness.pubkeys) != *pkhash
+                    || input.witness.signatures.len() < threshold as usize
+                {
+                    return Err(TransactionError::UnauthorizedSpend);
+                }
+                Ok(())
+            }
+            Script::Timelock {
+                height: unlock_height,
+            } => {
+                if height < unlock_height {
+                    return Err(TransactionError::Timelocked);
+                }
+                check_pubkey_hash(pkhash, input)
+            }
+            Script::AnyoneCanSpend => Ok(()),
+            Script::Htlc => {
+                let Some(spend) = &input.witness.htlc else {
+                    return Err(TransactionError::UnauthorizedSpend);
+                };
+                let terms = &spend.terms;
+                if terms.hash() != *pkhash {
+                    return Err(TransactionError::UnauthorizedSpend);
+                }
+                match &spend.preimage {
+                    Some(preimage) if payment_hash(preimage) != terms.payment_hash => {
+                        return Err(TransactionError::UnauthorizedSpend);
+                    }
+                    None if height < terms.timeout => return Err(TransactionError::Timelocked),
+                    _ => {}
+                }
+                check_pubkey_hash(&spend.signer(), input)
+            }
+        }
+    }
+}
+
+// The terms of an HTLC output, which its spender reveals in the witness
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Htlc {
+    pub payment_hash: [u8; 32],
+    // pkhash that can claim the output with the preimage
+    pub recipient: [u8; 32],
+    // pkhash that gets the output back once nobody claimed it
+    pub refund: [u8; 32],
+    pub timeout: u64,
+}
+
+impl_serde_struct!(Htlc {
+    payment_hash: [u8; 32] => hex,
+    recipient: [u8; 32] => hex,
+    refund: [u8; 32] => hex,
+    timeout: u64,
+});
+
+impl Htlc {
+    // The pkhash of an output locked with these terms
+    pub fn hash(&self) -> [u8; 32] {
+        let data = bincode::encode_to_vec(self, bincode::config::standard()).unwrap();
+        Sha256::digest(data).into()
+    }
+}
+
+pub fn payment_hash(preimage: &[u8; 32]) -> [u8; 32] {
+    Sha256::digest(preimage).into()
+}
+
+// Claims with the preimage, or refunds without it
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct HtlcSpend {
+    pub terms: Htlc,
+    pub preimage: Option<[u8; 32]>,
+}
+
+impl HtlcSpend {
+    // The pkhash of the key that must sign the input
+    pub fn signer(&self) -> [u8; 32] {
+        match self.preimage {
+            Some(_) => self.terms.recipient,
+            None => self.terms.refund,
+        }
+    }
+}
+
+fn check_pubkey_hash(pkhash: &[u8; 32], input: &TxInput) -> Result<(), TransactionError> {
+    if input.pubkey.is_empty() || pubkey_hash(&input.pubkey) != *pkhash {
+        return Err(TransactionError::UnauthorizedSpend);
+    }
+    Ok(())
+}
+
+pub fn pubkey_hash(pubkey: &[u8]) -> [u8; 32] {
+    Sha256::digest(pubkey).into()
+}
+
+// The pkhash of a multisig output. The order of the keys matters, since
+// signatures refer to them by position.
+pub fn multisig_hash(pubkeys: &[Vec<u8>]) -> [u8; 32] {
+    let data = bincode::encode_to_vec(pubkeys, bincode::config::standard()).unwrap();
+    Sha256::digest(data).into()
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Script::PayToPubkeyHash => write!(f, "p2pkh"),
+            Script::Multisig { threshold } => write!(f, "multisig({})", threshold),
+            Script::Timelock { height } => write!(f, "timelock({})", height),
+            Script::AnyoneCanSpend => write!(f, "anyone_can_spend"),
+            Script::Htlc => write!(f, "htlc"),
+        }
+    }
+}
+
+impl FromStr for Script {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || format!("unknown script {s}");
+        match s {
+            "p2pkh" => return Ok(Script::PayToPubkeyHash),
+            "anyone_can_spend" => return Ok(Script::AnyoneCanSpend),
+            "htlc" => return Ok(Script::Htlc),
+            _ => {}
+        }
+
+        let (name, arg) = s
+            .strip_suffix(')')
+            .and_then(|s| s.split_once('('))
+            .ok_or_else(unknown)?;
+        match name {
+            "multisig" => Ok(Script::Multisig {
+                threshold: arg.parse().map_err(|_| unknown())?,
+            }),
+            "timelock" => Ok(Script::Timelock {
+                height: arg.parse().map_err(|_| unknown())?,
+            }),
+            _ => Err(unknown()),
+        }
+    }
+}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+// Unlocking data of an input spending a multisig output: the full key set,
+// all of the input's signature scheme, and signatures referring to keys by
+// position in increasing order. For an HTLC output, its terms and maybe the
+// preimage, next to the input's own pubkey and signature. Empty for other
+// inputs, which use the input's own pubkey and signature.
+#[derive(Encode, Decode, Clone, Debug, PartialEq, Default)]
+pub struct Witness {
+    pub pubkeys: Vec<Vec<u8>>,
+    pub signatures: Vec<KeySignature>,
+    pub htlc: Option<HtlcSpend>,
+}
+
+#[derive(Encode, Decode, Clone, Debug, PartialEq)]
+pub struct KeySignature {
+    pub key: u8,
+    pub signature: [u8; 64],
+}
+
+impl_serde_struct!(KeySignature {
+    key: u8,
+    signature: [u8; 64] => hex,
+});
+
+impl Witness {
+    pub fn is_empty(&self) -> bool {
+        self.pubkeys.is_empty() && self.signatures.is_empty() && self.htlc.is_none()
+    }
+}
+
+// Keys are written as a list of hex strings
+struct WitnessEntry {
+    pubkeys: Vec<Hex<Vec<u8>>>,
+    signatures: Vec<KeySignature>,
+    htlc: Option<Htlc>,
+    preimage: Option<Hex<[u8; 32]>>,
+}
+
+impl_serde_struct!(WitnessEntry {
+    pubkeys: Vec<Hex<Vec<u8>>>,
+    signatures: Vec<KeySignature>,
+    htlc: Option<Htlc>,
+    preimage: Option<Hex<[u8; 32]>>,
+});
+
+impl Serialize for Witness {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        WitnessEntry {
+            pubkeys: self.pubkeys.iter().cloned().map(Hex).collect(),
+            signatures: self.signatures.clone(),
+            htlc: self.htlc.as_ref().map(|spend| spend.terms),
+            preimage: self.htlc.as_ref().and_then(|spend| spend.preimage).map(Hex),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Witness {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let entry = WitnessEntry::deserialize(deserializer)?;
+        Ok(Witness {
+            pubkeys: entry.pubkeys.into_iter().map(|key| key.0).collect(),
+            signatures: entry.signatures,
+            htlc: entry.htlc.map(|terms| HtlcSpend {
+                terms,
+                preimage: entry.preimage.map(|preimage| preimage.0),
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_names() {
+        for script in [
+            Script::PayToPubkeyHash,
+            Script::Multisig { threshold: 2 },
+            Script::Timelock { height: 500 },
+            Script::AnyoneCanSpend,
+            Script::Htlc,
+        ] {
+            assert_eq!(script.to_string().parse(), Ok(script));
+        }
+        assert!("multisig(x)".parse::<Script>().is_err());
+        assert!("timelock(5".parse::<Script>().is_err());
+        assert!("p2sh".parse::<Script>().is_err());
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut input = TxInput::new_unsigned([1; 32], 0);
+        input.pubkey = vec![2; 33];
+        let pkhash = pubkey_hash(&input.pubkey);
+
+        assert_eq!(Script::PayToPubkeyHash.evaluate(&pkhash, &input, 1), Ok(()));
+        assert_eq!(
+            Script::PayToPubkeyHash.evaluate(&[0; 32], &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+
+        let timelock = Script::Timelock { height: 10 };
+        assert_eq!(
+            timelock.evaluate(&pkhash, &input, 9),
+            Err(TransactionError::Timelocked)
+        );
+        assert_eq!(timelock.evaluate(&pkhash, &input, 10), Ok(()));
+
+        let unsigned = TxInput::new_unsigned([1; 32], 0);
+        assert_eq!(
+            Script::PayToPubkeyHash.evaluate(&pubkey_hash(&[]), &unsigned, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+        assert_eq!(
+            Script::AnyoneCanSpend.evaluate(&[0; 32], &unsigned, 1),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_evaluate_htlc() {
+        let mut input = TxInput::new_unsigned([1; 32], 0);
+        input.pubkey = vec![2; 33];
+        let terms = Htlc {
+            payment_hash: payment_hash(&[7; 32]),
+            recipient: pubkey_hash(&input.pubkey),
+            refund: pubkey_hash(&[3; 33]),
+            timeout: 10,
+        };
+        let pkhash = terms.hash();
+        assert_eq!(
+            Script::Htlc.evaluate(&pkhash, &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+
+        input.witness.htlc = Some(HtlcSpend {
+            terms,
+            preimage: Some([7; 32]),
+        });
+        assert_eq!(Script::Htlc.evaluate(&pkhash, &input, 1), Ok(()));
+        assert_eq!(
+            Script::Htlc.evaluate(&[0; 32], &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+        input.witness.htlc.as_mut().unwrap().preimage = Some([8; 32]);
+        assert_eq!(
+            Script::Htlc.evaluate(&pkhash, &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+
+        // refunds go to the other key, and only after the timeout
+        input.witness.htlc.as_mut().unwrap().preimage = None;
+        assert_eq!(
+            Script::Htlc.evaluate(&pkhash, &input, 9),
+            Err(TransactionError::Timelocked)
+        );
+        assert_eq!(
+            Script::Htlc.evaluate(&pkhash, &input, 10),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+        input.pubkey = vec![3; 33];
+        assert_eq!(Script::Htlc.evaluate(&pkhash, &input, 10), Ok(()));
+    }
+
+    #[test]
+    fn test_evaluate_multisig() {
+        let pubkeys = vec![vec![1; 33], vec![2; 33], vec![3; 33]];
+        let pkhash = multisig_hash(&pubkeys);
+        let script = Script::Multisig { threshold: 2 };
+
+        let mut input = TxInput::new_unsigned([1; 32], 0);
+        input.witness.pubkeys = pubkeys.clone();
+        input.witness.signatures.push(KeySignature {
+            key: 0,
+            signature: [0; 64],
+        });
+        assert_eq!(
+            script.evaluate(&pkhash, &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+
+        input.witness.signatures.push(KeySignature {
+            key: 2,
+            signature: [0; 64],
+        });
+        assert_eq!(script.evaluate(&pkhash, &input, 1), Ok(()));
+
+        // a different key set, or the same keys in another order
+        input.witness.pubkeys.swap(0, 1);
+        assert_eq!(
+            script.evaluate(&pkhash, &input, 1),
+            Err(TransactionError::UnauthorizedSpend)
+        );
+    }
+}
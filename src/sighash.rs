@@ -0,0 +1,280 @@
+// What an input's signature commits to. It always covers the input itself,
+// with the value it spends, the transaction's version, timestamp, locktime
+// and replaceability, and the chain. The sighash
+// type, stored in the input, picks the rest:
+//
+//   ALL     every output
+//   NONE    no output, so anyone may redirect the funds
+//   SINGLE  only the output at the input's own index
+//
+// Outputs are committed with their index, as several may pay the same
+// pkhash and only the index tells them apart.
+//
+// Any of them combined with ANYONECANPAY leaves out the other inputs, so more
+// can be added after signing, e.g. to crowdfund a payment.
+
+use crate::amount::Amount;
+use crate::hash::{OutPoint, TxId};
+use crate::signature::SignatureScheme;
+use crate::transaction::{Transaction, TxOutput};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SighashBase {
+    #[default]
+    All,
+    None,
+    Single,
+}
+
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct SighashType {
+    pub base: SighashBase,
+    pub anyone_can_pay: bool,
+}
+
+impl SighashType {
+    pub const ALL: SighashType = SighashType::new(SighashBase::All);
+    pub const NONE: SighashType = SighashType::new(SighashBase::None);
+    pub const SINGLE: SighashType = SighashType::new(SighashBase::Single);
+
+    const fn new(base: SighashBase) -> SighashType {
+        SighashType {
+            base,
+            anyone_can_pay: false,
+        }
+    }
+
+    pub const fn with_anyone_can_pay(self) -> SighashType {
+        SighashType {
+            anyone_can_pay: true,
+            ..self
+        }
+    }
+}
+
+// Written like "all" or "single|anyonecanpay"
+impl fmt::Display for SighashType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let base = match self.base {
+            SighashBase::All => "all",
+            SighashBase::None => "none",
+            SighashBase::Single => "single",
+        };
+        if self.anyone_can_pay {
+            write!(f, "{base}|anyonecanpay")
+        } else {
+            f.write_str(base)
+        }
+    }
+}
+
+impl FromStr for SighashType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base, anyone_can_pay) = match s.split_once('|') {
+            Some((base, "anyonecanpay")) => (base, true),
+            Some(_) => return Err(()),
+            None => (s, false),
+        };
+        let base = match base {
+            "all" => SighashBase::All,
+            "none" => SighashBase::None,
+            "single" => SighashBase::Single,
+            _ => return Err(()),
+        };
+        Ok(SighashType {
+            base,
+            anyone_can_pay,
+        })
+    }
+}
+
+impl Serialize for SighashType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SighashType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse()
+            .map_err(|_| de::Error::custom(format!("invalid sighash type {s}")))
+    }
+}
+
+// Signatures and witnesses are left out, so inputs can be signed in any
+// order
+#[derive(Encode)]
+struct SighashPreimage<'a> {
+    chain_id: &'a [u8; 32],
+    version: &'a u32,
+    sighash_type: SighashType,
+    txid: &'a TxId,
+    output: &'a u16,
+    scheme: &'a SignatureScheme,
+    pubkey: &'a [u8],
+    value: &'a Amount,
+    relative_locktime: &'a u32,
+    // Every input's outpoint and relative locktime, in order
+    inputs: Vec<(OutPoint, u32)>,
+    // The committed outputs, each with its index in the transaction
+    outputs: Vec<(u16, &'a TxOutput)>,
+    // Part of the txid, so changing it would change the id of a signed
+    // transaction
+    timestamp: &'a u64,
+    locktime: &'a u64,
+    replaceable: &'a bool,
+}
+
+// The message signed for input `index`. A SINGLE input without an output of
+// its own commits to no output, but such transactions are invalid.
+pub fn sighash(tx: &Transaction, index: usize, chain_id: &[u8; 32]) -> [u8; 32] {
+    let input = &tx.inputs[index];
+    let sighash_type = input.sighash_type;
+    let inputs = if sighash_type.anyone_can_pay {
+        Vec::new()
+    } else {
+        tx.inputs
+            .iter()
+            .map(|input| (input.outpoint(), input.relative_locktime))
+            .collect()
+    };
+    let range = match sighash_type.base {
+        SighashBase::All => 0..tx.outputs.len(),
+        SighashBase::None => 0..0,
+        SighashBase::Single => index..(index + 1).min(tx.outputs.len()),
+    };
+    let outputs = range
+        .map(|index| (index as u16, &tx.outputs[index]))
+        .collect();
+
+    let preimage = SighashPreimage {
+        chain_id,
+        version: &tx.version,
+        sighash_type,
+        txid: &input.txid,
+        output: &input.output,
+        scheme: &input.scheme,
+        pubkey: &input.pubkey,
+        value: &input.value,
+        relative_locktime: &input.relative_locktime,
+        inputs,
+        outputs,
+        timestamp: &tx.timestamp,
+        locktime: &tx.locktime,
+        replaceable: &tx.replaceable,
+    };
+    Sha256::digest(bincode::encode_to_vec(preimage, bincode::config::standard()).unwrap()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::TransactionError;
+    use crate::signature::PrivateKey;
+    use crate::transaction::TxInput;
+
+    const CHAIN_ID: [u8; 32] = [7; 32];
+
+    fn signed(sighash_type: SighashType, key: &PrivateKey) -> Transaction {
+        let mut tx = Transaction::new(
+            vec![
+                TxInput::new_unsigned([1; 32], 1),
+                TxInput::new_unsigned([2; 32], 1),
+            ],
+            vec![TxOutput::new(10, [1; 32]), TxOutput::new(20, [2; 32])],
+        );
+        tx.inputs[0].sighash_type = sighash_type;
+        tx.sign(key, &CHAIN_ID).unwrap();
+        tx
+    }
+
+    // Whether input 0's signature survives the change
+    fn still_valid(sighash_type: SighashType, change: impl Fn(&mut Transaction)) -> bool {
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let mut tx = signed(sighash_type, &key);
+        assert_eq!(tx.verify_input_signatures(0, &CHAIN_ID), Ok(()));
+        change(&mut tx);
+        tx.verify_input_signatures(0, &CHAIN_ID).is_ok()
+    }
+
+    #[test]
+    fn test_sighash_types() {
+        let change_output_0 = |tx: &mut Transaction| tx.outputs[0].value.0 += 1;
+        let change_output_1 = |tx: &mut Transaction| tx.outputs[1].value.0 += 1;
+        let add_input = |tx: &mut Transaction| tx.inputs.push(TxInput::new_unsigned([3; 32], 1));
+        let change_locktime = |tx: &mut Transaction| tx.locktime = 5;
+        let change_timestamp = |tx: &mut Transaction| tx.timestamp += 1;
+
+        assert!(!still_valid(SighashType::ALL, change_output_1));
+        assert!(!still_valid(SighashType::ALL, add_input));
+        assert!(!still_valid(SighashType::ALL, change_locktime));
+        assert!(!still_valid(SighashType::ALL, change_timestamp));
+        assert!(!still_valid(SighashType::NONE, change_timestamp));
+
+        assert!(still_valid(SighashType::NONE, change_output_0));
+        assert!(!still_valid(SighashType::NONE, add_input));
+
+        assert!(!still_valid(SighashType::SINGLE, change_output_0));
+        assert!(still_valid(SighashType::SINGLE, change_output_1));
+
+        let all = SighashType::ALL.with_anyone_can_pay();
+        assert!(still_valid(all, add_input));
+        assert!(!still_valid(all, change_output_1));
+        assert!(still_valid(
+            SighashType::NONE.with_anyone_can_pay(),
+            add_input
+        ));
+        assert!(!still_valid(all, change_locktime));
+        assert!(!still_valid(all, change_timestamp));
+
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let mut tx = signed(SighashType::ALL, &key);
+        tx.inputs[1].sighash_type = SighashType::SINGLE;
+        tx.outputs.pop();
+        tx.sign(&key, &CHAIN_ID).unwrap();
+        assert_eq!(
+            tx.verify_without_signatures(),
+            Err(TransactionError::InvalidSighashType)
+        );
+    }
+
+    #[test]
+    fn test_sighash_commits_to_output_index() {
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let single = SighashType::SINGLE.with_anyone_can_pay();
+        let mut tx = signed(single, &key);
+        tx.outputs[1].pkhash = tx.outputs[0].pkhash;
+        tx.sign(&key, &CHAIN_ID).unwrap();
+        assert_eq!(tx.verify_input_signatures(0, &CHAIN_ID), Ok(()));
+
+        // Moving the input along with its output to another index breaks it
+        tx.inputs.swap(0, 1);
+        tx.outputs.swap(0, 1);
+        assert!(tx.verify_input_signatures(1, &CHAIN_ID).is_err());
+    }
+
+    #[test]
+    fn test_sighash_type_names() {
+        for sighash_type in [
+            SighashType::ALL,
+            SighashType::NONE,
+            SighashType::SINGLE.with_anyone_can_pay(),
+        ] {
+            assert_eq!(sighash_type.to_string().parse(), Ok(sighash_type));
+        }
+        assert_eq!(
+            SighashType::NONE.with_anyone_can_pay().to_string(),
+            "none|anyonecanpay"
+        );
+        assert!("all|other".parse::<SighashType>().is_err());
+        assert!("any".parse::<SighashType>().is_err());
+    }
+}
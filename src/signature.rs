@@ -0,0 +1,413 @@
+use crate::consensus::{self, VerifyError};
+use crate::ed25519;
+use crate::errors::{SignerError, TransactionError};
+use bincode::{Decode, Encode};
+use k256::ecdsa::signature::Signer as _;
+use k256::ecdsa::{Signature, SigningKey};
+use k256::schnorr;
+use rand_core::RngCore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{LazyLock, Mutex};
+
+// Stored in every input so verifiers know how to interpret its public key
+// and signature
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SignatureScheme {
+    #[default]
+    Ecdsa,
+    Ed25519,
+    // BIP340 over secp256k1. Signatures add up, so the keys of an n-of-n
+    // multisig can be combined into one, see musig.rs
+    Schnorr,
+}
+
+impl SignatureScheme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SignatureScheme::Ecdsa => "ecdsa",
+            SignatureScheme::Ed25519 => "ed25519",
+            SignatureScheme::Schnorr => "schnorr",
+        }
+    }
+
+    pub fn verify(
+        &self,
+        pubkey: &[u8],
+        message: &[u8],
+        signature: &[u8; 64],
+    ) -> Result<(), TransactionError> {
+        let result = match self {
+            SignatureScheme::Ecdsa => consensus::verify_ecdsa(pubkey, message, signature),
+            SignatureScheme::Ed25519 => consensus::verify_ed25519(pubkey, message, signature),
+            SignatureScheme::Schnorr => consensus::verify_schnorr(pubkey, message, signature),
+        };
+        result.map_err(|e| match e {
+            VerifyError::InvalidPublicKey => TransactionError::InvalidPublicKey,
+            VerifyError::InvalidSignature => TransactionError::InvalidSignature,
+            VerifyError::NonCanonical => TransactionError::NonCanonicalSignature,
+            VerifyError::Mismatch => TransactionError::SignatureVerificationFailed,
+        })
+    }
+}
+
+impl Serialize for SignatureScheme {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for SignatureScheme {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match String::deserialize(deserializer)?.as_str() {
+            "ecdsa" => Ok(SignatureScheme::Ecdsa),
+            "ed25519" => Ok(SignatureScheme::Ed25519),
+            "schnorr" => Ok(SignatureScheme::Schnorr),
+            other => Err(de::Error::unknown_variant(
+                other,
+                &["ecdsa", "ed25519", "schnorr"],
+            )),
+        }
+    }
+}
+
+// One signature to verify, collected so that all of a block's signatures
+// can be checked together
+#[derive(Clone)]
+pub struct SignatureCheck<'a> {
+    pub scheme: SignatureScheme,
+    pub pubkey: &'a [u8],
+    pub message: [u8; 32],
+    pub signature: &'a [u8; 64],
+}
+
+impl SignatureCheck<'_> {
+    pub fn verify(&self) -> Result<(), TransactionError> {
+        self.scheme
+            .verify(self.pubkey, &self.message, self.signature)
+    }
+
+    fn cache_key(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update([self.scheme as u8]);
+        hasher.update((self.pubkey.len() as u32).to_be_bytes());
+        hasher.update(self.pubkey);
+        hasher.update(self.message);
+        hasher.update(self.signature);
+        hasher.finalize().into()
+    }
+}
+
+pub const SIGNATURE_CACHE_SIZE: usize = 50_000;
+
+// Signatures already found valid, so a transaction checked when it entered
+// the mempool isn't checked again when it arrives in a block
+pub static SIGNATURE_CACHE: LazyLock<SignatureCache> =
+    LazyLock::new(|| SignatureCache::new(SIGNATURE_CACHE_SIZE));
+
+// Once full, the least recently used entry is evicted
+pub struct SignatureCache {
+    capacity: usize,
+    inner: Mutex<CacheEntries>,
+}
+
+#[derive(Default)]
+struct CacheEntries {
+    last_used: HashMap<[u8; 32], u64>,
+    by_age: BTreeMap<u64, [u8; 32]>,
+    clock: u64,
+}
+
+impl CacheEntries {
+    fn touch(&mut self, key: [u8; 32]) {
+        self.clock += 1;
+        if let Some(previous) = self.last_used.insert(key, self.clock) {
+            self.by_age.remove(&previous);
+        }
+        self.by_age.insert(self.clock, key);
+    }
+}
+
+impl SignatureCache {
+    pub fn new(capacity: usize) -> SignatureCache {
+        SignatureCache {
+            capacity,
+            inner: Mutex::new(CacheEntries::default()),
+        }
+    }
+
+    pub fn contains(&self, check: &SignatureCheck) -> bool {
+        let key = check.cache_key();
+        let mut entries = self.inner.lock().unwrap();
+        let found = entries.last_used.contains_key(&key);
+        if found {
+            entries.touch(key);
+        }
+        found
+    }
+
+    pub fn insert(&self, check: &SignatureCheck) {
+        let mut entries = self.inner.lock().unwrap();
+        entries.touch(check.cache_key());
+        while entries.last_used.len() > self.capacity {
+            let (_, oldest) = entries.by_age.pop_first().unwrap();
+            entries.last_used.remove(&oldest);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().last_used.len()
+    }
+
+    pub fn clear(&self) {
+        *self.inner.lock().unwrap() = CacheEntries::default();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Below this, spawning threads costs more than it saves
+#[cfg(feature = "parallel")]
+const MIN_PARALLEL_BATCH: usize = 16;
+
+// Returns the error of the first failing check, in order. Checks found in
+// SIGNATURE_CACHE are skipped and the rest added to it once verified.
+pub fn verify_batch(checks: &[SignatureCheck]) -> Result<(), TransactionError> {
+    let pending: Vec<&SignatureCheck> = checks
+        .iter()
+        .filter(|check| !SIGNATURE_CACHE.contains(check))
+        .collect();
+    verify_all(&pending)?;
+    for check in pending {
+        SIGNATURE_CACHE.insert(check);
+    }
+    Ok(())
+}
+
+fn verify_all(checks: &[&SignatureCheck]) -> Result<(), TransactionError> {
+    #[cfg(feature = "parallel")]
+    if checks.len() >= MIN_PARALLEL_BATCH {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = checks.len().div_ceil(threads);
+        return std::thread::scope(|scope| {
+            let handles: Vec<_> = checks
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(|| chunk.iter().try_for_each(|check| check.verify())))
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap())
+        });
+    }
+
+    checks.iter().try_for_each(|check| check.verify())
+}
+
+pub enum PrivateKey {
+    Ecdsa(SigningKey),
+    Ed25519([u8; 32]),
+    Schnorr(schnorr::SigningKey),
+}
+
+impl PrivateKey {
+    pub fn random(scheme: SignatureScheme) -> PrivateKey {
+        match scheme {
+            SignatureScheme::Ecdsa => PrivateKey::Ecdsa(SigningKey::random(&mut rand_core::OsRng)),
+            SignatureScheme::Ed25519 => {
+                let mut secret = [0u8; 32];
+                rand_core::OsRng.fill_bytes(&mut secret);
+                PrivateKey::Ed25519(secret)
+            }
+            SignatureScheme::Schnorr => {
+                PrivateKey::Schnorr(schnorr::SigningKey::random(&mut rand_core::OsRng))
+            }
+        }
+    }
+
+    pub fn from_bytes(scheme: SignatureScheme, bytes: &[u8]) -> Option<PrivateKey> {
+        match scheme {
+            SignatureScheme::Ecdsa => SigningKey::from_slice(bytes).ok().map(PrivateKey::Ecdsa),
+            SignatureScheme::Ed25519 => bytes.try_into().ok().map(PrivateKey::Ed25519),
+            SignatureScheme::Schnorr => schnorr::SigningKey::from_bytes(bytes)
+                .ok()
+                .map(PrivateKey::Schnorr),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            PrivateKey::Ecdsa(key) => key.to_bytes().to_vec(),
+            PrivateKey::Ed25519(secret) => secret.to_vec(),
+            PrivateKey::Schnorr(key) => key.to_bytes().to_vec(),
+        }
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        match self {
+            PrivateKey::Ecdsa(_) => SignatureScheme::Ecdsa,
+            PrivateKey::Ed25519(_) => SignatureScheme::Ed25519,
+            PrivateKey::Schnorr(_) => SignatureScheme::Schnorr,
+        }
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            PrivateKey::Ecdsa(key) => key
+                .verifying_key()
+                .to_encoded_point(true)
+                .as_bytes()
+                .to_vec(),
+            PrivateKey::Ed25519(secret) => ed25519::public_key(secret).to_vec(),
+            PrivateKey::Schnorr(key) => key.verifying_key().to_bytes().to_vec(),
+        }
+    }
+
+    // ECDSA nonces are derived from the key and message as in RFC 6979, so
+    // signing needs no randomness and the same message always gets the same
+    // signature, always in low-s form. Schnorr nonces are derived the same
+    // way by BIP340, with the auxiliary randomness it allows left zero.
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        match self {
+            PrivateKey::Ecdsa(key) => {
+                let signature: Signature = key.sign(message);
+                let signature = signature.normalize_s().unwrap_or(signature);
+                signature.to_bytes().into()
+            }
+            PrivateKey::Ed25519(secret) => ed25519::sign(secret, message),
+            PrivateKey::Schnorr(key) => key.sign_raw(message, &[0; 32]).unwrap().to_bytes(),
+        }
+    }
+}
+
+// Signs with a key that may be held elsewhere, such as in a hardware wallet,
+// an HSM or a remote service, which is only ever shown the sighash of what
+// it signs. Wallet and TxBuilder sign through it; a PrivateKey is the signer
+// for a key kept in memory. Signing fails if the holder turns the request
+// down or can't be reached.
+pub trait Signer {
+    fn scheme(&self) -> SignatureScheme;
+    fn public_key(&self) -> Vec<u8>;
+    fn sign(&self, sighash: &[u8; 32]) -> Result<[u8; 64], SignerError>;
+}
+
+impl Signer for PrivateKey {
+    fn scheme(&self) -> SignatureScheme {
+        PrivateKey::scheme(self)
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        PrivateKey::public_key(self)
+    }
+
+    fn sign(&self, sighash: &[u8; 32]) -> Result<[u8; 64], SignerError> {
+        Ok(PrivateKey::sign(self, sighash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify_each_scheme() {
+        for scheme in [
+            SignatureScheme::Ecdsa,
+            SignatureScheme::Ed25519,
+            SignatureScheme::Schnorr,
+        ] {
+            let key = PrivateKey::random(scheme);
+            assert_eq!(key.scheme(), scheme);
+
+            let signature = key.sign(b"message");
+            assert_eq!(key.sign(b"message"), signature);
+            assert_eq!(
+                scheme.verify(&key.public_key(), b"message", &signature),
+                Ok(())
+            );
+            assert_eq!(
+                scheme.verify(&key.public_key(), b"tampered", &signature),
+                Err(TransactionError::SignatureVerificationFailed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_scheme_mismatch_rejected() {
+        let key = PrivateKey::random(SignatureScheme::Ed25519);
+        let signature = key.sign(b"message");
+        assert_eq!(
+            SignatureScheme::Ecdsa.verify(&key.public_key(), b"message", &signature),
+            Err(TransactionError::InvalidPublicKey)
+        );
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let pubkey = key.public_key();
+        let messages: Vec<[u8; 32]> = (0..40).map(|i| [i as u8; 32]).collect();
+        let signatures: Vec<[u8; 64]> = messages.iter().map(|m| key.sign(m)).collect();
+
+        let mut checks: Vec<SignatureCheck> = messages
+            .iter()
+            .zip(&signatures)
+            .map(|(message, signature)| SignatureCheck {
+                scheme: SignatureScheme::Ecdsa,
+                pubkey: &pubkey,
+                message: *message,
+                signature,
+            })
+            .collect();
+        assert_eq!(verify_batch(&checks), Ok(()));
+
+        // the earliest failure is reported
+        checks[30].message = [0xff; 32];
+        checks[5].pubkey = &[];
+        assert_eq!(
+            verify_batch(&checks),
+            Err(TransactionError::InvalidPublicKey)
+        );
+        checks[5].pubkey = &pubkey;
+        assert_eq!(
+            verify_batch(&checks),
+            Err(TransactionError::SignatureVerificationFailed)
+        );
+    }
+
+    #[test]
+    fn test_signature_cache() {
+        let key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let pubkey = key.public_key();
+        let signatures: Vec<[u8; 64]> = (0..3).map(|i| key.sign(&[i; 32])).collect();
+        let checks: Vec<SignatureCheck> = (0..3)
+            .map(|i| SignatureCheck {
+                scheme: SignatureScheme::Ecdsa,
+                pubkey: &pubkey,
+                message: [i; 32],
+                signature: &signatures[i as usize],
+            })
+            .collect();
+
+        let cache = SignatureCache::new(2);
+        cache.insert(&checks[0]);
+        cache.insert(&checks[1]);
+        // using the first entry makes the second the least recently used
+        assert!(cache.contains(&checks[0]));
+        cache.insert(&checks[2]);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&checks[0]));
+        assert!(!cache.contains(&checks[1]));
+        assert!(cache.contains(&checks[2]));
+
+        // only valid signatures are remembered
+        let mut invalid = checks[0].clone();
+        invalid.message = [9; 32];
+        assert!(verify_batch(&[invalid.clone()]).is_err());
+        assert!(!SIGNATURE_CACHE.contains(&invalid));
+        assert_eq!(verify_batch(&checks[1..2]), Ok(()));
+        assert!(SIGNATURE_CACHE.contains(&checks[1]));
+    }
+}
@@ -0,0 +1,485 @@
+// Where the UTXO set keeps its entries: in memory, or in an append-only file
+// on disk for chains whose set doesn't fit in memory.
+
+use crate::hash::{Hash256, OutPoint};
+use crate::transaction::TxOutput;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// Files smaller than this are never compacted
+const MIN_COMPACT_SIZE: u64 = 1 << 20;
+const KEY_SIZE: usize = 34;
+
+// The changes made by one block. A later change to the same outpoint replaces
+// an earlier one, so an output created and spent in the same block is simply
+// removed.
+#[derive(Default)]
+pub struct UtxoBatch {
+    // None removes the outpoint
+    changes: HashMap<OutPoint, Option<TxOutput>>,
+    // The block the store is at once the batch is written
+    tip: Option<Hash256>,
+}
+
+impl UtxoBatch {
+    pub fn new() -> UtxoBatch {
+        UtxoBatch::default()
+    }
+
+    pub fn put(&mut self, outpoint: OutPoint, output: TxOutput) {
+        self.changes.insert(outpoint, Some(output));
+    }
+
+    pub fn delete(&mut self, outpoint: OutPoint) {
+        self.changes.insert(outpoint, None);
+    }
+
+    pub fn set_tip(&mut self, tip: Hash256) {
+        self.tip = Some(tip);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.tip.is_none()
+    }
+}
+
+pub trait UtxoStore: Send {
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOutput>;
+
+    // Either every change in the batch is stored or none is
+    fn write_batch(&mut self, batch: UtxoBatch) -> io::Result<()>;
+
+    // In no particular order
+    fn entries(&self) -> Vec<(OutPoint, TxOutput)>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The block the entries are up to date with, if recorded
+    fn tip(&self) -> Option<Hash256>;
+
+    fn clear(&mut self) -> io::Result<()>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    utxos: HashMap<OutPoint, TxOutput>,
+    tip: Option<Hash256>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl UtxoStore for MemoryStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOutput> {
+        self.utxos.get(outpoint).cloned()
+    }
+
+    fn write_batch(&mut self, batch: UtxoBatch) -> io::Result<()> {
+        for (outpoint, change) in batch.changes {
+            match change {
+                Some(output) => self.utxos.insert(outpoint, output),
+                None => self.utxos.remove(&outpoint),
+            };
+        }
+        if batch.tip.is_some() {
+            self.tip = batch.tip;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(OutPoint, TxOutput)> {
+        self.utxos
+            .iter()
+            .map(|(outpoint, output)| (*outpoint, output.clone()))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    fn tip(&self) -> Option<Hash256> {
+        self.tip
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        *self = MemoryStore::new();
+        Ok(())
+    }
+}
+
+// Batches are appended to the file, each as its big-endian u32 length, the
+// batch, and the first 4 bytes of its SHA-256. Only the position of each
+// output is kept in memory. A batch cut short by a crash fails its checksum
+// and is dropped when the file is opened, along with anything after it.
+//
+// A batch is the optional tip (a flag byte, then the digest), the number of
+// changes as a big-endian u32, then the changes: the outpoint (txid, then
+// big-endian index), a flag byte, and for an added output its encoded length
+// as a big-endian u32 followed by the bincode encoding.
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    // outpoint -> position and length of the encoded output
+    index: HashMap<OutPoint, (u64, u32)>,
+    tip: Option<Hash256>,
+    // Bytes taken by the batches
+    file_len: u64,
+    // Bytes of the entries still in the set, to decide when to compact
+    live_len: u64,
+}
+
+impl FileStore {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<FileStore> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut store = FileStore {
+            path,
+            file,
+            index: HashMap::new(),
+            tip: None,
+            file_len: 0,
+            live_len: 0,
+        };
+        store.replay()?;
+        Ok(store)
+    }
+
+    fn replay(&mut self) -> io::Result<()> {
+        let total_len = self.file.metadata()?.len();
+        let mut reader = BufReader::new(self.file.try_clone()?);
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut offset = 0;
+        while offset + 4 <= total_len {
+            let mut len = [0u8; 4];
+            reader.read_exact(&mut len)?;
+            let len = u32::from_be_bytes(len) as u64;
+            if offset + 4 + len + 4 > total_len {
+                break;
+            }
+            let mut payload = vec![0u8; len as usize + 4];
+            reader.read_exact(&mut payload)?;
+            let (payload, checksum) = payload.split_at(len as usize);
+            if Sha256::digest(payload)[..4] != *checksum {
+                break;
+            }
+            let Some(changes) = parse_batch(payload, offset + 4) else {
+                break;
+            };
+            self.apply(changes);
+            offset += 4 + len + 4;
+        }
+        drop(reader);
+
+        // Drop a torn batch so new ones are appended after the last good one
+        if offset != total_len {
+            self.file.set_len(offset)?;
+        }
+        self.file_len = offset;
+        Ok(())
+    }
+
+    fn apply(&mut self, batch: ParsedBatch) {
+        for (outpoint, position) in batch.changes {
+            if let Some((_, len)) = self.index.remove(&outpoint) {
+                self.live_len -= KEY_SIZE as u64 + 5 + len as u64;
+            }
+            if let Some((offset, len)) = position {
+                self.index.insert(outpoint, (offset, len));
+                self.live_len += KEY_SIZE as u64 + 5 + len as u64;
+            }
+        }
+        if batch.tip.is_some() {
+            self.tip = batch.tip;
+        }
+    }
+
+    fn read_output(&self, offset: u64, len: u32) -> io::Result<TxOutput> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = vec![0u8; len as usize];
+        file.read_exact(&mut data)?;
+        bincode::decode_from_slice(&data, bincode::config::standard())
+            .map(|(output, _)| output)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Rewrites the file with only the entries still in the set
+    pub fn compact(&mut self) -> io::Result<()> {
+        let mut batch = UtxoBatch::new();
+        for (outpoint, output) in self.entries() {
+            batch.put(outpoint, output);
+        }
+        batch.tip = self.tip;
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&encode_batch(&batch))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(&tmp_path, &self.path)?;
+
+        *self = FileStore::open(&self.path)?;
+        Ok(())
+    }
+}
+
+impl UtxoStore for FileStore {
+    fn get(&self, outpoint: &OutPoint) -> Option<TxOutput> {
+        let &(offset, len) = self.index.get(outpoint)?;
+        // An entry that can't be read back means the file was changed under
+        // us, and carrying on would validate blocks against a wrong set
+        Some(
+            self.read_output(offset, len)
+                .expect("UTXO file is unreadable"),
+        )
+    }
+
+    fn write_batch(&mut self, batch: UtxoBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let record = encode_batch(&batch);
+        self.file.seek(SeekFrom::Start(self.file_len))?;
+        if let Err(e) = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data())
+        {
+            // Best effort, a torn batch is dropped on the next open anyway
+            let _ = self.file.set_len(self.file_len);
+            return Err(e);
+        }
+
+        let payload = &record[4..record.len() - 4];
+        self.apply(parse_batch(payload, self.file_len + 4).unwrap());
+        self.file_len += record.len() as u64;
+
+        if self.file_len > MIN_COMPACT_SIZE && self.file_len > 2 * self.live_len {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> Vec<(OutPoint, TxOutput)> {
+        self.index
+            .keys()
+            .filter_map(|outpoint| Some((*outpoint, self.get(outpoint)?)))
+            .collect()
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    fn tip(&self) -> Option<Hash256> {
+        self.tip
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.index.clear();
+        self.tip = None;
+        self.file_len = 0;
+        self.live_len = 0;
+        Ok(())
+    }
+}
+
+// A batch as read back: the position of each added output in the file
+struct ParsedBatch {
+    changes: Vec<(OutPoint, Option<(u64, u32)>)>,
+    tip: Option<Hash256>,
+}
+
+fn encode_batch(batch: &UtxoBatch) -> Vec<u8> {
+    let mut payload = Vec::new();
+    match batch.tip {
+        Some(tip) => {
+            payload.push(1);
+            payload.extend_from_slice(tip.as_bytes());
+        }
+        None => payload.push(0),
+    }
+    payload.extend_from_slice(&(batch.changes.len() as u32).to_be_bytes());
+    for (outpoint, change) in &batch.changes {
+        payload.extend_from_slice(outpoint.txid.as_bytes());
+        payload.extend_from_slice(&outpoint.index.to_be_bytes());
+        match change {
+            Some(output) => {
+                let data = bincode::encode_to_vec(output, bincode::config::standard()).unwrap();
+                payload.push(1);
+                payload.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                payload.extend_from_slice(&data);
+            }
+            None => payload.push(0),
+        }
+    }
+
+    let mut record = Vec::with_capacity(payload.len() + 8);
+    record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    record.extend_from_slice(&payload);
+    record.extend_from_slice(&Sha256::digest(&payload)[..4]);
+    record
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+// `offset` is where the payload starts in the file
+fn parse_batch(payload: &[u8], offset: u64) -> Option<ParsedBatch> {
+    let mut cursor = Cursor {
+        data: payload,
+        pos: 0,
+    };
+
+    let tip = match cursor.take(1)?[0] {
+        0 => None,
+        1 => Some(Hash256(cursor.take(32)?.try_into().unwrap())),
+        _ => return None,
+    };
+    let count = cursor.u32()?;
+    let mut changes = Vec::new();
+    for _ in 0..count {
+        let key = cursor.take(KEY_SIZE)?;
+        let outpoint = OutPoint::new(
+            <[u8; 32]>::try_from(&key[..32]).unwrap(),
+            u16::from_be_bytes([key[32], key[33]]),
+        );
+        let position = match cursor.take(1)?[0] {
+            0 => None,
+            1 => {
+                let len = cursor.u32()?;
+                let start = offset + cursor.pos as u64;
+                cursor.take(len as usize)?;
+                Some((start, len))
+            }
+            _ => return None,
+        };
+        changes.push((outpoint, position));
+    }
+    (cursor.pos == payload.len()).then_some(ParsedBatch { changes, tip })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("utxo_store_{name}_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_store_reopen() {
+        let path = temp_path("reopen");
+        let mut store = FileStore::open(&path).unwrap();
+
+        let mut batch = UtxoBatch::new();
+        batch.put(OutPoint::new([1; 32], 0), TxOutput::new(10, [3; 32]));
+        batch.put(OutPoint::new([1; 32], 1), TxOutput::new(20, [4; 32]));
+        batch.set_tip(Hash256([7; 32]));
+        store.write_batch(batch).unwrap();
+
+        // created and spent in the same batch
+        let mut batch = UtxoBatch::new();
+        batch.delete(OutPoint::new([1; 32], 0));
+        batch.put(OutPoint::new([2; 32], 0), TxOutput::new(30, [5; 32]));
+        batch.delete(OutPoint::new([2; 32], 0));
+        store.write_batch(batch).unwrap();
+        assert_eq!(store.len(), 1);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.tip(), Some(Hash256([7; 32])));
+        assert!(store.get(&OutPoint::new([1; 32], 0)).is_none());
+        assert_eq!(store.get(&OutPoint::new([1; 32], 1)).unwrap().value, 20);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_batch_dropped() {
+        let path = temp_path("torn");
+        let mut store = FileStore::open(&path).unwrap();
+        let mut batch = UtxoBatch::new();
+        batch.put(OutPoint::new([1; 32], 0), TxOutput::new(10, [3; 32]));
+        store.write_batch(batch).unwrap();
+        let good_len = fs::metadata(&path).unwrap().len();
+
+        let mut batch = UtxoBatch::new();
+        batch.put(OutPoint::new([2; 32], 0), TxOutput::new(20, [3; 32]));
+        let record = encode_batch(&batch);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&record[..record.len() - 3]).unwrap();
+        drop(file);
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+
+        // new batches go after the last good one
+        store.write_batch(batch).unwrap();
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compact_and_clear() {
+        let path = temp_path("compact");
+        let mut store = FileStore::open(&path).unwrap();
+        for i in 0..50u8 {
+            let mut batch = UtxoBatch::new();
+            batch.put(OutPoint::new([i; 32], 0), TxOutput::new(10, [3; 32]));
+            if i > 0 {
+                batch.delete(OutPoint::new([i - 1; 32], 0));
+            }
+            batch.set_tip(Hash256([i; 32]));
+            store.write_batch(batch).unwrap();
+        }
+        let before = fs::metadata(&path).unwrap().len();
+        store.compact().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < before);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.tip(), Some(Hash256([49; 32])));
+        assert!(store.get(&OutPoint::new([49; 32], 0)).is_some());
+
+        store.clear().unwrap();
+        assert!(store.is_empty());
+        assert_eq!(FileStore::open(&path).unwrap().tip(), None);
+        fs::remove_file(path).unwrap();
+    }
+}
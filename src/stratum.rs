@@ -0,0 +1,499 @@
+// Work for external miners, in the manner of Stratum: a worker subscribes,
+// is sent a job, a block header to find a nonce for, and is sent a new one
+// whenever a block connects. The node keeps the rest of each job's block, so
+// a worker only ever handles headers and can hash them with
+// consensus::Header, which builds without std. A solved job is turned back
+// into its block, checked and broadcast like any other.
+//
+// The protocol is newline-delimited JSON-RPC over TCP:
+//
+//   mining.subscribe                        -> the current job
+//   mining.submit [job, nonce, timestamp]   -> the block's digest
+//
+// and jobs are pushed as {"id": null, "method": "mining.notify",
+// "params": [job]}.
+
+use crate::block::{Block, BlockHeader};
+use crate::consensus::{self, PowAlgorithm};
+use crate::errors::{RpcError, WorkError};
+use crate::hash::Hash256;
+use crate::json::{self, Json, impl_serde_struct};
+use crate::log;
+use crate::network::Node;
+use crate::rpc::{INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Jobs kept for submissions at once; older ones are forgotten
+const MAX_JOBS: usize = 16;
+// How often a connection checks whether the worker has left
+const EVENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_LINE_SIZE: u64 = 64 * 1024;
+
+// Application error codes
+pub const UNKNOWN_JOB: i64 = -30;
+pub const STALE_WORK: i64 = -31;
+pub const INVALID_WORK: i64 = -32;
+pub const BLOCK_REJECTED: i64 = -33;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Job {
+    pub id: u64,
+    // The name of the chain's proof of work, see PowAlgorithm::name
+    pub algorithm: String,
+    // Workers change the nonce, and may move the timestamp forward
+    pub header: BlockHeader,
+}
+
+impl_serde_struct!(Job {
+    id: u64,
+    algorithm: String,
+    header: BlockHeader,
+});
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    templates: BTreeMap<u64, Block>,
+}
+
+// Hands out jobs for blocks paying `pkhash` on top of the node's tip, and
+// takes their solutions back. Cheap to clone.
+#[derive(Clone)]
+pub struct WorkProvider {
+    node: Node,
+    pkhash: [u8; 32],
+    jobs: Arc<Mutex<Jobs>>,
+}
+
+impl WorkProvider {
+    pub fn new(node: Node, pkhash: [u8; 32]) -> WorkProvider {
+        WorkProvider {
+            node,
+            pkhash,
+            jobs: Arc::new(Mutex::new(Jobs::default())),
+        }
+    }
+
+    // A job for a block with the mempool's best transactions
+    pub fn new_job(&self) -> Result<Job, WorkError> {
+        let (template, pow) = {
+            let blockchain = self.node.blockchain();
+            let template = blockchain
+                .create_block_template(self.pkhash)
+                .map_err(WorkError::Template)?;
+            (template, blockchain.params().pow)
+        };
+        let header = template.header.clone();
+
+        let mut jobs = self.jobs.lock().unwrap();
+        // Jobs on an older tip can't be solved usefully any more
+        jobs.templates
+            .retain(|_, block| block.header.prev_hash == header.prev_hash);
+        while jobs.templates.len() >= MAX_JOBS {
+            jobs.templates.pop_first();
+        }
+        let id = jobs.next_id;
+        jobs.next_id += 1;
+        jobs.templates.insert(id, template);
+        Ok(Job {
+            id,
+            algorithm: pow.name().to_string(),
+            header,
+        })
+    }
+
+    // Completes the job's block with the worker's nonce and timestamp, and
+    // submits it to the node, which broadcasts it
+    pub fn submit(&self, job: u64, nonce: u64, timestamp: u64) -> Result<Hash256, WorkError> {
+        let mut block = self
+            .jobs
+            .lock()
+            .unwrap()
+            .templates
+            .get(&job)
+            .cloned()
+            .ok_or(WorkError::UnknownJob)?;
+        block.header.nonce = nonce;
+        block.header.timestamp = timestamp;
+        block.update_digest();
+        let pow = self.node.blockchain().params().pow;
+        if !consensus::meets_target(&block.header.pow_hash(&pow), &block.header.target()) {
+            return Err(WorkError::InvalidProofOfWork);
+        }
+        if block.header.prev_hash != self.node.blockchain().prev_hash() {
+            return Err(WorkError::Stale);
+        }
+
+        let digest = block.digest;
+        let height = block.header.index;
+        self.node.submit_block(block).map_err(WorkError::Rejected)?;
+        self.jobs.lock().unwrap().templates.remove(&job);
+        log::info(
+            "stratum",
+            "block found by worker",
+            &[("height", &height), ("digest", &digest)],
+        );
+        Ok(digest)
+    }
+}
+
+pub struct StratumServer {
+    provider: WorkProvider,
+}
+
+impl StratumServer {
+    pub fn new(provider: WorkProvider) -> StratumServer {
+        StratumServer { provider }
+    }
+
+    pub fn listen(&self, addr: impl ToSocketAddrs) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+
+        let provider = self.provider.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let provider = provider.clone();
+                thread::spawn(move || handle_connection(provider, stream));
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+// Requests are answered on this thread. Once subscribed, a second one pushes
+// a new job every time a block connects, until the worker leaves.
+fn handle_connection(provider: WorkProvider, stream: TcpStream) {
+    let (Ok(reader), Ok(writer)) = (stream.try_clone(), stream.try_clone()) else {
+        return;
+    };
+    let writer = Arc::new(Mutex::new(writer));
+    let closed = Arc::new(AtomicBool::new(false));
+    let mut reader = BufReader::new(reader);
+    let mut subscribed = false;
+
+    loop {
+        let mut line = String::new();
+        match (&mut reader).take(MAX_LINE_SIZE).read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        let (response, subscribed_at) = handle_request(&provider, line.trim());
+        if write_line(&writer, &response).is_err() {
+            break;
+        }
+        if let Some(tip) = subscribed_at.filter(|_| !subscribed) {
+            subscribed = true;
+            let provider = provider.clone();
+            let writer = writer.clone();
+            let closed = closed.clone();
+            thread::spawn(move || notify_jobs(provider, writer, closed, tip));
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// `tip` is what the worker's last job builds on
+fn notify_jobs(
+    provider: WorkProvider,
+    writer: Arc<Mutex<TcpStream>>,
+    closed: Arc<AtomicBool>,
+    mut tip: Hash256,
+) {
+    let events = provider.node.blockchain().subscribe();
+    while !closed.load(Ordering::Relaxed) {
+        // Checked before waiting, for blocks connected before subscribing
+        if provider.node.blockchain().prev_hash() == tip {
+            match events.recv_timeout(EVENT_POLL_INTERVAL) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        // Blocks connected in a row only need the last job
+        events.try_iter().for_each(drop);
+        let Ok(job) = provider.new_job() else {
+            continue;
+        };
+        tip = job.header.prev_hash;
+        let notification = Json::Object(vec![
+            ("id".to_string(), Json::Null),
+            (
+                "method".to_string(),
+                Json::String("mining.notify".to_string()),
+            ),
+            (
+                "params".to_string(),
+                Json::Array(vec![json::to_value(&job).unwrap()]),
+            ),
+        ]);
+        if write_line(&writer, &notification).is_err() {
+            return;
+        }
+    }
+}
+
+// The response, and if the worker subscribed, the tip its job builds on
+fn handle_request(provider: &WorkProvider, line: &str) -> (Json, Option<Hash256>) {
+    let Ok(request) = line.parse::<Json>() else {
+        return (
+            error_response(Json::Null, PARSE_ERROR, "invalid JSON"),
+            None,
+        );
+    };
+    let id = request.get("id").cloned().unwrap_or(Json::Null);
+    let Some(method) = request.get("method").and_then(Json::as_str) else {
+        return (error_response(id, INVALID_REQUEST, "missing method"), None);
+    };
+    let params = request
+        .get("params")
+        .and_then(Json::as_array)
+        .map_or(&[][..], Vec::as_slice);
+
+    let mut subscribed_at = None;
+    let result = match method {
+        "mining.subscribe" => provider
+            .new_job()
+            .map(|job| {
+                subscribed_at = Some(job.header.prev_hash);
+                json::to_value(&job).unwrap()
+            })
+            .map_err(|e| (error_code(&e), e.to_string())),
+        "mining.submit" => match params {
+            [Json::U64(job), Json::U64(nonce), Json::U64(timestamp)] => provider
+                .submit(*job, *nonce, *timestamp)
+                .map(|digest| Json::String(digest.to_string()))
+                .map_err(|e| (error_code(&e), e.to_string())),
+            _ => Err((
+                INVALID_PARAMS,
+                "expected a job id, a nonce and a timestamp".to_string(),
+            )),
+        },
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {method}"))),
+    };
+
+    let response = match result {
+        Ok(result) => Json::Object(vec![
+            ("id".to_string(), id),
+            ("result".to_string(), result),
+            ("error".to_string(), Json::Null),
+        ]),
+        Err((code, message)) => error_response(id, code, &message),
+    };
+    (response, subscribed_at)
+}
+
+fn error_code(error: &WorkError) -> i64 {
+    match error {
+        WorkError::UnknownJob => UNKNOWN_JOB,
+        WorkError::Stale => STALE_WORK,
+        WorkError::InvalidProofOfWork => INVALID_WORK,
+        WorkError::Template(_) | WorkError::Rejected(_) => BLOCK_REJECTED,
+    }
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> Json {
+    Json::Object(vec![
+        ("id".to_string(), id),
+        ("result".to_string(), Json::Null),
+        (
+            "error".to_string(),
+            Json::Object(vec![
+                ("code".to_string(), Json::I64(code)),
+                ("message".to_string(), Json::String(message.to_string())),
+            ]),
+        ),
+    ])
+}
+
+fn write_line(writer: &Mutex<TcpStream>, message: &Json) -> io::Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writeln!(writer, "{message}")?;
+    writer.flush()
+}
+
+// A worker's side of the connection
+pub struct StratumClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+    // Jobs pushed while waiting for a response
+    jobs: VecDeque<Job>,
+}
+
+impl StratumClient {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<StratumClient> {
+        let writer = TcpStream::connect(addr)?;
+        Ok(StratumClient {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            next_id: 1,
+            jobs: VecDeque::new(),
+        })
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.writer.set_read_timeout(timeout)
+    }
+
+    // Returns the first job
+    pub fn subscribe(&mut self) -> Result<Job, RpcError> {
+        let result = self.call("mining.subscribe", Vec::new())?;
+        json::from_value(result).map_err(|_| RpcError::InvalidResponse)
+    }
+
+    // Waits for the next job pushed by the node
+    pub fn next_job(&mut self) -> Result<Job, RpcError> {
+        while self.jobs.is_empty() {
+            self.read_message()?;
+        }
+        Ok(self.jobs.pop_front().unwrap())
+    }
+
+    pub fn submit(&mut self, job: &Job, nonce: u64, timestamp: u64) -> Result<Hash256, RpcError> {
+        let params = vec![Json::U64(job.id), Json::U64(nonce), Json::U64(timestamp)];
+        let result = self.call("mining.submit", params)?;
+        result
+            .as_str()
+            .and_then(|digest| digest.parse().ok())
+            .ok_or(RpcError::InvalidResponse)
+    }
+
+    fn call(&mut self, method: &str, params: Vec<Json>) -> Result<Json, RpcError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let request = Json::Object(vec![
+            ("id".to_string(), Json::U64(id)),
+            ("method".to_string(), Json::String(method.to_string())),
+            ("params".to_string(), Json::Array(params)),
+        ]);
+        writeln!(self.writer, "{request}")?;
+        self.writer.flush()?;
+
+        loop {
+            let message = self.read_message()?;
+            if message.get("id") != Some(&Json::U64(id)) {
+                continue;
+            }
+            return match message.get("error") {
+                Some(error) if *error != Json::Null => {
+                    let code = match error.get("code") {
+                        Some(Json::I64(code)) => *code,
+                        Some(Json::U64(code)) => *code as i64,
+                        _ => 0,
+                    };
+                    let text = error.get("message").and_then(Json::as_str).unwrap_or("");
+                    Err(RpcError::Remote(code, text.to_string()))
+                }
+                _ => message
+                    .get("result")
+                    .cloned()
+                    .ok_or(RpcError::InvalidResponse),
+            };
+        }
+    }
+
+    // Queues pushed jobs, and returns every message
+    fn read_message(&mut self) -> Result<Json, RpcError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(RpcError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let message: Json = line.trim().parse().map_err(|_| RpcError::InvalidResponse)?;
+        if message.get("method").and_then(Json::as_str) == Some("mining.notify") {
+            let job = message
+                .get("params")
+                .and_then(Json::as_array)
+                .and_then(|params| params.first())
+                .cloned()
+                .and_then(|job| json::from_value(job).ok())
+                .ok_or(RpcError::InvalidResponse)?;
+            self.jobs.push_back(job);
+        }
+        Ok(message)
+    }
+}
+
+// Searches nonces from `start` for one solving the job, as a worker would,
+// trying at most `attempts`. None also if the job's algorithm is unknown.
+pub fn solve_job(job: &Job, start: u64, attempts: u64) -> Option<u64> {
+    let pow = PowAlgorithm::from_name(&job.algorithm)?;
+    let mut header = consensus::Header::from(&job.header);
+    (start..start.saturating_add(attempts)).find(|&nonce| {
+        header.nonce = nonce;
+        header.has_valid_work(&pow)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::params::ChainParams;
+    use crate::utils::mine;
+
+    #[test]
+    fn test_work_provider() {
+        let node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let provider = WorkProvider::new(node.clone(), [1; 32]);
+        let job = provider.new_job().unwrap();
+        let timestamp = job.header.timestamp;
+        assert_eq!(
+            provider.submit(job.id + 1, 0, timestamp),
+            Err(WorkError::UnknownJob)
+        );
+
+        let mut header = consensus::Header::from(&job.header);
+        let bad_nonce = (0..)
+            .find(|&nonce| {
+                header.nonce = nonce;
+                !header.has_valid_work(&PowAlgorithm::Sha256)
+            })
+            .unwrap();
+        assert_eq!(
+            provider.submit(job.id, bad_nonce, timestamp),
+            Err(WorkError::InvalidProofOfWork)
+        );
+
+        let nonce = solve_job(&job, 0, u64::MAX).unwrap();
+        let digest = provider.submit(job.id, nonce, timestamp).unwrap();
+        assert_eq!(node.blockchain().prev_hash(), digest);
+        let reward = node.blockchain().params().block_reward(1);
+        assert_eq!(node.blockchain().utxos.balance([1; 32]), reward);
+        // A job is only used once
+        assert_eq!(
+            provider.submit(job.id, nonce, timestamp),
+            Err(WorkError::UnknownJob)
+        );
+    }
+
+    #[test]
+    fn test_stale_job() {
+        let node = Node::new(Blockchain::new(ChainParams::regtest()));
+        let provider = WorkProvider::new(node.clone(), [1; 32]);
+        let job = provider.new_job().unwrap();
+
+        let mut block = node.blockchain().next_block();
+        mine(&mut block, [2; 32], node.blockchain().get_block_reward());
+        node.submit_block(block).unwrap();
+
+        let nonce = solve_job(&job, 0, u64::MAX).unwrap();
+        assert_eq!(
+            provider.submit(job.id, nonce, job.header.timestamp),
+            Err(WorkError::Stale)
+        );
+        // Jobs on the old tip are dropped once there is a new one
+        provider.new_job().unwrap();
+        assert_eq!(
+            provider.submit(job.id, nonce, job.header.timestamp),
+            Err(WorkError::UnknownJob)
+        );
+    }
+}
@@ -0,0 +1,339 @@
+// Atomic swaps of coins on one chain for coins on another, e.g. mainnet
+// and a second instance of the chain, using HTLCs with the same payment
+// hash on both sides. Either both transfers happen or neither does:
+//
+//   1. the initiator picks a secret preimage and funds an HTLC on its chain
+//      paying the participant, refundable after `timeout`
+//   2. the participant checks it and funds an HTLC on the other chain paying
+//      the initiator with the same payment hash, refundable sooner
+//   3. the initiator claims the participant's HTLC, revealing the preimage
+//   4. the participant reads the preimage from that claim and claims the
+//      initiator's HTLC
+//
+// If the other side stops responding, each party refunds its own HTLC once
+// it times out. The participant's HTLC has to time out well before the
+// initiator's, measured in time rather than blocks, as the two chains
+// don't share heights; otherwise the initiator could claim at the last
+// moment and refund its own HTLC before the participant gets to claim it.
+// Twice the time is usual.
+//
+// Each party keeps a Swap tracking its side. Transactions are returned
+// rather than sent, so the caller submits them to a Blockchain or
+// broadcasts them through its Node.
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::errors::{BuildError, SignerError, SwapError};
+use crate::hash::OutPoint;
+use crate::mempool::DEFAULT_MIN_RELAY_FEE_RATE;
+use crate::script::{self, Htlc, Script};
+use crate::transaction::{self, Transaction, TxInput, TxOutput};
+use crate::tx_builder::TxBuilder;
+use crate::wallet::Wallet;
+use rand_core::RngCore;
+use std::fmt;
+
+// Blocks a counterparty's HTLC must have left before it times out when it
+// is accepted, so there is time to claim it
+pub const MIN_CLAIM_BLOCKS: u64 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapRole {
+    Initiator,
+    Participant,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapState {
+    Created,
+    // Our HTLC is funded, the counterparty's not yet found
+    Funded,
+    // The counterparty's HTLC is found, ours not yet funded
+    CounterpartyFunded,
+    // Both HTLCs are funded
+    Locked,
+    // We spent the counterparty's HTLC: the swap is done
+    Claimed,
+    // We took our HTLC back after its timeout
+    Refunded,
+}
+
+impl fmt::Display for SwapState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            SwapState::Created => "created",
+            SwapState::Funded => "funded",
+            SwapState::CounterpartyFunded => "counterparty_funded",
+            SwapState::Locked => "locked",
+            SwapState::Claimed => "claimed",
+            SwapState::Refunded => "refunded",
+        };
+        f.write_str(name)
+    }
+}
+
+pub struct Swap {
+    role: SwapRole,
+    state: SwapState,
+    payment_hash: [u8; 32],
+    // Known to the initiator from the start, and to the participant once the
+    // initiator claims
+    preimage: Option<[u8; 32]>,
+    value: Amount,
+    // The HTLC we fund, on our chain
+    offered: Htlc,
+    offered_outpoint: Option<OutPoint>,
+    // The HTLC the counterparty funds, on its chain
+    expected: Option<Htlc>,
+    expected_outpoint: Option<OutPoint>,
+}
+
+impl Swap {
+    // Starts a swap of `value` on the wallet's chain to `counterparty`,
+    // refundable from `timeout` on
+    pub fn initiate(wallet: &Wallet, counterparty: [u8; 32], value: Amount, timeout: u64) -> Swap {
+        let mut preimage = [0u8; 32];
+        rand_core::OsRng.fill_bytes(&mut preimage);
+        let mut swap = Swap::participate(
+            wallet,
+            counterparty,
+            script::payment_hash(&preimage),
+            value,
+            timeout,
+        );
+        swap.role = SwapRole::Initiator;
+        swap.preimage = Some(preimage);
+        swap
+    }
+
+    // Joins a swap started by `counterparty` with `payment_hash`
+    pub fn participate(
+        wallet: &Wallet,
+        counterparty: [u8; 32],
+        payment_hash: [u8; 32],
+        value: Amount,
+        timeout: u64,
+    ) -> Swap {
+        Swap {
+            role: SwapRole::Participant,
+            state: SwapState::Created,
+            payment_hash,
+            preimage: None,
+            value,
+            offered: wallet.new_htlc(counterparty, payment_hash, timeout),
+            offered_outpoint: None,
+            expected: None,
+            expected_outpoint: None,
+        }
+    }
+
+    pub fn role(&self) -> SwapRole {
+        self.role
+    }
+
+    pub fn state(&self) -> SwapState {
+        self.state
+    }
+
+    pub fn payment_hash(&self) -> [u8; 32] {
+        self.payment_hash
+    }
+
+    pub fn preimage(&self) -> Option<[u8; 32]> {
+        self.preimage
+    }
+
+    // The terms of our HTLC, for the counterparty to look for
+    pub fn offered(&self) -> &Htlc {
+        &self.offered
+    }
+
+    pub fn offered_outpoint(&self) -> Option<OutPoint> {
+        self.offered_outpoint
+    }
+
+    pub fn expected(&self) -> Option<&Htlc> {
+        self.expected.as_ref()
+    }
+
+    // Builds the transaction funding our HTLC from the wallet's outputs on
+    // `chain`
+    pub fn fund(&mut self, wallet: &Wallet, chain: &Blockchain) -> Result<Transaction, SwapError> {
+        if !matches!(
+            self.state,
+            SwapState::Created | SwapState::CounterpartyFunded
+        ) {
+            return Err(SwapError::WrongState(self.state));
+        }
+        // The initiator goes first; the participant waits for its HTLC
+        if self.role == SwapRole::Participant && self.expected.is_none() {
+            return Err(SwapError::WrongState(self.state));
+        }
+
+        let htlc_output = TxOutput::new_htlc(self.value, &self.offered);
+        let mut builder = TxBuilder::new(wallet.pkhash);
+        builder.pay_output(htlc_output);
+        for outpoint in chain.utxos.utxos_from_pkhash(wallet.pkhash) {
+            let output = chain.utxos.get_utxo(&outpoint).unwrap();
+            builder.add_utxo(outpoint, output.value);
+        }
+        let tx = builder.build(wallet).map_err(SwapError::Build)?;
+
+        let index = tx
+            .outputs
+            .iter()
+            .position(|output| output.pkhash == self.offered.hash())
+            .unwrap();
+        self.offered_outpoint = Some(OutPoint::new(tx.id, index as u16));
+        self.state = match self.state {
+            SwapState::CounterpartyFunded => SwapState::Locked,
+            _ => SwapState::Funded,
+        };
+        Ok(tx)
+    }
+
+    // Checks that the counterparty funded `htlc` on `chain` as agreed: paying
+    // us at least `min_value` with our payment hash, and with time left to
+    // claim it
+    pub fn accept(
+        &mut self,
+        wallet: &Wallet,
+        chain: &Blockchain,
+        htlc: Htlc,
+        min_value: Amount,
+    ) -> Result<(), SwapError> {
+        if !matches!(self.state, SwapState::Created | SwapState::Funded) {
+            return Err(SwapError::WrongState(self.state));
+        }
+        if htlc.payment_hash != self.payment_hash || htlc.recipient != wallet.pkhash {
+            return Err(SwapError::WrongTerms);
+        }
+        if htlc.timeout < chain.height() + 1 + MIN_CLAIM_BLOCKS {
+            return Err(SwapError::TimeoutTooSoon);
+        }
+
+        let outpoint = chain
+            .utxos
+            .utxos_from_pkhash(htlc.hash())
+            .into_iter()
+            .find(|outpoint| {
+                let output = chain.utxos.get_utxo(outpoint).unwrap();
+                output.script == Script::Htlc && output.value >= min_value
+            })
+            .ok_or(SwapError::HtlcNotFound)?;
+
+        self.expected = Some(htlc);
+        self.expected_outpoint = Some(outpoint);
+        self.state = match self.state {
+            SwapState::Funded => SwapState::Locked,
+            _ => SwapState::CounterpartyFunded,
+        };
+        Ok(())
+    }
+
+    // Looks on our chain for the counterparty's claim of our HTLC, in the
+    // mempool or a block, and learns the preimage from it
+    pub fn find_preimage(&mut self, chain: &Blockchain) -> Option<[u8; 32]> {
+        if self.preimage.is_some() {
+            return self.preimage;
+        }
+        let outpoint = self.offered_outpoint?;
+        let in_mempool = chain
+            .mempool
+            .spender(&outpoint)
+            .and_then(|txid| chain.mempool.get(&txid))
+            .map(|entry| &entry.tx);
+        let in_blocks = chain.iter().rev().flat_map(|block| &block.transactions);
+        let preimage = in_mempool
+            .into_iter()
+            .chain(in_blocks)
+            .flat_map(|tx| &tx.inputs)
+            .filter(|input| input.outpoint() == outpoint)
+            .find_map(|input| input.witness.htlc.as_ref()?.preimage)
+            .filter(|preimage| script::payment_hash(preimage) == self.payment_hash)?;
+        self.preimage = Some(preimage);
+        Some(preimage)
+    }
+
+    // Builds the transaction claiming the counterparty's HTLC on `chain` to
+    // the wallet. The participant has to find the preimage first.
+    pub fn claim(&mut self, wallet: &Wallet, chain: &Blockchain) -> Result<Transaction, SwapError> {
+        if self.state != SwapState::Locked {
+            return Err(SwapError::WrongState(self.state));
+        }
+        let preimage = self.preimage.ok_or(SwapError::PreimageUnknown)?;
+        let htlc = self.expected.unwrap();
+        let tx = spend_htlc(wallet, chain, self.expected_outpoint.unwrap(), |tx| {
+            wallet.claim_htlc(tx, 0, &htlc, preimage)
+        })?;
+        self.state = SwapState::Claimed;
+        Ok(tx)
+    }
+
+    // Whether our HTLC timed out unclaimed, so it should be refunded
+    pub fn can_refund(&self, chain: &Blockchain) -> bool {
+        matches!(self.state, SwapState::Funded | SwapState::Locked)
+            && chain.height() + 1 >= self.offered.timeout
+            && self
+                .offered_outpoint
+                .is_some_and(|outpoint| chain.utxos.get_utxo(&outpoint).is_some())
+    }
+
+    // Builds the transaction taking our HTLC on `chain` back once it timed
+    // out
+    pub fn refund(
+        &mut self,
+        wallet: &Wallet,
+        chain: &Blockchain,
+    ) -> Result<Transaction, SwapError> {
+        if !matches!(self.state, SwapState::Funded | SwapState::Locked) {
+            return Err(SwapError::WrongState(self.state));
+        }
+        if chain.height() + 1 < self.offered.timeout {
+            return Err(SwapError::NotTimedOut(self.offered.timeout));
+        }
+        let htlc = self.offered;
+        let tx = spend_htlc(wallet, chain, self.offered_outpoint.unwrap(), |tx| {
+            wallet.refund_htlc(tx, 0, &htlc)
+        })?;
+        self.state = SwapState::Refunded;
+        Ok(tx)
+    }
+}
+
+// A transaction sending an HTLC output on `chain` to the wallet, less the
+// fee at the minimum relay fee rate. `sign` signs its only input.
+fn spend_htlc(
+    wallet: &Wallet,
+    chain: &Blockchain,
+    outpoint: OutPoint,
+    sign: impl Fn(&mut Transaction) -> Result<bool, SignerError>,
+) -> Result<Transaction, SwapError> {
+    let value = chain
+        .utxos
+        .get_utxo(&outpoint)
+        .ok_or(SwapError::HtlcNotFound)?
+        .value;
+    let mut fee = Amount::ZERO;
+    loop {
+        let remaining = value
+            .checked_sub(fee)
+            .filter(|remaining| *remaining > 0)
+            .ok_or(SwapError::Build(BuildError::InsufficientFunds {
+                available: value,
+                needed: fee,
+            }))?;
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(outpoint.txid, outpoint.index).with_value(value)],
+            vec![TxOutput::new(remaining, wallet.pkhash)],
+        );
+        if !sign(&mut tx).map_err(SwapError::Signer)? {
+            return Err(SwapError::WrongTerms);
+        }
+        let required = transaction::fee_for_size(tx.serialized_size(), DEFAULT_MIN_RELAY_FEE_RATE);
+        if fee >= required {
+            return Ok(tx);
+        }
+        fee = required;
+    }
+}
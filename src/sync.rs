@@ -0,0 +1,372 @@
+use crate::block::{Block, BlockHeader};
+use crate::clock::{Clock, SystemClock};
+use crate::engine::ConsensusEngine;
+use crate::errors::BlockValidationError;
+use crate::hash::Hash256;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Peers this many blocks ahead are synced from headers first; smaller gaps
+// are filled block by block
+pub const IBD_THRESHOLD: u64 = 16;
+// Headers sent in one message at most
+pub const MAX_HEADERS: usize = 2000;
+// Blocks past the next one to connect that may be downloaded at once
+pub const DOWNLOAD_WINDOW: u64 = 256;
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+// A block not received this long after it was requested is asked of
+// another peer
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncState {
+    Idle,
+    // Waiting for headers from the peer
+    Headers { peer: SocketAddr },
+    // Downloading the bodies of the headers received
+    Blocks,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeadersOutcome {
+    // Not asked for, or not from the peer asked
+    Ignored,
+    // The peer has more; ask again from header_locator()
+    More,
+    // The last headers arrived and block downloads can start
+    Done,
+}
+
+struct InFlight {
+    height: u64,
+    peer: SocketAddr,
+    requested_at: Instant,
+}
+
+// Initial block download: once a peer is far ahead, its headers are fetched
+// and checked first, then the blocks are requested from every peer that has
+// them, a window at a time, and handed back in order for connecting.
+pub struct SyncManager {
+    state: SyncState,
+    peer_heights: HashMap<SocketAddr, u64>,
+    // Headers whose blocks haven't been connected yet, by height
+    headers: BTreeMap<u64, Hash256>,
+    // Height and hash of the last header received, or of the tip when the
+    // sync started
+    last_header: (u64, Hash256),
+    in_flight: HashMap<Hash256, InFlight>,
+    // With the peer each was asked from
+    downloaded: BTreeMap<u64, (Block, SocketAddr)>,
+    // Height of the next block to hand back
+    next_height: u64,
+}
+
+impl SyncManager {
+    pub fn new() -> SyncManager {
+        SyncManager {
+            state: SyncState::Idle,
+            peer_heights: HashMap::new(),
+            headers: BTreeMap::new(),
+            last_header: (0, Hash256::default()),
+            in_flight: HashMap::new(),
+            downloaded: BTreeMap::new(),
+            next_height: 0,
+        }
+    }
+
+    pub fn state(&self) -> SyncState {
+        self.state
+    }
+
+    pub fn is_syncing(&self) -> bool {
+        self.state != SyncState::Idle
+    }
+
+    pub fn update_peer(&mut self, peer: SocketAddr, height: u64) {
+        let known = self.peer_heights.entry(peer).or_default();
+        *known = (*known).max(height);
+    }
+
+    // Its blocks in flight are requested again from other peers
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.peer_heights.remove(peer);
+        self.in_flight
+            .retain(|_, in_flight| in_flight.peer != *peer);
+        if self.state == (SyncState::Headers { peer: *peer }) {
+            self.reset();
+        }
+    }
+
+    // Starts syncing if a peer is far enough ahead of the tip, returning
+    // the peer to ask for headers
+    pub fn start(&mut self, height: u64, tip: Hash256) -> Option<SocketAddr> {
+        if self.is_syncing() {
+            return None;
+        }
+        let (&peer, _) = self
+            .peer_heights
+            .iter()
+            .filter(|(_, peer_height)| **peer_height > height + IBD_THRESHOLD)
+            .max_by_key(|(peer, peer_height)| (**peer_height, std::cmp::Reverse(**peer)))?;
+        self.state = SyncState::Headers { peer };
+        self.last_header = (height, tip);
+        self.next_height = height + 1;
+        Some(peer)
+    }
+
+    // Where the next headers should start from
+    pub fn header_locator(&self) -> Vec<Hash256> {
+        vec![self.last_header.1]
+    }
+
+    // Headers must extend those received so far and pass the chain's
+    // consensus engine; on an error the sync is abandoned and the peer
+    // should be punished
+    pub fn headers_received(
+        &mut self,
+        peer: SocketAddr,
+        headers: &[BlockHeader],
+        engine: &dyn ConsensusEngine,
+    ) -> Result<HeadersOutcome, BlockValidationError> {
+        if self.state != (SyncState::Headers { peer }) {
+            return Ok(HeadersOutcome::Ignored);
+        }
+        for header in headers {
+            let result = self.check_header(header, engine);
+            if result.is_err() {
+                self.reset();
+                return result.map(|_| HeadersOutcome::Ignored);
+            }
+            self.last_header = (header.index, header.hash());
+            self.headers.insert(header.index, self.last_header.1);
+        }
+
+        if headers.len() >= MAX_HEADERS {
+            return Ok(HeadersOutcome::More);
+        }
+        self.state = if self.headers.is_empty() {
+            SyncState::Idle
+        } else {
+            SyncState::Blocks
+        };
+        Ok(HeadersOutcome::Done)
+    }
+
+    fn check_header(
+        &self,
+        header: &BlockHeader,
+        engine: &dyn ConsensusEngine,
+    ) -> Result<(), BlockValidationError> {
+        let (height, hash) = self.last_header;
+        if header.prev_hash != hash {
+            return Err(BlockValidationError::InvalidPreviousHash);
+        }
+        if header.index != height + 1 {
+            return Err(BlockValidationError::InvalidIndex);
+        }
+        if header.timestamp > SystemClock.now() {
+            return Err(BlockValidationError::TimestampInFuture);
+        }
+        engine.check_header(header)
+    }
+
+    // The blocks to request now and whom from. Requests that timed out are
+    // handed to another peer; each peer gets at most
+    // MAX_BLOCKS_IN_FLIGHT_PER_PEER, the least busy first.
+    pub fn next_requests(&mut self, now: Instant) -> Vec<(SocketAddr, Hash256)> {
+        if self.state != SyncState::Blocks {
+            return Vec::new();
+        }
+        self.in_flight.retain(|_, in_flight| {
+            now.duration_since(in_flight.requested_at) < BLOCK_DOWNLOAD_TIMEOUT
+        });
+
+        let mut load: HashMap<SocketAddr, usize> =
+            self.peer_heights.keys().map(|peer| (*peer, 0)).collect();
+        for in_flight in self.in_flight.values() {
+            *load.entry(in_flight.peer).or_default() += 1;
+        }
+
+        let end = self.next_height + DOWNLOAD_WINDOW;
+        let wanted: Vec<(u64, Hash256)> = self
+            .headers
+            .range(self.next_height..end)
+            .filter(|(height, hash)| {
+                !self.downloaded.contains_key(height) && !self.in_flight.contains_key(hash)
+            })
+            .map(|(height, hash)| (*height, *hash))
+            .collect();
+
+        let mut requests = Vec::new();
+        for (height, hash) in wanted {
+            let peer = load
+                .iter()
+                .filter(|(peer, count)| {
+                    **count < MAX_BLOCKS_IN_FLIGHT_PER_PEER
+                        && self.peer_heights.get(*peer).is_some_and(|h| *h >= height)
+                })
+                .min_by_key(|(peer, count)| (**count, **peer))
+                .map(|(peer, _)| *peer);
+            let Some(peer) = peer else {
+                continue;
+            };
+            *load.get_mut(&peer).unwrap() += 1;
+            self.in_flight.insert(
+                hash,
+                InFlight {
+                    height,
+                    peer,
+                    requested_at: now,
+                },
+            );
+            requests.push((peer, hash));
+        }
+        requests
+    }
+
+    // Blocks the sync didn't ask for are given back, to be handled as usual
+    pub fn block_received(&mut self, block: Block) -> Option<Block> {
+        let hash = block.header.hash();
+        let Some(in_flight) = self.in_flight.remove(&hash) else {
+            return Some(block);
+        };
+        self.downloaded
+            .insert(in_flight.height, (block, in_flight.peer));
+        None
+    }
+
+    // The downloaded blocks that can be connected next, in order, with the
+    // peers they came from. The sync ends once every header's block has been
+    // handed back.
+    pub fn take_ready(&mut self) -> Vec<(Block, SocketAddr)> {
+        let mut ready = Vec::new();
+        while let Some(entry) = self.downloaded.remove(&self.next_height) {
+            self.headers.remove(&self.next_height);
+            self.next_height += 1;
+            ready.push(entry);
+        }
+        if self.state == SyncState::Blocks && self.headers.is_empty() {
+            self.reset();
+        }
+        ready
+    }
+
+    // The headers whose blocks are still to be connected, by height, each
+    // the parent of the next
+    pub fn headers(&self) -> impl Iterator<Item = (u64, Hash256)> + '_ {
+        self.headers.iter().map(|(height, hash)| (*height, *hash))
+    }
+
+    // Abandons the sync, e.g. after a downloaded block failed to connect
+    pub fn reset(&mut self) {
+        self.state = SyncState::Idle;
+        self.headers.clear();
+        self.in_flight.clear();
+        self.downloaded.clear();
+    }
+}
+
+impl Default for SyncManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::engine::ProofOfWorkEngine;
+    use crate::params::ChainParams;
+    use crate::utils::mine;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn chain(length: u64) -> Blockchain {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        for _ in 0..length {
+            let mut block = blockchain.next_block();
+            mine(&mut block, [1; 32], blockchain.get_block_reward());
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    }
+
+    #[test]
+    fn test_sync_from_two_peers() {
+        let source = chain(20);
+        let genesis = source.get_block_by_height(0).unwrap().digest;
+        let mut sync = SyncManager::new();
+
+        sync.update_peer(peer(1), 10);
+        assert_eq!(sync.start(0, genesis), None);
+        sync.update_peer(peer(1), 20);
+        sync.update_peer(peer(2), 15);
+        assert_eq!(sync.start(0, genesis), Some(peer(1)));
+        assert_eq!(sync.state(), SyncState::Headers { peer: peer(1) });
+
+        let headers = source.get_headers_after(&sync.header_locator(), MAX_HEADERS);
+        assert_eq!(
+            sync.headers_received(peer(2), &headers, &ProofOfWorkEngine::default()),
+            Ok(HeadersOutcome::Ignored)
+        );
+        assert_eq!(
+            sync.headers_received(peer(1), &headers, &ProofOfWorkEngine::default()),
+            Ok(HeadersOutcome::Done)
+        );
+
+        // the blocks past 15 can only come from the first peer
+        let now = Instant::now();
+        let requests = sync.next_requests(now);
+        assert_eq!(requests.len(), 20);
+        assert!(requests.iter().any(|(addr, _)| *addr == peer(2)));
+        assert!(requests[15..].iter().all(|(addr, _)| *addr == peer(1)));
+        assert!(sync.next_requests(now).is_empty());
+
+        // blocks are handed back in order, whatever order they arrive in
+        for height in (1..=20).rev() {
+            let block = source.get_block_by_height(height).unwrap().clone();
+            assert!(sync.block_received(block).is_none());
+            let ready = sync.take_ready();
+            assert_eq!(ready.len(), if height == 1 { 20 } else { 0 });
+        }
+        assert!(!sync.is_syncing());
+    }
+
+    #[test]
+    fn test_timeouts_and_bad_headers() {
+        let source = chain(20);
+        let genesis = source.get_block_by_height(0).unwrap().digest;
+        let mut sync = SyncManager::new();
+        sync.update_peer(peer(1), 20);
+        sync.update_peer(peer(2), 20);
+        sync.start(0, genesis);
+        let headers = source.get_headers_after(&[genesis], MAX_HEADERS);
+        sync.headers_received(peer(1), &headers, &ProofOfWorkEngine::default())
+            .unwrap();
+
+        let now = Instant::now();
+        let first = sync.next_requests(now);
+        assert_eq!(first.len(), 20);
+        sync.remove_peer(&peer(1));
+        let again = sync.next_requests(now);
+        assert_eq!(again.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER - 10);
+        assert!(again.iter().all(|(addr, _)| *addr == peer(2)));
+        assert_eq!(
+            sync.next_requests(now + BLOCK_DOWNLOAD_TIMEOUT).len(),
+            MAX_BLOCKS_IN_FLIGHT_PER_PEER
+        );
+
+        // headers that don't link up abandon the sync
+        sync.reset();
+        sync.start(0, genesis);
+        assert_eq!(
+            sync.headers_received(peer(2), &headers[1..], &ProofOfWorkEngine::default()),
+            Err(BlockValidationError::InvalidPreviousHash)
+        );
+        assert!(!sync.is_syncing());
+    }
+}
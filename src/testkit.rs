@@ -0,0 +1,319 @@
+use crate::blockchain::{BlockAcceptance, Blockchain};
+use crate::clock::MockClock;
+use crate::errors::TransactionError;
+use crate::hash::Hash256;
+use crate::network::Message;
+use crate::params::ChainParams;
+use crate::transaction::Transaction;
+use crate::utils;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+pub type NodeId = usize;
+
+// Deliveries handled by run_until_idle() before it gives up, in case nodes
+// keep messaging each other forever
+const MAX_DELIVERIES: usize = 1_000_000;
+
+struct Delivery {
+    from: NodeId,
+    to: NodeId,
+    message: Message,
+}
+
+// Several chains in one process, linked by virtual connections that deliver
+// messages after a set latency. Time only moves when the simulation is run,
+// so scenarios play out the same way every time. Nodes relay blocks and
+// transactions, and ask the sender for the parent of a block they can't
+// connect. A node on a losing branch switches once it has the blocks of
+// one with more work.
+pub struct Simulation {
+    nodes: Vec<Blockchain>,
+    // (lower id, higher id) -> latency in milliseconds
+    links: HashMap<(NodeId, NodeId), u64>,
+    // Nodes only reach those in the same group
+    groups: Vec<usize>,
+    // (delivery time, sequence number) -> delivery
+    queue: BTreeMap<(u64, u64), Delivery>,
+    sequence: u64,
+    // Milliseconds since the simulation started
+    now: u64,
+    // Seconds since the Unix epoch when the simulation started
+    start: u64,
+    clock: Arc<MockClock>,
+}
+
+impl Simulation {
+    pub fn new(nodes: usize) -> Simulation {
+        Simulation::with_params(ChainParams::regtest(), nodes)
+    }
+
+    pub fn with_params(params: ChainParams, nodes: usize) -> Simulation {
+        let start = params.genesis_timestamp + 1;
+        let clock = Arc::new(MockClock::new(start));
+        let groups = vec![0; nodes];
+        let nodes = (0..nodes)
+            .map(|_| {
+                let mut blockchain = Blockchain::new(params.clone());
+                blockchain.set_clock(clock.clone());
+                blockchain
+            })
+            .collect();
+        Simulation {
+            nodes,
+            groups,
+            links: HashMap::new(),
+            queue: BTreeMap::new(),
+            sequence: 0,
+            now: 0,
+            start,
+            clock,
+        }
+    }
+
+    pub fn node(&self, id: NodeId) -> &Blockchain {
+        &self.nodes[id]
+    }
+
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Blockchain {
+        &mut self.nodes[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Milliseconds of virtual time since the start
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    // Links two nodes, which then send each other their tips
+    pub fn connect(&mut self, a: NodeId, b: NodeId, latency_ms: u64) {
+        self.links.insert(link(a, b), latency_ms);
+        self.exchange_tips(a, b);
+    }
+
+    // Splits the nodes into groups that can't reach each other. Nodes left
+    // out of every group are cut off from all others. Messages already on
+    // their way across the split are lost.
+    pub fn partition(&mut self, groups: &[&[NodeId]]) {
+        // Group 0 is never assigned here, and each left-out node gets its
+        // own
+        for (id, group) in self.groups.iter_mut().enumerate() {
+            *group = groups.len() + 1 + id;
+        }
+        for (index, members) in groups.iter().enumerate() {
+            for &id in members.iter() {
+                self.groups[id] = index + 1;
+            }
+        }
+    }
+
+    // Reconnects every node to the others it is linked to, and has linked
+    // nodes that were apart send each other their tips
+    pub fn heal(&mut self) {
+        let previous = std::mem::replace(&mut self.groups, vec![0; self.nodes.len()]);
+        let mut rejoined: Vec<(NodeId, NodeId)> = self
+            .links
+            .keys()
+            .filter(|(a, b)| previous[*a] != previous[*b])
+            .copied()
+            .collect();
+        rejoined.sort();
+        for (a, b) in rejoined {
+            self.exchange_tips(a, b);
+        }
+    }
+
+    // Mines a block on top of the node's tip and sends it to its peers
+    pub fn mine(&mut self, id: NodeId, pkhash: [u8; 32]) -> Hash256 {
+        let blockchain = &mut self.nodes[id];
+        let mut block = blockchain.next_block();
+        utils::mine(&mut block, pkhash, blockchain.get_block_reward());
+        let digest = block.digest;
+        blockchain.add_block(block.clone()).unwrap();
+        self.relay(id, None, Message::Block(block));
+        digest
+    }
+
+    pub fn submit_transaction(
+        &mut self,
+        id: NodeId,
+        tx: Transaction,
+    ) -> Result<(), TransactionError> {
+        self.nodes[id].submit_transaction(tx.clone())?;
+        self.relay(id, None, Message::Transaction(tx));
+        Ok(())
+    }
+
+    // Delivers the messages due in the next `ms` milliseconds
+    pub fn run_for(&mut self, ms: u64) {
+        let end = self.now + ms;
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > end {
+                break;
+            }
+            let ((at, _), delivery) = entry.remove_entry();
+            self.set_time(at);
+            self.deliver(delivery);
+        }
+        self.set_time(end);
+    }
+
+    // Delivers messages until none are left. Returns false if they never
+    // ran out.
+    pub fn run_until_idle(&mut self) -> bool {
+        for _ in 0..MAX_DELIVERIES {
+            let Some(((at, _), delivery)) = self.queue.pop_first() else {
+                return true;
+            };
+            self.set_time(at);
+            self.deliver(delivery);
+        }
+        self.queue.is_empty()
+    }
+
+    pub fn tips(&self) -> Vec<Hash256> {
+        self.nodes.iter().map(|node| node.prev_hash()).collect()
+    }
+
+    // Whether every node has the same tip
+    pub fn converged(&self) -> bool {
+        self.tips().windows(2).all(|pair| pair[0] == pair[1])
+    }
+
+    fn set_time(&mut self, now: u64) {
+        self.now = now;
+        self.clock.set(self.start + now / 1000);
+    }
+
+    fn reachable(&self, a: NodeId, b: NodeId) -> bool {
+        self.links.contains_key(&link(a, b)) && self.groups[a] == self.groups[b]
+    }
+
+    fn send(&mut self, from: NodeId, to: NodeId, message: Message) {
+        if !self.reachable(from, to) {
+            return;
+        }
+        let at = self.now + self.links[&link(from, to)];
+        self.sequence += 1;
+        self.queue
+            .insert((at, self.sequence), Delivery { from, to, message });
+    }
+
+    fn relay(&mut self, from: NodeId, except: Option<NodeId>, message: Message) {
+        for to in 0..self.nodes.len() {
+            if to != from && Some(to) != except && self.links.contains_key(&link(from, to)) {
+                self.send(from, to, message.clone());
+            }
+        }
+    }
+
+    fn exchange_tips(&mut self, a: NodeId, b: NodeId) {
+        for (from, to) in [(a, b), (b, a)] {
+            if self.nodes[from].height() > 0 {
+                let tip = self.nodes[from].iter().last().unwrap().clone();
+                self.send(from, to, Message::Block(tip));
+            }
+        }
+    }
+
+    fn deliver(&mut self, delivery: Delivery) {
+        let Delivery { from, to, message } = delivery;
+        // Lost if the link went down on the way
+        if !self.reachable(from, to) {
+            return;
+        }
+        match message {
+            Message::Block(block) => {
+                let prev_hash = block.header.prev_hash;
+                let relay = block.clone();
+                match self.nodes[to].process_block(block) {
+                    Ok((BlockAcceptance::OrphanStored, _)) => {
+                        self.send(to, from, Message::GetBlockByHash(prev_hash));
+                    }
+                    Ok((_, connected)) => {
+                        self.relay(to, Some(from), Message::Block(relay));
+                        for digest in connected {
+                            let block = self.nodes[to].get_block_by_hash(&digest).cloned();
+                            if let Some(block) = block {
+                                self.relay(to, Some(from), Message::Block(block));
+                            }
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+            Message::GetBlockByHash(hash) => {
+                let block = self.nodes[to].get_block_by_hash(&hash).cloned();
+                if let Some(block) = block {
+                    self.send(to, from, Message::Block(block));
+                }
+            }
+            Message::Transaction(tx) if self.nodes[to].submit_transaction(tx.clone()).is_ok() => {
+                self.relay(to, Some(from), Message::Transaction(tx));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn link(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_propagate_with_latency() {
+        let mut sim = Simulation::new(3);
+        sim.connect(0, 1, 100);
+        sim.connect(1, 2, 100);
+
+        let digest = sim.mine(0, [1; 32]);
+        sim.run_for(150);
+        assert_eq!(sim.tips(), [digest, digest, sim.node(2).prev_hash()]);
+        assert_eq!(sim.node(2).height(), 0);
+        sim.run_for(50);
+        assert!(sim.converged());
+        assert_eq!(sim.now(), 200);
+        assert!(sim.run_until_idle());
+    }
+
+    #[test]
+    fn test_partition_and_heal() {
+        let mut sim = Simulation::new(4);
+        for (a, b) in [(0, 1), (1, 2), (2, 3), (3, 0)] {
+            sim.connect(a, b, 20);
+        }
+
+        // on its way when the network splits, and lost
+        sim.mine(0, [1; 32]);
+        sim.run_for(10);
+        sim.partition(&[&[0, 1], &[2, 3]]);
+        assert!(sim.run_until_idle());
+        assert_eq!(sim.node(1).height(), 1);
+        assert_eq!(sim.node(3).height(), 0);
+
+        for _ in 0..3 {
+            sim.mine(0, [1; 32]);
+        }
+        assert!(sim.run_until_idle());
+        assert_eq!(sim.node(1).height(), 4);
+        assert_eq!(sim.node(2).height(), 0);
+        assert!(!sim.converged());
+
+        // the other side catches up by asking for the parents it is missing
+        sim.heal();
+        assert!(sim.run_until_idle());
+        assert!(sim.converged());
+        assert_eq!(sim.node(3).height(), 4);
+    }
+}
@@ -1,60 +1,94 @@
-use crate::errors::TransactionError;
+use crate::address::Address;
+use crate::amount::Amount;
+use crate::clock::{Clock, SystemClock};
+use crate::errors::{DecodeError, SignerError, TransactionError};
+use crate::hash::{OutPoint, TxId};
+use crate::json::impl_serde_struct;
+use crate::script::{self, Htlc, HtlcSpend, KeySignature, Script, Witness};
+use crate::sighash::{self, SighashBase, SighashType};
+use crate::signature::{self, SignatureCheck, SignatureScheme, Signer};
 use crate::utils;
 use bincode::{Decode, Encode};
-use k256::ecdsa::signature::Verifier;
-use k256::ecdsa::{Signature, SigningKey, signature::Signer};
 use sha2::{Digest, Sha256};
+use std::ops::RangeInclusive;
 use std::{fmt, vec};
 
-//TODO: use ed25519
+// The version new transactions get. Version 1 signatures covered neither
+// the outputs nor the spent amounts.
+pub const TRANSACTION_VERSION: u32 = 2;
+// The versions this software can validate. Which of them a block may hold
+// depends on its height and ChainParams::transaction_versions.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u32> = 2..=TRANSACTION_VERSION;
+// Locktimes below this are block heights, the rest unix timestamps
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+// Bytes a miner may put in the coinbase, e.g. a tag or an extra nonce
+pub const MAX_COINBASE_DATA: usize = 100;
+// Encoded size of an input spending a pay-to-pubkey-hash output worth less
+// than 2^32 base units with an ECDSA signature, the usual cost of spending an
+// output
+pub const SPEND_INPUT_SIZE: usize = 143;
+
+// The fee paying `fee_rate` base units per 1000 bytes for `size` bytes,
+// rounded up
+pub fn fee_for_size(size: usize, fee_rate: u64) -> Amount {
+    Amount((size as u64).saturating_mul(fee_rate).div_ceil(1000))
+}
 
 #[derive(Encode, Decode, Clone)]
 pub struct TxInput {
-    pub txid: [u8; 32],
+    pub txid: TxId,
     pub output: u16,
+    pub scheme: SignatureScheme,
     pub signature: [u8; 64],
-    pub pubkey: [u8; 33],
+    pub pubkey: Vec<u8>,
+    // Value of the spent output. The signature commits to it, so a signer
+    // can't be misled about what it spends, and the UTXO set must agree.
+    pub value: Amount,
+    pub witness: Witness,
+    // Blocks that must be mined on top of the spent output's block before
+    // it can be spent, 0 for none
+    pub relative_locktime: u32,
+    // The parts of the transaction the input's signatures cover
+    pub sighash_type: SighashType,
 }
 
-impl TxInput {
-    pub fn sign(&mut self, signing_key: &SigningKey) {
-        self.pubkey = signing_key
-            .verifying_key()
-            .to_encoded_point(true)
-            .as_bytes()
-            .try_into()
-            .unwrap();
-
-        let tx_for_sign: TxInputForSign = (&*self).into();
-
-        let signature: Signature = signing_key.sign(&tx_for_sign.sighash());
-        self.signature = signature.to_bytes().into();
-    }
-
-    pub fn verify_signature(&self) -> Result<(), TransactionError> {
-        let tx_for_sign: TxInputForSign = self.into();
-
-        let verify_key = k256::ecdsa::VerifyingKey::from_sec1_bytes(&self.pubkey)
-            .map_err(|_| TransactionError::InvalidPublicKey)?;
-
-        let signature = k256::ecdsa::Signature::from_bytes((&self.signature).into())
-            .map_err(|_| TransactionError::InvalidSignature)?;
-
-        verify_key
-            .verify(&tx_for_sign.sighash(), &signature)
-            .map_err(|_| TransactionError::SignatureVerificationFailed)?;
-
-        Ok(())
-    }
-}
+impl_serde_struct!(TxInput {
+    txid: TxId,
+    output: u16,
+    scheme: SignatureScheme,
+    signature: [u8; 64] => hex,
+    pubkey: Vec<u8> => hex,
+    value: Amount,
+    witness: Witness,
+    relative_locktime: u32,
+    sighash_type: SighashType,
+});
 
 impl TxInput {
-    pub fn new_unsigned(txid: [u8; 32], output: u16) -> TxInput {
+    pub fn new_unsigned(txid: impl Into<TxId>, output: u16) -> TxInput {
         TxInput {
-            txid,
+            txid: txid.into(),
             output,
+            scheme: SignatureScheme::default(),
             signature: [0; 64],
-            pubkey: [0; 33],
+            pubkey: Vec::new(),
+            value: Amount::ZERO,
+            witness: Witness::default(),
+            relative_locktime: 0,
+            sighash_type: SighashType::ALL,
+        }
+    }
+
+    pub fn with_value(mut self, value: impl Into<Amount>) -> TxInput {
+        self.value = value.into();
+        self
+    }
+
+    // The output this input spends
+    pub fn outpoint(&self) -> OutPoint {
+        OutPoint {
+            txid: self.txid,
+            index: self.output,
         }
     }
 }
@@ -64,52 +98,94 @@ impl fmt::Display for TxInput {
         writeln!(
             f,
             "  TxID: {}, Output: {}, Signature: {}, Pubkey: {}",
-            hex::encode(self.txid),
+            self.txid,
             self.output,
             hex::encode(self.signature),
-            hex::encode(self.pubkey)
+            hex::encode(&self.pubkey)
         )?;
         Ok(())
     }
 }
 
-#[derive(Encode, Debug)]
-pub struct TxInputForSign<'a> {
-    pub txid: &'a [u8; 32],
-    pub output: &'a u16,
-    pub pubkey: &'a [u8; 33],
+#[derive(Encode, Decode, Clone)]
+pub struct TxOutput {
+    pub value: Amount,
+    pub pkhash: [u8; 32],
+    pub script: Script,
 }
 
-impl TxInputForSign<'_> {
-    fn as_bincode(&self) -> Vec<u8> {
-        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
+impl_serde_struct!(TxOutput {
+    value: Amount,
+    pkhash: [u8; 32] => hex,
+    script: Script,
+});
+
+impl TxOutput {
+    // Accepts either a decoded Address or a raw pkhash
+    pub fn new(value: impl Into<Amount>, recipient: impl Into<Address>) -> TxOutput {
+        TxOutput {
+            value: value.into(),
+            pkhash: recipient.into().pkhash,
+            script: Script::PayToPubkeyHash,
+        }
     }
 
-    fn sighash(&self) -> [u8; 32] {
-        let data = self.as_bincode();
-        Sha256::digest(&data).to_vec().try_into().unwrap()
+    // Spendable with signatures from `threshold` of the keys, which the
+    // spender has to list in the same order
+    pub fn new_multisig(value: impl Into<Amount>, threshold: u8, pubkeys: &[Vec<u8>]) -> TxOutput {
+        TxOutput {
+            value: value.into(),
+            pkhash: script::multisig_hash(pubkeys),
+            script: Script::Multisig { threshold },
+        }
     }
-}
 
-impl<'a> From<&'a TxInput> for TxInputForSign<'a> {
-    fn from(input: &'a TxInput) -> Self {
-        TxInputForSign {
-            txid: &input.txid,
-            output: &input.output,
-            pubkey: &input.pubkey,
+    pub fn new_timelocked(
+        value: impl Into<Amount>,
+        recipient: impl Into<Address>,
+        height: u64,
+    ) -> TxOutput {
+        TxOutput {
+            value: value.into(),
+            pkhash: recipient.into().pkhash,
+            script: Script::Timelock { height },
         }
     }
-}
 
-#[derive(Encode, Decode, Clone)]
-pub struct TxOutput {
-    pub value: u64,
-    pub pkhash: [u8; 32],
-}
+    pub fn new_htlc(value: impl Into<Amount>, htlc: &Htlc) -> TxOutput {
+        TxOutput {
+            value: value.into(),
+            pkhash: htlc.hash(),
+            script: Script::Htlc,
+        }
+    }
 
-impl TxOutput {
-    pub fn new(value: u64, pkhash: [u8; 32]) -> TxOutput {
-        TxOutput { value, pkhash }
+    pub fn new_anyone_can_spend(value: impl Into<Amount>) -> TxOutput {
+        TxOutput {
+            value: value.into(),
+            pkhash: [0; 32],
+            script: Script::AnyoneCanSpend,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        Address::from(self.pkhash)
+    }
+
+    pub fn serialized_size(&self) -> usize {
+        bincode::encode_to_vec(self, bincode::config::standard())
+            .unwrap()
+            .len()
+    }
+
+    // Outputs worth less than this cost more to spend at `fee_rate` than
+    // they are worth
+    pub fn dust_threshold(&self, fee_rate: u64) -> Amount {
+        fee_for_size(self.serialized_size() + SPEND_INPUT_SIZE, fee_rate)
+    }
+
+    pub fn is_dust(&self, fee_rate: u64) -> bool {
+        self.value < self.dust_threshold(fee_rate)
     }
 }
 
@@ -117,9 +193,10 @@ impl fmt::Display for TxOutput {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(
             f,
-            "  Value: {}, PKHash: {}",
+            "  Value: {}, PKHash: {}, Script: {}",
             self.value,
-            hex::encode(self.pkhash)
+            hex::encode(self.pkhash),
+            self.script
         )?;
         Ok(())
     }
@@ -127,47 +204,108 @@ impl fmt::Display for TxOutput {
 
 #[derive(Encode, Clone)]
 struct TransactionNoID<'a> {
+    version: &'a u32,
     inputs: &'a Vec<TxInput>,
     outputs: &'a Vec<TxOutput>,
     pub timestamp: &'a u64,
+    locktime: &'a u64,
+    replaceable: &'a bool,
 }
 
-#[derive(Encode, Decode)]
+#[derive(Encode, Decode, Clone)]
 pub struct Transaction {
-    pub id: [u8; 32],
+    pub version: u32,
+    pub id: TxId,
     pub timestamp: u64,
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
+    // Not valid in a block below this height, or before the chain's median
+    // time past reaches it if at least LOCKTIME_THRESHOLD. 0 for none.
+    pub locktime: u64,
+    // Opts in to being replaced in the mempool by a conflicting transaction
+    // paying a higher fee
+    pub replaceable: bool,
 }
 
-//TODO: Transaction builder patterns
+impl_serde_struct!(Transaction {
+    version: u32,
+    id: TxId,
+    timestamp: u64,
+    inputs: Vec<TxInput>,
+    outputs: Vec<TxOutput>,
+    locktime: u64,
+    replaceable: bool,
+});
 
 impl Transaction {
     pub fn new(inputs: Vec<TxInput>, outputs: Vec<TxOutput>) -> Transaction {
+        Transaction::new_with_clock(inputs, outputs, &SystemClock)
+    }
+
+    // Timestamped by `clock`
+    pub fn new_with_clock(
+        inputs: Vec<TxInput>,
+        outputs: Vec<TxOutput>,
+        clock: &dyn Clock,
+    ) -> Transaction {
         Transaction {
-            id: [0; 32],
-            timestamp: utils::unix_timestamp(),
+            version: TRANSACTION_VERSION,
+            id: TxId::default(),
+            timestamp: clock.now(),
             inputs,
             outputs,
+            locktime: 0,
+            replaceable: false,
         }
     }
 
-    pub fn new_coinbase(miner_pkhash: [u8; 32], reward: u64, block_height: u64) -> Transaction {
+    pub fn new_coinbase(
+        miner_pkhash: [u8; 32],
+        reward: impl Into<Amount>,
+        block_height: u64,
+    ) -> Transaction {
+        Transaction::new_coinbase_with_data(
+            miner_pkhash,
+            reward,
+            block_height,
+            Vec::new(),
+            SystemClock.now(),
+        )
+    }
+
+    // The coinbase input has no key, so its pubkey field carries the data
+    // instead. Like the rest of the input it is covered by the txid.
+    // Stamped with `timestamp`, normally that of its block.
+    pub fn new_coinbase_with_data(
+        miner_pkhash: [u8; 32],
+        reward: impl Into<Amount>,
+        block_height: u64,
+        data: Vec<u8>,
+        timestamp: u64,
+    ) -> Transaction {
         let mut txid = [0u8; 32];
 
         // Encode the block height as big-endian into the first 8 bytes of txid
         txid[0..8].copy_from_slice(&block_height.to_be_bytes());
 
         let mut tx = Transaction {
-            id: [0; 32],
-            timestamp: utils::unix_timestamp(),
+            version: TRANSACTION_VERSION,
+            id: TxId::default(),
+            timestamp,
             inputs: vec![TxInput {
-                txid,
+                txid: TxId(txid),
                 output: 0,
+                scheme: SignatureScheme::default(),
                 signature: [0; 64],
-                pubkey: [0; 33],
+                pubkey: data,
+                value: Amount::ZERO,
+                witness: Witness::default(),
+                relative_locktime: 0,
+                sighash_type: SighashType::ALL,
             }],
             outputs: vec![TxOutput::new(reward, miner_pkhash)],
+            locktime: 0,
+            replaceable: false,
         };
         tx.id = tx.hash();
         tx
@@ -175,94 +313,346 @@ impl Transaction {
 
     fn as_bincode_no_id(&self) -> Vec<u8> {
         let no_id = TransactionNoID {
+            version: &self.version,
             inputs: &self.inputs,
             outputs: &self.outputs,
             timestamp: &self.timestamp,
+            locktime: &self.locktime,
+            replaceable: &self.replaceable,
         };
 
         bincode::encode_to_vec(no_id, bincode::config::standard()).unwrap()
     }
 
-    fn hash(&self) -> [u8; 32] {
+    fn hash(&self) -> TxId {
         let data = self.as_bincode_no_id();
-        Sha256::digest(&data).to_vec().try_into().unwrap()
+        TxId(Sha256::digest(&data).into())
     }
 
-    pub fn sign(&mut self, signing_key: &SigningKey) {
-        self.inputs.iter_mut().for_each(|input| {
-            input.sign(signing_key);
-        });
+    // Needed after any change to the inputs or outputs, e.g. for a
+    // transaction that only spends anyone-can-spend outputs and so is never
+    // signed
+    pub fn update_id(&mut self) {
         self.id = self.hash();
     }
 
-    fn verify_signatures(&self) -> Result<(), TransactionError> {
-        for input in &self.inputs {
-            input.verify_signature()?;
+    // Multisig inputs are left alone; they are signed one key at a time with
+    // sign_multisig_input
+    pub fn sign(
+        &mut self,
+        signer: &(impl Signer + ?Sized),
+        chain_id: &[u8; 32],
+    ) -> Result<(), SignerError> {
+        for index in 0..self.inputs.len() {
+            if self.inputs[index].witness.is_empty() {
+                self.sign_input_unchecked(index, signer, chain_id)?;
+            }
         }
+        self.update_id();
+        Ok(())
+    }
+
+    // Signs a single pay-to-pubkey-hash input, with the sighash type already
+    // set on it
+    pub fn sign_input(
+        &mut self,
+        index: usize,
+        signer: &(impl Signer + ?Sized),
+        chain_id: &[u8; 32],
+    ) -> Result<(), SignerError> {
+        self.sign_input_unchecked(index, signer, chain_id)?;
+        self.update_id();
+        Ok(())
+    }
+
+    // Leaves the id as it was
+    fn sign_input_unchecked(
+        &mut self,
+        index: usize,
+        signer: &(impl Signer + ?Sized),
+        chain_id: &[u8; 32],
+    ) -> Result<(), SignerError> {
+        let input = &mut self.inputs[index];
+        input.scheme = signer.scheme();
+        input.pubkey = signer.public_key();
+
+        let sighash = sighash::sighash(self, index, chain_id);
+        self.inputs[index].signature = signer.sign(&sighash)?;
         Ok(())
     }
 
-    pub fn verify(&self) -> Result<(), TransactionError> {
+    // Sets input `index` up to be spent by the holders of an aggregate
+    // Schnorr key signing together (see musig.rs), returning the message
+    // they sign. The signature goes in with set_input_signature.
+    pub fn aggregate_sighash(
+        &mut self,
+        index: usize,
+        aggregate_key: &[u8; 32],
+        chain_id: &[u8; 32],
+    ) -> [u8; 32] {
+        let input = &mut self.inputs[index];
+        input.scheme = SignatureScheme::Schnorr;
+        input.pubkey = aggregate_key.to_vec();
+        sighash::sighash(self, index, chain_id)
+    }
+
+    pub fn set_input_signature(&mut self, index: usize, signature: [u8; 64]) {
+        self.inputs[index].signature = signature;
+        self.update_id();
+    }
+
+    // Spends an HTLC output, claiming it with the preimage or refunding it
+    // without. Returns false if the key is not the one `spend` requires.
+    pub fn sign_htlc_input(
+        &mut self,
+        index: usize,
+        signer: &(impl Signer + ?Sized),
+        spend: HtlcSpend,
+        chain_id: &[u8; 32],
+    ) -> Result<bool, SignerError> {
+        if script::pubkey_hash(&signer.public_key()) != spend.signer() {
+            return Ok(false);
+        }
+        self.inputs[index].witness = Witness {
+            htlc: Some(spend),
+            ..Witness::default()
+        };
+        self.sign_input(index, signer, chain_id)?;
+        Ok(true)
+    }
+
+    // Adds or replaces this key's signature for a multisig output locked to
+    // `pubkeys`. Returns false if the key is not one of them.
+    pub fn sign_multisig_input(
+        &mut self,
+        index: usize,
+        signer: &(impl Signer + ?Sized),
+        pubkeys: &[Vec<u8>],
+        chain_id: &[u8; 32],
+    ) -> Result<bool, SignerError> {
+        let pubkey = signer.public_key();
+        let Some(key) = pubkeys
+            .iter()
+            .position(|k| *k == pubkey)
+            .and_then(|key| u8::try_from(key).ok())
+        else {
+            return Ok(false);
+        };
+
+        // the keys are in the witness, so the input's own fields stay empty
+        let input = &mut self.inputs[index];
+        input.scheme = signer.scheme();
+        input.pubkey.clear();
+        input.signature = [0; 64];
+        input.witness.pubkeys = pubkeys.to_vec();
+
+        let signature = KeySignature {
+            key,
+            signature: signer.sign(&sighash::sighash(self, index, chain_id))?,
+        };
+        let signatures = &mut self.inputs[index].witness.signatures;
+        match signatures.binary_search_by_key(&key, |s| s.key) {
+            Ok(i) => signatures[i] = signature,
+            Err(i) => signatures.insert(i, signature),
+        }
+        self.update_id();
+        Ok(true)
+    }
+
+    fn verify_signatures(&self, chain_id: &[u8; 32]) -> Result<(), TransactionError> {
+        let mut checks = Vec::new();
+        self.signature_checks(chain_id, &mut checks)?;
+        signature::verify_batch(&checks)
+    }
+
+    // Checks whatever signatures the input carries. Whether they are enough
+    // to spend the output depends on its script, which is evaluated against
+    // the UTXO set.
+    pub fn verify_input_signatures(
+        &self,
+        index: usize,
+        chain_id: &[u8; 32],
+    ) -> Result<(), TransactionError> {
+        let mut checks = Vec::new();
+        self.input_signature_checks(index, chain_id, &mut checks)?;
+        checks.iter().try_for_each(SignatureCheck::verify)
+    }
+
+    pub fn signature_checks<'a>(
+        &'a self,
+        chain_id: &[u8; 32],
+        checks: &mut Vec<SignatureCheck<'a>>,
+    ) -> Result<(), TransactionError> {
+        for index in 0..self.inputs.len() {
+            self.input_signature_checks(index, chain_id, checks)?;
+        }
+        Ok(())
+    }
+
+    // Adds the signatures of input `index` to verify to `checks`, failing
+    // early if its witness is malformed
+    fn input_signature_checks<'a>(
+        &'a self,
+        index: usize,
+        chain_id: &[u8; 32],
+        checks: &mut Vec<SignatureCheck<'a>>,
+    ) -> Result<(), TransactionError> {
+        let input = &self.inputs[index];
+        let sighash = sighash::sighash(self, index, chain_id);
+        if !input.pubkey.is_empty() {
+            checks.push(SignatureCheck {
+                scheme: input.scheme,
+                pubkey: &input.pubkey,
+                message: sighash,
+                signature: &input.signature,
+            });
+        }
+
+        let mut previous_key = None;
+        for signature in &input.witness.signatures {
+            if previous_key.is_some_and(|key| signature.key <= key) {
+                return Err(TransactionError::InvalidSignature);
+            }
+            previous_key = Some(signature.key);
+
+            let pubkey = input
+                .witness
+                .pubkeys
+                .get(signature.key as usize)
+                .ok_or(TransactionError::InvalidPublicKey)?;
+            checks.push(SignatureCheck {
+                scheme: input.scheme,
+                pubkey,
+                message: sighash,
+                signature: &signature.signature,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn verify(&self, chain_id: &[u8; 32]) -> Result<(), TransactionError> {
+        self.verify_at(chain_id, SystemClock.now())
+    }
+
+    // With `now` as the current time
+    pub fn verify_at(&self, chain_id: &[u8; 32], now: u64) -> Result<(), TransactionError> {
+        self.verify_without_signatures_at(now)?;
+        self.verify_signatures(chain_id)
+    }
+
+    // Everything verify checks except the signatures, which are by far the
+    // most expensive part
+    pub fn verify_without_signatures(&self) -> Result<(), TransactionError> {
+        self.verify_without_signatures_at(SystemClock.now())
+    }
+
+    pub fn verify_without_signatures_at(&self, now: u64) -> Result<(), TransactionError> {
+        if !SUPPORTED_VERSIONS.contains(&self.version) {
+            return Err(TransactionError::UnsupportedVersion);
+        }
         if self.inputs.is_empty() {
             return Err(TransactionError::EmptyInputs);
         } else if self.outputs.is_empty() {
             return Err(TransactionError::EmptyOutputs);
         }
 
-        for input in &self.inputs {
+        for (index, input) in self.inputs.iter().enumerate() {
             if self
                 .inputs
                 .iter()
-                .filter(|i| i.txid == input.txid && i.output == input.output)
+                .filter(|i| i.outpoint() == input.outpoint())
                 .count()
                 > 1
             {
                 return Err(TransactionError::DuplicateInput);
             }
+            if input.sighash_type.base == SighashBase::Single && index >= self.outputs.len() {
+                return Err(TransactionError::InvalidSighashType);
+            }
         }
 
         for output in &self.outputs {
             if output.value == 0 {
                 return Err(TransactionError::ZeroValueOutput);
             }
-            if self
-                .outputs
-                .iter()
-                .filter(|o| o.pkhash == output.pkhash)
-                .count()
-                > 1
-            {
-                return Err(TransactionError::DuplicateOutput);
-            }
+        }
+        let total = Amount::checked_sum(self.outputs.iter().map(|output| output.value));
+        if !total.is_some_and(|total| total.is_valid()) {
+            return Err(TransactionError::AmountOutOfRange);
         }
 
         if self.id != self.hash() {
             return Err(TransactionError::InvalidID);
-        } else if self.timestamp > utils::unix_timestamp() {
+        } else if self.timestamp > now {
             return Err(TransactionError::InvalidTimestamp);
         }
 
-        self.verify_signatures()?;
-
         Ok(())
     }
-    pub fn verify_coinbase(&self) -> Result<(), TransactionError> {
-        if self.inputs.len() != 1
-            || self.outputs.len() != 1
-            || self.inputs[0].signature != [0; 64]
-            || self.inputs[0].pubkey != [0; 33]
-            || self.inputs[0].output != 0
-        {
-            return Err(TransactionError::InvalidCoinbase);
+
+    pub fn as_bincode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+
+    pub fn from_bincode(data: &[u8]) -> Result<Transaction, DecodeError> {
+        utils::decode_with_limit::<_, { utils::MAX_DECODE_SIZE }>(data)
+    }
+
+    // Hex encoded bincode, as sent to the RPC server
+    pub fn from_hex(data: &str) -> Result<Transaction, DecodeError> {
+        let data = hex::decode(data.trim()).map_err(|_| DecodeError::Invalid)?;
+        Transaction::from_bincode(&data)
+    }
+
+    pub fn serialized_size(&self) -> usize {
+        self.as_bincode().len()
+    }
+
+    // In base units per 1000 bytes, rounded down
+    pub fn fee_rate(&self, fee: Amount) -> u64 {
+        (fee.0 as u128 * 1000 / self.serialized_size() as u128) as u64
+    }
+
+    // Has the shape of a coinbase, whatever height it commits to
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1
+            && self.outputs.len() == 1
+            && self.inputs[0].signature == [0; 64]
+            && self.inputs[0].pubkey.len() <= MAX_COINBASE_DATA
+            && self.inputs[0].witness.is_empty()
+            && self.inputs[0].output == 0
+    }
+
+    pub fn coinbase_data(&self) -> Option<&[u8]> {
+        self.is_coinbase().then(|| self.inputs[0].pubkey.as_slice())
+    }
+
+    // The height the coinbase input commits to: big-endian in the first 8
+    // bytes of its txid, the rest zero
+    pub fn coinbase_height(&self) -> Option<u64> {
+        let txid = &self.inputs.first()?.txid.0;
+        if !self.is_coinbase() || txid[8..] != [0; 24] {
+            return None;
         }
+        Some(u64::from_be_bytes(txid[..8].try_into().unwrap()))
+    }
 
+    // Committing to the height keeps coinbases of different blocks from
+    // ever sharing a txid, which would overwrite outputs in the UTXO set
+    pub fn verify_coinbase(&self, height: u64) -> Result<(), TransactionError> {
+        if !self.is_coinbase() {
+            return Err(TransactionError::InvalidCoinbase);
+        }
+        if self.coinbase_height() != Some(height) {
+            return Err(TransactionError::CoinbaseHeightMismatch);
+        }
         Ok(())
     }
 }
 
 impl fmt::Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "Transaction ID: {}", hex::encode(self.id))?;
+        writeln!(f, "Transaction ID: {}", self.id)?;
         writeln!(f, "Timestamp: {}", self.timestamp)?;
         writeln!(f, "Inputs:")?;
         for input in &self.inputs {
@@ -280,6 +670,7 @@ impl fmt::Display for Transaction {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::MAX_MONEY;
     use crate::wallet::Wallet;
 
     #[test]
@@ -295,14 +686,36 @@ mod tests {
 
         let mut transaction = Transaction::new(inputs, outputs);
 
-        wallet.sign_transaction(&mut transaction);
+        wallet.sign_transaction(&mut transaction).unwrap();
 
-        assert!(!transaction.id.is_empty());
+        assert_ne!(transaction.id, TxId::default());
         assert!(!transaction.inputs[0].signature.is_empty());
         assert!(transaction.inputs[0].signature != transaction.inputs[1].signature);
         assert!(transaction.id == transaction.hash());
 
-        assert!(transaction.verify().is_ok());
+        assert!(transaction.verify(&wallet.chain_id()).is_ok());
+    }
+
+    #[test]
+    fn test_sign_and_verify_ed25519() {
+        let wallet = Wallet::with_scheme(SignatureScheme::Ed25519, Wallet::new().chain_id());
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([0; 32], 0)],
+            vec![TxOutput::new(50, [0; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+
+        assert_eq!(tx.inputs[0].scheme, SignatureScheme::Ed25519);
+        assert_eq!(tx.inputs[0].pubkey.len(), 32);
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
+
+        // the scheme byte is covered by the signature
+        tx.inputs[0].scheme = SignatureScheme::Ecdsa;
+        assert_eq!(
+            tx.verify_signatures(&wallet.chain_id()),
+            Err(TransactionError::InvalidPublicKey)
+        );
     }
 
     #[test]
@@ -318,12 +731,12 @@ mod tests {
 
         let mut transaction = Transaction::new(inputs, outputs);
 
-        wallet.sign_transaction(&mut transaction);
+        wallet.sign_transaction(&mut transaction).unwrap();
 
         transaction.inputs[0].signature[0] = 1;
 
         assert_eq!(
-            transaction.verify_signatures(),
+            transaction.verify_signatures(&wallet.chain_id()),
             Err(TransactionError::SignatureVerificationFailed)
         );
     }
@@ -335,13 +748,13 @@ mod tests {
             vec![TxOutput::new(50, [0; 32])],
         );
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
-        assert!(tx.verify().is_ok());
+        wallet.sign_transaction(&mut tx).unwrap();
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
 
         // tamper
         tx.inputs[0].signature[0] ^= 0xFF;
         assert_eq!(
-            tx.verify_signatures(),
+            tx.verify_signatures(&wallet.chain_id()),
             Err(TransactionError::SignatureVerificationFailed)
         );
     }
@@ -353,18 +766,34 @@ mod tests {
             vec![TxOutput::new(50, [0; 32])],
         );
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
-        assert!(tx.verify().is_ok());
+        wallet.sign_transaction(&mut tx).unwrap();
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
 
         // tamper
         tx.inputs[0].pubkey[1] ^= 0xAA;
-        let result = tx.verify_signatures();
+        let result = tx.verify_signatures(&wallet.chain_id());
         assert!(
             matches!(result, Err(TransactionError::SignatureVerificationFailed))
                 || matches!(result, Err(TransactionError::InvalidPublicKey))
         );
     }
 
+    #[test]
+    fn test_fails_if_signed_for_other_chain() {
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([0; 32], 0)],
+            vec![TxOutput::new(50, [0; 32])],
+        );
+        let wallet = Wallet::for_chain([7; 32]);
+        wallet.sign_transaction(&mut tx).unwrap();
+        assert!(tx.verify(&[7; 32]).is_ok());
+
+        assert_eq!(
+            tx.verify(&Wallet::new().chain_id()),
+            Err(TransactionError::SignatureVerificationFailed)
+        );
+    }
+
     #[test]
     fn test_fails_if_invalid_id() {
         let mut tx = Transaction::new(
@@ -372,11 +801,14 @@ mod tests {
             vec![TxOutput::new(50, [0; 32])],
         );
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
-        assert!(tx.verify().is_ok());
+        wallet.sign_transaction(&mut tx).unwrap();
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
 
-        tx.id[0] ^= 0xFF;
-        assert_eq!(tx.verify(), Err(TransactionError::InvalidID));
+        tx.id.0[0] ^= 0xFF;
+        assert_eq!(
+            tx.verify(&wallet.chain_id()),
+            Err(TransactionError::InvalidID)
+        );
     }
 
     #[test]
@@ -388,9 +820,12 @@ mod tests {
         tx.timestamp += 100;
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
 
-        assert_eq!(tx.verify(), Err(TransactionError::InvalidTimestamp));
+        assert_eq!(
+            tx.verify(&wallet.chain_id()),
+            Err(TransactionError::InvalidTimestamp)
+        );
     }
 
     #[test]
@@ -401,9 +836,30 @@ mod tests {
         );
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
 
-        assert_eq!(tx.verify(), Err(TransactionError::ZeroValueOutput));
+        assert_eq!(
+            tx.verify(&wallet.chain_id()),
+            Err(TransactionError::ZeroValueOutput)
+        );
+    }
+
+    #[test]
+    fn test_fails_if_above_max_money() {
+        let wallet = Wallet::new();
+        let half = MAX_MONEY.0 / 2 + 1;
+        for outputs in [
+            vec![TxOutput::new(MAX_MONEY.0 + 1, [1; 32])],
+            vec![TxOutput::new(half, [1; 32]), TxOutput::new(half, [2; 32])],
+            vec![TxOutput::new(u64::MAX, [1; 32]), TxOutput::new(1, [2; 32])],
+        ] {
+            let mut tx = Transaction::new(vec![TxInput::new_unsigned([0; 32], 0)], outputs);
+            wallet.sign_transaction(&mut tx).unwrap();
+            assert_eq!(
+                tx.verify(&wallet.chain_id()),
+                Err(TransactionError::AmountOutOfRange)
+            );
+        }
     }
 
     #[test]
@@ -417,22 +873,25 @@ mod tests {
         );
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
 
-        assert_eq!(tx.verify(), Err(TransactionError::DuplicateInput));
+        assert_eq!(
+            tx.verify(&wallet.chain_id()),
+            Err(TransactionError::DuplicateInput)
+        );
     }
 
     #[test]
-    fn test_fails_if_duplicate_output() {
+    fn test_outputs_to_same_pkhash() {
         let mut tx = Transaction::new(
             vec![TxInput::new_unsigned([0; 32], 0)],
             vec![TxOutput::new(50, [0; 32]), TxOutput::new(50, [0; 32])],
         );
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
 
-        assert_eq!(tx.verify(), Err(TransactionError::DuplicateOutput));
+        assert_eq!(tx.verify(&wallet.chain_id()), Ok(()));
     }
 
     #[test]
@@ -443,9 +902,12 @@ mod tests {
         );
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
 
-        assert_eq!(tx.verify_coinbase(), Err(TransactionError::InvalidCoinbase));
+        assert_eq!(
+            tx.verify_coinbase(0),
+            Err(TransactionError::InvalidCoinbase)
+        );
     }
 
     #[test]
@@ -456,8 +918,106 @@ mod tests {
         );
 
         let wallet = Wallet::new();
-        wallet.sign_transaction(&mut tx);
+        wallet.sign_transaction(&mut tx).unwrap();
+
+        assert_eq!(
+            tx.verify_coinbase(0),
+            Err(TransactionError::InvalidCoinbase)
+        );
+    }
+
+    #[test]
+    fn test_coinbase_data() {
+        let plain = Transaction::new_coinbase([1; 32], 50, 3);
+        let mut tagged = plain.clone();
+        tagged.inputs[0].pubkey = b"pool".to_vec();
+        tagged.update_id();
+        assert_ne!(plain.id, tagged.id);
+        assert_eq!(tagged.coinbase_data(), Some(&b"pool"[..]));
+        assert_eq!(plain.coinbase_data(), Some(&[][..]));
+
+        let max = Transaction::new_coinbase_with_data(
+            [1; 32],
+            50,
+            3,
+            vec![7; MAX_COINBASE_DATA],
+            plain.timestamp,
+        );
+        assert_eq!(max.verify_coinbase(3), Ok(()));
+        let too_long = Transaction::new_coinbase_with_data(
+            [1; 32],
+            50,
+            3,
+            vec![7; MAX_COINBASE_DATA + 1],
+            plain.timestamp,
+        );
+        assert_eq!(
+            too_long.verify_coinbase(3),
+            Err(TransactionError::InvalidCoinbase)
+        );
+        assert_eq!(too_long.coinbase_data(), None);
+    }
+
+    #[test]
+    fn test_coinbase_commits_to_height() {
+        let coinbase = Transaction::new_coinbase([1; 32], 50, 7);
+        assert_eq!(coinbase.coinbase_height(), Some(7));
+        assert_eq!(coinbase.verify_coinbase(7), Ok(()));
+        assert_eq!(
+            coinbase.verify_coinbase(8),
+            Err(TransactionError::CoinbaseHeightMismatch)
+        );
+
+        let mut other = coinbase.clone();
+        other.inputs[0].txid.0[31] = 1;
+        assert_eq!(other.coinbase_height(), None);
+        assert_eq!(
+            other.verify_coinbase(7),
+            Err(TransactionError::CoinbaseHeightMismatch)
+        );
+        assert_ne!(Transaction::new_coinbase([1; 32], 50, 8).id, coinbase.id);
+    }
+
+    #[test]
+    fn test_size_and_fee_rate() {
+        let wallet = Wallet::new();
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0).with_value(50_000_000)],
+            vec![TxOutput::new(500, [2; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        assert_eq!(tx.serialized_size(), tx.as_bincode().len());
+        assert_eq!(
+            bincode::encode_to_vec(&tx.inputs[0], bincode::config::standard())
+                .unwrap()
+                .len(),
+            SPEND_INPUT_SIZE
+        );
+
+        let size = tx.serialized_size() as u64;
+        assert_eq!(tx.fee_rate(Amount(size)), 1000);
+        assert_eq!(tx.fee_rate(Amount(size * 3)), 3000);
+        assert!(tx.fee_rate(Amount(size - 1)) < 1000);
+        assert_eq!(fee_for_size(1001, 1000), Amount(1001));
+        assert_eq!(fee_for_size(1001, 1), Amount(2));
+
+        let output = &tx.outputs[0];
+        assert_eq!(
+            output.dust_threshold(1000),
+            Amount((output.serialized_size() + SPEND_INPUT_SIZE) as u64)
+        );
+        assert!(!output.is_dust(1000));
+        assert!(output.is_dust(5000));
+        assert!(!output.is_dust(0));
+    }
+
+    #[test]
+    fn test_output_to_address() {
+        let wallet = Wallet::new();
+        let address: Address = wallet.address.to_string().parse().unwrap();
 
-        assert_eq!(tx.verify_coinbase(), Err(TransactionError::InvalidCoinbase));
+        let output = TxOutput::new(50, address);
+        assert_eq!(output.pkhash, wallet.pkhash);
+        assert_eq!(output.address(), wallet.address);
     }
 }
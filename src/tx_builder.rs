@@ -0,0 +1,474 @@
+use crate::address::Address;
+use crate::amount::Amount;
+use crate::errors::{BuildError, SignerError};
+use crate::fees::FeeEstimator;
+use crate::hash::OutPoint;
+use crate::mempool::DEFAULT_MIN_RELAY_FEE_RATE;
+use crate::payment_request::PaymentRequest;
+use crate::signature::{PrivateKey, SignatureScheme, Signer};
+use crate::transaction::{self, Transaction, TxInput, TxOutput};
+use crate::wallet::Wallet;
+use crate::wallet_state::WalletState;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fee {
+    Fixed(Amount),
+    // In base units per 1000 bytes of the signed transaction
+    Rate(u64),
+}
+
+// Builds and signs a payment from the wallet's outputs. Outputs are spent in
+// the order they were added until the payments and the fee are covered, and
+// the rest is sent back to the change address unless it is dust.
+pub struct TxBuilder {
+    payments: Vec<TxOutput>,
+    utxos: Vec<(OutPoint, Amount)>,
+    change: [u8; 32],
+    fee: Fee,
+    replaceable: bool,
+    locktime: u64,
+}
+
+impl TxBuilder {
+    pub fn new(change: impl Into<Address>) -> TxBuilder {
+        TxBuilder {
+            payments: Vec::new(),
+            utxos: Vec::new(),
+            change: change.into().pkhash,
+            fee: Fee::Rate(DEFAULT_MIN_RELAY_FEE_RATE),
+            replaceable: false,
+            locktime: 0,
+        }
+    }
+
+    pub fn pay(&mut self, value: impl Into<Amount>, recipient: impl Into<Address>) -> &mut Self {
+        self.payments.push(TxOutput::new(value, recipient));
+        self
+    }
+
+    // Fails if the request leaves the amount to the payer; set one on the
+    // request first
+    pub fn pay_request(&mut self, request: &PaymentRequest) -> Result<&mut Self, BuildError> {
+        let amount = request.amount.ok_or(BuildError::NoAmount)?;
+        Ok(self.pay(amount, request.address))
+    }
+
+    // Pays to any output, such as an HTLC or a multisig
+    pub fn pay_output(&mut self, output: TxOutput) -> &mut Self {
+        self.payments.push(output);
+        self
+    }
+
+    pub fn add_utxo(&mut self, outpoint: OutPoint, value: impl Into<Amount>) -> &mut Self {
+        self.utxos.push((outpoint, value.into()));
+        self
+    }
+
+    // Adds the outputs the wallet can spend, oldest first
+    pub fn add_spendable(&mut self, state: &WalletState) -> &mut Self {
+        let mut spendable = state.spendable();
+        spendable.sort_by_key(|(outpoint, owned)| (owned.height, *outpoint));
+        for (outpoint, owned) in spendable {
+            self.add_utxo(outpoint, owned.output.value);
+        }
+        self
+    }
+
+    pub fn fee(&mut self, fee: impl Into<Amount>) -> &mut Self {
+        self.fee = Fee::Fixed(fee.into());
+        self
+    }
+
+    pub fn fee_rate(&mut self, fee_rate: u64) -> &mut Self {
+        self.fee = Fee::Rate(fee_rate);
+        self
+    }
+
+    // A fee rate likely to confirm within `target_blocks`, or the default
+    // minimum relay fee rate while the estimator has too little data
+    pub fn estimate_fee(&mut self, estimator: &FeeEstimator, target_blocks: u64) -> &mut Self {
+        let fee_rate = estimator
+            .estimate(target_blocks)
+            .unwrap_or(DEFAULT_MIN_RELAY_FEE_RATE)
+            .max(DEFAULT_MIN_RELAY_FEE_RATE);
+        self.fee_rate(fee_rate)
+    }
+
+    pub fn replaceable(&mut self, replaceable: bool) -> &mut Self {
+        self.replaceable = replaceable;
+        self
+    }
+
+    pub fn locktime(&mut self, locktime: u64) -> &mut Self {
+        self.locktime = locktime;
+        self
+    }
+
+    pub fn build(&self, wallet: &Wallet) -> Result<Transaction, BuildError> {
+        self.build_excluding(|tx| wallet.sign_transaction(tx), |_| false)
+    }
+
+    pub fn build_with_signer(
+        &self,
+        signer: &(impl Signer + ?Sized),
+        chain_id: &[u8; 32],
+    ) -> Result<Transaction, BuildError> {
+        self.build_excluding(|tx| tx.sign(signer, chain_id), |_| false)
+    }
+
+    // Leaves the inputs for a key kept elsewhere to sign, e.g. on an offline
+    // machine through a PartialTransaction. The fee is worked out for ECDSA
+    // signatures, whose public keys are the largest, so signing with
+    // another scheme can only raise the fee rate.
+    pub fn build_unsigned(&self) -> Result<Transaction, BuildError> {
+        let sizing_key = PrivateKey::random(SignatureScheme::Ecdsa);
+        let mut tx = self.build_excluding(|tx| tx.sign(&sizing_key, &[0; 32]), |_| false)?;
+        for input in &mut tx.inputs {
+            input.pubkey.clear();
+            input.signature = [0; 64];
+        }
+        tx.update_id();
+        Ok(tx)
+    }
+
+    // Like build, but skips the outputs locked in `state` and locks the ones
+    // it spends, so another payment built before this one confirms doesn't
+    // pick them too. They are unlocked when it confirms or is abandoned, or
+    // with unlock_unspent if it is never sent.
+    pub fn build_and_lock(
+        &self,
+        wallet: &Wallet,
+        state: &mut WalletState,
+    ) -> Result<Transaction, BuildError> {
+        let tx = self.build_excluding(
+            |tx| wallet.sign_transaction(tx),
+            |outpoint| state.is_locked(outpoint),
+        )?;
+        for input in &tx.inputs {
+            state.lock_unspent(input.outpoint());
+        }
+        Ok(tx)
+    }
+
+    fn build_excluding(
+        &self,
+        sign: impl Fn(&mut Transaction) -> Result<(), SignerError>,
+        excluded: impl Fn(&OutPoint) -> bool,
+    ) -> Result<Transaction, BuildError> {
+        if self.payments.is_empty() {
+            return Err(BuildError::NoPayments);
+        }
+        let amount = Amount::checked_sum(self.payments.iter().map(|output| output.value))
+            .ok_or(BuildError::AmountOutOfRange)?;
+
+        // Adding inputs makes the transaction larger, so the fee is raised
+        // until it covers the signed size
+        let mut fee = match self.fee {
+            Fee::Fixed(fee) => fee,
+            Fee::Rate(_) => Amount::ZERO,
+        };
+        loop {
+            let needed = amount
+                .checked_add(fee)
+                .ok_or(BuildError::AmountOutOfRange)?;
+            let (inputs, total) = self.select(needed, &excluded)?;
+            let change = total.checked_sub(needed).unwrap();
+
+            let mut tx = Transaction::new(inputs, self.outputs(change));
+            tx.replaceable = self.replaceable;
+            tx.locktime = self.locktime;
+            sign(&mut tx).map_err(BuildError::Signer)?;
+
+            let Fee::Rate(fee_rate) = self.fee else {
+                return Ok(tx);
+            };
+            let required = transaction::fee_for_size(tx.serialized_size(), fee_rate);
+            if fee >= required {
+                return Ok(tx);
+            }
+            fee = required;
+        }
+    }
+
+    // The first outputs covering `needed`, with their total
+    fn select(
+        &self,
+        needed: Amount,
+        excluded: impl Fn(&OutPoint) -> bool,
+    ) -> Result<(Vec<TxInput>, Amount), BuildError> {
+        let mut inputs = Vec::new();
+        let mut total = Amount::ZERO;
+        for (outpoint, value) in &self.utxos {
+            if total >= needed {
+                break;
+            }
+            if excluded(outpoint) {
+                continue;
+            }
+            inputs.push(TxInput::new_unsigned(outpoint.txid, outpoint.index).with_value(*value));
+            total = total
+                .checked_add(*value)
+                .ok_or(BuildError::AmountOutOfRange)?;
+        }
+        if total < needed {
+            return Err(BuildError::InsufficientFunds {
+                available: total,
+                needed,
+            });
+        }
+        Ok((inputs, total))
+    }
+
+    // Change going to one of the recipients is folded into its payment,
+    // saving an output. Change too small to be worth
+    // spending is left to the miner.
+    fn outputs(&self, change: Amount) -> Vec<TxOutput> {
+        let mut outputs = self.payments.clone();
+        let change_output = TxOutput::new(change, self.change);
+        let fee_rate = match self.fee {
+            Fee::Rate(fee_rate) => fee_rate,
+            Fee::Fixed(_) => DEFAULT_MIN_RELAY_FEE_RATE,
+        };
+
+        if let Some(output) = outputs
+            .iter_mut()
+            .find(|output| output.pkhash == self.change)
+        {
+            output.value = output.value.checked_add(change).unwrap();
+        } else if change > 0 && !change_output.is_dust(fee_rate) {
+            outputs.push(change_output);
+        }
+        outputs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Block;
+    use crate::errors::KeystoreError;
+    use crate::psbt::PartialTransaction;
+    use crate::utxo::UTXOSet;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn funded(wallet: &Wallet, values: &[u64]) -> (TxBuilder, UTXOSet) {
+        let mut builder = TxBuilder::new(wallet.pkhash);
+        let mut utxos = UTXOSet::new();
+        for (i, value) in values.iter().enumerate() {
+            let outpoint = OutPoint::new([i as u8 + 1; 32], 0);
+            builder.add_utxo(outpoint, *value);
+            utxos.add_utxo(outpoint, TxOutput::new(*value, wallet.pkhash));
+        }
+        (builder, utxos)
+    }
+
+    #[test]
+    fn test_build_with_fee_rate() {
+        let wallet = Wallet::new();
+        let (mut builder, utxos) = funded(&wallet, &[30_000, 30_000, 30_000]);
+        builder.pay(50_000, [9; 32]).fee_rate(2000);
+
+        let tx = builder.build(&wallet).unwrap();
+        assert_eq!(tx.inputs.len(), 2);
+        assert_eq!(tx.outputs.len(), 2);
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
+
+        let fee = utxos.validate_transaction(&tx, 1).unwrap();
+        assert_eq!(fee, 60_000 - 50_000 - tx.outputs[1].value.0);
+        assert!(tx.fee_rate(fee) >= 2000);
+        assert_eq!(fee, transaction::fee_for_size(tx.serialized_size(), 2000));
+    }
+
+    #[test]
+    fn test_change_and_fixed_fee() {
+        let wallet = Wallet::new();
+        let (mut builder, _) = funded(&wallet, &[10_000]);
+
+        // the leftover 50 is dust, so it goes to the miner
+        builder.pay(9_450, [9; 32]).fee(500);
+        let tx = builder.build(&wallet).unwrap();
+        assert_eq!(tx.outputs.len(), 1);
+
+        // change to a recipient is added to its payment
+        let mut builder = TxBuilder::new([9; 32]);
+        builder
+            .add_utxo(OutPoint::new([1; 32], 0), 10_000)
+            .pay(1_000, [9; 32])
+            .fee(500)
+            .replaceable(true);
+        let tx = builder.build(&wallet).unwrap();
+        assert_eq!(tx.outputs.len(), 1);
+        assert_eq!(tx.outputs[0].value, 9_500);
+        assert!(tx.replaceable);
+    }
+
+    #[test]
+    fn test_build_unsigned_for_offline_signing() {
+        let wallet = Wallet::new();
+        let (mut builder, utxos) = funded(&wallet, &[30_000, 30_000]);
+        builder.pay(40_000, [9; 32]).fee_rate(2000);
+
+        let tx = builder.build_unsigned().unwrap();
+        assert!(tx.inputs.iter().all(|input| input.pubkey.is_empty()));
+        let mut ptx = PartialTransaction::new(tx);
+        ptx.fill_utxos(&utxos);
+
+        // carried to the offline machine and back as text
+        let mut offline = PartialTransaction::from_hex(&ptx.to_hex()).unwrap();
+        assert_eq!(wallet.sign_partial(&mut offline).unwrap(), 2);
+        ptx.merge(PartialTransaction::from_hex(&offline.to_hex()).unwrap())
+            .unwrap();
+
+        let signed = ptx.finalize().unwrap();
+        assert!(signed.verify(&wallet.chain_id()).is_ok());
+        let fee = utxos.validate_transaction(&signed, 1).unwrap();
+        assert_eq!(ptx.fee(), Some(fee));
+        assert!(signed.fee_rate(fee) >= 2000);
+    }
+
+    // Signs with a key it holds, like a hardware wallet would, counting the
+    // sighashes it is shown. Past `approved` of them, the user declines.
+    struct CountingSigner {
+        key: PrivateKey,
+        signed: Arc<AtomicUsize>,
+        approved: usize,
+    }
+
+    impl Signer for CountingSigner {
+        fn scheme(&self) -> SignatureScheme {
+            self.key.scheme()
+        }
+
+        fn public_key(&self) -> Vec<u8> {
+            self.key.public_key()
+        }
+
+        fn sign(&self, sighash: &[u8; 32]) -> Result<[u8; 64], SignerError> {
+            if self.signed.fetch_add(1, Ordering::SeqCst) >= self.approved {
+                return Err(SignerError::Rejected);
+            }
+            Ok(self.key.sign(sighash))
+        }
+    }
+
+    #[test]
+    fn test_build_with_external_signer() {
+        let signed = Arc::new(AtomicUsize::new(0));
+        let signer = CountingSigner {
+            key: PrivateKey::random(SignatureScheme::Schnorr),
+            signed: signed.clone(),
+            approved: usize::MAX,
+        };
+        let chain_id = Block::genesis().digest.0;
+        let wallet = Wallet::from_signer(signer, chain_id);
+        assert_eq!(wallet.scheme(), SignatureScheme::Schnorr);
+
+        let (mut builder, utxos) = funded(&wallet, &[30_000, 30_000]);
+        builder.pay(40_000, [9; 32]);
+        let tx = builder.build(&wallet).unwrap();
+        assert!(tx.verify(&chain_id).is_ok());
+        assert!(utxos.validate_transaction(&tx, 1).is_ok());
+        assert!(signed.load(Ordering::SeqCst) >= 2);
+
+        let tx = builder
+            .build_with_signer(wallet.signer(), &chain_id)
+            .unwrap();
+        assert!(tx.verify(&chain_id).is_ok());
+
+        // the key never leaves the signer
+        let path = std::env::temp_dir().join(format!("external_{}.dat", std::process::id()));
+        assert!(matches!(
+            wallet.save(&path, "pass"),
+            Err(KeystoreError::ExternalKey)
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_declining_signer() {
+        let signed = Arc::new(AtomicUsize::new(0));
+        let signer = CountingSigner {
+            key: PrivateKey::random(SignatureScheme::Ecdsa),
+            signed: signed.clone(),
+            approved: 1,
+        };
+        let wallet = Wallet::from_signer(signer, Block::genesis().digest.0);
+        let (mut builder, utxos) = funded(&wallet, &[30_000, 30_000]);
+        builder.pay(40_000, [9; 32]);
+        assert_eq!(
+            builder.build(&wallet).err(),
+            Some(BuildError::Signer(SignerError::Rejected))
+        );
+        assert_eq!(signed.load(Ordering::SeqCst), 2);
+
+        let mut ptx = PartialTransaction::new(builder.build_unsigned().unwrap());
+        ptx.fill_utxos(&utxos);
+        assert_eq!(wallet.sign_partial(&mut ptx), Err(SignerError::Rejected));
+    }
+
+    #[test]
+    fn test_build_errors() {
+        let wallet = Wallet::new();
+        let (mut builder, _) = funded(&wallet, &[1_000, 2_000]);
+        assert_eq!(builder.build(&wallet).err(), Some(BuildError::NoPayments));
+
+        builder.pay(2_500, [9; 32]).fee(600);
+        assert_eq!(
+            builder.build(&wallet).err(),
+            Some(BuildError::InsufficientFunds {
+                available: Amount(3_000),
+                needed: Amount(3_100),
+            })
+        );
+    }
+
+    #[test]
+    fn test_estimate_fee() {
+        let wallet = Wallet::new();
+        let (mut builder, utxos) = funded(&wallet, &[100_000]);
+        builder
+            .pay(50_000, [9; 32])
+            .estimate_fee(&FeeEstimator::new(), 2);
+        let tx = builder.build(&wallet).unwrap();
+        let fee = utxos.validate_transaction(&tx, 1).unwrap();
+        assert!(tx.fee_rate(fee) >= DEFAULT_MIN_RELAY_FEE_RATE);
+    }
+
+    #[test]
+    fn test_build_and_lock() {
+        let wallet = Wallet::new();
+        let mut state = WalletState::for_wallet(&wallet, 1);
+        for height in 1..=2 {
+            let mut block = Block::new(height, [0; 32], 0x2100_ffff, Vec::new());
+            block.add_coinbase_tx(wallet.pkhash, 30_000);
+            state.process_block(&block);
+        }
+        let mut builder = TxBuilder::new(wallet.pkhash);
+        builder.add_spendable(&state).pay(20_000, [9; 32]);
+
+        let first = builder.build_and_lock(&wallet, &mut state).unwrap();
+        let second = builder.build_and_lock(&wallet, &mut state).unwrap();
+        assert_ne!(first.inputs[0].outpoint(), second.inputs[0].outpoint());
+        assert!(state.spendable().is_empty());
+        assert!(matches!(
+            builder.build_and_lock(&wallet, &mut state),
+            Err(BuildError::InsufficientFunds { .. })
+        ));
+
+        // The second is never sent, and the first is abandoned
+        assert!(state.unlock_unspent(&second.inputs[0].outpoint()));
+        state.add_pending(first.clone());
+        assert_eq!(state.spendable().len(), 1);
+        state.abandon_transaction(&first.id);
+        assert_eq!(state.spendable().len(), 2);
+
+        // Spending in a block unlocks too
+        let third = builder.build_and_lock(&wallet, &mut state).unwrap();
+        let mut block = Block::new(3, [0; 32], 0x2100_ffff, Vec::new());
+        block.add_tx(third.clone());
+        block.add_coinbase_tx([0; 32], 30_000);
+        state.process_block(&block);
+        assert!(!state.is_locked(&third.inputs[0].outpoint()));
+    }
+}
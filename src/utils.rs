@@ -1,23 +1,114 @@
+use crate::amount::Amount;
 use crate::block::Block;
-use num_bigint::BigUint;
+use crate::blockchain::Blockchain;
+use crate::clock::{Clock, SystemClock};
+use crate::consensus::{self, PowAlgorithm, ProofOfWork};
+use crate::errors::{DecodeError, TransactionError};
+use bincode::Decode;
+use hmac::Mac;
+use hmac::digest::KeyInit;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn hash_less_than_target(hash: &[u8; 32], target: &[u8; 32]) -> bool {
-    let hash_num = BigUint::from_bytes_be(hash);
-    let target_num = BigUint::from_bytes_be(target);
-    hash_num < target_num
+// Blocks and transactions from peers or users may not claim more memory than
+// this when decoded
+pub(crate) const MAX_DECODE_SIZE: usize = 16 * 1024 * 1024;
+
+// Decodes untrusted bincode. Length prefixes are checked against `LIMIT`
+// before anything is allocated, and the whole input must be used.
+pub(crate) fn decode_with_limit<T: Decode<()>, const LIMIT: usize>(
+    data: &[u8],
+) -> Result<T, DecodeError> {
+    if data.len() > LIMIT {
+        return Err(DecodeError::TooLarge);
+    }
+    let config = bincode::config::standard().with_limit::<LIMIT>();
+    let (value, read) = bincode::decode_from_slice(data, config).map_err(|e| match e {
+        bincode::error::DecodeError::LimitExceeded => DecodeError::TooLarge,
+        _ => DecodeError::Invalid,
+    })?;
+    if read != data.len() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(value)
 }
 
-pub fn mine(block: &mut Block, miner_pkhash: [u8; 32], block_reward: u64) {
+pub fn mine(block: &mut Block, miner_pkhash: [u8; 32], block_reward: impl Into<Amount>) {
     block.add_coinbase_tx(miner_pkhash, block_reward);
-    while !hash_less_than_target(&block.digest, &block.target) {
-        block.update_nonce_and_timestamp();
+    solve(block);
+}
+
+// Finds a valid nonce for a block that already has its coinbase, with the
+// SHA-256 proof of work of the built-in networks
+pub fn solve(block: &mut Block) {
+    solve_with(block, &PowAlgorithm::Sha256);
+}
+
+pub fn solve_with(block: &mut Block, pow: &impl ProofOfWork) {
+    solve_with_clock(block, pow, &SystemClock);
+}
+
+// Restamping the header from `clock` as the nonces run out
+pub fn solve_with_clock(block: &mut Block, pow: &impl ProofOfWork, clock: &dyn Clock) {
+    let target = block.header.target();
+    while !consensus::meets_target(&block.header.pow_hash(pow), &target) {
+        block.update_nonce_and_timestamp(clock);
     }
 }
 
+// Like mine, but the coinbase also claims the fees paid by the block's
+// transactions
+pub fn mine_with_fees(
+    block: &mut Block,
+    blockchain: &Blockchain,
+    miner_pkhash: [u8; 32],
+) -> Result<(), TransactionError> {
+    let fees = blockchain.calculate_block_fees(block)?;
+    let reward = Amount(blockchain.get_block_reward())
+        .checked_add(fees)
+        .ok_or(TransactionError::AmountOutOfRange)?;
+    block.add_coinbase_tx(miner_pkhash, reward);
+    solve_with_clock(block, &blockchain.params().pow, blockchain.clock());
+    Ok(())
+}
+
 pub fn unix_timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs()
 }
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// PBKDF2 (RFC 8018) with an HMAC as the pseudorandom function
+pub(crate) fn pbkdf2<M: Mac + KeyInit + Clone>(
+    password: &[u8],
+    salt: &[u8],
+    iterations: u32,
+    out: &mut [u8],
+) {
+    let prf = <M as KeyInit>::new_from_slice(password).unwrap();
+    let block_len = <M as Mac>::finalize(prf.clone()).into_bytes().len();
+
+    for (block_index, chunk) in out.chunks_mut(block_len).enumerate() {
+        let mut u = prf
+            .clone()
+            .chain_update(salt)
+            .chain_update((block_index as u32 + 1).to_be_bytes())
+            .finalize()
+            .into_bytes();
+        let mut t = u.clone();
+
+        for _ in 1..iterations {
+            u = prf.clone().chain_update(&u).finalize().into_bytes();
+            t.iter_mut().zip(u.iter()).for_each(|(t, u)| *t ^= u);
+        }
+
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
@@ -1,67 +1,504 @@
+use crate::amount::Amount;
 use crate::block::Block;
-use crate::transaction::TxOutput;
-use std::collections::HashMap;
+use crate::errors::{SnapshotError, TransactionError, UndoError};
+use crate::hash::{Hash256, OutPoint, TxId};
+use crate::json::impl_serde_struct;
+use crate::script::Script;
+use crate::storage::{FileStore, MemoryStore, UtxoBatch, UtxoStore};
+use crate::transaction::{Transaction, TxOutput};
+use bincode::{Decode, Encode};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
 use std::fmt;
+use std::io;
+use std::path::Path;
+
+const SNAPSHOT_VERSION: u8 = 1;
+
+// The outputs a block spent from the set, with the values they had, in the
+// order its inputs spend them. Outputs created and spent within the block
+// were never in the set, so they aren't included.
+#[derive(Encode, Decode, Clone, Default)]
+pub struct UndoData {
+    pub spent: Vec<(OutPoint, TxOutput)>,
+}
 
 pub struct UTXOSet {
-    utxos: HashMap<([u8; 32], u16), TxOutput>,
+    store: Box<dyn UtxoStore>,
 }
 
 impl UTXOSet {
+    // Kept in memory
     pub fn new() -> UTXOSet {
+        UTXOSet::with_store(MemoryStore::new())
+    }
+
+    // Kept in a file, created if missing, which holds the set as it was
+    // when last written
+    pub fn open(path: impl AsRef<Path>) -> io::Result<UTXOSet> {
+        Ok(UTXOSet::with_store(FileStore::open(path)?))
+    }
+
+    pub fn with_store(store: impl UtxoStore + 'static) -> UTXOSet {
         UTXOSet {
-            utxos: HashMap::new(),
+            store: Box::new(store),
         }
     }
 
-    pub fn add_utxo(&mut self, txid: [u8; 32], index: u16, output: TxOutput) {
-        self.utxos.insert((txid, index), output);
+    // Writes to the store only fail on I/O errors, after which the set no
+    // longer matches the chain, so they are treated as fatal
+    fn write(&mut self, batch: UtxoBatch) {
+        self.store
+            .write_batch(batch)
+            .expect("could not write to the UTXO store");
+    }
+
+    pub fn add_utxo(&mut self, outpoint: OutPoint, output: TxOutput) {
+        let mut batch = UtxoBatch::new();
+        batch.put(outpoint, output);
+        self.write(batch);
+    }
+
+    pub fn remove_utxo(&mut self, outpoint: &OutPoint) {
+        let mut batch = UtxoBatch::new();
+        batch.delete(*outpoint);
+        self.write(batch);
+    }
+
+    pub fn get_utxo(&self, outpoint: &OutPoint) -> Option<TxOutput> {
+        self.store.get(outpoint)
     }
 
-    pub fn remove_utxo(&mut self, txid: [u8; 32], index: u16) {
-        self.utxos.remove(&(txid, index));
+    // The block the set was last updated with by update_with_block
+    pub fn tip(&self) -> Option<Hash256> {
+        self.store.tip()
     }
 
-    pub fn get_utxo(&self, txid: [u8; 32], index: u16) -> Option<&TxOutput> {
-        self.utxos.get(&(txid, index))
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.store.clear()
     }
 
-    pub fn update_with_block(&mut self, block: &Block) {
-        for tx in &block.transactions {
-            for input in &tx.inputs {
-                self.remove_utxo(input.txid, input.output);
+    // Checks the inputs of a non-coinbase transaction, to be included in a
+    // block at `height`, against the set and returns the fee it pays
+    pub fn validate_transaction(
+        &self,
+        tx: &Transaction,
+        height: u64,
+    ) -> Result<Amount, TransactionError> {
+        self.validate_transaction_with(tx, height, |_| None)
+    }
+
+    // Like validate_transaction, but inputs may also spend the outputs
+    // found by `unconfirmed`, e.g. those of mempool transactions or of
+    // earlier transactions in the same block
+    pub fn validate_transaction_with<'a>(
+        &'a self,
+        tx: &Transaction,
+        height: u64,
+        unconfirmed: impl Fn(&OutPoint) -> Option<&'a TxOutput>,
+    ) -> Result<Amount, TransactionError> {
+        let mut inputs_total = Amount::ZERO;
+
+        for input in &tx.inputs {
+            let outpoint = input.outpoint();
+            let utxo = self
+                .get_utxo(&outpoint)
+                .or_else(|| unconfirmed(&outpoint).cloned())
+                .ok_or(TransactionError::InvalidUTXO)?;
+            if input.value != utxo.value {
+                return Err(TransactionError::InputValueMismatch);
+            }
+
+            utxo.script.evaluate(&utxo.pkhash, input, height)?;
+
+            inputs_total = inputs_total
+                .checked_add(utxo.value)
+                .ok_or(TransactionError::AmountOutOfRange)?;
+        }
+
+        let outputs_total = Amount::checked_sum(tx.outputs.iter().map(|o| o.value))
+            .ok_or(TransactionError::AmountOutOfRange)?;
+        inputs_total
+            .checked_sub(outputs_total)
+            .ok_or(TransactionError::Overspend)
+    }
+
+    // The block's changes are written as a single batch. Returns what
+    // disconnect_block needs to undo them.
+    pub fn update_with_block(&mut self, block: &Block) -> UndoData {
+        let mut batch = UtxoBatch::new();
+        let mut undo = UndoData::default();
+        // Only the block's first transaction is its coinbase. A later one
+        // may have the same shape and still spend an output.
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let spends = if position == 0 {
+                &[][..]
+            } else {
+                &tx.inputs[..]
+            };
+            for input in spends {
+                let outpoint = input.outpoint();
+                if let Some(output) = self.get_utxo(&outpoint) {
+                    undo.spent.push((outpoint, output));
+                }
+                batch.delete(outpoint);
             }
             for (index, output) in tx.outputs.iter().enumerate() {
-                self.add_utxo(tx.id, index as u16, output.clone());
+                batch.put(OutPoint::new(tx.id, index as u16), output.clone());
             }
         }
+        batch.set_tip(block.digest);
+        self.write(batch);
+        undo
     }
 
-    pub fn utxos_from_pkhash(&self, pkhash: [u8; 32]) -> Vec<([u8; 32], u16)> {
-        self.utxos
+    // Rolls back update_with_block for the last block connected, leaving
+    // the set as it was before it. Nothing is written if the block isn't
+    // the set's tip or the undo data doesn't belong to it.
+    pub fn disconnect_block(&mut self, block: &Block, undo: &UndoData) -> Result<(), UndoError> {
+        if self.tip().is_some_and(|tip| tip != block.digest) {
+            return Err(UndoError::NotTip);
+        }
+
+        let created: HashSet<OutPoint> = block
+            .transactions
             .iter()
-            .filter_map(|((txid, index), output)| {
-                if output.pkhash == pkhash {
-                    Some((*txid, *index))
-                } else {
-                    None
-                }
-            })
+            .flat_map(|tx| (0..tx.outputs.len()).map(|index| OutPoint::new(tx.id, index as u16)))
+            .collect();
+        let spent: Vec<OutPoint> = block
+            .transactions
+            .iter()
+            .skip(1)
+            .flat_map(|tx| tx.inputs.iter().map(|input| input.outpoint()))
+            .filter(|outpoint| !created.contains(outpoint))
+            .collect();
+        if !spent
+            .iter()
+            .eq(undo.spent.iter().map(|(outpoint, _)| outpoint))
+        {
+            return Err(UndoError::Mismatch);
+        }
+
+        let mut batch = UtxoBatch::new();
+        for outpoint in created {
+            batch.delete(outpoint);
+        }
+        for (outpoint, output) in &undo.spent {
+            batch.put(*outpoint, output.clone());
+        }
+        batch.set_tip(block.header.prev_hash);
+        self.write(batch);
+        Ok(())
+    }
+
+    pub fn utxos_from_pkhash(&self, pkhash: [u8; 32]) -> Vec<OutPoint> {
+        self.store
+            .entries()
+            .into_iter()
+            .filter(|(_, output)| output.pkhash == pkhash)
+            .map(|(outpoint, _)| outpoint)
             .collect()
     }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+
+    fn sorted_entries(&self) -> Vec<(OutPoint, TxOutput)> {
+        let mut entries = self.store.entries();
+        entries.sort_by_key(|(outpoint, _)| *outpoint);
+        entries
+    }
+
+    fn encode_entries(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self.sorted_entries(), bincode::config::standard()).unwrap()
+    }
+
+    // Hash of the whole set, independent of insertion order. Nodes with the
+    // same chain agree on it, so a snapshot can be checked against a value
+    // taken from a trusted source.
+    pub fn commitment(&self) -> [u8; 32] {
+        Sha256::digest(self.encode_entries()).into()
+    }
+
+    // The version byte, the entries sorted by outpoint, then the commitment
+    pub fn snapshot(&self) -> Vec<u8> {
+        let entries = self.encode_entries();
+        let mut data = Vec::with_capacity(entries.len() + 33);
+        data.push(SNAPSHOT_VERSION);
+        data.extend_from_slice(&entries);
+        data.extend_from_slice(&Sha256::digest(&entries));
+        data
+    }
+
+    pub fn from_snapshot(data: &[u8]) -> Result<UTXOSet, SnapshotError> {
+        let (&version, rest) = data.split_first().ok_or(SnapshotError::Corrupt)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion);
+        }
+        if rest.len() < 32 {
+            return Err(SnapshotError::Corrupt);
+        }
+        let (entries, commitment) = rest.split_at(rest.len() - 32);
+        if Sha256::digest(entries).as_slice() != commitment {
+            return Err(SnapshotError::CommitmentMismatch);
+        }
+
+        let (entries, read): (Vec<(OutPoint, TxOutput)>, usize) =
+            bincode::decode_from_slice(entries, bincode::config::standard())
+                .map_err(|_| SnapshotError::Corrupt)?;
+        if read != rest.len() - 32 {
+            return Err(SnapshotError::Corrupt);
+        }
+        let mut utxos = UTXOSet::new();
+        let mut batch = UtxoBatch::new();
+        for (outpoint, output) in entries {
+            batch.put(outpoint, output);
+        }
+        utxos.write(batch);
+        Ok(utxos)
+    }
+
+    pub fn total_value(&self) -> Amount {
+        Amount(
+            self.store
+                .entries()
+                .iter()
+                .map(|(_, output)| output.value.0)
+                .sum(),
+        )
+    }
+
+    pub fn balance(&self, pkhash: [u8; 32]) -> u64 {
+        self.store
+            .entries()
+            .iter()
+            .filter(|(_, output)| output.pkhash == pkhash)
+            .map(|(_, output)| output.value.0)
+            .sum()
+    }
+
+    // The balance of every pkhash holding anything
+    pub fn balances(&self) -> BTreeMap<[u8; 32], u64> {
+        let mut balances = BTreeMap::new();
+        for (_, output) in self.store.entries() {
+            *balances.entry(output.pkhash).or_default() += output.value.0;
+        }
+        balances
+    }
+}
+
+// The set is written as a list of entries sorted by outpoint
+struct UTXOEntry {
+    txid: TxId,
+    index: u16,
+    value: Amount,
+    pkhash: [u8; 32],
+    script: Script,
+}
+
+impl_serde_struct!(UTXOEntry {
+    txid: TxId,
+    index: u16,
+    value: Amount,
+    pkhash: [u8; 32] => hex,
+    script: Script,
+});
+
+impl Serialize for UTXOSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries: Vec<UTXOEntry> = self
+            .sorted_entries()
+            .into_iter()
+            .map(|(outpoint, output)| UTXOEntry {
+                txid: outpoint.txid,
+                index: outpoint.index,
+                value: output.value,
+                pkhash: output.pkhash,
+                script: output.script,
+            })
+            .collect();
+        entries.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UTXOSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut utxos = UTXOSet::new();
+        for entry in Vec::<UTXOEntry>::deserialize(deserializer)? {
+            utxos.add_utxo(
+                OutPoint::new(entry.txid, entry.index),
+                TxOutput {
+                    value: entry.value,
+                    pkhash: entry.pkhash,
+                    script: entry.script,
+                },
+            );
+        }
+        Ok(utxos)
+    }
+}
+
+impl Default for UTXOSet {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl fmt::Display for UTXOSet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for ((txid, index), output) in &self.utxos {
+        for (outpoint, output) in self.sorted_entries() {
             write!(
                 f,
                 "TxID: {}, Index: {}, Output: {}",
-                hex::encode(txid),
-                index,
-                output
+                outpoint.txid, outpoint.index, output
             )?;
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+    use crate::transaction::TxInput;
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut utxos = UTXOSet::new();
+        utxos.add_utxo(OutPoint::new([2; 32], 1), TxOutput::new(20, [4; 32]));
+        utxos.add_utxo(OutPoint::new([1; 32], 0), TxOutput::new(10, [3; 32]));
+
+        let text = json::to_string(&utxos).unwrap();
+        assert!(text.starts_with(&format!(
+            "[{{\"txid\":\"{}\",\"index\":0",
+            hex::encode([1; 32])
+        )));
+
+        let decoded: UTXOSet = json::from_str(&text).unwrap();
+        assert_eq!(
+            decoded.get_utxo(&OutPoint::new([2; 32], 1)).unwrap().value,
+            20
+        );
+        assert_eq!(
+            decoded.get_utxo(&OutPoint::new([1; 32], 0)).unwrap().pkhash,
+            [3; 32]
+        );
+        assert_eq!(json::to_string(&decoded).unwrap(), text);
+    }
+
+    #[test]
+    fn test_disconnect_block() {
+        let spent = OutPoint::new([1; 32], 1);
+        let mut utxos = UTXOSet::new();
+        utxos.add_utxo(spent, TxOutput::new(50, [3; 32]));
+        utxos.add_utxo(OutPoint::new([2; 32], 0), TxOutput::new(20, [4; 32]));
+        let before = utxos.commitment();
+
+        // the second transaction spends an output created by the first
+        let mut first = Transaction::new(
+            vec![TxInput::new_unsigned(spent.txid, spent.index)],
+            vec![TxOutput::new(40, [5; 32]), TxOutput::new(10, [6; 32])],
+        );
+        first.update_id();
+        let mut second = Transaction::new(
+            vec![TxInput::new_unsigned(first.id, 1)],
+            vec![TxOutput::new(10, [8; 32])],
+        );
+        second.update_id();
+        let mut block = Block::new(1, Hash256([9; 32]), 0x2100_ffff, Vec::new());
+        block.add_tx(first);
+        block.add_tx(second);
+        block.add_coinbase_tx([7; 32], 100);
+
+        let undo = utxos.update_with_block(&block);
+        assert_eq!(undo.spent.len(), 1);
+        assert_eq!(undo.spent[0].0, spent);
+        assert_eq!(undo.spent[0].1.value, 50);
+        assert_eq!(utxos.len(), 4);
+
+        assert_eq!(
+            utxos.disconnect_block(&block, &UndoData::default()),
+            Err(UndoError::Mismatch)
+        );
+        utxos.disconnect_block(&block, &undo).unwrap();
+        assert_eq!(utxos.commitment(), before);
+        assert_eq!(utxos.tip(), Some(block.header.prev_hash));
+        assert_eq!(
+            utxos.disconnect_block(&block, &undo),
+            Err(UndoError::NotTip)
+        );
+    }
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let mut utxos = UTXOSet::new();
+        utxos.add_utxo(OutPoint::new([2; 32], 1), TxOutput::new(20, [4; 32]));
+        utxos.add_utxo(
+            OutPoint::new([1; 32], 0),
+            TxOutput::new_timelocked(10, [3; 32], 7),
+        );
+
+        let mut reordered = UTXOSet::new();
+        reordered.add_utxo(
+            OutPoint::new([1; 32], 0),
+            TxOutput::new_timelocked(10, [3; 32], 7),
+        );
+        reordered.add_utxo(OutPoint::new([2; 32], 1), TxOutput::new(20, [4; 32]));
+        assert_eq!(utxos.commitment(), reordered.commitment());
+
+        let restored = UTXOSet::from_snapshot(&utxos.snapshot()).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored.commitment(), utxos.commitment());
+        assert_eq!(
+            restored
+                .get_utxo(&OutPoint::new([1; 32], 0))
+                .unwrap()
+                .script,
+            Script::Timelock { height: 7 }
+        );
+
+        utxos.remove_utxo(&OutPoint::new([2; 32], 1));
+        assert_ne!(utxos.commitment(), restored.commitment());
+    }
+
+    #[test]
+    fn test_invalid_snapshot() {
+        let mut utxos = UTXOSet::new();
+        utxos.add_utxo(OutPoint::new([1; 32], 0), TxOutput::new(10, [3; 32]));
+        let snapshot = utxos.snapshot();
+
+        let mut tampered = snapshot.clone();
+        tampered[10] ^= 1;
+        assert_eq!(
+            UTXOSet::from_snapshot(&tampered).err(),
+            Some(SnapshotError::CommitmentMismatch)
+        );
+
+        let mut future = snapshot.clone();
+        future[0] = SNAPSHOT_VERSION + 1;
+        assert_eq!(
+            UTXOSet::from_snapshot(&future).err(),
+            Some(SnapshotError::UnsupportedVersion)
+        );
+        assert_eq!(
+            UTXOSet::from_snapshot(&snapshot[..snapshot.len() - 1]).err(),
+            Some(SnapshotError::CommitmentMismatch)
+        );
+        assert_eq!(
+            UTXOSet::from_snapshot(&snapshot[..20]).err(),
+            Some(SnapshotError::Corrupt)
+        );
+        assert!(
+            UTXOSet::from_snapshot(&UTXOSet::new().snapshot())
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -1,32 +1,561 @@
+use crate::address::Address;
+use crate::block::Block;
+use crate::errors::{BuildError, KeystoreError, MnemonicError, MusigError, PsbtError, SignerError};
+use crate::hash::OutPoint;
+use crate::hd::ExtendedKey;
+use crate::keystore::Keystore;
+use crate::mnemonic::{self, Wordlist};
+use crate::musig::{KeyAggContext, PartialSignature, SecretNonce, Session};
+use crate::payment_request::PaymentRequest;
+use crate::psbt::PartialTransaction;
+use crate::script::{Htlc, HtlcSpend};
+use crate::signature::{PrivateKey, SignatureScheme, Signer};
 use crate::transaction::Transaction;
-use k256::ecdsa::{SigningKey, VerifyingKey};
+use crate::tx_builder::TxBuilder;
+use crate::utxo::UTXOSet;
+use crate::wallet_state::WalletState;
 use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+// Addresses of an HD wallet are derived at m/0'/i'
+const ADDRESS_ACCOUNT: u32 = 0;
+
+// A key held in memory, or one that only signs through a Signer, such as a
+// hardware wallet, and can't be saved or used for MuSig
+enum WalletKey {
+    Local(PrivateKey),
+    External(Box<dyn Signer + Send + Sync>),
+}
 
 pub struct Wallet {
-    signing_key: SigningKey,
-    verifying_key: VerifyingKey,
+    key: WalletKey,
+    hd_master: Option<ExtendedKey>,
+    chain_id: [u8; 32],
     pub pkhash: [u8; 32],
-    pub address: String,
+    pub address: Address,
 }
 
 impl Wallet {
     pub fn new() -> Wallet {
-        let signing_key = SigningKey::random(&mut rand_core::OsRng);
-        let verifying_key = *signing_key.verifying_key();
+        Wallet::for_chain(Block::genesis().digest.0)
+    }
+
+    pub fn for_chain(chain_id: [u8; 32]) -> Wallet {
+        Wallet::with_scheme(SignatureScheme::Ecdsa, chain_id)
+    }
+
+    pub fn with_scheme(scheme: SignatureScheme, chain_id: [u8; 32]) -> Wallet {
+        Wallet::from_key(PrivateKey::random(scheme), chain_id)
+    }
 
-        let encoded_point = verifying_key.to_encoded_point(true);
-        let pubkey_bytes = encoded_point.as_bytes();
-        let pubkey_hex = hex::encode(pubkey_bytes);
+    // Creates an HD wallet from a new 12 word mnemonic, returning the phrase
+    // so it can be written down
+    pub fn generate_mnemonic(chain_id: [u8; 32]) -> (Wallet, String) {
+        let phrase = mnemonic::generate(12).unwrap();
+        let wallet = Wallet::from_mnemonic_for_chain(&phrase, chain_id).unwrap();
+        (wallet, phrase)
+    }
+
+    pub fn from_mnemonic(phrase: &str) -> Result<Wallet, MnemonicError> {
+        Wallet::from_mnemonic_for_chain(phrase, Block::genesis().digest.0)
+    }
+
+    pub fn from_mnemonic_for_chain(
+        phrase: &str,
+        chain_id: [u8; 32],
+    ) -> Result<Wallet, MnemonicError> {
+        Wallet::from_mnemonic_in(phrase, "", Wordlist::english(), chain_id)
+    }
+
+    // A phrase from another language's wordlist, optionally with the BIP39
+    // passphrase, which gives a different wallet for the same words
+    pub fn from_mnemonic_in(
+        phrase: &str,
+        passphrase: &str,
+        wordlist: &Wordlist,
+        chain_id: [u8; 32],
+    ) -> Result<Wallet, MnemonicError> {
+        let seed = mnemonic::to_seed_in(phrase, passphrase, wordlist)?;
+        // Only fails for one in ~2^127 seeds
+        let master = ExtendedKey::master(SignatureScheme::Ecdsa, &seed)
+            .ok_or(MnemonicError::InvalidChecksum)?;
+        Ok(Wallet::from_master(master, chain_id))
+    }
+
+    // An HD wallet whose keys, and so its addresses and signatures, are the
+    // same on every run for the same seed, e.g. for tests and simulations
+    pub fn from_seed(seed: [u8; 32]) -> Wallet {
+        Wallet::from_seed_for_chain(seed, Block::genesis().digest.0)
+    }
+
+    pub fn from_seed_for_chain(seed: [u8; 32], chain_id: [u8; 32]) -> Wallet {
+        // Only fails for one in ~2^127 seeds
+        let master = ExtendedKey::master(SignatureScheme::Ecdsa, &seed).unwrap();
+        Wallet::from_master(master, chain_id)
+    }
+
+    fn from_master(master: ExtendedKey, chain_id: [u8; 32]) -> Wallet {
+        let mut wallet = Wallet::from_key(Wallet::derive_key(&master, 0), chain_id);
+        wallet.hd_master = Some(master);
+        wallet
+    }
+
+    pub(crate) fn from_key(signing_key: PrivateKey, chain_id: [u8; 32]) -> Wallet {
+        Wallet::with_key(WalletKey::Local(signing_key), chain_id)
+    }
+
+    // A wallet whose key lives outside it
+    pub fn from_signer(signer: impl Signer + Send + Sync + 'static, chain_id: [u8; 32]) -> Wallet {
+        Wallet::with_key(WalletKey::External(Box::new(signer)), chain_id)
+    }
+
+    fn with_key(key: WalletKey, chain_id: [u8; 32]) -> Wallet {
+        let pkhash: [u8; 32] = match &key {
+            WalletKey::Local(signing_key) => Sha256::digest(signing_key.public_key()).into(),
+            WalletKey::External(signer) => Sha256::digest(signer.public_key()).into(),
+        };
 
         Wallet {
-            signing_key,
-            verifying_key,
-            pkhash: Sha256::digest(pubkey_bytes).into(),
-            address: pubkey_hex,
+            key,
+            hd_master: None,
+            chain_id,
+            pkhash,
+            address: Address::from(pkhash),
         }
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction) {
-        tx.sign(&self.signing_key);
+    // Skips past the rare indices that don't produce a valid key, as BIP32
+    // recommends
+    fn derive_key(master: &ExtendedKey, index: u32) -> PrivateKey {
+        let account = master.derive_hardened(ADDRESS_ACCOUNT).unwrap();
+        (index..)
+            .find_map(|i| account.derive_hardened(i))
+            .unwrap()
+            .private_key()
+    }
+
+    pub fn is_hd(&self) -> bool {
+        self.hd_master.is_some()
+    }
+
+    // Returns a wallet that signs with the key at `index`, or None if this is
+    // not an HD wallet
+    pub fn derive(&self, index: u32) -> Option<Wallet> {
+        let master = self.hd_master.as_ref()?;
+        Some(Wallet::from_key(
+            Wallet::derive_key(master, index),
+            self.chain_id,
+        ))
+    }
+
+    pub fn derive_address(&self, index: u32) -> Option<[u8; 32]> {
+        self.derive(index).map(|wallet| wallet.pkhash)
+    }
+
+    // Scans derived addresses in order until `gap_limit` consecutive ones have
+    // no outputs, returning the outpoints found with the index that owns them.
+    // A plain wallet only has its own address to scan.
+    pub fn find_utxos(&self, utxos: &UTXOSet, gap_limit: u32) -> Vec<(u32, OutPoint)> {
+        if !self.is_hd() {
+            return utxos
+                .utxos_from_pkhash(self.pkhash)
+                .into_iter()
+                .map(|outpoint| (0, outpoint))
+                .collect();
+        }
+
+        let mut found = Vec::new();
+        let mut unused = 0;
+        let mut index = 0;
+        while unused < gap_limit {
+            let outpoints = utxos.utxos_from_pkhash(self.derive_address(index).unwrap());
+            if outpoints.is_empty() {
+                unused += 1;
+            } else {
+                unused = 0;
+                found.extend(outpoints.into_iter().map(|outpoint| (index, outpoint)));
+            }
+            index += 1;
+        }
+        found
+    }
+
+    pub fn balance(&self, utxos: &UTXOSet, gap_limit: u32) -> u64 {
+        self.find_utxos(utxos, gap_limit)
+            .iter()
+            .filter_map(|(_, outpoint)| utxos.get_utxo(outpoint))
+            .map(|output| output.value.0)
+            .sum()
+    }
+
+    // A request for a payment to this wallet's address, to fill in with an
+    // amount and a label
+    pub fn payment_request(&self) -> PaymentRequest {
+        PaymentRequest::new(self.address)
+    }
+
+    // Pays a parsed request from the outputs in `state` at the minimum relay
+    // fee rate, sending the change back to this wallet and locking the
+    // outputs spent. Use a TxBuilder to choose the fee.
+    pub fn pay_request(
+        &self,
+        request: &PaymentRequest,
+        state: &mut WalletState,
+    ) -> Result<Transaction, BuildError> {
+        TxBuilder::new(self.pkhash)
+            .add_spendable(state)
+            .pay_request(request)?
+            .build_and_lock(self, state)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>, passphrase: &str) -> Result<(), KeystoreError> {
+        let secret = match (&self.hd_master, &self.key) {
+            (Some(master), _) => master.to_bytes().to_vec(),
+            (None, WalletKey::Local(signing_key)) => signing_key.to_bytes(),
+            (None, WalletKey::External(_)) => return Err(KeystoreError::ExternalKey),
+        };
+        let keystore = Keystore::encrypt(
+            &secret,
+            self.scheme(),
+            self.chain_id,
+            self.is_hd(),
+            passphrase,
+        );
+        fs::write(path, keystore.as_bincode())?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>, passphrase: &str) -> Result<Wallet, KeystoreError> {
+        let keystore = Keystore::from_bincode(&fs::read(path)?)?;
+        let secret = keystore.decrypt(passphrase)?;
+        if keystore.hd {
+            let master =
+                ExtendedKey::from_bytes(keystore.scheme, &secret).ok_or(KeystoreError::Corrupt)?;
+            return Ok(Wallet::from_master(master, keystore.chain_id));
+        }
+        let signing_key =
+            PrivateKey::from_bytes(keystore.scheme, &secret).ok_or(KeystoreError::Corrupt)?;
+        Ok(Wallet::from_key(signing_key, keystore.chain_id))
+    }
+
+    pub fn signer(&self) -> &(dyn Signer + Send + Sync) {
+        match &self.key {
+            WalletKey::Local(signing_key) => signing_key,
+            WalletKey::External(signer) => signer.as_ref(),
+        }
+    }
+
+    pub fn scheme(&self) -> SignatureScheme {
+        self.signer().scheme()
+    }
+
+    pub fn chain_id(&self) -> [u8; 32] {
+        self.chain_id
+    }
+
+    pub fn sign_transaction(&self, tx: &mut Transaction) -> Result<(), SignerError> {
+        tx.sign(self.signer(), &self.chain_id)
+    }
+
+    // Signs only input `index`, leaving the others to the wallets owning the
+    // outputs they spend, so several parties can fund one transaction.
+    // Signatures are left out of the sighash, so the order doesn't matter.
+    pub fn sign_input(&self, tx: &mut Transaction, index: usize) -> Result<(), SignerError> {
+        tx.sign_input(index, self.signer(), &self.chain_id)
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signer().public_key()
+    }
+
+    // Adds this wallet's signature to an input spending a multisig output
+    // locked to `pubkeys`, keeping those of the other signers. Returns false
+    // if the wallet's key is not in the set.
+    pub fn sign_multisig_input(
+        &self,
+        tx: &mut Transaction,
+        input: usize,
+        pubkeys: &[Vec<u8>],
+    ) -> Result<bool, SignerError> {
+        tx.sign_multisig_input(input, self.signer(), pubkeys, &self.chain_id)
+    }
+
+    // This wallet's share of a signature for an aggregate key, which needs a
+    // Schnorr wallet whose key is one of those aggregated
+    pub fn sign_musig(
+        &self,
+        session: &Session,
+        context: &KeyAggContext,
+        nonce: SecretNonce,
+    ) -> Result<PartialSignature, MusigError> {
+        match &self.key {
+            WalletKey::Local(signing_key) => session.sign(context, nonce, signing_key),
+            WalletKey::External(_) => Err(MusigError::ExternalKey),
+        }
+    }
+
+    // Terms for locking coins to `recipient` until `timeout`, after which
+    // they come back to this wallet
+    pub fn new_htlc(&self, recipient: [u8; 32], payment_hash: [u8; 32], timeout: u64) -> Htlc {
+        Htlc {
+            payment_hash,
+            recipient,
+            refund: self.pkhash,
+            timeout,
+        }
+    }
+
+    // Signs an input spending an HTLC output to this wallet, revealing the
+    // preimage. Returns false if the wallet is not the recipient.
+    pub fn claim_htlc(
+        &self,
+        tx: &mut Transaction,
+        input: usize,
+        htlc: &Htlc,
+        preimage: [u8; 32],
+    ) -> Result<bool, SignerError> {
+        let spend = HtlcSpend {
+            terms: *htlc,
+            preimage: Some(preimage),
+        };
+        tx.sign_htlc_input(input, self.signer(), spend, &self.chain_id)
+    }
+
+    // Signs an input taking back an unclaimed HTLC output, valid in blocks
+    // from its timeout on. Returns false if the wallet did not fund it.
+    pub fn refund_htlc(
+        &self,
+        tx: &mut Transaction,
+        input: usize,
+        htlc: &Htlc,
+    ) -> Result<bool, SignerError> {
+        let spend = HtlcSpend {
+            terms: *htlc,
+            preimage: None,
+        };
+        tx.sign_htlc_input(input, self.signer(), spend, &self.chain_id)
+    }
+
+    // Signs every input of the partial transaction this wallet's key can
+    // spend, returning how many it signed. Stops at the first the signer
+    // fails to sign.
+    pub fn sign_partial(&self, ptx: &mut PartialTransaction) -> Result<usize, SignerError> {
+        let mut signed = 0;
+        for input in 0..ptx.inputs.len() {
+            match ptx.add_signature(input, self.signer(), &self.chain_id) {
+                Ok(()) => signed += 1,
+                Err(PsbtError::Signer(error)) => return Err(error),
+                Err(_) => {}
+            }
+        }
+        Ok(signed)
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{TxInput, TxOutput};
+
+    #[test]
+    fn test_save_and_load() {
+        for scheme in [
+            SignatureScheme::Ecdsa,
+            SignatureScheme::Ed25519,
+            SignatureScheme::Schnorr,
+        ] {
+            let wallet = Wallet::with_scheme(scheme, [3; 32]);
+            let path = std::env::temp_dir().join(format!("wallet_{}.key", wallet.address));
+
+            wallet.save(&path, "correct horse").unwrap();
+            let loaded = Wallet::load(&path, "correct horse").unwrap();
+            assert_eq!(loaded.pkhash, wallet.pkhash);
+            assert_eq!(loaded.address, wallet.address);
+            assert_eq!(loaded.scheme(), scheme);
+            assert_eq!(loaded.chain_id(), [3; 32]);
+
+            assert!(matches!(
+                Wallet::load(&path, "battery staple"),
+                Err(KeystoreError::WrongPassphrase)
+            ));
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cosign_multisig_input() {
+        let signers: Vec<Wallet> = (0..3).map(|_| Wallet::for_chain([3; 32])).collect();
+        let pubkeys: Vec<Vec<u8>> = signers.iter().map(Wallet::public_key).collect();
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0)],
+            vec![TxOutput::new(50, [2; 32])],
+        );
+        // signing order doesn't matter
+        assert!(
+            signers[2]
+                .sign_multisig_input(&mut tx, 0, &pubkeys)
+                .unwrap()
+        );
+        assert!(
+            signers[0]
+                .sign_multisig_input(&mut tx, 0, &pubkeys)
+                .unwrap()
+        );
+        assert!(
+            !Wallet::for_chain([3; 32])
+                .sign_multisig_input(&mut tx, 0, &pubkeys)
+                .unwrap()
+        );
+
+        let keys: Vec<u8> = tx.inputs[0]
+            .witness
+            .signatures
+            .iter()
+            .map(|s| s.key)
+            .collect();
+        assert_eq!(keys, vec![0, 2]);
+        assert_eq!(tx.verify(&[3; 32]), Ok(()));
+
+        // a regular signature over the rest leaves the multisig input alone
+        signers[1].sign_transaction(&mut tx).unwrap();
+        assert!(tx.inputs[0].pubkey.is_empty());
+        assert_eq!(tx.verify(&[3; 32]), Ok(()));
+
+        tx.inputs[0].witness.signatures[1].signature[0] ^= 1;
+        tx.update_id();
+        assert!(tx.verify(&[3; 32]).is_err());
+    }
+
+    const PHRASE: &str =
+        "legal winner thank year wave sausage worth useful legal winner thank yellow";
+
+    #[test]
+    fn test_mnemonic_is_deterministic() {
+        let wallet = Wallet::from_mnemonic(PHRASE).unwrap();
+        let again = Wallet::from_mnemonic(&PHRASE.to_uppercase()).unwrap();
+        assert_eq!(wallet.pkhash, again.pkhash);
+        assert_eq!(wallet.derive_address(0), Some(wallet.pkhash));
+        assert_eq!(wallet.derive_address(7), again.derive_address(7));
+        assert_ne!(wallet.derive_address(0), wallet.derive_address(1));
+
+        assert!(Wallet::new().derive_address(0).is_none());
+        assert_eq!(
+            Wallet::from_mnemonic("legal winner").err(),
+            Some(MnemonicError::InvalidWordCount)
+        );
+
+        let chain_id = Block::genesis().digest.0;
+        let protected =
+            Wallet::from_mnemonic_in(PHRASE, "secret", Wordlist::english(), chain_id).unwrap();
+        assert_ne!(protected.pkhash, wallet.pkhash);
+        let unprotected =
+            Wallet::from_mnemonic_in(PHRASE, "", Wordlist::english(), chain_id).unwrap();
+        assert_eq!(unprotected.pkhash, wallet.pkhash);
+    }
+
+    #[test]
+    fn test_seed_is_deterministic() {
+        let wallet = Wallet::from_seed([5; 32]);
+        let again = Wallet::from_seed([5; 32]);
+        assert!(wallet.is_hd());
+        assert_eq!(wallet.pkhash, again.pkhash);
+        assert_eq!(wallet.derive_address(4), again.derive_address(4));
+        assert_ne!(wallet.pkhash, Wallet::from_seed([6; 32]).pkhash);
+        assert_eq!(
+            Wallet::from_seed_for_chain([5; 32], [3; 32]).pkhash,
+            wallet.pkhash
+        );
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned([1; 32], 0)],
+            vec![TxOutput::new(1000, [9; 32])],
+        );
+        let mut tx_again = tx.clone();
+        wallet.sign_transaction(&mut tx).unwrap();
+        again.sign_transaction(&mut tx_again).unwrap();
+        assert_eq!(tx.inputs[0].signature, tx_again.inputs[0].signature);
+        assert_eq!(tx.id, tx_again.id);
+    }
+
+    #[test]
+    fn test_generated_mnemonic_restores_wallet() {
+        let (wallet, phrase) = Wallet::generate_mnemonic([3; 32]);
+        let restored = Wallet::from_mnemonic_for_chain(&phrase, [3; 32]).unwrap();
+        assert_eq!(restored.derive_address(3), wallet.derive_address(3));
+    }
+
+    #[test]
+    fn test_save_and_load_hd() {
+        let wallet = Wallet::from_mnemonic(PHRASE).unwrap();
+        let path = std::env::temp_dir().join(format!("wallet_hd_{}.key", wallet.address));
+
+        wallet.save(&path, "correct horse").unwrap();
+        let loaded = Wallet::load(&path, "correct horse").unwrap();
+        assert!(loaded.is_hd());
+        assert_eq!(loaded.pkhash, wallet.pkhash);
+        assert_eq!(loaded.derive_address(5), wallet.derive_address(5));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_pay_request() {
+        let wallet = Wallet::new();
+        let mut state = WalletState::for_wallet(&wallet, 1);
+        let mut block = Block::new(1, [0; 32], 0x2100_ffff, Vec::new());
+        block.add_coinbase_tx(wallet.pkhash, 30_000);
+        state.process_block(&block);
+
+        let payee = Wallet::new();
+        let uri = payee
+            .payment_request()
+            .with_amount(20_000)
+            .with_label("Shop")
+            .encode();
+        let request: PaymentRequest = uri.parse().unwrap();
+        let tx = wallet.pay_request(&request, &mut state).unwrap();
+        assert_eq!(tx.outputs[0].pkhash, payee.pkhash);
+        assert_eq!(tx.outputs[0].value, 20_000);
+        assert_eq!(tx.outputs[1].pkhash, wallet.pkhash);
+        assert!(tx.verify(&wallet.chain_id()).is_ok());
+        assert!(state.spendable().is_empty());
+
+        assert_eq!(
+            wallet
+                .pay_request(&payee.payment_request(), &mut state)
+                .err(),
+            Some(BuildError::NoAmount)
+        );
+    }
+
+    #[test]
+    fn test_find_utxos_across_addresses() {
+        let wallet = Wallet::from_mnemonic(PHRASE).unwrap();
+        let mut utxos = UTXOSet::new();
+        for (txid, index) in [([1; 32], 0), ([2; 32], 3), ([3; 32], 30)] {
+            utxos.add_utxo(
+                OutPoint::new(txid, 0),
+                TxOutput::new(10, wallet.derive_address(index).unwrap()),
+            );
+        }
+
+        let mut found = wallet.find_utxos(&utxos, 5);
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (0, OutPoint::new([1; 32], 0)),
+                (3, OutPoint::new([2; 32], 0))
+            ]
+        );
+        assert_eq!(wallet.balance(&utxos, 5), 20);
+        assert_eq!(wallet.balance(&utxos, 30), 30);
     }
 }
@@ -0,0 +1,298 @@
+// The wallets loaded into a running node, by name. Each has its own
+// WalletState, so their outputs, pending transactions and locked outputs are
+// tracked apart and a payment from one never spends another's outputs.
+//
+// Wallet files are kept in the manager's directory as <name>.key. A manager
+// without one, as for a node run with --in-memory, keeps the wallets it
+// creates in memory only.
+
+use crate::blockchain::Blockchain;
+use crate::errors::WalletManagerError;
+use crate::wallet::Wallet;
+use crate::wallet_state::WalletState;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+// Derived addresses of an HD wallet watched for payments
+pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+const MAX_NAME_LENGTH: usize = 64;
+
+pub struct LoadedWallet {
+    pub name: String,
+    pub wallet: Wallet,
+    pub state: WalletState,
+}
+
+pub struct WalletManager {
+    dir: Option<PathBuf>,
+    gap_limit: u32,
+    wallets: BTreeMap<String, LoadedWallet>,
+}
+
+// Names end up in file names and URL paths, so they are kept to letters,
+// digits, '-' and '_'
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+}
+
+impl WalletManager {
+    pub fn new() -> WalletManager {
+        WalletManager {
+            dir: None,
+            gap_limit: DEFAULT_GAP_LIMIT,
+            wallets: BTreeMap::new(),
+        }
+    }
+
+    pub fn in_dir(dir: impl Into<PathBuf>) -> WalletManager {
+        WalletManager {
+            dir: Some(dir.into()),
+            ..WalletManager::new()
+        }
+    }
+
+    // Applies to wallets loaded afterwards
+    pub fn set_gap_limit(&mut self, gap_limit: u32) {
+        self.gap_limit = gap_limit;
+    }
+
+    fn path(&self, name: &str) -> Option<PathBuf> {
+        Some(self.dir.as_ref()?.join(format!("{name}.key")))
+    }
+
+    // Loads a wallet without saving it. Its outputs are found by the next
+    // sync, which scans the chain from the start for a new wallet.
+    pub fn add(
+        &mut self,
+        name: &str,
+        wallet: Wallet,
+    ) -> Result<&mut LoadedWallet, WalletManagerError> {
+        if !is_valid_name(name) {
+            return Err(WalletManagerError::InvalidName(name.to_string()));
+        }
+        if self.wallets.contains_key(name) {
+            return Err(WalletManagerError::AlreadyLoaded(name.to_string()));
+        }
+        let loaded = LoadedWallet {
+            name: name.to_string(),
+            state: WalletState::for_wallet(&wallet, self.gap_limit),
+            wallet,
+        };
+        Ok(self.wallets.entry(name.to_string()).or_insert(loaded))
+    }
+
+    // Saves the wallet under `name`, if the manager has a directory, and
+    // loads it
+    pub fn create(
+        &mut self,
+        name: &str,
+        wallet: Wallet,
+        passphrase: &str,
+    ) -> Result<&mut LoadedWallet, WalletManagerError> {
+        if !is_valid_name(name) {
+            return Err(WalletManagerError::InvalidName(name.to_string()));
+        }
+        if self.wallets.contains_key(name) {
+            return Err(WalletManagerError::AlreadyLoaded(name.to_string()));
+        }
+        if let Some(path) = self.path(name) {
+            if path.exists() {
+                return Err(WalletManagerError::AlreadyExists(name.to_string()));
+            }
+            std::fs::create_dir_all(self.dir.as_ref().unwrap())?;
+            wallet.save(&path, passphrase)?;
+        }
+        self.add(name, wallet)
+    }
+
+    pub fn load(
+        &mut self,
+        name: &str,
+        passphrase: &str,
+    ) -> Result<&mut LoadedWallet, WalletManagerError> {
+        if !is_valid_name(name) {
+            return Err(WalletManagerError::InvalidName(name.to_string()));
+        }
+        if self.wallets.contains_key(name) {
+            return Err(WalletManagerError::AlreadyLoaded(name.to_string()));
+        }
+        let path = self.path(name).ok_or(WalletManagerError::NoWalletDir)?;
+        if !path.exists() {
+            return Err(WalletManagerError::NotFound(name.to_string()));
+        }
+        let wallet = Wallet::load(&path, passphrase)?;
+        self.add(name, wallet)
+    }
+
+    // Its pending transactions are forgotten, though they may still confirm
+    pub fn unload(&mut self, name: &str) -> Option<LoadedWallet> {
+        self.wallets.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LoadedWallet> {
+        self.wallets.get(name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut LoadedWallet> {
+        self.wallets.get_mut(name)
+    }
+
+    // The wallet named, or the only one loaded if no name is given, as a
+    // call not routed to a wallet can't pick between several
+    pub fn select(&mut self, name: Option<&str>) -> Result<&mut LoadedWallet, WalletManagerError> {
+        match name {
+            Some(name) => self
+                .wallets
+                .get_mut(name)
+                .ok_or_else(|| WalletManagerError::NotLoaded(name.to_string())),
+            None if self.wallets.len() > 1 => Err(WalletManagerError::NotSpecified),
+            None => self
+                .wallets
+                .values_mut()
+                .next()
+                .ok_or(WalletManagerError::NoWallets),
+        }
+    }
+
+    // In name order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.wallets.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.wallets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wallets.is_empty()
+    }
+
+    // Feeds each wallet the blocks added since its last sync
+    pub fn sync(&mut self, blockchain: &Blockchain) {
+        for loaded in self.wallets.values_mut() {
+            loaded.state.sync(blockchain);
+        }
+    }
+}
+
+impl Default for WalletManager {
+    fn default() -> Self {
+        WalletManager::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::KeystoreError;
+    use crate::params::ChainParams;
+    use crate::utils::mine;
+
+    #[test]
+    fn test_wallets_are_tracked_apart() {
+        let mut blockchain = Blockchain::new(ChainParams::mainnet());
+        let chain_id = blockchain.chain_id();
+        let mut manager = WalletManager::new();
+        let alice = manager
+            .add("alice", Wallet::from_seed_for_chain([1; 32], chain_id))
+            .unwrap()
+            .wallet
+            .pkhash;
+        manager
+            .add("bob", Wallet::from_seed_for_chain([2; 32], chain_id))
+            .unwrap();
+        assert_eq!(manager.names().collect::<Vec<_>>(), ["alice", "bob"]);
+
+        let mut block = blockchain.next_block();
+        let reward = blockchain.get_block_reward();
+        mine(&mut block, alice, reward);
+        blockchain.add_block(block).unwrap();
+        manager.sync(&blockchain);
+        assert_eq!(manager.get("alice").unwrap().state.balance(), reward);
+        assert_eq!(manager.get("bob").unwrap().state.balance(), 0);
+
+        let bob = manager.get("bob").unwrap().wallet.payment_request();
+        let loaded = manager.select(Some("alice")).unwrap();
+        let tx = loaded
+            .wallet
+            .pay_request(&bob.with_amount(1_000), &mut loaded.state)
+            .unwrap();
+        assert!(loaded.state.is_locked(&tx.inputs[0].outpoint()));
+        assert!(
+            !manager
+                .get("bob")
+                .unwrap()
+                .state
+                .is_locked(&tx.inputs[0].outpoint())
+        );
+
+        assert!(matches!(
+            manager.select(None),
+            Err(WalletManagerError::NotSpecified)
+        ));
+        assert!(manager.unload("alice").is_some());
+        assert_eq!(manager.select(None).unwrap().name, "bob");
+        assert!(manager.unload("bob").is_some());
+        assert!(matches!(
+            manager.select(None),
+            Err(WalletManagerError::NoWallets)
+        ));
+        assert!(matches!(
+            manager.select(Some("bob")),
+            Err(WalletManagerError::NotLoaded(_))
+        ));
+    }
+
+    #[test]
+    fn test_create_and_load() {
+        let dir = std::env::temp_dir().join(format!("wallets_{}", std::process::id()));
+        let mut manager = WalletManager::in_dir(&dir);
+        let address = manager
+            .create("savings", Wallet::from_seed([3; 32]), "pass")
+            .unwrap()
+            .wallet
+            .address;
+        assert!(matches!(
+            manager.add("savings", Wallet::new()),
+            Err(WalletManagerError::AlreadyLoaded(_))
+        ));
+
+        let mut other = WalletManager::in_dir(&dir);
+        assert!(matches!(
+            other.create("savings", Wallet::new(), "pass"),
+            Err(WalletManagerError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            other.load("savings", "wrong"),
+            Err(WalletManagerError::Keystore(KeystoreError::WrongPassphrase))
+        ));
+        assert_eq!(
+            other.load("savings", "pass").unwrap().wallet.address,
+            address
+        );
+        assert!(matches!(
+            other.load("spending", "pass"),
+            Err(WalletManagerError::NotFound(_))
+        ));
+        assert!(matches!(
+            WalletManager::new().load("savings", "pass"),
+            Err(WalletManagerError::NoWalletDir)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        for name in ["", "../savings", "my wallet", &"a".repeat(65)] {
+            assert!(
+                matches!(
+                    manager.add(name, Wallet::new()),
+                    Err(WalletManagerError::InvalidName(_))
+                ),
+                "{name}"
+            );
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::clock::{Clock, SystemClock};
+use crate::hash::{OutPoint, TxId};
+use crate::log;
+use crate::network::Node;
+use crate::transaction::{Transaction, TxInput, TxOutput};
+use crate::wallet::Wallet;
+use std::collections::{HashMap, HashSet};
+
+// Seconds between broadcasts of a transaction that hasn't confirmed
+pub const REBROADCAST_INTERVAL: u64 = 15 * 60;
+
+// An output paying to one of the watched pkhashes, with the height of the
+// block that created it
+#[derive(Clone)]
+pub struct OwnedOutput {
+    pub output: TxOutput,
+    pub height: u64,
+}
+
+// A transaction the wallet sent that is not yet confirmed
+struct PendingTx {
+    tx: Transaction,
+    last_broadcast: u64,
+}
+
+// The wallet's view of the chain: its unspent outputs, the heights of the
+// transactions touching it, and the transactions it sent that are not yet
+// confirmed. Blocks are fed in order, by sync() or process_block().
+//
+// Pending transactions are sent to peers again every REBROADCAST_INTERVAL,
+// in case they were dropped from mempools, until they confirm, a
+// conflicting spend confirms, or they are abandoned.
+#[derive(Default)]
+pub struct WalletState {
+    pkhashes: HashSet<[u8; 32]>,
+    utxos: HashMap<OutPoint, OwnedOutput>,
+    tx_heights: HashMap<TxId, u64>,
+    pending: HashMap<TxId, PendingTx>,
+    // Outputs set aside for a transaction being built or sent, which
+    // spendable() leaves out. Unlocked once spent in a block.
+    locked: HashSet<OutPoint>,
+    // Height of the next block to process
+    next_height: u64,
+}
+
+impl WalletState {
+    pub fn new() -> WalletState {
+        WalletState::default()
+    }
+
+    // Watches the first `gap_limit` derived addresses of an HD wallet, or
+    // the single address of a plain one
+    pub fn for_wallet(wallet: &Wallet, gap_limit: u32) -> WalletState {
+        let mut state = WalletState::new();
+        if wallet.is_hd() {
+            for index in 0..gap_limit {
+                state.watch(wallet.derive_address(index).unwrap());
+            }
+        } else {
+            state.watch(wallet.pkhash);
+        }
+        state
+    }
+
+    // Outputs already confirmed to the pkhash are only found by a rescan
+    pub fn watch(&mut self, pkhash: [u8; 32]) {
+        self.pkhashes.insert(pkhash);
+    }
+
+    pub fn is_watched(&self, pkhash: &[u8; 32]) -> bool {
+        self.pkhashes.contains(pkhash)
+    }
+
+    // Processes the blocks added since the last sync
+    pub fn sync(&mut self, blockchain: &Blockchain) {
+        for block in blockchain.blocks_in_range(self.next_height..blockchain.height() + 1) {
+            self.process_block(block);
+        }
+    }
+
+    // Processes the chain again from `height`, e.g. after importing a key
+    // whose outputs may already be confirmed. Blocks are processed in order,
+    // so outputs created and spent since are removed again.
+    pub fn rescan_from(&mut self, blockchain: &Blockchain, height: u64) {
+        self.next_height = self.next_height.min(height);
+        self.sync(blockchain);
+    }
+
+    pub fn process_block(&mut self, block: &Block) {
+        let height = block.header.index;
+        for (position, tx) in block.transactions.iter().enumerate() {
+            let mut relevant = false;
+            if position > 0 {
+                for input in &tx.inputs {
+                    relevant |= self.utxos.remove(&input.outpoint()).is_some();
+                    self.locked.remove(&input.outpoint());
+                }
+                self.drop_conflicts(tx);
+            }
+            for (index, output) in tx.outputs.iter().enumerate() {
+                if self.pkhashes.contains(&output.pkhash) {
+                    relevant = true;
+                    let owned = OwnedOutput {
+                        output: output.clone(),
+                        height,
+                    };
+                    self.utxos.insert(OutPoint::new(tx.id, index as u16), owned);
+                }
+            }
+
+            if self.pending.remove(&tx.id).is_some() || relevant {
+                self.tx_heights.insert(tx.id, height);
+            }
+        }
+        self.next_height = self.next_height.max(height + 1);
+    }
+
+    // Pending transactions other than `tx` spending one of its inputs can
+    // never confirm once it has
+    fn drop_conflicts(&mut self, tx: &Transaction) {
+        let spent: HashSet<OutPoint> = tx.inputs.iter().map(TxInput::outpoint).collect();
+        let conflicts: Vec<TxId> = self
+            .pending
+            .values()
+            .filter(|pending| {
+                pending.tx.id != tx.id
+                    && pending
+                        .tx
+                        .inputs
+                        .iter()
+                        .any(|input| spent.contains(&input.outpoint()))
+            })
+            .map(|pending| pending.tx.id)
+            .collect();
+        for txid in conflicts {
+            self.abandon_transaction(&txid);
+        }
+    }
+
+    // Records a transaction sent by the wallet, so its inputs aren't spent
+    // again before it confirms
+    pub fn add_pending(&mut self, tx: Transaction) {
+        self.add_pending_at(tx, SystemClock.now());
+    }
+
+    // With `now` as the time it was broadcast
+    pub fn add_pending_at(&mut self, tx: Transaction, now: u64) {
+        if !self.tx_heights.contains_key(&tx.id) {
+            let pending = PendingTx {
+                tx,
+                last_broadcast: now,
+            };
+            self.pending.insert(pending.tx.id, pending);
+        }
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &Transaction> {
+        self.pending.values().map(|pending| &pending.tx)
+    }
+
+    // Stops tracking a pending transaction, so its inputs can be spent
+    // again, e.g. by a replacement. It may still confirm if peers have it.
+    pub fn abandon_transaction(&mut self, txid: &TxId) -> Option<Transaction> {
+        let pending = self.pending.remove(txid)?;
+        for input in &pending.tx.inputs {
+            self.locked.remove(&input.outpoint());
+        }
+        Some(pending.tx)
+    }
+
+    // Keeps an output from being picked for another transaction
+    pub fn lock_unspent(&mut self, outpoint: OutPoint) {
+        self.locked.insert(outpoint);
+    }
+
+    // Returns whether it was locked
+    pub fn unlock_unspent(&mut self, outpoint: &OutPoint) -> bool {
+        self.locked.remove(outpoint)
+    }
+
+    pub fn is_locked(&self, outpoint: &OutPoint) -> bool {
+        self.locked.contains(outpoint)
+    }
+
+    // The pending transactions last broadcast at least REBROADCAST_INTERVAL
+    // before `now`, which are marked as broadcast at `now`
+    pub fn due_for_rebroadcast(&mut self, now: u64) -> Vec<Transaction> {
+        let mut due: Vec<Transaction> = self
+            .pending
+            .values_mut()
+            .filter(|pending| now.saturating_sub(pending.last_broadcast) >= REBROADCAST_INTERVAL)
+            .map(|pending| {
+                pending.last_broadcast = now;
+                pending.tx.clone()
+            })
+            .collect();
+        // Parents before their children
+        due.sort_by_key(|tx| self.pending_depth(tx));
+        due
+    }
+
+    // The length of the longest chain of pending parents
+    fn pending_depth(&self, tx: &Transaction) -> usize {
+        tx.inputs
+            .iter()
+            .filter_map(|input| self.pending.get(&input.txid))
+            .map(|parent| 1 + self.pending_depth(&parent.tx))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Sends the transactions due to the node's peers. Those the node
+    // rejects stay pending, to be tried again later or abandoned.
+    pub fn rebroadcast(&mut self, node: &Node) -> Vec<TxId> {
+        let mut sent = Vec::new();
+        for tx in self.due_for_rebroadcast(SystemClock.now()) {
+            match node.rebroadcast_transaction(&tx) {
+                Ok(()) => sent.push(tx.id),
+                Err(e) => log::warn(
+                    "wallet",
+                    "rebroadcast rejected",
+                    &[("txid", &tx.id), ("error", &e)],
+                ),
+            }
+        }
+        sent
+    }
+
+    pub fn utxos(&self) -> &HashMap<OutPoint, OwnedOutput> {
+        &self.utxos
+    }
+
+    // The unspent outputs neither locked nor used by a pending transaction
+    pub fn spendable(&self) -> Vec<(OutPoint, &OwnedOutput)> {
+        let spent: HashSet<OutPoint> = self
+            .pending
+            .values()
+            .flat_map(|pending| pending.tx.inputs.iter().map(TxInput::outpoint))
+            .collect();
+        self.utxos
+            .iter()
+            .filter(|(outpoint, _)| !spent.contains(*outpoint) && !self.locked.contains(*outpoint))
+            .map(|(outpoint, owned)| (*outpoint, owned))
+            .collect()
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().map(|owned| owned.output.value.0).sum()
+    }
+
+    // 0 for pending or unknown transactions
+    pub fn confirmations(&self, txid: &TxId) -> u64 {
+        match self.tx_heights.get(txid) {
+            Some(height) => self.next_height - height,
+            None => 0,
+        }
+    }
+
+    pub fn synced_height(&self) -> u64 {
+        self.next_height.saturating_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::ChainParams;
+    use crate::signature::SignatureScheme;
+    use crate::utils::{mine, mine_with_fees};
+
+    fn mine_to(blockchain: &mut Blockchain, pkhash: [u8; 32]) -> TxId {
+        let mut block = blockchain.next_block();
+        mine(&mut block, pkhash, blockchain.get_block_reward());
+        let coinbase_id = block.transactions[0].id;
+        blockchain.add_block(block).unwrap();
+        coinbase_id
+    }
+
+    #[test]
+    fn test_tracks_utxos_and_pending() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+        let reward = blockchain.get_block_reward();
+        let mut state = WalletState::for_wallet(&wallet, 20);
+
+        let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+        mine_to(&mut blockchain, [0; 32]);
+        state.sync(&blockchain);
+        assert_eq!(state.balance(), reward);
+        assert_eq!(state.confirmations(&coinbase_id), 2);
+        assert_eq!(state.synced_height(), 2);
+
+        let mut tx = Transaction::new(
+            vec![TxInput::new_unsigned(coinbase_id, 0).with_value(reward)],
+            vec![
+                TxOutput::new(100, [4; 32]),
+                TxOutput::new(reward - 150, wallet.pkhash),
+            ],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        let txid = tx.id;
+        state.add_pending(tx.clone());
+        assert!(state.spendable().is_empty());
+        assert_eq!(state.confirmations(&txid), 0);
+
+        let mut block = blockchain.next_block();
+        block.add_tx(tx);
+        mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+        blockchain.add_block(block).unwrap();
+        state.sync(&blockchain);
+
+        assert_eq!(state.pending().count(), 0);
+        assert_eq!(state.balance(), reward - 150);
+        assert_eq!(state.spendable().len(), 1);
+        assert_eq!(state.confirmations(&txid), 1);
+    }
+
+    #[test]
+    fn test_rebroadcast_and_abandon() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+        let reward = blockchain.get_block_reward();
+        let mut state = WalletState::for_wallet(&wallet, 20);
+        let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+        state.sync(&blockchain);
+
+        let spend = |fee| {
+            let mut tx = Transaction::new(
+                vec![TxInput::new_unsigned(coinbase_id, 0).with_value(reward)],
+                vec![TxOutput::new(reward - fee, [4; 32])],
+            );
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        };
+        let tx = spend(1000);
+        state.add_pending_at(tx.clone(), 0);
+        assert!(
+            state
+                .due_for_rebroadcast(REBROADCAST_INTERVAL - 1)
+                .is_empty()
+        );
+        assert_eq!(state.due_for_rebroadcast(REBROADCAST_INTERVAL).len(), 1);
+        assert!(
+            state
+                .due_for_rebroadcast(REBROADCAST_INTERVAL + 1)
+                .is_empty()
+        );
+
+        // Not in the node's mempool yet, so it is submitted
+        state.add_pending_at(tx.clone(), 0);
+        let node = Node::new(blockchain);
+        assert_eq!(state.rebroadcast(&node), vec![tx.id]);
+        assert!(node.blockchain().mempool.contains(&tx.id));
+
+        assert_eq!(
+            state.abandon_transaction(&tx.id).map(|tx| tx.id),
+            Some(tx.id)
+        );
+        assert_eq!(state.spendable().len(), 1);
+        assert!(state.abandon_transaction(&tx.id).is_none());
+
+        // A confirmed replacement ends the original's rebroadcasts
+        state.add_pending_at(tx.clone(), 0);
+        let replacement = spend(2000);
+        let mut blockchain = node.blockchain();
+        blockchain.mempool.remove_transaction(&tx.id);
+        let mut block = blockchain.next_block();
+        block.add_tx(replacement);
+        mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+        blockchain.add_block(block).unwrap();
+        state.sync(&blockchain);
+        assert_eq!(state.pending().count(), 0);
+        assert_eq!(state.balance(), 0);
+    }
+
+    #[test]
+    fn test_rescan_after_import() {
+        let mut blockchain = Blockchain::new(ChainParams::regtest());
+        let reward = blockchain.get_block_reward();
+        mine_to(&mut blockchain, [7; 32]);
+        mine_to(&mut blockchain, [0; 32]);
+
+        let mut state = WalletState::new();
+        state.sync(&blockchain);
+        state.watch([7; 32]);
+        state.sync(&blockchain);
+        assert_eq!(state.balance(), 0);
+
+        state.rescan_from(&blockchain, 2);
+        assert_eq!(state.balance(), 0);
+        state.rescan_from(&blockchain, 0);
+        assert_eq!(state.balance(), reward);
+        assert_eq!(state.synced_height(), 2);
+    }
+}
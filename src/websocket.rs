@@ -0,0 +1,246 @@
+// The parts of RFC 6455 needed to push events to clients: the opening
+// handshake and unfragmented frames.
+
+use base64ct::{Base64, Encoding};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xa;
+
+// Clients only send control frames and short filter updates
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// The Sec-WebSocket-Accept value answering a client's Sec-WebSocket-Key
+pub fn accept_key(key: &str) -> String {
+    let hash = sha1(format!("{}{HANDSHAKE_GUID}", key.trim()).as_bytes());
+    let mut buf = [0u8; 28];
+    Base64::encode(&hash, &mut buf).unwrap().to_string()
+}
+
+pub fn write_handshake_response<W: Write>(writer: &mut W, key: &str) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )?;
+    writer.flush()
+}
+
+// Frames from a client must be masked, frames from a server must not
+pub fn write_frame<W: Write>(
+    writer: &mut W,
+    opcode: u8,
+    payload: &[u8],
+    mask: Option<[u8; 4]>,
+) -> io::Result<()> {
+    let mut frame = vec![0x80 | opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(payload),
+    }
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+// Returns the opcode and unmasked payload. Fragmented frames are rejected.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    if head[0] & 0x80 == 0 {
+        return Err(invalid("fragmented frame"));
+    }
+    let opcode = head[0] & 0x0f;
+
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as usize
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            usize::try_from(u64::from_be_bytes(len)).map_err(|_| invalid("frame too large"))?
+        }
+        len => len as usize,
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid("frame too large"));
+    }
+
+    let mask = if head[1] & 0x80 != 0 {
+        let mut mask = [0u8; 4];
+        reader.read_exact(&mut mask)?;
+        Some(mask)
+    } else {
+        None
+    };
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    if let Some(mask) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    Ok((opcode, payload))
+}
+
+// Opens a WebSocket connection to `path` on a server, e.g. a node's RPC
+// endpoint
+pub fn connect(addr: impl ToSocketAddrs, path: &str) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr)?;
+    let key = "dGhlIHNhbXBsZSBub25jZQ==";
+    write!(
+        stream,
+        "GET {path} HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    )?;
+    stream.flush()?;
+
+    // Read byte by byte so no frame data is buffered away
+    let mut reader = BufReader::with_capacity(1, &stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    if !status.starts_with("HTTP/1.1 101") {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            status.trim_end().to_string(),
+        ));
+    }
+
+    let expected = accept_key(key);
+    let mut accepted = false;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("sec-websocket-accept")
+        {
+            accepted = value.trim() == expected;
+        }
+    }
+
+    if !accepted {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid handshake response",
+        ));
+    }
+    Ok(stream)
+}
+
+// Only used for the handshake, where SHA-1's weaknesses don't matter
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in h.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key() {
+        // the example from RFC 6455
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(
+            hex::encode(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let long = vec![7u8; 300];
+        let mut buf = Vec::new();
+        write_frame(&mut buf, OPCODE_TEXT, b"hello", Some([1, 2, 3, 4])).unwrap();
+        write_frame(&mut buf, OPCODE_TEXT, &long, None).unwrap();
+        write_frame(&mut buf, OPCODE_CLOSE, &[], None).unwrap();
+
+        let mut reader = buf.as_slice();
+        assert_eq!(
+            read_frame(&mut reader).unwrap(),
+            (OPCODE_TEXT, b"hello".to_vec())
+        );
+        assert_eq!(read_frame(&mut reader).unwrap(), (OPCODE_TEXT, long));
+        assert_eq!(read_frame(&mut reader).unwrap(), (OPCODE_CLOSE, vec![]));
+    }
+}
@@ -1,26 +1,57 @@
-use rust_blockchain::{blockchain, errors, transaction, utils, wallet};
+use rust_blockchain::{
+    amount, block, blockchain, clock, consensus, deployments, errors, events, fixtures, hash,
+    merkle, musig, params, script, signature, transaction, tx_builder, utils, utxo, wallet,
+};
 
-use blockchain::Blockchain;
-use errors::{BlockValidationError, TransactionError};
+use amount::Amount;
+use block::Block;
+use blockchain::{BlockAcceptance, Blockchain, HistoryEntry};
+use clock::MockClock;
+use consensus::{PowAlgorithm, ProofOfWork};
+use deployments::{Deployment, DeploymentState};
+use errors::{BlockValidationError, ChainError, StorageError, TransactionError};
+use events::ChainEvent;
+use fixtures::Fixture;
+use hash::{Hash256, OutPoint, TxId};
+use k256::ecdsa::Signature;
+use musig::{KeyAggContext, Session};
+use params::ChainParams;
+use signature::SignatureScheme;
+use std::sync::Arc;
 use transaction::{Transaction, TxInput, TxOutput};
-use utils::mine;
+use tx_builder::TxBuilder;
+use utils::{mine, mine_with_fees, solve};
+use utxo::UTXOSet;
 use wallet::Wallet;
 
+// An input spending an output of the chain's UTXO set, with its value
+fn spend_input(blockchain: &Blockchain, txid: impl Into<TxId>, index: u16) -> TxInput {
+    let txid = txid.into();
+    let value = blockchain
+        .utxos
+        .get_utxo(&OutPoint::new(txid, index))
+        .map_or(Amount::ZERO, |output| output.value);
+    TxInput::new_unsigned(txid, index).with_value(value)
+}
+
 #[test]
 fn test_spend_utxo() {
     let wallet = Wallet::new();
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
 
     mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
 
-    assert_eq!(blockchain.add_block(block), Ok(()));
+    assert_eq!(
+        blockchain.add_block(block),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
 
-    let (txid, index) = &blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let OutPoint { txid, index } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
 
     let mut block2 = blockchain.next_block();
 
-    let inputs = vec![TxInput::new_unsigned(*txid, *index)];
+    let inputs = vec![spend_input(&blockchain, txid, index)];
 
     let outputs = vec![
         TxOutput::new(100, [0; 32]),       // unspendable
@@ -29,24 +60,100 @@ fn test_spend_utxo() {
 
     let mut tx = Transaction::new(inputs, outputs);
 
-    wallet.sign_transaction(&mut tx);
+    wallet.sign_transaction(&mut tx).unwrap();
     let txid = tx.id;
 
     block2.add_tx(tx);
     mine(&mut block2, wallet.pkhash, blockchain.get_block_reward());
 
-    assert_eq!(blockchain.add_block(block2), Ok(()));
+    assert_eq!(
+        blockchain.add_block(block2),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
     assert!(
-        blockchain.utxos.get_utxo(txid, 0).is_some()
-            && blockchain.utxos.get_utxo(txid, 1).is_some()
+        blockchain.utxos.get_utxo(&OutPoint::new(txid, 0)).is_some()
+            && blockchain.utxos.get_utxo(&OutPoint::new(txid, 1)).is_some()
+    );
+}
+
+#[test]
+fn test_outputs_to_same_address() {
+    let wallet = Wallet::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let reward = blockchain.get_block_reward();
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    // A payment to the wallet with change back to it
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![
+            TxOutput::new(reward / 2, wallet.pkhash),
+            TxOutput::new(reward / 2 - 1000, wallet.pkhash),
+        ],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+    blockchain.submit_transaction(tx).unwrap();
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(100);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+    assert_eq!(blockchain.utxos.utxos_from_pkhash(wallet.pkhash).len(), 2);
+    assert_eq!(blockchain.utxos.balance(wallet.pkhash), reward - 1000);
+
+    // Each output is spent on its own
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, 1)],
+        vec![TxOutput::new(reward / 2 - 2000, [1; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    blockchain.submit_transaction(tx).unwrap();
+    assert!(blockchain.utxos.get_utxo(&OutPoint::new(txid, 0)).is_some());
+}
+
+#[test]
+fn test_inputs_signed_by_different_wallets() {
+    let alice = Wallet::new();
+    let bob = Wallet::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let reward = blockchain.get_block_reward();
+    let alice_coinbase = mine_to(&mut blockchain, alice.pkhash);
+    let bob_coinbase = mine_to(&mut blockchain, bob.pkhash);
+
+    let mut tx = Transaction::new(
+        vec![
+            spend_input(&blockchain, alice_coinbase, 0),
+            spend_input(&blockchain, bob_coinbase, 0),
+        ],
+        vec![TxOutput::new(2 * reward - 1000, [1; 32])],
+    );
+
+    // Each key only authorizes the input spending its own output
+    bob.sign_input(&mut tx, 0).unwrap();
+    alice.sign_input(&mut tx, 1).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(tx.clone()),
+        Err(TransactionError::UnauthorizedSpend)
+    );
+
+    bob.sign_input(&mut tx, 1).unwrap();
+    alice.sign_input(&mut tx, 0).unwrap();
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(100);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(block),
+        Ok(BlockAcceptance::ConnectedToTip)
     );
+    assert_eq!(blockchain.utxos.balance([1; 32]), 2 * reward - 1000);
 }
 
 #[test]
 fn test_invalid_index() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
-    block.index = 2;
+    block.header.index = 2;
     mine(&mut block, [0; 32], blockchain.get_block_reward());
     assert_eq!(
         blockchain.add_block(block),
@@ -56,16 +163,16 @@ fn test_invalid_index() {
 
 #[test]
 fn test_invalid_timestamp() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
     mine(&mut block, [0; 32], blockchain.get_block_reward());
     blockchain.add_block(block).unwrap();
 
     let mut block2 = blockchain.next_block();
-    block2.timestamp = 1000;
+    block2.header.timestamp = 1000;
     block2.add_coinbase_tx([0; 32], blockchain.get_block_reward());
-    while block2.hash() > block2.target {
-        block2.nonce += 1;
+    while block2.hash().0 > block2.header.target() {
+        block2.header.nonce += 1;
     }
     block2.update_digest();
 
@@ -75,11 +182,57 @@ fn test_invalid_timestamp() {
     )
 }
 
+#[test]
+fn test_timestamps_with_mock_clock() {
+    let start = ChainParams::regtest().genesis_timestamp + 1000;
+    let clock = Arc::new(MockClock::new(start));
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    blockchain.set_clock(clock.clone());
+
+    let mut block = blockchain.next_block();
+    assert_eq!(block.header.timestamp, start);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    // The coinbase takes the block's time, not the wall clock's
+    assert_eq!(block.transactions[0].timestamp, start);
+    blockchain.add_block(block).unwrap();
+
+    let mut block = blockchain.next_block();
+    block.header.timestamp = start + 60;
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    assert_eq!(
+        blockchain.add_block(block.clone()),
+        Err(BlockValidationError::TimestampInFuture)
+    );
+    clock.advance(60);
+    assert_eq!(blockchain.clock().now(), start + 60);
+    assert_eq!(
+        blockchain.add_block(block),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+
+    // transactions from the future are rejected too
+    let mut block = blockchain.next_block();
+    let mut tx = Transaction::new_with_clock(
+        vec![TxInput::new_unsigned([1; 32], 1)],
+        vec![TxOutput::new(1, [1; 32]), TxOutput::new(1, [2; 32])],
+        &MockClock::new(start + 61),
+    );
+    tx.update_id();
+    block.add_tx(tx);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::InvalidTimestamp
+        ))
+    );
+}
+
 #[test]
 fn test_invalid_prev_hash() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
-    block.prev_hash = [0; 32];
+    block.header.prev_hash = Hash256([0; 32]);
     mine(&mut block, [0; 32], blockchain.get_block_reward());
     assert_eq!(
         blockchain.add_block(block),
@@ -89,7 +242,7 @@ fn test_invalid_prev_hash() {
 
 #[test]
 fn test_unauthorized_spend_rejected() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let wallet = Wallet::new();
 
     let mut block = blockchain.next_block();
@@ -97,17 +250,20 @@ fn test_unauthorized_spend_rejected() {
     mine(&mut block, [0; 32], blockchain.get_block_reward()); // block reward UTXO will be owned by null address
     blockchain.add_block(block).unwrap();
 
-    let (txid, output_index) = blockchain.utxos.utxos_from_pkhash([0; 32])[0];
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash([0; 32])[0];
 
     let inputs = vec![
-        TxInput::new_unsigned(txid, output_index), // attempt to spend coinbase UTXO
+        spend_input(&blockchain, txid, output_index), // attempt to spend coinbase UTXO
     ];
 
     let outputs = vec![TxOutput::new(100, [0; 32])];
 
     let mut tx = Transaction::new(inputs, outputs);
     let mut block = blockchain.next_block();
-    wallet.sign_transaction(&mut tx);
+    wallet.sign_transaction(&mut tx).unwrap();
     block.add_tx(tx);
 
     mine(&mut block, [0; 32], blockchain.get_block_reward());
@@ -122,10 +278,184 @@ fn test_unauthorized_spend_rejected() {
     );
 }
 
+#[test]
+fn test_tampered_spend_rejected() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![
+            TxOutput::new(1000, [1; 32]),
+            TxOutput::new(reward - 2000, wallet.pkhash),
+        ],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    let tampered = |change: &dyn Fn(&mut Transaction)| {
+        let mut tx = tx.clone();
+        change(&mut tx);
+        tx.update_id();
+        tx
+    };
+    // Redirecting an output, or changing its value, breaks the signature
+    let redirected = tampered(&|tx| tx.outputs[1].pkhash = [6; 32]);
+    let changed = tampered(&|tx| tx.outputs[0].value = Amount(reward - 2000));
+    let added = tampered(&|tx| tx.outputs.push(TxOutput::new(500, [6; 32])));
+    let undervalued = tampered(&|tx| tx.inputs[0].value = Amount(1));
+    for tx in [redirected, changed, added, undervalued] {
+        assert_eq!(
+            blockchain.submit_transaction(tx),
+            Err(TransactionError::SignatureVerificationFailed)
+        );
+    }
+
+    // Signed over the wrong value, so the UTXO set disagrees
+    let mut wrong_value = tx.clone();
+    wrong_value.inputs[0].value = Amount(reward - 1);
+    wallet.sign_transaction(&mut wrong_value).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(wrong_value),
+        Err(TransactionError::InputValueMismatch)
+    );
+
+    let old_version = tampered(&|tx| tx.version = 1);
+    assert_eq!(
+        blockchain.submit_transaction(old_version),
+        Err(TransactionError::UnsupportedVersion)
+    );
+
+    // The high-s twin of the signature is rejected rather than giving the
+    // transaction a second txid
+    let malleated = tampered(&|tx| {
+        let signature = Signature::from_bytes(&tx.inputs[0].signature.into()).unwrap();
+        let high_s = Signature::from_scalars(signature.r(), -*signature.s()).unwrap();
+        tx.inputs[0].signature = high_s.to_bytes().into();
+    });
+    assert_ne!(malleated.id, tx.id);
+    assert_eq!(
+        blockchain.submit_transaction(malleated),
+        Err(TransactionError::NonCanonicalSignature)
+    );
+
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+}
+
+#[test]
+fn test_transaction_version_activation() {
+    let params = ChainParams {
+        transaction_versions: vec![(2, 3)],
+        ..ChainParams::regtest()
+    };
+    let mut blockchain = Blockchain::new(params);
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [1; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(tx.clone()),
+        Err(TransactionError::VersionNotActive)
+    );
+    let mut block = blockchain.next_block();
+    block.add_tx(tx.clone());
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::VersionNotActive
+        ))
+    );
+
+    mine_to(&mut blockchain, [0; 32]);
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+}
+
+#[test]
+fn test_deployment_activation() {
+    let deployment = |name, bit| Deployment {
+        name,
+        bit,
+        start_height: 4,
+        timeout_height: 12,
+    };
+    let params = ChainParams {
+        deployments: vec![deployment("signaled", 0), deployment("ignored", 1)],
+        signal_period: 4,
+        signal_threshold: 3,
+        ..ChainParams::regtest()
+    };
+    let mut blockchain = Blockchain::new(params);
+    assert_eq!(blockchain.deployment_state("unknown"), None);
+
+    let mut states = Vec::new();
+    while blockchain.height() < 16 {
+        states.push((
+            blockchain.deployment_state("signaled").unwrap(),
+            blockchain.deployment_state("ignored").unwrap(),
+        ));
+        let mut block = blockchain.next_block();
+        // Miners signal for the first deployment only
+        block.header.version &= !2;
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+
+    // Indexed by height, genesis left out
+    use DeploymentState::*;
+    assert_eq!(states[3 - 1], (Defined, Defined));
+    assert_eq!(states[4 - 1], (Started, Started));
+    assert_eq!(states[8 - 1], (LockedIn, Started));
+    assert_eq!(states[12 - 1], (Active, Failed));
+    assert_eq!(
+        blockchain.get_block_by_height(4).unwrap().header.version,
+        0x2000_0001
+    );
+    assert_eq!(
+        blockchain.get_block_by_height(8).unwrap().header.version,
+        0x2000_0000
+    );
+}
+
+#[test]
+fn test_chain_stats() {
+    let params = ChainParams::regtest();
+    let start = params.genesis_timestamp;
+    let clock = Arc::new(MockClock::new(start));
+    let mut blockchain = Blockchain::new(params);
+    blockchain.set_clock(clock.clone());
+    let stats = blockchain.stats();
+    assert_eq!(
+        (stats.height, stats.hash_rate, stats.transactions),
+        (0, 0, 0)
+    );
+
+    for interval in [10, 20, 60] {
+        clock.advance(interval);
+        let mut block = blockchain.next_block();
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+    let stats = blockchain.stats();
+    assert_eq!(stats.height, 3);
+    assert_eq!(stats.average_block_interval, 30);
+    assert_eq!(stats.median_block_interval, 20);
+    let work = blockchain.total_work() - blockchain.chain_work_at(0).unwrap();
+    assert_eq!(stats.hash_rate, u64::try_from(work / 90u64).unwrap());
+    assert_eq!(stats.transactions, 3);
+    assert_eq!(stats.utxos, 3);
+    assert_eq!(stats.supply, Amount(150_000_000));
+}
+
 #[test]
 fn test_missing_coinbase_tx() {
     let wallet = Wallet::new();
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
 
     let inputs = vec![
@@ -136,12 +466,12 @@ fn test_missing_coinbase_tx() {
     let outputs = vec![TxOutput::new(100, [0; 32]), TxOutput::new(200, [1; 32])];
 
     let mut tx = Transaction::new(inputs, outputs);
-    wallet.sign_transaction(&mut tx);
+    wallet.sign_transaction(&mut tx).unwrap();
 
     block.add_tx(tx);
 
-    while block.hash() > block.target {
-        block.nonce += 1;
+    while block.hash().0 > block.header.target() {
+        block.header.nonce += 1;
     }
     block.update_digest();
 
@@ -155,9 +485,9 @@ fn test_missing_coinbase_tx() {
 
 #[test]
 fn test_invalid_block_reward() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
-    mine(&mut block, [0; 32], 1000);
+    mine(&mut block, [0; 32], blockchain.get_block_reward() + 1);
 
     assert_eq!(
         blockchain.add_block(block),
@@ -167,45 +497,89 @@ fn test_invalid_block_reward() {
     )
 }
 
+#[test]
+fn test_supply_cap() {
+    let params = ChainParams {
+        max_money: Amount(120_000_000),
+        ..ChainParams::regtest()
+    };
+    let mut blockchain = Blockchain::new(params);
+    for _ in 0..2 {
+        let mut block = blockchain.next_block();
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+    assert_eq!(blockchain.supply(), Amount(100_000_000));
+    assert_eq!(blockchain.get_block_reward(), 20_000_000);
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], 50_000_000);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::SupplyCapExceeded)
+    );
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+    assert_eq!(blockchain.supply(), Amount(120_000_000));
+    assert_eq!(blockchain.get_block_reward(), 0);
+
+    let mut tx = Transaction::new(
+        vec![TxInput::new_unsigned([1; 32], 1)],
+        vec![TxOutput::new(120_000_001, [1; 32])],
+    );
+    tx.update_id();
+    assert_eq!(
+        blockchain.submit_transaction(tx),
+        Err(TransactionError::AmountOutOfRange)
+    );
+}
+
 #[test]
 fn test_duplicate_coinbase_tx() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let mut block = blockchain.next_block();
 
     block.add_coinbase_tx([0; 32], blockchain.get_block_reward());
     // add coinbase tx again in mine function
     mine(&mut block, [0; 32], blockchain.get_block_reward());
 
+    // the second coinbase is treated as a regular transaction spending an
+    // output that does not exist
     assert_eq!(
         blockchain.add_block(block),
         Err(BlockValidationError::InvalidTransactions(
-            TransactionError::InvalidPublicKey
+            TransactionError::InvalidUTXO
         ))
     )
 }
 
 #[test]
 fn test_double_spend() {
-    let mut blockchain = Blockchain::new();
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
     let wallet = Wallet::new();
 
     let mut block = blockchain.next_block();
     mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
     blockchain.add_block(block).unwrap();
 
-    let (txid, output_index) = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
 
     let mut tx = Transaction::new(
-        vec![TxInput::new_unsigned(txid, output_index)],
+        vec![spend_input(&blockchain, txid, output_index)],
         vec![TxOutput::new(100, [0; 32])],
     );
-    wallet.sign_transaction(&mut tx);
+    wallet.sign_transaction(&mut tx).unwrap();
 
     let mut double_spend_tx = Transaction::new(
-        vec![TxInput::new_unsigned(txid, output_index)],
+        vec![spend_input(&blockchain, txid, output_index)],
         vec![TxOutput::new(100, wallet.pkhash)],
     );
-    wallet.sign_transaction(&mut double_spend_tx);
+    wallet.sign_transaction(&mut double_spend_tx).unwrap();
 
     let mut block2 = blockchain.next_block();
     block2.add_tx(tx);
@@ -220,3 +594,1803 @@ fn test_double_spend() {
         ))
     );
 }
+
+#[test]
+fn test_spend_already_spent_output() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+    let input = spend_input(&blockchain, coinbase_id, 0);
+
+    let spend = |value| {
+        let mut tx = Transaction::new(vec![input.clone()], vec![TxOutput::new(value, [0; 32])]);
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    };
+
+    let mut block = blockchain.next_block();
+    block.add_tx(spend(100));
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    let double_spend = spend(200);
+    assert_eq!(
+        blockchain.submit_transaction(double_spend.clone()),
+        Err(TransactionError::AlreadySpent)
+    );
+    let mut block = blockchain.next_block();
+    block.add_tx(double_spend);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::AlreadySpent
+        ))
+    );
+
+    // an output index the transaction never had
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 5)],
+        vec![TxOutput::new(100, [0; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(tx),
+        Err(TransactionError::InvalidUTXO)
+    );
+}
+
+#[test]
+fn test_spend_unconfirmed_output() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut parent = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(reward - 1000, wallet.pkhash)],
+    );
+    wallet.sign_transaction(&mut parent).unwrap();
+    let mut child = Transaction::new(
+        vec![TxInput::new_unsigned(parent.id, 0).with_value(reward - 1000)],
+        vec![TxOutput::new(reward - 2000, [4; 32])],
+    );
+    wallet.sign_transaction(&mut child).unwrap();
+
+    blockchain.submit_transaction(parent.clone()).unwrap();
+    blockchain.submit_transaction(child.clone()).unwrap();
+
+    // both fit in one block, but only with the parent first
+    let mut block = blockchain.next_block();
+    block.add_tx(child.clone());
+    block.add_tx(parent.clone());
+    mine(&mut block, [0; 32], reward);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::InvalidUTXO
+        ))
+    );
+
+    let mut block = blockchain.next_block();
+    for tx in blockchain.mempool.select_for_block(10) {
+        block.add_tx(tx);
+    }
+    assert_eq!(blockchain.calculate_block_fees(&block), Ok(Amount(2000)));
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+    assert!(blockchain.mempool.is_empty());
+    assert_eq!(blockchain.utxos.balance([4; 32]), reward - 2000);
+}
+
+#[test]
+fn test_block_template() {
+    let mut params = ChainParams::regtest();
+    params.max_block_size = 600;
+    let mut blockchain = Blockchain::new(params);
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let coinbase_ids = [
+        mine_to(&mut blockchain, wallet.pkhash),
+        mine_to(&mut blockchain, wallet.pkhash),
+    ];
+
+    for (coinbase_id, fee) in coinbase_ids.into_iter().zip([1000, 2000]) {
+        let mut tx = Transaction::new(
+            vec![spend_input(&blockchain, coinbase_id, 0)],
+            vec![TxOutput::new(reward - fee, [4; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        blockchain.submit_transaction(tx).unwrap();
+    }
+
+    // only the better paying transaction fits
+    let mut block = blockchain.create_block_template([0; 32]).unwrap();
+    assert_eq!(block.transactions.len(), 2);
+    assert_eq!(block.transactions[0].outputs[0].value, reward + 2000);
+    solve(&mut block);
+    blockchain.add_block(block).unwrap();
+
+    let mut block = blockchain.create_block_template([0; 32]).unwrap();
+    assert_eq!(block.transactions[0].outputs[0].value, reward + 1000);
+    let tx = block.transactions[1].clone();
+    block.add_tx(tx);
+    solve(&mut block);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::BlockTooLarge)
+    );
+}
+
+#[test]
+fn test_transaction_for_other_chain_rejected() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let mut other_chain_id = blockchain.chain_id();
+    other_chain_id[0] ^= 0xFF;
+    let wallet = Wallet::for_chain(other_chain_id);
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(100, [0; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    let mut block2 = blockchain.next_block();
+    block2.add_tx(tx);
+    mine(&mut block2, wallet.pkhash, blockchain.get_block_reward());
+
+    assert_eq!(
+        blockchain.add_block(block2),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::SignatureVerificationFailed
+        ))
+    );
+}
+
+#[test]
+fn test_mempool_evicted_on_block() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(1000, [0; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+    assert!(blockchain.mempool.contains(&txid));
+
+    let path = std::env::temp_dir().join(format!("mempool_{}.bin", hex::encode(txid)));
+    blockchain.mempool.save_to_disk(&path).unwrap();
+
+    let mut block2 = blockchain.next_block();
+    for tx in blockchain.mempool.select_for_block(10) {
+        block2.add_tx(tx);
+    }
+    mine(&mut block2, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block2).unwrap();
+
+    assert!(blockchain.mempool.is_empty());
+
+    // the saved transaction is now confirmed, so it is not re-added
+    assert_eq!(blockchain.load_mempool(&path).unwrap(), 0);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_utxos_on_disk() {
+    let dir = std::env::temp_dir().join(format!("utxos_on_disk_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (chain_path, utxo_path) = (dir.join("chain.dat"), dir.join("utxos.dat"));
+    let _ = std::fs::remove_file(&utxo_path);
+
+    let params = ChainParams::regtest();
+    let utxos = UTXOSet::open(&utxo_path).unwrap();
+    let mut blockchain = Blockchain::with_utxos(params.clone(), utxos);
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [3; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+    blockchain.save_to_disk(&chain_path).unwrap();
+    let tip = blockchain.prev_hash();
+    drop(blockchain);
+
+    // the set is at the tip of the file, so it is used as it is
+    let utxos = UTXOSet::open(&utxo_path).unwrap();
+    assert_eq!(utxos.tip(), Some(tip));
+    let loaded = Blockchain::load_from_disk_with_utxos(&chain_path, params.clone(), utxos).unwrap();
+    assert_eq!(loaded.height(), 2);
+    assert_eq!(loaded.utxos.balance([3; 32]), 1000);
+    assert!(loaded.utxos.utxos_from_pkhash(wallet.pkhash).is_empty());
+    drop(loaded);
+
+    // a set that is behind is rebuilt from the blocks
+    let mut stale = UTXOSet::open(&utxo_path).unwrap();
+    stale.clear().unwrap();
+    stale.add_utxo(OutPoint::new([9; 32], 0), TxOutput::new(5, [9; 32]));
+    let loaded = Blockchain::load_from_disk_with_utxos(&chain_path, params, stale).unwrap();
+    assert_eq!(loaded.utxos.len(), 2);
+    assert_eq!(loaded.utxos.balance([9; 32]), 0);
+    assert_eq!(loaded.utxos.tip(), Some(tip));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_undo_data() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+    let before = blockchain.utxos.commitment();
+    let parent = blockchain.prev_hash();
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [3; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let tip = blockchain.get_block_by_height(2).unwrap().clone();
+    let undo = blockchain.undo_data(&tip.digest).unwrap().clone();
+    assert_eq!(undo.spent.len(), 1);
+    assert_eq!(undo.spent[0].0, OutPoint::new(coinbase_id, 0));
+
+    blockchain.utxos.disconnect_block(&tip, &undo).unwrap();
+    assert_eq!(blockchain.utxos.commitment(), before);
+    assert_eq!(blockchain.utxos.tip(), Some(parent));
+}
+
+#[test]
+fn test_save_and_load_chain() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+
+    for _ in 0..2 {
+        let mut block = blockchain.next_block();
+        mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(100, [3; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let path = std::env::temp_dir().join(format!("chain_{}.bin", hex::encode(wallet.pkhash)));
+    blockchain.save_to_disk(&path).unwrap();
+
+    let loaded = Blockchain::load_from_disk(&path, ChainParams::mainnet()).unwrap();
+    assert_eq!(loaded.height(), 3);
+    assert_eq!(loaded.prev_hash(), blockchain.prev_hash());
+    assert_eq!(loaded.utxos.utxos_from_pkhash([3; 32]).len(), 1);
+    assert_eq!(loaded.utxos.utxos_from_pkhash(wallet.pkhash).len(), 2);
+
+    // corrupt the last byte of the final block
+    let mut data = std::fs::read(&path).unwrap();
+    let last = data.len() - 1;
+    data[last] ^= 0xFF;
+    std::fs::write(&path, data).unwrap();
+
+    assert!(matches!(
+        Blockchain::load_from_disk(&path, ChainParams::mainnet()),
+        Err(StorageError::InvalidBlock(3, _)) | Err(StorageError::Corrupt)
+    ));
+
+    // a length prefix claiming far more than is left, or than any block
+    // could be
+    let mut data = std::fs::read(&path).unwrap();
+    data.extend_from_slice(&100u32.to_be_bytes());
+    data.extend_from_slice(&[0; 10]);
+    std::fs::write(&path, &data).unwrap();
+    assert!(matches!(
+        Blockchain::load_from_disk(&path, ChainParams::mainnet()),
+        Err(StorageError::Corrupt)
+    ));
+    std::fs::write(&path, u32::MAX.to_be_bytes()).unwrap();
+    assert!(matches!(
+        Blockchain::load_from_disk(&path, ChainParams::mainnet()),
+        Err(StorageError::Corrupt)
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_inclusion_proof() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+
+    for _ in 0..3 {
+        let mut block = blockchain.next_block();
+        mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+
+    let mut block = blockchain.next_block();
+    let mut txids = Vec::new();
+    for OutPoint {
+        txid,
+        index: output_index,
+    } in blockchain.utxos.utxos_from_pkhash(wallet.pkhash)
+    {
+        let mut tx = Transaction::new(
+            vec![spend_input(&blockchain, txid, output_index)],
+            vec![TxOutput::new(100, [5; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        txids.push(tx.id);
+        block.add_tx(tx);
+    }
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    for txid in &txids {
+        let (header, proof) = blockchain.prove_inclusion(txid).unwrap();
+        assert_eq!(header.hash(), blockchain.prev_hash());
+        assert!(merkle::verify_proof(
+            &header,
+            &proof,
+            txid,
+            &blockchain.params().pow
+        ));
+        assert!(!merkle::verify_proof(
+            &header,
+            &proof,
+            &TxId([5; 32]),
+            &blockchain.params().pow
+        ));
+    }
+
+    let (mut header, proof) = blockchain.prove_inclusion(&txids[0]).unwrap();
+    header.merkle_root[0] ^= 1;
+    assert!(!merkle::verify_proof(
+        &header,
+        &proof,
+        &txids[0],
+        &blockchain.params().pow
+    ));
+    assert!(blockchain.prove_inclusion(&TxId([5; 32])).is_none());
+}
+
+#[test]
+fn test_spend_with_ed25519_wallet() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ed25519, blockchain.chain_id());
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(100, [4; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    let mut block2 = blockchain.next_block();
+    block2.add_tx(tx);
+    mine(&mut block2, wallet.pkhash, blockchain.get_block_reward());
+
+    assert_eq!(
+        blockchain.add_block(block2),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+    assert_eq!(blockchain.utxos.utxos_from_pkhash([4; 32]).len(), 1);
+}
+
+#[test]
+fn test_spend_aggregate_schnorr_key() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let signers: Vec<Wallet> = (0..3)
+        .map(|_| Wallet::with_scheme(SignatureScheme::Schnorr, blockchain.chain_id()))
+        .collect();
+    let pubkeys: Vec<Vec<u8>> = signers.iter().map(Wallet::public_key).collect();
+    let context = KeyAggContext::new(&pubkeys).unwrap();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, context.pkhash(), blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(context.pkhash())[0];
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(blockchain.get_block_reward() - 1000, [4; 32])],
+    );
+    let message = tx.aggregate_sighash(0, &context.aggregate_key(), &blockchain.chain_id());
+
+    let (secret_nonces, public_nonces): (Vec<_>, Vec<_>) =
+        signers.iter().map(|_| musig::generate_nonce()).unzip();
+    let session = Session::new(&context, &public_nonces, &message).unwrap();
+    let partials: Vec<_> = signers
+        .iter()
+        .zip(secret_nonces)
+        .map(|(signer, nonce)| signer.sign_musig(&session, &context, nonce).unwrap())
+        .collect();
+    tx.set_input_signature(0, session.aggregate(&partials).unwrap());
+
+    // one key and one signature, the size of a single signer's input
+    assert_eq!(tx.inputs[0].pubkey.len(), 32);
+    assert!(tx.inputs[0].witness.is_empty());
+    assert_eq!(blockchain.submit_transaction(tx.clone()), Ok(()));
+
+    let mut block2 = blockchain.next_block();
+    block2.add_tx(tx);
+    mine(&mut block2, [1; 32], blockchain.get_block_reward());
+    assert_eq!(
+        blockchain.add_block(block2),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+    assert_eq!(blockchain.utxos.utxos_from_pkhash([4; 32]).len(), 1);
+}
+
+#[test]
+fn test_wait_for_confirmations() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    let digest = block.digest;
+    blockchain.add_block(block).unwrap();
+
+    let mut stream = blockchain.subscribe_blocks(6);
+    assert_eq!(stream.confirmations(&digest), 1);
+
+    let miner = std::thread::spawn(move || {
+        for _ in 0..5 {
+            let mut block = blockchain.next_block();
+            mine(&mut block, [0; 32], blockchain.get_block_reward());
+            blockchain.add_block(block).unwrap();
+        }
+        blockchain
+    });
+    let confirmed = stream.wait_for_confirmations(&digest, 6).unwrap();
+    assert_eq!(confirmed.header.index, 1);
+    assert_eq!(stream.tip(), 6);
+
+    let blockchain = miner.join().unwrap();
+    assert_eq!(blockchain.height(), 6);
+}
+
+#[test]
+fn test_transaction_confirmations() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+    assert_eq!(blockchain.confirmations(&coinbase_id), Some(1));
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(blockchain.get_block_reward() - 1000, [4; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    assert_eq!(blockchain.confirmations(&tx.id), None);
+    blockchain.submit_transaction(tx.clone()).unwrap();
+    assert_eq!(blockchain.confirmations(&tx.id), Some(0));
+    assert!(!blockchain.is_final(&tx.id, 0));
+
+    for _ in 0..3 {
+        let mut block = blockchain.next_block();
+        block.transactions = blockchain.mempool.select_for_block(10);
+        mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+        blockchain.add_block(block).unwrap();
+    }
+    assert_eq!(blockchain.confirmations(&tx.id), Some(3));
+    assert_eq!(blockchain.confirmations(&coinbase_id), Some(4));
+    assert!(blockchain.is_final(&tx.id, 3));
+    assert!(!blockchain.is_final(&tx.id, 6));
+}
+
+#[test]
+fn test_coinbase_claims_fees() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let wallet = Wallet::new();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let OutPoint {
+        txid,
+        index: output_index,
+    } = blockchain.utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, txid, output_index)],
+        vec![TxOutput::new(blockchain.get_block_reward() - 500, [6; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    let mut block2 = blockchain.next_block();
+    block2.add_tx(tx.clone());
+    assert_eq!(blockchain.calculate_block_fees(&block2), Ok(Amount(500)));
+
+    // claiming more than reward + fees is rejected
+    let mut greedy = blockchain.next_block();
+    greedy.add_tx(tx);
+    mine(&mut greedy, [7; 32], blockchain.get_block_reward() + 501);
+    assert_eq!(
+        blockchain.add_block(greedy),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::InvalidCoinbase
+        ))
+    );
+
+    mine_with_fees(&mut block2, &blockchain, [7; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(block2),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+
+    let outpoint = blockchain.utxos.utxos_from_pkhash([7; 32])[0];
+    assert_eq!(
+        blockchain.utxos.get_utxo(&outpoint).unwrap().value,
+        blockchain.get_block_reward() + 500
+    );
+}
+
+#[test]
+fn test_block_with_wrong_target_rejected() {
+    let mut blockchain = Blockchain::new(ChainParams::mainnet());
+    let mut block = blockchain.next_block();
+    // an easier target than the network allows
+    block.header.bits = ChainParams::regtest().bits;
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTarget)
+    );
+}
+
+#[test]
+fn test_chain_with_other_pow() {
+    let params = ChainParams::mainnet().with_pow(PowAlgorithm::DoubleSha256);
+    let mut blockchain = Blockchain::new(params);
+
+    // Work for the digest's SHA-256 alone doesn't count
+    let mut block = blockchain.next_block();
+    mine(&mut block, [1; 32], blockchain.get_block_reward());
+    while consensus::meets_target(
+        &block.header.pow_hash(&PowAlgorithm::DoubleSha256),
+        &block.header.target(),
+    ) {
+        utils::solve(&mut block);
+    }
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidProofOfWork)
+    );
+
+    let mut block = blockchain.next_block();
+    mine_with_fees(&mut block, &blockchain, [1; 32]).unwrap();
+    let hash = PowAlgorithm::DoubleSha256.hash(&consensus::Header::from(&block.header).encode());
+    assert!(consensus::meets_target(&hash, &block.header.target()));
+    assert_eq!(block.digest, block.hash());
+    assert_eq!(
+        blockchain.add_block(block),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+}
+
+#[test]
+fn test_regtest_reward_halves() {
+    let params = ChainParams::regtest();
+    let mut blockchain = Blockchain::new(params.clone());
+    while blockchain.height() < params.halving_interval - 1 {
+        let mut block = blockchain.next_block();
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+    assert_eq!(blockchain.get_block_reward(), params.initial_reward / 2);
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], params.initial_reward);
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::InvalidCoinbase
+        ))
+    );
+}
+
+#[test]
+fn test_load_chain_for_other_network() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    let path = std::env::temp_dir().join(format!(
+        "chain_regtest_{}.bin",
+        hex::encode(blockchain.prev_hash())
+    ));
+    blockchain.save_to_disk(&path).unwrap();
+
+    assert_eq!(
+        Blockchain::load_from_disk(&path, ChainParams::regtest())
+            .unwrap()
+            .height(),
+        1
+    );
+    assert!(matches!(
+        Blockchain::load_from_disk(&path, ChainParams::testnet()),
+        Err(StorageError::GenesisMismatch)
+    ));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn test_orphans_connect_when_parent_arrives() {
+    let mut source = Blockchain::new(ChainParams::regtest());
+    let mut blocks = Vec::new();
+    for _ in 0..3 {
+        let mut block = source.next_block();
+        mine(&mut block, [0; 32], source.get_block_reward());
+        blocks.push(block.clone());
+        source.add_block(block).unwrap();
+    }
+
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    for block in blocks[1..].iter().rev() {
+        assert_eq!(
+            blockchain.process_block(block.clone()),
+            Ok((BlockAcceptance::OrphanStored, vec![]))
+        );
+    }
+    assert_eq!(blockchain.orphans.len(), 2);
+
+    assert_eq!(
+        blockchain.process_block(blocks[0].clone()),
+        Ok((
+            BlockAcceptance::ConnectedToTip,
+            vec![blocks[1].digest, blocks[2].digest]
+        ))
+    );
+    assert_eq!(blockchain.height(), 3);
+    assert!(blockchain.orphans.is_empty());
+}
+
+#[test]
+fn test_invalid_orphan_not_stored() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let mut block = Block::new(2, [1; 32], blockchain.params().bits, Vec::new());
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    // the stored digest no longer matches the header
+    block.digest = Hash256([0; 32]);
+
+    assert_eq!(
+        blockchain.process_block(block),
+        Err(BlockValidationError::HashDigestMismatch)
+    );
+    assert!(blockchain.orphans.is_empty());
+}
+
+// A branch from `parent`, each block paying to `pkhash`
+fn branch(blockchain: &Blockchain, parent: &Block, pkhash: [u8; 32], len: u64) -> Vec<Block> {
+    let mut blocks: Vec<Block> = Vec::new();
+    for _ in 0..len {
+        let prev = blocks.last().unwrap_or(parent);
+        let index = prev.header.index + 1;
+        let mut block = Block::new(index, prev.digest.0, blockchain.params().bits, Vec::new());
+        mine(&mut block, pkhash, blockchain.params().block_reward(index));
+        blocks.push(block);
+    }
+    blocks
+}
+
+#[test]
+fn test_reorg_to_branch_with_more_work() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let events = blockchain.subscribe();
+    let wallet = Wallet::for_chain(blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+
+    let mut fork = blockchain.next_block();
+    mine(&mut fork, wallet.pkhash, reward);
+    blockchain.add_block(fork.clone()).unwrap();
+
+    // the chain's second block spends the first's coinbase
+    let mut builder = TxBuilder::new(wallet.pkhash);
+    builder
+        .add_utxo(OutPoint::new(fork.transactions[0].id, 0), reward)
+        .pay(1_000, [4; 32])
+        .fee(1_000);
+    let tx = builder.build(&wallet).unwrap();
+    blockchain.submit_transaction(tx.clone()).unwrap();
+    let mut replaced = blockchain.next_block();
+    replaced.transactions = blockchain.mempool.select_for_block(100);
+    mine_with_fees(&mut replaced, &blockchain, [0; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(replaced.clone()),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+    assert!(blockchain.mempool.is_empty());
+
+    let other = branch(&blockchain, &fork, [1; 32], 2);
+    assert_eq!(
+        blockchain.add_block(other[0].clone()),
+        Ok(BlockAcceptance::AcceptedOnSideChain)
+    );
+    // a tie keeps the chain
+    assert_eq!(blockchain.prev_hash(), replaced.digest);
+    assert_eq!(
+        blockchain.add_block(other[1].clone()),
+        Ok(BlockAcceptance::CausedReorg { depth: 1 })
+    );
+    assert_eq!(blockchain.height(), 3);
+    assert_eq!(blockchain.prev_hash(), other[1].digest);
+    assert_eq!(
+        blockchain
+            .side_blocks()
+            .map(|block| block.digest)
+            .collect::<Vec<_>>(),
+        vec![replaced.digest]
+    );
+    assert!(blockchain.mempool.contains(&tx.id));
+    assert_eq!(blockchain.utxos.balance([4; 32]), 0);
+    assert_eq!(blockchain.supply(), blockchain.utxos.total_value());
+    assert!(events.try_iter().any(|event| event
+        == ChainEvent::BlockDisconnected {
+            digest: replaced.digest,
+            height: 2,
+        }));
+    assert_eq!(
+        blockchain.add_block(other[0].clone()),
+        Err(BlockValidationError::AlreadyKnown)
+    );
+
+    // and back again, confirming the transaction
+    let back = branch(&blockchain, &replaced, [2; 32], 2);
+    assert_eq!(
+        blockchain.add_block(back[0].clone()),
+        Ok(BlockAcceptance::AcceptedOnSideChain)
+    );
+    assert_eq!(
+        blockchain.add_block(back[1].clone()),
+        Ok(BlockAcceptance::CausedReorg { depth: 2 })
+    );
+    assert_eq!(blockchain.height(), 4);
+    assert_eq!(
+        blockchain.get_block_by_height(2).unwrap().digest,
+        replaced.digest
+    );
+    assert!(blockchain.mempool.is_empty());
+    assert_eq!(blockchain.utxos.balance([4; 32]), 1_000);
+    assert_eq!(blockchain.supply(), blockchain.utxos.total_value());
+}
+
+#[test]
+fn test_reorg_after_restart() {
+    let dir = std::env::temp_dir().join(format!("reorg_after_restart_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (chain_path, utxo_path) = (dir.join("chain.dat"), dir.join("utxos.dat"));
+    let _ = std::fs::remove_file(&utxo_path);
+
+    let params = ChainParams::regtest();
+    let utxos = UTXOSet::open(&utxo_path).unwrap();
+    let mut blockchain = Blockchain::with_utxos(params.clone(), utxos);
+    let wallet = Wallet::for_chain(blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let mut fork = blockchain.next_block();
+    mine(&mut fork, wallet.pkhash, reward);
+    blockchain.add_block(fork.clone()).unwrap();
+
+    // the second block spends the first's coinbase
+    let mut builder = TxBuilder::new(wallet.pkhash);
+    builder
+        .add_utxo(OutPoint::new(fork.transactions[0].id, 0), reward)
+        .pay(1_000, [4; 32])
+        .fee(1_000);
+    let mut block = blockchain.next_block();
+    block.add_tx(builder.build(&wallet).unwrap());
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block.clone()).unwrap();
+    let spent = |blockchain: &Blockchain| {
+        let undo = blockchain.undo_data(&block.digest).unwrap();
+        undo.spent
+            .iter()
+            .map(|(outpoint, output)| (*outpoint, output.value))
+            .collect::<Vec<_>>()
+    };
+    let undo = spent(&blockchain);
+    assert_eq!(undo.len(), 1);
+    blockchain.save_to_disk(&chain_path).unwrap();
+    drop(blockchain);
+
+    // the set is at the tip, so the undo data is rebuilt from the blocks
+    let utxos = UTXOSet::open(&utxo_path).unwrap();
+    let mut loaded = Blockchain::load_from_disk_with_utxos(&chain_path, params, utxos).unwrap();
+    assert_eq!(spent(&loaded), undo);
+    assert_eq!(loaded.utxos.balance([4; 32]), 1_000);
+
+    let other = branch(&loaded, &fork, [1; 32], 2);
+    assert_eq!(
+        loaded.add_block(other[0].clone()),
+        Ok(BlockAcceptance::AcceptedOnSideChain)
+    );
+    assert_eq!(
+        loaded.add_block(other[1].clone()),
+        Ok(BlockAcceptance::CausedReorg { depth: 1 })
+    );
+    assert_eq!(loaded.prev_hash(), other[1].digest);
+    assert_eq!(loaded.utxos.balance([4; 32]), 0);
+    assert_eq!(loaded.utxos.balance(wallet.pkhash), reward);
+    assert_eq!(loaded.supply(), loaded.utxos.total_value());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_reorg_to_invalid_branch_rolled_back() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let genesis = blockchain.get_block_by_height(0).unwrap().clone();
+    for block in branch(&blockchain, &genesis, [0; 32], 2) {
+        blockchain.add_block(block).unwrap();
+    }
+    let tip = blockchain.prev_hash();
+    let supply = blockchain.supply();
+
+    let mut other = branch(&blockchain, &genesis, [1; 32], 1);
+    let mut overpaid = Block::new(2, other[0].digest.0, blockchain.params().bits, Vec::new());
+    mine(
+        &mut overpaid,
+        [1; 32],
+        blockchain.params().block_reward(2) + 1,
+    );
+    other.push(overpaid);
+    other.extend(branch(&blockchain, &other[1], [1; 32], 1));
+
+    // its transactions aren't checked until the branch overtakes
+    for block in &other[..2] {
+        assert_eq!(
+            blockchain.add_block(block.clone()),
+            Ok(BlockAcceptance::AcceptedOnSideChain)
+        );
+    }
+    let overpaid = BlockValidationError::InvalidTransactions(TransactionError::InvalidCoinbase);
+    assert_eq!(
+        blockchain.add_block(other[2].clone()),
+        Err(overpaid.clone())
+    );
+
+    assert_eq!(blockchain.height(), 2);
+    assert_eq!(blockchain.prev_hash(), tip);
+    assert_eq!(blockchain.supply(), supply);
+    assert_eq!(blockchain.utxos.balance([1; 32]), 0);
+    assert_eq!(
+        blockchain
+            .side_blocks()
+            .map(|block| block.digest)
+            .collect::<Vec<_>>(),
+        vec![other[0].digest]
+    );
+    assert_eq!(
+        blockchain.invalid_block_reason(&other[1].digest),
+        Some(&overpaid)
+    );
+    assert_eq!(
+        blockchain.add_block(other[2].clone()),
+        Err(BlockValidationError::InvalidAncestor)
+    );
+}
+
+#[test]
+fn test_invalid_blocks_remembered() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let reward = blockchain.get_block_reward();
+    let overpaid = BlockValidationError::InvalidTransactions(TransactionError::InvalidCoinbase);
+
+    let mut invalid = blockchain.next_block();
+    mine(&mut invalid, [0; 32], reward + 1);
+    let mut child = Block::new(2, invalid.digest.0, blockchain.params().bits, Vec::new());
+    mine(&mut child, [0; 32], reward);
+    let mut grandchild = Block::new(3, child.digest.0, blockchain.params().bits, Vec::new());
+    mine(&mut grandchild, [0; 32], reward);
+
+    assert_eq!(
+        blockchain.process_block(child.clone()),
+        Ok((BlockAcceptance::OrphanStored, vec![]))
+    );
+    assert_eq!(
+        blockchain.process_block(invalid.clone()),
+        Err(overpaid.clone())
+    );
+    assert_eq!(
+        blockchain.invalid_block_reason(&invalid.digest),
+        Some(&overpaid)
+    );
+    // The orphan building on it was dropped along with it
+    assert!(blockchain.orphans.is_empty());
+    assert_eq!(
+        blockchain.invalid_block_reason(&child.digest),
+        Some(&BlockValidationError::InvalidAncestor)
+    );
+
+    assert_eq!(blockchain.add_block(invalid), Err(overpaid));
+    assert_eq!(
+        blockchain.process_block(grandchild.clone()),
+        Err(BlockValidationError::InvalidAncestor)
+    );
+    assert!(
+        blockchain
+            .invalid_block_reason(&grandchild.digest)
+            .is_some()
+    );
+
+    // A valid block whose transactions were tampered with stays acceptable
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], reward);
+    let mut tampered = block.clone();
+    tampered.transactions[0].outputs[0].value = Amount(1);
+    tampered.transactions[0].update_id();
+    assert_eq!(
+        blockchain.add_block(tampered),
+        Err(BlockValidationError::InvalidMerkleRoot)
+    );
+    assert_eq!(blockchain.invalid_block_reason(&block.digest), None);
+    assert_eq!(
+        blockchain.add_block(block),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+}
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/regtest.fixture"
+);
+
+// The first `blocks` blocks of the fixture chain, replayed rather than mined
+fn regtest_chain(blocks: u64) -> Blockchain {
+    let fixture = Fixture::load(FIXTURE).unwrap();
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    for block in fixture.blockchain.iter().skip(1).take(blocks as usize) {
+        blockchain.add_block(block.clone()).unwrap();
+    }
+    blockchain
+}
+
+// Set UPDATE_FIXTURES to write the file again after changing the generator
+#[test]
+fn test_fixture_file_matches_generator() {
+    let fixture = Fixture::generate(1, 4, 40);
+    let mut expected = Vec::new();
+    fixture.write_to(&mut expected).unwrap();
+    if std::env::var_os("UPDATE_FIXTURES").is_some() {
+        std::fs::write(FIXTURE, &expected).unwrap();
+    }
+    assert_eq!(std::fs::read(FIXTURE).unwrap(), expected);
+
+    let loaded = Fixture::load(FIXTURE).unwrap();
+    assert_eq!(loaded.blockchain.height(), 40);
+    assert_eq!(loaded.wallets.len(), 4);
+    assert_eq!(
+        loaded.blockchain.prev_hash(),
+        fixture.blockchain.prev_hash()
+    );
+}
+
+#[test]
+fn test_block_locator() {
+    let blockchain = regtest_chain(40);
+    let locator = blockchain.get_block_locator();
+
+    let heights: Vec<u64> = locator
+        .iter()
+        .map(|hash| blockchain.get_block_by_hash(hash).unwrap().header.index)
+        .collect();
+    assert_eq!(
+        heights,
+        vec![40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 29, 25, 17, 1, 0]
+    );
+    assert_eq!(
+        regtest_chain(0).get_block_locator(),
+        vec![blockchain.chain_id()]
+    );
+}
+
+#[test]
+fn test_headers_after_locator() {
+    let blockchain = regtest_chain(20);
+    let mut behind = Blockchain::new(ChainParams::regtest());
+    for height in 1..=12 {
+        let block = blockchain.get_block_by_height(height).unwrap().clone();
+        behind.add_block(block).unwrap();
+    }
+
+    let headers = blockchain.get_headers_after(&behind.get_block_locator(), 5);
+    let heights: Vec<u64> = headers.iter().map(|header| header.index).collect();
+    assert_eq!(heights, vec![13, 14, 15, 16, 17]);
+    assert_eq!(headers[0].prev_hash, behind.prev_hash());
+    for pair in headers.windows(2) {
+        assert_eq!(pair[1].prev_hash, pair[0].hash());
+    }
+
+    // nothing in common: start after genesis
+    assert_eq!(
+        blockchain.get_headers_after(&[Hash256([9; 32])], 100).len(),
+        20
+    );
+    assert!(
+        blockchain
+            .get_headers_after(&blockchain.get_block_locator(), 100)
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_iterate_and_query_blocks() {
+    let blockchain = regtest_chain(5);
+
+    let heights: Vec<u64> = blockchain.iter().map(|block| block.header.index).collect();
+    assert_eq!(heights, vec![0, 1, 2, 3, 4, 5]);
+    for block in &blockchain {
+        assert_eq!(
+            blockchain.get_block_by_hash(&block.digest).unwrap().digest,
+            block.digest
+        );
+    }
+    assert!(blockchain.get_block_by_hash(&Hash256([1; 32])).is_none());
+    assert!(blockchain.get_block_by_height(6).is_none());
+
+    let range = blockchain.blocks_in_range(2..4);
+    assert_eq!(range.len(), 2);
+    assert_eq!(range[0].header.index, 2);
+    assert_eq!(range[1].header.index, 3);
+    assert_eq!(blockchain.blocks_in_range(4..100).len(), 2);
+    assert!(blockchain.blocks_in_range(10..20).is_empty());
+}
+
+#[test]
+fn test_transaction_index() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(100, [4; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    let (tx, height) = blockchain.get_transaction(&txid).unwrap();
+    assert_eq!((tx.id, height), (txid, 2));
+    let (coinbase, height) = blockchain.get_transaction(&coinbase_id).unwrap();
+    assert!(coinbase.is_coinbase());
+    assert_eq!(height, 1);
+    assert!(blockchain.get_transaction(&TxId([5; 32])).is_none());
+}
+
+#[test]
+fn test_address_history() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+    assert!(blockchain.address_index().is_none());
+    // blocks already connected are indexed too
+    blockchain.enable_address_index();
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![
+            TxOutput::new(100, [4; 32]),
+            TxOutput::new(reward - 150, wallet.pkhash),
+        ],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    assert_eq!(
+        blockchain.address_history(wallet.pkhash),
+        vec![
+            HistoryEntry {
+                txid: coinbase_id,
+                height: 1,
+                received: reward,
+                sent: 0,
+            },
+            HistoryEntry {
+                txid,
+                height: 2,
+                received: reward - 150,
+                sent: reward,
+            },
+        ]
+    );
+    assert_eq!(blockchain.utxos.balance(wallet.pkhash), reward - 150);
+
+    let history = blockchain.address_history([4; 32]);
+    assert_eq!(history.len(), 1);
+    assert_eq!((history[0].received, history[0].sent), (100, 0));
+
+    let index = blockchain.address_index().unwrap();
+    assert_eq!(index.received_by(&wallet.pkhash).len(), 2);
+    assert_eq!(index.sent_by(&wallet.pkhash)[0].txid, txid);
+    let unspent = index.unspent_by(&wallet.pkhash);
+    assert_eq!(unspent.len(), 1);
+    assert_eq!(unspent[0].outpoint, OutPoint::new(txid, 1));
+    assert_eq!(unspent[0].value, reward - 150);
+}
+
+#[test]
+fn test_mempool_eviction_and_expiry() {
+    // Transactions are timestamped with the system clock
+    let clock = Arc::new(MockClock::new(utils::unix_timestamp()));
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    blockchain.set_clock(clock.clone());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbases: Vec<TxId> = (0..3)
+        .map(|_| mine_to(&mut blockchain, wallet.pkhash))
+        .collect();
+    let reward = blockchain.get_block_reward();
+    let spend = |coinbase_id, fee| {
+        let mut tx = Transaction::new(
+            vec![spend_input(&blockchain, coinbase_id, 0)],
+            vec![TxOutput::new(reward - fee, [4; 32])],
+        );
+        wallet.sign_transaction(&mut tx).unwrap();
+        tx
+    };
+    let medium = spend(coinbases[0], 5_000);
+    let low = spend(coinbases[1], 1_000);
+    let high = spend(coinbases[2], 10_000);
+    let events = blockchain.subscribe();
+
+    blockchain
+        .mempool
+        .set_max_size(medium.serialized_size() + 10);
+    blockchain.submit_transaction(medium.clone()).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(low),
+        Err(TransactionError::MempoolFull)
+    );
+    blockchain.submit_transaction(high.clone()).unwrap();
+    assert!(!blockchain.mempool.contains(&medium.id));
+
+    blockchain.mempool.set_max_age(600);
+    clock.advance(601);
+    let digest = {
+        let mut block = blockchain.next_block();
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        let digest = block.digest;
+        blockchain.add_block(block).unwrap();
+        digest
+    };
+    assert!(blockchain.mempool.is_empty());
+
+    let events: Vec<ChainEvent> = events.try_iter().collect();
+    assert_eq!(
+        events[..3],
+        [
+            ChainEvent::TxAccepted(medium.id),
+            ChainEvent::TxEvicted(medium.id),
+            ChainEvent::TxAccepted(high.id),
+        ]
+    );
+    assert_eq!(events[3], ChainEvent::BlockConnected { digest, height: 4 });
+    assert_eq!(events.last(), Some(&ChainEvent::TxExpired(high.id)));
+}
+
+#[test]
+fn test_chain_events() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+    let events = blockchain.subscribe();
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(100, [4; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    let digest = block.digest;
+    let new_coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    assert_eq!(
+        events.try_iter().collect::<Vec<ChainEvent>>(),
+        vec![
+            ChainEvent::BlockConnected { digest, height: 2 },
+            ChainEvent::TxConfirmed(new_coinbase_id),
+            ChainEvent::TxConfirmed(txid),
+            ChainEvent::UTXOSpent(OutPoint::new(coinbase_id, 0)),
+        ]
+    );
+}
+
+#[test]
+fn test_block_filters() {
+    let mut blockchain = regtest_chain(2);
+    mine_to(&mut blockchain, [5; 32]);
+
+    // light clients find the block paying them without its transactions
+    let relevant: Vec<u64> = (0..=blockchain.height())
+        .filter(|&height| {
+            blockchain
+                .get_block_filter(height)
+                .unwrap()
+                .contains_pkhash(&[5; 32])
+        })
+        .collect();
+    assert!(relevant.contains(&3));
+    assert!(blockchain.get_block_filter(4).is_none());
+}
+
+#[test]
+fn test_timelocked_output() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    let mut lock_tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new_timelocked(reward - 1000, wallet.pkhash, 4)],
+    );
+    wallet.sign_transaction(&mut lock_tx).unwrap();
+    let lock_txid = lock_tx.id;
+    blockchain.submit_transaction(lock_tx).unwrap();
+
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(10);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    let mut spend = Transaction::new(
+        vec![spend_input(&blockchain, lock_txid, 0)],
+        vec![TxOutput::new(reward - 2000, [4; 32])],
+    );
+    wallet.sign_transaction(&mut spend).unwrap();
+
+    // the next block is at height 3
+    assert_eq!(
+        blockchain.submit_transaction(spend.clone()),
+        Err(TransactionError::Timelocked)
+    );
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+    assert_eq!(blockchain.submit_transaction(spend), Ok(()));
+}
+
+#[test]
+fn test_htlc_claim_and_refund() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let sender = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let recipient = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let preimage = [42; 32];
+    let payment_hash = script::payment_hash(&preimage);
+    let htlc = sender.new_htlc(recipient.pkhash, payment_hash, 100);
+    let expiring = sender.new_htlc(recipient.pkhash, payment_hash, 4);
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, sender.pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    let mut lock_tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![
+            TxOutput::new_htlc(reward / 2 - 1000, &htlc),
+            TxOutput::new_htlc(reward / 2, &expiring),
+        ],
+    );
+    sender.sign_transaction(&mut lock_tx).unwrap();
+    let lock_txid = lock_tx.id;
+    blockchain.submit_transaction(lock_tx).unwrap();
+
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(10);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    let mut claim = Transaction::new(
+        vec![spend_input(&blockchain, lock_txid, 0)],
+        vec![TxOutput::new(reward / 2 - 2000, recipient.pkhash)],
+    );
+    assert!(
+        !sender
+            .claim_htlc(&mut claim.clone(), 0, &htlc, preimage)
+            .unwrap()
+    );
+    // a wrong preimage
+    assert!(recipient.claim_htlc(&mut claim, 0, &htlc, [0; 32]).unwrap());
+    assert_eq!(
+        blockchain.submit_transaction(claim.clone()),
+        Err(TransactionError::UnauthorizedSpend)
+    );
+    assert!(
+        recipient
+            .claim_htlc(&mut claim, 0, &htlc, preimage)
+            .unwrap()
+    );
+    assert_eq!(blockchain.submit_transaction(claim), Ok(()));
+
+    let mut refund = Transaction::new(
+        vec![spend_input(&blockchain, lock_txid, 1)],
+        vec![TxOutput::new(reward / 2 - 1000, sender.pkhash)],
+    );
+    assert!(
+        !recipient
+            .refund_htlc(&mut refund.clone(), 0, &expiring)
+            .unwrap()
+    );
+    assert!(sender.refund_htlc(&mut refund, 0, &expiring).unwrap());
+    // the next block is at height 3
+    assert_eq!(
+        blockchain.submit_transaction(refund.clone()),
+        Err(TransactionError::Timelocked)
+    );
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+    assert_eq!(blockchain.submit_transaction(refund), Ok(()));
+}
+
+#[test]
+fn test_anyone_can_spend_output() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new_anyone_can_spend(reward)],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine(&mut block, [0; 32], blockchain.get_block_reward());
+    blockchain.add_block(block).unwrap();
+
+    // no signature needed
+    let mut claim = Transaction::new(
+        vec![spend_input(&blockchain, txid, 0)],
+        vec![TxOutput::new(reward - 1000, [4; 32])],
+    );
+    claim.update_id();
+    // it has the shape of a coinbase, but only a block's first transaction
+    // is one
+    assert!(claim.is_coinbase());
+    assert_eq!(blockchain.submit_transaction(claim.clone()), Ok(()));
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(100);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+    assert!(blockchain.utxos.get_utxo(&OutPoint::new(txid, 0)).is_none());
+    assert_eq!(blockchain.supply(), blockchain.utxos.total_value());
+
+    let mut again = Transaction::new(
+        vec![TxInput::new_unsigned(txid, 0).with_value(reward)],
+        vec![TxOutput::new(reward - 2000, [4; 32])],
+    );
+    again.update_id();
+    assert!(blockchain.submit_transaction(again.clone()).is_err());
+    let mut block = blockchain.next_block();
+    block.add_tx(again);
+    mine(&mut block, [0; 32], blockchain.get_block_reward() + 2000);
+    assert!(blockchain.add_block(block).is_err());
+
+    // while a regular output still requires one
+    let mut block = blockchain.next_block();
+    mine(&mut block, wallet.pkhash, blockchain.get_block_reward());
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+    let mut unsigned = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [4; 32])],
+    );
+    unsigned.update_id();
+    assert_eq!(
+        blockchain.submit_transaction(unsigned),
+        Err(TransactionError::UnauthorizedSpend)
+    );
+}
+
+#[test]
+fn test_spend_multisig_output() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let chain_id = blockchain.chain_id();
+    let signers: Vec<Wallet> = (0..3)
+        .map(|_| Wallet::with_scheme(SignatureScheme::Ecdsa, chain_id))
+        .collect();
+    let pubkeys: Vec<Vec<u8>> = signers.iter().map(Wallet::public_key).collect();
+    let reward = blockchain.get_block_reward();
+
+    let mut block = blockchain.next_block();
+    mine(&mut block, signers[0].pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+
+    let mut fund = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new_multisig(reward - 1000, 2, &pubkeys)],
+    );
+    signers[0].sign_transaction(&mut fund).unwrap();
+    let fund_id = fund.id;
+    blockchain.submit_transaction(fund).unwrap();
+
+    let mut block = blockchain.next_block();
+    block.transactions = blockchain.mempool.select_for_block(10);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    blockchain.add_block(block).unwrap();
+
+    let mut spend = Transaction::new(
+        vec![spend_input(&blockchain, fund_id, 0)],
+        vec![TxOutput::new(reward - 2000, [4; 32])],
+    );
+    assert!(
+        signers[1]
+            .sign_multisig_input(&mut spend, 0, &pubkeys)
+            .unwrap()
+    );
+    assert_eq!(
+        blockchain.submit_transaction(spend.clone()),
+        Err(TransactionError::UnauthorizedSpend)
+    );
+
+    assert!(
+        signers[2]
+            .sign_multisig_input(&mut spend, 0, &pubkeys)
+            .unwrap()
+    );
+    assert_eq!(blockchain.submit_transaction(spend), Ok(()));
+}
+
+// Mines a block paying to the wallet and returns the coinbase txid
+fn mine_to(blockchain: &mut Blockchain, pkhash: [u8; 32]) -> TxId {
+    let mut block = blockchain.next_block();
+    mine(&mut block, pkhash, blockchain.get_block_reward());
+    let coinbase_id = block.transactions[0].id;
+    blockchain.add_block(block).unwrap();
+    coinbase_id
+}
+
+#[test]
+fn test_fee_estimation() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let reward = blockchain.get_block_reward();
+    let coinbase_ids = [
+        mine_to(&mut blockchain, wallet.pkhash),
+        mine_to(&mut blockchain, wallet.pkhash),
+    ];
+    assert_eq!(blockchain.fee_estimator.estimate(1), None);
+    assert_eq!(
+        blockchain.estimate_fee_rate(1),
+        blockchain.mempool.min_relay_fee_rate()
+    );
+
+    for (coinbase_id, fee_rate) in coinbase_ids.into_iter().zip([5000, 8000]) {
+        let mut builder = TxBuilder::new(wallet.pkhash);
+        builder
+            .add_utxo(OutPoint::new(coinbase_id, 0), reward)
+            .pay(1000, [4; 32])
+            .fee_rate(fee_rate);
+        blockchain
+            .submit_transaction(builder.build(&wallet).unwrap())
+            .unwrap();
+    }
+    let mut block = blockchain.create_block_template([0; 32]).unwrap();
+    solve(&mut block);
+    blockchain.add_block(block).unwrap();
+
+    // both confirmed in the next block, so the cheaper rate was enough
+    assert_eq!(blockchain.fee_estimator.tracked_blocks(), 3);
+    let estimate = blockchain.estimate_fee_rate(1);
+    assert!((5000..8000).contains(&estimate));
+
+    let mut builder = TxBuilder::new(wallet.pkhash);
+    builder
+        .pay(1000, [5; 32])
+        .estimate_fee(&blockchain.fee_estimator, 1);
+    for (_, outpoint) in wallet.find_utxos(&blockchain.utxos, 1) {
+        let value = blockchain.utxos.get_utxo(&outpoint).unwrap().value;
+        builder.add_utxo(outpoint, value);
+    }
+    let tx = builder.build(&wallet).unwrap();
+    let fee = blockchain.utxos.validate_transaction(&tx, 4).unwrap();
+    assert!(tx.fee_rate(fee) >= estimate);
+}
+
+#[test]
+fn test_absolute_locktime() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [4; 32])],
+    );
+    tx.locktime = 3;
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    // can't go in block 2
+    assert_eq!(
+        blockchain.submit_transaction(tx.clone()),
+        Err(TransactionError::LocktimeNotReached)
+    );
+    let mut block = blockchain.next_block();
+    block.add_tx(tx.clone());
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::LocktimeNotReached
+        ))
+    );
+
+    mine_to(&mut blockchain, [0; 32]);
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+}
+
+#[test]
+fn test_timestamp_locktime() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(1000, [4; 32])],
+    );
+    tx.locktime = blockchain.median_time_past() + 1;
+    wallet.sign_transaction(&mut tx).unwrap();
+    assert_eq!(
+        blockchain.submit_transaction(tx.clone()),
+        Err(TransactionError::LocktimeNotReached)
+    );
+
+    tx.locktime = blockchain.median_time_past();
+    wallet.sign_transaction(&mut tx).unwrap();
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+}
+
+#[test]
+fn test_relative_locktime() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, blockchain.chain_id());
+    // confirmed at height 1
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+
+    let mut input = spend_input(&blockchain, coinbase_id, 0);
+    input.relative_locktime = 3;
+    let mut tx = Transaction::new(vec![input], vec![TxOutput::new(1000, [4; 32])]);
+    wallet.sign_transaction(&mut tx).unwrap();
+
+    mine_to(&mut blockchain, [0; 32]);
+    assert_eq!(
+        blockchain.submit_transaction(tx.clone()),
+        Err(TransactionError::RelativeLocktimeNotReached)
+    );
+
+    mine_to(&mut blockchain, [0; 32]);
+    assert_eq!(blockchain.submit_transaction(tx), Ok(()));
+}
+
+#[test]
+fn test_checkpoints() {
+    let mut source = Blockchain::new(ChainParams::regtest());
+    let wallet = Wallet::with_scheme(SignatureScheme::Ecdsa, source.chain_id());
+    let coinbase_id = mine_to(&mut source, wallet.pkhash);
+
+    // a block whose signature doesn't verify
+    let mut tx = Transaction::new(
+        vec![spend_input(&source, coinbase_id, 0)],
+        vec![TxOutput::new(100, [4; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    tx.inputs[0].signature[0] ^= 1;
+    tx.update_id();
+    let mut bad_signature = source.next_block();
+    bad_signature.add_tx(tx);
+    mine_with_fees(&mut bad_signature, &source, [0; 32]).unwrap();
+    assert!(source.add_block(bad_signature.clone()).is_err());
+
+    let mut params = ChainParams::regtest();
+    params.checkpoints = vec![(2, bad_signature.digest)];
+    let block = source.get_block_by_height(1).unwrap().clone();
+    let chain = [(1, block.digest), (2, bad_signature.digest)];
+
+    // below the checkpoint but not known to lead to it
+    let mut unchecked = Blockchain::new(params.clone());
+    unchecked.add_block(block.clone()).unwrap();
+    unchecked.note_checkpointed_chain(chain.into_iter().take(1));
+    assert!(matches!(
+        unchecked.add_block(bad_signature.clone()),
+        Err(BlockValidationError::InvalidTransactions(_))
+    ));
+
+    let mut blockchain = Blockchain::new(params);
+    blockchain.note_checkpointed_chain(chain);
+    blockchain.add_block(block).unwrap();
+
+    // a side block off the checkpoint's chain is checked in full
+    let mut off_branch = Blockchain::new(ChainParams::regtest()).next_block();
+    off_branch.add_tx(bad_signature.transactions[1].clone());
+    mine(&mut off_branch, [1; 32], blockchain.get_block_reward());
+    assert!(matches!(
+        blockchain.add_block(off_branch),
+        Err(BlockValidationError::InvalidTransactions(_))
+    ));
+
+    let mut other = blockchain.next_block();
+    mine(&mut other, [0; 32], blockchain.get_block_reward());
+    assert_eq!(
+        blockchain.add_block(other),
+        Err(BlockValidationError::CheckpointMismatch)
+    );
+    assert_eq!(
+        blockchain.add_block(bad_signature),
+        Ok(BlockAcceptance::ConnectedToTip)
+    );
+
+    // past the checkpoint signatures are checked again
+    let coinbase_id = mine_to(&mut blockchain, wallet.pkhash);
+    let mut tx = Transaction::new(
+        vec![spend_input(&blockchain, coinbase_id, 0)],
+        vec![TxOutput::new(100, [5; 32])],
+    );
+    tx.inputs[0].pubkey = wallet.public_key();
+    tx.update_id();
+    let mut block = blockchain.next_block();
+    block.add_tx(tx);
+    mine_with_fees(&mut block, &blockchain, [0; 32]).unwrap();
+    assert_eq!(
+        blockchain.add_block(block),
+        Err(BlockValidationError::InvalidTransactions(
+            TransactionError::InvalidSignature
+        ))
+    );
+}
+
+#[test]
+fn test_chain_errors() {
+    fn add(blockchain: &mut Blockchain, block: Block) -> Result<(), ChainError> {
+        let height = block.header.index;
+        blockchain
+            .add_block(block)
+            .map_err(|e| e.at_height(height))?;
+        Ok(())
+    }
+
+    fn submit(blockchain: &mut Blockchain, tx: Transaction) -> Result<(), ChainError> {
+        blockchain.submit_transaction(tx)?;
+        Ok(())
+    }
+
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let mut block = blockchain.next_block();
+    mine(&mut block, [0; 32], blockchain.get_block_reward() + 1);
+    let err = add(&mut blockchain, block).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "invalid block 1: invalid transaction: invalid coinbase"
+    );
+    assert!(std::error::Error::source(&err).is_some());
+
+    let mut tx = Transaction::new(vec![], vec![TxOutput::new(1, [1; 32])]);
+    tx.update_id();
+    let err = submit(&mut blockchain, tx).unwrap_err();
+    assert!(matches!(
+        err,
+        ChainError::InvalidTransaction {
+            txid: None,
+            error: TransactionError::EmptyInputs
+        }
+    ));
+    assert_eq!(err.to_string(), "invalid transaction: no inputs");
+}
+
+#[test]
+fn test_chain_work() {
+    let mut blockchain = Blockchain::new(ChainParams::regtest());
+    let genesis_work = blockchain.total_work().clone();
+    assert_eq!(
+        genesis_work,
+        blockchain.iter().next().unwrap().header.work()
+    );
+
+    for _ in 0..3 {
+        let mut block = blockchain.next_block();
+        mine(&mut block, [0; 32], blockchain.get_block_reward());
+        blockchain.add_block(block).unwrap();
+    }
+    assert_eq!(blockchain.total_work(), &(genesis_work.clone() * 4u8));
+    assert_eq!(
+        blockchain.chain_work_at(1),
+        Some(&(genesis_work.clone() * 2u8))
+    );
+    assert_eq!(blockchain.chain_work_at(4), None);
+
+    let work = blockchain.total_work().clone();
+    assert!(!blockchain.is_better_tip(&work));
+    assert!(blockchain.is_better_tip(&(work + 1u8)));
+}
@@ -0,0 +1,265 @@
+use rust_blockchain::{blockchain, network, params, runtime, transaction, utils, wallet};
+
+use blockchain::Blockchain;
+use network::{Message, Node, SyncState, write_message};
+use params::ChainParams;
+use runtime::{NodeRuntime, RuntimeConfig};
+use std::io::Read;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+use transaction::{Transaction, TxInput, TxOutput};
+use utils::mine;
+use wallet::Wallet;
+
+fn wait_until(condition: impl Fn() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+fn mine_block(node: &Node, pkhash: [u8; 32]) {
+    let mut block = node.blockchain().next_block();
+    let reward = node.blockchain().get_block_reward();
+    mine(&mut block, pkhash, reward);
+    node.submit_block(block).unwrap();
+}
+
+#[test]
+fn test_new_node_syncs_on_connect() {
+    let wallet = Wallet::new();
+    let node_a = Node::new(Blockchain::new(ChainParams::mainnet()));
+    for _ in 0..3 {
+        mine_block(&node_a, wallet.pkhash);
+    }
+
+    let addr = node_a.listen("127.0.0.1:0").unwrap();
+    let node_b = Node::new(Blockchain::new(ChainParams::mainnet()));
+    node_b.connect(addr).unwrap();
+
+    assert!(wait_until(|| node_b.blockchain().height() == 3));
+    assert_eq!(
+        node_b.blockchain().prev_hash(),
+        node_a.blockchain().prev_hash()
+    );
+}
+
+#[test]
+fn test_wait_for_block() {
+    let wallet = Wallet::new();
+    let node_a = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let node_b = Node::new(Blockchain::new(ChainParams::mainnet()));
+    mine_block(&node_b, wallet.pkhash);
+    let addr = node_a.listen("127.0.0.1:0").unwrap();
+    node_b.connect(addr).unwrap();
+    assert!(wait_until(|| node_a.blockchain().height() == 1));
+
+    assert!(node_b.wait_for_block(1, Duration::ZERO).is_some());
+    assert!(
+        node_b
+            .wait_for_block(2, Duration::from_millis(50))
+            .is_none()
+    );
+    thread::scope(|scope| {
+        let waiter = scope.spawn(|| node_b.wait_for_block(3, Duration::from_secs(10)));
+        mine_block(&node_a, wallet.pkhash);
+        mine_block(&node_a, wallet.pkhash);
+        let block = waiter.join().unwrap().unwrap();
+        assert_eq!(block.digest, node_a.blockchain().prev_hash());
+    });
+}
+
+#[test]
+fn test_blocks_and_transactions_propagate() {
+    let wallet = Wallet::new();
+    let node_a = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let node_b = Node::new(Blockchain::new(ChainParams::mainnet()));
+
+    let addr = node_a.listen("127.0.0.1:0").unwrap();
+    node_b.connect(addr).unwrap();
+    assert!(wait_until(
+        || node_a.peer_count() == 1 && node_b.peer_count() == 1
+    ));
+
+    mine_block(&node_a, wallet.pkhash);
+    assert!(wait_until(|| node_b.blockchain().height() == 1));
+    let digest = node_b.blockchain().prev_hash();
+    assert_eq!(node_a.block_meta().get(&digest).unwrap().source, None);
+    assert!(wait_until(|| node_b.block_meta().get(&digest).is_some()));
+    assert_eq!(
+        node_b.block_meta().get(&digest).unwrap().source,
+        Some(addr.to_string())
+    );
+
+    let outpoint = node_b.blockchain().utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let value = node_b.blockchain().utxos.get_utxo(&outpoint).unwrap().value;
+    let mut tx = Transaction::new(
+        vec![TxInput::new_unsigned(outpoint.txid, outpoint.index).with_value(value)],
+        vec![TxOutput::new(1000, [0; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = tx.id;
+
+    node_b.submit_transaction(tx).unwrap();
+    assert!(wait_until(|| node_a.blockchain().mempool.contains(&txid)));
+}
+
+#[test]
+fn test_compact_block_fetches_missing_transactions() {
+    let wallet = Wallet::new();
+    let node_a = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let node_b = Node::new(Blockchain::new(ChainParams::mainnet()));
+
+    let addr = node_a.listen("127.0.0.1:0").unwrap();
+    node_b.connect(addr).unwrap();
+    assert!(wait_until(
+        || node_a.peer_count() == 1 && node_b.peer_count() == 1
+    ));
+
+    mine_block(&node_a, wallet.pkhash);
+    mine_block(&node_a, wallet.pkhash);
+    assert!(wait_until(|| node_b.blockchain().height() == 2));
+
+    // one transaction is relayed to both nodes, the other only known to A
+    let utxos = node_a.blockchain().utxos.utxos_from_pkhash(wallet.pkhash);
+    let txs: Vec<Transaction> = utxos
+        .into_iter()
+        .map(|outpoint| {
+            let value = node_a.blockchain().utxos.get_utxo(&outpoint).unwrap().value;
+            let mut tx = Transaction::new(
+                vec![TxInput::new_unsigned(outpoint.txid, outpoint.index).with_value(value)],
+                vec![TxOutput::new(1000, [0; 32])],
+            );
+            wallet.sign_transaction(&mut tx).unwrap();
+            tx
+        })
+        .collect();
+    node_a.submit_transaction(txs[0].clone()).unwrap();
+    assert!(wait_until(|| node_b
+        .blockchain()
+        .mempool
+        .contains(&txs[0].id)));
+    node_a
+        .blockchain()
+        .submit_transaction(txs[1].clone())
+        .unwrap();
+
+    let mut block = node_a.blockchain().next_block();
+    for tx in &txs {
+        block.add_tx(tx.clone());
+    }
+    let reward = node_a.blockchain().get_block_reward();
+    mine(&mut block, wallet.pkhash, reward);
+    let digest = block.digest;
+    node_a.submit_block(block).unwrap();
+
+    assert!(wait_until(|| node_b.blockchain().height() == 3));
+    assert_eq!(node_b.blockchain().prev_hash(), digest);
+    assert!(node_b.blockchain().mempool.is_empty());
+}
+
+#[test]
+fn test_runtime_mines_and_relays() {
+    let wallet = Wallet::new();
+    let params = ChainParams::regtest();
+    let runtime_a = NodeRuntime::start(
+        Node::new(Blockchain::new(params.clone())),
+        RuntimeConfig {
+            listen: Some("127.0.0.1:0".to_string()),
+            mine_to: Some(wallet.pkhash),
+            miner_threads: 1,
+            ..RuntimeConfig::default()
+        },
+    )
+    .unwrap();
+    let runtime_b = NodeRuntime::start(
+        Node::new(Blockchain::new(params)),
+        RuntimeConfig {
+            peers: vec![runtime_a.listen_addr().unwrap().to_string()],
+            ..RuntimeConfig::default()
+        },
+    )
+    .unwrap();
+
+    let node_b = runtime_b.node();
+    assert!(wait_until(|| node_b.blockchain().height() >= 2));
+    let node_a = runtime_a.node().clone();
+    runtime_a.shutdown();
+    let height = node_a.blockchain().height();
+    assert!(wait_until(|| node_b.blockchain().height() == height));
+
+    // a block mined elsewhere is validated by the chain thread
+    let mut block = node_b.blockchain().next_block();
+    mine(&mut block, [0; 32], node_b.blockchain().get_block_reward());
+    runtime_b.handle().submit_block(block.clone()).unwrap();
+    assert_eq!(node_b.blockchain().height(), height + 1);
+    assert!(runtime_b.handle().submit_block(block).is_err());
+}
+
+#[test]
+fn test_peer_sending_invalid_block_is_banned() {
+    let node = Node::new(Blockchain::new(ChainParams::regtest()));
+    let addr = node.listen("127.0.0.1:0").unwrap();
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    assert!(wait_until(|| node.peer_count() == 1));
+    let mut block = node.blockchain().next_block();
+    mine(&mut block, [1; 32], node.blockchain().get_block_reward());
+    block.header.merkle_root = [0; 32];
+    block.update_digest();
+    write_message(&mut stream, &node.envelope(), &Message::Block(block)).unwrap();
+
+    assert!(wait_until(|| node.peer_count() == 0));
+    assert!(node.peer_manager().is_banned(&addr.ip()));
+    assert_eq!(node.blockchain().height(), 0);
+
+    // reconnecting is refused
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut buf = [0; 1];
+    assert_eq!(stream.read(&mut buf).unwrap_or(0), 0);
+}
+
+#[test]
+fn test_addresses_are_gossiped() {
+    let node_a = Node::new(Blockchain::new(ChainParams::regtest()));
+    let node_b = Node::new(Blockchain::new(ChainParams::regtest()));
+    let node_c = Node::new(Blockchain::new(ChainParams::regtest()));
+    let addr_a = node_a.listen("127.0.0.1:0").unwrap();
+    let addr_c = node_c.listen("127.0.0.1:0").unwrap();
+    node_a.addr_manager().add(addr_c, 1);
+
+    // B learns about C from A, then connects to it
+    node_b.connect(addr_a).unwrap();
+    assert!(wait_until(|| node_b.addr_manager().len() == 2));
+    assert_eq!(node_b.connect_to_known_peers(8), 1);
+    assert!(wait_until(|| node_c.peer_count() == 1));
+    assert_eq!(node_b.peer_manager().outbound_count(), 2);
+}
+
+#[test]
+fn test_initial_block_download_from_two_peers() {
+    let node_a = Node::new(Blockchain::new(ChainParams::regtest()));
+    let node_c = Node::new(Blockchain::new(ChainParams::regtest()));
+    for _ in 0..40 {
+        mine_block(&node_a, [1; 32]);
+        let block = node_a.blockchain().iter().last().unwrap().clone();
+        node_c.blockchain().add_block(block).unwrap();
+    }
+    let addr_a = node_a.listen("127.0.0.1:0").unwrap();
+    let addr_c = node_c.listen("127.0.0.1:0").unwrap();
+
+    let node_b = Node::new(Blockchain::new(ChainParams::regtest()));
+    node_b.connect(addr_a).unwrap();
+    node_b.connect(addr_c).unwrap();
+    assert!(wait_until(|| node_b.blockchain().height() == 40));
+    assert_eq!(
+        node_b.blockchain().prev_hash(),
+        node_a.blockchain().prev_hash()
+    );
+    assert!(wait_until(|| node_b.sync_state() == SyncState::Idle));
+}
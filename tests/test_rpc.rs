@@ -0,0 +1,477 @@
+use rust_blockchain::{
+    blockchain, errors, hash, json, mempool, network, params, rpc, transaction, utils, wallet,
+    websocket,
+};
+
+use blockchain::Blockchain;
+use errors::RpcError;
+use hash::OutPoint;
+use json::Json;
+use mempool::DEFAULT_MIN_RELAY_FEE_RATE;
+use network::Node;
+use params::ChainParams;
+use std::io::{Read, Write};
+use transaction::{Transaction, TxInput, TxOutput};
+use utils::{mine, mine_with_fees};
+use wallet::Wallet;
+
+#[test]
+fn test_rpc_over_http() {
+    let wallet = Wallet::new();
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let auth = rpc::RpcAuth::random();
+    let addr = rpc::RpcServer::new(node.clone(), auth.clone())
+        .listen("127.0.0.1:0")
+        .unwrap();
+
+    let mut block = node.blockchain().next_block();
+    let reward = node.blockchain().get_block_reward();
+    mine(&mut block, wallet.pkhash, reward);
+    let hash = hex::encode(block.digest);
+    let size = block.as_bincode().len() as u64;
+    node.submit_block(block).unwrap();
+
+    assert_eq!(
+        rpc::call(addr, &auth, "getblockcount", vec![]).unwrap(),
+        Json::U64(1)
+    );
+    let info = rpc::call(addr, &auth, "getchaininfo", vec![]).unwrap();
+    assert_eq!(
+        info.get("network"),
+        Some(&Json::String("mainnet".to_string()))
+    );
+    assert_eq!(
+        info.get("chain_id"),
+        Some(&Json::String(hex::encode(node.blockchain().chain_id())))
+    );
+    assert_eq!(info.get("supply"), Some(&Json::U64(reward)));
+    assert_eq!(info.get("transactions"), Some(&Json::U64(1)));
+    let block = rpc::call(addr, &auth, "getblock", vec![Json::String(hash.clone())]).unwrap();
+    assert_eq!(
+        block.get("header").and_then(|header| header.get("index")),
+        Some(&Json::U64(1))
+    );
+    assert_eq!(
+        rpc::call(
+            addr,
+            &auth,
+            "getbalance",
+            vec![Json::String(wallet.address.to_string())]
+        )
+        .unwrap(),
+        Json::U64(reward)
+    );
+
+    let meta = rpc::call(addr, &auth, "getblockmeta", vec![Json::U64(1)]).unwrap();
+    assert_eq!(meta.get("hash"), Some(&Json::String(hash.clone())));
+    assert_eq!(meta.get("source"), Some(&Json::Null));
+    assert_eq!(meta.get("size"), Some(&Json::U64(size)));
+    assert!(matches!(
+        rpc::call(addr, &auth, "getblockmeta", vec![Json::U64(0)]),
+        Err(RpcError::Remote(rpc::NOT_FOUND, _))
+    ));
+
+    let OutPoint { txid, index } = node.blockchain().utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let unspent = rpc::call(
+        addr,
+        &auth,
+        "listunspent",
+        vec![Json::String(hex::encode(wallet.pkhash))],
+    )
+    .unwrap();
+    assert_eq!(unspent.as_array().unwrap().len(), 1);
+    assert_eq!(
+        unspent.as_array().unwrap()[0].get("value"),
+        Some(&Json::U64(reward))
+    );
+    let mut tx = Transaction::new(
+        vec![TxInput::new_unsigned(txid, index).with_value(reward)],
+        vec![TxOutput::new(reward - 1000, [1; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let raw = Json::String(hex::encode(tx.as_bincode()));
+
+    assert_eq!(
+        rpc::call(addr, &auth, "sendrawtransaction", vec![raw.clone()]).unwrap(),
+        Json::String(hex::encode(tx.id))
+    );
+    let txid = Json::String(hex::encode(tx.id));
+    assert_eq!(
+        rpc::call(addr, &auth, "getconfirmations", vec![txid]).unwrap(),
+        Json::U64(0)
+    );
+    assert!(matches!(
+        rpc::call(
+            addr,
+            &auth,
+            "getconfirmations",
+            vec![Json::String(hex::encode([9; 32]))]
+        ),
+        Err(RpcError::Remote(rpc::NOT_FOUND, _))
+    ));
+    let info = rpc::call(addr, &auth, "getmininginfo", vec![]).unwrap();
+    assert_eq!(info.get("blocks"), Some(&Json::U64(1)));
+    assert_eq!(
+        info.get("bits"),
+        Some(&Json::U64(ChainParams::mainnet().bits.into()))
+    );
+    assert_eq!(info.get("mining"), Some(&Json::Bool(false)));
+    assert_eq!(info.get("attempts"), Some(&Json::U64(0)));
+    assert_eq!(info.get("expectedtime"), Some(&Json::Null));
+
+    let info = rpc::call(addr, &auth, "getmempoolinfo", vec![]).unwrap();
+    assert_eq!(info.get("size"), Some(&Json::U64(1)));
+    assert_eq!(info.get("fees"), Some(&Json::U64(1000)));
+    assert_eq!(
+        info.get("minrelayfeerate"),
+        Some(&Json::U64(DEFAULT_MIN_RELAY_FEE_RATE))
+    );
+
+    assert!(matches!(
+        rpc::call(addr, &auth, "sendrawtransaction", vec![raw]),
+        Err(RpcError::Remote(rpc::TRANSACTION_REJECTED, _))
+    ));
+}
+
+#[test]
+fn test_rpc_requires_auth() {
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let auth = rpc::RpcAuth::new("alice", "secret");
+    let addr = rpc::RpcServer::new(node, auth.clone())
+        .listen("127.0.0.1:0")
+        .unwrap();
+
+    assert_eq!(
+        rpc::call(addr, &auth, "getblockcount", vec![]).unwrap(),
+        Json::U64(0)
+    );
+    assert!(matches!(
+        rpc::call(
+            addr,
+            &rpc::RpcAuth::new("alice", "guess"),
+            "listwallets",
+            vec![]
+        ),
+        Err(RpcError::Unauthorized)
+    ));
+
+    // what a cross-origin form post looks like, with and without credentials
+    let post = |headers: &str| {
+        let body = r#"{"jsonrpc":"2.0","method":"createwallet","params":["x"],"id":1}"#;
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST / HTTP/1.1\r\n{headers}Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    assert!(post("Content-Type: text/plain\r\n").starts_with("HTTP/1.1 401"));
+    assert!(
+        post("Authorization: Basic YWxpY2U6c2VjcmV0\r\nContent-Type: text/plain\r\n")
+            .starts_with("HTTP/1.1 415")
+    );
+    assert!(post("Authorization: Basic YWxpY2U6c2VjcmV0\r\n").starts_with("HTTP/1.1 415"));
+    assert!(
+        post("Authorization: Basic YWxpY2U6c2VjcmV0\r\nContent-Type: application/json\r\n")
+            .starts_with("HTTP/1.1 200")
+    );
+}
+
+fn read_event(stream: &mut std::net::TcpStream) -> Json {
+    let (opcode, payload) = websocket::read_frame(stream).unwrap();
+    assert_eq!(opcode, websocket::OPCODE_TEXT);
+    String::from_utf8(payload).unwrap().parse().unwrap()
+}
+
+#[test]
+fn test_websocket_events() {
+    let wallet = Wallet::new();
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let auth = rpc::RpcAuth::random();
+    let addr = rpc::RpcServer::new(node.clone(), auth.clone())
+        .listen("127.0.0.1:0")
+        .unwrap();
+    let reward = node.blockchain().get_block_reward();
+
+    let path = format!("{}?address={}", rpc::WEBSOCKET_PATH, wallet.address);
+    let mut stream = websocket::connect(addr, &path).unwrap();
+    stream
+        .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+        .unwrap();
+
+    // the first block doesn't touch the wallet, so only the second is sent
+    for pkhash in [[9; 32], wallet.pkhash] {
+        let mut block = node.blockchain().next_block();
+        mine(&mut block, pkhash, reward);
+        node.submit_block(block).unwrap();
+    }
+    let event = read_event(&mut stream);
+    assert_eq!(event.get("type"), Some(&Json::String("block".to_string())));
+    assert_eq!(
+        event
+            .get("block")
+            .and_then(|block| block.get("header"))
+            .and_then(|header| header.get("index")),
+        Some(&Json::U64(2))
+    );
+
+    let OutPoint { txid, index } = node.blockchain().utxos.utxos_from_pkhash(wallet.pkhash)[0];
+    let mut tx = Transaction::new(
+        vec![TxInput::new_unsigned(txid, index).with_value(reward)],
+        vec![TxOutput::new(reward - 1000, [1; 32])],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    let txid = hex::encode(tx.id);
+    node.submit_transaction(tx).unwrap();
+
+    let event = read_event(&mut stream);
+    assert_eq!(
+        event.get("type"),
+        Some(&Json::String("transaction".to_string()))
+    );
+    assert_eq!(
+        event.get("transaction").and_then(|tx| tx.get("id")),
+        Some(&Json::String(txid))
+    );
+
+    // pings are answered, and a close is acknowledged
+    websocket::write_frame(&mut stream, websocket::OPCODE_PING, b"hi", Some([1; 4])).unwrap();
+    assert_eq!(
+        websocket::read_frame(&mut stream).unwrap(),
+        (websocket::OPCODE_PONG, b"hi".to_vec())
+    );
+    websocket::write_frame(&mut stream, websocket::OPCODE_CLOSE, &[], Some([1; 4])).unwrap();
+    assert_eq!(
+        websocket::read_frame(&mut stream).unwrap().0,
+        websocket::OPCODE_CLOSE
+    );
+
+    assert!(websocket::connect(addr, "/other").is_err());
+}
+
+#[test]
+fn test_explorer_over_http() {
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let addr = rpc::RpcServer::new(node, rpc::RpcAuth::random())
+        .listen("127.0.0.1:0")
+        .unwrap();
+
+    let get = |path: &str| {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let page = get("/explorer");
+    assert!(page.starts_with("HTTP/1.1 200 OK"));
+    assert!(page.contains("Content-Type: text/html"));
+    let blocks = get("/explorer/api/blocks");
+    assert!(blocks.contains("Content-Type: application/json"));
+    let body: Json = blocks.split("\r\n\r\n").nth(1).unwrap().parse().unwrap();
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert!(get("/explorer/api/tx/00").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn test_invoice_paid() {
+    let wallet = Wallet::new();
+    let payee = Wallet::new();
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    node.invoices().set_confirmations(2);
+    let auth = rpc::RpcAuth::random();
+    let addr = rpc::RpcServer::new(node.clone(), auth.clone())
+        .listen("127.0.0.1:0")
+        .unwrap();
+
+    let mut block = node.blockchain().next_block();
+    let reward = node.blockchain().get_block_reward();
+    mine(&mut block, wallet.pkhash, reward);
+    let coinbase_id = block.transactions[0].id;
+    node.submit_block(block).unwrap();
+
+    let payee_address = Json::String(payee.address.to_string());
+    let invoice = rpc::call(
+        addr,
+        &auth,
+        "createinvoice",
+        vec![
+            payee_address.clone(),
+            Json::U64(5_000),
+            Json::U64(600),
+            Json::String("order 12".to_string()),
+        ],
+    )
+    .unwrap();
+    let id = invoice.get("id").cloned().unwrap();
+    assert_eq!(
+        invoice.get("status"),
+        Some(&Json::String("unpaid".to_string()))
+    );
+    assert_eq!(
+        invoice.get("uri"),
+        Some(&Json::String(format!(
+            "rbc:{}?amount=0.00005&message=order%2012",
+            payee.address
+        )))
+    );
+    assert!(matches!(
+        rpc::call(
+            addr,
+            &auth,
+            "createinvoice",
+            vec![payee_address, Json::U64(1_000)]
+        ),
+        Err(RpcError::Remote(rpc::WALLET_ERROR, _))
+    ));
+
+    let mut tx = Transaction::new(
+        vec![TxInput::new_unsigned(coinbase_id, 0).with_value(reward)],
+        vec![
+            TxOutput::new(5_000, payee.pkhash),
+            TxOutput::new(reward - 6_000, wallet.pkhash),
+        ],
+    );
+    wallet.sign_transaction(&mut tx).unwrap();
+    node.submit_transaction(tx.clone()).unwrap();
+    let invoice = rpc::call(addr, &auth, "getinvoice", vec![id.clone()]).unwrap();
+    assert_eq!(
+        invoice.get("status"),
+        Some(&Json::String("confirming".to_string()))
+    );
+    assert_eq!(invoice.get("confirmations"), Some(&Json::U64(0)));
+    assert_eq!(invoice.get("received"), Some(&Json::U64(5_000)));
+
+    for txs in [vec![tx], vec![]] {
+        let mut block = node.blockchain().next_block();
+        txs.into_iter().for_each(|tx| block.add_tx(tx));
+        mine_with_fees(&mut block, &node.blockchain(), [0; 32]).unwrap();
+        node.submit_block(block).unwrap();
+    }
+    let invoice = rpc::call(addr, &auth, "getinvoice", vec![id]).unwrap();
+    assert_eq!(
+        invoice.get("status"),
+        Some(&Json::String("paid".to_string()))
+    );
+    let invoices = rpc::call(addr, &auth, "listinvoices", vec![]).unwrap();
+    assert_eq!(invoices.as_array().unwrap().len(), 1);
+    assert!(matches!(
+        rpc::call(addr, &auth, "getinvoice", vec![Json::U64(9)]),
+        Err(RpcError::Remote(rpc::NOT_FOUND, _))
+    ));
+}
+
+#[test]
+fn test_wallet_routing() {
+    let node = Node::new(Blockchain::new(ChainParams::mainnet()));
+    let chain_id = node.blockchain().chain_id();
+    let alice = Wallet::from_seed_for_chain([1; 32], chain_id);
+    let bob = Wallet::from_seed_for_chain([2; 32], chain_id);
+    let (alice_pkhash, bob_address) = (alice.pkhash, bob.address);
+    node.wallets().add("alice", alice).unwrap();
+    node.wallets().add("bob", bob).unwrap();
+    let auth = rpc::RpcAuth::random();
+    let addr = rpc::RpcServer::new(node.clone(), auth.clone())
+        .listen("127.0.0.1:0")
+        .unwrap();
+
+    let mut block = node.blockchain().next_block();
+    let reward = node.blockchain().get_block_reward();
+    mine(&mut block, alice_pkhash, reward);
+    node.submit_block(block).unwrap();
+
+    assert_eq!(
+        rpc::call(addr, &auth, "listwallets", vec![]).unwrap(),
+        Json::Array(vec![
+            Json::String("alice".to_string()),
+            Json::String("bob".to_string())
+        ])
+    );
+    assert!(matches!(
+        rpc::call(addr, &auth, "getbalance", vec![]),
+        Err(RpcError::Remote(rpc::WALLET_NOT_SPECIFIED, _))
+    ));
+    assert!(matches!(
+        rpc::call_wallet(addr, &auth, "carol", "getbalance", vec![]),
+        Err(RpcError::Remote(rpc::WALLET_NOT_FOUND, _))
+    ));
+    assert_eq!(
+        rpc::call_wallet(addr, &auth, "alice", "getbalance", vec![]).unwrap(),
+        Json::U64(reward)
+    );
+    assert_eq!(
+        rpc::call_wallet(addr, &auth, "bob", "getbalance", vec![]).unwrap(),
+        Json::U64(0)
+    );
+
+    let txid = rpc::call_wallet(
+        addr,
+        &auth,
+        "alice",
+        "sendtoaddress",
+        vec![Json::String(bob_address.to_string()), Json::U64(5_000)],
+    )
+    .unwrap();
+    let txid: hash::TxId = txid.as_str().unwrap().parse().unwrap();
+    let info = rpc::call_wallet(addr, &auth, "alice", "getwalletinfo", vec![]).unwrap();
+    assert_eq!(info.get("pending"), Some(&Json::U64(1)));
+    assert_eq!(info.get("spendable"), Some(&Json::U64(0)));
+    // bob has nothing to spend
+    assert!(matches!(
+        rpc::call_wallet(
+            addr,
+            &auth,
+            "bob",
+            "sendtoaddress",
+            vec![Json::String(bob_address.to_string()), Json::U64(1_000)]
+        ),
+        Err(RpcError::Remote(rpc::WALLET_ERROR, _))
+    ));
+
+    let tx = node.blockchain().mempool.get(&txid).unwrap().tx.clone();
+    let mut block = node.blockchain().next_block();
+    block.add_tx(tx);
+    mine_with_fees(&mut block, &node.blockchain(), [0; 32]).unwrap();
+    node.submit_block(block).unwrap();
+    assert_eq!(
+        rpc::call_wallet(addr, &auth, "bob", "getbalance", vec![]).unwrap(),
+        Json::U64(5_000)
+    );
+    let info = rpc::call_wallet(addr, &auth, "alice", "getwalletinfo", vec![]).unwrap();
+    assert_eq!(info.get("pending"), Some(&Json::U64(0)));
+    assert_eq!(info.get("syncedheight"), Some(&Json::U64(2)));
+
+    rpc::call_wallet(addr, &auth, "bob", "unloadwallet", vec![]).unwrap();
+    assert_eq!(
+        rpc::call(addr, &auth, "getbalance", vec![]).unwrap(),
+        rpc::call_wallet(addr, &auth, "alice", "getbalance", vec![]).unwrap()
+    );
+    let created = rpc::call(
+        addr,
+        &auth,
+        "createwallet",
+        vec![Json::String("carol".to_string())],
+    )
+    .unwrap();
+    assert_eq!(
+        created
+            .get("mnemonic")
+            .and_then(Json::as_str)
+            .map(|phrase| phrase.split(' ').count()),
+        Some(12)
+    );
+    assert!(matches!(
+        rpc::call(
+            addr,
+            &auth,
+            "createwallet",
+            vec![Json::String("../carol".to_string())]
+        ),
+        Err(RpcError::Remote(rpc::INVALID_PARAMS, _))
+    ));
+}
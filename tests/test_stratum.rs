@@ -0,0 +1,53 @@
+use rust_blockchain::{blockchain, consensus, errors, network, params, stratum, utils};
+
+use blockchain::Blockchain;
+use errors::RpcError;
+use network::Node;
+use params::ChainParams;
+use std::time::Duration;
+use stratum::{StratumClient, StratumServer, WorkProvider};
+use utils::mine;
+
+#[test]
+fn test_external_worker_mines_blocks() {
+    let node = Node::new(Blockchain::new(ChainParams::regtest()));
+    let provider = WorkProvider::new(node.clone(), [1; 32]);
+    let addr = StratumServer::new(provider).listen("127.0.0.1:0").unwrap();
+
+    let mut client = StratumClient::connect(addr).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let job = client.subscribe().unwrap();
+    assert_eq!(job.header.index, 1);
+    assert_eq!(job.algorithm, "sha256");
+
+    // A nonce that misses the target is turned down
+    let mut header = consensus::Header::from(&job.header);
+    let bad_nonce = (0..)
+        .find(|&nonce| {
+            header.nonce = nonce;
+            !header.has_valid_work(&consensus::PowAlgorithm::Sha256)
+        })
+        .unwrap();
+    assert!(matches!(
+        client.submit(&job, bad_nonce, job.header.timestamp),
+        Err(RpcError::Remote(stratum::INVALID_WORK, _))
+    ));
+
+    let nonce = stratum::solve_job(&job, 0, u64::MAX).unwrap();
+    let digest = client.submit(&job, nonce, job.header.timestamp).unwrap();
+    assert_eq!(node.blockchain().prev_hash(), digest);
+
+    // The worker is sent work on the new tip, and again when someone else
+    // finds a block
+    let job = client.next_job().unwrap();
+    assert_eq!(job.header.prev_hash, digest);
+    let mut block = node.blockchain().next_block();
+    mine(&mut block, [2; 32], node.blockchain().get_block_reward());
+    let other = block.digest;
+    node.submit_block(block).unwrap();
+    let job = client.next_job().unwrap();
+    assert_eq!(job.header.prev_hash, other);
+    assert_eq!(job.header.index, 3);
+}
@@ -0,0 +1,197 @@
+use rust_blockchain::{amount, blockchain, errors, params, signature, swap, utils, wallet};
+
+use amount::Amount;
+use blockchain::Blockchain;
+use errors::SwapError;
+use params::ChainParams;
+use signature::SignatureScheme;
+use swap::{Swap, SwapRole, SwapState};
+use utils::{mine, mine_with_fees};
+use wallet::Wallet;
+
+const VALUE: Amount = Amount(10_000_000);
+
+// Mines the chain's mempool into a block
+fn confirm(chain: &mut Blockchain) {
+    let mut block = chain.next_block();
+    block.transactions = chain.mempool.select_for_block(100);
+    mine_with_fees(&mut block, chain, [0; 32]).unwrap();
+    chain.add_block(block).unwrap();
+}
+
+fn mine_to(chain: &mut Blockchain, pkhash: [u8; 32]) {
+    let mut block = chain.next_block();
+    mine(&mut block, pkhash, chain.get_block_reward());
+    chain.add_block(block).unwrap();
+}
+
+fn wallet_for(chain: &Blockchain) -> Wallet {
+    Wallet::with_scheme(SignatureScheme::Ecdsa, chain.chain_id())
+}
+
+// Alice swaps coins on chain A for Bob's coins on chain B. Each has a
+// wallet on both chains.
+struct Setup {
+    chain_a: Blockchain,
+    chain_b: Blockchain,
+    alice_a: Wallet,
+    alice_b: Wallet,
+    bob_a: Wallet,
+    bob_b: Wallet,
+}
+
+fn setup() -> Setup {
+    let mut chain_a = Blockchain::new(ChainParams::mainnet());
+    let mut chain_b = Blockchain::new(ChainParams::testnet());
+    let (alice_a, bob_a) = (wallet_for(&chain_a), wallet_for(&chain_a));
+    let (alice_b, bob_b) = (wallet_for(&chain_b), wallet_for(&chain_b));
+    mine_to(&mut chain_a, alice_a.pkhash);
+    mine_to(&mut chain_b, bob_b.pkhash);
+    Setup {
+        chain_a,
+        chain_b,
+        alice_a,
+        alice_b,
+        bob_a,
+        bob_b,
+    }
+}
+
+#[test]
+fn test_atomic_swap() {
+    let Setup {
+        mut chain_a,
+        mut chain_b,
+        alice_a,
+        alice_b,
+        bob_a,
+        bob_b,
+    } = setup();
+
+    // 1. Alice locks her coins on chain A
+    let mut alice = Swap::initiate(&alice_a, bob_a.pkhash, VALUE, 40);
+    assert_eq!(alice.role(), SwapRole::Initiator);
+    chain_a
+        .submit_transaction(alice.fund(&alice_a, &chain_a).unwrap())
+        .unwrap();
+    confirm(&mut chain_a);
+    assert_eq!(alice.state(), SwapState::Funded);
+
+    // 2. Bob checks them and locks his on chain B, timing out sooner
+    let mut bob = Swap::participate(&bob_b, alice_b.pkhash, alice.payment_hash(), VALUE, 20);
+    assert_eq!(
+        bob.fund(&bob_b, &chain_b).err(),
+        Some(SwapError::WrongState(SwapState::Created))
+    );
+    bob.accept(&bob_a, &chain_a, *alice.offered(), VALUE)
+        .unwrap();
+    chain_b
+        .submit_transaction(bob.fund(&bob_b, &chain_b).unwrap())
+        .unwrap();
+    confirm(&mut chain_b);
+    assert_eq!(bob.state(), SwapState::Locked);
+    assert_eq!(bob.find_preimage(&chain_b), None);
+
+    // 3. Alice claims Bob's coins, revealing the preimage
+    alice
+        .accept(&alice_b, &chain_b, *bob.offered(), VALUE)
+        .unwrap();
+    assert_eq!(alice.state(), SwapState::Locked);
+    chain_b
+        .submit_transaction(alice.claim(&alice_b, &chain_b).unwrap())
+        .unwrap();
+    assert_eq!(alice.state(), SwapState::Claimed);
+
+    // 4. Bob learns it, from the mempool already, and claims Alice's coins
+    assert_eq!(bob.find_preimage(&chain_b), alice.preimage());
+    confirm(&mut chain_b);
+    chain_a
+        .submit_transaction(bob.claim(&bob_a, &chain_a).unwrap())
+        .unwrap();
+    confirm(&mut chain_a);
+    assert_eq!(bob.state(), SwapState::Claimed);
+
+    assert!(chain_a.utxos.balance(bob_a.pkhash) > VALUE.0 - 1000);
+    assert!(chain_b.utxos.balance(alice_b.pkhash) > VALUE.0 - 1000);
+    assert!(!alice.can_refund(&chain_a));
+}
+
+#[test]
+fn test_swap_refunds() {
+    let Setup {
+        mut chain_a,
+        mut chain_b,
+        alice_a,
+        alice_b,
+        bob_a,
+        bob_b,
+    } = setup();
+
+    let mut alice = Swap::initiate(&alice_a, bob_a.pkhash, VALUE, 20);
+    chain_a
+        .submit_transaction(alice.fund(&alice_a, &chain_a).unwrap())
+        .unwrap();
+    confirm(&mut chain_a);
+
+    // Bob insists on time to claim, and on being paid
+    let mut bob = Swap::participate(&bob_b, alice_b.pkhash, alice.payment_hash(), VALUE, 10);
+    let mut short = Swap::initiate(&alice_a, bob_a.pkhash, VALUE, 5);
+    chain_a
+        .submit_transaction(short.fund(&alice_a, &chain_a).unwrap())
+        .unwrap();
+    confirm(&mut chain_a);
+    assert_eq!(
+        bob.accept(&bob_a, &chain_a, *short.offered(), VALUE),
+        Err(SwapError::WrongTerms)
+    );
+    assert_eq!(
+        Swap::participate(&bob_b, alice_b.pkhash, short.payment_hash(), VALUE, 10).accept(
+            &bob_a,
+            &chain_a,
+            *short.offered(),
+            VALUE
+        ),
+        Err(SwapError::TimeoutTooSoon)
+    );
+    assert_eq!(
+        bob.accept(&alice_a, &chain_a, *alice.offered(), VALUE),
+        Err(SwapError::WrongTerms)
+    );
+    bob.accept(&bob_a, &chain_a, *alice.offered(), VALUE)
+        .unwrap();
+    chain_b
+        .submit_transaction(bob.fund(&bob_b, &chain_b).unwrap())
+        .unwrap();
+    confirm(&mut chain_b);
+
+    // Alice never claims, so Bob takes his coins back at height 10 on
+    // chain B, and Alice hers at height 20 on chain A
+    assert!(!bob.can_refund(&chain_b));
+    assert_eq!(
+        bob.refund(&bob_b, &chain_b).err(),
+        Some(SwapError::NotTimedOut(10))
+    );
+    while chain_b.height() + 1 < 10 {
+        mine_to(&mut chain_b, [0; 32]);
+    }
+    assert!(bob.can_refund(&chain_b));
+    chain_b
+        .submit_transaction(bob.refund(&bob_b, &chain_b).unwrap())
+        .unwrap();
+    confirm(&mut chain_b);
+    assert_eq!(bob.state(), SwapState::Refunded);
+    assert!(!bob.can_refund(&chain_b));
+
+    while chain_a.height() + 1 < 20 {
+        mine_to(&mut chain_a, [0; 32]);
+    }
+    assert!(alice.can_refund(&chain_a));
+    chain_a
+        .submit_transaction(alice.refund(&alice_a, &chain_a).unwrap())
+        .unwrap();
+    assert_eq!(alice.state(), SwapState::Refunded);
+    assert_eq!(
+        alice.claim(&alice_b, &chain_b).err(),
+        Some(SwapError::WrongState(SwapState::Refunded))
+    );
+}